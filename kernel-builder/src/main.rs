@@ -2,12 +2,16 @@ use std::{
     fs,
     io::{self, Read, Write},
     path::{Path, PathBuf},
-    process::{ChildStdout, Command, ExitCode, Stdio},
-    sync::atomic::{AtomicUsize, Ordering},
+    process::{Child, ChildStdout, Command, ExitCode, Stdio},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use bootloader::BootConfig;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use rayon::prelude::*;
 
 /// Struct to store the command line args parsed by clap
@@ -15,16 +19,41 @@ use rayon::prelude::*;
 #[command(author="Mark Ross", version="0.1", about="Compiles and optionally runs the kernel", long_about = None)]
 struct Args {
     /// Runs the kernel using qemu after compiling it.
+    /// Writes to the kernel image's drive persist after qemu exits, unlike when testing.
     /// Requires `--bios-path` to be set.
     #[arg(long, action, conflicts_with = "test", requires = "bios_path")]
     run: bool,
 
     /// Compiles the kernel in test mode and tests it. Pass a space-separated list of numbers to only run those tests.
+    /// qemu is run with `-snapshot`, so any writes to drives made by tests are discarded.
     /// Requires `--bios-path` to be set.
     #[arg(long, action, num_args = 0.., requires = "bios_path")]
     test: Option<Vec<usize>>,
 
+    /// Compiles the kernel in test mode and runs only the tests whose names contain the given substring.
+    /// Requires `--bios-path` to be set.
+    ///
+    /// Example usage: `kernel-builder --test-name xhci`
+    #[arg(
+        long,
+        value_name = "SUBSTRING",
+        conflicts_with_all = ["run", "test"],
+        requires = "bios_path"
+    )]
+    test_name: Option<String>,
+
+    /// The number of seconds each test may run for before it is killed and counted as a failure.
+    /// Has no effect if not combined with --test or --test-name.
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    test_timeout: u64,
+
+    /// The format to print test results in.
+    /// Has no effect if not combined with --test or --test-name.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
+
     /// Runs the kernel ready for a debugger to attach, with serial output written to the given file.
+    /// A GDB script to load the kernel's symbols and attach is written to `kernel.gdb` in the same directory.
     /// Has no effect if not combined with --run.
     #[arg(long, value_name = "SERIAL_FILE")]
     debug: Option<String>,
@@ -33,10 +62,30 @@ struct Args {
     #[arg(long, value_name = "FILE")]
     qemu_debug: Option<String>,
 
+    /// The file the kernel's logs are written to, through a second serial port (COM2).
+    /// Has no effect if not combined with --run. When running tests, logs are printed with the rest of the output instead.
+    #[arg(long, value_name = "FILE", default_value = "kernel.log")]
+    log_file: String,
+
     /// Compiles the kernel in release mode.
     #[arg(long, action)]
     release: bool,
 
+    /// Runs qemu with KVM hardware acceleration, which is much faster than full emulation.
+    /// Has no effect if not combined with --run or --test. Requires `/dev/kvm` to be available.
+    #[arg(long, action)]
+    kvm: bool,
+
+    /// The amount of RAM to give the VM, in any format qemu's -m flag accepts (e.g. `64M` or `1G`).
+    /// Has no effect if not combined with --run or --test.
+    #[arg(long, value_name = "SIZE", default_value = "256M")]
+    qemu_mem: String,
+
+    /// The number of CPUs to give the VM, using qemu's -smp flag.
+    /// Has no effect if not combined with --run or --test.
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    qemu_cpus: u32,
+
     /// The path to the BIOS file to run
     #[arg(long)]
     bios_path: Option<String>,
@@ -49,6 +98,31 @@ struct Args {
     qemu_device: Vec<String>,
 }
 
+/// The virtual address the bootloader maps the kernel at.
+/// This must be the same as `KERNEL_VIRT_ADDR` in `kernel/src/main.rs`.
+const KERNEL_VIRT_ADDR: u64 = 0xFFFF800000000000;
+
+/// The port qemu listens for a debugger on when run with --debug
+const GDB_PORT: u16 = 1234;
+
+/// The value the kernel writes to the `isa-debug-exit` device when the test runner succeeds.
+/// This must be the same as `QemuExitCode::Success` in `kernel/src/tests/mod.rs`.
+const QEMU_EXIT_SUCCESS: i32 = 0x10;
+
+/// The exit code of qemu when the test runner succeeds.
+/// The `isa-debug-exit` device makes qemu exit with `(value << 1) | 1`, so that it can't be confused with qemu exiting
+/// normally with code 0.
+const QEMU_SUCCESS_EXIT_CODE: i32 = (QEMU_EXIT_SUCCESS << 1) | 1;
+
+/// The format to print test results in
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Coloured output for a terminal, with each result printed as soon as the test finishes
+    Human,
+    /// A JSON array of objects with `index`, `name`, `passed`, and `serial_output` fields, printed once all tests finish
+    Json,
+}
+
 /// This builder may be invoked with `pwd` = `project-root/kernel-builder`, `project-root/kernel` or just `project-root`.
 /// This function computes the relative path to the `kernel` crate for either of these options.
 fn kernel_dir() -> &'static str {
@@ -97,7 +171,15 @@ fn prepare_qemu_command(args: &Args, file: &str, test: bool) -> Command {
 
     c.arg("-bios").arg(bios_path);
 
-    c.arg("-machine").arg("q35");
+    if args.kvm {
+        c.arg("-machine").arg("q35,accel=kvm");
+        c.arg("-enable-kvm").arg("-cpu").arg("host");
+    } else {
+        c.arg("-machine").arg("q35");
+    }
+
+    c.arg("-m").arg(&args.qemu_mem);
+    c.arg("-smp").arg(args.qemu_cpus.to_string());
 
     c.arg("-drive")
         .arg(format!("if=none,format=raw,id=os-drive,file={}", file)); // Load the specified image as a drive
@@ -116,7 +198,8 @@ fn prepare_qemu_command(args: &Args, file: &str, test: bool) -> Command {
     }
 
     if let Some(ref file) = args.debug {
-        c.arg("-s") // Listen for debugger on port 1234
+        c.arg("-gdb")
+            .arg(format!("tcp::{GDB_PORT}")) // Listen for debugger on port `GDB_PORT`
             .arg("-S") // Don't start until debugger gives command to
             .arg("-daemonize") // Run in background
             .arg("-serial")
@@ -129,6 +212,10 @@ fn prepare_qemu_command(args: &Args, file: &str, test: bool) -> Command {
         c.arg("-serial").arg("stdio"); // Redirect serial to stdout
     }
 
+    if !test {
+        c.arg("-serial").arg(format!("file:{}", args.log_file)); // Write logs from COM2 to the log file
+    }
+
     // Pass along other qemu args
     for arg in &args.qemu_device {
         c.arg("-device").arg(arg);
@@ -137,6 +224,25 @@ fn prepare_qemu_command(args: &Args, file: &str, test: bool) -> Command {
     c
 }
 
+/// Checks that qemu will be able to use KVM, by opening `/dev/kvm` for reading and writing.
+/// Returns a message describing the problem if it can't.
+fn check_kvm_available() -> Result<(), String> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .map(|_| ())
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                "/dev/kvm does not exist. Check that virtualisation is enabled in the BIOS and the kvm kernel module is loaded.".to_string()
+            }
+            io::ErrorKind::PermissionDenied => {
+                "permission denied opening /dev/kvm. Check that your user is in the `kvm` group.".to_string()
+            }
+            _ => format!("could not open /dev/kvm: {e}"),
+        })
+}
+
 fn prepare_kernel_and_initrd(args: &Args, kernel_in: &Path, kernel_out: &Path, initrd_out: &Path) {
     // Remove debugging symbols from the kernel because they'll be provided by the initrd
     let mut objcopy_command = Command::new("objcopy");
@@ -152,14 +258,133 @@ fn prepare_kernel_and_initrd(args: &Args, kernel_in: &Path, kernel_out: &Path, i
 
     assert!(objcopy_success, "Objcopy should have run successfully");
 
-    if args.release {
-        // The bootloader crate doesn't like empty initrds, so put a todo message there
-        fs::write(initrd_out, b"TODO: initrd for release builds")
-            .expect("Should have been able to create an initrd file");
-    } else {
-        fs::copy(kernel_in, initrd_out)
+    // Collect the initrd's files in a directory next to the archive, starting from a fresh copy of `kernel/initrd`
+    let initrd_root = initrd_out.with_file_name("initrd-root");
+    if initrd_root.exists() {
+        fs::remove_dir_all(&initrd_root)
+            .expect("Should have been able to remove old initrd directory");
+    }
+    fs::create_dir_all(&initrd_root).expect("Should have been able to create initrd directory");
+
+    let initrd_src = PathBuf::from(kernel_dir()).join("initrd");
+    if initrd_src.exists() {
+        copy_dir(&initrd_src, &initrd_root);
+    }
+
+    // The kernel reads its debug symbols from the initrd to print backtraces
+    if !args.release {
+        fs::copy(kernel_in, initrd_root.join("kernel.debug"))
             .expect("Should have been able to copy from kernel to initrd");
     }
+
+    pack_initrd(&initrd_root, initrd_out);
+}
+
+/// Recursively copies the contents of the directory `from` into the directory `to`
+fn copy_dir(from: &Path, to: &Path) {
+    for entry in fs::read_dir(from).expect("Should have been able to read directory") {
+        let entry = entry.expect("Should have been able to read directory entry");
+        let to = to.join(entry.file_name());
+
+        if entry.path().is_dir() {
+            fs::create_dir_all(&to).expect("Should have been able to create directory");
+            copy_dir(&entry.path(), &to);
+        } else {
+            fs::copy(entry.path(), &to).expect("Should have been able to copy file");
+        }
+    }
+}
+
+/// Packs everything in the directory `root` into a CPIO archive in the "newc" format at `out`,
+/// which is the format the kernel's `initrd` module reads.
+/// Paths in the archive are relative to `root`, so `root/a/b` is stored as `a/b`.
+fn pack_initrd(root: &Path, out: &Path) {
+    let mut archive = Vec::new();
+
+    add_dir_to_archive(&mut archive, root, root);
+    write_cpio_entry(&mut archive, "TRAILER!!!", 0, &[]);
+
+    fs::write(out, archive).expect("Should have been able to write initrd");
+}
+
+/// Adds the contents of `dir` to a CPIO archive, with paths relative to `root`.
+/// Entries are added in sorted order so that the same files always produce the same archive.
+fn add_dir_to_archive(archive: &mut Vec<u8>, root: &Path, dir: &Path) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .expect("Should have been able to read initrd directory")
+        .map(|entry| {
+            entry
+                .expect("Should have been able to read directory entry")
+                .path()
+        })
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .components()
+            .map(|c| {
+                c.as_os_str()
+                    .to_str()
+                    .expect("initrd paths should be UTF-8")
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if path.is_dir() {
+            write_cpio_entry(archive, &name, 0o040755, &[]);
+            add_dir_to_archive(archive, root, &path);
+        } else {
+            let data = fs::read(&path).expect("Should have been able to read initrd file");
+            write_cpio_entry(archive, &name, 0o100644, &data);
+        }
+    }
+}
+
+/// Appends a single entry to a CPIO archive in the "newc" format.
+/// This is a 110-byte header of ASCII hex fields, then the NUL-terminated name, then the data,
+/// with the name and data each padded to a multiple of 4 bytes.
+fn write_cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let file_size = u32::try_from(data.len()).expect("initrd files should be smaller than 4GiB");
+    let name_size = u32::try_from(name.len() + 1).unwrap();
+
+    archive.extend_from_slice(b"070701");
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor, namesize, check
+    for field in [0, mode, 0, 0, 1, 0, file_size, 0, 0, 0, 0, name_size, 0] {
+        write!(archive, "{field:08x}").unwrap();
+    }
+
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+/// Writes a GDB script to `kernel.gdb` next to `serial_file`, which loads the symbols of the kernel at `kernel`
+/// offset to where the bootloader maps it, and then attaches to qemu.
+/// The script can be run with `gdb -x <path>`.
+fn write_gdb_script(serial_file: &str, kernel: &Path) -> PathBuf {
+    let script_path = Path::new(serial_file).with_file_name("kernel.gdb");
+    let kernel = fs::canonicalize(kernel).expect("Should have been able to find kernel binary");
+
+    let script = format!(
+        "# Generated by kernel-builder for --debug. Run with `gdb -x {}`\n\
+        # Discard any previously loaded symbols\n\
+        symbol-file\n\
+        # The kernel is linked at address 0 but mapped at {KERNEL_VIRT_ADDR:#x} by the bootloader\n\
+        add-symbol-file {} -o {KERNEL_VIRT_ADDR:#x}\n\
+        target remote :{GDB_PORT}\n",
+        script_path.display(),
+        kernel.display(),
+    );
+
+    fs::write(&script_path, script).expect("Should have been able to write GDB script");
+
+    script_path
 }
 
 fn main() -> ExitCode {
@@ -171,8 +396,16 @@ fn main() -> ExitCode {
 
     let args = &Args::parse();
 
-    // If the --test flag is set, test the kernel instead
-    if args.test.is_some() {
+    // Check for KVM up front, as qemu's own error is unclear and would only appear after compiling
+    if args.kvm {
+        if let Err(e) = check_kvm_available() {
+            eprintln!("--kvm was passed but KVM is not available: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // If the --test or --test-name flag is set, test the kernel instead
+    if args.test.is_some() || args.test_name.is_some() {
         if args.run {
             panic!("--run and --test flags are mutually exclusive");
         }
@@ -208,8 +441,7 @@ fn main() -> ExitCode {
 
     let kernel_no_debug = out_dir.join("kernel");
 
-    // TODO: make initrd a proper file system.
-    // For now it's the kernel's debug symbols for debug builds, and empty on release
+    // A CPIO archive of `kernel/initrd`, plus the kernel's debug symbols for debug builds
     let initrd = out_dir.join("initrd");
 
     prepare_kernel_and_initrd(args, &kernel, &kernel_no_debug, &initrd);
@@ -229,6 +461,14 @@ fn main() -> ExitCode {
         .expect("Should have been able to create UEFI image");
 
     if args.run {
+        if let Some(ref serial_file) = args.debug {
+            let script_path = write_gdb_script(serial_file, &kernel);
+            println!(
+                "Waiting for a debugger - attach with `gdb -x {}`",
+                script_path.display()
+            );
+        }
+
         prepare_qemu_command(args, uefi_path.to_str().unwrap(), false)
             .spawn()
             .unwrap()
@@ -280,7 +520,10 @@ fn run_tests(args: &Args) -> ExitCode {
         .strip_suffix(")\n")
         .unwrap(); // Strip the end bracket
 
-    println!("Using test kernel binary at {test_bin}");
+    // Keep stdout as just the results when they're being parsed by another program
+    if args.format == OutputFormat::Human {
+        println!("Using test kernel binary at {test_bin}");
+    }
 
     // Parse the path to the test kernel
     let kernel = PathBuf::from(kernel_dir).join(test_bin);
@@ -289,8 +532,7 @@ fn run_tests(args: &Args) -> ExitCode {
 
     let kernel_no_debug = out_dir.join("kernel");
 
-    // TODO: make initrd a proper file system.
-    // For now it's the kernel's debug symbols for debug builds, and empty on release
+    // A CPIO archive of `kernel/initrd`, plus the kernel's debug symbols for debug builds
     let initrd = out_dir.join("initrd");
 
     prepare_kernel_and_initrd(args, &kernel, &kernel_no_debug, &initrd);
@@ -302,21 +544,38 @@ fn run_tests(args: &Args) -> ExitCode {
         .create_disk_image(&uefi_path)
         .unwrap();
 
+    if let Some(ref name) = args.test_name {
+        let test_nums = find_tests_by_name(args, &uefi_path, name);
+
+        if test_nums.is_empty() {
+            eprintln!("No tests matched {name:?}");
+            return ExitCode::FAILURE;
+        }
+
+        return run_qemu_tests(test_nums, args, &uefi_path);
+    }
+
     let test_nums = args.test.clone().unwrap();
     if !test_nums.is_empty() {
         return run_qemu_tests(test_nums, args, &uefi_path);
     }
 
     // Run the kernel in qemu to ask it how many tests there are
-    let (mut qemu_command, mut stdin, chars) =
-        prepare_qemu_test(args, uefi_path.to_str().unwrap()).unwrap();
+    let deadline = test_deadline(args);
+    let (mut qemu_command, mut stdin, mut chars) =
+        prepare_qemu_test(args, uefi_path.to_str().unwrap(), deadline).unwrap();
 
     // Send the 'count' command. The kernel should respond with a number of tests
     stdin
         .write_all(b"count\n")
         .expect("Failed to write to stdin");
 
-    let output = chars.collect::<Vec<u8>>();
+    let output = chars.rest();
+    assert!(!chars.timed_out(), "Timed out counting tests");
+    if let Some(e) = chars.error() {
+        panic!("Failed to read test count: {e}");
+    }
+
     let num_tests = std::str::from_utf8(&output)
         .unwrap()
         .trim()
@@ -324,45 +583,93 @@ fn run_tests(args: &Args) -> ExitCode {
         .unwrap();
 
     // Check that the test runner exited successfully
-    // TODO: investigate why this isn't the same number as defined in the kernel
-    assert_eq!(qemu_command.wait().unwrap().code().unwrap(), 33);
+    assert_eq!(
+        wait_with_deadline(&mut qemu_command, deadline).unwrap(),
+        Some(QEMU_SUCCESS_EXIT_CODE)
+    );
 
     run_qemu_tests(0..num_tests, args, &uefi_path)
 }
 
+/// Gets the time by which a test started now must finish, from the `--test-timeout` argument
+fn test_deadline(args: &Args) -> Instant {
+    Instant::now() + Duration::from_secs(args.test_timeout)
+}
+
+/// Runs the kernel in qemu to get the list of tests, and returns the indices of the tests whose names contain `name`.
+fn find_tests_by_name(args: &Args, uefi_path: &Path, name: &str) -> Vec<usize> {
+    let deadline = test_deadline(args);
+    let (mut qemu_command, mut stdin, mut chars) =
+        prepare_qemu_test(args, uefi_path.to_str().unwrap(), deadline).unwrap();
+
+    // Send the 'list' command. The kernel should respond with a line of the form `index: name` for each test
+    stdin
+        .write_all(b"list\n")
+        .expect("Failed to write to stdin");
+
+    let output = chars.rest();
+    assert!(!chars.timed_out(), "Timed out listing tests");
+    if let Some(e) = chars.error() {
+        panic!("Failed to read test list: {e}");
+    }
+
+    // Check that the test runner exited successfully
+    assert_eq!(
+        wait_with_deadline(&mut qemu_command, deadline).unwrap(),
+        Some(QEMU_SUCCESS_EXIT_CODE)
+    );
+
+    String::from_utf8_lossy(&output)
+        .lines()
+        .filter_map(|line| {
+            let (index, test_name) = line.split_once(": ")?;
+            let index = index.trim().parse().ok()?;
+
+            test_name.contains(name).then_some(index)
+        })
+        .collect()
+}
+
 fn run_qemu_tests(
     test_nums: impl IntoParallelIterator<Item = usize> + IntoIterator<Item = usize>,
     args: &Args,
     uefi_path: &Path,
 ) -> ExitCode {
-    // How many tests failed
-    // This is atomic rather than just mutable because the following iterator is multi-threaded
-    let failures = AtomicUsize::new(0);
-    let total = AtomicUsize::new(0);
+    // This is behind a mutex because the following iterator is multi-threaded
+    let results = Mutex::new(Vec::new());
 
     // Check each test in parallel
     test_nums
         .into_par_iter()
         .try_for_each(|i| -> Result<(), io::Error> {
-            let success = run_qemu_test(i, args, uefi_path)?;
-            total.fetch_add(1, Ordering::Relaxed);
+            let result = run_qemu_test(i, args, uefi_path)?;
 
-            if !success {
-                failures.fetch_add(1, Ordering::Relaxed);
+            // Human-readable results are printed as soon as each test finishes
+            if args.format == OutputFormat::Human {
+                print_test_result(&result, args)?;
             }
 
+            results.lock().unwrap().push(result);
+
             Ok(())
         })
         .unwrap();
 
-    let failures = failures.load(Ordering::Relaxed);
-    let total = total.load(Ordering::Relaxed);
-
-    println!(
-        "\n{} out of {} tests completed successfully",
-        total - failures,
-        total
-    );
+    let mut results = results.into_inner().unwrap();
+    let total = results.len();
+    let failures = results.iter().filter(|result| !result.passed).count();
+
+    match args.format {
+        OutputFormat::Human => println!(
+            "\n{} out of {} tests completed successfully",
+            total - failures,
+            total
+        ),
+        OutputFormat::Json => {
+            results.sort_by_key(|result| result.index);
+            println!("{}", test_results_to_json(&results));
+        }
+    }
 
     if failures != 0 {
         ExitCode::FAILURE
@@ -371,9 +678,37 @@ fn run_qemu_tests(
     }
 }
 
-fn run_qemu_test(i: usize, args: &Args, uefi_path: &Path) -> Result<bool, io::Error> {
-    let (mut qemu_command, mut stdin, chars) =
-        prepare_qemu_test(args, uefi_path.to_str().unwrap())?;
+/// The result of running a single test
+#[derive(Debug)]
+struct TestResult {
+    /// The index of the test
+    index: usize,
+    /// The name of the test, or `None` if the kernel timed out before printing it
+    name: Option<String>,
+    /// Whether the test passed
+    passed: bool,
+    /// Whether the test was killed for running for longer than `--test-timeout`
+    timed_out: bool,
+    /// Everything the kernel wrote to serial output after being sent the `run` command
+    serial_output: Vec<u8>,
+}
+
+fn run_qemu_test(i: usize, args: &Args, uefi_path: &Path) -> Result<TestResult, io::Error> {
+    let deadline = test_deadline(args);
+    let (mut qemu_command, mut stdin, mut chars) =
+        match prepare_qemu_test(args, uefi_path.to_str().unwrap(), deadline) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return Ok(TestResult {
+                    index: i,
+                    name: None,
+                    passed: false,
+                    timed_out: true,
+                    serial_output: Vec::new(),
+                });
+            }
+            Err(e) => return Err(e),
+        };
 
     // Send a 'run' command with the command number
     stdin
@@ -381,51 +716,139 @@ fn run_qemu_test(i: usize, args: &Args, uefi_path: &Path) -> Result<bool, io::Er
         .expect("Failed to write to stdin");
 
     // Get the output of the rest of the kernel's execution so that it can be printed in case the test fails
-    let output = chars.rest();
+    let mut output = chars.rest();
+    // A read error only fails this test, so that the rest of the tests can still run
+    let read_error = chars.error().map(ToString::to_string);
+    if let Some(e) = &read_error {
+        output.extend_from_slice(format!("\n<failed to read serial output: {e}>\n").as_bytes());
+    }
 
     // Extract the test name from the output
     let test_name: Vec<u8> = output.split(|c| *c == b'\n').next().unwrap().to_vec();
     let test_name = std::str::from_utf8(&test_name).unwrap().trim_end();
 
+    // If the output stopped because of the deadline, this kills qemu straight away
+    let exit_code = wait_with_deadline(&mut qemu_command, deadline)?;
+
     // Check that the test runner exited successfully
-    // TODO: investigate why this isn't the same number as defined in the kernel
-    if qemu_command.wait().unwrap().code().unwrap() == 33 {
+    Ok(TestResult {
+        index: i,
+        name: Some(test_name.to_string()),
+        passed: exit_code == Some(QEMU_SUCCESS_EXIT_CODE) && read_error.is_none(),
+        timed_out: exit_code.is_none(),
+        serial_output: output,
+    })
+}
+
+/// Prints the result of a test in the human-readable format
+fn print_test_result(result: &TestResult, args: &Args) -> Result<(), io::Error> {
+    let i = result.index;
+    let test_name = result.name.as_deref().unwrap_or("test");
+
+    // Lock stdout to prevent another test's output from being in the middle of this multi-line print
+    let mut stdout = std::io::stdout().lock();
+
+    if result.passed {
         // TODO: change these ANSI codes to something more portable
-        println!("[{i:3}] Running {test_name}... [\x1b[32mOK\x1b[0m]");
+        writeln!(stdout, "[{i:3}] Running {test_name}... [\x1b[32mOK\x1b[0m]")?;
 
-        Ok(true)
-    } else {
-        // Lock stdout to prevent another test's output from being in the middle of this multi-line print
-        let mut stdout = std::io::stdout().lock();
+        return Ok(());
+    }
+
+    if result.name.is_none() {
+        writeln!(
+            stdout,
+            "[{i:3}] Running {test_name}... [\x1b[31mTIMED OUT\x1b[0m] (kernel never became ready)"
+        )?;
+
+        return Ok(());
+    }
 
-        // If the test fails, print its output in yellow to be more obvious
+    // If the test fails, print its output in yellow to be more obvious
+    if result.timed_out {
+        writeln!(
+            stdout,
+            "[{i:3}] Running {test_name}... [\x1b[31mTIMED OUT\x1b[0m] after {} seconds",
+            args.test_timeout
+        )?;
+    } else {
         writeln!(
             stdout,
             "[{i:3}] Running {test_name}... [\x1b[31mERROR\x1b[0m]"
         )?;
-        writeln!(stdout, "\x1b[31mSerial output of failed test:\x1b[0m")?;
-        writeln!(stdout, "\x1b[33m-----------------------------------")?;
-        writeln!(stdout, "{}", String::from_utf8_lossy(&output))?;
-        writeln!(stdout, "-----------------------------------\x1b[0m")?;
-
-        if args.release {
-            if let Some(runner) = std::env::current_exe().unwrap().to_str() {
-                writeln!(
-                    stdout,
-                    "\x1b[31mRun in debug mode for a stack backtrace: `{runner} --test {i}`\x1b[0m"
-                )?;
-            }
+    }
+    writeln!(stdout, "\x1b[31mSerial output of failed test:\x1b[0m")?;
+    writeln!(stdout, "\x1b[33m-----------------------------------")?;
+    writeln!(stdout, "{}", String::from_utf8_lossy(&result.serial_output))?;
+    writeln!(stdout, "-----------------------------------\x1b[0m")?;
+
+    if args.release {
+        if let Some(runner) = std::env::current_exe().unwrap().to_str() {
+            writeln!(
+                stdout,
+                "\x1b[31mRun in debug mode for a backtrace with file and line numbers: `{runner} --test {i}`\x1b[0m"
+            )?;
         }
+    }
 
-        Ok(false)
+    Ok(())
+}
+
+/// Formats test results as a JSON array of objects with `index`, `name`, `passed`, and `serial_output` fields.
+/// `name` is `null` if the kernel timed out before the test started.
+fn test_results_to_json(results: &[TestResult]) -> String {
+    let objects: Vec<String> = results
+        .iter()
+        .map(|result| {
+            let name = match result.name {
+                Some(ref name) => json_string(name),
+                None => "null".to_string(),
+            };
+
+            format!(
+                "{{\"index\":{},\"name\":{name},\"passed\":{},\"serial_output\":{}}}",
+                result.index,
+                result.passed,
+                json_string(&String::from_utf8_lossy(&result.serial_output)),
+            )
+        })
+        .collect();
+
+    format!("[{}]", objects.join(","))
+}
+
+/// Formats a string as a quoted JSON string literal, escaping any characters which need it
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
     }
+
+    out.push('"');
+    out
 }
 
 /// Launches the kernel in qemu from the image at the given path and waits for it to write a message to stdout
 /// indicating it's listening for a test command.
+///
+/// Output will not be read after the given deadline. If the kernel doesn't become ready before then,
+/// qemu is killed and an error of kind [`TimedOut`] is returned.
+///
+/// [`TimedOut`]: io::ErrorKind::TimedOut
 fn prepare_qemu_test(
     args: &Args,
     uefi_path: &str,
+    deadline: Instant,
 ) -> Result<
     (
         std::process::Child,
@@ -443,13 +866,32 @@ fn prepare_qemu_test(
     let stdout = qemu_command.stdout.take().expect("Failed to open stdout");
     let stdin = qemu_command.stdin.take().expect("Failed to open stdin");
 
-    let mut chars = ChildStdoutIter::new(stdout);
+    let mut chars = ChildStdoutIter::new(stdout, deadline);
 
     // Wait for the kernel to print the ready message
     'outer: loop {
         for c in b">>>>>> READY FOR TEST COMMAND\n" {
-            if *c != chars.next().unwrap() {
-                continue 'outer;
+            match chars.next() {
+                Some(next) if next == *c => (),
+                Some(_) => continue 'outer,
+                None if chars.timed_out() => {
+                    qemu_command.kill()?;
+                    qemu_command.wait()?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Kernel did not become ready for a test command in time",
+                    ));
+                }
+                None => {
+                    if let Some(e) = chars.error.take() {
+                        return Err(e);
+                    }
+
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Kernel exited before becoming ready for a test command",
+                    ));
+                }
             }
         }
 
@@ -460,57 +902,131 @@ fn prepare_qemu_test(
 }
 
 /// A wrapper around a child process, which exposes an iterator over the process's stdout.
+///
+/// The output is read on a separate thread, so that reading can give up once a deadline has passed
+/// even if the process never writes anything.
 #[derive(Debug)]
 struct ChildStdoutIter {
-    /// The process whose output is being iterated
-    process: ChildStdout,
-    /// The buffered output
-    buffer: [u8; 256],
+    /// Receives chunks of the process's output from the thread reading it, or the error which stopped it reading
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    /// The most recently received chunk of output
+    buffer: Vec<u8>,
     /// The current position in the buffer
     i: usize,
-    /// The length of data in the buffer
-    n: usize,
+    /// The time after which no more output will be read
+    deadline: Instant,
+    /// Whether reading stopped because the deadline passed
+    timed_out: bool,
+    /// The error which stopped the output from being read, if any
+    error: Option<io::Error>,
 }
 
 impl Iterator for ChildStdoutIter {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.i < self.n {
-            let v = self.buffer[self.i];
-            self.i += 1;
-            Some(v)
-        } else {
-            self.i = 1;
-            self.n = self.process.read(&mut self.buffer).unwrap();
-            if self.n == 0 {
-                None
-            } else {
-                Some(self.buffer[0])
+        while self.i >= self.buffer.len() {
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+
+            match self.receiver.recv_timeout(timeout) {
+                Ok(Ok(chunk)) => {
+                    self.buffer = chunk;
+                    self.i = 0;
+                }
+                Ok(Err(e)) => {
+                    self.error = Some(e);
+                    return None;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.timed_out = true;
+                    return None;
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
             }
         }
+
+        let v = self.buffer[self.i];
+        self.i += 1;
+        Some(v)
     }
 }
 
 impl ChildStdoutIter {
-    /// Gets the rest of the process's output (that is, anything that's not been consumed by [`next`])
+    /// Gets the rest of the process's output (that is, anything that's not been consumed by [`next`]),
+    /// up until the process exits, the deadline passes, or reading fails. Check [`error`] for whether reading failed.
+    ///
+    /// [`error`]: ChildStdoutIter::error
     ///
     /// [`next`]: ChildStdoutIter::next
-    fn rest(mut self) -> Vec<u8> {
-        let mut v = self.buffer[self.i..self.n].to_vec();
+    fn rest(&mut self) -> Vec<u8> {
+        self.by_ref().collect()
+    }
 
-        self.process.read_to_end(&mut v).unwrap();
+    /// Whether reading output stopped because the deadline passed
+    fn timed_out(&self) -> bool {
+        self.timed_out
+    }
 
-        v
+    /// The error which stopped the output from being read, if reading stopped because of an error.
+    /// [`next`] and [`rest`] return what had been read before the error, so this should be checked after they stop.
+    ///
+    /// [`next`]: ChildStdoutIter::next
+    /// [`rest`]: ChildStdoutIter::rest
+    fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
     }
 
-    /// Constructs a new [`ChildStdoutIter`]
-    fn new(process: ChildStdout) -> Self {
+    /// Constructs a new [`ChildStdoutIter`], which will stop reading output at the given deadline
+    fn new(mut process: ChildStdout, deadline: Instant) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        // The thread exits when the process's stdout is closed, or when the iterator is dropped
+        std::thread::spawn(move || {
+            let mut buffer = [0; 256];
+
+            loop {
+                match process.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if sender.send(Ok(buffer[..n].to_vec())).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => {
+                        // If the iterator has been dropped, there's nothing to report the error to
+                        let _ = sender.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+
         Self {
-            process,
-            buffer: [0; 256],
+            receiver,
+            buffer: Vec::new(),
             i: 0,
-            n: 0,
+            deadline,
+            timed_out: false,
+            error: None,
         }
     }
 }
+
+/// Waits for a child process to exit, killing it if it hasn't exited by the given deadline.
+/// Returns the process's exit code, or [`None`] if it was killed.
+fn wait_with_deadline(process: &mut Child, deadline: Instant) -> Result<Option<i32>, io::Error> {
+    loop {
+        if let Some(status) = process.try_wait()? {
+            return Ok(status.code());
+        }
+
+        if Instant::now() >= deadline {
+            process.kill()?;
+            process.wait()?;
+            return Ok(None);
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,200 @@
+//! A driver for the _High Precision Event Timer_, which is used as a monotonic nanosecond clock.
+//!
+//! The HPET is found using the ACPI `HPET` table, and its registers are described in the [HPET specification].
+//! Only the main counter is used - the HPET's comparators are left disabled.
+//!
+//! [HPET specification]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use conquer_once::spin::OnceCell;
+use log::{info, warn};
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use crate::global_state::KERNEL_STATE;
+
+use super::{find_table, read_physical_array};
+
+/// The offset of the _General Capabilities and ID_ register
+const CAPABILITIES_REGISTER: u64 = 0x000;
+/// The offset of the _General Configuration_ register
+const CONFIGURATION_REGISTER: u64 = 0x010;
+/// The offset of the _Main Counter Value_ register
+const MAIN_COUNTER_REGISTER: u64 = 0x0F0;
+
+/// The bit of the capabilities register which is set if the main counter is 64 bits wide
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+/// The bit of the configuration register which starts the main counter
+const ENABLE_CNF: u64 = 1;
+
+/// The largest valid counter period, in femtoseconds. The spec requires the counter to run at at least 10MHz.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// The number of femtoseconds in a nanosecond
+const FS_PER_NS: u128 = 1_000_000;
+
+/// The system's HPET, once [`init`] has found it
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// A High Precision Event Timer
+#[derive(Debug)]
+struct Hpet {
+    /// The virtual address where the HPET's registers are mapped
+    registers: VirtAddr,
+    /// The number of femtoseconds per increment of the main counter
+    period_fs: u64,
+    /// Whether the main counter is 64 bits wide. If not, it wraps around every 2^32 increments.
+    is_64_bit: bool,
+    /// The last value read from the main counter, extended to 64 bits if the counter is only 32 bits wide
+    last_count: AtomicU64,
+}
+
+impl Hpet {
+    /// Reads the register at the given offset
+    fn read_reg(&self, offset: u64) -> u64 {
+        // SAFETY: `registers` is the mapping of the HPET's registers, which are 64-bit aligned
+        unsafe { (self.registers + offset).as_ptr::<u64>().read_volatile() }
+    }
+
+    /// Writes the register at the given offset
+    ///
+    /// # Safety
+    /// The write must not put the HPET in an invalid state
+    unsafe fn write_reg(&self, offset: u64, value: u64) {
+        // SAFETY: `registers` is the mapping of the HPET's registers, which are 64-bit aligned.
+        // The caller guarantees that the write is sound.
+        unsafe {
+            (self.registers + offset)
+                .as_mut_ptr::<u64>()
+                .write_volatile(value);
+        }
+    }
+
+    /// Reads the main counter. If the counter is only 32 bits wide, it is extended to 64 bits using the last value read,
+    /// so the result is monotonic as long as the counter is read at least once per wrap-around (about 5 minutes at 14MHz).
+    fn count(&self) -> u64 {
+        let raw = self.read_reg(MAIN_COUNTER_REGISTER);
+
+        if self.is_64_bit {
+            return raw;
+        }
+
+        let raw = raw & 0xFFFF_FFFF;
+        let mut count = 0;
+
+        // The closure always returns `Some`, so this can't fail
+        let _ = self
+            .last_count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                let mut extended = (last & !0xFFFF_FFFF) | raw;
+                if extended < last {
+                    extended += 1 << 32;
+                }
+
+                count = extended;
+                Some(extended)
+            });
+
+        count
+    }
+}
+
+/// Finds the HPET using the ACPI `HPET` table, maps its registers, and starts its main counter.
+/// If the system has no HPET, [`nanoseconds`] will return [`None`].
+///
+/// # Safety
+/// * This function may only be called once
+/// * `rsdp_addr` must be the physical address of the RSDP
+pub unsafe fn init(rsdp_addr: u64) {
+    // SAFETY: The caller guarantees that `rsdp_addr` is valid
+    let Some(table) = (unsafe { find_table(rsdp_addr, *b"HPET") }) else {
+        warn!("No HPET table found, falling back to the timer interrupt for timing");
+        return;
+    };
+
+    // The `Base Address` field is a Generic Address Structure at offset 40,
+    // whose first byte is the address space (0 for memory) and whose address is at offset 4
+    // SAFETY: The table was found through the RSDT, so it is valid to read
+    let base_address: [u8; 12] = unsafe { read_physical_array(table + 40) };
+    let address_space = base_address[0];
+    let address = u64::from_le_bytes(base_address[4..].try_into().unwrap());
+
+    if address_space != 0 {
+        warn!("HPET registers are not memory mapped (address space {address_space})");
+        return;
+    }
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(address));
+
+    // SAFETY: The HPET's registers are MMIO, so mapping them doesn't alias any memory.
+    // This function is only called once, so they aren't mapped anywhere else.
+    let pages = unsafe {
+        KERNEL_STATE
+            .physical_memory_accessor
+            .lock()
+            .map_frames(PhysFrameRange {
+                start: frame,
+                end: frame + 1,
+            })
+    };
+
+    let mut hpet = Hpet {
+        registers: pages.start.start_address() + (address % 4096),
+        period_fs: 0,
+        is_64_bit: false,
+        last_count: AtomicU64::new(0),
+    };
+
+    let capabilities = hpet.read_reg(CAPABILITIES_REGISTER);
+    hpet.period_fs = capabilities >> 32;
+    hpet.is_64_bit = capabilities & COUNT_SIZE_CAP != 0;
+
+    if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+        warn!("HPET has invalid counter period {}fs", hpet.period_fs);
+        return;
+    }
+
+    // SAFETY: Enabling the main counter doesn't cause any interrupts, as the comparators are disabled at reset
+    unsafe {
+        let configuration = hpet.read_reg(CONFIGURATION_REGISTER);
+        hpet.write_reg(CONFIGURATION_REGISTER, configuration | ENABLE_CNF);
+    }
+
+    info!(
+        "Found HPET at {address:#x} running at {}kHz",
+        1_000_000_000_000 / hpet.period_fs
+    );
+
+    HPET.init_once(|| hpet);
+}
+
+/// Gets the number of nanoseconds since the HPET was started, or [`None`] if the system has no HPET.
+/// The result never decreases.
+pub fn nanoseconds() -> Option<u64> {
+    let hpet = HPET.try_get().ok()?;
+    let ns = u128::from(hpet.count()) * u128::from(hpet.period_fs) / FS_PER_NS;
+
+    Some(ns.try_into().unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nanoseconds;
+
+    #[test_case]
+    fn test_hpet_monotonic() {
+        let start = nanoseconds().expect("QEMU should provide a HPET");
+        let mut last = start;
+
+        for _ in 0..10_000 {
+            let now = nanoseconds().unwrap();
+            assert!(now >= last, "HPET went backwards from {last} to {now}");
+            last = now;
+        }
+
+        assert!(last > start, "HPET didn't advance");
+    }
+}
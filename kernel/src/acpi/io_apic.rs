@@ -183,6 +183,9 @@ impl IoApicRegisters {
     }
 }
 
+// SAFETY: The registers are only accessed through `&mut self` methods, so moving them to another core is sound.
+unsafe impl Send for IoApicRegisters {}
+
 impl Drop for IoApicRegisters {
     fn drop(&mut self) {
         let start = Page::containing_address(VirtAddr::from_ptr(self.0));
@@ -208,6 +211,8 @@ impl IoApicRegisters {
     const ADDRESS_REGISTER_OFFSET: usize = 0x00;
     /// The offset into the I/O APIC physical registers of the data register
     const DATA_REGISTER_OFFSET: usize = 0x10;
+    /// The offset into the I/O APIC physical registers of the EOI register
+    const EOI_REGISTER_OFFSET: usize = 0x40;
 
     /// Reads from a register. This method requires an `&mut self` parameter because
     /// reading a logical register requires writing to the physical address register, which is not thread safe.
@@ -287,23 +292,52 @@ impl IoApicRegisters {
         Ok(())
     }
 
-    /// Sets the interrupt for the primary port of an
-    /// [8042 PS/2 controller] (IRQ 1) to go to interrupt number `vector`.
+    /// Sets the global system interrupt `gsi` to be sent to the core with the given local APIC ID on interrupt number `vector`,
+    /// with the given trigger mode and polarity.
+    ///
+    /// Legacy PCI interrupts (`INTx`) are level-triggered and active-low, as are some interrupts described by ACPI.
+    /// The interrupt handler for a level-triggered interrupt must send an EOI to this I/O APIC as well as the local APIC
+    /// (see [`end_of_interrupt`]), or the interrupt will not be sent again.
     ///
     /// # Safety
     /// The `local_apic_id` must refer to a local APIC, and its associated core must be
     /// set up to receive interrupts from this source.
     ///
-    /// [8042 PS/2 controller]: crate::cpu::ps2::Ps2Controller8042
-    pub unsafe fn set_ps2_primary_port_interrupt(
+    /// [`end_of_interrupt`]: IoApicRegisters::end_of_interrupt
+    pub unsafe fn set_redirection(
         &mut self,
-        local_apic_id: u8,
+        gsi: u8,
         vector: u8,
+        trigger_mode: InterruptTriggerMode,
+        active_state: InterruptActiveState,
+        local_apic_id: u8,
     ) -> Result<(), ()> {
         let entry = RedirectionEntry::new()
             .with_vector(vector)
             .with_delivery_mode(InterruptDeliveryMode::Fixed)
             .with_destination_mode(InterruptDestinationMode::Physical)
+            .with_active_state(active_state)
+            .with_trigger_mode(trigger_mode)
+            .with_masked(false)
+            .with_destination(local_apic_id);
+
+        // SAFETY: The entry is valid as it was just constructed.
+        // The core being ready is the caller's responsibility.
+        unsafe { self.write_redirection_entry(gsi, entry) }
+    }
+
+    /// Sets the global system interrupt `gsi` to be sent to the core with the given local APIC ID
+    /// as a _non-maskable interrupt_, which is received even if the core has interrupts disabled.
+    ///
+    /// # Safety
+    /// The `local_apic_id` must refer to a local APIC, and its associated core must be
+    /// set up to receive NMIs from this source.
+    pub unsafe fn set_nmi(&mut self, gsi: u8, local_apic_id: u8) -> Result<(), ()> {
+        // The vector is ignored for NMIs, and NMIs are always edge-triggered
+        let entry = RedirectionEntry::new()
+            .with_vector(0)
+            .with_delivery_mode(InterruptDeliveryMode::Nmi)
+            .with_destination_mode(InterruptDestinationMode::Physical)
             .with_active_state(InterruptActiveState::ActiveHigh)
             .with_trigger_mode(InterruptTriggerMode::EdgeTriggered)
             .with_masked(false)
@@ -311,7 +345,55 @@ impl IoApicRegisters {
 
         // SAFETY: The entry is valid as it was just constructed.
         // The core being ready is the caller's responsibility.
-        unsafe { self.write_redirection_entry(1, entry) }
+        unsafe { self.write_redirection_entry(gsi, entry) }
+    }
+
+    /// Tells the I/O APIC that a level-triggered interrupt on `vector` has been handled,
+    /// so that it will send the interrupt again if the signal is still active.
+    ///
+    /// The local APIC also broadcasts its EOIs to the I/O APIC, but the broadcast can be suppressed,
+    /// so this is written explicitly when the I/O APIC has an EOI register (version `0x20` and later).
+    ///
+    /// # Safety
+    /// This method may only be called from the interrupt handler for a level-triggered interrupt which this I/O APIC sent.
+    pub unsafe fn end_of_interrupt(&mut self, vector: u8) {
+        if self.get_version().version() < 0x20 {
+            return;
+        }
+
+        // SAFETY: This is the EOI register of the I/O APIC.
+        // The caller guarantees that an interrupt on this vector is being handled.
+        unsafe {
+            core::ptr::write_volatile(
+                self.0.byte_add(Self::EOI_REGISTER_OFFSET),
+                u32::from(vector),
+            );
+        }
+    }
+
+    /// Sets the interrupt for the primary port of an
+    /// [8042 PS/2 controller] (IRQ 1) to go to interrupt number `vector`.
+    ///
+    /// # Safety
+    /// The `local_apic_id` must refer to a local APIC, and its associated core must be
+    /// set up to receive interrupts from this source.
+    ///
+    /// [8042 PS/2 controller]: crate::cpu::ps2::Ps2Controller8042
+    pub unsafe fn set_ps2_primary_port_interrupt(
+        &mut self,
+        local_apic_id: u8,
+        vector: u8,
+    ) -> Result<(), ()> {
+        // SAFETY: The core being ready is the caller's responsibility.
+        unsafe {
+            self.set_redirection(
+                1,
+                vector,
+                InterruptTriggerMode::EdgeTriggered,
+                InterruptActiveState::ActiveHigh,
+                local_apic_id,
+            )
+        }
     }
 
     /// Sets the interrupt for the secondary port of an
@@ -327,17 +409,55 @@ impl IoApicRegisters {
         local_apic_id: u8,
         vector: u8,
     ) -> Result<(), ()> {
-        let entry = RedirectionEntry::new()
-            .with_vector(vector)
-            .with_delivery_mode(InterruptDeliveryMode::Fixed)
-            .with_destination_mode(InterruptDestinationMode::Physical)
-            .with_active_state(InterruptActiveState::ActiveHigh)
-            .with_trigger_mode(InterruptTriggerMode::EdgeTriggered)
-            .with_masked(false)
-            .with_destination(local_apic_id);
+        // SAFETY: The core being ready is the caller's responsibility.
+        unsafe {
+            self.set_redirection(
+                12,
+                vector,
+                InterruptTriggerMode::EdgeTriggered,
+                InterruptActiveState::ActiveHigh,
+                local_apic_id,
+            )
+        }
+    }
 
-        // SAFETY: The entry is valid as it was just constructed.
-        // The core being ready is the caller's responsibility.
-        unsafe { self.write_redirection_entry(12, entry) }
+    /// Sets the ACPI _System Control Interrupt_ on the global system interrupt `gsi` to go to interrupt number `vector`.
+    ///
+    /// ACPI specifies that the SCI is level-triggered and active-low unless the MADT overrides this,
+    /// so the caller should find the trigger mode and polarity using [`isa_irq_route`].
+    ///
+    /// [`isa_irq_route`]: crate::cpu::interrupt_controllers::isa_irq_route
+    ///
+    /// # Safety
+    /// The `local_apic_id` must refer to a local APIC, and its associated core must be
+    /// set up to receive interrupts from this source.
+    pub unsafe fn set_acpi_sci_interrupt(
+        &mut self,
+        local_apic_id: u8,
+        gsi: u8,
+        vector: u8,
+        trigger_mode: InterruptTriggerMode,
+        active_state: InterruptActiveState,
+    ) -> Result<(), ()> {
+        // SAFETY: The core being ready is the caller's responsibility.
+        unsafe { self.set_redirection(gsi, vector, trigger_mode, active_state, local_apic_id) }
+    }
+
+    /// Sets the interrupt for the COM1 serial port (IRQ 4) to go to interrupt number `vector`.
+    ///
+    /// # Safety
+    /// The `local_apic_id` must refer to a local APIC, and its associated core must be
+    /// set up to receive interrupts from this source.
+    pub unsafe fn set_serial_interrupt(&mut self, local_apic_id: u8, vector: u8) -> Result<(), ()> {
+        // SAFETY: The core being ready is the caller's responsibility.
+        unsafe {
+            self.set_redirection(
+                4,
+                vector,
+                InterruptTriggerMode::EdgeTriggered,
+                InterruptActiveState::ActiveHigh,
+                local_apic_id,
+            )
+        }
     }
 }
@@ -4,18 +4,36 @@
 mod ipi;
 mod lvt;
 
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
 use x86_64::{
     structures::paging::{frame::PhysFrameRange, page::PageRange, Page, PhysFrame},
     PhysAddr, VirtAddr,
 };
 
-use crate::{acpi::local_apic::lvt::LvtRegisters, global_state::KERNEL_STATE, println};
+use crate::{
+    acpi::{hpet, local_apic::lvt::LvtRegisters},
+    cpu::pit,
+    global_state::KERNEL_STATE,
+    println,
+};
 
 use self::{
     ipi::{DeliveryMode, DestinationMode, DestinationShorthand, InterruptCommandRegister},
     lvt::TimerMode,
 };
 
+/// The number of times per second the local APIC timer counts down when using [`TIMER_DIVISOR`].
+/// This starts as a value measured in qemu, and is replaced by [`calibrate_timer`].
+/// All cores' timers run off the same bus clock, so this is shared between them.
+///
+/// [`TIMER_DIVISOR`]: LocalApicRegisters::TIMER_DIVISOR
+/// [`calibrate_timer`]: LocalApicRegisters::calibrate_timer
+static TIMER_COUNTS_PER_SECOND: AtomicU32 = AtomicU32::new(2_500_000);
+
 #[bitfield(u32)]
 struct TaskPriorityRegister {
     #[bits(4)]
@@ -114,6 +132,8 @@ impl LocalApicRegisters {
     const LVT_CORRECTED_MACHINE_CHECK_INTERRUPT_CMCI_OFFSET: usize = 0x2F0;
     /// The offset of the interrupt_command field
     const INTERRUPT_COMMAND_OFFSET: usize = 0x300;
+    /// The offset of the high half of the interrupt_command field, which holds the destination APIC ID in its top byte
+    const INTERRUPT_COMMAND_DESTINATION_OFFSET: usize = 0x310;
     /// The offset of the lvt_timer field
     const LVT_TIMER_OFFSET: usize = 0x320;
     /// The offset of the lvt_thermal_sensor field
@@ -213,14 +233,94 @@ impl LocalApicRegisters {
         }
     }
 
+    /// The divisor used for the local APIC timer
+    const TIMER_DIVISOR: u8 = 128;
+
+    /// How long the timer is left counting down for by [`calibrate_timer`][Self::calibrate_timer]
+    const CALIBRATION_NS: u64 = 10_000_000;
+
+    /// Calculates the value of the `initial_count` register needed for the timer to fire `hz` times per second.
+    /// Returns [`None`] if `hz` is 0 or too high for the timer to represent.
+    pub fn timer_initial_count(hz: usize) -> Option<u32> {
+        let hz: u32 = hz.try_into().ok()?;
+        let count = TIMER_COUNTS_PER_SECOND
+            .load(Ordering::Relaxed)
+            .checked_div(hz)?;
+
+        if count == 0 {
+            None
+        } else {
+            Some(count)
+        }
+    }
+
+    /// Measures how fast the local APIC timer counts down, against the HPET if the system has one
+    /// and against channel 2 of the PIT otherwise.
+    /// The result is used by [`timer_initial_count`][Self::timer_initial_count] from then on.
+    ///
+    /// # Safety
+    /// The timer must not be in use, and nothing else may be using channel 2 of the PIT.
+    /// Interrupts should be disabled, or the measurement may be inaccurate.
+    pub unsafe fn calibrate_timer(&mut self) {
+        // SAFETY: The timer is masked, so counting down won't send any interrupts.
+        unsafe {
+            self.write_reg(
+                Self::LVT_TIMER_OFFSET,
+                LvtRegisters::new()
+                    .with_masked(true)
+                    .with_timer_mode(TimerMode::OneShot)
+                    .into(),
+            );
+            self.write_reg(
+                Self::DIVIDE_CONFIGURATION_OFFSET,
+                Self::create_divide_value(Self::TIMER_DIVISOR),
+            );
+        }
+
+        let start = hpet::nanoseconds();
+
+        // SAFETY: The timer is masked, so this just starts the count.
+        unsafe { self.write_reg(Self::INITIAL_COUNT_OFFSET, u32::MAX) };
+
+        let elapsed_ns = if let Some(start) = start {
+            loop {
+                let elapsed = hpet::nanoseconds().unwrap() - start;
+                if elapsed >= Self::CALIBRATION_NS {
+                    break elapsed;
+                }
+                spin_loop();
+            }
+        } else {
+            // SAFETY: The caller guarantees that nothing else is using channel 2 of the PIT
+            unsafe { pit::wait_channel_2(Self::CALIBRATION_NS) }
+        };
+
+        let counted = u32::MAX - self.current_count();
+
+        // SAFETY: Writing 0 stops the timer.
+        unsafe { self.write_reg(Self::INITIAL_COUNT_OFFSET, 0) };
+
+        let counts_per_second = u64::from(counted) * 1_000_000_000 / elapsed_ns;
+        match u32::try_from(counts_per_second) {
+            Ok(counts_per_second) if counts_per_second != 0 => {
+                TIMER_COUNTS_PER_SECOND.store(counts_per_second, Ordering::Relaxed);
+                log::debug!("Local APIC timer counts {counts_per_second} times per second");
+            }
+            _ => log::warn!(
+                "Measured an invalid local APIC timer speed of {counts_per_second} counts per second"
+            ),
+        }
+    }
+
     /// Enables the local interrupt timer.
-    /// The interrupts will target the given interrupt vector.
+    /// The interrupts will target the given interrupt vector, and will occur `hz` times per second.
     ///
     /// # Safety
     /// The CPU must be set up to receive timer interrupts at the given vector.
     ///
-    /// TODO: allow specifying frequency
-    pub unsafe fn enable_timer(&mut self, vector: u8) {
+    /// # Panics
+    /// If `hz` is not a valid frequency (see [`timer_initial_count`][Self::timer_initial_count])
+    pub unsafe fn enable_timer(&mut self, vector: u8, hz: usize) {
         // Set up the timer interrupt to target the given vector
         // and occur periodically rather than just once.
 
@@ -242,16 +342,29 @@ impl LocalApicRegisters {
         unsafe {
             self.write_reg(
                 Self::DIVIDE_CONFIGURATION_OFFSET,
-                Self::create_divide_value(128),
+                Self::create_divide_value(Self::TIMER_DIVISOR),
             );
         }
 
         // SAFETY: This will start the timer.
         // It is the caller's responsibility that the interrupts are received properly.
+        unsafe { self.set_timer_frequency(hz) }
+    }
+
+    /// Changes the frequency of the local interrupt timer to `hz` interrupts per second.
+    /// Writing the `initial_count` register restarts the timer's count.
+    ///
+    /// # Safety
+    /// The timer must have been set up with [`enable_timer`][Self::enable_timer].
+    ///
+    /// # Panics
+    /// If `hz` is not a valid frequency (see [`timer_initial_count`][Self::timer_initial_count])
+    pub unsafe fn set_timer_frequency(&mut self, hz: usize) {
+        let initial_count = Self::timer_initial_count(hz).expect("Timer frequency should be valid");
+
+        // SAFETY: The timer is already set up to send interrupts to a valid vector
         unsafe {
-            // Number chosen to get about 100 interrupts per second in qemu
-            // TODO: calculate this number properly
-            self.write_reg(Self::INITIAL_COUNT_OFFSET, 25000);
+            self.write_reg(Self::INITIAL_COUNT_OFFSET, initial_count);
         }
     }
 
@@ -300,6 +413,64 @@ impl LocalApicRegisters {
         move || unsafe { core::ptr::write_volatile(ptr, value.into()) }
     }
 
+    /// Sends an IPI to the core with the given local APIC ID, and waits for it to be accepted.
+    ///
+    /// # Safety
+    /// The caller is responsible for the behaviour of the destination core in response to the IPI
+    unsafe fn send_ipi(&mut self, destination: u8, command: InterruptCommandRegister) {
+        let command = command
+            .with_destination_mode(DestinationMode::Physical)
+            .with_destination_shorthand(DestinationShorthand::NoShorthand);
+
+        // SAFETY: Writing the destination has no side effects. Writing the low half sends the IPI,
+        // which the caller is responsible for.
+        unsafe {
+            self.write_reg(
+                Self::INTERRUPT_COMMAND_DESTINATION_OFFSET,
+                u32::from(destination) << 24,
+            );
+            self.write_reg(Self::INTERRUPT_COMMAND_OFFSET, command.into());
+        }
+
+        while InterruptCommandRegister::from(self.interrupt_command()).send_pending() {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Sends an INIT IPI to the core with the given local APIC ID, which resets it and leaves it waiting for a
+    /// startup IPI (see [`send_startup_ipi`]).
+    ///
+    /// # Safety
+    /// The destination core must not be this core, and must not be running any code which the kernel relies on.
+    ///
+    /// [`send_startup_ipi`]: LocalApicRegisters::send_startup_ipi
+    pub unsafe fn send_init_ipi(&mut self, destination: u8) {
+        // SAFETY: The caller guarantees that the core can be reset
+        unsafe {
+            self.send_ipi(
+                destination,
+                InterruptCommandRegister::new().with_delivery_mode(DeliveryMode::Init),
+            );
+        }
+    }
+
+    /// Sends a startup IPI to the core with the given local APIC ID, which makes a core that has just received an
+    /// INIT IPI start executing in real mode at the start of physical page `page` (i.e. at address `page << 12`).
+    ///
+    /// # Safety
+    /// The destination core must have just been sent an INIT IPI, and there must be valid real mode code at the start of `page`.
+    pub unsafe fn send_startup_ipi(&mut self, destination: u8, page: u8) {
+        // SAFETY: The caller guarantees that there is code for the core to execute
+        unsafe {
+            self.send_ipi(
+                destination,
+                InterruptCommandRegister::new()
+                    .with_vector_number(page)
+                    .with_delivery_mode(DeliveryMode::StartUp),
+            );
+        }
+    }
+
     /// Prints out the APIC's registers
     #[rustfmt::skip]
     pub fn debug_re(&self) {
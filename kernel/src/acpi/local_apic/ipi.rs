@@ -117,8 +117,8 @@ pub struct InterruptCommandRegister {
     #[bits(1)]
     pub destination_mode: DestinationMode,
 
-    /// Whether the interrupt has been delivered yet (read only)
-    pub delivered: bool,
+    /// Whether the interrupt is still waiting to be accepted by its destination (read only)
+    pub send_pending: bool,
 
     #[bits(1)]
     _reserved: (),
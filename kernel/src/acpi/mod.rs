@@ -1,30 +1,56 @@
 //! Code for interacting with the [`acpica_bindings`] crate for ACPI management
 
+pub mod hpet;
 pub mod io_apic;
 pub mod local_apic;
+pub mod power_button;
 
-use core::{convert::Infallible, sync::atomic::Ordering::Relaxed};
+use core::{
+    convert::Infallible,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering::Relaxed},
+};
 
 use acpica_bindings::{
     handler::AcpiHandler, register_interface, status::AcpiError, types::AcpiPhysicalAddress,
 };
-use log::{debug, info, trace};
+use log::{debug, error, info, trace, warn};
 use x86_64::{
-    instructions::{hlt, port::Port},
-    structures::paging::{frame::PhysFrameRange, page::PageRange, Page, PhysFrame},
+    instructions::{hlt, interrupts, port::Port},
+    structures::paging::{mapper::TranslateResult, Page, PageTableFlags, Size4KiB, Translate},
     PhysAddr, VirtAddr,
 };
 
 use crate::{
-    cpu::{register_interrupt_callback, remove_interrupt_callback, CallbackRemoveError},
+    cpu::{
+        register_interrupt_callback, remove_interrupt_callback, CallbackRemoveError,
+        InterruptIndex, MappedRegion,
+    },
     global_state::KERNEL_STATE,
     graphics::flush,
     pci, print, println,
+    scheduler::Task,
 };
 
+/// The global system interrupt which ACPICA installed its _System Control Interrupt_ handler on,
+/// or [`u32::MAX`] if it hasn't installed one.
+/// The SCI is how the hardware signals ACPI events such as the power button being pressed.
+static SCI_INTERRUPT: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// The number of callbacks passed to [`AcpiHandler::execute`] which haven't finished running yet
+static RUNNING_CALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Gets the global system interrupt which ACPICA uses for the _System Control Interrupt_, if it has installed a handler.
+/// The interrupt should be routed to [`InterruptIndex::AcpiSci`].
+pub fn sci_interrupt() -> Option<u32> {
+    match SCI_INTERRUPT.load(Relaxed) {
+        u32::MAX => None,
+        interrupt => Some(interrupt),
+    }
+}
+
 /// Whether an interrupt is active high or low.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum InterruptActiveState {
+pub enum InterruptActiveState {
     /// The interrupt is sent when the signal is active
     ActiveHigh,
     /// The interrupt is sent when the signal is not active
@@ -69,7 +95,7 @@ impl InterruptActiveState {
 
 /// Whether an interrupt is edge- or level-triggered.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum InterruptTriggerMode {
+pub enum InterruptTriggerMode {
     /// The interrupt is sent once when the signal changes
     EdgeTriggered,
     /// The interrupt is sent repeatedly until the signal changes back
@@ -150,12 +176,85 @@ pub unsafe fn power_off() -> Result<Infallible, PowerOffError> {
     Err(PowerOffError::DidntTurnOff)
 }
 
+/// Copies `N` bytes of physical memory starting at `address`
+///
+/// # Safety
+/// `address..address + N` must be valid to read
+unsafe fn read_physical_array<const N: usize>(address: PhysAddr) -> [u8; N] {
+    // `with_mapping` maps whole pages starting from the one containing `address`,
+    // so the length is extended to cover the part of that page before `address`
+    let len = N + usize::try_from(address.as_u64() % 4096).unwrap();
+
+    // SAFETY: The caller guarantees that the memory is valid to read
+    unsafe {
+        KERNEL_STATE
+            .physical_memory_accessor
+            .lock()
+            .with_mapping(address, len, |ptr| ptr.cast::<[u8; N]>().read_unaligned())
+    }
+}
+
+/// Finds the ACPI table with the given signature by searching the XSDT, or the RSDT on ACPI 1.0 systems,
+/// and returns its physical address.
+///
+/// This is used to find tables before ACPICA has been initialised, or tables which [`acpica_bindings`] doesn't parse.
+///
+/// # Safety
+/// `rsdp_addr` must be the physical address of the RSDP
+unsafe fn find_table(rsdp_addr: u64, signature: [u8; 4]) -> Option<PhysAddr> {
+    /// The length of the header at the start of every table
+    const HEADER_LENGTH: usize = 36;
+
+    /// Copies `N` bytes of the ACPI tables starting at `address`
+    fn read<const N: usize>(address: u64) -> [u8; N] {
+        // SAFETY: `find_table`'s caller guarantees that the RSDP is valid, and it only points to valid tables
+        unsafe { read_physical_array(PhysAddr::new(address)) }
+    }
+
+    let rsdp: [u8; 36] = read(rsdp_addr);
+    if &rsdp[..8] != b"RSD PTR " {
+        return None;
+    }
+
+    // Use the XSDT if the RSDP is from ACPI 2.0 or later, as it has 64-bit pointers
+    let revision = rsdp[15];
+    let xsdt_addr = u64::from_le_bytes(rsdp[24..32].try_into().unwrap());
+    let (sdt_addr, entry_size) = if revision >= 2 && xsdt_addr != 0 {
+        (xsdt_addr, 8)
+    } else {
+        let rsdt_addr = u32::from_le_bytes(rsdp[16..20].try_into().unwrap());
+        (u64::from(rsdt_addr), 4)
+    };
+
+    let header: [u8; HEADER_LENGTH] = read(sdt_addr);
+    let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let num_entries = length.saturating_sub(HEADER_LENGTH) / entry_size;
+
+    (0..num_entries).find_map(|i| {
+        let entry_addr = sdt_addr + (HEADER_LENGTH + i * entry_size) as u64;
+
+        let table_addr = if entry_size == 8 {
+            u64::from_le_bytes(read(entry_addr))
+        } else {
+            u64::from(u32::from_le_bytes(read(entry_addr)))
+        };
+
+        let table_signature: [u8; 4] = read(table_addr);
+
+        (table_signature == signature).then(|| PhysAddr::new(table_addr))
+    })
+}
+
 /// Initialises the [`acpica_bindings`] crate.
 ///
 /// # Safety
 /// * This function may only be called once.
-/// * `rsdp_addr` must be the virtual address of the RSDP.
+/// * `rsdp_addr` must be the physical address of the RSDP.
 pub unsafe fn init(rsdp_addr: u64) {
+    // The HPET is initialised first so that ACPICA has an accurate timer
+    // SAFETY: This function is only called once, and `rsdp_addr` is valid
+    unsafe { hpet::init(rsdp_addr) };
+
     trace!(target: "acpi_init", "Initialising ACPICA");
     flush().unwrap();
 
@@ -187,6 +286,9 @@ pub unsafe fn init(rsdp_addr: u64) {
     let acpica_initialization = acpica_initialization.initialize_objects().unwrap();
     KERNEL_STATE.acpica.init(acpica_initialization);
 
+    // SAFETY: ACPICA has been enabled, this function is only called once, and `rsdp_addr` is valid
+    unsafe { power_button::init(rsdp_addr) };
+
     trace!(target: "acpi_init", "Done initialising ACPICA");
     flush().unwrap();
 }
@@ -256,6 +358,38 @@ macro_rules! write_physical {
     }};
 }
 
+/// Checks whether every page spanned by `pointer..pointer + length` is mapped in the kernel page table,
+/// and if `writable` is `true`, whether they are all writable.
+fn is_mapped(pointer: *const u8, length: usize, writable: bool) -> bool {
+    if length == 0 {
+        return true;
+    }
+
+    let Ok(start) = VirtAddr::try_new(pointer as u64) else {
+        return false;
+    };
+    let Some(end) = (pointer as u64).checked_add(length as u64 - 1) else {
+        return false;
+    };
+    let Ok(end) = VirtAddr::try_new(end) else {
+        return false;
+    };
+
+    let mut pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    );
+
+    let page_table = KERNEL_STATE.page_table.lock();
+
+    pages.all(|page| match page_table.translate(page.start_address()) {
+        TranslateResult::Mapped { flags, .. } => {
+            !writable || flags.contains(PageTableFlags::WRITABLE)
+        }
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => false,
+    })
+}
+
 /// The type which implements [`AcpiHandler`] in order to interact with the [`acpica_bindings`] crate
 #[derive(Debug)]
 struct AcpiInterface {
@@ -285,71 +419,61 @@ unsafe impl AcpiHandler for AcpiInterface {
     ) -> Result<*mut u8, acpica_bindings::types::AcpiMappingError> {
         // trace!(target: "map_memory", "Mapping {length} bytes from {physical_address:?}");
 
-        let start_frame = PhysFrame::containing_address(PhysAddr::new(physical_address.0 as _));
-        let num_frames = length as u64 / 4096 + 2;
-
         // SAFETY: The address is valid for reads
         let mapping = unsafe {
             KERNEL_STATE
                 .physical_memory_accessor
                 .lock()
-                .map_frames(PhysFrameRange {
-                    start: start_frame,
-                    end: start_frame + num_frames,
-                })
+                .map_region(PhysAddr::new(physical_address.0 as _), length)
         };
 
-        // SAFETY: Parameter to `add` is less than 4096 so can't overflow
-        unsafe {
-            Ok(mapping
-                .start
-                .start_address()
-                .as_ptr::<u8>()
-                .add(physical_address.0 % 4096)
-                .cast_mut())
-        }
+        // ACPICA passes the pointer back to `unmap_memory` when it has finished with the mapping
+        Ok(mapping.leak())
     }
 
     unsafe fn unmap_memory(&mut self, address: *mut u8, length: usize) {
         // trace!(target: "unmap_memory", "Unmapping {length} bytes from {address:?}");
 
-        let start_page = Page::containing_address(VirtAddr::new(address as _));
-        let num_pages = length as u64 / 4096 + 2;
-
-        // SAFETY: This memory was previously mapped by ACPICA and is no longer in use
-        unsafe {
-            KERNEL_STATE
-                .physical_memory_accessor
-                .lock()
-                .unmap_frames(PageRange {
-                    start: start_page,
-                    end: start_page + num_pages,
-                });
-        }
+        // SAFETY: This memory was previously mapped by `map_memory` with the same length, and is no longer in use
+        drop(unsafe { MappedRegion::from_raw_parts(address, length) });
     }
 
     fn get_physical_address(
         &mut self,
-        _logical_address: *mut u8,
+        logical_address: *mut u8,
     ) -> Result<
         Option<acpica_bindings::types::AcpiPhysicalAddress>,
         acpica_bindings::status::AcpiError,
     > {
-        todo!()
+        let Ok(virt_addr) = VirtAddr::try_new(logical_address as u64) else {
+            return Ok(None);
+        };
+
+        // The page table walk includes the offset into the page, so this works for addresses which aren't page-aligned
+        let phys_addr = KERNEL_STATE.page_table.lock().translate_addr(virt_addr);
+
+        Ok(phys_addr.map(|addr| AcpiPhysicalAddress(addr.as_u64().try_into().unwrap())))
     }
 
+    // ACPICA only installs a handler for the SCI, so this is the only interrupt which is supported.
+    // `interrupt_number` is a global system interrupt rather than an interrupt vector,
+    // so the handler is registered on `InterruptIndex::AcpiSci` and the interrupt is routed there by the IO APIC.
     unsafe fn install_interrupt_handler(
         &mut self,
         interrupt_number: u32,
         callback: acpica_bindings::types::AcpiInterruptCallback,
     ) -> Result<(), acpica_bindings::status::AcpiError> {
-        register_interrupt_callback(
-            interrupt_number
-                .try_into()
-                .expect("Interrupt number should have been <= 255"),
-            callback,
-        )
-        .map_err(|_| AcpiError::Error)
+        if let Err(existing) =
+            SCI_INTERRUPT.compare_exchange(u32::MAX, interrupt_number, Relaxed, Relaxed)
+        {
+            if existing != interrupt_number {
+                warn!("ACPICA tried to install a handler for interrupt {interrupt_number}, but the SCI is already {existing}");
+                return Err(AcpiError::Error);
+            }
+        }
+
+        register_interrupt_callback(InterruptIndex::AcpiSci.as_u8(), callback)
+            .map_err(|_| AcpiError::Error)
     }
 
     // SAFETY: The interrupt handler is removed if present.
@@ -359,7 +483,11 @@ unsafe impl AcpiHandler for AcpiInterface {
         interrupt_number: u32,
         callback: acpica_bindings::types::AcpiInterruptCallbackTag,
     ) -> Result<(), acpica_bindings::status::AcpiError> {
-        match remove_interrupt_callback(interrupt_number.try_into().unwrap(), callback) {
+        if sci_interrupt() != Some(interrupt_number) {
+            return Err(AcpiError::NotExist);
+        }
+
+        match remove_interrupt_callback(InterruptIndex::AcpiSci.as_u8(), callback) {
             Ok(()) => Ok(()),
             Err(CallbackRemoveError::LockTaken) => panic!(),
             Err(CallbackRemoveError::NotFound) => Err(AcpiError::NotExist),
@@ -378,27 +506,61 @@ unsafe impl AcpiHandler for AcpiInterface {
         }
     }
 
+    // The callback is run later as a scheduler task, which is how ACPICA delivers GPEs and notify handlers
+    // outside of the SCI handler.
     unsafe fn execute(
         &mut self,
-        _callback: acpica_bindings::types::AcpiThreadCallback,
+        mut callback: acpica_bindings::types::AcpiThreadCallback,
     ) -> Result<(), acpica_bindings::status::AcpiError> {
-        todo!()
+        RUNNING_CALLBACKS.fetch_add(1, Relaxed);
+
+        Task::register_detached(Some("acpica"), async move {
+            // SAFETY: ACPICA passed this callback to be run asynchronously, and it is only called once
+            unsafe { callback.call() };
+            RUNNING_CALLBACKS.fetch_sub(1, Relaxed);
+        });
+
+        Ok(())
     }
 
+    // SAFETY: This doesn't return until all callbacks passed to `execute` have completed,
+    // unless it would deadlock because tasks can't be polled.
     unsafe fn wait_for_events(&mut self) {
-        todo!()
+        while RUNNING_CALLBACKS.load(Relaxed) != 0 {
+            // Tasks are polled in the timer interrupt handler, so if interrupts are disabled
+            // (e.g. if this was called from a task) the callbacks will never complete
+            if !interrupts::are_enabled() {
+                warn!("Can't wait for ACPICA callbacks with interrupts disabled");
+                return;
+            }
+
+            hlt();
+        }
     }
 
-    // SAFETY: This won't return until the given time elapses (assuming 100 ticks per second)
+    // SAFETY: This won't return until the given time elapses
     unsafe fn sleep(&mut self, millis: usize) {
-        let target_kernel_ticks = KERNEL_STATE.ticks() + millis / 10;
-        while KERNEL_STATE.ticks() < target_kernel_ticks {
+        let target_uptime = KERNEL_STATE.uptime_ns() + millis as u64 * 1_000_000;
+        while KERNEL_STATE.uptime_ns() < target_uptime {
             hlt();
         }
     }
 
-    unsafe fn stall(&mut self, _micros: usize) {
-        todo!()
+    // SAFETY: This busy-waits until the given time elapses
+    unsafe fn stall(&mut self, micros: usize) {
+        if let Some(start) = hpet::nanoseconds() {
+            let target = start + micros as u64 * 1000;
+            while hpet::nanoseconds().unwrap() < target {
+                core::hint::spin_loop();
+            }
+        } else {
+            // Without a HPET, use writes to the POST code port, which each take about 1µs
+            let mut port = Port::<u8>::new(0x80);
+            for _ in 0..micros {
+                // SAFETY: Port 0x80 is only used for POST codes, so writing to it has no effect
+                unsafe { port.write(0) };
+            }
+        }
     }
 
     unsafe fn read_port_u8(
@@ -461,10 +623,10 @@ unsafe impl AcpiHandler for AcpiInterface {
         Ok(())
     }
 
-    /// SAFETY: Ticks will not overflow, so this timer won't decrease.
+    /// SAFETY: The kernel's uptime only ever increases, so this timer won't decrease.
     unsafe fn get_timer(&mut self) -> u64 {
-        // TODO: actually implement a timer        
-        KERNEL_STATE.ticks() as u64 * 100_000
+        // ACPICA's timer is in units of 100ns
+        hpet::nanoseconds().unwrap_or_else(|| KERNEL_STATE.uptime_ns()) / 100
     }
 
     // SAFETY: The read is volatile and unaligned
@@ -523,12 +685,12 @@ unsafe impl AcpiHandler for AcpiInterface {
         Ok(write_physical!(8, address, value))
     }
 
-    unsafe fn readable(&mut self, _pointer: *mut core::ffi::c_void, _length: usize) -> bool {
-        todo!()
+    unsafe fn readable(&mut self, pointer: *mut core::ffi::c_void, length: usize) -> bool {
+        is_mapped(pointer.cast(), length, false)
     }
 
-    unsafe fn writable(&mut self, _pointer: *mut core::ffi::c_void, _length: usize) -> bool {
-        todo!()
+    unsafe fn writable(&mut self, pointer: *mut core::ffi::c_void, length: usize) -> bool {
+        is_mapped(pointer.cast(), length, true)
     }
 
     unsafe fn read_pci_config_u8(
@@ -701,11 +863,15 @@ unsafe impl AcpiHandler for AcpiInterface {
 
     unsafe fn signal_fatal(
         &mut self,
-        _fatal_type: u32,
-        _code: u32,
-        _argument: u32,
+        fatal_type: u32,
+        code: u32,
+        argument: u32,
     ) -> Result<(), AcpiError> {
-        todo!("Fatal signal")
+        error!(
+            target: "signal_fatal",
+            "ACPI fatal error: type {fatal_type:#x}, code {code:#x}, argument {argument:#x}"
+        );
+        Ok(())
     }
 
     unsafe fn signal_breakpoint(&mut self, message: &str) -> Result<(), AcpiError> {
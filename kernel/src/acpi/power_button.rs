@@ -0,0 +1,135 @@
+//! Handling of the ACPI fixed power button, which shuts the system down when it is pressed.
+//!
+//! ACPICA would normally handle the button through `AcpiInstallFixedEventHandler`, but [`acpica_bindings`]
+//! doesn't expose it, so the button's status and enable bits are accessed directly.
+//! They are in the PM1 event registers, whose ports are found in the `FACP` table (the FADT).
+//! See section 4.8.3.1 of the [ACPI specification] for the registers' layout.
+//!
+//! [ACPI specification]: https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#pm1-event-grouping
+
+use conquer_once::spin::OnceCell;
+use log::{info, warn};
+use x86_64::instructions::port::Port;
+
+use crate::{scheduler::Task, shutdown::shutdown};
+
+use super::{find_table, read_physical_array};
+
+/// The offset in the FADT of the `PM1a_EVT_BLK` field
+const PM1A_EVENT_BLOCK_OFFSET: u64 = 56;
+/// The offset in the FADT of the `PM1b_EVT_BLK` field
+const PM1B_EVENT_BLOCK_OFFSET: u64 = 60;
+/// The offset in the FADT of the `PM1_EVT_LEN` field
+const PM1_EVENT_LENGTH_OFFSET: u64 = 88;
+/// The offset in the FADT of the fixed feature `Flags` field
+const FLAGS_OFFSET: u64 = 112;
+
+/// The bit of the FADT's flags which is set if the power button is a control method device
+/// rather than a fixed feature
+const PWR_BUTTON_FLAG: u32 = 1 << 4;
+/// The bit of the PM1 status and enable registers for the power button
+const PWRBTN: u16 = 1 << 8;
+
+/// The PM1 event register blocks, once [`init`] has enabled the power button
+static EVENT_BLOCKS: OnceCell<EventBlocks> = OnceCell::uninit();
+
+/// The I/O ports of the PM1 event register blocks
+#[derive(Debug)]
+struct EventBlocks {
+    /// The first port of each block which exists.
+    /// Each block has a status register followed by an enable register.
+    blocks: [Option<u16>; 2],
+    /// The length of each block in bytes, half of which is the status register
+    length: u16,
+}
+
+impl EventBlocks {
+    /// Gets the status and enable registers of each block
+    fn registers(&self) -> impl Iterator<Item = (Port<u16>, Port<u16>)> + '_ {
+        self.blocks
+            .iter()
+            .flatten()
+            .map(|&block| (Port::new(block), Port::new(block + self.length / 2)))
+    }
+}
+
+/// Enables the power button's fixed event, so that pressing it sends an SCI which [`handle_sci`] will detect.
+///
+/// # Safety
+/// * This function may only be called once, after ACPICA has been enabled, as ACPICA disables all fixed events when it is enabled.
+/// * `rsdp_addr` must be the physical address of the RSDP
+pub unsafe fn init(rsdp_addr: u64) {
+    // SAFETY: The caller guarantees that `rsdp_addr` is valid
+    let Some(fadt) = (unsafe { find_table(rsdp_addr, *b"FACP") }) else {
+        warn!("No FADT found, so the power button won't work");
+        return;
+    };
+
+    // SAFETY: The table was found through the RSDT, so it is valid to read
+    let (pm1a, pm1b, length, flags) = unsafe {
+        (
+            u32::from_le_bytes(read_physical_array(fadt + PM1A_EVENT_BLOCK_OFFSET)),
+            u32::from_le_bytes(read_physical_array(fadt + PM1B_EVENT_BLOCK_OFFSET)),
+            u8::from_le_bytes(read_physical_array(fadt + PM1_EVENT_LENGTH_OFFSET)),
+            u32::from_le_bytes(read_physical_array(fadt + FLAGS_OFFSET)),
+        )
+    };
+
+    if flags & PWR_BUTTON_FLAG != 0 {
+        info!("The power button is a control method device, which isn't supported");
+        return;
+    }
+
+    // A block address of 0 means the block doesn't exist
+    let port = |block: u32| u16::try_from(block).ok().filter(|&port| port != 0);
+    let Some(pm1a) = port(pm1a) else {
+        warn!("The FADT has no PM1a event block, so the power button won't work");
+        return;
+    };
+
+    let event_blocks = EventBlocks {
+        blocks: [Some(pm1a), port(pm1b)],
+        length: length.into(),
+    };
+
+    for (mut status, mut enable) in event_blocks.registers() {
+        // SAFETY: These are the PM1 event registers, and only the power button's bits are changed.
+        // The status register is write-1-to-clear, so only the power button's status is cleared.
+        unsafe {
+            status.write(PWRBTN);
+            let enabled = enable.read();
+            enable.write(enabled | PWRBTN);
+        }
+    }
+
+    EVENT_BLOCKS.init_once(|| event_blocks);
+}
+
+/// Checks whether the power button was pressed, and if so, clears its status and starts shutting down.
+/// This is called from the SCI handler before ACPICA's handler, so that ACPICA doesn't disable the event
+/// because it has no handler for it.
+pub fn handle_sci() {
+    let Ok(event_blocks) = EVENT_BLOCKS.try_get() else {
+        return;
+    };
+
+    let mut pressed = false;
+    for (mut status, _) in event_blocks.registers() {
+        // SAFETY: This is a PM1 status register. It is write-1-to-clear, so only the power button's status is cleared.
+        unsafe {
+            if status.read() & PWRBTN != 0 {
+                status.write(PWRBTN);
+                pressed = true;
+            }
+        }
+    }
+
+    if pressed {
+        // Shutting down locks ACPICA and the drivers, so it can't be done in the interrupt handler
+        Task::register_detached(Some("power button"), async {
+            // SAFETY: The power button was pressed, so the user has asked for the system to be turned off
+            let error = unsafe { shutdown() };
+            warn!("Failed to power off after the power button was pressed: {error:?}");
+        });
+    }
+}
@@ -0,0 +1,150 @@
+//! The [`DmaBuffer`] type, for memory which is shared with a device using DMA
+
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of, ManuallyDrop},
+};
+
+use x86_64::{structures::paging::frame::PhysFrameRange, PhysAddr};
+
+use crate::{
+    cpu::MappedRegion,
+    global_state::KERNEL_STATE,
+    util::generic_mutability::{Immutable, IndexOutOfBoundsError, Mutable, VolatileSlice},
+};
+
+/// An owned, dynamically allocated `T` in memory which is shared with a device using _Direct Memory Access_ (DMA).
+///
+/// The buffer guarantees that:
+/// * The `T` is stored in physically contiguous, page-aligned memory, so the whole value can be found by the device
+///     from [`phys_addr`][DmaBuffer::phys_addr] alone.
+/// * The memory is mapped as uncacheable, so every write by the CPU reaches memory before the next instruction,
+///     and every read sees the latest value written by the device. No cache flushes are needed before handing
+///     the buffer to the device or after the device has written to it.
+/// * All accesses are volatile, so the compiler doesn't elide or reorder reads and writes which the device
+///     may observe or race with.
+///
+/// `T` must be [`Copy`], as the device may change the value at any time, so it is only ever read and written whole.
+///
+/// The buffer must not be dropped while the device may still access it, as its memory will be reused.
+#[derive(Debug)]
+pub struct DmaBuffer<T: Copy> {
+    /// The physical frames where the value is stored
+    frames: PhysFrameRange,
+
+    /// The mapping of [`frames`] into virtual memory.
+    /// This is dropped manually so that the frames are unmapped before they are freed.
+    ///
+    /// [`frames`]: DmaBuffer::frames
+    mapping: ManuallyDrop<MappedRegion>,
+
+    /// The buffer logically owns a `T`
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy> DmaBuffer<T> {
+    /// The number of frames needed to store a `T`
+    const FRAMES: u64 = {
+        let frames = size_of::<T>().div_ceil(0x1000);
+        if frames == 0 {
+            1
+        } else {
+            frames as u64
+        }
+    };
+
+    /// Allocates a new [`DmaBuffer`] containing `value`
+    ///
+    /// # Panics
+    /// * If `T` needs to be more than page-aligned
+    /// * If there are no free physical frames
+    pub fn new(value: T) -> Self {
+        assert!(align_of::<T>() <= 0x1000);
+
+        let frames = KERNEL_STATE
+            .frame_allocator
+            .lock()
+            .allocate_consecutive(Self::FRAMES, 0x1000)
+            .expect("Should have been able to allocate frames for a DMA buffer");
+
+        // SAFETY: `frames` was just allocated, so it is not being used.
+        // `map_region` maps the frames as uncacheable, which the type's coherency guarantees rely on.
+        let mapping = unsafe {
+            KERNEL_STATE
+                .physical_memory_accessor
+                .lock()
+                .map_region(frames.start.start_address(), size_of::<T>())
+        };
+
+        let mut buffer = Self {
+            frames,
+            mapping: ManuallyDrop::new(mapping),
+            _phantom: PhantomData,
+        };
+        buffer.write(value);
+        buffer
+    }
+
+    /// Gets the physical address of the value, to be given to the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    /// Gets a pointer to the value.
+    /// Reads through the pointer must be volatile, as the device may write to the value at any time.
+    pub fn as_ptr(&self) -> *const T {
+        self.mapping.as_ptr()
+    }
+
+    /// Gets a mutable pointer to the value.
+    /// Reads and writes through the pointer must be volatile, as the device may access the value at any time.
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.mapping.as_mut_ptr()
+    }
+
+    /// Reads the value
+    pub fn read(&self) -> T {
+        // SAFETY: The mapping points to an initialised `T`, which is only accessed through this buffer and the device
+        unsafe { self.as_ptr().read_volatile() }
+    }
+
+    /// Writes `value` to the buffer
+    pub fn write(&mut self, value: T) {
+        // SAFETY: The mapping is valid for writes of a `T`, and is only accessed through this buffer and the device
+        unsafe { self.as_mut_ptr().write_volatile(value) }
+    }
+}
+
+impl<E: Copy, const N: usize> DmaBuffer<[E; N]> {
+    /// Gets the elements of the array as a [`VolatileSlice`]
+    pub fn as_slice(&self) -> VolatileSlice<E, Immutable> {
+        // SAFETY: The mapping points to `N` initialised `E`s, which are borrowed immutably for the slice's lifetime
+        unsafe { VolatileSlice::new(self.as_ptr().cast(), N) }
+    }
+
+    /// Gets the elements of the array as a mutable [`VolatileSlice`]
+    pub fn as_slice_mut(&mut self) -> VolatileSlice<E, Mutable> {
+        // SAFETY: The mapping points to `N` initialised `E`s, which are borrowed mutably for the slice's lifetime
+        unsafe { VolatileSlice::new(self.as_mut_ptr().cast(), N) }
+    }
+
+    /// Reads the element at `index`, or returns an error if `index` is out of bounds
+    pub fn get(&self, index: usize) -> Result<E, IndexOutOfBoundsError> {
+        self.as_slice().get(index)
+    }
+
+    /// Writes `value` to the element at `index`, or returns an error if `index` is out of bounds
+    pub fn set(&mut self, index: usize, value: E) -> Result<(), IndexOutOfBoundsError> {
+        self.as_slice_mut().set(index, value)
+    }
+}
+
+impl<T: Copy> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        // SAFETY: `mapping` is not used again after this
+        unsafe { ManuallyDrop::drop(&mut self.mapping) };
+
+        // SAFETY: `frames` was allocated using `allocate_consecutive` in `new`, and is now no longer in use.
+        unsafe { KERNEL_STATE.frame_allocator.lock().free(self.frames) };
+    }
+}
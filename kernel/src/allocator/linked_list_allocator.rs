@@ -2,6 +2,7 @@
 
 use core::alloc::GlobalAlloc;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use x86_64::structures::paging::{
     mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -505,6 +506,47 @@ impl LinkedListAllocator {
     }
 }
 
+/// The number of bytes currently allocated on the kernel heap, as requested by callers of [`GlobalAlloc`]
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// The highest value [`ALLOCATED_BYTES`] has reached since boot or since [`reset_peak_heap_usage`] was last called
+static PEAK_ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Statistics about how much memory is allocated on the kernel heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The number of bytes currently allocated
+    pub allocated: usize,
+    /// The highest number of bytes which have been allocated at once
+    pub peak: usize,
+}
+
+/// Gets how much memory is allocated on the kernel heap.
+/// These are the sizes requested by callers, so they don't include [`ListNode`]s or padding.
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        allocated: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        peak: PEAK_ALLOCATED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Resets the [`peak`] heap usage to the amount of memory currently allocated
+///
+/// [`peak`]: HeapStats::peak
+pub fn reset_peak_heap_usage() {
+    PEAK_ALLOCATED_BYTES.store(ALLOCATED_BYTES.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Records that `size` more bytes have been allocated
+fn record_allocation(size: usize) {
+    let allocated = ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_ALLOCATED_BYTES.fetch_max(allocated, Ordering::Relaxed);
+}
+
+/// Records that `size` bytes have been freed
+fn record_deallocation(size: usize) {
+    ALLOCATED_BYTES.fetch_sub(size, Ordering::Relaxed);
+}
+
 /// A wrapper around
 #[derive(Debug)]
 pub struct GlobalKernelHeapAllocator(GlobalState<LinkedListAllocator>);
@@ -548,13 +590,14 @@ unsafe impl GlobalAlloc for GlobalKernelHeapAllocator {
                 // Check that the pointer has the correct alignment
                 .map(|ptr| {
                     debug_assert_eq!(ptr as usize, align_up(ptr as usize, layout.align()));
+                    record_allocation(layout.size());
                     ptr
                 })
                 .unwrap_or(null_mut())
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: core::alloc::Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
         // SAFETY: This function's safety requirements are the same as the called function
         unsafe {
             // Use `offset(-1)` because the given `ptr` points to the allocated memory, not to the node.
@@ -564,6 +607,8 @@ unsafe impl GlobalAlloc for GlobalKernelHeapAllocator {
                 // SAFETY: `ptr` is valid so `node` must be valid too
                 .deallocate_region(&mut *node);
         }
+
+        record_deallocation(layout.size());
     }
 
     unsafe fn realloc(
@@ -587,6 +632,8 @@ unsafe impl GlobalAlloc for GlobalKernelHeapAllocator {
                 // Check that the new pointer has the correct alignment
                 .map(|ptr| {
                     debug_assert_eq!(ptr as usize, align_up(ptr as usize, layout.align()));
+                    record_deallocation(layout.size());
+                    record_allocation(new_size);
                     ptr
                 })
                 .unwrap_or(null_mut())
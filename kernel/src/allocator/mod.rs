@@ -1,18 +1,21 @@
 //! Manages the kernel heap
 
+mod dma_buffer;
 mod linked_list_allocator;
 mod list_node;
 #[cfg(test)]
 mod tests;
 
-use x86_64::structures::paging::{
-    frame::PhysFrameRange, page::PageRange, FrameAllocator, Page, PhysFrame,
-};
+use core::mem::ManuallyDrop;
+
+use x86_64::structures::paging::{frame::PhysFrameRange, FrameAllocator, Page, PhysFrame};
 
-use crate::global_state::KERNEL_STATE;
+use crate::{cpu::MappedRegion, global_state::KERNEL_STATE};
 
+pub use self::dma_buffer::DmaBuffer;
 pub use self::linked_list_allocator::{
-    AllocationError, GlobalKernelHeapAllocator, LinkedListAllocator,
+    heap_stats, reset_peak_heap_usage, AllocationError, GlobalKernelHeapAllocator, HeapStats,
+    LinkedListAllocator,
 };
 
 /// The start address of the kernel heap
@@ -53,10 +56,11 @@ pub struct PageBox {
     /// The physical frame
     phys_frame: PhysFrame,
 
-    /// The virtual page mapped to [`phys_frame`]
+    /// The mapping of [`phys_frame`] into virtual memory.
+    /// This is dropped manually so that the page is unmapped before the frame is freed.
     ///
     /// [`phys_frame`]: PageBox::phys_frame
-    virt_page: Page,
+    mapping: ManuallyDrop<MappedRegion>,
 }
 
 impl PageBox {
@@ -70,22 +74,17 @@ impl PageBox {
             .allocate_frame()
             .unwrap();
 
-        let frames = PhysFrameRange {
-            start: phys_frame,
-            end: phys_frame + 1, // Exclusive range so add 1
-        };
-
         // SAFETY: `phys_frame` was just allocated, so it is not being used.
-        let virt_pages = unsafe {
+        let mapping = unsafe {
             KERNEL_STATE
                 .physical_memory_accessor
                 .lock()
-                .map_frames(frames)
+                .map_region(phys_frame.start_address(), 0x1000)
         };
 
         Self {
             phys_frame,
-            virt_page: virt_pages.start,
+            mapping: ManuallyDrop::new(mapping),
         }
     }
 
@@ -104,12 +103,12 @@ impl PageBox {
 
     /// Gets a pointer to the start of the page
     pub fn as_ptr<T>(&self) -> *const T {
-        self.virt_page.start_address().as_ptr()
+        self.mapping.as_ptr()
     }
 
     /// Gets a mutable pointer to the start of the page
     pub fn as_mut_ptr<T>(&mut self) -> *mut T {
-        self.virt_page.start_address().as_mut_ptr()
+        self.mapping.as_mut_ptr()
     }
 
     /// Gets the [`PhysFrame`] allocated for this [`PageBox`]
@@ -119,24 +118,14 @@ impl PageBox {
 
     /// Gets the virtual [`Page`] allocated for this [`PageBox`]
     pub fn virt_page(&self) -> Page {
-        self.virt_page
+        Page::containing_address(self.mapping.start())
     }
 }
 
 impl Drop for PageBox {
     fn drop(&mut self) {
-        // SAFETY: `virt_page` was allocated using `map_frames` in `new`, and is now no longer in use
-        unsafe {
-            let range = PageRange {
-                start: self.virt_page,
-                end: self.virt_page + 1,
-            };
-
-            KERNEL_STATE
-                .physical_memory_accessor
-                .lock()
-                .unmap_frames(range);
-        }
+        // SAFETY: `mapping` is not used again after this
+        unsafe { ManuallyDrop::drop(&mut self.mapping) };
 
         // SAFETY: `phys_frame` was allocated using `allocate_frame` in `new`, and is now no longer in use.
         unsafe {
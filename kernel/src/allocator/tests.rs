@@ -104,3 +104,34 @@ fn test_offset() {
         ptr as usize - ListNode::OFFSET
     );
 }
+
+/// Tests that a [`DmaBuffer`][super::DmaBuffer] spanning several pages is physically contiguous,
+/// and that its elements can be read and written
+#[test_case]
+fn test_dma_buffer() {
+    use x86_64::{structures::paging::Translate, VirtAddr};
+
+    use super::DmaBuffer;
+    use crate::{global_state::KERNEL_STATE, util::generic_mutability::IndexOutOfBoundsError};
+
+    let mut buffer = DmaBuffer::new([0u32; 2000]);
+    assert!(buffer.phys_addr().is_aligned(0x1000u64));
+
+    // The last element is on the second page, and should be mapped right after the first
+    let last = VirtAddr::from_ptr(buffer.as_ptr()) + 1999u64 * 4;
+    assert_eq!(
+        KERNEL_STATE.page_table.lock().translate_addr(last),
+        Some(buffer.phys_addr() + 1999u64 * 4)
+    );
+
+    buffer.set(1999, 42).unwrap();
+    assert_eq!(buffer.get(1999), Ok(42));
+    assert_eq!(buffer.read()[1999], 42);
+    assert_eq!(
+        buffer.set(2000, 1),
+        Err(IndexOutOfBoundsError {
+            index: 2000,
+            len: 2000
+        })
+    );
+}
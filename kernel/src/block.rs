@@ -0,0 +1,157 @@
+//! The [`BlockDevice`] trait, which abstracts over drivers for devices which store data in fixed-size blocks,
+//! and the list of block devices which have been found.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{future::Future, pin::Pin};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::println;
+
+/// An error which can occur when reading from or writing to a [`BlockDevice`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockDeviceError {
+    /// Some of the blocks are past the end of the device
+    OutOfRange,
+    /// The length of the buffer isn't the number of blocks multiplied by the [block size]
+    ///
+    /// [block size]: BlockDevice::block_size
+    InvalidBufferLength,
+    /// The device failed to carry out the request
+    IoError,
+    /// The device doesn't support the request, e.g. writing to a read-only device
+    Unsupported,
+}
+
+/// The future returned by [`BlockDevice::read_blocks`] and [`BlockDevice::write_blocks`]
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockDeviceError>> + 'a>>;
+
+/// A device which stores data in fixed-size blocks, addressed by their logical block address (LBA).
+///
+/// Only virtio block devices implement this so far.
+/// TODO: USB mass storage devices (the Bulk-Only Transport with SCSI commands) need a class driver on the xHCI
+/// driver's tasks, which doesn't exist yet, so they are not found as block devices.
+pub trait BlockDevice: Send + Sync {
+    /// Gets the size of each block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Gets the number of blocks on the device
+    fn num_blocks(&self) -> u64;
+
+    /// Reads `count` blocks starting at `lba` into `buf`.
+    /// The length of `buf` must be `count` multiplied by the [block size].
+    ///
+    /// [block size]: BlockDevice::block_size
+    fn read_blocks<'a>(&'a self, lba: u64, count: u64, buf: &'a mut [u8]) -> BlockFuture<'a>;
+
+    /// Writes `buf` to the device starting at `lba`.
+    /// The length of `buf` must be a multiple of the [block size].
+    ///
+    /// [block size]: BlockDevice::block_size
+    #[allow(dead_code)] // Not yet used by a filesystem
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a>;
+
+    /// Checks that `count` blocks starting at `lba` are on the device,
+    /// and that a buffer of `buffer_len` bytes holds exactly that many blocks.
+    fn check_request(
+        &self,
+        lba: u64,
+        count: u64,
+        buffer_len: usize,
+    ) -> Result<(), BlockDeviceError> {
+        // A request too long to fit in a `u64` can't match the buffer's length either
+        let request_len = count.checked_mul(self.block_size() as u64);
+        if request_len != Some(buffer_len as u64) {
+            return Err(BlockDeviceError::InvalidBufferLength);
+        }
+
+        match lba.checked_add(count) {
+            Some(end) if end <= self.num_blocks() => Ok(()),
+            _ => Err(BlockDeviceError::OutOfRange),
+        }
+    }
+}
+
+/// The block devices which have been found by any driver
+static BLOCK_DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// Adds a device to the list printed by [`blkls`], returning its index in the list
+pub fn register_block_device(device: Arc<dyn BlockDevice>) -> usize {
+    // Drivers may run in interrupt handlers, so disable interrupts to avoid deadlock
+    without_interrupts(|| {
+        let mut devices = BLOCK_DEVICES.lock();
+        devices.push(device);
+        devices.len() - 1
+    })
+}
+
+/// Gets the block devices which have been found
+pub fn block_devices() -> Vec<Arc<dyn BlockDevice>> {
+    without_interrupts(|| BLOCK_DEVICES.lock().clone())
+}
+
+/// Lists the block devices which have been found
+pub fn blkls(_: &[&str]) {
+    let devices = block_devices();
+
+    if devices.is_empty() {
+        println!("No block devices");
+        return;
+    }
+
+    for (i, device) in devices.iter().enumerate() {
+        let size = device.num_blocks().saturating_mul(device.block_size() as u64);
+        println!(
+            "blk{i}  {} blocks of {} bytes  ({} KiB)",
+            device.num_blocks(),
+            device.block_size(),
+            size / 1024
+        );
+    }
+}
+
+/// Tests that [`BlockDevice::check_request`] rejects out-of-range blocks and wrongly-sized buffers
+#[test_case]
+fn test_check_request() {
+    /// A device with 16 blocks of 512 bytes, which can't actually be read or written
+    struct TestDevice;
+
+    impl BlockDevice for TestDevice {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn num_blocks(&self) -> u64 {
+            16
+        }
+
+        fn read_blocks<'a>(&'a self, _: u64, _: u64, _: &'a mut [u8]) -> BlockFuture<'a> {
+            Box::pin(async { Err(BlockDeviceError::Unsupported) })
+        }
+
+        fn write_blocks<'a>(&'a self, _: u64, _: &'a [u8]) -> BlockFuture<'a> {
+            Box::pin(async { Err(BlockDeviceError::Unsupported) })
+        }
+    }
+
+    assert_eq!(TestDevice.check_request(0, 16, 16 * 512), Ok(()));
+    assert_eq!(TestDevice.check_request(15, 1, 512), Ok(()));
+    assert_eq!(
+        TestDevice.check_request(15, 2, 2 * 512),
+        Err(BlockDeviceError::OutOfRange)
+    );
+    assert_eq!(
+        TestDevice.check_request(u64::MAX, 2, 2 * 512),
+        Err(BlockDeviceError::OutOfRange)
+    );
+    assert_eq!(
+        TestDevice.check_request(0, 2, 512),
+        Err(BlockDeviceError::InvalidBufferLength)
+    );
+    // `count` multiplied by the block size overflows, and would wrap around to 0
+    assert_eq!(
+        TestDevice.check_request(0, 1 << 55, 0),
+        Err(BlockDeviceError::InvalidBufferLength)
+    );
+}
@@ -0,0 +1,116 @@
+//! Detection of the CPU's features using the `CPUID` instruction
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+
+/// The features of the CPU which are relevant to the kernel, as reported by `CPUID`.
+///
+/// For more info, see the [Intel 64 and IA-32 Architectures Software Developer’s Manual] volume 2A section 3.3 (`CPUID`)
+///
+/// [Intel 64 and IA-32 Architectures Software Developer’s Manual]: https://cdrdv2.intel.com/v1/dl/getContent/671200
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    /// The vendor ID string, e.g. `GenuineIntel` or `AuthenticAMD`
+    vendor: [u8; 12],
+    /// Whether the _Page Attribute Table_ is supported, which is needed to map pages as write-combining
+    pub pat: bool,
+    /// Whether SSE instructions are supported
+    pub sse: bool,
+    /// Whether SSE2 instructions are supported
+    pub sse2: bool,
+    /// Whether SSE3 instructions are supported
+    pub sse3: bool,
+    /// Whether SSSE3 instructions are supported
+    pub ssse3: bool,
+    /// Whether SSE4.1 instructions are supported
+    pub sse4_1: bool,
+    /// Whether SSE4.2 instructions are supported
+    pub sse4_2: bool,
+    /// Whether the `XSAVE` family of instructions and the `XCR0` register are supported
+    pub xsave: bool,
+    /// Whether AVX instructions are supported. These can only be used once they are enabled in `XCR0`.
+    pub avx: bool,
+    /// Whether AVX2 instructions are supported
+    pub avx2: bool,
+}
+
+impl CpuFeatures {
+    /// Gets the vendor ID string, e.g. `GenuineIntel` or `AuthenticAMD`
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("<invalid>")
+    }
+
+    /// Gets the names of the features which are supported, in the same format as `/proc/cpuinfo` on Linux
+    pub fn flags(&self) -> impl Iterator<Item = &'static str> {
+        [
+            (self.pat, "pat"),
+            (self.sse, "sse"),
+            (self.sse2, "sse2"),
+            (self.sse3, "pni"),
+            (self.ssse3, "ssse3"),
+            (self.sse4_1, "sse4_1"),
+            (self.sse4_2, "sse4_2"),
+            (self.xsave, "xsave"),
+            (self.avx, "avx"),
+            (self.avx2, "avx2"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+    }
+}
+
+/// Gets whether bit `bit` of `value` is set
+fn bit(value: u32, bit: u32) -> bool {
+    value & (1 << bit) != 0
+}
+
+/// Runs `CPUID` to get the features of the core this function is called on
+pub fn cpu_features() -> CpuFeatures {
+    // SAFETY: All x86_64 CPUs support CPUID, and leaf 0 is always valid
+    let CpuidResult {
+        eax: max_leaf,
+        ebx,
+        ecx,
+        edx,
+    } = unsafe { __cpuid(0) };
+
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&ecx.to_le_bytes());
+
+    // SAFETY: Leaf 1 is always valid on x86_64
+    let leaf_1 = unsafe { __cpuid(1) };
+    let leaf_7 = if max_leaf >= 7 {
+        // SAFETY: The CPU reported that it supports leaf 7
+        unsafe { __cpuid_count(7, 0) }
+    } else {
+        CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        }
+    };
+
+    CpuFeatures {
+        vendor,
+        pat: bit(leaf_1.edx, 16),
+        sse: bit(leaf_1.edx, 25),
+        sse2: bit(leaf_1.edx, 26),
+        sse3: bit(leaf_1.ecx, 0),
+        ssse3: bit(leaf_1.ecx, 9),
+        sse4_1: bit(leaf_1.ecx, 19),
+        sse4_2: bit(leaf_1.ecx, 20),
+        xsave: bit(leaf_1.ecx, 26),
+        avx: bit(leaf_1.ecx, 28),
+        avx2: bit(leaf_7.ebx, 5),
+    }
+}
+
+/// Tests that SSE2 is reported as supported, as all x86_64 CPUs support it
+#[test_case]
+fn test_sse2_detected() {
+    let features = cpu_features();
+    assert!(features.sse && features.sse2);
+    assert!(features.flags().any(|flag| flag == "sse2"));
+}
@@ -1,6 +1,9 @@
 //! Contains the [`BootInfoFrameAllocator`] type which allocates frames of physical memory
 // TODO: rewrite this to be able to deallocate frames
 
+use core::ops::Range;
+
+use alloc::vec::Vec;
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
 use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
@@ -15,6 +18,11 @@ pub struct BootInfoFrameAllocator {
     current_region: usize,
     /// The next frame in the [`current_region`][Self::current_region] to be allocated
     current_frame: u64,
+    /// A frame which has been reserved by [`reserve_frame_in`], which [`allocate_frame`] skips
+    ///
+    /// [`reserve_frame_in`]: BootInfoFrameAllocator::reserve_frame_in
+    /// [`allocate_frame`]: BootInfoFrameAllocator::allocate_frame
+    reserved_frame: Option<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -29,7 +37,90 @@ impl BootInfoFrameAllocator {
             memory_map,
             current_region: 0,
             current_frame: 0,
+            reserved_frame: None,
+        }
+    }
+
+    /// Finds a frame which hasn't been allocated yet and starts in the given range of physical addresses,
+    /// and reserves it so that it won't be allocated later.
+    ///
+    /// Frames are normally allocated in the order of the memory map, so this searches the whole map instead,
+    /// for frames which have to be at a particular address. Only one frame can be reserved,
+    /// so this returns [`None`] if a frame is already reserved, or if there is no free frame in the range.
+    pub fn reserve_frame_in(&mut self, addresses: Range<u64>) -> Option<PhysFrame> {
+        if self.reserved_frame.is_some() {
+            return None;
+        }
+
+        let frame = self
+            .memory_map
+            .iter()
+            .enumerate()
+            .skip(self.current_region)
+            .filter(|(_, region)| region.kind == MemoryRegionKind::Usable)
+            .find_map(|(i, region)| {
+                // Frames before `current_frame` in the current region have already been allocated
+                let mut start = region.start;
+                if i == self.current_region {
+                    start += 0x1000 * self.current_frame;
+                }
+
+                let start = start.max(addresses.start.next_multiple_of(0x1000));
+                let end = region.end.min(addresses.end);
+                (start + 0x1000 <= end).then_some(start)
+            })
+            .map(|start| PhysFrame::containing_address(PhysAddr::new(start)))?;
+
+        self.reserved_frame = Some(frame);
+        Some(frame)
+    }
+
+    /// Gets the total number of bytes of physical memory in the memory map, of any kind
+    pub fn total_memory(&self) -> u64 {
+        self.memory_map
+            .iter()
+            .map(|region| region.end - region.start)
+            .sum()
+    }
+
+    /// Gets the number of bytes of physical memory which the memory map marks as usable by the kernel
+    pub fn usable_memory(&self) -> u64 {
+        self.memory_map
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| region.end - region.start)
+            .sum()
+    }
+
+    /// Gets the number of bytes of physical memory in the memory map which are reserved, i.e. not usable by the kernel
+    pub fn reserved_memory(&self) -> u64 {
+        self.total_memory() - self.usable_memory()
+    }
+
+    /// Gets the memory map which frames are allocated from
+    pub fn memory_map(&self) -> &'static [MemoryRegion] {
+        self.memory_map
+    }
+
+    /// Gets the number of bytes of physical memory of each [`MemoryRegionKind`] in `memory_map`,
+    /// in the order each kind first appears in the map.
+    ///
+    /// This allocates, so it takes the [`memory_map`] rather than the allocator, so that it isn't called
+    /// while the allocator is locked: growing the heap also locks the allocator, which would deadlock.
+    ///
+    /// [`memory_map`]: BootInfoFrameAllocator::memory_map
+    pub fn memory_by_kind(memory_map: &[MemoryRegion]) -> Vec<(MemoryRegionKind, u64)> {
+        let mut kinds: Vec<(MemoryRegionKind, u64)> = Vec::new();
+
+        for region in memory_map {
+            let size = region.end - region.start;
+            match kinds.iter_mut().find(|(kind, _)| *kind == region.kind) {
+                Some((_, total)) => *total += size,
+                None => kinds.push((region.kind, size)),
+            }
         }
+
+        kinds
     }
 
     /// Allocates consecutive physical frames.
@@ -98,7 +189,12 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 
             self.current_frame += 1;
 
-            return Some(PhysFrame::containing_address(PhysAddr::new(frame)));
+            let frame = PhysFrame::containing_address(PhysAddr::new(frame));
+            if Some(frame) == self.reserved_frame {
+                continue;
+            }
+
+            return Some(frame);
         }
     }
 }
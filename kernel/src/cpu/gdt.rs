@@ -1,26 +1,32 @@
 //! Code to register a new GDT
 
+use alloc::{boxed::Box, vec};
 use x86_64::{
     instructions::tables::load_tss,
     registers::segmentation::{Segment, CS, DS, ES, SS},
     structures::{
-        gdt::{Descriptor, GlobalDescriptorTable},
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
     },
-    VirtAddr,
+    PrivilegeLevel, VirtAddr,
 };
 
 /// The size of each stack in bytes
 const STACK_SIZE: usize = 50 * 4096;
+/// The index into the TSS of the stack used for the [`DOUBLE_FAULT_STACK`].
+/// This is the first entry in the interrupt stack table so that it's always valid, even if other entries are changed.
+pub const DOUBLE_FAULT_STACK_INDEX: u16 = 0;
 /// The index into the TSS of the stack used for the [`INTERRUPTS_STACK`]
-pub const INTERRUPTS_STACK_INDEX: u16 = 0;
-/// The index into the TSS of the stack used for the [`DOUBLE_FAULT_STACK`]
-pub const DOUBLE_FAULT_STACK_INDEX: u16 = 1;
+pub const INTERRUPTS_STACK_INDEX: u16 = 1;
 
 /// An array of bytes which will be used as the stack for most interrupts and exceptions.
 /// The regular kernel stack is not used so that if it becomes invalid (e.g. if it overflows)
 /// then the kernel can still handle exceptions.
 static mut INTERRUPTS_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// An array of bytes which will be used as the stack when an interrupt or syscall moves the CPU from ring 3 to ring 0,
+/// if the interrupt doesn't have its own stack in the interrupt stack table.
+/// The CPU loads this from `RSP0` in the TSS, as the user program's stack can't be trusted.
+static mut KERNEL_ENTRY_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 /// An array of bytes which will be used as the stack for the double fault exception handler.
 /// This stack is separate from the regular kernel stack for the same reasons as [`INTERRUPTS_STACK`],
 /// and is also separate from [`INTERRUPTS_STACK`] as additional protection against triple faults.
@@ -38,15 +44,24 @@ pub unsafe fn init_gdt() {
     // so no other code can be reading or modifying `TSS` and `GDT` while these references exists
     let (tss, gdt) = unsafe { (&mut TSS, &mut GDT) };
 
-    tss.interrupt_stack_table[INTERRUPTS_STACK_INDEX as usize] =
-        // SAFETY: Rust code never reads or writes to `INTERRUPTS_STACK`, so the CPU can use it as a stack.
-        VirtAddr::from_ptr(unsafe { &INTERRUPTS_STACK }) + STACK_SIZE;
     tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] =
         // SAFETY: Rust code never reads or writes to `DOUBLE_FAULT_STACK`, so the CPU can use it as a stack.
         VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK }) + STACK_SIZE;
+    tss.interrupt_stack_table[INTERRUPTS_STACK_INDEX as usize] =
+        // SAFETY: Rust code never reads or writes to `INTERRUPTS_STACK`, so the CPU can use it as a stack.
+        VirtAddr::from_ptr(unsafe { &INTERRUPTS_STACK }) + STACK_SIZE;
+    tss.privilege_stack_table[0] =
+        // SAFETY: Rust code never reads or writes to `KERNEL_ENTRY_STACK`, so the CPU can use it as a stack.
+        VirtAddr::from_ptr(unsafe { &KERNEL_ENTRY_STACK }) + STACK_SIZE;
 
     let code_segment = gdt.add_entry(Descriptor::kernel_code_segment());
     let data_segment = gdt.add_entry(Descriptor::kernel_data_segment());
+    debug_assert_eq!(code_segment.index(), CODE_SEGMENT_INDEX);
+    debug_assert_eq!(data_segment.index(), DATA_SEGMENT_INDEX);
+    let user_data_segment = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_segment = gdt.add_entry(Descriptor::user_code_segment());
+    debug_assert_eq!(user_data_segment, user_data_selector());
+    debug_assert_eq!(user_code_segment, user_code_selector());
     let tss_segment = gdt.add_entry(Descriptor::tss_segment(tss));
     gdt.load();
 
@@ -67,6 +82,70 @@ pub unsafe fn init_gdt() {
     }
 }
 
+/// The index into the GDT of the kernel code segment added by [`init_gdt`]
+const CODE_SEGMENT_INDEX: u16 = 1;
+/// The index into the GDT of the kernel data segment added by [`init_gdt`]
+const DATA_SEGMENT_INDEX: u16 = 2;
+
+/// The index into the GDT of the user data segment added by [`init_gdt`]
+const USER_DATA_SEGMENT_INDEX: u16 = 3;
+/// The index into the GDT of the user code segment added by [`init_gdt`]
+const USER_CODE_SEGMENT_INDEX: u16 = 4;
+
+/// Gets the selector which user programs should use for their stack and data segments
+pub fn user_data_selector() -> SegmentSelector {
+    SegmentSelector::new(USER_DATA_SEGMENT_INDEX, PrivilegeLevel::Ring3)
+}
+
+/// Gets the selector which user programs should use for their code segment
+pub fn user_code_selector() -> SegmentSelector {
+    SegmentSelector::new(USER_CODE_SEGMENT_INDEX, PrivilegeLevel::Ring3)
+}
+
+/// Constructs and loads a GDT and TSS for an application processor, and sets its segment registers.
+///
+/// A TSS can only be loaded by one core at a time, so each AP gets its own TSS with its own interrupt stacks,
+/// in its own GDT. The GDT has the same layout as the one constructed by [`init_gdt`], so that the same segment selectors
+/// are valid on every core, and the kernel's IDT can be loaded on the AP.
+///
+/// # Safety
+/// [`init_gdt`] must have returned, and this function must only be called once on each application processor.
+pub unsafe fn load_ap_gdt() {
+    // The stacks are allocated rather than on the AP's own small stack, and are never freed as the AP uses them until it stops
+    let stack_top = || {
+        let stack = Box::leak(vec![0u8; STACK_SIZE].into_boxed_slice());
+        (VirtAddr::from_ptr(stack.as_ptr()) + STACK_SIZE).align_down(16u64)
+    };
+
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] = stack_top();
+    tss.interrupt_stack_table[INTERRUPTS_STACK_INDEX as usize] = stack_top();
+    tss.privilege_stack_table[0] = stack_top();
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_segment = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_segment = gdt.add_entry(Descriptor::kernel_data_segment());
+    debug_assert_eq!(code_segment.index(), CODE_SEGMENT_INDEX);
+    debug_assert_eq!(data_segment.index(), DATA_SEGMENT_INDEX);
+    gdt.add_entry(Descriptor::user_data_segment());
+    gdt.add_entry(Descriptor::user_code_segment());
+    let tss_segment = gdt.add_entry(Descriptor::tss_segment(tss));
+    gdt.load();
+
+    // SAFETY: The TSS is valid, and is only loaded on this core
+    unsafe {
+        load_tss(tss_segment);
+    }
+
+    // SAFETY: The registers point to valid entries in the GDT.
+    unsafe {
+        CS::set_reg(code_segment);
+        DS::set_reg(data_segment);
+        ES::set_reg(data_segment);
+        SS::set_reg(data_segment);
+    }
+}
+
 /// One of the stacks used by the kernel
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Stack {
@@ -74,6 +153,8 @@ pub enum Stack {
     InterruptHandler,
     /// The stack for the double fault interrupt handler
     DoubleFaultHandler,
+    /// The stack for interrupts and syscalls from user mode
+    KernelEntry,
     /// Another stack
     Other,
 }
@@ -86,13 +167,45 @@ pub fn get_stack(address: usize) -> Stack {
     // SAFETY: This is just getting an address, not reading or writing
     let double_fault_stack_pointer = unsafe { DOUBLE_FAULT_STACK.as_ptr() as usize };
 
+    // SAFETY: This is just getting an address, not reading or writing
+    let kernel_entry_stack_pointer = unsafe { KERNEL_ENTRY_STACK.as_ptr() as usize };
+
     if (interrupts_stack_pointer..interrupts_stack_pointer + STACK_SIZE).contains(&address) {
         Stack::InterruptHandler
     } else if (double_fault_stack_pointer..double_fault_stack_pointer + STACK_SIZE)
         .contains(&address)
     {
         Stack::DoubleFaultHandler
+    } else if (kernel_entry_stack_pointer..kernel_entry_stack_pointer + STACK_SIZE)
+        .contains(&address)
+    {
+        Stack::KernelEntry
     } else {
         Stack::Other
     }
 }
+
+/// Tests that the TSS's interrupt stack table entries point to the top of the right stacks.
+/// The stacks grow downwards, so the first byte pushed goes just below the entry.
+#[test_case]
+fn test_interrupt_stack_table() {
+    // SAFETY: `TSS` is only written by `init_gdt`, which has already returned
+    let interrupt_stack_table = unsafe { TSS.interrupt_stack_table };
+    let stack_of_entry = |index: u16| {
+        get_stack(
+            (interrupt_stack_table[usize::from(index)] - 1u64)
+                .as_u64()
+                .try_into()
+                .unwrap(),
+        )
+    };
+
+    assert_eq!(
+        stack_of_entry(DOUBLE_FAULT_STACK_INDEX),
+        Stack::DoubleFaultHandler
+    );
+    assert_eq!(
+        stack_of_entry(INTERRUPTS_STACK_INDEX),
+        Stack::InterruptHandler
+    );
+}
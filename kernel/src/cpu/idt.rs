@@ -3,23 +3,34 @@
 use acpica_bindings::types::{
     AcpiInterruptCallback, AcpiInterruptCallbackTag, AcpiInterruptHandledStatus,
 };
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
 use log::{trace, warn};
 use spin::Mutex;
-use x86_64::{structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode}, VirtAddr};
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::Page,
+    },
+    PrivilegeLevel, VirtAddr,
+};
 
 use crate::{
     cpu::interrupt_controllers::end_interrupt,
     global_state::KERNEL_STATE,
-    graphics::{flush, Colour, WRITER},
+    graphics::{blink_cursor, flush, update_mouse_cursor, Colour, WRITER},
     println,
-    scheduler::poll_tasks,
+    scheduler::{poll_tasks, watchdog},
+    serial,
+    syscall::{syscall_entry_address, SYSCALL_VECTOR},
 };
 // use crate::cpu::ps2::PS2_CONTROLLER;
 
 use super::{
     gdt::{DOUBLE_FAULT_STACK_INDEX, INTERRUPTS_STACK_INDEX},
     interrupt_controllers::PIC_1_OFFSET,
+    kernel_stack_guard_page,
     ps2::PS2_CONTROLLER,
 };
 
@@ -85,6 +96,106 @@ pub fn remove_interrupt_callback(
     }
 }
 
+/// The number of times each vector has been received by [`unknown_interrupt`]
+static INTERRUPT_COUNTS: [AtomicU64; 256] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+/// The number of spurious interrupts the local APIC has sent
+static SPURIOUS_INTERRUPT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Gets the number of times each vector has been received without a dedicated handler,
+/// including vectors with a handler registered using [`register_vector`] or ACPICA.
+pub fn interrupt_counts() -> [u64; 256] {
+    core::array::from_fn(|i| INTERRUPT_COUNTS[i].load(Ordering::Relaxed))
+}
+
+/// Gets the number of spurious interrupts the local APIC has sent
+pub fn spurious_interrupt_count() -> u64 {
+    SPURIOUS_INTERRUPT_COUNT.load(Ordering::Relaxed)
+}
+
+/// A handler registered with [`register_vector`]
+type VectorHandler = Box<dyn FnMut() + Send>;
+
+/// The handlers registered with [`register_vector`], indexed by interrupt vector
+static VECTOR_HANDLERS: Mutex<[Option<VectorHandler>; 256]> = {
+    const NO_HANDLER: Option<VectorHandler> = None;
+    Mutex::new([NO_HANDLER; 256])
+};
+
+/// An error which can occur when registering a handler with [`register_vector`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorRegisterError {
+    /// The vector is a CPU exception, or is used by one of the kernel's own handlers
+    ReservedVector,
+    /// Another handler is already registered for the vector
+    AlreadyRegistered,
+}
+
+/// A guard for a handler registered with [`register_vector`].
+/// When this is dropped, the handler is removed and interrupts on the vector are ignored again.
+#[derive(Debug)]
+#[must_use = "The handler is removed when the guard is dropped"]
+pub struct VectorHandlerGuard {
+    /// The vector the handler is registered for
+    vector: u8,
+}
+
+impl VectorHandlerGuard {
+    /// Gets the vector which the handler is registered for
+    #[allow(dead_code)] // Not yet used by any driver
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+impl Drop for VectorHandlerGuard {
+    fn drop(&mut self) {
+        // Disable interrupts so that the handler can't run on this core while the lock is held
+        without_interrupts(|| VECTOR_HANDLERS.lock()[self.vector as usize] = None);
+    }
+}
+
+/// Registers `handler` to be called whenever an interrupt is received on `vector`, for instance from a device using MSI.
+/// The interrupt controller is sent an EOI after the handler returns, so the handler doesn't need to do this itself.
+///
+/// The handler is called from an interrupt context, so it must not block or try to register or remove handlers.
+/// It stays registered until the returned [`VectorHandlerGuard`] is dropped.
+#[allow(dead_code)] // Not yet used by any driver
+pub fn register_vector(
+    vector: u8,
+    handler: impl FnMut() + Send + 'static,
+) -> Result<VectorHandlerGuard, VectorRegisterError> {
+    /// The vectors which have dedicated handlers, or whose handling is done by ACPICA's callbacks
+    const RESERVED_VECTORS: [InterruptIndex; 7] = [
+        InterruptIndex::Timer,
+        InterruptIndex::Ps2PrimaryPort,
+        InterruptIndex::Ps2SecondaryPort,
+        InterruptIndex::Serial,
+        InterruptIndex::AcpiSci,
+        InterruptIndex::Spurious,
+        InterruptIndex::Syscall,
+    ];
+
+    // The first 32 vectors are CPU exceptions
+    if vector < PIC_1_OFFSET || RESERVED_VECTORS.iter().any(|i| i.as_u8() == vector) {
+        return Err(VectorRegisterError::ReservedVector);
+    }
+
+    // Disable interrupts so that the handler can't run on this core while the lock is held
+    without_interrupts(|| {
+        let slot = &mut VECTOR_HANDLERS.lock()[vector as usize];
+        if slot.is_some() {
+            return Err(VectorRegisterError::AlreadyRegistered);
+        }
+
+        *slot = Some(Box::new(handler));
+        Ok(VectorHandlerGuard { vector })
+    })
+}
+
 /// Registers all normal interrupt handlers (which don't take an error code) to [`unknown_interrupt`].
 ///
 /// This is a macro rather than a normal loop because [`unknown_interrupt`] needs to take its vector number as a const generic.
@@ -204,6 +315,9 @@ pub unsafe fn init() {
             .set_handler_fn(breakpoint_handler)
             .set_stack_index(INTERRUPTS_STACK_INDEX);
 
+        // NMIs don't use an interrupt stack, as they can arrive while another handler is using one
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+
         idt.page_fault.set_handler_fn(page_fault_handler);
         // .set_stack_index(INTERRUPTS_STACK_INDEX);
 
@@ -227,6 +341,23 @@ pub unsafe fn init() {
         idt[InterruptIndex::Ps2SecondaryPort.as_usize()]
             .set_handler_fn(ps2_secondary_port_handler)
             .set_stack_index(INTERRUPTS_STACK_INDEX);
+        // Serial port interrupt
+        idt[InterruptIndex::Serial.as_usize()]
+            .set_handler_fn(serial_handler)
+            .set_stack_index(INTERRUPTS_STACK_INDEX);
+        // Local APIC spurious interrupt
+        idt[InterruptIndex::Spurious.as_usize()]
+            .set_handler_fn(spurious_interrupt_handler)
+            .set_stack_index(INTERRUPTS_STACK_INDEX);
+    }
+
+    // SAFETY: `syscall_entry_address` is the address of a valid interrupt handler.
+    // The handler doesn't use an interrupt stack, so that the CPU switches to the TSS's `RSP0` stack
+    // when a user program makes a syscall. User programs need to be able to trigger it, so it's usable from ring 3.
+    unsafe {
+        idt[InterruptIndex::Syscall.as_usize()]
+            .set_handler_addr(syscall_entry_address())
+            .set_privilege_level(PrivilegeLevel::Ring3);
     }
 
     // SAFETY: this is the only place this static is accessed, and it may only be accessed once.
@@ -236,6 +367,18 @@ pub unsafe fn init() {
     }
 }
 
+/// Loads the IDT constructed by [`init`] on an application processor.
+///
+/// # Safety
+/// [`init`] must have returned, and the AP must have loaded its own TSS with [`load_ap_gdt`],
+/// so that the IDT's interrupt stack indices refer to stacks which only this core uses.
+///
+/// [`load_ap_gdt`]: super::gdt::load_ap_gdt
+pub unsafe fn load_ap_idt() {
+    // SAFETY: `init` has returned, so `IDT` is no longer modified
+    unsafe { IDT.as_ref().unwrap().load() };
+}
+
 /// Gets a list of all currently registered interrupt handler functions.
 pub fn interrupt_handler_addresses() -> [VirtAddr; 256] {
     let mut addresses = [VirtAddr::new(0); 256];
@@ -274,6 +417,14 @@ pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Ps2PrimaryPort = PIC_1_OFFSET + 1,
     Ps2SecondaryPort = PIC_1_OFFSET + 2,
+    Serial = PIC_1_OFFSET + 4,
+    /// The ACPI _System Control Interrupt_, which is handled by ACPICA's callbacks in [`unknown_interrupt`].
+    /// This is the same vector the PIC uses for IRQ 9, which is the SCI on most systems.
+    AcpiSci = PIC_1_OFFSET + 9,
+    /// The local APIC's spurious interrupt vector, which is handled by [`spurious_interrupt_handler`].
+    Spurious = 0xFF,
+    /// The vector user programs use to make syscalls, which is handled by [`crate::syscall`].
+    Syscall = SYSCALL_VECTOR,
 }
 
 impl InterruptIndex {
@@ -301,14 +452,42 @@ impl InterruptIndex {
 extern "x86-interrupt" fn unknown_interrupt<const N: u8>(_: InterruptStackFrame) {
     /// A non-generic inner function - this stops all this code being monomorphized, which would waste memory
     fn inner(interrupt: u8) {
-        warn!(target: "unknown_interrupt", "Unknown interrupt - calling ACPICA callbacks {interrupt}");
+        INTERRUPT_COUNTS[interrupt as usize].fetch_add(1, Ordering::Relaxed);
+
+        if let Some(handler) = VECTOR_HANDLERS
+            .try_lock()
+            .as_mut()
+            .and_then(|handlers| handlers[interrupt as usize].as_mut())
+        {
+            handler();
+            return;
+        }
 
-        let callbacks = &mut ACPI_CALLBACKS.try_lock().unwrap()[interrupt as usize];
-        callbacks.retain_mut(|callback| {
+        if interrupt == InterruptIndex::AcpiSci.as_u8() {
+            crate::acpi::power_button::handle_sci();
+        }
+
+        let Some(mut callbacks) = ACPI_CALLBACKS.try_lock() else {
+            warn!(target: "unknown_interrupt", "ACPICA callbacks are locked - dropping interrupt {interrupt}");
+            return;
+        };
+        let callbacks = &mut callbacks[interrupt as usize];
+
+        if callbacks.is_empty() {
+            warn!(target: "unknown_interrupt", "Unknown interrupt {interrupt}");
+            return;
+        }
+
+        // Callbacks stay registered until ACPICA removes them with `remove_interrupt_callback`
+        let mut handled = false;
+        for callback in callbacks.iter_mut() {
             // SAFETY: This is the correct interrupt handler
-            let r = unsafe { callback.call() };
-            r != AcpiInterruptHandledStatus::Handled
-        });
+            handled |= unsafe { callback.call() } == AcpiInterruptHandledStatus::Handled;
+        }
+
+        if !handled {
+            trace!(target: "unknown_interrupt", "No ACPICA callback handled interrupt {interrupt}");
+        }
     }
 
     inner(N);
@@ -320,6 +499,14 @@ extern "x86-interrupt" fn unknown_interrupt<const N: u8>(_: InterruptStackFrame)
     }
 }
 
+/// Interrupt handler for the local APIC's spurious interrupt vector.
+///
+/// The APIC sends this vector when an interrupt is withdrawn before the CPU accepts it.
+/// It doesn't set the in-service bit for spurious interrupts, so this handler must not send an EOI.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    SPURIOUS_INTERRUPT_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
 /// Interrupt handler for any interrupt there is not a dedicated handler for, for interrupts with an error code
 extern "x86-interrupt" fn unknown_interrupt_with_error_code<const N: usize>(
     stack_frame: InterruptStackFrame,
@@ -356,10 +543,35 @@ extern "x86-interrupt" fn page_fault_handler(
         lock.set_colour(Colour::RED);
     }
 
-    println!("EXCEPTION: PAGE FAULT");
+    // CR2 holds the virtual address which the CPU was trying to access when the fault occurred
     let accessed_address = Cr2::read();
+
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+
+    let cause = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation on a present page"
+    } else {
+        "page not present"
+    };
+
+    println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", accessed_address);
+    println!("Access type: {access} ({cause})");
+    println!("Instruction Pointer: {:?}", stack_frame.instruction_pointer);
     println!("Error Code: {:?}", error_code);
+
+    if let Some(guard_page) = kernel_stack_guard_page() {
+        if guard_page == Page::containing_address(accessed_address) {
+            println!("The accessed address is in the kernel stack's guard page - the kernel stack has overflowed");
+        }
+    }
+
     println!("{:#?}", stack_frame);
     panic!("Page fault");
 }
@@ -398,6 +610,15 @@ extern "x86-interrupt" fn ps2_secondary_port_handler(_stack_frame: InterruptStac
     unsafe { end_interrupt(InterruptIndex::Ps2SecondaryPort.as_u8()) }
 }
 
+/// The interrupt handler which is called when data is received on the COM1 serial port
+extern "x86-interrupt" fn serial_handler(_stack_frame: InterruptStackFrame) {
+    serial::receive_pending();
+
+    // SAFETY:
+    // This function is a hardware interrupt handler, so it must tell the interrupt controller that the handler has completed before exiting.
+    unsafe { end_interrupt(InterruptIndex::Serial.as_u8()) }
+}
+
 /// The interrupt handler which is called when a double fault occurs, when a CPU exception occurs during an interrupt handler,
 /// or when an interrupt is raised which does not have an associated handler.
 /// If an exception happens inside the double fault handler, the CPU resets.
@@ -405,6 +626,9 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    #[cfg(test)]
+    crate::tests::double_fault_hook();
+
     if let Ok(mut lock) = WRITER.try_locked_if_init() {
         lock.set_colour(Colour::RED);
     }
@@ -417,11 +641,21 @@ extern "x86-interrupt" fn double_fault_handler(
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     KERNEL_STATE.increment_ticks();
 
-    if KERNEL_STATE.ticks() % 2 == 0 {
+    // Flush the screen about 50 times per second, regardless of the tick rate
+    if KERNEL_STATE.ticks() % (KERNEL_STATE.tick_hz() / 50).max(1) == 0 {
         // Ignore result
         let _ = flush();
     }
 
+    // Draw any mouse movement which couldn't be drawn in the mouse's interrupt handler
+    update_mouse_cursor();
+
+    // Blink the text cursor twice per second
+    if KERNEL_STATE.ticks() % (KERNEL_STATE.tick_hz() / 2).max(1) == 0 {
+        // Ignore result
+        let _ = blink_cursor();
+    }
+
     poll_tasks();
 
     // SAFETY:
@@ -431,11 +665,36 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+/// Handler for non-maskable interrupts, which are sent by the PIT to drive the [`watchdog`].
+/// NMIs don't need an EOI.
+extern "x86-interrupt" fn nmi_handler(_stack_frame: InterruptStackFrame) {
+    watchdog::tick();
+}
+
 /// Exception handler for when an invalid instruction is encountered
 extern "x86-interrupt" fn invalid_opcode(stack_frame: InterruptStackFrame) {
     panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
 }
 
+/// Tests that only one handler can be registered for a vector at once, and that dropping the guard removes it
+#[test_case]
+fn test_register_vector() {
+    assert_eq!(
+        register_vector(InterruptIndex::Timer.as_u8(), || ()).unwrap_err(),
+        VectorRegisterError::ReservedVector
+    );
+
+    let guard = register_vector(0xF0, || ()).unwrap();
+    assert_eq!(guard.vector(), 0xF0);
+    assert_eq!(
+        register_vector(0xF0, || ()).unwrap_err(),
+        VectorRegisterError::AlreadyRegistered
+    );
+
+    drop(guard);
+    let _guard = register_vector(0xF0, || ()).unwrap();
+}
+
 /// Tests that invoking an `int3` instruction does not panic
 #[test_case]
 fn test_breakpoint_no_panic() {
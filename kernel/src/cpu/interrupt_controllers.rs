@@ -1,13 +1,20 @@
 //! Code to manage different interrupt handlers
 
-use core::{fmt::Debug, sync::atomic::AtomicU64};
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
+use acpica_bindings::types::tables::madt::MadtRecord;
 use pic8259::ChainedPics;
 use spin::Mutex;
 use x86_64::instructions::interrupts::without_interrupts;
 
 use crate::{
-    acpi::{io_apic::IoApicRegisters, local_apic::LocalApicRegisters},
+    acpi::{
+        io_apic::IoApicRegisters, local_apic::LocalApicRegisters, sci_interrupt,
+        InterruptActiveState, InterruptTriggerMode,
+    },
     cpu::idt::InterruptIndex,
     global_state::KERNEL_STATE,
 };
@@ -56,6 +63,15 @@ unsafe impl Send for InterruptController {}
 
 /// The currently enabled interrupt controller
 static CURRENT_CONTROLLER: Mutex<InterruptController> = Mutex::new(InterruptController::None);
+/// The system's I/O APIC, once [`init_io_apic`] has been called
+static IO_APIC: Mutex<Option<IoApicRegisters>> = Mutex::new(None);
+/// Which vectors the I/O APIC sends level-triggered interrupts on.
+/// [`end_interrupt`] sends an EOI to the I/O APIC as well as the local APIC for these vectors.
+static LEVEL_TRIGGERED_VECTORS: [AtomicBool; 256] = {
+    const EDGE: AtomicBool = AtomicBool::new(false);
+    [EDGE; 256]
+};
+
 /// The number of calls to [`end_interrupt`] while the PIC was the active controller
 pub static PIC_EOI: AtomicU64 = AtomicU64::new(0);
 /// The number of calls to [`end_interrupt`] while the APIC was the active controller
@@ -81,6 +97,13 @@ pub unsafe fn end_interrupt(interrupt_id: u8) {
             APIC_EOI.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
             // SAFETY: This function is called from an interrupt handler
             unsafe { apic.notify_end_of_interrupt() }
+
+            if LEVEL_TRIGGERED_VECTORS[interrupt_id as usize].load(Ordering::Relaxed) {
+                if let Some(io_apic) = IO_APIC.try_lock().as_mut().and_then(|i| i.as_mut()) {
+                    // SAFETY: This function is called from the handler for a level-triggered interrupt from the I/O APIC
+                    unsafe { io_apic.end_of_interrupt(interrupt_id) }
+                }
+            }
         }
     }
 }
@@ -139,10 +162,16 @@ pub unsafe fn init_local_apic() -> Result<(), ()> {
         };
 
         // SAFETY: The IDT is set up so the CPU can receive interrupts.
-        unsafe { local_apic.enable(0xFF) };
+        unsafe { local_apic.enable(InterruptIndex::Spurious.as_u8()) };
+
+        // SAFETY: The timer hasn't been started yet, and nothing else uses channel 2 of the PIT.
+        // Interrupts are disabled.
+        unsafe { local_apic.calibrate_timer() };
 
         // SAFETY: This interrupt vector is set up to receive timer interrupts
-        unsafe { local_apic.enable_timer(InterruptIndex::Timer.as_u8() as _) };
+        unsafe {
+            local_apic.enable_timer(InterruptIndex::Timer.as_u8() as _, KERNEL_STATE.tick_hz())
+        };
 
         // local_apic.debug_registers();
 
@@ -152,21 +181,54 @@ pub unsafe fn init_local_apic() -> Result<(), ()> {
     Ok(())
 }
 
+/// An error which can occur when changing the tick rate using [`set_tick_hz`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetTickHzError {
+    /// The current interrupt controller is not a local APIC, so the timer can't be configured
+    NoLocalApic,
+    /// The given frequency was 0 or too high for the timer to represent
+    InvalidFrequency,
+}
+
+/// Sets how many timer interrupts occur per second.
+/// This changes the rate at which [`ticks`] increases and tasks are polled.
+///
+/// [`ticks`]: crate::global_state::KernelState::ticks
+pub fn set_tick_hz(hz: usize) -> Result<(), SetTickHzError> {
+    if LocalApicRegisters::timer_initial_count(hz).is_none() {
+        return Err(SetTickHzError::InvalidFrequency);
+    }
+
+    // Disable interrupts so that no ticks happen between changing the timer and updating the kernel state
+    without_interrupts(|| match *CURRENT_CONTROLLER.lock() {
+        InterruptController::None | InterruptController::Pic(_) => Err(SetTickHzError::NoLocalApic),
+        InterruptController::LocalApic(ref mut apic) => {
+            // SAFETY: The timer was enabled when the local APIC was initialised
+            unsafe { apic.set_timer_frequency(hz) };
+            KERNEL_STATE.set_tick_hz(hz);
+            Ok(())
+        }
+    })
+}
+
 /// Initialises the I/O APIC, and sets interrupts from PS/2 devices to be sent to this core.
 ///
 /// # Safety
 /// * This function may only be called once on the whole system,
 /// unlike [`init_local_apic`] which may be called once per core.
 /// * The core which this function is called on must be set up to receive interrupts from PS/2 devices
-/// on their respective [`InterruptIndex`]es.
+/// and the serial port on their respective [`InterruptIndex`]es.
 ///
 /// # Panics
 /// If this core's local APIC is not set up, i.e. if [`init_local_apic`] hasn't been called
 pub unsafe fn init_io_apic() -> Result<(), ()> {
-    let acpica = KERNEL_STATE.acpica.lock();
-    let madt = acpica.madt();
-
-    let io_apic_addr = madt.io_apic_address().unwrap().into();
+    let io_apic_addr = KERNEL_STATE
+        .acpica
+        .lock()
+        .madt()
+        .io_apic_address()
+        .unwrap()
+        .into();
 
     // SAFETY: The pointer was fetched from ACPI tables so it must be valid.
     // This function is only called once so `IoApicRegisters::new` will only be called once.
@@ -185,11 +247,203 @@ pub unsafe fn init_io_apic() -> Result<(), ()> {
         io_apic
             .set_ps2_secondary_port_interrupt(id, InterruptIndex::Ps2SecondaryPort.as_u8())
             .unwrap();
+        io_apic
+            .set_serial_interrupt(id, InterruptIndex::Serial.as_u8())
+            .unwrap();
+
+        if let Some(sci) = sci_interrupt() {
+            // The SCI is an ISA IRQ if it is below 16, in which case the MADT may override it
+            let route = match u8::try_from(sci) {
+                Ok(irq) if irq < 16 => isa_irq_route(
+                    irq,
+                    InterruptTriggerMode::LevelTriggered,
+                    InterruptActiveState::ActiveLow,
+                ),
+                _ => IsaIrqRoute {
+                    gsi: sci,
+                    trigger_mode: InterruptTriggerMode::LevelTriggered,
+                    active_state: InterruptActiveState::ActiveLow,
+                },
+            };
+
+            // Record the trigger mode before the interrupt can be sent, so that the first interrupt is acknowledged properly
+            LEVEL_TRIGGERED_VECTORS[InterruptIndex::AcpiSci.as_usize()].store(
+                route.trigger_mode == InterruptTriggerMode::LevelTriggered,
+                Ordering::Relaxed,
+            );
+            io_apic
+                .set_acpi_sci_interrupt(
+                    id,
+                    route.gsi.try_into().unwrap(),
+                    InterruptIndex::AcpiSci.as_u8(),
+                    route.trigger_mode,
+                    route.active_state,
+                )
+                .unwrap();
+        }
     }
 
+    without_interrupts(|| *IO_APIC.lock() = Some(io_apic));
+
     Ok(())
 }
 
+/// How an ISA IRQ is connected to the I/O APIC, found using [`isa_irq_route`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaIrqRoute {
+    /// The global system interrupt which the IRQ is connected to
+    pub gsi: u32,
+    /// Whether the interrupt is edge- or level-triggered
+    pub trigger_mode: InterruptTriggerMode,
+    /// Whether the interrupt is active high or low
+    pub active_state: InterruptActiveState,
+}
+
+/// Finds which global system interrupt the ISA IRQ `irq` is connected to, and its trigger mode and polarity,
+/// from the MADT's interrupt source overrides.
+///
+/// If the MADT doesn't override the IRQ, it is connected to the GSI with the same number.
+/// `default_trigger_mode` and `default_active_state` are used if there is no override,
+/// or if the override says that they conform to the bus's specifications.
+pub fn isa_irq_route(
+    irq: u8,
+    default_trigger_mode: InterruptTriggerMode,
+    default_active_state: InterruptActiveState,
+) -> IsaIrqRoute {
+    let acpica = KERNEL_STATE.acpica.lock();
+    let source_override = acpica.madt().records().find_map(|record| match record {
+        MadtRecord::IoApicInterruptSourceOverride(source_override)
+            if source_override.irq_source == irq =>
+        {
+            Some(source_override)
+        }
+        _ => None,
+    });
+
+    let Some(source_override) = source_override else {
+        return IsaIrqRoute {
+            gsi: irq.into(),
+            trigger_mode: default_trigger_mode,
+            active_state: default_active_state,
+        };
+    };
+
+    // Bits 0-1 of the flags are the polarity, and bits 2-3 are the trigger mode.
+    // For both, 0b00 means the bus's default, and 0b10 is reserved.
+    let active_state = match source_override.flags & 0b11 {
+        0b01 => InterruptActiveState::ActiveHigh,
+        0b11 => InterruptActiveState::ActiveLow,
+        _ => default_active_state,
+    };
+    let trigger_mode = match (source_override.flags >> 2) & 0b11 {
+        0b01 => InterruptTriggerMode::EdgeTriggered,
+        0b11 => InterruptTriggerMode::LevelTriggered,
+        _ => default_trigger_mode,
+    };
+
+    IsaIrqRoute {
+        gsi: source_override.global_system_interrupt,
+        trigger_mode,
+        active_state,
+    }
+}
+
+/// An error which can occur when routing an interrupt using [`route_interrupt`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteInterruptError {
+    /// The I/O APIC hasn't been initialised with [`init_io_apic`]
+    NoIoApic,
+    /// The current interrupt controller is not a local APIC, so interrupts can't be routed to it
+    NoLocalApic,
+    /// The I/O APIC rejected the redirection entry
+    InvalidEntry,
+}
+
+/// Routes the global system interrupt `gsi` to interrupt number `vector` on the core this function is called on,
+/// with the given trigger mode and polarity. This is used for interrupts which don't have a fixed [`InterruptIndex`],
+/// such as legacy PCI `INTx` interrupts, which are level-triggered and active-low.
+///
+/// If the interrupt is level-triggered, [`end_interrupt`] will also send an EOI to the I/O APIC for `vector`.
+///
+/// # Safety
+/// The core this function is called on must be set up to receive interrupts on `vector` from this source.
+#[allow(dead_code)] // Not yet used by any driver
+pub unsafe fn route_interrupt(
+    gsi: u8,
+    vector: u8,
+    trigger_mode: InterruptTriggerMode,
+    active_state: InterruptActiveState,
+) -> Result<(), RouteInterruptError> {
+    let id = current_apic_id()
+        .ok_or(RouteInterruptError::NoLocalApic)?
+        .try_into()
+        .unwrap();
+
+    without_interrupts(|| {
+        let mut io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_mut().ok_or(RouteInterruptError::NoIoApic)?;
+
+        // Record the trigger mode before the interrupt can be sent, so that the first interrupt is acknowledged properly
+        LEVEL_TRIGGERED_VECTORS[vector as usize].store(
+            trigger_mode == InterruptTriggerMode::LevelTriggered,
+            Ordering::Relaxed,
+        );
+
+        // SAFETY: The caller guarantees that this core is set up to receive the interrupt
+        unsafe { io_apic.set_redirection(gsi, vector, trigger_mode, active_state, id) }
+            .map_err(|()| RouteInterruptError::InvalidEntry)
+    })
+}
+
+/// Routes the global system interrupt `gsi` to the core this function is called on as a _non-maskable interrupt_.
+///
+/// # Safety
+/// The core this function is called on must be set up to receive NMIs from this source.
+pub unsafe fn route_nmi(gsi: u8) -> Result<(), RouteInterruptError> {
+    let id = current_apic_id()
+        .ok_or(RouteInterruptError::NoLocalApic)?
+        .try_into()
+        .unwrap();
+
+    without_interrupts(|| {
+        let mut io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_mut().ok_or(RouteInterruptError::NoIoApic)?;
+
+        // SAFETY: The caller guarantees that this core is set up to receive the NMI
+        unsafe { io_apic.set_nmi(gsi, id) }.map_err(|()| RouteInterruptError::InvalidEntry)
+    })
+}
+
+/// An error indicating that an operation needs a local APIC, but the current interrupt controller is not a local APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoLocalApicError;
+
+/// Runs `f` with this core's local APIC, with interrupts disabled.
+fn with_local_apic<T>(f: impl FnOnce(&mut LocalApicRegisters) -> T) -> Result<T, NoLocalApicError> {
+    without_interrupts(|| match *CURRENT_CONTROLLER.lock() {
+        InterruptController::None | InterruptController::Pic(_) => Err(NoLocalApicError),
+        InterruptController::LocalApic(ref mut apic) => Ok(f(apic)),
+    })
+}
+
+/// Sends an INIT IPI to the core with the given local APIC ID.
+///
+/// # Safety
+/// The destination core must not be this core, and must not be running any code which the kernel relies on.
+pub unsafe fn send_init_ipi(apic_id: u8) -> Result<(), NoLocalApicError> {
+    // SAFETY: The caller guarantees that the core can be reset
+    with_local_apic(|apic| unsafe { apic.send_init_ipi(apic_id) })
+}
+
+/// Sends a startup IPI to the core with the given local APIC ID, making it start executing at physical address `page << 12`.
+///
+/// # Safety
+/// The destination core must have just been sent an INIT IPI, and there must be valid real mode code at the start of `page`.
+pub unsafe fn send_startup_ipi(apic_id: u8, page: u8) -> Result<(), NoLocalApicError> {
+    // SAFETY: The caller guarantees that there is code for the core to execute
+    with_local_apic(|apic| unsafe { apic.send_startup_ipi(apic_id, page) })
+}
+
 /// Sends an interrupt to the core this function is called from with the given vector
 ///
 /// # Safety
@@ -2,25 +2,36 @@
 //! This includes managing the GDT and IDT, configuring the PICs, and loading interrupt handlers.
 
 // pub mod allocator;
+pub mod features;
 mod frame_allocator;
 pub mod gdt;
 mod idt;
 pub mod interrupt_controllers;
+pub mod pat;
+pub mod pit;
 pub mod ps2;
+pub mod smp;
 
 pub use frame_allocator::BootInfoFrameAllocator;
 pub use idt::{
-    register_interrupt_callback, remove_interrupt_callback, CallbackAddError, CallbackRemoveError,
-    interrupt_handler_addresses
+    interrupt_counts, interrupt_handler_addresses, register_interrupt_callback, register_vector,
+    remove_interrupt_callback, spurious_interrupt_count, CallbackAddError, CallbackRemoveError,
+    InterruptIndex, VectorHandlerGuard, VectorRegisterError,
 };
 
+use alloc::vec::Vec;
 use bootloader_api::info::MemoryRegions;
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::warn;
 use x86_64::structures::paging::PhysFrame;
 
 use x86_64::structures::paging::{
-    frame::PhysFrameRange, page::PageRange, FrameAllocator, Mapper, OffsetPageTable, Page,
-    PageTable, PageTableFlags,
+    frame::PhysFrameRange,
+    mapper::{MapToError, MappedFrame, TranslateResult},
+    page::PageRange,
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, Size2MiB,
+    Translate,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
@@ -56,14 +67,167 @@ const PHYSICAL_MEMORY_ACCESS_START: u64 = 0x5000_0000_0000;
 /// TODO: check that these address ranges are free
 const PHYSICAL_MEMORY_ACCESS_MAX_SIZE: u64 = 25 * 1024 * 1024; // 25 MiFrames = 100 GiB
 
+/// A region of physical memory which has been mapped into virtual memory by [`PhysicalMemoryAccessor::map_region`].
+/// When this struct is dropped, the mapping is deleted.
+#[derive(Debug)]
+pub struct MappedRegion {
+    /// The virtual address where the start of the region is mapped.
+    /// This has the same offset into its page as the physical address of the region.
+    start: VirtAddr,
+    /// The length of the region in bytes
+    len: usize,
+}
+
+impl MappedRegion {
+    /// Reconstructs a [`MappedRegion`] from a pointer returned by [`leak`][Self::leak].
+    ///
+    /// # Safety
+    /// * `ptr` must have been returned by [`leak`][Self::leak] on a [`MappedRegion`] of length `len`
+    /// * The region must not have been reconstructed since it was leaked
+    pub unsafe fn from_raw_parts(ptr: *mut u8, len: usize) -> Self {
+        Self {
+            start: VirtAddr::new(ptr as u64),
+            len,
+        }
+    }
+
+    /// Consumes the [`MappedRegion`] without unmapping it, returning a pointer to the start of the region.
+    /// The region can be unmapped later by passing the pointer to [`from_raw_parts`][Self::from_raw_parts] and dropping the result.
+    pub fn leak(self) -> *mut u8 {
+        let ptr = self.as_mut_ptr();
+        core::mem::forget(self);
+        ptr
+    }
+
+    /// Gets the virtual address where the start of the region is mapped
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Gets a pointer to the start of the region
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.start.as_ptr()
+    }
+
+    /// Gets a mutable pointer to the start of the region
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+
+    /// Gets the pages which the region is mapped into
+    fn pages(&self) -> PageRange {
+        let start = Page::containing_address(self.start);
+        let end = Page::containing_address(self.start + (self.len.max(1) as u64 - 1)) + 1;
+
+        Page::range(start, end)
+    }
+}
+
+impl Drop for MappedRegion {
+    fn drop(&mut self) {
+        // SAFETY: The pages were mapped by `map_region`, and the region is no longer in use
+        unsafe {
+            KERNEL_STATE
+                .physical_memory_accessor
+                .lock()
+                .unmap_frames(self.pages());
+        }
+    }
+}
+
+/// The number of 4KiB frames in a 2MiB huge page
+const FRAMES_PER_HUGE_PAGE: u64 = 512;
+
 /// Helper struct for accessing physical addresses
 #[derive(Debug)]
 pub struct PhysicalMemoryAccessor {
     /// The index of the next virtual frame to be allocated from [`PHYSICAL_MEMORY_ACCESS_START`]
     next_frame: u64,
+    /// Ranges of virtual frames below [`next_frame`] which have been unmapped and can be reused,
+    /// as a start index and a length in frames.
+    /// The ranges are sorted by start index, and adjacent ranges are merged.
+    ///
+    /// [`next_frame`]: PhysicalMemoryAccessor::next_frame
+    free_ranges: Vec<(u64, u64)>,
 }
 
 impl PhysicalMemoryAccessor {
+    /// Constructs a new [`PhysicalMemoryAccessor`] with no frames mapped
+    const fn new() -> Self {
+        Self {
+            next_frame: 0,
+            free_ranges: Vec::new(),
+        }
+    }
+
+    /// Finds `num_frames` consecutive unused virtual frames, and returns the index of the first one.
+    /// Previously unmapped frames are reused before [`next_frame`] is increased.
+    ///
+    /// [`next_frame`]: PhysicalMemoryAccessor::next_frame
+    fn allocate_virtual_frames(&mut self, num_frames: u64) -> u64 {
+        let reusable = self
+            .free_ranges
+            .iter()
+            .position(|&(_, len)| len >= num_frames);
+
+        if let Some(i) = reusable {
+            let (start, len) = self.free_ranges[i];
+
+            if len == num_frames {
+                self.free_ranges.remove(i);
+            } else {
+                self.free_ranges[i] = (start + num_frames, len - num_frames);
+            }
+
+            return start;
+        }
+
+        let start = self.next_frame;
+        self.next_frame += num_frames;
+
+        if self.next_frame >= PHYSICAL_MEMORY_ACCESS_MAX_SIZE {
+            panic!("Used up MMIO mapping space");
+        }
+
+        start
+    }
+
+    /// Marks `num_frames` virtual frames starting at index `start` as unused, so that they can be reused
+    /// by [`allocate_virtual_frames`].
+    ///
+    /// This may allocate, so the [page table][crate::KernelState::page_table] must not be locked
+    /// as the heap allocator may need to lock it to grow the heap.
+    ///
+    /// [`allocate_virtual_frames`]: PhysicalMemoryAccessor::allocate_virtual_frames
+    fn free_virtual_frames(&mut self, mut start: u64, mut num_frames: u64) {
+        let mut i = self.free_ranges.partition_point(|&(s, _)| s < start);
+
+        // Merge with the following range if they are adjacent
+        if let Some(&(next_start, next_len)) = self.free_ranges.get(i) {
+            if start + num_frames == next_start {
+                num_frames += next_len;
+                self.free_ranges.remove(i);
+            }
+        }
+
+        // Merge with the preceding range if they are adjacent
+        if let Some(&(prev_start, prev_len)) = i.checked_sub(1).map(|i| &self.free_ranges[i]) {
+            if prev_start + prev_len == start {
+                start = prev_start;
+                num_frames += prev_len;
+                i -= 1;
+                self.free_ranges.remove(i);
+            }
+        }
+
+        // If the range is at the end of the used frames, shrink the used frames instead of storing it
+        if start + num_frames == self.next_frame {
+            self.next_frame = start;
+        } else {
+            self.free_ranges.insert(i, (start, num_frames));
+        }
+    }
+
     /// Maps the given page range into virtual memory and returns the address where they were mapped
     ///
     /// # Safety
@@ -73,17 +237,12 @@ impl PhysicalMemoryAccessor {
             PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
 
         let num_frames = frames.end - frames.start;
-        let mut page_table = KERNEL_STATE.page_table.lock();
-        let mut frame_allocator = KERNEL_STATE.frame_allocator.lock();
-
         let start_virtual_page =
-            Page::containing_address(VirtAddr::new(PHYSICAL_MEMORY_ACCESS_START)) + self.next_frame;
-
-        self.next_frame += frames.end - frames.start;
+            Page::containing_address(VirtAddr::new(PHYSICAL_MEMORY_ACCESS_START))
+                + self.allocate_virtual_frames(num_frames);
 
-        if self.next_frame >= PHYSICAL_MEMORY_ACCESS_MAX_SIZE {
-            panic!("Used up MMIO mapping space");
-        }
+        let mut page_table = KERNEL_STATE.page_table.lock();
+        let mut frame_allocator = KERNEL_STATE.frame_allocator.lock();
 
         let start_physical_page = frames.start;
 
@@ -119,24 +278,181 @@ impl PhysicalMemoryAccessor {
         }
     }
 
-    /// Unmaps an area of memory which was mapped using [`map_frames`][Self::map_frames].
+    /// Maps the given page range into virtual memory and returns the address where they were mapped.
+    /// Unlike [`map_frames`], this uses 2MiB huge pages for any parts of the range which are 2MiB-aligned and at least 2MiB long,
+    /// which is faster and uses fewer page table entries for large MMIO regions.
+    /// The rest of the range is mapped using 4KiB pages.
+    ///
+    /// The returned range can be unmapped with [`unmap_frames`].
     ///
     /// # Safety
-    /// * `pages` must be a page range which was allocated using [`map_frames`][Self::map_frames].
-    /// * The pages will be unmapped, so any pointers derived from them will cease to be valid.
-    pub unsafe fn unmap_frames(&mut self, pages: PageRange) {
+    /// The memory in `frames` must not be being used by other code
+    ///
+    /// [`map_frames`]: PhysicalMemoryAccessor::map_frames
+    /// [`unmap_frames`]: PhysicalMemoryAccessor::unmap_frames
+    pub unsafe fn map_frames_huge(&mut self, frames: PhysFrameRange) -> PageRange {
+        let num_frames = frames.end - frames.start;
+        let start_index = frames.start.start_address().as_u64() / 4096;
+        // The number of frames before the first 2MiB-aligned frame in the range
+        let unaligned_frames = start_index.next_multiple_of(FRAMES_PER_HUGE_PAGE) - start_index;
+
+        // If there are no huge pages in the range, map it normally
+        if num_frames < unaligned_frames + FRAMES_PER_HUGE_PAGE {
+            // SAFETY: The caller guarantees that the memory is not being used
+            return unsafe { self.map_frames(frames) };
+        }
+
+        // The virtual range needs to have the same offset into a huge page as the physical range,
+        // so allocate enough extra frames to align it and then free the frames either side.
+        let padded_start = self.allocate_virtual_frames(num_frames + FRAMES_PER_HUGE_PAGE - 1);
+        let offset = (start_index + FRAMES_PER_HUGE_PAGE - padded_start % FRAMES_PER_HUGE_PAGE)
+            % FRAMES_PER_HUGE_PAGE;
+        let virtual_start = padded_start + offset;
+
+        if offset != FRAMES_PER_HUGE_PAGE - 1 {
+            self.free_virtual_frames(
+                virtual_start + num_frames,
+                FRAMES_PER_HUGE_PAGE - 1 - offset,
+            );
+        }
+        if offset != 0 {
+            self.free_virtual_frames(padded_start, offset);
+        }
+
+        let start_virtual_page =
+            Page::containing_address(VirtAddr::new(PHYSICAL_MEMORY_ACCESS_START)) + virtual_start;
+
+        let flags: PageTableFlags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+
         let mut page_table = KERNEL_STATE.page_table.lock();
+        let mut frame_allocator = KERNEL_STATE.frame_allocator.lock();
+
+        let mut i = 0;
+        while i < num_frames {
+            let frame = frames.start + i;
+            let page = start_virtual_page + i;
 
+            if frame.start_address().is_aligned(Size2MiB::SIZE)
+                && num_frames - i >= FRAMES_PER_HUGE_PAGE
+            {
+                let huge_frame = PhysFrame::<Size2MiB>::containing_address(frame.start_address());
+                let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+
+                // SAFETY: This virtual page has not been used yet.
+                // It is the caller's responsibility to make sure the physical frame is valid.
+                let result = unsafe {
+                    page_table.map_to(
+                        huge_page,
+                        huge_frame,
+                        flags | PageTableFlags::HUGE_PAGE,
+                        &mut *frame_allocator,
+                    )
+                };
+
+                match result {
+                    Ok(flush) => {
+                        flush.flush();
+                        i += FRAMES_PER_HUGE_PAGE;
+                        continue;
+                    }
+                    // Part of this huge page was previously mapped using 4KiB pages, so a page table still exists for it.
+                    // Map this part of the range using 4KiB pages as well.
+                    Err(MapToError::PageAlreadyMapped(_)) => (),
+                    Err(e) => panic!("Failed to map huge page: {e:?}"),
+                }
+            }
+
+            // SAFETY: This virtual frame has not been used yet.
+            // It is the caller's responsibility to make sure the physical frame is valid.
+            unsafe {
+                page_table
+                    .map_to(page, frame, flags, &mut *frame_allocator)
+                    .unwrap()
+                    .flush();
+            }
+            i += 1;
+        }
+
+        PageRange {
+            start: start_virtual_page,
+            end: start_virtual_page + num_frames,
+        }
+    }
+
+    /// Unmaps an area of memory which was mapped using [`map_frames`][Self::map_frames] or [`map_frames_huge`][Self::map_frames_huge].
+    ///
+    /// # Safety
+    /// * `pages` must be a page range which was allocated using [`map_frames`][Self::map_frames] or [`map_frames_huge`][Self::map_frames_huge].
+    /// * The pages will be unmapped, so any pointers derived from them will cease to be valid.
+    pub unsafe fn unmap_frames(&mut self, pages: PageRange) {
         debug_assert!(pages.start.start_address().as_u64() >= PHYSICAL_MEMORY_ACCESS_START);
         debug_assert!(
             pages.end.start_address().as_u64()
                 < PHYSICAL_MEMORY_ACCESS_START + PHYSICAL_MEMORY_ACCESS_MAX_SIZE * 4096
         );
 
-        for page in pages {
-            // SAFETY: This page is within the physical memory access range and is no longer used
-            page_table.unmap(page).unwrap().1.flush();
+        {
+            let mut page_table = KERNEL_STATE.page_table.lock();
+
+            let mut page = pages.start;
+            while page < pages.end {
+                let translation = page_table.translate(page.start_address());
+
+                if let TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(_),
+                    ..
+                } = translation
+                {
+                    let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+
+                    debug_assert_eq!(huge_page.start_address(), page.start_address());
+                    debug_assert!(page + FRAMES_PER_HUGE_PAGE <= pages.end);
+
+                    // SAFETY: This page is within the physical memory access range and is no longer used
+                    page_table.unmap(huge_page).unwrap().1.flush();
+                    page += FRAMES_PER_HUGE_PAGE;
+                } else {
+                    // SAFETY: This page is within the physical memory access range and is no longer used
+                    page_table.unmap(page).unwrap().1.flush();
+                    page += 1;
+                }
+            }
         }
+
+        let start =
+            pages.start - Page::containing_address(VirtAddr::new(PHYSICAL_MEMORY_ACCESS_START));
+        self.free_virtual_frames(start, pages.end - pages.start);
+    }
+
+    /// Maps `len` bytes of physical memory starting at `address` into virtual memory.
+    /// The memory is unmapped when the returned [`MappedRegion`] is dropped.
+    ///
+    /// # Safety
+    /// * Physical memory or an MMIO mapping must exist for all pages spanned in the range `address .. address + len`
+    /// * The memory must not be being used by other code
+    pub unsafe fn map_region(&mut self, address: PhysAddr, len: usize) -> MappedRegion {
+        let start = PhysFrame::containing_address(address);
+        let end = PhysFrame::containing_address(address + (len.max(1) as u64 - 1)) + 1;
+
+        // SAFETY: The caller guarantees that the memory is valid and not in use
+        let pages = unsafe { self.map_frames_huge(PhysFrameRange { start, end }) };
+
+        MappedRegion {
+            // `pages` starts on the page boundary before `address`, so add the offset into the page
+            start: pages.start.start_address() + address.as_u64() % 4096,
+            len,
+        }
+    }
+
+    /// Unmaps a [`MappedRegion`].
+    /// This does the same as dropping the region, but can be used while the [`PhysicalMemoryAccessor`] is locked.
+    pub fn unmap_region(&mut self, region: MappedRegion) {
+        let pages = region.pages();
+        core::mem::forget(region);
+
+        // SAFETY: The pages were mapped by `map_region`, and `region` has been consumed so they are no longer in use
+        unsafe { self.unmap_frames(pages) }
     }
 
     /// Maps `len` bytes of physical memory starting at `address` into virtual memory,
@@ -150,46 +466,88 @@ impl PhysicalMemoryAccessor {
     where
         F: FnOnce(*mut ()) -> T,
     {
-        let frame = PhysFrame::containing_address(address);
-
-        // SAFETY: `address` is valid for `len` bytes of
-        let mapping = unsafe {
-            self.map_frames(PhysFrameRange {
-                start: frame,
-                end: frame + len.div_ceil(4096).try_into().unwrap(),
-            })
-        };
-
-        let addr_usize: usize = address.as_u64().try_into().unwrap();
-
-        // `mapping` starts on the page boundary before the given `address`, so increase the pointer to reach the target `address`
-        //
-        // SAFETY: Something exists in the address space for all pages in the given range,
-        // which means constructing this pointer is valid
-        let ptr = unsafe {
-            mapping
-                .start
-                .start_address()
-                .as_mut_ptr::<()>()
-                .byte_add(addr_usize % 4096)
-        };
-
-        let v = f(ptr);
-
-        // SAFETY: These frames were just allocated with `map_frames`s
-        unsafe {
-            self.unmap_frames(mapping);
-        }
+        // SAFETY: The caller guarantees that the memory is valid
+        let mapping = unsafe { self.map_region(address, len) };
+
+        let v = f(mapping.as_mut_ptr());
+
+        // Dropping `mapping` would lock the `PhysicalMemoryAccessor` again, so unmap it explicitly
+        self.unmap_region(mapping);
 
         v
     }
 }
 
+/// Prints how the kernel's page table translates the given virtual address:
+/// the entry used at each level of the page table, followed by the physical address the page is mapped to.
+pub fn print_translation(addr: VirtAddr) {
+    let page_table = KERNEL_STATE.page_table.lock();
+    let phys_offset = page_table.phys_offset();
+
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table: &PageTable = page_table.level_4_table();
+    for (level, index) in (1..=4).rev().zip(indices) {
+        let entry = &table[index];
+        let flags = entry.flags();
+
+        println!(
+            "Level {level} entry {:>3}: {:#x} {flags:?}",
+            u16::from(index),
+            entry.addr()
+        );
+
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(PageTableFlags::HUGE_PAGE)
+            || level == 1
+        {
+            break;
+        }
+
+        // SAFETY: All of physical memory is mapped at `phys_offset`,
+        // and the entry is present and not a huge page, so it points to the next level page table.
+        table = unsafe { &*(phys_offset + entry.addr().as_u64()).as_ptr() };
+    }
+
+    match page_table.translate(addr) {
+        TranslateResult::Mapped { frame, offset, .. } => println!(
+            "{addr:#x} is mapped to {:#x} ({} byte page)",
+            frame.start_address() + offset,
+            frame.size()
+        ),
+        TranslateResult::NotMapped => println!("{addr:#x} is not mapped"),
+        TranslateResult::InvalidFrameAddress(frame) => {
+            println!("{addr:#x} is mapped to an invalid frame address {frame:#x}")
+        }
+    }
+}
+
 /// The size in frames of the kernel stack
 const KERNEL_STACK_SIZE: u64 = 100;
 
+/// The address of the guard page below the kernel stack, or 0 if [`init_kernel_stack`] hasn't placed one.
+static KERNEL_STACK_GUARD_PAGE: AtomicU64 = AtomicU64::new(0);
+
+/// Gets the page which is left unmapped below the kernel stack by [`init_kernel_stack`],
+/// or [`None`] if no guard page has been placed.
+pub fn kernel_stack_guard_page() -> Option<Page> {
+    match KERNEL_STACK_GUARD_PAGE.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(Page::containing_address(VirtAddr::new(addr))),
+    }
+}
+
 /// Initialises the kernel stack to a known size.
 /// To prevent data from being overwritten, any pages which are already mapped by the bootloader will not be changed.
+///
+/// The page just past the end of the stack is left unmapped as a guard page, so that if the stack overflows
+/// the CPU raises a page fault rather than the kernel silently corrupting whatever memory is below the stack.
+/// The guard page can be retrieved using [`kernel_stack_guard_page`].
 pub unsafe fn init_kernel_stack() {
     let mut stack_ptr: u64;
 
@@ -235,6 +593,19 @@ pub unsafe fn init_kernel_stack() {
             }
         }
     }
+
+    let guard_page = stack_base_page - KERNEL_STACK_SIZE;
+
+    // If something is already mapped where the guard page should go, it isn't unmapped
+    // because it could be in use by other mappings the bootloader set up.
+    if mapper.translate_page(guard_page).is_ok() {
+        warn!(
+            "Page {:#x} below the kernel stack is already mapped, so no guard page was placed",
+            guard_page.start_address()
+        );
+    } else {
+        KERNEL_STACK_GUARD_PAGE.store(guard_page.start_address().as_u64(), Ordering::Relaxed);
+    }
 }
 
 /// This function:
@@ -256,7 +627,7 @@ pub unsafe fn init_cpu(physical_memory_offset: VirtAddr) -> OffsetPageTable<'sta
 
     KERNEL_STATE
         .physical_memory_accessor
-        .init(PhysicalMemoryAccessor { next_frame: 0 });
+        .init(PhysicalMemoryAccessor::new());
 
     // SAFETY:
     // The given level_4_table is correct as long as `physical_memory_offset` is correct,
@@ -302,8 +673,19 @@ pub unsafe fn init_ps2() {
     }
 }
 
-/// Enables SSE (SIMD float processing) by writing values into the `cr0` and `cr4` registers
+/// Enables SSE (SIMD float processing) by writing values into the `cr0` and `cr4` registers.
+/// If the CPU supports AVX, it is also enabled using `XCR0`.
+///
+/// # Panics
+/// If the CPU doesn't support SSE2, which the kernel is compiled to use for floating point numbers
 fn enable_sse() {
+    let features = features::cpu_features();
+    assert!(
+        features.sse && features.sse2,
+        "The CPU doesn't support SSE2, which the kernel needs for floating point numbers. \
+        Use a different CPU model (e.g. QEMU's `-cpu qemu64`)."
+    );
+
     let mut cr0: u64;
     let mut cr4: u64;
 
@@ -338,6 +720,33 @@ fn enable_sse() {
     }
 
     println!("Enabled SSE");
+
+    if features.xsave && features.avx {
+        enable_avx();
+        println!("Enabled AVX");
+    }
+}
+
+/// Enables AVX by enabling `XSAVE` in `cr4`, and then setting the x87, SSE, and AVX state bits of `XCR0`.
+/// The caller must check that the CPU supports `XSAVE` and AVX.
+fn enable_avx() {
+    // SAFETY: Setting cr4 bit 18 enables the XSAVE instructions and XCR0, which the caller checked are supported.
+    // XCR0 bits 0, 1, and 2 enable the x87, SSE, and AVX state, and bit 0 must always be set.
+    unsafe {
+        asm!(
+            "mov {cr4}, cr4",
+            "or {cr4}, 1 << 18",
+            "mov cr4, {cr4}",
+            "xor ecx, ecx",
+            "xgetbv",
+            "or eax, 0b111",
+            "xsetbv",
+            cr4 = out(reg) _,
+            out("eax") _,
+            out("ecx") _,
+            out("edx") _,
+        );
+    }
 }
 
 /// Initialises the [global frame allocator][crate::global_state::KernelState::frame_allocator].
@@ -347,10 +756,23 @@ fn enable_sse() {
 pub unsafe fn init_frame_allocator(memory_map: &'static MemoryRegions) {
     // SAFETY:
     // `memory_map` is valid as a safety condition of this function
-    let frame_allocator = unsafe { BootInfoFrameAllocator::new(memory_map) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(memory_map) };
+    smp::reserve_trampoline_frame(&mut frame_allocator);
     KERNEL_STATE.frame_allocator.init(frame_allocator);
 }
 
+/// Tests that overflowing the kernel stack hits the guard page placed by [`init_kernel_stack`],
+/// rather than silently writing to whatever memory is below the stack.
+///
+/// The test passes from within the double fault handler, which checks that the faulting address was in the guard page.
+#[test_case]
+fn test_kernel_stack_guard_page() {
+    assert!(kernel_stack_guard_page().is_some());
+
+    crate::tests::expect_stack_overflow();
+    crate::tests::overflow_stack();
+}
+
 /// Tests that floating point numbers are usable and work correctly
 #[test_case]
 fn test_floats() {
@@ -372,3 +794,68 @@ fn test_floats() {
     let error = libm::fabs(a - 1990.0);
     assert!(error < libm::pow(10.0, -10.0));
 }
+
+/// Tests that unmapped virtual frames are reused, so that mapping and unmapping in a loop doesn't use up the mapping space
+#[test_case]
+fn test_physical_memory_accessor_reuses_frames() {
+    let frame = KERNEL_STATE
+        .frame_allocator
+        .lock()
+        .allocate_frame()
+        .unwrap();
+
+    let mut accessor = KERNEL_STATE.physical_memory_accessor.lock();
+    let next_frame = accessor.next_frame;
+
+    for _ in 0..10_000 {
+        // SAFETY: `frame` was just allocated, so nothing else is using it
+        let region = unsafe { accessor.map_region(frame.start_address(), 0x1000) };
+        accessor.unmap_region(region);
+    }
+
+    assert_eq!(accessor.next_frame, next_frame);
+}
+
+/// Tests that [`map_frames_huge`] maps the aligned part of a range with a huge page and the rest with normal pages,
+/// and that [`unmap_frames`] unmaps both kinds of page
+///
+/// [`map_frames_huge`]: PhysicalMemoryAccessor::map_frames_huge
+/// [`unmap_frames`]: PhysicalMemoryAccessor::unmap_frames
+#[test_case]
+fn test_map_frames_huge() {
+    /// Gets the size of the page which `page` is mapped by, or [`None`] if it isn't mapped
+    fn mapped_size(page: Page) -> Option<u64> {
+        match KERNEL_STATE
+            .page_table
+            .lock()
+            .translate(page.start_address())
+        {
+            TranslateResult::Mapped { frame, .. } => Some(frame.size()),
+            _ => None,
+        }
+    }
+
+    // One frame before a 2MiB boundary, a huge page, then one more frame.
+    // Nothing is read or written through the mapping, so it doesn't matter what the memory is used for.
+    let start = PhysFrame::containing_address(PhysAddr::new(0x1F_F000));
+    let frames = PhysFrameRange {
+        start,
+        end: start + FRAMES_PER_HUGE_PAGE + 2,
+    };
+
+    let mut accessor = KERNEL_STATE.physical_memory_accessor.lock();
+
+    // SAFETY: The mapping is not used
+    let pages = unsafe { accessor.map_frames_huge(frames) };
+
+    assert_eq!(mapped_size(pages.start), Some(4096));
+    assert_eq!(mapped_size(pages.start + 1), Some(Size2MiB::SIZE));
+    assert_eq!(mapped_size(pages.end - 1), Some(4096));
+
+    // SAFETY: The pages were just mapped and are not used
+    unsafe { accessor.unmap_frames(pages) };
+
+    for page in [pages.start, pages.start + 1, pages.end - 1] {
+        assert_eq!(mapped_size(page), None);
+    }
+}
@@ -0,0 +1,250 @@
+//! Programming the _Page Attribute Table_ (PAT), which sets the memory types that page table entries can select.
+//!
+//! A page's memory type is chosen by its `PWT`, `PCD`, and `PAT` bits, which together index into the PAT.
+//! At power-on, the first four entries are write-back, write-through, uncacheable-minus, and uncacheable,
+//! so pages can't be mapped as write-combining. [`init`] changes the write-through entry to write-combining,
+//! which is much faster than uncacheable for memory which is written to in bulk but never read, such as the framebuffer.
+//!
+//! For more info, see the [Intel 64 and IA-32 Architectures Software Developer’s Manual] volume 3A section 12.12
+//! (Page Attribute Table).
+//!
+//! [Intel 64 and IA-32 Architectures Software Developer’s Manual]: https://cdrdv2.intel.com/v1/dl/getContent/671200
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::{FlagUpdateError, MappedFrame, TranslateResult},
+        Mapper, Page, PageSize, PageTableFlags, Size2MiB, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::global_state::KERNEL_STATE;
+
+use super::features::cpu_features;
+
+/// The MSR which holds the PAT
+const IA32_PAT: u32 = 0x277;
+
+/// A memory type which can be set in the PAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    /// Reads and writes go straight to memory, in order
+    Uncacheable = 0,
+    /// Reads aren't cached, but writes are buffered and combined into larger writes
+    WriteCombining = 1,
+    /// Reads are cached, and writes go to both the cache and memory
+    WriteThrough = 4,
+    /// Reads are cached, but writes go straight to memory
+    WriteProtected = 5,
+    /// Reads and writes are cached. This is the memory type of normal RAM.
+    WriteBack = 6,
+    /// Like [`Uncacheable`][MemoryType::Uncacheable], but can be overridden to write-combining by the MTRRs
+    UncacheableMinus = 7,
+}
+
+/// The PAT set by [`init`]. This is the power-on default, except that entry 1 is write-combining instead of write-through.
+const KERNEL_PAT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncacheableMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncacheableMinus,
+    MemoryType::Uncacheable,
+];
+
+/// Whether [`init`] has programmed the PAT with [`KERNEL_PAT`]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Gets the value of the `IA32_PAT` MSR for [`KERNEL_PAT`]
+fn kernel_pat_value() -> u64 {
+    KERNEL_PAT
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, &memory_type)| {
+            value | ((memory_type as u64) << (i * 8))
+        })
+}
+
+impl MemoryType {
+    /// Gets the page table flags which select this memory type in [`KERNEL_PAT`],
+    /// or [`None`] if it can't be selected without the `PAT` bit, which the kernel doesn't use.
+    fn page_table_flags(self) -> Option<PageTableFlags> {
+        let index = KERNEL_PAT[..4]
+            .iter()
+            .position(|&memory_type| memory_type == self)?;
+
+        let mut flags = PageTableFlags::empty();
+        flags.set(PageTableFlags::WRITE_THROUGH, index & 1 != 0);
+        flags.set(PageTableFlags::NO_CACHE, index & 2 != 0);
+        Some(flags)
+    }
+}
+
+/// Writes back and invalidates the CPU's caches
+fn wbinvd() {
+    // SAFETY: Writing back the caches doesn't change the contents of memory
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+}
+
+/// Programs this core's PAT with [`KERNEL_PAT`], if the CPU supports it, and returns whether it was programmed.
+///
+/// # Safety
+/// This must be called on every core, so that all cores agree on the memory type of each page.
+/// No pages may be mapped with only the `PWT` flag set, as their memory type will change.
+pub unsafe fn init() -> bool {
+    if !cpu_features().pat {
+        return false;
+    }
+
+    // The caches and TLB are flushed around the change, so that no entries use the old memory types
+    wbinvd();
+    // SAFETY: The CPU supports the PAT, and the caller guarantees that changing entry 1 doesn't affect existing pages
+    unsafe { Msr::new(IA32_PAT).write(kernel_pat_value()) };
+    tlb::flush_all();
+    wbinvd();
+
+    ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Gets whether [`init`] has programmed the PAT, so that [`MemoryType::WriteCombining`] can be used
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// An error which can occur when changing the memory type of pages with [`set_memory_type`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetMemoryTypeError {
+    /// The memory type can't be selected by the kernel's PAT, or needs the PAT to have been programmed by [`init`]
+    UnsupportedMemoryType(MemoryType),
+    /// A page in the region isn't mapped
+    NotMapped(VirtAddr),
+    /// A page in the region is a 1GiB page
+    UnsupportedPageSize(VirtAddr),
+    /// The page table couldn't be updated
+    FlagUpdate(FlagUpdateError),
+}
+
+/// The memory type of a page, saved by [`saved_memory_type`] so that it can be put back by [`restore_memory_type`].
+///
+/// This holds the page's `PWT` and `PCD` flags rather than a [`MemoryType`], as the PAT entry they select
+/// may not be known, e.g. if the CPU doesn't support the PAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavedMemoryType(PageTableFlags);
+
+/// Gets the memory type of the page containing `addr`, so that it can be restored after being changed
+pub fn saved_memory_type(addr: VirtAddr) -> Result<SavedMemoryType, SetMemoryTypeError> {
+    match KERNEL_STATE.page_table.lock().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Ok(SavedMemoryType(
+            flags & (PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE),
+        )),
+        _ => Err(SetMemoryTypeError::NotMapped(addr)),
+    }
+}
+
+/// Changes the memory type of the pages containing the `len` bytes starting at `start`.
+///
+/// # Safety
+/// No other mapping of the same physical memory may be accessed with a different memory type.
+pub unsafe fn set_memory_type(
+    start: VirtAddr,
+    len: usize,
+    memory_type: MemoryType,
+) -> Result<(), SetMemoryTypeError> {
+    let type_flags = memory_type
+        .page_table_flags()
+        .filter(|_| memory_type != MemoryType::WriteCombining || is_enabled())
+        .ok_or(SetMemoryTypeError::UnsupportedMemoryType(memory_type))?;
+
+    // SAFETY: The caller guarantees that changing the memory type is sound
+    unsafe { set_type_flags(start, len, type_flags) }
+}
+
+/// Changes the memory type of the pages containing the `len` bytes starting at `start`
+/// back to one saved by [`saved_memory_type`].
+///
+/// # Safety
+/// No other mapping of the same physical memory may be accessed with a different memory type.
+pub unsafe fn restore_memory_type(
+    start: VirtAddr,
+    len: usize,
+    saved: SavedMemoryType,
+) -> Result<(), SetMemoryTypeError> {
+    // SAFETY: The caller guarantees that changing the memory type is sound
+    unsafe { set_type_flags(start, len, saved.0) }
+}
+
+/// Sets the `PWT` and `PCD` flags of the pages containing the `len` bytes starting at `start` to those in `type_flags`
+///
+/// # Safety
+/// No other mapping of the same physical memory may be accessed with a different memory type.
+unsafe fn set_type_flags(
+    start: VirtAddr,
+    len: usize,
+    type_flags: PageTableFlags,
+) -> Result<(), SetMemoryTypeError> {
+    let end = start + len as u64;
+    let mut page_table = KERNEL_STATE.page_table.lock();
+    let mut addr = start.align_down(Size4KiB::SIZE);
+
+    while addr < end {
+        let TranslateResult::Mapped { frame, flags, .. } = page_table.translate(addr) else {
+            return Err(SetMemoryTypeError::NotMapped(addr));
+        };
+
+        let flags =
+            (flags - (PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE)) | type_flags;
+
+        match frame {
+            MappedFrame::Size4KiB(_) => {
+                let page = Page::<Size4KiB>::containing_address(addr);
+                // SAFETY: Only the memory type of the page changes, which the caller guarantees is sound
+                unsafe { page_table.update_flags(page, flags) }
+                    .map_err(SetMemoryTypeError::FlagUpdate)?
+                    .flush();
+            }
+            MappedFrame::Size2MiB(_) => {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                // SAFETY: Only the memory type of the page changes, which the caller guarantees is sound
+                unsafe { page_table.update_flags(page, flags) }
+                    .map_err(SetMemoryTypeError::FlagUpdate)?
+                    .flush();
+            }
+            MappedFrame::Size1GiB(_) => return Err(SetMemoryTypeError::UnsupportedPageSize(addr)),
+        }
+
+        addr = addr.align_down(frame.size()) + frame.size();
+    }
+
+    // Write back anything cached with the old memory type
+    wbinvd();
+
+    Ok(())
+}
+
+/// Tests the value written to the PAT, and that it was written if the CPU supports it
+#[test_case]
+fn test_kernel_pat() {
+    assert_eq!(kernel_pat_value(), 0x0007_0406_0007_0106);
+
+    if is_enabled() {
+        // SAFETY: The PAT is enabled, so the CPU supports the MSR. Reading it has no side effects.
+        assert_eq!(unsafe { Msr::new(IA32_PAT).read() }, kernel_pat_value());
+    }
+
+    assert_eq!(
+        MemoryType::Uncacheable.page_table_flags(),
+        Some(PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE)
+    );
+    assert_eq!(MemoryType::WriteThrough.page_table_flags(), None);
+}
@@ -0,0 +1,99 @@
+//! The legacy _Programmable Interval Timer_.
+//!
+//! The kernel's ticks come from the local APIC timer, so the PIT is free to be used as a second timer.
+//! It is used to send periodic NMIs to the [scheduler watchdog][crate::scheduler::watchdog],
+//! which need to keep arriving even if the local APIC timer's interrupt handler never returns.
+//! Channel 2 is used to calibrate the local APIC timer on systems without a HPET.
+
+use core::hint::spin_loop;
+
+use x86_64::instructions::port::Port;
+
+/// The frequency of the PIT's input clock
+const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+
+/// The I/O port of channel 0's reload value
+const CHANNEL_0_DATA_PORT: u16 = 0x40;
+/// The I/O port of the mode/command register
+const COMMAND_PORT: u16 = 0x43;
+/// The command to set channel 0 to mode 2 (rate generator), with the reload value written as the low byte then high byte
+const RATE_GENERATOR_COMMAND: u8 = 0b0011_0100;
+
+/// The I/O port of channel 2's reload value
+const CHANNEL_2_DATA_PORT: u16 = 0x42;
+/// The I/O port which controls channel 2's gate and the PC speaker, and reads channel 2's output
+const CHANNEL_2_GATE_PORT: u16 = 0x61;
+/// The bit of [`CHANNEL_2_GATE_PORT`] which enables channel 2's count
+const CHANNEL_2_GATE: u8 = 1 << 0;
+/// The bit of [`CHANNEL_2_GATE_PORT`] which connects channel 2 to the PC speaker
+const SPEAKER_ENABLE: u8 = 1 << 1;
+/// The bit of [`CHANNEL_2_GATE_PORT`] which reads channel 2's output
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+/// The command to set channel 2 to mode 0 (interrupt on terminal count), with the reload value written as the low byte then high byte
+const ONE_SHOT_CHANNEL_2_COMMAND: u8 = 0b1011_0000;
+
+/// The ISA IRQ which channel 0 sends interrupts on.
+/// On almost all systems with an I/O APIC, including QEMU, the MADT overrides this to GSI 2,
+/// so the GSI should be found using [`isa_irq_route`][crate::cpu::interrupt_controllers::isa_irq_route].
+pub const PIT_IRQ: u8 = 0;
+
+/// An error which can occur when starting the PIT with [`start_periodic`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidFrequencyError;
+
+/// Starts channel 0 of the PIT sending interrupts `hz` times per second.
+/// The lowest possible frequency is about 19Hz.
+///
+/// # Safety
+/// Nothing else may be using the PIT.
+pub unsafe fn start_periodic(hz: u32) -> Result<(), InvalidFrequencyError> {
+    let reload_value = PIT_FREQUENCY_HZ
+        .checked_div(hz)
+        .and_then(|reload_value| u16::try_from(reload_value).ok())
+        .filter(|&reload_value| reload_value > 1)
+        .ok_or(InvalidFrequencyError)?;
+    let [low, high] = reload_value.to_le_bytes();
+
+    // SAFETY: These are the PIT's ports, and the caller guarantees that nothing else is using it
+    unsafe {
+        Port::<u8>::new(COMMAND_PORT).write(RATE_GENERATOR_COMMAND);
+        let mut data = Port::<u8>::new(CHANNEL_0_DATA_PORT);
+        data.write(low);
+        data.write(high);
+    }
+
+    Ok(())
+}
+
+/// Busy-waits for about `ns` nanoseconds using channel 2 of the PIT, which is otherwise only used for the PC speaker.
+/// The wait is rounded to a whole number of PIT ticks and is at most about 55ms.
+/// Returns how many nanoseconds were actually waited.
+///
+/// # Safety
+/// Nothing else may be using channel 2 of the PIT or the PC speaker.
+pub unsafe fn wait_channel_2(ns: u64) -> u64 {
+    let ticks = (ns * u64::from(PIT_FREQUENCY_HZ) / 1_000_000_000).clamp(1, u16::MAX.into());
+    let [low, high] = u16::try_from(ticks).unwrap().to_le_bytes();
+
+    // SAFETY: These are the PIT's ports, and the caller guarantees that nothing else is using channel 2.
+    // The gate is kept low while the count is written so that it doesn't start early,
+    // and the speaker is kept disconnected so that it doesn't make a sound.
+    unsafe {
+        let mut gate = Port::<u8>::new(CHANNEL_2_GATE_PORT);
+        let value = gate.read() & !(CHANNEL_2_GATE | SPEAKER_ENABLE);
+        gate.write(value);
+
+        Port::<u8>::new(COMMAND_PORT).write(ONE_SHOT_CHANNEL_2_COMMAND);
+        let mut data = Port::<u8>::new(CHANNEL_2_DATA_PORT);
+        data.write(low);
+        data.write(high);
+
+        gate.write(value | CHANNEL_2_GATE);
+        while gate.read() & CHANNEL_2_OUTPUT == 0 {
+            spin_loop();
+        }
+        gate.write(value);
+    }
+
+    ticks * 1_000_000_000 / u64::from(PIT_FREQUENCY_HZ)
+}
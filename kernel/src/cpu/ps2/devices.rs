@@ -2,18 +2,24 @@
 
 use core::fmt::Debug;
 
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet2};
+use log::warn;
+use pc_keyboard::{layouts, HandleControl, KeyState, Keyboard, ScancodeSet2};
 
-use crate::input::push_key;
+use crate::{
+    graphics::move_mouse_cursor,
+    input::{
+        push_key, push_key_event, push_mouse_event, KeyEvent, Modifiers, MouseButtons, MouseEvent,
+    },
+};
 
-use super::{Ps2ControllerInitialisationError, Ps2Port, Ps2Ports};
+use super::{Ps2ControllerInitialisationError, Ps2Port, Ps2Ports, SCANCODE_SET};
 
 /// A device which is connected to a PS/2 port
 pub(super) enum Ps2Device {
     /// An AT keyboard
     ATKeyboard,
     /// A standard 3-button mouse
-    StandardMouse,
+    StandardMouse(StandardMouse),
     /// A mouse which has a scroll wheel
     MouseWithScrollWheel,
     /// A 5-button mouse
@@ -30,7 +36,7 @@ impl Debug for Ps2Device {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::ATKeyboard => write!(f, "ATKeyboard"),
-            Self::StandardMouse => write!(f, "StandardMouse"),
+            Self::StandardMouse(_) => write!(f, "StandardMouse"),
             Self::MouseWithScrollWheel => write!(f, "MouseWithScrollWheel"),
             Self::FiveButtonMouse => write!(f, "FiveButtonMouse"),
             Self::MF2Keyboard(_) => write!(f, "MF2Keyboard"),
@@ -46,15 +52,37 @@ impl Ps2Device {
         Self::MF2Keyboard(Mf2Keyboard::new())
     }
 
+    /// Constructs a new standard mouse device
+    pub const fn new_mouse() -> Self {
+        Self::StandardMouse(StandardMouse::new())
+    }
+
     /// Initialises the device on the given port.
     pub unsafe fn init(
         &mut self,
         port: Ps2Port,
         ports: &mut Ps2Ports,
     ) -> Result<(), Ps2ControllerInitialisationError> {
-        if let Self::StandardMouse = self {
-            // SAFETY: This command will activate the mouse
-            unsafe { ports.port_send_command(port, super::Ps2DeviceCommand::EnableScanning)? };
+        match self {
+            Self::StandardMouse(_) => {
+                // SAFETY: This command will activate the mouse
+                unsafe { ports.port_send_command(port, super::Ps2DeviceCommand::EnableScanning)? };
+            }
+            Self::MF2Keyboard(_) => {
+                // Don't rely on the keyboard's default scancode set, as `pc_keyboard` only decodes one set
+                // SAFETY: The device is a keyboard, and `Mf2Keyboard` decodes `SCANCODE_SET`
+                unsafe { ports.set_scancode_set(port, SCANCODE_SET)? };
+
+                // SAFETY: The device is a keyboard. Interrupts are disabled while devices are initialised.
+                match unsafe { ports.get_scancode_set(port) } {
+                    Ok(SCANCODE_SET) => (),
+                    Ok(set) => warn!(
+                        "Keyboard on {port:?} PS/2 port reports scancode set {set:#x} rather than {SCANCODE_SET} - keys may be decoded wrong"
+                    ),
+                    Err(e) => warn!("Couldn't confirm scancode set of keyboard on {port:?} PS/2 port: {e:?}"),
+                }
+            }
+            _ => (),
         }
 
         Ok(())
@@ -70,11 +98,7 @@ impl Ps2Device {
         unsafe {
             match self {
                 Self::MF2Keyboard(k) => k.poll(port, ports),
-                Self::StandardMouse => {
-                    let Some(packet) = ports.read() else {
-                        return;
-                    };
-                }
+                Self::StandardMouse(m) => m.poll(port, ports),
                 _ => todo!(),
             }
         }
@@ -82,16 +106,25 @@ impl Ps2Device {
 }
 
 /// An Mf2 keyboard device
-pub(super) struct Mf2Keyboard(Keyboard<layouts::Us104Key, ScancodeSet2>);
+pub(super) struct Mf2Keyboard {
+    /// The decoder which turns scancodes into keys
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet2>,
+    /// Which modifier keys are currently held
+    modifiers: Modifiers,
+}
 
 impl Mf2Keyboard {
     /// Constructs a new [`Mf2Keyboard`] in a default state
     const fn new() -> Self {
-        Self(Keyboard::new(
-            ScancodeSet2::new(),
-            layouts::Us104Key,
-            HandleControl::Ignore,
-        ))
+        Self {
+            // Ctrl + a letter is decoded as the matching control character, so that e.g. Ctrl+C isn't typed as 'c'
+            keyboard: Keyboard::new(
+                ScancodeSet2::new(),
+                layouts::Us104Key,
+                HandleControl::MapLettersToUnicode,
+            ),
+            modifiers: Modifiers::NONE,
+        }
     }
 
     /// Polls the keyboard for keypresses
@@ -106,10 +139,110 @@ impl Mf2Keyboard {
         };
 
         // Parse the scancode using the pc-keyboard crate
-        if let Ok(Some(key_event)) = self.0.add_byte(scancode) {
-            if let Some(key) = self.0.process_keyevent(key_event) {
+        if let Ok(Some(key_event)) = self.keyboard.add_byte(scancode) {
+            let pressed = key_event.state != KeyState::Up;
+            self.modifiers.update(key_event.code, pressed);
+
+            push_key_event(KeyEvent {
+                key: key_event.code,
+                pressed,
+                modifiers: self.modifiers,
+            });
+
+            if let Some(key) = self.keyboard.process_keyevent(key_event) {
                 push_key(key);
             }
         }
     }
 }
+
+/// A standard 3-button mouse, which sends its movement in 3-byte packets
+pub(super) struct StandardMouse {
+    /// The bytes of the packet currently being received
+    packet: [u8; 3],
+    /// How many bytes of [`packet`][Self::packet] have been received
+    received: usize,
+}
+
+impl StandardMouse {
+    /// The bit of the first byte of a packet which is always set, used to find the start of a packet
+    const ALWAYS_SET: u8 = 1 << 3;
+
+    /// Constructs a new [`StandardMouse`] which is waiting for the start of a packet
+    const fn new() -> Self {
+        Self {
+            packet: [0; 3],
+            received: 0,
+        }
+    }
+
+    /// Reads a byte from the mouse, and if it completes a packet, handles the packet
+    ///
+    /// # Safety
+    /// As this function does not check that any read data comes from the mouse,
+    /// it should only be called from the interrupt handler for the mouse's PS/2 port.
+    unsafe fn poll(&mut self, _port: Ps2Port, ports: &mut Ps2Ports) {
+        // SAFETY: This is called from an interrupt handler which means any data comes from this device
+        let Some(byte) = (unsafe { ports.read() }) else {
+            return;
+        };
+
+        // If a byte was lost, skip bytes until one which could be the start of a packet
+        if self.received == 0 && byte & Self::ALWAYS_SET == 0 {
+            return;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+
+        if self.received == self.packet.len() {
+            self.received = 0;
+
+            let event = Self::parse_packet(self.packet);
+
+            move_mouse_cursor(event.dx.into(), event.dy.into());
+            push_mouse_event(event);
+        }
+    }
+
+    /// Parses a 3-byte movement packet into a [`MouseEvent`]
+    fn parse_packet([flags, x, y]: [u8; 3]) -> MouseEvent {
+        /// Sign-extends a 9-bit movement value, or returns 0 if the movement overflowed
+        fn movement(value: u8, sign: bool, overflow: bool) -> i16 {
+            match (overflow, sign) {
+                (true, _) => 0,
+                (false, false) => i16::from(value),
+                (false, true) => i16::from(value) - 0x100,
+            }
+        }
+
+        let dx = movement(x, flags & (1 << 4) != 0, flags & (1 << 6) != 0);
+        let dy = movement(y, flags & (1 << 5) != 0, flags & (1 << 7) != 0);
+
+        MouseEvent {
+            dx,
+            // The mouse reports upwards movement as positive, but screen coordinates go down
+            dy: -dy,
+            buttons: MouseButtons {
+                left: flags & (1 << 0) != 0,
+                right: flags & (1 << 1) != 0,
+                middle: flags & (1 << 2) != 0,
+            },
+        }
+    }
+}
+
+#[test_case]
+fn test_parse_mouse_packet() {
+    // Moving right by 5 and down by 3 (-3 upwards) with the left button held
+    let event = StandardMouse::parse_packet([0b0010_1001, 5, 0xFD]);
+    assert_eq!(event.dx, 5);
+    assert_eq!(event.dy, 3);
+    assert!(event.buttons.left);
+    assert!(!event.buttons.right);
+
+    // Overflowed movement is ignored
+    let event = StandardMouse::parse_packet([0b0100_1000, 0xFF, 10]);
+    assert_eq!(event.dx, 0);
+    assert_eq!(event.dy, -10);
+}
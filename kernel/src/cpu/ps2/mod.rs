@@ -2,7 +2,7 @@
 
 pub mod devices;
 
-use log::debug;
+use log::{debug, warn};
 use x86_64::instructions::{hlt, port::Port};
 
 use crate::global_state::{GlobalState, KERNEL_STATE};
@@ -51,9 +51,14 @@ const DATA_PORT: u16 = 0x60;
 /// The port number to write commands to
 const COMMAND_PORT: u16 = 0x64;
 
-/// The number of [`ticks`][crate::global_state::KernelState::ticks]
-/// which the controller will wait for data before giving up
-const TIMEOUT_TRIES: usize = 5;
+/// The number of nanoseconds which the controller will wait for data before giving up
+const TIMEOUT_NS: u64 = 50_000_000;
+
+/// The scancode set which keyboards are set to send.
+/// This must match the scancode set that [`pc_keyboard`] is decoding in [`Mf2Keyboard`].
+///
+/// [`Mf2Keyboard`]: devices::Mf2Keyboard
+const SCANCODE_SET: u8 = 2;
 
 /// The global PS/2 controller
 pub static PS2_CONTROLLER: GlobalState<Ps2Controller8042> = GlobalState::new();
@@ -199,7 +204,7 @@ impl Ps2Controller8042 {
     ///
     /// [`DisablePrimaryPort`]: Ps2ControllerCommand::DisablePrimaryPort
     /// [`DisableSecondaryPort`]: Ps2ControllerCommand::DisableSecondaryPort
-    unsafe fn disable(&mut self) -> Result<(), Ps2ControllerInitialisationError> {
+    pub unsafe fn disable(&mut self) -> Result<(), Ps2ControllerInitialisationError> {
         // SAFETY: This will disable the PS/2 controller' first port
         unsafe {
             self.ports
@@ -220,7 +225,7 @@ impl Ps2Controller8042 {
         match bytes {
             [None, Some(_)] => panic!("Invalid device id bytes"),
             [None, _] => Ps2Device::ATKeyboard,
-            [Some(0x00), _] => Ps2Device::StandardMouse,
+            [Some(0x00), _] => Ps2Device::new_mouse(),
             [Some(0x03), _] => Ps2Device::MouseWithScrollWheel,
             [Some(0x04), _] => Ps2Device::FiveButtonMouse,
             [Some(0xAB), Some(0x83) | Some(0xC1)] => Ps2Device::new_keyboard(),
@@ -341,6 +346,14 @@ impl Ps2Controller8042 {
         unsafe {
             let mut config = self.ports.read_configuration()?;
 
+            // Keyboards send scancode set 2, which `pc_keyboard` decodes directly,
+            // so the controller mustn't translate them to scancode set 1.
+            // Some controllers turn translation back on after being tested.
+            if config.primary_port_translation() {
+                warn!("PS/2 controller has scancode translation enabled - disabling it so that keys are decoded correctly");
+                config.set_primary_port_translation(false);
+            }
+
             config.set_primary_port_interrupts_enabled(true);
 
             if has_secondary_port {
@@ -499,9 +512,9 @@ impl Ps2Ports {
     /// # Safety
     /// The caller must make sure that the data is properly parsed and responded to.
     pub unsafe fn read_timeout(&mut self) -> Option<u8> {
-        let target_value = KERNEL_STATE.ticks() + TIMEOUT_TRIES;
+        let target_value = KERNEL_STATE.uptime_ns() + TIMEOUT_NS;
 
-        while KERNEL_STATE.ticks() < target_value {
+        while KERNEL_STATE.uptime_ns() < target_value {
             // SAFETY: The safety of this operation is the caller's responsibility
             unsafe {
                 if let Some(data) = self.read() {
@@ -575,8 +588,8 @@ impl Ps2Ports {
         unsafe { self.write_timeout(value) }
     }
 
-    /// Writes `value` to the controller. This method waits up to [`TIMEOUT_TRIES`]
-    /// kernel ticks for the output buffer to be free before giving up.
+    /// Writes `value` to the controller. This method waits up to [`TIMEOUT_NS`]
+    /// nanoseconds for the output buffer to be free before giving up.
     ///
     /// # Safety
     /// The caller must ensure that the byte written is valid and has the intended effect.
@@ -588,11 +601,11 @@ impl Ps2Ports {
         Ok(())
     }
 
-    /// Waits up to [`TIMEOUT_TRIES`]  kernel ticks for the output buffer to be free.
+    /// Waits up to [`TIMEOUT_NS`] nanoseconds for the output buffer to be free.
     fn wait_for_write_buffer_empty(&mut self) -> Result<(), Ps2ControllerInitialisationError> {
-        let target_value = KERNEL_STATE.ticks() + TIMEOUT_TRIES;
+        let target_value = KERNEL_STATE.uptime_ns() + TIMEOUT_NS;
 
-        while KERNEL_STATE.ticks() < target_value {
+        while KERNEL_STATE.uptime_ns() < target_value {
             if self.read_status().write_data_queued() {
                 hlt();
                 continue;
@@ -624,6 +637,73 @@ impl Ps2Ports {
         }
     }
 
+    /// Writes a command byte followed by a data byte to the given port,
+    /// and checks whether the device acknowledges both bytes.
+    ///
+    /// # Safety
+    /// The caller must ensure that the command written has the intended effect.
+    unsafe fn port_send_command_with_data(
+        &mut self,
+        port: Ps2Port,
+        command: Ps2DeviceCommand,
+        data: u8,
+    ) -> Result<Option<()>, Ps2ControllerInitialisationError> {
+        // SAFETY: The caller is responsible for the effect of the command
+        if unsafe { self.port_send_command(port, command)? }.is_none() {
+            return Ok(None);
+        }
+
+        // SAFETY: The device is expecting the command's data byte
+        unsafe { self.write_port(port, data)? }
+
+        // SAFETY: The device will acknowledge the data byte
+        match unsafe { self.read_timeout() } {
+            None => Ok(None),
+            Some(0xFA) => Ok(Some(())),
+            Some(_) => Err(Ps2ControllerInitialisationError::PortReinitError(port)),
+        }
+    }
+
+    /// Tells the keyboard on the given port to send scancodes from the given scancode set (1, 2, or 3).
+    ///
+    /// # Safety
+    /// The device on the port must be a keyboard, and the code decoding its scancodes must expect the new set.
+    unsafe fn set_scancode_set(
+        &mut self,
+        port: Ps2Port,
+        set: u8,
+    ) -> Result<(), Ps2ControllerInitialisationError> {
+        assert!((1..=3).contains(&set), "Invalid scancode set {set}");
+
+        // SAFETY: The caller is responsible for decoding the new scancode set
+        unsafe {
+            self.port_send_command_with_data(port, Ps2DeviceCommand::ScancodeSet, set)?
+                .ok_or(Ps2ControllerInitialisationError::MissingData)
+        }
+    }
+
+    /// Asks the keyboard on the given port which scancode set it is sending.
+    ///
+    /// If the controller is translating scancodes from the port, the response is translated too,
+    /// so scancode set 2 is reported as `0x41` rather than `2`.
+    ///
+    /// # Safety
+    /// The device on the port must be a keyboard, and interrupts must be disabled for the port
+    /// so that the response isn't read by the interrupt handler.
+    unsafe fn get_scancode_set(
+        &mut self,
+        port: Ps2Port,
+    ) -> Result<u8, Ps2ControllerInitialisationError> {
+        // SAFETY: A data byte of 0 asks for the current scancode set rather than changing it
+        unsafe {
+            self.port_send_command_with_data(port, Ps2DeviceCommand::ScancodeSet, 0)?
+                .ok_or(Ps2ControllerInitialisationError::MissingData)?;
+        }
+
+        // SAFETY: The keyboard sends the scancode set after acknowledging the command
+        unsafe { self.read_timeout() }.ok_or(Ps2ControllerInitialisationError::MissingData)
+    }
+
     /// Re-initialises the given PS/2 port, sends the identify command (TODO: enum-ify and link) and parses the response.
     ///
     /// # Safety
@@ -805,6 +885,9 @@ enum Ps2DeviceCommand {
     EnableScanning,
     /// Causes the device to send bytes identifying what kind of device it is
     Identify,
+    /// Gets or sets a keyboard's scancode set. This command is followed by a data byte,
+    /// which is 0 to get the current scancode set or 1, 2, or 3 to set it.
+    ScancodeSet,
 }
 
 impl Ps2DeviceCommand {
@@ -815,6 +898,7 @@ impl Ps2DeviceCommand {
             Self::DisableScanning => 0xF5,
             Self::EnableScanning => 0xF4,
             Self::Identify => 0xF2,
+            Self::ScancodeSet => 0xF0,
         }
     }
 }
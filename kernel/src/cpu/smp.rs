@@ -0,0 +1,443 @@
+//! Code to start the system's _Application Processors_ (APs), i.e. every core other than the one the kernel was booted on.
+//!
+//! Each AP is started by sending it an INIT IPI followed by startup IPIs, which make it start executing
+//! in real mode at the start of a page below 1MiB. The trampoline in that page switches the core into long mode
+//! using the kernel's page tables, and jumps to [`ap_entry`].
+//!
+//! APs don't yet run any kernel code - they load the kernel's IDT, with their own TSS and interrupt stacks,
+//! register themselves in the list of [`online_cpus`], and then halt with interrupts disabled.
+//!
+//! For more info, see the [Intel 64 and IA-32 Architectures Software Developer’s Manual] volume 3 section 9.4
+//!
+//! [Intel 64 and IA-32 Architectures Software Developer’s Manual]: https://cdrdv2.intel.com/v1/dl/getContent/671200
+
+use core::{
+    arch::global_asm,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use acpica_bindings::types::tables::madt::MadtRecord;
+use alloc::{boxed::Box, vec, vec::Vec};
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::{control::Cr3, model_specific::Msr},
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate},
+    PhysAddr, VirtAddr,
+};
+
+use crate::global_state::KERNEL_STATE;
+
+use super::{gdt, idt, interrupt_controllers, pat, BootInfoFrameAllocator};
+
+/// The size of each AP's stack in bytes
+const AP_STACK_SIZE: usize = 4 * 4096;
+
+/// How long to wait after sending an INIT IPI before sending a startup IPI, in nanoseconds
+const INIT_DELAY_NS: u64 = 10_000_000;
+/// How long to wait for an AP to start after the first startup IPI before sending a second one, in nanoseconds
+const FIRST_STARTUP_TIMEOUT_NS: u64 = 10_000_000;
+/// How long to wait for an AP to start after the second startup IPI before giving up on it, in nanoseconds
+const SECOND_STARTUP_TIMEOUT_NS: u64 = 100_000_000;
+
+/// A core which is running kernel code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    /// The core's local APIC ID
+    pub apic_id: u8,
+    /// Whether the core is the _Bootstrap Processor_ which the kernel was booted on
+    pub is_bsp: bool,
+}
+
+/// The cores which are running kernel code, in the order they were started
+static ONLINE_CPUS: Mutex<Vec<Cpu>> = Mutex::new(Vec::new());
+
+/// Set by [`ap_entry`] once the AP being started has left the trampoline,
+/// so that the trampoline's page can be re-used for the next AP.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The physical address of the frame reserved by [`reserve_trampoline_frame`], or 0 if no frame could be reserved
+static TRAMPOLINE_FRAME: AtomicU64 = AtomicU64::new(0);
+
+// The code which APs start executing in real mode.
+// The code is copied to a page below 1MiB, and the fields at the end are filled in by `write_trampoline`.
+// CS is set to the page's address >> 4 and IP to 0 by the startup IPI, so the code and data are addressed relative to the start.
+global_asm!(
+    ".section .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_gdt",
+    ".global ap_trampoline_gdt_ptr",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_long_mode_ptr",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_entry",
+    ".global ap_trampoline_arg",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov %cs, %ax",
+    "    mov %ax, %ds",
+    "    lgdtl (ap_trampoline_gdt_ptr - ap_trampoline_start)",
+    // Enable PAE, SSE, and unmasked SSE exceptions
+    "    mov $0x620, %eax",
+    "    mov %eax, %cr4",
+    "    mov (ap_trampoline_cr3 - ap_trampoline_start), %eax",
+    "    mov %eax, %cr3",
+    // Enable long mode and the no-execute bit in the EFER MSR
+    "    mov $0xc0000080, %ecx",
+    "    rdmsr",
+    "    or $0x900, %eax",
+    "    wrmsr",
+    // Enable paging, write protection, and protected mode, with the same FPU settings as `enable_sse`
+    "    mov $0x80010033, %eax",
+    "    mov %eax, %cr0",
+    "    ljmpl *(ap_trampoline_long_mode_ptr - ap_trampoline_start)",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    mov $0x10, %ax",
+    "    mov %ax, %ds",
+    "    mov %ax, %es",
+    "    mov %ax, %ss",
+    "    mov ap_trampoline_stack(%rip), %rsp",
+    "    mov ap_trampoline_arg(%rip), %rdi",
+    "    mov ap_trampoline_entry(%rip), %rax",
+    "    call *%rax",
+    "    ud2",
+    ".align 16",
+    // A minimal GDT with a 64-bit code segment at 0x08 and a data segment at 0x10
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00af9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "ap_trampoline_gdt_ptr:",
+    "    .word 23",
+    "    .long 0",
+    "ap_trampoline_long_mode_ptr:",
+    "    .long 0",
+    "    .word 0x08",
+    ".align 8",
+    "ap_trampoline_cr3:",
+    "    .quad 0",
+    "ap_trampoline_stack:",
+    "    .quad 0",
+    "ap_trampoline_entry:",
+    "    .quad 0",
+    "ap_trampoline_arg:",
+    "    .quad 0",
+    "ap_trampoline_end:",
+    ".previous",
+    options(att_syntax)
+);
+
+extern "C" {
+    /// The start of the AP trampoline code
+    static ap_trampoline_start: u8;
+    /// The end of the AP trampoline code
+    static ap_trampoline_end: u8;
+    /// The trampoline's temporary GDT
+    static ap_trampoline_gdt: u8;
+    /// The base address field of the pointer to the trampoline's GDT, at offset 2
+    static ap_trampoline_gdt_ptr: u8;
+    /// The trampoline's 64-bit code
+    static ap_trampoline_long_mode: u8;
+    /// The offset field of the far pointer to [`ap_trampoline_long_mode`]
+    static ap_trampoline_long_mode_ptr: u8;
+    /// The physical address of the PML4 to load into CR3
+    static ap_trampoline_cr3: u8;
+    /// The AP's initial stack pointer
+    static ap_trampoline_stack: u8;
+    /// The address of the function to call in long mode
+    static ap_trampoline_entry: u8;
+    /// The argument to pass to the function
+    static ap_trampoline_arg: u8;
+}
+
+/// Gets the offset of a symbol in the trampoline from [`ap_trampoline_start`]
+macro_rules! trampoline_offset {
+    ($symbol: ident) => {
+        // SAFETY: This only takes the address of the symbols, without reading them
+        unsafe { addr_of!($symbol) as usize - addr_of!(ap_trampoline_start) as usize }
+    };
+}
+
+/// Reserves a frame below 1MiB for the AP trampoline, as the startup IPI can only start a core at an address below 1MiB.
+///
+/// The whole memory map is searched, as the usable memory below 1MiB may not be the first usable region in the map.
+pub(super) fn reserve_trampoline_frame(allocator: &mut BootInfoFrameAllocator) {
+    // Frame 0 holds the real mode IVT and BIOS data area on some systems, so it isn't used
+    match allocator.reserve_frame_in(0x1000..0x10_0000) {
+        Some(frame) => TRAMPOLINE_FRAME.store(frame.start_address().as_u64(), Ordering::Relaxed),
+        None => warn!("No frame below 1MiB is free, so application processors can't be started"),
+    }
+}
+
+/// An error which can occur when starting APs with [`start_aps`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartApsError {
+    /// [`reserve_trampoline_frame`] couldn't find a frame below 1MiB for the trampoline
+    NoTrampolineFrame,
+    /// The trampoline's page was already mapped in the kernel's page table, so it couldn't be identity mapped
+    TrampolinePageInUse,
+    /// The kernel's PML4 is above 4GiB, so the trampoline can't load it in real mode
+    PageTableTooHigh,
+    /// The current interrupt controller is not a local APIC, so IPIs can't be sent
+    NoLocalApic,
+    /// The AP with the given local APIC ID didn't start after two startup IPIs
+    ApDidNotStart(u8),
+}
+
+impl From<interrupt_controllers::NoLocalApicError> for StartApsError {
+    fn from(_: interrupt_controllers::NoLocalApicError) -> Self {
+        Self::NoLocalApic
+    }
+}
+
+/// Gets whether the core this function is called on is the BSP, from the BSP flag in the `IA32_APIC_BASE` MSR
+pub fn is_bsp() -> bool {
+    // SAFETY: This MSR exists on all processors with a local APIC, and reading it has no side effects
+    let apic_base = unsafe { Msr::new(0x1B).read() };
+    apic_base & (1 << 8) != 0
+}
+
+/// Gets a list of the cores which are running kernel code
+pub fn online_cpus() -> Vec<Cpu> {
+    interrupts::without_interrupts(|| ONLINE_CPUS.lock().clone())
+}
+
+/// Gets the local APIC IDs of the enabled cores listed in the MADT
+fn enabled_apic_ids() -> Vec<u8> {
+    KERNEL_STATE
+        .acpica
+        .lock()
+        .madt()
+        .records()
+        .filter_map(|record| match record {
+            // Bit 0 of the flags is set if the processor is enabled
+            MadtRecord::ProcessorLocalApic(lapic) if lapic.flags & 1 != 0 => Some(lapic.apic_id),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Waits until `condition` returns `true` or `timeout_ns` nanoseconds have passed.
+/// Returns whether `condition` returned `true`.
+fn wait_until(timeout_ns: u64, condition: impl Fn() -> bool) -> bool {
+    let target_uptime = KERNEL_STATE.uptime_ns() + timeout_ns;
+    while KERNEL_STATE.uptime_ns() < target_uptime {
+        if condition() {
+            return true;
+        }
+        hlt();
+    }
+    condition()
+}
+
+/// Starts all the enabled APs listed in the MADT, and adds them and this core to the list of [`online_cpus`].
+///
+/// If an AP fails to start, no more APs are started, as it may still start later and execute the trampoline.
+///
+/// # Safety
+/// This function must only be called once, on the BSP, after the local APIC and heap are initialised
+/// and while interrupts are enabled.
+pub unsafe fn start_aps() -> Result<(), StartApsError> {
+    let bsp_apic_id =
+        (interrupt_controllers::current_apic_id().ok_or(StartApsError::NoLocalApic)? >> 24) as u8;
+    interrupts::without_interrupts(|| {
+        ONLINE_CPUS.lock().push(Cpu {
+            apic_id: bsp_apic_id,
+            is_bsp: true,
+        })
+    });
+
+    let ap_ids: Vec<_> = enabled_apic_ids()
+        .into_iter()
+        .filter(|&id| id != bsp_apic_id)
+        .collect();
+    if ap_ids.is_empty() {
+        return Ok(());
+    }
+
+    let frame_addr = match TRAMPOLINE_FRAME.load(Ordering::Relaxed) {
+        0 => return Err(StartApsError::NoTrampolineFrame),
+        addr => PhysAddr::new(addr),
+    };
+    let frame = PhysFrame::<Size4KiB>::containing_address(frame_addr);
+
+    let (pml4, _) = Cr3::read();
+    if pml4.start_address().as_u64() > u32::MAX.into() {
+        return Err(StartApsError::PageTableTooHigh);
+    }
+
+    // Identity map the trampoline, so that it's still mapped when the AP enables paging
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame_addr.as_u64()));
+    {
+        let mut page_table = KERNEL_STATE.page_table.lock();
+        if page_table.translate_page(page).is_ok() {
+            return Err(StartApsError::TrampolinePageInUse);
+        }
+
+        let mut allocator = KERNEL_STATE.frame_allocator.lock();
+        // SAFETY: The page was not mapped, and the frame is reserved for the trampoline
+        unsafe {
+            page_table
+                .map_to(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    &mut *allocator,
+                )
+                .expect("Mapping the trampoline should have succeeded")
+                .flush();
+        }
+    }
+
+    let result = ap_ids.into_iter().try_for_each(|apic_id| {
+        // SAFETY: The page is mapped and reserved for the trampoline
+        unsafe { start_ap(page.start_address(), apic_id) }
+    });
+
+    // If an AP didn't start, it may still execute the trampoline later, so the page is left mapped
+    if !matches!(result, Err(StartApsError::ApDidNotStart(_))) {
+        let mut page_table = KERNEL_STATE.page_table.lock();
+        page_table
+            .unmap(page)
+            .expect("Unmapping the trampoline should have succeeded")
+            .1
+            .flush();
+    }
+
+    result
+}
+
+/// Copies the trampoline to the identity-mapped page at `page_addr`, and starts the AP with the given local APIC ID.
+///
+/// # Safety
+/// The page must be identity mapped and reserved for the trampoline.
+unsafe fn start_ap(page_addr: VirtAddr, apic_id: u8) -> Result<(), StartApsError> {
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+
+    // SAFETY: The page is reserved for the trampoline, so nothing else is using it.
+    // No AP is running the trampoline, as the previous AP has left it.
+    unsafe { write_trampoline(page_addr, stack_top, apic_id) };
+
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    // SAFETY: The AP is not running any kernel code, and the trampoline has been written to the page
+    unsafe {
+        interrupt_controllers::send_init_ipi(apic_id)?;
+        wait_until(INIT_DELAY_NS, || false);
+
+        let vector = (page_addr.as_u64() >> 12) as u8;
+        interrupt_controllers::send_startup_ipi(apic_id, vector)?;
+        if !wait_until(FIRST_STARTUP_TIMEOUT_NS, || {
+            AP_STARTED.load(Ordering::SeqCst)
+        }) {
+            interrupt_controllers::send_startup_ipi(apic_id, vector)?;
+            if !wait_until(SECOND_STARTUP_TIMEOUT_NS, || {
+                AP_STARTED.load(Ordering::SeqCst)
+            }) {
+                return Err(StartApsError::ApDidNotStart(apic_id));
+            }
+        }
+    }
+
+    info!("Started AP with APIC ID {apic_id}");
+    Ok(())
+}
+
+/// Copies the trampoline code to the page at `page_addr`, and fills in its fields.
+///
+/// # Safety
+/// The page must be identity mapped, and nothing else may be using it.
+unsafe fn write_trampoline(page_addr: VirtAddr, stack_top: u64, apic_id: u8) {
+    let len = trampoline_offset!(ap_trampoline_end);
+    assert!(len <= 0x1000, "The AP trampoline is longer than a page");
+
+    let base = page_addr.as_mut_ptr::<u8>();
+    let phys = u32::try_from(page_addr.as_u64()).unwrap();
+
+    // The GDT pointer's base and the long mode pointer's offset are 32 bits wide
+    let pointers = [
+        (
+            trampoline_offset!(ap_trampoline_gdt_ptr) + 2,
+            phys + trampoline_offset!(ap_trampoline_gdt) as u32,
+        ),
+        (
+            trampoline_offset!(ap_trampoline_long_mode_ptr),
+            phys + trampoline_offset!(ap_trampoline_long_mode) as u32,
+        ),
+    ];
+    let fields = [
+        (
+            trampoline_offset!(ap_trampoline_cr3),
+            Cr3::read().0.start_address().as_u64(),
+        ),
+        (trampoline_offset!(ap_trampoline_stack), stack_top),
+        (
+            trampoline_offset!(ap_trampoline_entry),
+            ap_entry as usize as u64,
+        ),
+        (trampoline_offset!(ap_trampoline_arg), apic_id.into()),
+    ];
+
+    // SAFETY: The trampoline is shorter than a page, and the page is free for the trampoline to be written to.
+    unsafe { base.copy_from_nonoverlapping(addr_of!(ap_trampoline_start), len) };
+
+    for (offset, value) in pointers {
+        // SAFETY: The pointers are within the trampoline, which is within the page
+        unsafe { base.add(offset).cast::<u32>().write_unaligned(value) };
+    }
+    for (offset, value) in fields {
+        // SAFETY: The fields are within the trampoline, which is within the page
+        unsafe { base.add(offset).cast::<u64>().write_unaligned(value) };
+    }
+}
+
+/// The function APs call once the trampoline has put them in long mode.
+/// This registers the AP as online and halts it.
+extern "C" fn ap_entry(apic_id: u64) -> ! {
+    // SAFETY: The BSP has loaded the GDT before starting any APs, and this is an AP
+    unsafe { gdt::load_ap_gdt() };
+
+    // SAFETY: The BSP has loaded the IDT before starting any APs, and this AP has just loaded its own TSS
+    unsafe { idt::load_ap_idt() };
+
+    // SAFETY: The BSP programmed its PAT in the same way before starting any APs
+    unsafe { pat::init() };
+
+    ONLINE_CPUS.lock().push(Cpu {
+        apic_id: apic_id as u8,
+        is_bsp: false,
+    });
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    loop {
+        interrupts::disable();
+        hlt();
+    }
+}
+
+/// Tests that the kernel (and so its tests) runs on the BSP
+#[test_case]
+fn test_is_bsp() {
+    assert!(is_bsp());
+}
+
+/// Tests that exactly one of the online cores is the BSP, and that no core is listed twice
+#[test_case]
+fn test_online_cpus() {
+    let cpus = online_cpus();
+    assert_eq!(cpus.iter().filter(|cpu| cpu.is_bsp).count(), 1);
+
+    for (i, cpu) in cpus.iter().enumerate() {
+        assert!(cpus[i + 1..]
+            .iter()
+            .all(|other| other.apic_id != cpu.apic_id));
+    }
+}
@@ -0,0 +1,319 @@
+//! Loading and running static ELF executables, such as programs in the initrd.
+//!
+//! Programs are loaded into the lower half of the kernel's address space, which is otherwise unused,
+//! and are run in ring 3 on their own stack. The program's entry point is jumped to with no arguments
+//! and a 16-byte aligned stack, and the program talks to the kernel and exits using the syscalls in [`crate::syscall`].
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use object::{
+    elf::{FileHeader64, EM_X86_64, ET_EXEC, PF_W, PF_X, PT_LOAD},
+    read::elf::{FileHeader, ProgramHeader},
+    NativeEndian,
+};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::{syscall::run_user_program, KERNEL_STATE};
+
+/// An ELF file
+type ElfFile<'a> = object::read::elf::ElfFile<'a, FileHeader64<NativeEndian>>;
+
+/// The lowest address a program can be loaded at. The first pages are left unmapped to catch null pointer accesses.
+const PROGRAM_REGION_START: u64 = 0x40_0000;
+/// The top of each program's stack. The region above this is left unmapped.
+const PROGRAM_STACK_TOP: u64 = 0x7FFF_FFFF_0000;
+/// The number of pages in each program's stack
+const PROGRAM_STACK_PAGES: u64 = 16;
+/// The highest address a program can be loaded at, which is below its stack and a guard page
+const PROGRAM_REGION_END: u64 = PROGRAM_STACK_TOP - (PROGRAM_STACK_PAGES + 1) * 0x1000;
+
+/// An error which can occur when loading a program with [`LoadedProgram::load`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// The file couldn't be parsed as an ELF file
+    Parse(object::read::Error),
+    /// The file is not an executable, e.g. it is a shared library or position independent executable
+    NotExecutable,
+    /// The file is not for x86_64
+    WrongArchitecture,
+    /// A segment is outside the region programs can be loaded in, or its data is outside the file
+    BadSegment,
+    /// A page the program needs is already mapped, e.g. by another program which is running
+    AddressInUse(VirtAddr),
+    /// There wasn't enough memory to load the program
+    OutOfMemory,
+}
+
+impl From<object::read::Error> for ExecError {
+    fn from(value: object::read::Error) -> Self {
+        Self::Parse(value)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for ExecError {
+    fn from(value: MapToError<Size4KiB>) -> Self {
+        match value {
+            MapToError::FrameAllocationFailed => Self::OutOfMemory,
+            // Pages are checked to be unmapped before mapping them, so this only happens if something else maps them first
+            MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => {
+                Self::AddressInUse(VirtAddr::zero())
+            }
+        }
+    }
+}
+
+/// A program which has been loaded into memory by [`LoadedProgram::load`].
+/// When this is dropped, the program's memory is unmapped.
+#[derive(Debug)]
+pub struct LoadedProgram {
+    /// The address of the program's entry point
+    entry: VirtAddr,
+    /// The pages mapped for the program's segments and stack
+    pages: Vec<Page>,
+}
+
+impl LoadedProgram {
+    /// Loads a static, position-dependent x86_64 ELF executable into memory, and maps a stack for it.
+    pub fn load(elf: &[u8]) -> Result<Self, ExecError> {
+        let file = ElfFile::parse(elf)?;
+        let endian = file.endian();
+        let header = file.elf_header();
+
+        if header.e_type(endian) != ET_EXEC {
+            return Err(ExecError::NotExecutable);
+        }
+        if header.e_machine(endian) != EM_X86_64 {
+            return Err(ExecError::WrongArchitecture);
+        }
+
+        // Work out which pages are needed and with what flags first, as segments can share pages
+        let mut pages: BTreeMap<Page, PageTableFlags> = BTreeMap::new();
+        let mut segments = Vec::new();
+
+        for segment in file.elf_program_headers() {
+            if segment.p_type(endian) != PT_LOAD || segment.p_memsz(endian) == 0 {
+                continue;
+            }
+
+            let start = segment.p_vaddr(endian);
+            let data = segment
+                .data(endian, elf)
+                .map_err(|()| ExecError::BadSegment)?;
+            let end = start
+                .checked_add(segment.p_memsz(endian))
+                .ok_or(ExecError::BadSegment)?;
+
+            if start < PROGRAM_REGION_START
+                || end > PROGRAM_REGION_END
+                || data.len() as u64 > segment.p_memsz(endian)
+            {
+                return Err(ExecError::BadSegment);
+            }
+
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            if segment.p_flags(endian) & PF_W != 0 {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if segment.p_flags(endian) & PF_X == 0 {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+
+            let range = Page::range_inclusive(
+                Page::containing_address(VirtAddr::new(start)),
+                Page::containing_address(VirtAddr::new(end - 1)),
+            );
+            for page in range {
+                // A page shared by two segments needs the permissions of both
+                let page_flags = pages
+                    .entry(page)
+                    .or_insert(flags | PageTableFlags::NO_EXECUTE);
+                *page_flags |= flags & PageTableFlags::WRITABLE;
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    page_flags.remove(PageTableFlags::NO_EXECUTE);
+                }
+            }
+
+            segments.push((VirtAddr::new(start), data));
+        }
+
+        let stack_start =
+            Page::containing_address(VirtAddr::new(PROGRAM_STACK_TOP)) - PROGRAM_STACK_PAGES;
+        for page in Page::range(stack_start, stack_start + PROGRAM_STACK_PAGES) {
+            pages.insert(
+                page,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_EXECUTE,
+            );
+        }
+
+        let page_table = KERNEL_STATE.page_table.lock();
+        if let Some(&page) = pages
+            .keys()
+            .find(|&&page| page_table.translate_page(page).is_ok())
+        {
+            return Err(ExecError::AddressInUse(page.start_address()));
+        }
+        drop(page_table);
+
+        // Construct the program before mapping anything, so that the pages are unmapped again if mapping fails
+        let mut program = Self {
+            entry: VirtAddr::new(header.e_entry(endian)),
+            pages: Vec::with_capacity(pages.len()),
+        };
+        program.map_pages(&pages, &segments)?;
+
+        Ok(program)
+    }
+
+    /// Allocates and maps frames for the given pages, and copies the segments' data into them.
+    /// Mapped pages are added to [`pages`][LoadedProgram::pages] as they are mapped,
+    /// so that they are unmapped when the program is dropped even if this fails partway through.
+    fn map_pages(
+        &mut self,
+        pages: &BTreeMap<Page, PageTableFlags>,
+        segments: &[(VirtAddr, &[u8])],
+    ) -> Result<(), ExecError> {
+        let mut page_table = KERNEL_STATE.page_table.lock();
+        let mut frame_allocator = KERNEL_STATE.frame_allocator.lock();
+        let phys_offset = page_table.phys_offset();
+
+        for (&page, &flags) in pages {
+            let frame: PhysFrame = frame_allocator
+                .allocate_frame()
+                .ok_or(ExecError::OutOfMemory)?;
+            let frame_ptr = (phys_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+
+            // SAFETY: The frame was just allocated, so nothing else is using it,
+            // and all of physical memory is mapped at `phys_offset`.
+            unsafe { frame_ptr.write_bytes(0, 0x1000) };
+
+            // Copy the parts of any segments which are in this page
+            for &(start, data) in segments {
+                let data_end = start + data.len() as u64;
+                let copy_start = start.max(page.start_address());
+                let copy_end = data_end.min(page.start_address() + 0x1000u64);
+                if copy_start >= copy_end {
+                    continue;
+                }
+
+                let src = &data[(copy_start - start) as usize..(copy_end - start) as usize];
+                // SAFETY: The copied range is within the frame, which is only used by this program
+                unsafe {
+                    frame_ptr
+                        .add((copy_start - page.start_address()) as usize)
+                        .copy_from_nonoverlapping(src.as_ptr(), src.len());
+                }
+            }
+
+            // SAFETY: The page was checked to be unmapped, and the frame was just allocated.
+            // The page is in the lower half, which the kernel doesn't use for anything else.
+            unsafe {
+                page_table
+                    .map_to(page, frame, flags, &mut *frame_allocator)?
+                    .flush();
+            }
+            self.pages.push(page);
+        }
+
+        Ok(())
+    }
+
+    /// Runs the program in ring 3 until it makes the exit syscall, and returns its exit code.
+    ///
+    /// # Safety
+    /// This must only be called on the BSP, and not while another program is running.
+    pub unsafe fn run(&self) -> i64 {
+        // SAFETY: The program's segments and stack were mapped as user accessible by `load`,
+        // and the stack top is page aligned. The caller guarantees that no other program is running.
+        unsafe { run_user_program(self.entry, VirtAddr::new(PROGRAM_STACK_TOP)) }
+    }
+}
+
+impl Drop for LoadedProgram {
+    fn drop(&mut self) {
+        let mut page_table = KERNEL_STATE.page_table.lock();
+        let mut frame_allocator = KERNEL_STATE.frame_allocator.lock();
+
+        for &page in &self.pages {
+            let (frame, flush) = page_table
+                .unmap(page)
+                .expect("Program's pages should still be mapped");
+            flush.flush();
+
+            // SAFETY: The frame was allocated for the program, which has finished running
+            unsafe { frame_allocator.free(PhysFrame::range(frame, frame + 1)) };
+        }
+    }
+}
+
+/// Tests that a minimal program is loaded and run in user mode, and that it can write to the console and exit
+#[test_case]
+fn test_run_program() {
+    /// The address the test program's only segment is loaded at
+    const LOAD_ADDRESS: u64 = PROGRAM_REGION_START;
+    /// The length of the ELF header and program header, after which the code starts
+    const HEADERS_LENGTH: usize = 64 + 56;
+    /// The message the program writes
+    const MESSAGE: &[u8] = b"Hello from user mode\n";
+    /// The length of the program's code, after which the message starts
+    const CODE_LENGTH: usize = 23;
+
+    let message_address = LOAD_ADDRESS as u32 + (HEADERS_LENGTH + CODE_LENGTH) as u32;
+    let mut code = Vec::new();
+    code.extend_from_slice(&[0xB8, 1, 0, 0, 0]); // mov eax, SYSCALL_WRITE
+    code.push(0xBF); // mov edi, message_address
+    code.extend_from_slice(&message_address.to_le_bytes());
+    code.push(0xBE); // mov esi, MESSAGE.len()
+    code.extend_from_slice(&(MESSAGE.len() as u32).to_le_bytes());
+    code.extend_from_slice(&[0xCD, 0x80]); // int 0x80
+    code.extend_from_slice(&[0x89, 0xC7]); // mov edi, eax
+    code.extend_from_slice(&[0x31, 0xC0]); // xor eax, eax (SYSCALL_EXIT)
+    code.extend_from_slice(&[0xCD, 0x80]); // int 0x80
+    assert_eq!(code.len(), CODE_LENGTH);
+
+    let file_length = (HEADERS_LENGTH + CODE_LENGTH + MESSAGE.len()) as u64;
+    let mut elf = Vec::new();
+
+    // ELF header
+    elf.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+    elf.extend_from_slice(&EM_X86_64.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes()); // Version
+    elf.extend_from_slice(&(LOAD_ADDRESS + HEADERS_LENGTH as u64).to_le_bytes()); // Entry point
+    elf.extend_from_slice(&64u64.to_le_bytes()); // Program header offset
+    elf.extend_from_slice(&0u64.to_le_bytes()); // Section header offset
+    elf.extend_from_slice(&0u32.to_le_bytes()); // Flags
+    elf.extend_from_slice(&64u16.to_le_bytes()); // ELF header size
+    elf.extend_from_slice(&56u16.to_le_bytes()); // Program header size
+    elf.extend_from_slice(&1u16.to_le_bytes()); // Number of program headers
+    elf.extend_from_slice(&[0; 6]); // No section headers
+
+    // Program header
+    elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+    elf.extend_from_slice(&(PF_X | object::elf::PF_R).to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes()); // Offset
+    elf.extend_from_slice(&LOAD_ADDRESS.to_le_bytes()); // Virtual address
+    elf.extend_from_slice(&LOAD_ADDRESS.to_le_bytes()); // Physical address
+    elf.extend_from_slice(&file_length.to_le_bytes()); // File size
+    elf.extend_from_slice(&file_length.to_le_bytes()); // Memory size
+    elf.extend_from_slice(&0x1000u64.to_le_bytes()); // Alignment
+
+    elf.extend_from_slice(&code);
+    elf.extend_from_slice(MESSAGE);
+
+    let program = LoadedProgram::load(&elf).unwrap();
+    // SAFETY: Tests run on the BSP, and no other program is running
+    assert_eq!(unsafe { program.run() }, MESSAGE.len() as i64);
+
+    // The pages are unmapped when the program is dropped, so it can be loaded again
+    drop(program);
+    let program = LoadedProgram::load(&elf).unwrap();
+    // SAFETY: Same as above
+    assert_eq!(unsafe { program.run() }, MESSAGE.len() as i64);
+}
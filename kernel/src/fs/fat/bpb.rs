@@ -0,0 +1,106 @@
+//! Parsing the BIOS parameter block (BPB) at the start of a FAT volume, which describes the volume's layout
+
+use super::FatError;
+use crate::fs::partition::has_boot_signature;
+
+/// The minimum number of clusters in a FAT32 volume.
+/// Volumes with fewer clusters are FAT12 or FAT16, even if they claim to be FAT32.
+const MIN_FAT32_CLUSTERS: u32 = 65525;
+
+/// The fields of a FAT32 volume's BIOS parameter block which are needed to read and write the volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BiosParameterBlock {
+    /// The number of bytes in each sector
+    pub bytes_per_sector: u16,
+    /// The number of sectors in each cluster
+    pub sectors_per_cluster: u8,
+    /// The number of sectors before the first FAT, including the boot sector
+    pub reserved_sectors: u16,
+    /// The number of copies of the FAT
+    pub num_fats: u8,
+    /// The total number of sectors in the volume
+    pub total_sectors: u32,
+    /// The number of sectors in each FAT
+    pub fat_size: u32,
+    /// The first cluster of the root directory
+    pub root_cluster: u32,
+    /// The sector of the FSInfo structure relative to the start of the volume, or [`None`] if the volume doesn't have one
+    pub fs_info_sector: Option<u16>,
+}
+
+impl BiosParameterBlock {
+    /// Parses the BPB from the first sector of a volume
+    pub fn parse(sector: &[u8]) -> Result<Self, FatError> {
+        if !has_boot_signature(sector) || !matches!(sector[0], 0xEB | 0xE9) {
+            return Err(FatError::NoFilesystem);
+        }
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(sector[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = sector[13];
+        let reserved_sectors = u16_at(14);
+        let num_fats = sector[16];
+        let root_entry_count = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            total_sectors => total_sectors as u32,
+        };
+        let fat_size_16 = u16_at(22);
+
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || num_fats == 0
+        {
+            return Err(FatError::NoFilesystem);
+        }
+
+        // FAT12 and FAT16 volumes have a fixed-size root directory and a 16-bit FAT size
+        if root_entry_count != 0 || fat_size_16 != 0 {
+            return Err(FatError::UnsupportedFatType);
+        }
+
+        let bpb = Self {
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            total_sectors,
+            fat_size: u32_at(36),
+            root_cluster: u32_at(44),
+            fs_info_sector: match u16_at(48) {
+                0 | 0xFFFF => None,
+                sector => Some(sector),
+            },
+        };
+
+        if bpb.data_start() >= total_sectors {
+            return Err(FatError::NoFilesystem);
+        }
+        if bpb.cluster_count() < MIN_FAT32_CLUSTERS {
+            return Err(FatError::UnsupportedFatType);
+        }
+
+        Ok(bpb)
+    }
+
+    /// The sector of the first FAT, relative to the start of the volume
+    pub fn fat_start(&self) -> u32 {
+        self.reserved_sectors as u32
+    }
+
+    /// The sector of the first cluster (cluster 2), relative to the start of the volume
+    pub fn data_start(&self) -> u32 {
+        self.fat_start() + self.num_fats as u32 * self.fat_size
+    }
+
+    /// The number of clusters in the data region
+    pub fn cluster_count(&self) -> u32 {
+        (self.total_sectors - self.data_start()) / self.sectors_per_cluster as u32
+    }
+}
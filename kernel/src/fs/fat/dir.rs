@@ -0,0 +1,392 @@
+//! Parsing directory entries, including the long file name (LFN) entries which precede a file's short (8.3) entry
+
+use alloc::{string::String, vec::Vec};
+
+/// The length of a directory entry in bytes
+pub const ENTRY_LENGTH: usize = 32;
+
+/// The attribute bit of an entry which is the volume's label rather than a file
+const ATTR_VOLUME_ID: u8 = 0x08;
+/// The attribute bit of a directory
+const ATTR_DIRECTORY: u8 = 0x10;
+/// The attribute bit which marks a file as changed since it was last backed up, which is set on new files
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes of a long file name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// The first byte of a free entry which marks the end of the directory
+pub const END_OF_DIRECTORY: u8 = 0x00;
+/// The first byte of an entry whose file has been deleted
+pub const DELETED: u8 = 0xE5;
+
+/// The short name of the `..` entry, which every directory other than the root directory has as its second entry
+const DOT_DOT_NAME: &[u8; 11] = b"..         ";
+
+/// The flag in the sequence number of an LFN entry which marks it as the last (and first stored) part of the name
+const LFN_LAST_ENTRY: u8 = 0x40;
+/// The number of UCS-2 characters in each LFN entry
+const LFN_CHARS_PER_ENTRY: usize = 13;
+/// The byte offsets of the UCS-2 characters in an LFN entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// The flag in byte 12 of a short entry which indicates that the base name is displayed in lower case
+const LOWER_CASE_BASE: u8 = 0x08;
+/// The flag in byte 12 of a short entry which indicates that the extension is displayed in lower case
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// A file or directory in a directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name of the entry, which is the long file name if it has one
+    pub name: String,
+    /// The entry's attribute bits
+    pub attributes: u8,
+    /// The first cluster of the entry's data, or 0 if it is empty
+    pub first_cluster: u32,
+    /// The length of the file in bytes. This is always 0 for directories.
+    pub size: u32,
+    /// The first cluster of the directory containing the entry, or 0 if it hasn't been set by the caller of [`parse_entries`]
+    pub(super) dir_cluster: u32,
+    /// The byte offset of the entry's short entry in the directory's data
+    pub(super) offset: usize,
+}
+
+impl DirEntry {
+    /// Whether the entry is a directory
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+}
+
+/// Calculates the checksum of a short name, which is stored in each of the name's LFN entries
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Formats a short (8.3) name, using the lower case flags in byte 12 of the entry
+fn short_name(entry: &[u8]) -> String {
+    let case = entry[12];
+    let convert = |bytes: &[u8], lower: bool| -> String {
+        let s: String = bytes.iter().map(|&b| b as char).collect();
+        let s = s.trim_end_matches(' ');
+        if lower {
+            s.to_ascii_lowercase()
+        } else {
+            String::from(s)
+        }
+    };
+
+    let mut name = convert(&entry[..8], case & LOWER_CASE_BASE != 0);
+    // 0x05 is used for names starting with 0xE5, which would otherwise mark the entry as deleted
+    if name.starts_with('\u{5}') {
+        name.replace_range(..1, "\u{E5}");
+    }
+
+    let extension = convert(&entry[8..11], case & LOWER_CASE_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+
+    name
+}
+
+/// The characters other than letters and digits which are allowed in a short name
+const SHORT_NAME_SPECIAL_CHARS: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// Encodes a name as a short (8.3) name and the lower case flags for byte 12 of its entry.
+/// Returns [`None`] if the name can't be represented as a short name, so would need a long file name entry.
+///
+/// Each of the base name and extension can be all upper case or all lower case, but not a mix of both.
+pub fn encode_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    let mut case = 0;
+    let (base_field, extension_field) = short_name.split_at_mut(8);
+
+    for (part, field, lower_flag) in [
+        (base, base_field, LOWER_CASE_BASE),
+        (extension, extension_field, LOWER_CASE_EXTENSION),
+    ] {
+        let bytes = part.as_bytes();
+        if !bytes
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL_CHARS.contains(b))
+        {
+            return None;
+        }
+
+        let has_upper = bytes.iter().any(u8::is_ascii_uppercase);
+        let has_lower = bytes.iter().any(u8::is_ascii_lowercase);
+        match (has_upper, has_lower) {
+            (true, true) => return None,
+            (false, true) => case |= lower_flag,
+            _ => (),
+        }
+
+        field[..bytes.len()].copy_from_slice(&part.to_ascii_uppercase().into_bytes());
+    }
+
+    Some((short_name, case))
+}
+
+/// Constructs a short directory entry for a new, empty file with the given name from [`encode_short_name`]
+pub fn new_file_entry(short_name: [u8; 11], case: u8) -> [u8; ENTRY_LENGTH] {
+    let mut entry = [0; ENTRY_LENGTH];
+    entry[..11].copy_from_slice(&short_name);
+    entry[11] = ATTR_ARCHIVE;
+    entry[12] = case;
+    entry
+}
+
+/// Sets the first cluster and size fields of a short directory entry
+pub fn set_cluster_and_size(entry: &mut [u8], first_cluster: u32, size: u32) {
+    entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+/// Parses the entries of a directory from its data.
+/// Deleted entries, volume labels, and the `.` and `..` entries are skipped.
+pub fn parse_entries(data: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+
+    // The parts of the long name read so far, and the checksum of the short name they belong to
+    let mut long_name: Vec<u16> = Vec::new();
+    let mut long_name_checksum = None;
+
+    for (i, entry) in data.chunks_exact(ENTRY_LENGTH).enumerate() {
+        match entry[0] {
+            END_OF_DIRECTORY => break,
+            DELETED => {
+                long_name_checksum = None;
+                continue;
+            }
+            _ => (),
+        }
+
+        let attributes = entry[11];
+
+        if attributes & 0x3F == ATTR_LONG_NAME {
+            let sequence = entry[0];
+            let checksum = entry[13];
+
+            // LFN entries are stored in reverse order, so the last part of the name comes first
+            if sequence & LFN_LAST_ENTRY != 0 {
+                long_name.clear();
+                long_name_checksum = Some(checksum);
+            } else if long_name_checksum != Some(checksum) {
+                long_name_checksum = None;
+                continue;
+            }
+
+            let chars = LFN_CHAR_OFFSETS
+                .iter()
+                .map(|&offset| u16::from_le_bytes([entry[offset], entry[offset + 1]]));
+            let mut part: Vec<u16> = chars.collect();
+            part.append(&mut long_name);
+            long_name = part;
+
+            continue;
+        }
+
+        let checksum = long_name_checksum.take();
+
+        if attributes & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+
+        let name = match checksum {
+            Some(checksum) if checksum == lfn_checksum(&entry[..11]) => {
+                let end = long_name
+                    .iter()
+                    .position(|&c| c == 0)
+                    .unwrap_or(long_name.len());
+                char::decode_utf16(long_name[..end].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            }
+            _ => short_name(entry),
+        };
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        entries.push(parse_short_entry(entry, name, i * ENTRY_LENGTH));
+    }
+
+    entries
+}
+
+/// Parses the `..` entry of a directory from its data, which points to the directory's parent.
+/// Its first cluster is 0 if the parent is the root directory.
+/// Returns [`None`] if the directory has no `..` entry, which is the case for the root directory.
+pub fn parse_parent_entry(data: &[u8]) -> Option<DirEntry> {
+    let entry = data.chunks_exact(ENTRY_LENGTH).nth(1)?;
+
+    if &entry[..11] == DOT_DOT_NAME && entry[11] & ATTR_DIRECTORY != 0 {
+        Some(parse_short_entry(entry, "..".into(), ENTRY_LENGTH))
+    } else {
+        None
+    }
+}
+
+/// Constructs a [`DirEntry`] from the fields of a short entry at byte `offset` in its directory
+fn parse_short_entry(entry: &[u8], name: String, offset: usize) -> DirEntry {
+    let cluster_high = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+    let cluster_low = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+
+    DirEntry {
+        name,
+        attributes: entry[11],
+        first_cluster: cluster_high << 16 | cluster_low,
+        size: u32::from_le_bytes(entry[28..32].try_into().unwrap()),
+        dir_cluster: 0,
+        offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{
+        encode_short_name, lfn_checksum, parse_entries, parse_parent_entry, short_name,
+        ENTRY_LENGTH, LFN_CHAR_OFFSETS, LFN_LAST_ENTRY,
+    };
+
+    /// Constructs a short directory entry
+    fn short_entry(name: &[u8; 11], attributes: u8, case: u8, cluster: u32, size: u32) -> [u8; 32] {
+        let mut entry = [0; ENTRY_LENGTH];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[12] = case;
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// Constructs the LFN entries for `name`, in the order they are stored
+    fn lfn_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+        let mut chars: Vec<u16> = name.encode_utf16().collect();
+        if chars.len() % 13 != 0 {
+            chars.push(0);
+        }
+        while chars.len() % 13 != 0 {
+            chars.push(0xFFFF);
+        }
+
+        let count = chars.len() / 13;
+        let mut entries: Vec<[u8; 32]> = chars
+            .chunks(13)
+            .enumerate()
+            .map(|(i, part)| {
+                let mut entry = [0; ENTRY_LENGTH];
+                entry[0] = i as u8 + 1;
+                if i + 1 == count {
+                    entry[0] |= LFN_LAST_ENTRY;
+                }
+                entry[11] = 0x0F;
+                entry[13] = lfn_checksum(short_name);
+                for (&offset, c) in LFN_CHAR_OFFSETS.iter().zip(part) {
+                    entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+                }
+                entry
+            })
+            .collect();
+
+        entries.reverse();
+        entries
+    }
+
+    #[test_case]
+    fn test_parse_entries() {
+        let mut data = Vec::new();
+
+        data.extend_from_slice(&short_entry(b"VOLUME     ", 0x08, 0, 0, 0));
+        data.extend_from_slice(&short_entry(b".          ", 0x10, 0, 5, 0));
+        for entry in lfn_entries("A long file name.txt", b"ALONGF~1TXT") {
+            data.extend_from_slice(&entry);
+        }
+        data.extend_from_slice(&short_entry(b"ALONGF~1TXT", 0x20, 0, 0x12345, 100));
+        data.extend_from_slice(&short_entry(b"README  MD ", 0x20, 0x08, 7, 5));
+        let mut deleted = short_entry(b"DELETED    ", 0x20, 0, 8, 0);
+        deleted[0] = 0xE5;
+        data.extend_from_slice(&deleted);
+        // An LFN entry whose checksum doesn't match the short entry is ignored
+        for entry in lfn_entries("Wrong name", b"OTHER      ") {
+            data.extend_from_slice(&entry);
+        }
+        data.extend_from_slice(&short_entry(b"EFI        ", 0x10, 0, 9, 0));
+        data.extend_from_slice(&[0; ENTRY_LENGTH]);
+        data.extend_from_slice(&short_entry(b"AFTEREND   ", 0x20, 0, 10, 0));
+
+        let entries = parse_entries(&data);
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+
+        assert_eq!(names, ["A long file name.txt", "readme.MD", "EFI"]);
+        assert_eq!(entries[0].first_cluster, 0x12345);
+        assert_eq!(entries[0].size, 100);
+        assert!(!entries[0].is_dir());
+        assert!(entries[2].is_dir());
+        // The offsets are of the short entries, after the volume label, `.`, and the 2 LFN entries
+        assert_eq!(entries[0].offset, 4 * ENTRY_LENGTH);
+        assert_eq!(entries[1].offset, 5 * ENTRY_LENGTH);
+    }
+
+    #[test_case]
+    fn test_parse_parent_entry() {
+        // A directory in the root directory, whose `..` entry has a first cluster of 0
+        let mut data = Vec::new();
+        data.extend_from_slice(&short_entry(b".          ", 0x10, 0, 5, 0));
+        data.extend_from_slice(&short_entry(b"..         ", 0x10, 0, 0, 0));
+        data.extend_from_slice(&short_entry(b"README  MD ", 0x20, 0, 7, 5));
+
+        let parent = parse_parent_entry(&data).unwrap();
+        assert_eq!(parent.name, "..");
+        assert_eq!(parent.first_cluster, 0);
+        assert!(parent.is_dir());
+
+        // The root directory has no `.` or `..` entries
+        assert_eq!(parse_parent_entry(&data[ENTRY_LENGTH * 2..]), None);
+    }
+
+    #[test_case]
+    fn test_encode_short_name() {
+        assert_eq!(encode_short_name("README.MD"), Some((*b"README  MD ", 0)));
+        assert_eq!(encode_short_name("notes"), Some((*b"NOTES      ", 0x08)));
+
+        // Encoding a name and then formatting it gives back the same name
+        for name in ["readme.MD", "A.TXT", "log_1.txt", "DIR"] {
+            let (encoded, case) = encode_short_name(name).unwrap();
+            let mut entry = [0; ENTRY_LENGTH];
+            entry[..11].copy_from_slice(&encoded);
+            entry[12] = case;
+            assert_eq!(short_name(&entry), name);
+        }
+
+        // These need long file names
+        for name in [
+            "",
+            ".",
+            "..",
+            "toolongname.txt",
+            "a.text",
+            "MixedCase",
+            "a b",
+            "a.b.c",
+        ] {
+            assert_eq!(encode_short_name(name), None, "{name}");
+        }
+    }
+}
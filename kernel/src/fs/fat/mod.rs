@@ -0,0 +1,727 @@
+//! Support for reading and writing FAT32 filesystems.
+//!
+//! A FAT volume starts with reserved sectors containing the [BIOS parameter block], followed by one or more copies
+//! of the file allocation table (FAT), and then the data region. The data region is divided into clusters,
+//! and the FAT has one 32-bit entry per cluster holding the number of the next cluster in the same file,
+//! so files and directories are stored as chains of clusters. A directory's data is an array of 32-byte [entries].
+//!
+//! The format is described in Microsoft's "FAT32 File System Specification".
+//!
+//! Writes go straight to the device, with no caching, so nothing needs to be flushed. Every copy of the FAT is updated.
+//! New files can only be created with names which fit in a short (8.3) name, as long file names aren't written.
+//!
+//! [BIOS parameter block]: bpb::BiosParameterBlock
+//! [entries]: dir::DirEntry
+
+mod bpb;
+mod dir;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::block::{BlockDevice, BlockDeviceError};
+
+use self::bpb::BiosParameterBlock;
+pub use self::dir::DirEntry;
+
+use super::partition::partition_starts;
+
+/// The bits of a FAT entry which hold the next cluster. The top 4 bits are reserved.
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+/// FAT entries greater than or equal to this mark the end of a cluster chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// The FAT entry of a cluster which contains bad sectors
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+/// The FAT entry of a free cluster
+const FREE_CLUSTER: u32 = 0;
+/// The FAT entry written to the last cluster of a chain
+const END_OF_CHAIN_MARKER: u32 = 0x0FFF_FFFF;
+/// The number of the first cluster in the data region
+const FIRST_CLUSTER: u32 = 2;
+
+/// The signature at the start of the FSInfo sector
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+/// The signature at offset 484 of the FSInfo sector
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// The offset of the free cluster count in the FSInfo sector. The next free cluster hint follows it.
+const FS_INFO_FREE_COUNT_OFFSET: usize = 488;
+
+/// An error which can occur when reading a FAT filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    /// The block device returned an error
+    Device(BlockDeviceError),
+    /// No FAT filesystem was found on the device
+    NoFilesystem,
+    /// The filesystem is FAT12 or FAT16, which aren't supported
+    UnsupportedFatType,
+    /// The filesystem's sector size isn't the same as the block size of the device
+    UnsupportedSectorSize,
+    /// A cluster chain is malformed, e.g. it contains a free or bad cluster, or it loops
+    BadClusterChain,
+    /// There are no free clusters left on the volume
+    NoSpace,
+    /// The file is larger than the 4GiB which FAT32 can store
+    FileTooLarge,
+    /// A file can't be created with the given name because it isn't a valid short (8.3) name
+    InvalidName,
+    /// A file can't be created because an entry with the same name already exists
+    AlreadyExists,
+    /// A file can't be created because its parent directory doesn't exist
+    NoSuchDirectory,
+}
+
+impl From<BlockDeviceError> for FatError {
+    fn from(value: BlockDeviceError) -> Self {
+        Self::Device(value)
+    }
+}
+
+/// A FAT32 filesystem on a [`BlockDevice`].
+///
+/// Writes through different [`FatFilesystem`]s for the same volume aren't synchronised,
+/// so only one task should write to a volume at once.
+pub struct FatFilesystem {
+    /// The device the filesystem is on
+    device: Arc<dyn BlockDevice>,
+    /// The block address of the start of the volume
+    start_lba: u64,
+    /// The volume's BIOS parameter block
+    bpb: BiosParameterBlock,
+    /// The cluster to start searching for a free cluster from
+    next_free_cluster: AtomicU32,
+    /// Whether the free cluster count in the FSInfo sector has been marked as unknown,
+    /// which is done before the FAT is first changed, as the count isn't kept up to date
+    fs_info_invalidated: AtomicBool,
+}
+
+impl core::fmt::Debug for FatFilesystem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FatFilesystem")
+            .field("start_lba", &self.start_lba)
+            .field("bpb", &self.bpb)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FatFilesystem {
+    /// Finds a FAT32 filesystem on the device, either covering the whole device or in one of its partitions.
+    /// If there are several, the first is used.
+    pub async fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FatError> {
+        let mut candidates = vec![0];
+        candidates.extend(partition_starts(&*device).await?);
+
+        let mut error = FatError::NoFilesystem;
+        let mut sector = vec![0; device.block_size()];
+
+        for start_lba in candidates {
+            if start_lba >= device.num_blocks() {
+                continue;
+            }
+
+            device.read_blocks(start_lba, 1, &mut sector).await?;
+
+            match BiosParameterBlock::parse(&sector) {
+                Ok(bpb) if bpb.bytes_per_sector as usize != device.block_size() => {
+                    error = FatError::UnsupportedSectorSize;
+                }
+                Ok(bpb) => {
+                    return Ok(Self {
+                        device,
+                        start_lba,
+                        bpb,
+                        next_free_cluster: AtomicU32::new(FIRST_CLUSTER),
+                        fs_info_invalidated: AtomicBool::new(false),
+                    })
+                }
+                Err(FatError::NoFilesystem) => (),
+                // Report that a filesystem was found but can't be read, rather than that none was found
+                Err(e) => error = e,
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Gets the number of bytes in each cluster
+    fn cluster_size(&self) -> usize {
+        self.bpb.bytes_per_sector as usize * self.bpb.sectors_per_cluster as usize
+    }
+
+    /// Gets the block address of the first sector of the given cluster
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.start_lba
+            + self.bpb.data_start() as u64
+            + (cluster - FIRST_CLUSTER) as u64 * self.bpb.sectors_per_cluster as u64
+    }
+
+    /// Gets the block address of the sector of the first FAT containing the entry for the given cluster,
+    /// and the byte offset of the entry in that sector
+    fn fat_entry_location(&self, cluster: u32) -> (u64, usize) {
+        let bytes_per_sector = self.bpb.bytes_per_sector as u64;
+        let offset = cluster as u64 * 4;
+        let lba = self.start_lba + self.bpb.fat_start() as u64 + offset / bytes_per_sector;
+        (lba, (offset % bytes_per_sector) as usize)
+    }
+
+    /// Reads one sector of the volume
+    async fn read_sector(&self, lba: u64) -> Result<Vec<u8>, FatError> {
+        let mut sector = vec![0; self.bpb.bytes_per_sector as usize];
+        self.device.read_blocks(lba, 1, &mut sector).await?;
+        Ok(sector)
+    }
+
+    /// Gets the FAT entry for the given cluster, which is the next cluster in the chain
+    async fn fat_entry(&self, cluster: u32) -> Result<u32, FatError> {
+        let (lba, offset) = self.fat_entry_location(cluster);
+        let sector = self.read_sector(lba).await?;
+
+        let entry = u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+        Ok(entry & FAT_ENTRY_MASK)
+    }
+
+    /// Sets the FAT entry for the given cluster in every copy of the FAT.
+    /// The reserved top 4 bits of the entry are preserved.
+    async fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FatError> {
+        self.invalidate_fs_info().await?;
+
+        let (first_lba, offset) = self.fat_entry_location(cluster);
+
+        for i in 0..self.bpb.num_fats as u64 {
+            let lba = first_lba + i * self.bpb.fat_size as u64;
+            let mut sector = self.read_sector(lba).await?;
+
+            let old = u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+            let new = (old & !FAT_ENTRY_MASK) | (value & FAT_ENTRY_MASK);
+            sector[offset..offset + 4].copy_from_slice(&new.to_le_bytes());
+
+            self.device.write_blocks(lba, &sector).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Marks the free cluster count and next free cluster hint in the FSInfo sector as unknown, if it hasn't been done already.
+    /// The count would otherwise be wrong once clusters are allocated or freed.
+    async fn invalidate_fs_info(&self) -> Result<(), FatError> {
+        let Some(fs_info_sector) = self.bpb.fs_info_sector else {
+            return Ok(());
+        };
+        if self.fs_info_invalidated.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let lba = self.start_lba + fs_info_sector as u64;
+        let mut sector = self.read_sector(lba).await?;
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+        if u32_at(0) != FS_INFO_LEAD_SIGNATURE || u32_at(484) != FS_INFO_STRUCT_SIGNATURE {
+            return Ok(());
+        }
+
+        sector[FS_INFO_FREE_COUNT_OFFSET..FS_INFO_FREE_COUNT_OFFSET + 8].fill(0xFF);
+        self.device.write_blocks(lba, &sector).await?;
+
+        Ok(())
+    }
+
+    /// Checks that a cluster number refers to a cluster in the data region
+    fn check_cluster(&self, cluster: u32) -> Result<(), FatError> {
+        if (FIRST_CLUSTER..self.bpb.cluster_count() + FIRST_CLUSTER).contains(&cluster) {
+            Ok(())
+        } else {
+            Err(FatError::BadClusterChain)
+        }
+    }
+
+    /// Gets the clusters of the chain starting at `first_cluster`, in order.
+    /// A `first_cluster` of 0 is an empty chain.
+    async fn chain_clusters(&self, first_cluster: u32) -> Result<Vec<u32>, FatError> {
+        let mut clusters = Vec::new();
+        if first_cluster == 0 {
+            return Ok(clusters);
+        }
+
+        let mut cluster = first_cluster;
+
+        loop {
+            self.check_cluster(cluster)?;
+
+            // A chain can't be longer than the number of clusters, so a longer one must contain a loop
+            if clusters.len() >= self.bpb.cluster_count() as usize {
+                return Err(FatError::BadClusterChain);
+            }
+            clusters.push(cluster);
+
+            cluster = match self.fat_entry(cluster).await? {
+                next if next >= END_OF_CHAIN => break,
+                BAD_CLUSTER => return Err(FatError::BadClusterChain),
+                next => next,
+            };
+        }
+
+        Ok(clusters)
+    }
+
+    /// Reads the whole cluster chain starting at `first_cluster`.
+    /// A `first_cluster` of 0 is an empty chain.
+    async fn read_chain(&self, first_cluster: u32) -> Result<Vec<u8>, FatError> {
+        let clusters = self.chain_clusters(first_cluster).await?;
+        let mut data = vec![0; clusters.len() * self.cluster_size()];
+
+        for (&cluster, chunk) in clusters.iter().zip(data.chunks_mut(self.cluster_size())) {
+            self.device
+                .read_blocks(
+                    self.cluster_lba(cluster),
+                    self.bpb.sectors_per_cluster as u64,
+                    chunk,
+                )
+                .await?;
+        }
+
+        Ok(data)
+    }
+
+    /// Finds a free cluster and marks it as the end of a chain
+    async fn allocate_cluster(&self) -> Result<u32, FatError> {
+        let end = self.bpb.cluster_count() + FIRST_CLUSTER;
+        let start = self
+            .next_free_cluster
+            .load(Ordering::Relaxed)
+            .clamp(FIRST_CLUSTER, end - 1);
+
+        // The sector of the FAT which was read last, to avoid reading each sector once for every entry in it
+        let mut cached_sector: Option<(u64, Vec<u8>)> = None;
+
+        // Search from the hint to the end, and then wrap around to the start
+        for cluster in (start..end).chain(FIRST_CLUSTER..start) {
+            let (lba, offset) = self.fat_entry_location(cluster);
+            if cached_sector.as_ref().map(|(cached_lba, _)| *cached_lba) != Some(lba) {
+                cached_sector = Some((lba, self.read_sector(lba).await?));
+            }
+            let (_, sector) = cached_sector.as_ref().unwrap();
+
+            let entry = u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+            if entry & FAT_ENTRY_MASK == FREE_CLUSTER {
+                self.set_fat_entry(cluster, END_OF_CHAIN_MARKER).await?;
+                self.next_free_cluster.store(cluster + 1, Ordering::Relaxed);
+                return Ok(cluster);
+            }
+        }
+
+        Err(FatError::NoSpace)
+    }
+
+    /// Writes `data` to the cluster chain starting at `first_cluster`, replacing its contents.
+    /// Clusters are allocated or freed so that the chain is just long enough to hold the data.
+    /// Returns the first cluster of the new chain, which is 0 if `data` is empty.
+    async fn write_chain(&self, first_cluster: u32, data: &[u8]) -> Result<u32, FatError> {
+        let mut clusters = self.chain_clusters(first_cluster).await?;
+        let needed = data.len().div_ceil(self.cluster_size());
+
+        // Free any clusters which aren't needed, after cutting them off the end of the chain
+        if needed < clusters.len() {
+            if let Some(&last) = needed.checked_sub(1).and_then(|i| clusters.get(i)) {
+                self.set_fat_entry(last, END_OF_CHAIN_MARKER).await?;
+            }
+            for &cluster in &clusters[needed..] {
+                self.set_fat_entry(cluster, FREE_CLUSTER).await?;
+            }
+            clusters.truncate(needed);
+        }
+
+        while clusters.len() < needed {
+            let cluster = self.allocate_cluster().await?;
+            if let Some(&last) = clusters.last() {
+                self.set_fat_entry(last, cluster).await?;
+            }
+            clusters.push(cluster);
+        }
+
+        let mut buffer = vec![0; self.cluster_size()];
+        for (&cluster, chunk) in clusters.iter().zip(data.chunks(self.cluster_size())) {
+            // The end of the last cluster is zeroed
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            buffer[chunk.len()..].fill(0);
+            self.device
+                .write_blocks(self.cluster_lba(cluster), &buffer)
+                .await?;
+        }
+
+        Ok(clusters.first().copied().unwrap_or(0))
+    }
+
+    /// Reads the entries of the directory starting at the given cluster
+    async fn read_dir_at(&self, cluster: u32) -> Result<Vec<DirEntry>, FatError> {
+        let mut entries = dir::parse_entries(&self.read_chain(cluster).await?);
+        for entry in &mut entries {
+            entry.dir_cluster = cluster;
+        }
+        Ok(entries)
+    }
+
+    /// Reads the sector containing the directory entry at byte `offset` in the directory starting at `dir_cluster`,
+    /// calls `f` with the entry's bytes, and writes the sector back
+    async fn modify_dir_entry(
+        &self,
+        dir_cluster: u32,
+        offset: usize,
+        f: impl FnOnce(&mut [u8]),
+    ) -> Result<(), FatError> {
+        let clusters = self.chain_clusters(dir_cluster).await?;
+        let cluster = *clusters
+            .get(offset / self.cluster_size())
+            .ok_or(FatError::BadClusterChain)?;
+
+        let bytes_per_sector = self.bpb.bytes_per_sector as usize;
+        let offset_in_cluster = offset % self.cluster_size();
+        let lba = self.cluster_lba(cluster) + (offset_in_cluster / bytes_per_sector) as u64;
+
+        let mut sector = self.read_sector(lba).await?;
+        let start = offset_in_cluster % bytes_per_sector;
+        f(&mut sector[start..start + dir::ENTRY_LENGTH]);
+        self.device.write_blocks(lba, &sector).await?;
+
+        Ok(())
+    }
+
+    /// Finds the byte offset of a free entry in the directory starting at `dir_cluster`,
+    /// adding a cluster to the directory if it is full
+    async fn free_dir_entry(&self, dir_cluster: u32) -> Result<usize, FatError> {
+        let data = self.read_chain(dir_cluster).await?;
+
+        let free = data
+            .chunks_exact(dir::ENTRY_LENGTH)
+            .position(|entry| matches!(entry[0], dir::END_OF_DIRECTORY | dir::DELETED));
+        if let Some(i) = free {
+            return Ok(i * dir::ENTRY_LENGTH);
+        }
+
+        // The new cluster is zeroed, so all its entries are free
+        let mut new_data = data;
+        let offset = new_data.len();
+        new_data.resize(offset + self.cluster_size(), 0);
+        self.write_chain(dir_cluster, &new_data).await?;
+
+        Ok(offset)
+    }
+
+    /// Gets the first cluster of the directory with the given entry.
+    /// The `..` entry of a directory in the root directory has a first cluster of 0 rather than the root directory's cluster.
+    fn dir_first_cluster(&self, entry: &DirEntry) -> u32 {
+        if entry.first_cluster == 0 {
+            self.bpb.root_cluster
+        } else {
+            entry.first_cluster
+        }
+    }
+
+    /// Finds the entry at `path`. Returns [`None`] for the root directory, which has no entry.
+    /// The outer [`Option`] is [`None`] if there is no such entry.
+    async fn find(&self, path: &str) -> Result<Option<Option<DirEntry>>, FatError> {
+        let mut current: Option<DirEntry> = None;
+
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let cluster = match &current {
+                None => self.bpb.root_cluster,
+                Some(entry) if entry.is_dir() => self.dir_first_cluster(entry),
+                Some(_) => return Ok(None),
+            };
+
+            let entry = if component == ".." {
+                // The root directory has no `..` entry, and is its own parent
+                if cluster == self.bpb.root_cluster {
+                    current = None;
+                    continue;
+                }
+
+                dir::parse_parent_entry(&self.read_chain(cluster).await?)
+            } else {
+                // Names are case-insensitive
+                self.read_dir_at(cluster)
+                    .await?
+                    .into_iter()
+                    .find(|entry| entry.name.eq_ignore_ascii_case(component))
+            };
+
+            match entry {
+                Some(entry) => current = Some(entry),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(current))
+    }
+
+    /// Opens the file at `path`, which is relative to the root directory.
+    /// Returns [`None`] if there is no such file, or if it is a directory.
+    pub async fn open(&self, path: &str) -> Result<Option<File<'_>>, FatError> {
+        match self.find(path).await? {
+            Some(Some(entry)) if !entry.is_dir() => Ok(Some(File { fs: self, entry })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Creates an empty file at `path`, which is relative to the root directory.
+    /// The file's name must be a valid short (8.3) name, as long file names aren't written.
+    pub async fn create(&self, path: &str) -> Result<File<'_>, FatError> {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let (short_name, case) = dir::encode_short_name(name).ok_or(FatError::InvalidName)?;
+
+        let dir_cluster = match self.find(parent).await? {
+            Some(None) => self.bpb.root_cluster,
+            Some(Some(entry)) if entry.is_dir() => self.dir_first_cluster(&entry),
+            _ => return Err(FatError::NoSuchDirectory),
+        };
+
+        if self
+            .read_dir_at(dir_cluster)
+            .await?
+            .iter()
+            .any(|entry| entry.name.eq_ignore_ascii_case(name))
+        {
+            return Err(FatError::AlreadyExists);
+        }
+
+        let offset = self.free_dir_entry(dir_cluster).await?;
+        let new_entry = dir::new_file_entry(short_name, case);
+        self.modify_dir_entry(dir_cluster, offset, |entry| {
+            entry.copy_from_slice(&new_entry)
+        })
+        .await?;
+
+        Ok(File {
+            fs: self,
+            entry: DirEntry {
+                name: name.into(),
+                attributes: dir::ATTR_ARCHIVE,
+                first_cluster: 0,
+                size: 0,
+                dir_cluster,
+                offset,
+            },
+        })
+    }
+
+    /// Lists the entries of the directory at `path`, which is relative to the root directory.
+    /// Returns [`None`] if there is no such directory.
+    pub async fn read_dir(&self, path: &str) -> Result<Option<Vec<DirEntry>>, FatError> {
+        let cluster = match self.find(path).await? {
+            None => return Ok(None),
+            Some(None) => self.bpb.root_cluster,
+            Some(Some(entry)) if entry.is_dir() => self.dir_first_cluster(&entry),
+            Some(Some(_)) => return Ok(None),
+        };
+
+        self.read_dir_at(cluster).await.map(Some)
+    }
+}
+
+/// A file in a [`FatFilesystem`]
+#[derive(Debug)]
+pub struct File<'a> {
+    /// The filesystem the file is in
+    fs: &'a FatFilesystem,
+    /// The file's directory entry
+    entry: DirEntry,
+}
+
+impl<'a> File<'a> {
+    /// Reads the whole contents of the file
+    pub async fn read_to_end(&self) -> Result<Vec<u8>, FatError> {
+        let mut data = self.fs.read_chain(self.entry.first_cluster).await?;
+
+        if data.len() < self.entry.size as usize {
+            return Err(FatError::BadClusterChain);
+        }
+        data.truncate(self.entry.size as usize);
+
+        Ok(data)
+    }
+
+    /// Adds `data` to the end of the file, and updates the file's directory entry.
+    ///
+    /// The whole file is rewritten, so this is only suitable for small files.
+    pub async fn append(&mut self, data: &[u8]) -> Result<(), FatError> {
+        let mut contents = self.read_to_end().await?;
+        contents.extend_from_slice(data);
+        self.write_all(&contents).await
+    }
+
+    /// Replaces the contents of the file with `data`, and updates the file's directory entry
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<(), FatError> {
+        let size = u32::try_from(data.len()).map_err(|_| FatError::FileTooLarge)?;
+
+        let first_cluster = self.fs.write_chain(self.entry.first_cluster, data).await?;
+        self.entry.first_cluster = first_cluster;
+        self.entry.size = size;
+
+        self.fs
+            .modify_dir_entry(self.entry.dir_cluster, self.entry.offset, |entry| {
+                dir::set_cluster_and_size(entry, first_cluster, size)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll},
+    };
+
+    use spin::Mutex;
+
+    use super::{FatFilesystem, END_OF_CHAIN, FREE_CLUSTER};
+    use crate::{
+        block::{BlockDevice, BlockFuture},
+        scheduler::no_op_waker,
+    };
+
+    /// The number of bytes in each block of a [`MemoryDevice`]
+    const BLOCK_SIZE: usize = 512;
+    /// The number of reserved sectors in the test volume
+    const RESERVED_SECTORS: u16 = 32;
+    /// The number of sectors in each FAT of the test volume, which is enough for [`CLUSTERS`] entries
+    const FAT_SIZE: u32 = 520;
+    /// The number of clusters in the test volume, which is the fewest a FAT32 volume can have, rounded up
+    const CLUSTERS: u32 = 66000;
+
+    /// A block device stored in memory. Blocks which haven't been written read as zeroes,
+    /// so only the blocks which are used take up memory.
+    struct MemoryDevice {
+        /// The number of blocks on the device
+        num_blocks: u64,
+        /// The blocks which have been written
+        blocks: Mutex<BTreeMap<u64, Vec<u8>>>,
+    }
+
+    impl BlockDevice for MemoryDevice {
+        fn block_size(&self) -> usize {
+            BLOCK_SIZE
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.num_blocks
+        }
+
+        fn read_blocks<'a>(&'a self, lba: u64, count: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+            Box::pin(async move {
+                self.check_request(lba, count, buf.len())?;
+                let blocks = self.blocks.lock();
+                for (i, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+                    match blocks.get(&(lba + i as u64)) {
+                        Some(block) => chunk.copy_from_slice(block),
+                        None => chunk.fill(0),
+                    }
+                }
+                Ok(())
+            })
+        }
+
+        fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+            Box::pin(async move {
+                let count = (buf.len() / BLOCK_SIZE) as u64;
+                self.check_request(lba, count, buf.len())?;
+                let mut blocks = self.blocks.lock();
+                for (i, chunk) in buf.chunks(BLOCK_SIZE).enumerate() {
+                    blocks.insert(lba + i as u64, chunk.to_vec());
+                }
+                Ok(())
+            })
+        }
+    }
+
+    /// Constructs a device containing an empty FAT32 volume with 2 FATs and one sector per cluster,
+    /// whose root directory is cluster 2
+    fn formatted_device() -> MemoryDevice {
+        let mut boot_sector = vec![0; BLOCK_SIZE];
+        boot_sector[0] = 0xEB;
+        boot_sector[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        boot_sector[13] = 1;
+        boot_sector[14..16].copy_from_slice(&RESERVED_SECTORS.to_le_bytes());
+        boot_sector[16] = 2;
+        let total_sectors = RESERVED_SECTORS as u32 + 2 * FAT_SIZE + CLUSTERS;
+        boot_sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+        boot_sector[36..40].copy_from_slice(&FAT_SIZE.to_le_bytes());
+        boot_sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot_sector[510] = 0x55;
+        boot_sector[511] = 0xAA;
+
+        // The first 2 entries are reserved, and the third ends the root directory's chain
+        let mut fat_sector = vec![0; BLOCK_SIZE];
+        for (i, entry) in [0x0FFF_FFF8u32, 0x0FFF_FFFF, 0x0FFF_FFFF]
+            .into_iter()
+            .enumerate()
+        {
+            fat_sector[i * 4..i * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+
+        let mut blocks = BTreeMap::new();
+        blocks.insert(0, boot_sector);
+        blocks.insert(RESERVED_SECTORS as u64, fat_sector.clone());
+        blocks.insert(RESERVED_SECTORS as u64 + FAT_SIZE as u64, fat_sector);
+
+        MemoryDevice {
+            num_blocks: total_sectors as u64,
+            blocks: Mutex::new(blocks),
+        }
+    }
+
+    /// Polls a future once, expecting it to be ready.
+    /// The futures of a [`MemoryDevice`] are always ready, so neither are the filesystem's futures which use it.
+    fn ready<F: Future>(future: F) -> F::Output {
+        let waker = no_op_waker();
+        match pin!(future).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Future wasn't ready"),
+        }
+    }
+
+    /// Reads one copy of the FAT
+    fn read_fat(device: &MemoryDevice, index: u64) -> Vec<u8> {
+        let mut fat = vec![0; FAT_SIZE as usize * BLOCK_SIZE];
+        let lba = RESERVED_SECTORS as u64 + index * FAT_SIZE as u64;
+        ready(device.read_blocks(lba, FAT_SIZE as u64, &mut fat)).unwrap();
+        fat
+    }
+
+    #[test_case]
+    fn test_write_read_and_shrink_file() {
+        let device = Arc::new(formatted_device());
+        let fs = ready(FatFilesystem::new(device.clone())).unwrap();
+
+        // 3 clusters, the last of which is partly used
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let mut file = ready(fs.create("DATA.BIN")).unwrap();
+        ready(file.write_all(&data)).unwrap();
+
+        let clusters = ready(fs.chain_clusters(file.entry.first_cluster)).unwrap();
+        assert_eq!(clusters.len(), 3);
+
+        let reopened = ready(fs.open("data.bin")).unwrap().unwrap();
+        assert_eq!(ready(reopened.read_to_end()).unwrap(), data);
+        assert_eq!(read_fat(&device, 0), read_fat(&device, 1));
+
+        // Overwriting with data which fits in one cluster frees the other 2
+        ready(file.write_all(b"short")).unwrap();
+        assert_eq!(file.entry.first_cluster, clusters[0]);
+        assert!(ready(fs.fat_entry(clusters[0])).unwrap() >= END_OF_CHAIN);
+        for &cluster in &clusters[1..] {
+            assert_eq!(ready(fs.fat_entry(cluster)).unwrap(), FREE_CLUSTER);
+        }
+
+        let reopened = ready(fs.open("DATA.BIN")).unwrap().unwrap();
+        assert_eq!(ready(reopened.read_to_end()).unwrap(), b"short");
+
+        // Emptying the file frees its last cluster
+        ready(file.write_all(&[])).unwrap();
+        assert_eq!(file.entry.first_cluster, 0);
+        assert_eq!(ready(fs.fat_entry(clusters[0])).unwrap(), FREE_CLUSTER);
+        assert_eq!(read_fat(&device, 0), read_fat(&device, 1));
+    }
+}
@@ -0,0 +1,6 @@
+//! Filesystems which are read from [`BlockDevice`]s
+//!
+//! [`BlockDevice`]: crate::block::BlockDevice
+
+pub mod fat;
+mod partition;
@@ -0,0 +1,95 @@
+//! Finding the partitions on a [`BlockDevice`] from its MBR or GPT partition table.
+//!
+//! The MBR partition table is 4 16-byte entries at offset 446 of the first block.
+//! A GPT disk has an MBR with a single protective partition of type `0xEE`,
+//! and the GPT header in the second block, which points to an array of partition entries.
+
+use alloc::{vec, vec::Vec};
+
+use crate::block::{BlockDevice, BlockDeviceError};
+
+/// The offset of the first MBR partition entry in the first block
+const MBR_PARTITIONS_OFFSET: usize = 446;
+/// The length of an MBR partition entry
+const MBR_PARTITION_LENGTH: usize = 16;
+/// The partition type of an MBR partition protecting a GPT disk
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// The signature at the start of a GPT header
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// The maximum number of GPT partition entries which are read.
+/// This is the number which the specification requires space to be reserved for.
+const MAX_GPT_PARTITIONS: u32 = 128;
+
+/// Checks for the `0x55, 0xAA` signature at the end of a boot sector
+pub fn has_boot_signature(sector: &[u8]) -> bool {
+    sector.len() >= 512 && sector[510] == 0x55 && sector[511] == 0xAA
+}
+
+/// Reads `count` blocks from the device starting at `lba`
+async fn read(device: &dyn BlockDevice, lba: u64, count: u64) -> Result<Vec<u8>, BlockDeviceError> {
+    let mut buf = vec![0; count as usize * device.block_size()];
+    device.read_blocks(lba, count, &mut buf).await?;
+    Ok(buf)
+}
+
+/// Gets the starting block address of each partition on the device, in the order they appear in the partition table.
+/// Returns an empty list if the device has no partition table.
+pub async fn partition_starts(device: &dyn BlockDevice) -> Result<Vec<u64>, BlockDeviceError> {
+    let mbr = read(device, 0, 1).await?;
+    if !has_boot_signature(&mbr) {
+        return Ok(Vec::new());
+    }
+
+    let mut starts = Vec::new();
+    let mut is_gpt = false;
+
+    for entry in mbr[MBR_PARTITIONS_OFFSET..]
+        .chunks_exact(MBR_PARTITION_LENGTH)
+        .take(4)
+    {
+        let partition_type = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+
+        match partition_type {
+            0 => (),
+            MBR_TYPE_GPT_PROTECTIVE => is_gpt = true,
+            _ => starts.push(start as u64),
+        }
+    }
+
+    if is_gpt {
+        return gpt_partition_starts(device).await;
+    }
+
+    Ok(starts)
+}
+
+/// Gets the starting block address of each partition in the device's GPT
+async fn gpt_partition_starts(device: &dyn BlockDevice) -> Result<Vec<u64>, BlockDeviceError> {
+    let header = read(device, 1, 1).await?;
+    if header[..8] != *GPT_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let num_entries =
+        u32::from_le_bytes(header[80..84].try_into().unwrap()).min(MAX_GPT_PARTITIONS);
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if entry_size < 128 {
+        return Ok(Vec::new());
+    }
+
+    let entries_len = num_entries as usize * entry_size;
+    let blocks = entries_len.div_ceil(device.block_size()) as u64;
+    let entries = read(device, entries_lba, blocks).await?;
+
+    let starts = entries[..entries_len]
+        .chunks_exact(entry_size)
+        // Unused entries have a type GUID of all zeroes
+        .filter(|entry| entry[..16].iter().any(|&b| b != 0))
+        .map(|entry| u64::from_le_bytes(entry[32..40].try_into().unwrap()))
+        .collect();
+
+    Ok(starts)
+}
@@ -1,17 +1,19 @@
 //! Types for managing the kernel's global state
 
 use core::cell::RefCell;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
 
 use acpica_bindings::AcpicaOperationFullyInitialized;
 use bootloader_api::BootInfo;
 use spin::rwlock::RwLock;
 use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
 use x86_64::structures::paging::OffsetPageTable;
 
+use crate::acpi::hpet;
 use crate::allocator::{LinkedListAllocator, ALLOCATOR};
 use crate::cpu::{BootInfoFrameAllocator, PhysicalMemoryAccessor};
-use crate::println;
 
 /// A piece of global state.
 #[derive(Debug)]
@@ -52,9 +54,49 @@ impl<T> GlobalState<T> {
     /// Lock the contained [`Mutex`], wrapped in a [`GlobalStateLock`]
     ///
     /// # Panics
-    /// If the [`GlobalState`] is already locked
+    /// If the [`GlobalState`] hasn't been initialised with [`init`][GlobalState::init].
+    /// This function spins forever if the [`GlobalState`] is already locked.
+    #[track_caller]
     pub fn lock(&self) -> GlobalStateLock<T> {
-        GlobalStateLock(self.0.lock())
+        let lock = self.0.lock();
+        if lock.is_none() {
+            GlobalStateLock::<T>::panic();
+        }
+
+        GlobalStateLock(lock)
+    }
+
+    /// Tries to lock the contained [`Mutex`] for up to `ticks` kernel ticks, returning [`None`] if it is still locked after that.
+    /// This is for code which might be called while the lock is already held (e.g. from an interrupt handler),
+    /// so that a double lock fails rather than hanging the kernel.
+    ///
+    /// If interrupts are disabled, the holder of the lock can't run to release it, so this returns [`None`]
+    /// straight away if the lock is taken. In debug builds, the caller's location is logged when the lock times out.
+    #[track_caller]
+    #[allow(dead_code)] // Not yet used by any driver
+    pub fn lock_timeout(&self, ticks: usize) -> Option<GlobalStateLock<T>> {
+        let start = KERNEL_STATE.ticks();
+
+        loop {
+            if let Some(lock) = self.try_lock() {
+                return Some(lock);
+            }
+
+            if !interrupts::are_enabled() || KERNEL_STATE.ticks() - start >= ticks {
+                break;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        #[cfg(debug_assertions)]
+        log::warn!(
+            "Timed out locking GlobalState<{}> at {}",
+            core::any::type_name::<T>(),
+            core::panic::Location::caller()
+        );
+
+        None
     }
 
     /// Tries to lock the contained [`Mutex`]
@@ -83,9 +125,15 @@ pub struct GlobalStateLock<'a, T>(MutexGuard<'a, Option<T>>);
 
 impl<'a, T> GlobalStateLock<'a, T> {
     /// Panics with a message including the name of the type in the lock.
+    ///
+    /// The message is put in the panic rather than printed separately,
+    /// as printing could deadlock if the uninitialised state is the screen's writer.
+    #[track_caller]
     fn panic() -> ! {
-        println!("Type is {}", core::any::type_name::<T>());
-        panic!("GlobalState should have been initialised")
+        panic!(
+            "GlobalState<{}> used before init()",
+            core::any::type_name::<T>()
+        )
     }
 }
 
@@ -121,25 +169,73 @@ pub struct KernelState {
 
     /// How many timer interrupts there have been while the kernel was running
     ticks: AtomicUsize,
+    /// How many timer interrupts occur each second
+    tick_hz: AtomicUsize,
+    /// The approximate number of nanoseconds since the kernel timer was started.
+    /// This is tracked separately from [`ticks`][KernelState::ticks] so that it stays correct if the tick rate changes.
+    uptime_ns: AtomicU64,
     /// Whether to print out ACPICA debug messages
     pub print_acpica_debug: AtomicBool,
 }
 
 impl KernelState {
     /// Gets the number of ticks since the kernel was initialised.
-    /// This should increase by about 100 each second (TODO: be more precise / configurable)
+    /// This increases by about [`tick_hz`][KernelState::tick_hz] each second.
     pub fn ticks(&self) -> usize {
         self.ticks.load(Ordering::Relaxed)
     }
 
-    /// Adds one to [`ticks`][KernelState::ticks]
+    /// Gets the number of timer interrupts per second.
+    /// This can be changed using [`set_tick_hz`][crate::cpu::interrupt_controllers::set_tick_hz].
+    pub fn tick_hz(&self) -> usize {
+        self.tick_hz.load(Ordering::Relaxed)
+    }
+
+    /// Gets the approximate number of nanoseconds between timer interrupts
+    pub fn ns_per_tick(&self) -> u64 {
+        1_000_000_000 / self.tick_hz() as u64
+    }
+
+    /// Gets the approximate number of nanoseconds since the kernel timer was started
+    pub fn uptime_ns(&self) -> u64 {
+        self.uptime_ns.load(Ordering::Relaxed)
+    }
+
+    /// Converts a number of [`ticks`][KernelState::ticks] to a [`Duration`], at the current [`tick_hz`][KernelState::tick_hz].
+    /// If the tick rate has changed since the ticks were counted, the result will be inaccurate.
+    pub fn ticks_to_duration(&self, ticks: usize) -> Duration {
+        Duration::from_nanos(ticks as u64 * self.ns_per_tick())
+    }
+
+    /// Gets the time since the kernel was started.
+    /// This is read from the HPET if the system has one, as it is more precise than counting timer interrupts,
+    /// and falls back to [`uptime_ns`][KernelState::uptime_ns] otherwise.
+    pub fn uptime(&self) -> Duration {
+        Duration::from_nanos(hpet::nanoseconds().unwrap_or_else(|| self.uptime_ns()))
+    }
+
+    /// Sets the value returned by [`tick_hz`][KernelState::tick_hz].
+    /// This does not change the rate of the timer itself, which is done by
+    /// [`set_tick_hz`][crate::cpu::interrupt_controllers::set_tick_hz].
+    pub(crate) fn set_tick_hz(&self, hz: usize) {
+        assert_ne!(hz, 0, "Tick rate must be non-zero");
+        self.tick_hz.store(hz, Ordering::Relaxed);
+    }
+
+    /// Adds one to [`ticks`][KernelState::ticks], and advances [`uptime_ns`][KernelState::uptime_ns] by one tick
     pub fn increment_ticks(&self) {
         self.ticks
             .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |i| i.checked_add(1))
             .unwrap();
+
+        self.uptime_ns
+            .fetch_add(self.ns_per_tick(), Ordering::Relaxed);
     }
 }
 
+/// The number of timer interrupts per second which the kernel timer is started with
+pub const DEFAULT_TICK_HZ: usize = 100;
+
 /// The global kernel state
 pub static KERNEL_STATE: KernelState = KernelState {
     initrd: RwLock::new(None),
@@ -151,6 +247,8 @@ pub static KERNEL_STATE: KernelState = KernelState {
     acpica: GlobalState::new(),
 
     ticks: AtomicUsize::new(0),
+    tick_hz: AtomicUsize::new(DEFAULT_TICK_HZ),
+    uptime_ns: AtomicU64::new(0),
     print_acpica_debug: AtomicBool::new(false),
 };
 
@@ -160,3 +258,16 @@ pub type KernelPageTable = OffsetPageTable<'static>;
 pub type KernelFrameAllocator = BootInfoFrameAllocator;
 /// A type alias for the kernel's heap allocator. This makes it easier to change the exact type in future.
 pub type KernelHeapAllocator = LinkedListAllocator;
+
+/// Tests that [`GlobalState::lock_timeout`] gives up if the state is already locked
+#[test_case]
+fn test_lock_timeout() {
+    let state = GlobalState::new();
+    state.init(0);
+
+    let lock = state.lock_timeout(1).unwrap();
+    assert!(state.lock_timeout(1).is_none());
+
+    drop(lock);
+    assert!(state.lock_timeout(1).is_some());
+}
@@ -1,4 +1,7 @@
-//! Contains the [`FONT_BITMAPS`] const
+//! Contains the [`FONT_BITMAPS`] and [`REPLACEMENT_BITMAP`] consts
+
+/// Bitmap drawn for characters which aren't in [`FONT_BITMAPS`]. This is a hollow box.
+pub const REPLACEMENT_BITMAP: [u8; 8] = [0x7E, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x00];
 
 /// Bitmap font for ASCII characters
 pub const FONT_BITMAPS: [[u8; 8]; 128] = [
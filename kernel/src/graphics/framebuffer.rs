@@ -2,7 +2,9 @@
 
 use alloc::vec;
 use alloc::vec::Vec;
-use bootloader_api::info::{FrameBuffer, FrameBufferInfo};
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use log::warn;
+use x86_64::VirtAddr;
 
 use super::Colour;
 
@@ -14,6 +16,9 @@ pub struct FrameBufferController {
     back_buffer: Vec<u8>,
     /// The front buffer. Writing to this buffer will show pixels on the screen
     front_buffer: &'static mut [u8],
+    /// The byte offsets of the red, green, and blue channels within a pixel,
+    /// or [`None`] if the framebuffer is greyscale with one byte per pixel
+    channel_offsets: Option<[usize; 3]>,
 
     /// The index of the first byte in the array which has changed.
     /// This is used to avoid rewriting the whole screen when only a small part has changed
@@ -26,10 +31,31 @@ pub struct FrameBufferController {
 impl FrameBufferController {
     /// Constructs a new controller from the given info and framebuffer.
     pub fn new(info: FrameBufferInfo, framebuffer: &'static mut FrameBuffer) -> Self {
+        let channel_offsets = match info.pixel_format {
+            PixelFormat::Rgb => Some([0, 1, 2]),
+            PixelFormat::Bgr => Some([2, 1, 0]),
+            PixelFormat::U8 => None,
+            PixelFormat::Unknown {
+                red_position,
+                green_position,
+                blue_position,
+            } => Some([
+                usize::from(red_position / 8),
+                usize::from(green_position / 8),
+                usize::from(blue_position / 8),
+            ]),
+            // Formats added to the bootloader in future aren't understood, so assume BGR, which is the most common
+            format => {
+                warn!("Unsupported pixel format {format:?}, assuming BGR");
+                Some([2, 1, 0])
+            }
+        };
+
         Self {
             info,
             back_buffer: vec![0; info.byte_len],
             front_buffer: framebuffer.buffer_mut(),
+            channel_offsets,
 
             changed_start: 0,
             changed_end: info.byte_len,
@@ -50,23 +76,92 @@ impl FrameBufferController {
         self.changed_end = 0;
     }
 
+    /// Gets the address and length in bytes of the front buffer, which is the memory the screen is drawn from
+    pub fn front_buffer_region(&self) -> (VirtAddr, usize) {
+        (
+            VirtAddr::from_ptr(self.front_buffer.as_ptr()),
+            self.front_buffer.len(),
+        )
+    }
+
+    /// The width of the framebuffer in pixels
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    /// The height of the framebuffer in pixels
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    /// Whether the pixel at position (`x`, `y`) is inside the framebuffer
+    fn in_bounds(&self, x: usize, y: usize) -> bool {
+        x < self.info.width && y < self.info.height
+    }
+
+    /// Records that the rectangle with the top left corner at (`x`, `y`) and the given `width` and `height`
+    /// has changed, so that it will be copied to the front buffer by the next [`flush`][Self::flush]
+    fn mark_changed(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let write_start = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        let write_end =
+            ((y + height - 1) * self.info.stride + (x + width)) * self.info.bytes_per_pixel;
+
+        self.changed_start = self.changed_start.min(write_start);
+        self.changed_end = self.changed_end.max(write_end).min(self.info.byte_len);
+    }
+
     /// Sets the pixel at position (`x`, `y`) from the top left of the framebuffer to the given colour.
     /// Returns `Ok(())` if the write succeeded, or `Err(())` if it failed
     /// (if the coordinate given is outside the buffer)
+    ///
+    /// This doesn't record that the pixel has changed - callers should call [`mark_changed`][Self::mark_changed].
     #[inline]
     fn write_pixel(&mut self, x: usize, y: usize, colour: Colour) -> Result<(), ()> {
-        if x > self.info.width || y > self.info.height {
+        if !self.in_bounds(x, y) {
             return Err(());
         }
 
         let pixel_start = (y * self.info.stride + x) * self.info.bytes_per_pixel;
-        self.back_buffer[pixel_start] = colour.blue;
-        self.back_buffer[pixel_start + 1] = colour.green;
-        self.back_buffer[pixel_start + 2] = colour.red;
+
+        match self.channel_offsets {
+            Some([red, green, blue]) => {
+                self.back_buffer[pixel_start + red] = colour.red;
+                self.back_buffer[pixel_start + green] = colour.green;
+                self.back_buffer[pixel_start + blue] = colour.blue;
+            }
+            None => self.back_buffer[pixel_start] = colour.grey(),
+        }
 
         Ok(())
     }
 
+    /// Gets the colour of the pixel at position (`x`, `y`) from the top left of the framebuffer.
+    /// Returns `Err(())` if the coordinate given is outside the buffer.
+    #[inline]
+    fn read_pixel(&self, x: usize, y: usize) -> Result<Colour, ()> {
+        if !self.in_bounds(x, y) {
+            return Err(());
+        }
+
+        let pixel_start = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+
+        match self.channel_offsets {
+            Some([red, green, blue]) => Ok(Colour {
+                red: self.back_buffer[pixel_start + red],
+                green: self.back_buffer[pixel_start + green],
+                blue: self.back_buffer[pixel_start + blue],
+            }),
+            None => {
+                let grey = self.back_buffer[pixel_start];
+                Ok(Colour::from_rgb(grey, grey, grey))
+            }
+        }
+    }
+
     /// Clears the whole buffer with the given colour
     pub fn clear(&mut self, colour: Colour) {
         for y in 0..self.info.height {
@@ -80,6 +175,7 @@ impl FrameBufferController {
     }
 
     /// Draws an 8x8 pixel bitmap into the buffer with the top-left corner at (`start_x`, `start_y`).
+    /// Each pixel of the bitmap is drawn as a `scale` by `scale` block, so the drawn area is `8 * scale` pixels wide and high.
     ///
     /// Each row of the bitmap is one byte in the input array, and one pixel is one bit within the byte
     /// (LSB = left, MSB = right, 1 = `front`, 0 = `back`).
@@ -91,29 +187,130 @@ impl FrameBufferController {
         start_y: usize,
         front: Colour,
         back: Colour,
+        scale: usize,
     ) -> Result<(), ()> {
         for (y, row) in bitmap.iter().enumerate() {
             for x in 0..8 {
                 // Extract one bit from the bitmap
                 let colour = if row & (1 << x) != 0 { front } else { back };
 
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        self.write_pixel(
+                            x * scale + dx + start_x,
+                            y * scale + dy + start_y,
+                            colour,
+                        )?;
+                    }
+                }
+            }
+        }
+
+        self.mark_changed(start_x, start_y, 8 * scale, 8 * scale);
+
+        Ok(())
+    }
+
+    /// Reads the 8x8 pixel block with the top-left corner at (`start_x`, `start_y`), indexed by `[y][x]`.
+    /// The block can be drawn back with [`write_cell`][Self::write_cell].
+    pub fn read_cell(&self, start_x: usize, start_y: usize) -> Result<[[Colour; 8]; 8], ()> {
+        let mut cell = [[Colour::BLACK; 8]; 8];
+
+        for (y, row) in cell.iter_mut().enumerate() {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = self.read_pixel(x + start_x, y + start_y)?;
+            }
+        }
+
+        Ok(cell)
+    }
+
+    /// Draws an 8x8 pixel block, indexed by `[y][x]`, with the top-left corner at (`start_x`, `start_y`)
+    pub fn write_cell(
+        &mut self,
+        cell: [[Colour; 8]; 8],
+        start_x: usize,
+        start_y: usize,
+    ) -> Result<(), ()> {
+        for (y, row) in cell.iter().enumerate() {
+            for (x, &colour) in row.iter().enumerate() {
                 self.write_pixel(x + start_x, y + start_y, colour)?;
             }
         }
 
-        let write_start = (start_y * self.info.stride + start_x) * self.info.bytes_per_pixel;
-        let write_end =
-            ((start_y + 8) * self.info.stride + (start_x + 8)) * self.info.bytes_per_pixel;
+        self.mark_changed(start_x, start_y, 8, 8);
 
-        self.changed_start = self.changed_start.min(write_start);
-        self.changed_end = self.changed_end.max(write_end);
+        Ok(())
+    }
+
+    /// Sets the pixel at position (`x`, `y`) from the top left of the framebuffer to the given colour.
+    /// Returns `Err(())` if the coordinate given is outside the buffer.
+    pub fn draw_pixel(&mut self, x: usize, y: usize, colour: Colour) -> Result<(), ()> {
+        self.write_pixel(x, y, colour)?;
+        self.mark_changed(x, y, 1, 1);
 
         Ok(())
     }
 
-    /// Draws a rectangle with the top left corner at (`x`, `y`),
-    /// with the given `width` and `height`, filled with the given colour
-    #[allow(dead_code)]
+    /// Draws a straight line from (`x0`, `y0`) to (`x1`, `y1`), including both ends, using [Bresenham's algorithm].
+    /// Returns `Err(())` without drawing anything if either end is outside the buffer.
+    ///
+    /// [Bresenham's algorithm]: https://en.wikipedia.org/wiki/Bresenham%27s_line_algorithm
+    pub fn draw_line(
+        &mut self,
+        x0: usize,
+        y0: usize,
+        x1: usize,
+        y1: usize,
+        colour: Colour,
+    ) -> Result<(), ()> {
+        if !self.in_bounds(x0, y0) || !self.in_bounds(x1, y1) {
+            return Err(());
+        }
+
+        // The framebuffer is much smaller than `isize::MAX`, so these conversions can't fail
+        let [mut x, mut y, x1_signed, y1_signed] =
+            [x0, y0, x1, y1].map(|n| isize::try_from(n).unwrap());
+
+        let dx = (x1_signed - x).abs();
+        let dy = -(y1_signed - y).abs();
+        let step_x = if x < x1_signed { 1 } else { -1 };
+        let step_y = if y < y1_signed { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            // Every point on the line is between the two ends, so is inside the buffer
+            self.write_pixel(x.unsigned_abs(), y.unsigned_abs(), colour)?;
+
+            if x == x1_signed && y == y1_signed {
+                break;
+            }
+
+            let error_2 = 2 * error;
+
+            if error_2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+
+            if error_2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+
+        self.mark_changed(
+            x0.min(x1),
+            y0.min(y1),
+            x0.abs_diff(x1) + 1,
+            y0.abs_diff(y1) + 1,
+        );
+
+        Ok(())
+    }
+
+    /// Draws the outline of a rectangle with the top left corner at (`x`, `y`), with the given `width` and `height`.
+    /// Returns `Err(())` without drawing anything if any part of the rectangle is outside the buffer.
     pub fn draw_rect(
         &mut self,
         x: usize,
@@ -122,17 +319,55 @@ impl FrameBufferController {
         height: usize,
         colour: Colour,
     ) -> Result<(), ()> {
-        for y in y..y + width {
-            for x in x..x + height {
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+
+        if !self.in_bounds(x + width - 1, y + height - 1) {
+            return Err(());
+        }
+
+        for x in x..x + width {
+            self.write_pixel(x, y, colour)?;
+            self.write_pixel(x, y + height - 1, colour)?;
+        }
+
+        for y in y..y + height {
+            self.write_pixel(x, y, colour)?;
+            self.write_pixel(x + width - 1, y, colour)?;
+        }
+
+        self.mark_changed(x, y, width, height);
+
+        Ok(())
+    }
+
+    /// Draws a rectangle with the top left corner at (`x`, `y`),
+    /// with the given `width` and `height`, filled with the given colour.
+    /// Returns `Err(())` without drawing anything if any part of the rectangle is outside the buffer.
+    pub fn fill_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        colour: Colour,
+    ) -> Result<(), ()> {
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+
+        if !self.in_bounds(x + width - 1, y + height - 1) {
+            return Err(());
+        }
+
+        for y in y..y + height {
+            for x in x..x + width {
                 self.write_pixel(x, y, colour)?;
             }
         }
 
-        let write_start = (y * self.info.stride + x) * self.info.bytes_per_pixel;
-        let write_end = ((y + height) * self.info.stride + (x + width)) * self.info.bytes_per_pixel;
-
-        self.changed_start = write_start.min(write_start);
-        self.changed_end = write_end.max(write_end);
+        self.mark_changed(x, y, width, height);
 
         Ok(())
     }
@@ -3,12 +3,22 @@
 mod font_const;
 mod framebuffer;
 
-use crate::global_state::{GlobalState, TryLockedIfInitError};
-use bootloader_api::info::{FrameBuffer, PixelFormat};
-use core::fmt;
+use crate::{
+    cpu::pat::{self, MemoryType, SavedMemoryType, SetMemoryTypeError},
+    global_state::{GlobalState, TryLockedIfInitError, KERNEL_STATE},
+};
+use bootloader_api::info::FrameBuffer;
+use core::{
+    fmt,
+    sync::atomic::{AtomicIsize, Ordering},
+    time::Duration,
+};
 use spin::Mutex;
 
-use self::{font_const::FONT_BITMAPS, framebuffer::FrameBufferController};
+use self::{
+    font_const::{FONT_BITMAPS, REPLACEMENT_BITMAP},
+    framebuffer::FrameBufferController,
+};
 
 /// A 24-bit colour
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -46,11 +56,64 @@ impl Colour {
 
     /// Yellow
     pub const YELLOW: Self = Self::from_rgb(255, 255, 0);
+
+    /// Converts the colour to a single greyscale brightness value, for framebuffers with one byte per pixel
+    pub fn grey(self) -> u8 {
+        let weighted =
+            u32::from(self.red) * 77 + u32::from(self.green) * 150 + u32::from(self.blue) * 29;
+
+        // The weights add up to 256, so this is at most 255
+        u8::try_from(weighted >> 8).unwrap()
+    }
+
+    /// Splits a colour packed as `0xAARRGGBB` into the colour and its alpha value.
+    /// The alpha can be passed to [`blend`] to draw the colour over a background.
+    ///
+    /// [`blend`]: Colour::blend
+    pub const fn from_argb(argb: u32) -> (Self, u8) {
+        let [alpha, red, green, blue] = argb.to_be_bytes();
+        (Self { red, green, blue }, alpha)
+    }
+
+    /// Packs the colour as `0xAARRGGBB`, with an alpha value of 255 (fully opaque)
+    pub const fn to_u32(self) -> u32 {
+        u32::from_be_bytes([0xFF, self.red, self.green, self.blue])
+    }
+
+    /// Alpha-composites this colour over `bg`. An `alpha` of 255 gives this colour,
+    /// and an `alpha` of 0 gives `bg`, with the values in between mixing the two linearly.
+    pub fn blend(self, bg: Self, alpha: u8) -> Self {
+        let mix = |fg: u8, bg: u8| {
+            let alpha = u16::from(alpha);
+            let mixed = u16::from(fg) * alpha + u16::from(bg) * (255 - alpha);
+
+            // Round to the nearest value. This is at most 255 because it's a weighted average of two `u8`s
+            u8::try_from((mixed + 127) / 255).unwrap()
+        };
+
+        Self {
+            red: mix(self.red, bg.red),
+            green: mix(self.green, bg.green),
+            blue: mix(self.blue, bg.blue),
+        }
+    }
 }
 
-/// The size in pixels of each character
+/// The size in pixels of each character, before scaling
 const CHAR_OFFSET: usize = 10;
 
+/// The largest scale which text can be drawn at using [`Writer::set_scale`]
+pub const MAX_SCALE: usize = 4;
+
+/// An error which can occur when changing the size of text using [`Writer::set_scale`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetScaleError {
+    /// The scale was 0 or greater than [`MAX_SCALE`]
+    InvalidScale,
+    /// The screen is too small to fit enough rows of text at the given scale
+    ScreenTooSmall,
+}
+
 /// A text writer into a framebuffer
 pub struct Writer {
     /// The current row the [`Writer`] is writing at
@@ -62,30 +125,76 @@ pub struct Writer {
     width: usize,
     /// The maximum height in rows the [`Writer`] can reach before scrolling the screen
     height: usize,
+    /// The total number of rows the [`Writer`] has scrolled the screen by
+    lines_scrolled: usize,
+    /// How many pixels wide and high each pixel of the font is drawn as
+    scale: usize,
 
     /// The current [`Colour`] of the text the [`Writer`] is rendering
     colour: Colour,
+
+    /// Whether the text cursor is shown at the current position
+    cursor_visible: bool,
+    /// Whether the text cursor blinks when [`blink_cursor`][Writer::blink_cursor] is called
+    cursor_blinks: bool,
+    /// Whether the text cursor is in the 'on' phase of blinking
+    cursor_blink_on: bool,
+    /// The 8x8 blocks under the text cursor as they were before the cursor was drawn, or all [`None`] if the cursor isn't drawn.
+    /// When text is scaled the cursor covers `scale * scale` blocks, with block (`x`, `y`) stored at index `y * MAX_SCALE + x`.
+    cursor_cells: [Option<SavedCell>; MAX_SCALE * MAX_SCALE],
+
+    /// The position in pixels of the tip of the mouse cursor, or [`None`] if the mouse hasn't moved yet
+    mouse_position: Option<(usize, usize)>,
+    /// The pixels under the mouse cursor as they were before it was drawn, or [`None`] if the mouse cursor isn't drawn
+    mouse_cell: Option<SavedCell>,
+
     /// The framebuffer the [`Writer`] is rendering into
     buffer: FrameBufferController,
 }
 
+/// The contents of an 8x8 block of pixels, saved so that it can be restored after the text or mouse cursor is erased
+struct SavedCell {
+    /// The x position in pixels of the top left of the block
+    x: usize,
+    /// The y position in pixels of the top left of the block
+    y: usize,
+    /// The pixels of the block, indexed by `[y][x]`
+    pixels: [[Colour; 8]; 8],
+}
+
+/// The shape of the mouse cursor, an arrow pointing up and to the left.
+/// This is in the same format as [`FONT_BITMAPS`].
+const MOUSE_CURSOR_BITMAP: [u8; 8] = [0x01, 0x03, 0x07, 0x0F, 0x1F, 0x07, 0x0D, 0x18];
+
 /// How many lines to scroll at a time
 const SCROLL_LINES: usize = 10;
 
 impl Writer {
-    /// Writes a character to the screen
+    /// Writes a character to the screen.
+    /// Characters which aren't in the font are drawn as [`REPLACEMENT_BITMAP`], so they still take up one cell.
     fn write_char(&mut self, c: char) {
         if c == '\n' {
             self.row += 1;
             self.column = 0;
-        } else if c.is_ascii() {
-            let start_x = self.column * CHAR_OFFSET;
-            let start_y = self.row * CHAR_OFFSET;
+        } else {
+            let start_x = self.column * self.char_size();
+            let start_y = self.row * self.char_size();
 
-            let bitmap = FONT_BITMAPS[c as usize];
+            let bitmap = if c.is_ascii() {
+                FONT_BITMAPS[c as usize]
+            } else {
+                REPLACEMENT_BITMAP
+            };
 
             self.buffer
-                .draw_packed_bitmap(bitmap, start_x, start_y, self.colour, Colour::BLACK)
+                .draw_packed_bitmap(
+                    bitmap,
+                    start_x,
+                    start_y,
+                    self.colour,
+                    Colour::BLACK,
+                    self.scale,
+                )
                 .unwrap();
         }
 
@@ -98,29 +207,262 @@ impl Writer {
 
         if self.row >= self.height {
             self.buffer
-                .scroll(CHAR_OFFSET * SCROLL_LINES, Colour::BLACK);
+                .scroll(self.char_size() * SCROLL_LINES, Colour::BLACK);
             self.row = self.height - SCROLL_LINES;
+            self.lines_scrolled += SCROLL_LINES;
+        }
+    }
+
+    /// Draws a string to the screen at the current position, without echoing it to the serial port.
+    /// This is used for redrawing text which has already been printed, such as by the shell's line editor.
+    pub fn draw_str(&mut self, s: &str) {
+        self.erase_overlays();
+
+        for c in s.chars() {
+            self.write_char(c);
+        }
+
+        self.draw_overlays();
+    }
+
+    /// Erases the mouse cursor and the text cursor, restoring what was under them.
+    /// This must be called before anything else is drawn or the screen is scrolled,
+    /// so that the saved pixels are still accurate when they are restored.
+    fn erase_overlays(&mut self) {
+        // The mouse cursor is drawn on top, so it is erased first
+        self.erase_mouse_cursor();
+        self.erase_cursor();
+    }
+
+    /// Draws the text cursor and the mouse cursor, if they should be shown
+    fn draw_overlays(&mut self) {
+        self.draw_cursor();
+        self.draw_mouse_cursor();
+    }
+
+    /// Draws the text cursor at the current position by inverting the cell there, if it should be shown.
+    /// The cell's previous contents are saved so that [`erase_cursor`][Writer::erase_cursor] can restore them.
+    fn draw_cursor(&mut self) {
+        if !self.cursor_visible
+            || !self.cursor_blink_on
+            || self.cursor_cells.iter().any(Option::is_some)
+        {
+            return;
+        }
+
+        let start_x = self.column * self.char_size();
+        let start_y = self.row * self.char_size();
+        let colour = self.colour;
+
+        // A scaled character is drawn over several 8x8 blocks, so invert each of them
+        for block_y in 0..self.scale {
+            for block_x in 0..self.scale {
+                let x = start_x + block_x * 8;
+                let y = start_y + block_y * 8;
+
+                let Ok(pixels) = self.buffer.read_cell(x, y) else {
+                    continue;
+                };
+
+                let inverted = pixels.map(|row| {
+                    row.map(|p| {
+                        if p == Colour::BLACK {
+                            colour
+                        } else {
+                            Colour::BLACK
+                        }
+                    })
+                });
+
+                self.buffer.write_cell(inverted, x, y).unwrap();
+
+                self.cursor_cells[block_y * MAX_SCALE + block_x] = Some(SavedCell { x, y, pixels });
+            }
         }
     }
 
+    /// Restores the cell under the text cursor, if the cursor is drawn.
+    /// Use [`erase_overlays`][Writer::erase_overlays] rather than calling this directly,
+    /// in case the mouse cursor is drawn on top of the text cursor.
+    fn erase_cursor(&mut self) {
+        for cell in self.cursor_cells.iter_mut().filter_map(Option::take) {
+            self.buffer.write_cell(cell.pixels, cell.x, cell.y).unwrap();
+        }
+    }
+
+    /// Sets whether the text cursor is shown at the current position
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.erase_overlays();
+        self.cursor_visible = visible;
+        self.cursor_blink_on = true;
+        self.draw_overlays();
+    }
+
+    /// Sets whether the text cursor blinks. If it doesn't, it is shown constantly while it is visible.
+    pub fn set_cursor_blinks(&mut self, blinks: bool) {
+        self.erase_overlays();
+        self.cursor_blinks = blinks;
+
+        if !blinks {
+            self.cursor_blink_on = true;
+        }
+
+        self.draw_overlays();
+    }
+
+    /// Toggles the text cursor between being drawn and not, if it is visible and set to blink.
+    /// This is called periodically from the timer interrupt.
+    pub fn blink_cursor(&mut self) {
+        if !self.cursor_visible || !self.cursor_blinks {
+            return;
+        }
+
+        self.erase_overlays();
+        self.cursor_blink_on = !self.cursor_blink_on;
+        self.draw_overlays();
+    }
+
+    /// Draws the mouse cursor at [`mouse_position`][Writer::mouse_position], if the mouse has moved.
+    /// The pixels under it are saved so that [`erase_mouse_cursor`][Writer::erase_mouse_cursor] can restore them.
+    fn draw_mouse_cursor(&mut self) {
+        if self.mouse_cell.is_some() {
+            return;
+        }
+
+        let Some((x, y)) = self.mouse_position else {
+            return;
+        };
+
+        let Ok(pixels) = self.buffer.read_cell(x, y) else {
+            return;
+        };
+
+        // The cursor is drawn over the existing pixels, which show through around the arrow
+        let mut cursor = pixels;
+        for (row, bits) in cursor.iter_mut().zip(MOUSE_CURSOR_BITMAP) {
+            for (i, pixel) in row.iter_mut().enumerate() {
+                if bits & (1 << i) != 0 {
+                    *pixel = Colour::WHITE;
+                }
+            }
+        }
+
+        self.buffer.write_cell(cursor, x, y).unwrap();
+        self.mouse_cell = Some(SavedCell { x, y, pixels });
+    }
+
+    /// Restores the pixels under the mouse cursor, if it is drawn
+    fn erase_mouse_cursor(&mut self) {
+        if let Some(cell) = self.mouse_cell.take() {
+            self.buffer.write_cell(cell.pixels, cell.x, cell.y).unwrap();
+        }
+    }
+
+    /// Moves the mouse cursor by the given number of pixels, and redraws it.
+    /// Positive `dx` is to the right and positive `dy` is down.
+    /// The cursor is clamped so that it stays completely on the screen.
+    /// Before the first movement the cursor isn't shown, and it appears in the middle of the screen.
+    pub fn move_mouse_cursor(&mut self, dx: isize, dy: isize) {
+        let max_x = self.buffer.width().saturating_sub(8);
+        let max_y = self.buffer.height().saturating_sub(8);
+
+        let (x, y) = self.mouse_position.unwrap_or((max_x / 2, max_y / 2));
+
+        let x = x.saturating_add_signed(dx).min(max_x);
+        let y = y.saturating_add_signed(dy).min(max_y);
+
+        self.erase_mouse_cursor();
+        self.mouse_position = Some((x, y));
+        self.draw_mouse_cursor();
+    }
+
+    /// Gets the position in pixels of the tip of the mouse cursor, or [`None`] if the mouse hasn't moved yet
+    pub fn mouse_position(&self) -> Option<(usize, usize)> {
+        self.mouse_position
+    }
+
+    /// Gets the current position of the [`Writer`], as `(row, column)`
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    /// Moves the [`Writer`] to the given `row` and `column`.
+    /// Positions off the screen are clamped to the last row or column.
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        self.erase_overlays();
+
+        self.row = row.min(self.height - 1);
+        self.column = column.min(self.width - 1);
+
+        self.draw_overlays();
+    }
+
+    /// Gets the total number of rows the screen has been scrolled by.
+    /// Comparing this value before and after writing text shows whether any rows on the screen have moved.
+    pub fn lines_scrolled(&self) -> usize {
+        self.lines_scrolled
+    }
+
     /// Sets the [`colour`][Writer::colour] of the [`Writer`]
     pub fn set_colour(&mut self, colour: Colour) {
         self.colour = colour;
     }
 
+    /// Gets the size in pixels of each character, including the gap between characters
+    fn char_size(&self) -> usize {
+        CHAR_OFFSET * self.scale
+    }
+
+    /// Gets how many pixels wide and high each pixel of the font is drawn as
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Sets how many pixels wide and high each pixel of the font is drawn as, so that text is drawn `scale` times larger.
+    /// Text which is already on the screen can't be redrawn at the new size, so the screen is cleared.
+    pub fn set_scale(&mut self, scale: usize) -> Result<(), SetScaleError> {
+        if !(1..=MAX_SCALE).contains(&scale) {
+            return Err(SetScaleError::InvalidScale);
+        }
+
+        let (width, height) = text_size(self.buffer.width(), self.buffer.height(), scale);
+
+        // Scrolling needs at least `SCROLL_LINES` rows
+        if width == 0 || height <= SCROLL_LINES {
+            return Err(SetScaleError::ScreenTooSmall);
+        }
+
+        self.scale = scale;
+        self.width = width;
+        self.height = height;
+
+        self.row = 0;
+        self.column = 0;
+        self.clear();
+
+        Ok(())
+    }
+
     /// Clears the entire framebuffer with the given [`Colour`]
-    #[allow(dead_code)]
     pub fn clear(&mut self) {
+        // The saved cells are wiped by the clear, so they shouldn't be restored
+        self.cursor_cells = Default::default();
+        self.mouse_cell = None;
         self.buffer.clear(Colour::BLACK);
+        self.draw_overlays();
     }
 }
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.erase_overlays();
+
         for c in s.chars() {
             self.write_char(c);
             serial_print!("{c}");
         }
+
+        self.draw_overlays();
         Ok(())
     }
 }
@@ -148,38 +490,282 @@ static WRITE_ERROR: Mutex<Option<WriteError>> = Mutex::new(None);
 pub fn init_graphics(framebuffer: &'static mut FrameBuffer) {
     let info = framebuffer.info();
 
-    assert_eq!(info.pixel_format, PixelFormat::Bgr, "TODO: non-bgr formats");
-
     let mut buffer = FrameBufferController::new(info, framebuffer);
 
     buffer.clear(Colour::BLACK);
 
+    let (width, height) = text_size(info.width, info.height, 1);
+
     WRITER.init(Writer {
         row: 0,
         column: 0,
-        width: info.width / CHAR_OFFSET - 1,
-        height: info.height / CHAR_OFFSET - 1,
+        width,
+        height,
+        lines_scrolled: 0,
+        scale: 1,
         colour: Colour::WHITE,
+        cursor_visible: false,
+        cursor_blinks: true,
+        cursor_blink_on: true,
+        cursor_cells: Default::default(),
+        mouse_position: None,
+        mouse_cell: None,
         buffer,
     });
 }
 
-/// Flushes [`WRITER`]
+/// Gets the number of columns and rows of text which fit on a screen of the given size in pixels,
+/// when text is drawn at the given scale
+fn text_size(width: usize, height: usize, scale: usize) -> (usize, usize) {
+    let char_size = CHAR_OFFSET * scale;
+
+    (
+        (width / char_size).saturating_sub(1),
+        (height / char_size).saturating_sub(1),
+    )
+}
+
+/// Flushes [`WRITER`]. Returns `Err(())` if it is locked or hasn't been initialised.
 pub fn flush() -> Result<(), ()> {
-    let mut writer = WRITER.try_lock().ok_or(())?;
+    let mut writer = WRITER.try_locked_if_init().map_err(|_| ())?;
 
     writer.buffer.flush();
 
     Ok(())
 }
 
+/// Changes the memory type of the framebuffer's mapping.
+///
+/// The bootloader maps the framebuffer with the firmware's memory type, which is usually uncacheable.
+/// [`MemoryType::WriteCombining`] is much faster for the framebuffer, as flushing the screen is one large write.
+pub fn set_framebuffer_memory_type(memory_type: MemoryType) -> Result<(), SetMemoryTypeError> {
+    let writer = WRITER.lock();
+    let (start, len) = writer.buffer.front_buffer_region();
+
+    // SAFETY: The framebuffer is only accessed through the front buffer, which is this mapping
+    unsafe { pat::set_memory_type(start, len, memory_type) }
+}
+
+/// Gets the memory type of the framebuffer's mapping, so that it can be put back by [`restore_framebuffer_memory_type`]
+pub fn saved_framebuffer_memory_type() -> Result<SavedMemoryType, SetMemoryTypeError> {
+    let (start, _) = WRITER.lock().buffer.front_buffer_region();
+    pat::saved_memory_type(start)
+}
+
+/// Changes the memory type of the framebuffer's mapping back to one saved by [`saved_framebuffer_memory_type`]
+pub fn restore_framebuffer_memory_type(saved: SavedMemoryType) -> Result<(), SetMemoryTypeError> {
+    let writer = WRITER.lock();
+    let (start, len) = writer.buffer.front_buffer_region();
+
+    // SAFETY: The framebuffer is only accessed through the front buffer, which is this mapping
+    unsafe { pat::restore_memory_type(start, len, saved) }
+}
+
+/// Scrolls the screen by one line of text `count` times, flushing it to the framebuffer after each scroll,
+/// and returns how long this took. This is used to compare the speed of the framebuffer with different memory types.
+///
+/// The screen is cleared afterwards, as the text which was on it has been scrolled away.
+pub fn time_scrolls(count: usize) -> Duration {
+    let mut writer = WRITER.lock();
+    let char_size = writer.char_size();
+
+    let start = KERNEL_STATE.uptime();
+    for _ in 0..count {
+        writer.buffer.scroll(char_size, Colour::BLACK);
+        writer.buffer.flush();
+    }
+    let time = KERNEL_STATE.uptime() - start;
+
+    writer.set_position(0, 0);
+    writer.clear();
+
+    time
+}
+
 /// Clears the display, resetting the cursor to the top
 pub fn clear() {
     let mut writer = WRITER.lock();
 
-    writer.buffer.clear(Colour::BLACK);
-    writer.column = 1;
-    writer.row = 1;
+    writer.clear();
+    writer.set_position(1, 1);
+}
+
+/// Draws a test pattern of coloured squares, a rectangle outline, and lines in the top right of the screen,
+/// to check that the drawing primitives work. Returns `Err(())` if the screen is too small for the pattern.
+pub fn draw_test_pattern() -> Result<(), ()> {
+    /// The width and height of the pattern in pixels
+    const SIZE: usize = 100;
+    /// The width and height of each coloured square
+    const SQUARE: usize = SIZE / 5;
+
+    let mut writer = WRITER.lock();
+    writer.erase_overlays();
+    let buffer = &mut writer.buffer;
+
+    let x = buffer.width().checked_sub(SIZE + 10).ok_or(())?;
+    let y = 10;
+
+    buffer.fill_rect(x, y, SIZE, SIZE, Colour::BLACK)?;
+
+    let colours = [
+        Colour::RED,
+        Colour::GREEN,
+        Colour::BLUE,
+        Colour::YELLOW,
+        Colour::WHITE,
+    ];
+
+    for (i, colour) in colours.into_iter().enumerate() {
+        buffer.fill_rect(x + i * SQUARE, y, SQUARE, SQUARE, colour)?;
+    }
+
+    // A box below the squares with its diagonals and centre line drawn in
+    let box_y = y + SQUARE + 5;
+    let box_height = SIZE - SQUARE - 5;
+    let right = x + SIZE - 1;
+    let bottom = box_y + box_height - 1;
+
+    buffer.draw_rect(x, box_y, SIZE, box_height, Colour::WHITE)?;
+    buffer.draw_line(x, box_y, right, bottom, Colour::RED)?;
+    buffer.draw_line(right, box_y, x, bottom, Colour::GREEN)?;
+    buffer.draw_line(x + SIZE / 2, box_y, x + SIZE / 2, bottom, Colour::BLUE)?;
+
+    // A dotted line under the box
+    for dot_x in (x..=right).step_by(4) {
+        buffer.draw_pixel(dot_x, bottom + 3, Colour::YELLOW)?;
+    }
+
+    writer.draw_overlays();
+
+    Ok(())
+}
+
+/// Mouse movement in the x direction which hasn't been drawn yet because [`WRITER`] was locked
+static PENDING_MOUSE_DX: AtomicIsize = AtomicIsize::new(0);
+/// Mouse movement in the y direction which hasn't been drawn yet because [`WRITER`] was locked
+static PENDING_MOUSE_DY: AtomicIsize = AtomicIsize::new(0);
+
+/// Moves the mouse cursor by the given number of pixels. Positive `dx` is to the right and positive `dy` is down.
+///
+/// This is called from the mouse's interrupt handler, so if [`WRITER`] is locked the movement is stored
+/// and drawn later by [`update_mouse_cursor`].
+pub fn move_mouse_cursor(dx: isize, dy: isize) {
+    PENDING_MOUSE_DX.fetch_add(dx, Ordering::Relaxed);
+    PENDING_MOUSE_DY.fetch_add(dy, Ordering::Relaxed);
+
+    update_mouse_cursor();
+}
+
+/// Draws any mouse movement which hasn't been drawn yet, if [`WRITER`] is initialised and not locked
+pub fn update_mouse_cursor() {
+    let Ok(mut writer) = WRITER.try_locked_if_init() else {
+        return;
+    };
+
+    let dx = PENDING_MOUSE_DX.swap(0, Ordering::Relaxed);
+    let dy = PENDING_MOUSE_DY.swap(0, Ordering::Relaxed);
+
+    if dx != 0 || dy != 0 {
+        writer.move_mouse_cursor(dx, dy);
+    }
+}
+
+/// Toggles the text cursor for blinking, if [`WRITER`] is initialised and not locked
+pub fn blink_cursor() -> Result<(), ()> {
+    let mut writer = WRITER.try_locked_if_init().map_err(|_| ())?;
+
+    writer.blink_cursor();
+
+    Ok(())
+}
+
+#[test_case]
+fn test_colour_blend() {
+    let fg = Colour::from_rgb(200, 100, 50);
+    let bg = Colour::from_rgb(10, 20, 30);
+
+    // Fully opaque and fully transparent colours shouldn't be affected by rounding
+    assert_eq!(fg.blend(bg, 255), fg);
+    assert_eq!(fg.blend(bg, 0), bg);
+    assert_eq!(
+        Colour::WHITE.blend(Colour::BLACK, 128),
+        Colour::from_rgb(128, 128, 128)
+    );
+
+    assert_eq!(Colour::from_argb(fg.to_u32()), (fg, 255));
+    assert_eq!(Colour::from_argb(0x80FF0000), (Colour::RED, 0x80));
+}
+
+#[test_case]
+fn test_non_ascii_advances_cursor() {
+    let mut writer = WRITER.lock();
+
+    writer.set_position(0, 0);
+    writer.draw_str("café");
+
+    assert_eq!(writer.position(), (0, 4));
+}
+
+#[test_case]
+fn test_cursor_restores_cell() {
+    let mut writer = WRITER.lock();
+
+    writer.set_position(0, 0);
+    writer.draw_str("a");
+    writer.set_position(0, 0);
+    let before = writer.buffer.read_cell(0, 0).unwrap();
+
+    writer.set_cursor_visible(true);
+    assert_ne!(writer.buffer.read_cell(0, 0).unwrap(), before);
+
+    // Moving the cursor away should put back the character under it
+    writer.set_position(0, 1);
+    assert_eq!(writer.buffer.read_cell(0, 0).unwrap(), before);
+
+    writer.set_cursor_visible(false);
+}
+
+#[test_case]
+fn test_set_scale() {
+    let mut writer = WRITER.lock();
+
+    assert_eq!(writer.set_scale(0), Err(SetScaleError::InvalidScale));
+    assert_eq!(
+        writer.set_scale(MAX_SCALE + 1),
+        Err(SetScaleError::InvalidScale)
+    );
+
+    // Each pixel of a scaled glyph is drawn as a 2x2 block
+    writer.set_scale(2).unwrap();
+    writer.draw_str("A");
+    let cell = writer.buffer.read_cell(0, 0).unwrap();
+    for (y, row) in cell.iter().enumerate() {
+        for (x, &pixel) in row.iter().enumerate() {
+            assert_eq!(pixel, cell[y & !1][x & !1]);
+        }
+    }
+
+    writer.set_scale(1).unwrap();
+}
+
+#[test_case]
+fn test_draw_line() {
+    let mut writer = WRITER.lock();
+    let buffer = &mut writer.buffer;
+
+    buffer.fill_rect(0, 0, 8, 8, Colour::BLACK).unwrap();
+    buffer.draw_line(0, 0, 3, 3, Colour::RED).unwrap();
+
+    let cell = buffer.read_cell(0, 0).unwrap();
+    for (i, row) in cell.iter().enumerate().take(4) {
+        assert_eq!(row[i], Colour::RED);
+    }
+    assert_eq!(cell[0][1], Colour::BLACK);
+    assert_eq!(cell[4][4], Colour::BLACK);
+
+    // Lines which go off the screen aren't drawn
+    let width = buffer.width();
+    assert!(buffer.draw_line(0, 0, width, 0, Colour::RED).is_err());
 }
 
 #[doc(hidden)]
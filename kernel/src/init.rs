@@ -1,14 +1,15 @@
 //! Code to initialise the kernel and hardware
 
-use crate::{acpi, allocator, cpu, log, println};
+use crate::{acpi, allocator, cpu, log, println, scheduler, serial, symbols};
 
+use ::log::{info, warn};
 use bootloader_api::BootInfo;
 use x86_64::VirtAddr;
 
 use crate::global_state::*;
 use crate::graphics::flush;
 use crate::graphics::init_graphics;
-use crate::input::init_keybuffer;
+use crate::graphics::set_framebuffer_memory_type;
 
 /// Initialises the kernel and constructs a [`KernelState`] struct to represent it.
 ///
@@ -58,9 +59,24 @@ pub unsafe fn init(boot_info: &'static mut BootInfo) {
 
     // println!("Initialised heap");
 
+    match symbols::init() {
+        Ok(count) => info!("Loaded {count} kernel symbols"),
+        // Release builds don't have debug symbols in the initrd
+        Err(symbols::SymbolsInitError::NoDebugSymbols) => (),
+        Err(e) => warn!("Failed to load kernel symbols: {e:?}"),
+    }
+
     init_graphics(boot_info.framebuffer.as_mut().unwrap());
     println!("Initialised graphics");
 
+    // SAFETY: This is the BSP, and the APs program their PATs when they start.
+    // Nothing is mapped with only the write-through flag set.
+    if unsafe { cpu::pat::init() } {
+        if let Err(e) = set_framebuffer_memory_type(cpu::pat::MemoryType::WriteCombining) {
+            warn!("Failed to map the framebuffer as write-combining: {e:?}");
+        }
+    }
+
     let _ = flush();
 
     // SAFETY: This function is only called once
@@ -72,7 +88,7 @@ pub unsafe fn init(boot_info: &'static mut BootInfo) {
     // The bootloader gets the rsdp pointer from the BIOS or UEFI so it is valid and accurate.
     unsafe { acpi::init(boot_info.rsdp_addr.into_option().unwrap()) };
 
-    init_keybuffer();
+    serial::init_input_buffer();
 
     // println!("Initialising APIC");
     let _ = flush();
@@ -86,6 +102,18 @@ pub unsafe fn init(boot_info: &'static mut BootInfo) {
     unsafe { cpu::interrupt_controllers::init_io_apic().unwrap() };
     let _ = flush();
 
+    // SAFETY: This function is only called once, on the BSP, after the I/O APIC is initialised.
+    // Nothing else uses the PIT.
+    if let Err(e) = unsafe { scheduler::watchdog::start() } {
+        warn!("Failed to start the scheduler watchdog: {e:?}");
+    }
+
+    // SAFETY: This function is only called once, on the BSP.
+    // The local APIC and heap are initialised, and interrupts are enabled.
+    if let Err(e) = unsafe { cpu::smp::start_aps() } {
+        warn!("Failed to start application processors: {e:?}");
+    }
+
     // SAFETY: This function is only called once.
     unsafe { cpu::init_ps2() };
 
@@ -0,0 +1,277 @@
+//! Reading files from the initrd, which the bootloader loads into memory alongside the kernel.
+//!
+//! The initrd is a CPIO archive in the "newc" format, which `kernel-builder` packs from the `kernel/initrd` directory.
+//! Each entry is a 110-byte header of ASCII hex fields, followed by the entry's NUL-terminated path and then its data,
+//! with the path and data each padded to a multiple of 4 bytes. The archive ends with an entry named `TRAILER!!!`.
+//!
+//! The format is described in the [`cpio` man page].
+//!
+//! [`cpio` man page]: https://man.archlinux.org/man/cpio.5#New_ASCII_Format
+
+use crate::KERNEL_STATE;
+
+/// The path of the kernel's debug symbols in the initrd. This file is only present in debug builds.
+pub const DEBUG_SYMBOLS_PATH: &str = "kernel.debug";
+
+/// The magic number at the start of each entry's header
+const MAGIC: &[u8] = b"070701";
+/// The length of an entry's header, including the magic number
+const HEADER_LENGTH: usize = 110;
+/// The path of the entry which marks the end of the archive
+const TRAILER_PATH: &str = "TRAILER!!!";
+
+/// The bits of an entry's [`mode`] which hold the type of the entry
+///
+/// [`mode`]: Entry::mode
+const MODE_TYPE_MASK: u32 = 0o170000;
+/// The type bits of an entry's [`mode`] for a regular file
+///
+/// [`mode`]: Entry::mode
+const MODE_TYPE_FILE: u32 = 0o100000;
+
+/// An error occurring while parsing the initrd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    /// An entry's header did not start with the magic number
+    BadMagic,
+    /// A field in an entry's header was not valid hex
+    BadHeaderField,
+    /// An entry's path was not valid UTF-8 or was not NUL-terminated
+    BadPath,
+    /// The archive ended in the middle of an entry, or without a trailer entry
+    Truncated,
+}
+
+/// An entry in the initrd, which is a file, directory, or other kind of node
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    /// The path of the entry, without a leading `/` or `./`
+    pub path: &'a str,
+    /// The entry's file type and permissions, in the same format as `st_mode` on unix
+    pub mode: u32,
+    /// The contents of the entry
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// Whether the entry is a regular file
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_TYPE_FILE
+    }
+}
+
+/// A CPIO archive which files can be read from
+#[derive(Debug, Clone, Copy)]
+pub struct Initrd<'a> {
+    /// The bytes of the archive
+    data: &'a [u8],
+}
+
+impl<'a> Initrd<'a> {
+    /// Wraps the bytes of a CPIO archive. The archive is not checked until it is read.
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Gets an iterator over the entries in the archive, not including the trailer.
+    /// If the archive is malformed, the iterator returns an error and then stops.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            rest: self.data,
+            finished: false,
+        }
+    }
+
+    /// Gets the contents of the regular file at `path`, or [`None`] if there isn't one.
+    /// Leading `/` or `./` in `path` are ignored.
+    pub fn open(&self, path: &str) -> Option<&'a [u8]> {
+        let path = normalise_path(path);
+
+        self.entries()
+            .map_while(Result::ok)
+            .find(|entry| entry.is_file() && entry.path == path)
+            .map(|entry| entry.data)
+    }
+}
+
+/// An iterator over the entries in an [`Initrd`]
+#[derive(Debug, Clone)]
+pub struct Entries<'a> {
+    /// The part of the archive which hasn't been read yet
+    rest: &'a [u8],
+    /// Whether the trailer or an error has been reached
+    finished: bool,
+}
+
+impl<'a> Entries<'a> {
+    /// Reads the entry at the start of [`rest`], and moves [`rest`] past it.
+    /// Returns [`None`] if the entry is the trailer.
+    ///
+    /// [`rest`]: Entries::rest
+    fn read_entry(&mut self) -> Result<Option<Entry<'a>>, InitrdError> {
+        let header = self
+            .rest
+            .get(..HEADER_LENGTH)
+            .ok_or(InitrdError::Truncated)?;
+
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(InitrdError::BadMagic);
+        }
+
+        let mode = header_field(header, 1)?;
+        let file_size = usize::try_from(header_field(header, 6)?).unwrap();
+        let path_size = usize::try_from(header_field(header, 11)?).unwrap();
+
+        // The path and data are each padded so that they end on a multiple of 4 bytes
+        let path_end = HEADER_LENGTH + path_size;
+        let data_start = path_end.next_multiple_of(4);
+        let data_end = data_start + file_size;
+
+        let path = self
+            .rest
+            .get(HEADER_LENGTH..path_end)
+            .ok_or(InitrdError::Truncated)?;
+        let data = self
+            .rest
+            .get(data_start..data_end)
+            .ok_or(InitrdError::Truncated)?;
+
+        // The path includes a NUL terminator
+        let Some((0, path)) = path.split_last() else {
+            return Err(InitrdError::BadPath);
+        };
+        let path = core::str::from_utf8(path).map_err(|_| InitrdError::BadPath)?;
+
+        // The padding after the last entry may be missing
+        self.rest = self.rest.get(data_end.next_multiple_of(4)..).unwrap_or(&[]);
+
+        if path == TRAILER_PATH {
+            return Ok(None);
+        }
+
+        Ok(Some(Entry {
+            path: normalise_path(path),
+            mode,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, InitrdError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.read_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Parses the `i`th 8-character hex field of an entry's header, counting from the field after the magic number
+fn header_field(header: &[u8], i: usize) -> Result<u32, InitrdError> {
+    let start = MAGIC.len() + i * 8;
+    let field =
+        core::str::from_utf8(&header[start..start + 8]).map_err(|_| InitrdError::BadHeaderField)?;
+
+    u32::from_str_radix(field, 16).map_err(|_| InitrdError::BadHeaderField)
+}
+
+/// Removes a leading `./` and any leading `/`s from a path, so that all paths in the archive are relative
+fn normalise_path(path: &str) -> &str {
+    path.strip_prefix("./")
+        .unwrap_or(path)
+        .trim_start_matches('/')
+}
+
+/// Gets the contents of the regular file at `path` in the initrd which the bootloader loaded.
+/// Returns [`None`] if there is no such file, or if the initrd hasn't been set yet.
+pub fn open(path: &str) -> Option<&'static [u8]> {
+    let initrd = (*KERNEL_STATE.initrd.read())?;
+
+    Initrd::new(initrd).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{Initrd, InitrdError};
+
+    /// Appends an entry to a CPIO archive, in the same way as `kernel-builder`
+    fn push_entry(archive: &mut Vec<u8>, path: &str, mode: u32, data: &[u8]) {
+        let fields = [
+            0,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            u32::try_from(data.len()).unwrap(),
+            0,
+            0,
+            0,
+            0,
+            u32::try_from(path.len() + 1).unwrap(),
+            0,
+        ];
+
+        archive.extend_from_slice(b"070701");
+        for field in fields {
+            archive.extend_from_slice(alloc::format!("{field:08x}").as_bytes());
+        }
+
+        archive.extend_from_slice(path.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    #[test_case]
+    fn test_initrd_open() {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "etc", 0o040755, &[]);
+        push_entry(&mut archive, "etc/motd", 0o100644, b"hello");
+        push_entry(&mut archive, "./empty", 0o100644, &[]);
+        push_entry(&mut archive, "TRAILER!!!", 0, &[]);
+
+        let initrd = Initrd::new(&archive);
+
+        assert_eq!(initrd.entries().count(), 3);
+        assert_eq!(initrd.open("etc/motd"), Some(&b"hello"[..]));
+        assert_eq!(initrd.open("/etc/motd"), Some(&b"hello"[..]));
+        assert_eq!(initrd.open("empty"), Some(&[][..]));
+        // Directories can't be opened as files
+        assert_eq!(initrd.open("etc"), None);
+        assert_eq!(initrd.open("missing"), None);
+    }
+
+    #[test_case]
+    fn test_initrd_malformed() {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "file", 0o100644, b"contents");
+
+        // Without a trailer, the archive ends in the middle of the next header
+        let errors: Vec<_> = Initrd::new(&archive).entries().collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].unwrap_err(), InitrdError::Truncated);
+
+        archive[0] = b'1';
+        let mut entries = Initrd::new(&archive).entries();
+        assert_eq!(entries.next().unwrap().unwrap_err(), InitrdError::BadMagic);
+        assert!(entries.next().is_none());
+    }
+}
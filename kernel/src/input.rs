@@ -1,33 +1,311 @@
-//! Methods related to keyboard inputs
+//! Methods related to keyboard and mouse inputs
 
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use pc_keyboard::DecodedKey;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use crate::println;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{global_state::KERNEL_STATE, println};
+
+/// The number of events each input queue can hold before the oldest events are dropped
+const QUEUE_CAPACITY: usize = 256;
 
 /// A buffer of keyboard inputs. An input will be added to this buffer when a key is pressed,
 /// and removed when it is read by an input handler.
-static INPUT_BUFFER: OnceCell<ArrayQueue<DecodedKey>> = OnceCell::uninit();
+static INPUT_BUFFER: EventQueue<DecodedKey, QUEUE_CAPACITY> = EventQueue::new();
+
+/// A buffer of key events. An event will be added to this buffer when a key is pressed or released,
+/// and removed when it is read by an input handler.
+/// Unlike [`INPUT_BUFFER`], this contains every key including modifiers, and records releases.
+static KEY_EVENT_BUFFER: EventQueue<KeyEvent, QUEUE_CAPACITY> = EventQueue::new();
+
+/// A buffer of mouse events. An event will be added to this buffer when the mouse moves or a button changes,
+/// and removed when it is read by an input handler.
+static MOUSE_BUFFER: EventQueue<MouseEvent, QUEUE_CAPACITY> = EventQueue::new();
+
+/// Whether Ctrl+C has been pressed since [`clear_interrupt`] was last called
+static INTERRUPT_REQUESTED: AtomicBool = AtomicBool::new(false);
 
-/// Initialise [`INPUT_BUFFER`] with a new heap allocated [`ArrayQueue`].
-pub fn init_keybuffer() {
-    INPUT_BUFFER.init_once(|| ArrayQueue::new(1024));
+/// An event in an [`EventQueue`], together with when it happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampedEvent<T> {
+    /// The event
+    pub event: T,
+    /// The value of [`ticks`] when the event was pushed
+    ///
+    /// [`ticks`]: crate::global_state::KernelState::ticks
+    pub tick: usize,
 }
 
-/// Push a keypress into [`INPUT_BUFFER`]
-pub fn push_key(key: DecodedKey) {
-    if let Ok(buffer) = INPUT_BUFFER.try_get() {
-        match buffer.push(key) {
-            Ok(_) => (),
-            Err(_) => println!("ERROR: Dropped input"),
+/// The events in an [`EventQueue`], stored as a ring buffer
+#[derive(Debug)]
+struct EventRing<T, const N: usize> {
+    /// The storage for the events. Only the `len` slots starting at `head` (wrapping around) are [`Some`].
+    events: [Option<TimestampedEvent<T>>; N],
+    /// The index of the oldest event
+    head: usize,
+    /// The number of events in the ring
+    len: usize,
+}
+
+/// A fixed-capacity queue of input events. Events are pushed from interrupt handlers, so the queue never allocates,
+/// and pushing to a full queue drops the oldest event rather than failing.
+#[derive(Debug)]
+pub struct EventQueue<T, const N: usize> {
+    /// The events in the queue
+    ring: Mutex<EventRing<T, N>>,
+    /// The number of events which have been dropped because the queue was full
+    dropped: AtomicUsize,
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    /// Constructs a new empty [`EventQueue`]
+    pub const fn new() -> Self {
+        Self {
+            ring: Mutex::new(EventRing {
+                events: [None; N],
+                head: 0,
+                len: 0,
+            }),
+            dropped: AtomicUsize::new(0),
         }
-    } else {
-        println!("ERROR: Input buffer not initialised");
     }
+
+    /// Adds an event to the end of the queue, recording the current tick.
+    /// If the queue is full, the oldest event is dropped and the dropped event counter is incremented.
+    pub fn push(&self, event: T) {
+        let event = TimestampedEvent {
+            event,
+            tick: KERNEL_STATE.ticks(),
+        };
+
+        // Interrupts are disabled while the lock is held, so an interrupt handler pushing an event can't deadlock
+        without_interrupts(|| {
+            let mut ring = self.ring.lock();
+
+            if ring.len == N {
+                ring.head = (ring.head + 1) % N;
+                ring.len -= 1;
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let i = (ring.head + ring.len) % N;
+            ring.events[i] = Some(event);
+            ring.len += 1;
+        });
+    }
+
+    /// Removes the oldest event from the queue
+    pub fn pop(&self) -> Option<TimestampedEvent<T>> {
+        without_interrupts(|| {
+            let mut ring = self.ring.lock();
+
+            if ring.len == 0 {
+                return None;
+            }
+
+            let head = ring.head;
+            let event = ring.events[head].take();
+            ring.head = (head + 1) % N;
+            ring.len -= 1;
+
+            event
+        })
+    }
+
+    /// The number of events in the queue
+    pub fn len(&self) -> usize {
+        without_interrupts(|| self.ring.lock().len)
+    }
+
+    /// The number of events which have been dropped because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Which modifier keys are held
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Modifiers {
+    /// Whether the left shift key is held
+    pub left_shift: bool,
+    /// Whether the right shift key is held
+    pub right_shift: bool,
+    /// Whether the left control key is held
+    pub left_ctrl: bool,
+    /// Whether the right control key is held
+    pub right_ctrl: bool,
+    /// Whether the left alt key is held
+    pub left_alt: bool,
+    /// Whether the right alt (Alt Gr) key is held
+    pub right_alt: bool,
+}
+
+impl Modifiers {
+    /// No modifier keys held
+    pub const NONE: Self = Self {
+        left_shift: false,
+        right_shift: false,
+        left_ctrl: false,
+        right_ctrl: false,
+        left_alt: false,
+        right_alt: false,
+    };
+
+    /// Whether either shift key is held
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    /// Whether either control key is held
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    /// Whether either alt key is held
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    /// Updates the state of the modifiers when `key` is pressed or released.
+    /// Keys which aren't modifiers are ignored.
+    pub fn update(&mut self, key: KeyCode, pressed: bool) {
+        let modifier = match key {
+            KeyCode::LShift => &mut self.left_shift,
+            KeyCode::RShift => &mut self.right_shift,
+            KeyCode::LControl => &mut self.left_ctrl,
+            KeyCode::RControl => &mut self.right_ctrl,
+            KeyCode::LAlt => &mut self.left_alt,
+            KeyCode::RAltGr => &mut self.right_alt,
+            _ => return,
+        };
+
+        *modifier = pressed;
+    }
+}
+
+/// A key being pressed or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The key which was pressed or released
+    pub key: KeyCode,
+    /// Whether the key was pressed (`true`) or released (`false`)
+    pub pressed: bool,
+    /// Which modifier keys were held after the event, so pressing a modifier includes itself
+    pub modifiers: Modifiers,
+}
+
+/// The state of the mouse buttons in a [`MouseEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    /// Whether the left button is held
+    pub left: bool,
+    /// Whether the right button is held
+    pub right: bool,
+    /// Whether the middle button is held
+    pub middle: bool,
+}
+
+/// A movement of the mouse, or a change in which buttons are held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// How far the mouse moved to the right
+    pub dx: i16,
+    /// How far the mouse moved down
+    pub dy: i16,
+    /// Which buttons are held
+    pub buttons: MouseButtons,
+}
+
+/// Push a keypress into [`INPUT_BUFFER`]. If the buffer is full, the oldest keypress is dropped.
+pub fn push_key(key: DecodedKey) {
+    INPUT_BUFFER.push(key);
 }
 
 /// Get a keypress from [`INPUT_BUFFER`]
 pub fn pop_key() -> Option<DecodedKey> {
-    INPUT_BUFFER.try_get().ok()?.pop()
+    INPUT_BUFFER.pop().map(|key| key.event)
+}
+
+/// Push a key event into [`KEY_EVENT_BUFFER`]. If the buffer is full, the oldest event is dropped.
+///
+/// If the event is Ctrl+C being pressed, this also sets the flag checked by [`interrupt_requested`].
+pub fn push_key_event(event: KeyEvent) {
+    if event.pressed && event.key == KeyCode::C && event.modifiers.ctrl() {
+        INTERRUPT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    KEY_EVENT_BUFFER.push(event);
+}
+
+/// Get a key event from [`KEY_EVENT_BUFFER`]
+#[allow(dead_code)]
+pub fn pop_key_event() -> Option<TimestampedEvent<KeyEvent>> {
+    KEY_EVENT_BUFFER.pop()
+}
+
+/// Whether Ctrl+C has been pressed since [`clear_interrupt`] was last called.
+/// Long-running shell commands should check this and stop early if it is set.
+pub fn interrupt_requested() -> bool {
+    INTERRUPT_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Clears the flag set when Ctrl+C is pressed, returning whether it was set
+pub fn clear_interrupt() -> bool {
+    INTERRUPT_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Push a mouse event into [`MOUSE_BUFFER`]. If the buffer is full, the oldest event is dropped.
+pub fn push_mouse_event(event: MouseEvent) {
+    MOUSE_BUFFER.push(event);
+}
+
+/// Get a mouse event from [`MOUSE_BUFFER`]
+#[allow(dead_code)]
+pub fn pop_mouse_event() -> Option<TimestampedEvent<MouseEvent>> {
+    MOUSE_BUFFER.pop()
+}
+
+/// Prints the number of events waiting in each input queue, and how many have been dropped because the queue was full
+pub fn print_queue_stats() {
+    println!("Queue       Queued  Dropped");
+    println!(
+        "keys        {:<7} {}",
+        INPUT_BUFFER.len(),
+        INPUT_BUFFER.dropped()
+    );
+    println!(
+        "key events  {:<7} {}",
+        KEY_EVENT_BUFFER.len(),
+        KEY_EVENT_BUFFER.dropped()
+    );
+    println!(
+        "mouse       {:<7} {}",
+        MOUSE_BUFFER.len(),
+        MOUSE_BUFFER.dropped()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventQueue;
+
+    #[test_case]
+    fn test_event_queue_drops_oldest() {
+        let queue: EventQueue<u8, 4> = EventQueue::new();
+
+        for i in 0..6 {
+            queue.push(i);
+        }
+
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.dropped(), 2);
+
+        for i in 2..6 {
+            assert_eq!(queue.pop().map(|e| e.event), Some(i));
+        }
+        assert!(queue.pop().is_none());
+    }
 }
@@ -1,7 +1,10 @@
 //! Code for logging data using the [`log`] crate,
 
-use log::Log;
+use core::fmt::Display;
 
+use log::{Level, Log};
+
+use crate::global_state::KERNEL_STATE;
 use crate::graphics::{Colour, WRITER};
 use crate::{print, println};
 
@@ -30,57 +33,95 @@ impl Log for KernelLogger {
             return;
         }
 
-        print!("[");
+        let ticks = KERNEL_STATE.ticks();
+        let level = record.level();
+        let name = level_name(level);
+        let location = Location(record);
+
+        // Logs are only shown on the screen if there's no serial port for them,
+        // so that they aren't also copied to COM1 by `print!` before the screen is set up
+        if crate::serial::has_log_port() {
+            crate::serial::log_print(format_args!(
+                "[{ticks:>5}] \x1b[{}m{name}\x1b[0m {location}: {}\n",
+                level_ansi_colour(level),
+                record.args()
+            ));
+            return;
+        }
 
-        let colour = match record.level() {
-            log::Level::Error => Colour::RED,
-            log::Level::Warn => Colour::YELLOW,
-            log::Level::Info => Colour::WHITE,
-            log::Level::Debug => Colour::BLUE,
-            log::Level::Trace => Colour::GREEN,
-        };
+        print!("[{ticks:>5}] ");
 
         if let Ok(mut w) = WRITER.try_locked_if_init() {
-            w.set_colour(colour);
+            w.set_colour(level_colour(level));
         }
 
-        let level_str = match record.level() {
-            log::Level::Error => "ERROR",
-            log::Level::Warn => "WARNING",
-            log::Level::Info => "INFO",
-            log::Level::Debug => "DEBUG",
-            log::Level::Trace => "TRACE",
-        };
-
-        print!("{level_str}");
+        print!("{name}");
 
         if let Ok(mut w) = WRITER.try_locked_if_init() {
             w.set_colour(Colour::WHITE);
         }
 
-        match (record.module_path(), record.file()) {
-            // If the record is an error, print the whole file path not just the module
-            (_, Some(file)) if record.level() == log::Level::Error => {
-                print!(" {file}");
-                if let Some(line) = record.line() {
-                    print!(":{line}");
-                }
-            }
-            (Some(module), _) => {
-                print!(" {module}");
+        println!(" {location}: {}", record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Gets the name of a log level, padded so that the messages after it line up
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN ",
+        Level::Info => "INFO ",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+/// Gets the colour a log level's name is drawn in on the screen
+fn level_colour(level: Level) -> Colour {
+    match level {
+        Level::Error => Colour::RED,
+        Level::Warn => Colour::YELLOW,
+        Level::Info => Colour::WHITE,
+        Level::Debug => Colour::BLUE,
+        Level::Trace => Colour::GREEN,
+    }
+}
+
+/// Gets the ANSI _Select Graphic Rendition_ code for the colour matching [`level_colour`],
+/// for colouring a log level's name on the serial port
+fn level_ansi_colour(level: Level) -> u8 {
+    match level {
+        Level::Error => 31,
+        Level::Warn => 33,
+        Level::Info => 37,
+        Level::Debug => 34,
+        Level::Trace => 32,
+    }
+}
+
+/// Formats where a log record came from: its target, followed by the file and line for errors
+struct Location<'a, 'b>(&'a log::Record<'b>);
+
+impl Display for Location<'_, '_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let record = self.0;
+        write!(f, "{}", record.target())?;
+
+        // If the record is an error, print where it came from as well
+        if record.level() == Level::Error {
+            if let Some(file) = record.file() {
+                write!(f, " ({file}")?;
                 if let Some(line) = record.line() {
-                    print!(":{line}");
+                    write!(f, ":{line}")?;
                 }
+                write!(f, ")")?;
             }
-            _ => (),
         }
 
-        print!("] ");
-
-        println!("{}", record.args());
+        Ok(())
     }
-
-    fn flush(&self) {}
 }
 
 /// Sets up logging for the kernel
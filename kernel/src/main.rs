@@ -45,35 +45,37 @@ extern crate bitfield_struct;
 // Use the std alloc crate for heap allocation
 extern crate alloc;
 
-use alloc::{string::String, vec::Vec};
 use bootloader_api::{BootInfo, BootloaderConfig};
-use cpu::interrupt_controllers::send_debug_self_interrupt;
 
 #[macro_use]
 mod serial;
 
 mod acpi;
 mod allocator;
+mod block;
 mod cpu;
 mod devices;
+mod exec;
+mod fs;
 mod global_state;
 mod graphics;
 mod init;
+mod initrd;
 mod input;
 mod log;
 mod panic;
 mod pci;
 mod scheduler;
+mod shell;
+mod shutdown;
+mod symbols;
+mod syscall;
 mod util;
 
 #[cfg(test)]
 mod tests;
 
 use global_state::*;
-use input::pop_key;
-use pci::lspci;
-
-use crate::{acpi::power_off, graphics::clear, scheduler::num_tasks};
 
 /// The starting virtual address where the kernel will be mapped by the bootloader
 const KERNEL_VIRT_ADDR: u64 = 0xFFFF800000000000;
@@ -108,105 +110,5 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     // SAFETY: Just for debugging
     // unsafe { power_off().unwrap() };
 
-    shell_loop()
-}
-
-/// Loops while receiving commands from keyboard input
-fn shell_loop() -> ! {
-    let mut input = String::new();
-
-    print!(">");
-
-    loop {
-        x86_64::instructions::hlt();
-
-        if let Some(key) = pop_key() {
-            match key {
-                pc_keyboard::DecodedKey::Unicode(c) => {
-                    print!("{c}");
-
-                    #[allow(unreachable_code)]
-                    // This is needed because of a bug in rustc to do with uninhabited types
-                    if c == '\n' {
-                        let commands: Vec<_> =
-                            input.split_whitespace().filter(|a| !a.is_empty()).collect();
-                        if let Some(c) = commands.first() {
-                            match *c {
-                                "echo" => echo(&commands[1..]),
-                                "lspci" => lspci(&commands[1..]),
-                                // SAFETY: This is just a debug console, so killing the OS is fine.
-                                // TODO: shut down the kernel first
-                                "poweroff" => unsafe {
-                                    power_off().unwrap();
-                                },
-                                "clear" => clear(),
-                                "kinfo" => kinfo(&commands[1..]),
-                                // SAFETY: For debugging only, not sound
-                                "interrupt" => unsafe { debug_interrupt(&commands[1..]) },
-                                "panic" => panic!("User-instructed panic"),
-                                _ => println!("Unknown command {c}"),
-                            }
-                        }
-
-                        input.clear();
-                        print!(">");
-                    } else {
-                        input.push(c);
-                    }
-                }
-                pc_keyboard::DecodedKey::RawKey(_) => {}
-            }
-        }
-    }
-}
-
-/// The `echo` command - prints its arguments separated by a space
-fn echo(args: &[&str]) {
-    for arg in args {
-        print!("{arg} ");
-    }
-    println!();
-}
-
-/// Prints info about the kernel's state
-fn kinfo(args: &[&str]) {
-    match args.first().copied() {
-        Some("schedule") => {
-            println!("Kernel ticks: {}", KERNEL_STATE.ticks());
-            println!("Registered tasks: {}", num_tasks());
-        }
-
-        Some("acpi") => {
-            let acpica = KERNEL_STATE.acpica.lock();
-
-            println!("MADT: {:?}", acpica.madt());
-            println!("FADT: {:?}", acpica.fadt());
-            println!("DSDT: {:?}", acpica.dsdt());
-
-            if let Some(mcfg) = acpica.mcfg() {
-                println!("MCFG: {:?}", acpica.mcfg());
-                for record in mcfg.records() {
-                    println!("    Record: {record:?}");
-                }
-            }
-        }
-
-        Some(a) => {
-            println!("Unknown argument '{a}'");
-        }
-        None => println!("Provide argument for what to give info about"),
-    }
-}
-
-/// Sends an interrupt on the vector specified in the first argument
-unsafe fn debug_interrupt(args: &[&str]) {
-    match args.first().map(|n| n.parse()) {
-        Some(Ok(vector)) => {
-            // SAFETY: For debugging only, not sound
-            unsafe { send_debug_self_interrupt(vector) }
-        }
-        _ => {
-            println!("First argument must be interrupt vector");
-        }
-    };
+    shell::shell_loop()
 }
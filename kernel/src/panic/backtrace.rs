@@ -16,7 +16,10 @@ use gimli::{
 use object::{elf::FileHeader64, Object, ObjectSection};
 use x86_64::VirtAddr;
 
-use crate::{print, println, KERNEL_STATE, KERNEL_VIRT_ADDR};
+use crate::{
+    initrd::{Initrd, DEBUG_SYMBOLS_PATH},
+    print, println, KERNEL_STATE, KERNEL_VIRT_ADDR,
+};
 
 /// An error occurring while trying to print a backtrace.
 ///
@@ -29,6 +32,8 @@ pub enum BacktracePrintError {
     InitRdLocked,
     /// Initrd was not set - this will be the case if a panic occurs very early in the boot process.
     InitRdUnset,
+    /// The kernel's debug symbols were not in initrd - this will be the case in release builds
+    NoDebugSymbols,
     /// Couldn't read the object file in initrd
     ObjectReadError(object::read::Error),
     /// Error when handling DWARF data
@@ -128,9 +133,12 @@ fn backtrace_impl() -> Result<(), BacktracePrintError> {
         .try_read()
         .ok_or(BacktracePrintError::InitRdLocked)?
         .ok_or(BacktracePrintError::InitRdUnset)?;
+    let debug_symbols = Initrd::new(rd)
+        .open(DEBUG_SYMBOLS_PATH)
+        .ok_or(BacktracePrintError::NoDebugSymbols)?;
 
     // Parse the ELF file and get the sections which will be needed below
-    let object_file: ElfFile = ElfFile::parse(rd)?;
+    let object_file: ElfFile = ElfFile::parse(debug_symbols)?;
     let base_addresses = get_base_addresses(&object_file)?;
     let eh_frame_header = get_eh_frame_header(&object_file, &base_addresses)?;
     let eh_frame = get_eh_frame(&object_file)?;
@@ -0,0 +1,103 @@
+//! A simple stack unwinder which follows the chain of frame pointers saved on the stack.
+//!
+//! This only works if every function saves the caller's RBP register and points RBP at the saved value,
+//! which is only guaranteed when the kernel is built with `-Cforce-frame-pointers=yes`.
+//! `.cargo/config.toml` sets this flag along with the `frame_pointers` cfg, which this module is gated behind.
+//!
+//! Unlike [`backtrace`][super::backtrace], this doesn't need DWARF unwinding info, so it works in release builds.
+//! If the kernel's symbols were loaded by [`symbols::init`], each address is printed with the name of the function it's in.
+
+use core::arch::asm;
+
+use x86_64::{structures::paging::Translate, VirtAddr};
+
+use crate::{print, println, symbols, KERNEL_STATE, KERNEL_VIRT_ADDR};
+
+/// The maximum number of frames to print, in case the chain of frame pointers loops
+const MAX_FRAMES: usize = 64;
+
+/// Prints the return address of each stack frame, and the function it's in if the kernel's symbols are loaded
+pub fn backtrace() {
+    if !symbols::is_loaded() {
+        println!("Kernel symbols not loaded - printing addresses only");
+    }
+
+    let mut frame_number = 0;
+    walk_frames(|return_address| {
+        frame_number += 1;
+        print!("#{frame_number:03} 0x{return_address:016x}");
+
+        // Look up one before the return address, as a call at the very end of a function returns to the next function
+        match symbols::resolve(return_address - 1) {
+            Some((name, offset)) => println!(" @ {:#}+{offset:#x}", rustc_demangle::demangle(name)),
+            None => println!(),
+        }
+    });
+}
+
+/// Calls `f` with the return address of each stack frame, starting with the caller of this function.
+///
+/// The whole chain is walked before this function returns, as the frames it reads would be overwritten by later calls.
+fn walk_frames(mut f: impl FnMut(u64)) {
+    let mut rbp: u64;
+    // SAFETY: This only reads the RBP register
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    for _ in 0..MAX_FRAMES {
+        if !is_valid_frame(rbp) {
+            return;
+        }
+
+        // SAFETY: `is_valid_frame` checked that both values are mapped.
+        // With frame pointers, each frame starts with the caller's RBP followed by the return address.
+        let (next_rbp, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+
+        // Code outside the kernel (e.g. the bootloader or a user program) doesn't have frames to follow
+        if return_address < KERNEL_VIRT_ADDR {
+            return;
+        }
+        f(return_address);
+
+        // The stack grows downwards, so each caller's frame is above the previous one
+        if next_rbp <= rbp {
+            return;
+        }
+        rbp = next_rbp;
+    }
+}
+
+/// Checks whether `rbp` could point to a stack frame, i.e. that it is aligned, in the kernel's address space,
+/// and that the saved RBP and return address are mapped.
+fn is_valid_frame(rbp: u64) -> bool {
+    if rbp < KERNEL_VIRT_ADDR || rbp % 8 != 0 || rbp.checked_add(16).is_none() {
+        return false;
+    }
+
+    // The page table might be locked by the code which panicked, in which case the frame can't be checked.
+    // The other checks catch most invalid frames anyway.
+    match KERNEL_STATE.page_table.try_lock() {
+        Some(page_table) => {
+            page_table.translate_addr(VirtAddr::new(rbp)).is_some()
+                && page_table.translate_addr(VirtAddr::new(rbp + 8)).is_some()
+        }
+        None => true,
+    }
+}
+
+/// Tests that the chain of frame pointers can be followed from a test function
+#[test_case]
+fn test_walk_frames() {
+    use alloc::vec::Vec;
+
+    let mut return_addresses = Vec::new();
+    walk_frames(|address| return_addresses.push(address));
+
+    // There are at least the test runner and the kernel's entry point above this function
+    assert!(return_addresses.len() >= 2);
+    assert!(return_addresses
+        .iter()
+        .all(|&address| address >= KERNEL_VIRT_ADDR));
+}
@@ -2,6 +2,31 @@
 
 #[cfg(debug_assertions)]
 pub mod backtrace;
+#[cfg(frame_pointers)]
+pub mod frame_pointers;
+
+/// Prints a backtrace from the panic site.
+///
+/// In debug builds, this uses the DWARF unwinding info in the kernel's debug symbols, which gives file and line numbers.
+/// If that isn't available or fails, the chain of frame pointers is followed instead if the kernel was built with them.
+pub fn print_backtrace() {
+    use crate::println;
+
+    #[cfg(debug_assertions)]
+    match backtrace::backtrace() {
+        Ok(()) => return,
+        Err(e) => println!("Error printing backtrace: {e:?}"),
+    }
+
+    #[cfg(frame_pointers)]
+    {
+        println!("Following frame pointers:");
+        frame_pointers::backtrace();
+    }
+
+    #[cfg(not(any(debug_assertions, frame_pointers)))]
+    println!("Backtraces need a debug build or frame pointers");
+}
 
 /// This function is called on panic.
 #[cfg(not(test))]
@@ -24,11 +49,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
     println!("In stack {:?}", get_stack(stack_pointer_approx));
 
-    #[cfg(debug_assertions)]
-    match backtrace::backtrace() {
-        Ok(_) => (),
-        Err(e) => println!("Error printing backtrace: {e:?}"),
-    }
+    print_backtrace();
 
     // There's no nice way to handle this because unwrapping would cause a second panic,
     // while just printing an error would require a second call to `flush`.
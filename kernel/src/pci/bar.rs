@@ -3,6 +3,7 @@
 use core::fmt::Debug;
 
 use x86_64::{
+    instructions::interrupts::without_interrupts,
     structures::paging::{frame::PhysFrameRange, PhysFrame},
     PhysAddr,
 };
@@ -63,11 +64,9 @@ pub enum BarValue {
         prefetchable: bool,
     },
 
-    /// The BAR is a port number
-    /// TODO: figure out what's up with these
-    #[allow(dead_code)]
+    /// The BAR is the start of a range of IO ports used by the PCI device
     IOSpace {
-        /// The port number (I think) of the device
+        /// The first port number of the range
         base_address: u32,
     },
 }
@@ -125,6 +124,14 @@ impl<'a> Bar<'a> {
     pub fn read_value(&self) -> BarValue {
         // SAFETY: This struct is unsafe to construct from a PciRegister which is not a BAR
         let lower_32 = unsafe { self.function.read_reg(self.register) };
+
+        // Bit 0 is set for IO space BARs, whose other bits have a different layout
+        if lower_32 & 1 != 0 {
+            return BarValue::IOSpace {
+                base_address: lower_32 & !0b11,
+            };
+        }
+
         let prefetchable = lower_32 & (1 << 3) != 0;
         let bar_type = (lower_32 >> 1) & 0b11;
 
@@ -157,8 +164,16 @@ impl<'a> Bar<'a> {
         }
     }
 
-    /// Gets the size of the BAR
+    /// Gets the size of the BAR, or 0 if the BAR isn't implemented by the device.
+    ///
+    /// This briefly disables the device's memory and IO space accesses, so interrupts are disabled
+    /// while the BAR is sized to stop interrupt handlers accessing the device in the meantime.
     pub fn get_size(&self) -> u64 {
+        without_interrupts(|| self.get_size_inner())
+    }
+
+    /// Implementation of [`get_size`][Bar::get_size]
+    fn get_size_inner(&self) -> u64 {
         /// The register offset of the status and command registers.
         /// The command register is used to turn off memory and IO accesses while calculating the size of the BAR.
         const STATUS_AND_COMMAND_REGISTER: u8 = 1;
@@ -195,8 +210,9 @@ impl<'a> Bar<'a> {
         }
 
         // Only the writes to the top bits will have succeeded, so doing a bitwise not will make this only the lower bits.
-        // Then adding one will give back the power of 2 size of the BAR
-        (!masked_address + 1).into()
+        // Then adding one will give back the power of 2 size of the BAR.
+        // If no bits could be set, the BAR isn't implemented and this wraps to 0.
+        (!masked_address).wrapping_add(1).into()
     }
 
     /// Writes a 32 bit value to the base address of this BAR.
@@ -7,7 +7,10 @@ use crate::{
     util::generic_mutability::{Mutability, Mutable, RefDebug, Reference},
 };
 
-use super::{msix::MsixCapability, MessageSignalledInterruptsCapability};
+use super::{
+    msix::MsixCapability, power_management::PowerManagementCapability,
+    MessageSignalledInterruptsCapability,
+};
 
 
 /// A type of capability entry on a PCI device.
@@ -19,7 +22,7 @@ pub enum CapabilityEntry<'a, M: Mutability + RefDebug<'a, PcieMappedRegisters>>
     /// PCI Power Management Interface.
     ///
     /// Documentation for this capability can be found in the _PCI Bus Power Management Interface Specification_. (TODO: link)
-    PciPowerManagementInterface,
+    PciPowerManagementInterface(PowerManagementCapability<'a, M>),
     /// Accelerated Graphics Port
     ///
     /// Documentation for this capability can be found in the _Accelerated Graphics Port Interface Specification_. (TODO: link)
@@ -101,7 +104,11 @@ impl<'a, M: Mutability + RefDebug<'a, PcieMappedRegisters>> CapabilityEntry<'a,
         // https://pcisig.com/sites/default/files/files/PCI_Code-ID_r_1_12__v9_Jan_2020.pdf
         let entry = match id {
             0x00 => Self::Null,
-            0x01 => Self::PciPowerManagementInterface,
+            // SAFETY: `offset` is a valid index,
+            // and the id value is 1 so it is a power management capability
+            0x01 => unsafe {
+                Self::PciPowerManagementInterface(PowerManagementCapability::new(function, offset))
+            },
             0x02 => Self::AcceleratedGraphicsPort,
             0x03 => Self::VitalProductData,
             0x04 => Self::SlotIdentification,
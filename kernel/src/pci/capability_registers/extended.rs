@@ -0,0 +1,144 @@
+//! The [`ExtendedCapabilityId`] type for identifying the PCI Express extended capabilities of a PCI device,
+//! which are stored in a linked list starting at byte offset 0x100 in the configuration space.
+
+/// The header at the start of every PCI Express extended capability structure
+#[bitfield(u32)]
+pub struct ExtendedCapabilityHeader {
+    /// The ID of the capability, which can be parsed using [`ExtendedCapabilityId::from_id`]
+    pub id: u16,
+    /// The version of the capability structure, which is specific to each capability
+    #[bits(4)]
+    pub version: u8,
+    /// The byte offset of the next capability in the list, or 0 if this is the last capability
+    #[bits(12)]
+    pub next_offset: u16,
+}
+
+/// The type of a PCI Express extended capability.
+///
+/// These IDs can be found in section 3 of the [PCI Code and ID Assignment Specification].
+///
+/// [PCI Code and ID Assignment Specification]: https://pcisig.com/sites/default/files/files/PCI_Code-ID_r_1_12__v9_Jan_2020.pdf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedCapabilityId {
+    /// A placeholder capability, containing no extra registers
+    Null,
+    /// Advanced Error Reporting (AER)
+    AdvancedErrorReporting,
+    /// Virtual Channel
+    VirtualChannel,
+    /// Device Serial Number, which contains a 64-bit serial number unique to the device
+    DeviceSerialNumber,
+    /// Power Budgeting
+    PowerBudgeting,
+    /// Root Complex Link Declaration
+    RootComplexLinkDeclaration,
+    /// Root Complex Internal Link Control
+    RootComplexInternalLinkControl,
+    /// Root Complex Event Collector Endpoint Association
+    RootComplexEventCollectorEndpointAssociation,
+    /// Multi-Function Virtual Channel
+    MultiFunctionVirtualChannel,
+    /// Virtual Channel, used when a Multi-Function Virtual Channel capability is also present
+    VirtualChannelMfvc,
+    /// Root Complex Register Block Header
+    RootComplexRegisterBlockHeader,
+    /// A vendor specific capability
+    VendorSpecific,
+    /// Configuration Access Correlation
+    ConfigurationAccessCorrelation,
+    /// Access Control Services (ACS)
+    AccessControlServices,
+    /// Alternative Routing-ID Interpretation (ARI)
+    AlternativeRoutingIdInterpretation,
+    /// Address Translation Services (ATS)
+    AddressTranslationServices,
+    /// Single Root I/O Virtualization (SR-IOV)
+    SingleRootIoVirtualization,
+    /// Multi-Root I/O Virtualization (MR-IOV)
+    MultiRootIoVirtualization,
+    /// Multicast
+    Multicast,
+    /// Page Request Interface (PRI)
+    PageRequestInterface,
+    /// Resizable BAR
+    ResizableBar,
+    /// Dynamic Power Allocation
+    DynamicPowerAllocation,
+    /// TLP Processing Hints
+    TlpProcessingHints,
+    /// Latency Tolerance Reporting
+    LatencyToleranceReporting,
+    /// Secondary PCI Express
+    SecondaryPciExpress,
+    /// Process Address Space ID (PASID)
+    ProcessAddressSpaceId,
+    /// L1 PM Substates
+    L1PmSubstates,
+    /// Precision Time Measurement
+    PrecisionTimeMeasurement,
+    /// Designated Vendor-Specific
+    DesignatedVendorSpecific,
+    /// Data Link Feature
+    DataLinkFeature,
+    /// Physical Layer 16.0 GT/s
+    PhysicalLayer16GTs,
+    /// An ID which isn't recognised
+    Other(u16),
+}
+
+impl ExtendedCapabilityId {
+    /// Parses the ID from the [`id`] field of an [`ExtendedCapabilityHeader`]
+    ///
+    /// [`id`]: ExtendedCapabilityHeader::id
+    pub fn from_id(id: u16) -> Self {
+        match id {
+            0x0000 => Self::Null,
+            0x0001 => Self::AdvancedErrorReporting,
+            0x0002 => Self::VirtualChannel,
+            0x0003 => Self::DeviceSerialNumber,
+            0x0004 => Self::PowerBudgeting,
+            0x0005 => Self::RootComplexLinkDeclaration,
+            0x0006 => Self::RootComplexInternalLinkControl,
+            0x0007 => Self::RootComplexEventCollectorEndpointAssociation,
+            0x0008 => Self::MultiFunctionVirtualChannel,
+            0x0009 => Self::VirtualChannelMfvc,
+            0x000A => Self::RootComplexRegisterBlockHeader,
+            0x000B => Self::VendorSpecific,
+            0x000C => Self::ConfigurationAccessCorrelation,
+            0x000D => Self::AccessControlServices,
+            0x000E => Self::AlternativeRoutingIdInterpretation,
+            0x000F => Self::AddressTranslationServices,
+            0x0010 => Self::SingleRootIoVirtualization,
+            0x0011 => Self::MultiRootIoVirtualization,
+            0x0012 => Self::Multicast,
+            0x0013 => Self::PageRequestInterface,
+            0x0015 => Self::ResizableBar,
+            0x0016 => Self::DynamicPowerAllocation,
+            0x0017 => Self::TlpProcessingHints,
+            0x0018 => Self::LatencyToleranceReporting,
+            0x0019 => Self::SecondaryPciExpress,
+            0x001B => Self::ProcessAddressSpaceId,
+            0x001E => Self::L1PmSubstates,
+            0x001F => Self::PrecisionTimeMeasurement,
+            0x0023 => Self::DesignatedVendorSpecific,
+            0x0025 => Self::DataLinkFeature,
+            0x0026 => Self::PhysicalLayer16GTs,
+            id => Self::Other(id),
+        }
+    }
+}
+
+/// Tests that [`ExtendedCapabilityHeader`] splits a header into its ID, version, and next pointer
+#[test_case]
+fn test_extended_capability_header() {
+    // A Device Serial Number capability, version 1, followed by another capability at 0x150
+    let header = ExtendedCapabilityHeader::from(0x1501_0003);
+
+    assert_eq!(
+        ExtendedCapabilityId::from_id(header.id()),
+        ExtendedCapabilityId::DeviceSerialNumber
+    );
+    assert_eq!(header.version(), 1);
+    assert_eq!(header.next_offset(), 0x150);
+}
@@ -6,13 +6,17 @@
 
 // TODO: deduplicate all these modules
 pub mod capability;
+pub mod extended;
 pub mod msi;
 pub mod msix;
+pub mod power_management;
 
 use core::fmt::Debug;
 
 pub use msi::*;
 pub use capability::*;
+pub use extended::*;
+pub use power_management::*;
 
 use crate::util::generic_mutability::{Immutable, Mutable};
 
@@ -211,6 +215,12 @@ impl Debug for MsixTableEntry {
 /// if [`has_capabilities_list`][super::registers::StatusRegister::has_capabilities_list] is true
 const CAPABILITIES_REGISTER_OFFSET: u8 = 0xD;
 
+/// The byte offset of the first PCI Express extended capability in the configuration space
+const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+
+/// The size in bytes of a PCI Express function's configuration space
+const CONFIGURATION_SPACE_SIZE: u16 = 0x1000;
+
 impl PciMappedFunction {
     /// Gets an iterator over the capability registers of the given `function`.
     /// Each item of the returned iterator is a tuple of ([`CapabilityEntry`], [`u8`]) where the `u8`
@@ -236,8 +246,9 @@ impl PciMappedFunction {
                 // SAFETY: `i` was either read directly from the device's registers or returned from `CapabilityEntry::new`, so it is valid.
                 let (capability_id, next_pointer) = unsafe { CapabilityEntry::new(self, i) };
 
+                let offset = i;
                 i = next_pointer;
-                Some((capability_id, i))
+                Some((capability_id, offset))
             }
         }))
     }
@@ -268,10 +279,57 @@ impl PciMappedFunction {
                 // SAFETY: `i` was either read directly from the device's registers or returned from `CapabilityEntry::new`, so it is valid.
                 let (capability_id, next_pointer) = unsafe { CapabilityEntry::new_mut(self, i) };
 
+                let offset = i;
                 i = next_pointer;
 
-                Some((capability_id, i))
+                Some((capability_id, offset))
             }
         }))
     }
+
+    /// Gets an iterator over the PCI Express extended capabilities of the given `function`,
+    /// which are stored in the configuration space after the first 256 bytes.
+    /// Each item of the returned iterator is a tuple of ([`ExtendedCapabilityId`], [`u16`]) where the `u16`
+    /// is the byte offset of where the capability starts.
+    ///
+    /// If the function has no extended capabilities (e.g. because it is a conventional PCI function),
+    /// the iterator is empty.
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = (ExtendedCapabilityId, u16)> + '_ {
+        let mut offset = EXTENDED_CAPABILITIES_OFFSET;
+
+        // Each capability is at least 4 bytes long, so a list longer than this must contain a loop
+        let mut remaining = (CONFIGURATION_SPACE_SIZE - EXTENDED_CAPABILITIES_OFFSET) / 4;
+
+        core::iter::from_fn(move || {
+            // The last capability in the list has a null next pointer.
+            // Pointers into the first 256 bytes or past the end of the configuration space are invalid.
+            if !(EXTENDED_CAPABILITIES_OFFSET..CONFIGURATION_SPACE_SIZE).contains(&offset)
+                || remaining == 0
+            {
+                return None;
+            }
+            remaining -= 1;
+
+            // SAFETY: `offset` is within the extended configuration space.
+            // Reading from capability headers has no side effects.
+            let register = unsafe { self.read_extended_reg(offset / 4) };
+
+            // A header of 0 means there are no extended capabilities,
+            // and all ones means the extended configuration space isn't accessible
+            if register == 0 || register == u32::MAX {
+                return None;
+            }
+
+            let header = ExtendedCapabilityHeader::from(register);
+            let capability_offset = offset;
+
+            // The bottom 2 bits of the next pointer are reserved
+            offset = header.next_offset() & !0b11;
+
+            Some((
+                ExtendedCapabilityId::from_id(header.id()),
+                capability_offset,
+            ))
+        })
+    }
 }
@@ -21,6 +21,8 @@ pub struct MessageSignalledInterruptsCapability<'a, M: Mutability> {
 
     /// The memory-mapped data register
     data: M::Ptr<u16>,
+    /// The memory-mapped mask bits register, if the device supports per-vector masking
+    mask_bits: Option<M::Ptr<u32>>,
 
     /// PhantomData for the lifetime of the memory-mapped registers
     _p: PhantomData<M::Ref<'a, PcieMappedRegisters>>,
@@ -49,20 +51,29 @@ impl<'a, M: Mutability> MessageSignalledInterruptsCapability<'a, M> {
 
         let is_64_bit = control.is_64_bit();
 
+        // `capability_start_ptr` is a `u32` pointer, so these offsets are in registers rather than bytes
         let message_address_high = if is_64_bit {
-            // SAFETY: The message address high register is at offset 8 in the MSI capabilities structure
-            unsafe { Some(capability_start_ptr.add(8).cast()) }
+            // SAFETY: The message address high register is at byte offset 8 in the MSI capabilities structure
+            unsafe { Some(capability_start_ptr.add(2)) }
         } else {
             None
         };
 
-        let offset_for_64_bit = if is_64_bit { 4 } else { 0 };
+        // The registers after the message address are one register later if the address is 64-bit
+        let offset_for_64_bit = usize::from(is_64_bit);
 
-        // SAFETY: The message address low register is at offset 4 in the MSI capabilities structure
-        let message_address_low = unsafe { capability_start_ptr.add(4).cast() };
+        // SAFETY: The message address low register is at byte offset 4 in the MSI capabilities structure
+        let message_address_low = unsafe { capability_start_ptr.add(1) };
 
-        // SAFETY: The data register is at offset 8 in the MSI capabilities structure
-        let data = unsafe { capability_start_ptr.add(12 + offset_for_64_bit).cast() };
+        // SAFETY: The data register is at byte offset 8 in the MSI capabilities structure, or 12 if the address is 64-bit
+        let data = unsafe { capability_start_ptr.add(2 + offset_for_64_bit).cast() };
+
+        let mask_bits = if control.per_vector_masks() {
+            // SAFETY: The mask bits register is at byte offset 12 in the MSI capabilities structure, or 16 if the address is 64-bit
+            unsafe { Some(capability_start_ptr.add(3 + offset_for_64_bit)) }
+        } else {
+            None
+        };
 
         Self {
             control: control_ptr,
@@ -70,6 +81,7 @@ impl<'a, M: Mutability> MessageSignalledInterruptsCapability<'a, M> {
             message_address_high,
 
             data,
+            mask_bits,
             _p: PhantomData,
         }
     }
@@ -104,6 +116,17 @@ impl<'a, M: Mutability> MessageSignalledInterruptsCapability<'a, M> {
         // SAFETY: It's unsound to create a reference in to a `PcieMappedRegisters`, so no references exist for this data
         unsafe { self.data.as_const_ptr().read_volatile() }
     }
+
+    /// Reads the [`mask_bits`] register, if the device supports per-vector masking.
+    /// If bit `n` is set, the device won't send interrupts for vector `n`.
+    ///
+    /// [`mask_bits`]: MessageSignalledInterruptsCapability::mask_bits
+    pub fn mask_bits(&self) -> Option<u32> {
+        self.mask_bits.map(|p| {
+            // SAFETY: It's unsound to create a reference in to a `PcieMappedRegisters`, so no references exist for this data
+            unsafe { p.as_const_ptr().read_volatile() }
+        })
+    }
 }
 
 /// An error occurring when trying to write a 64-bit address to a device which doesn't support them
@@ -160,4 +183,32 @@ impl<'a> MessageSignalledInterruptsCapability<'a, Mutable> {
         self.write_message_address(address as _).unwrap();
         self.write_data(data);
     }
+
+    /// Writes to the [`mask_bits`] register, if the device supports per-vector masking.
+    /// Does nothing if the device doesn't support it.
+    ///
+    /// [`mask_bits`]: MessageSignalledInterruptsCapability::mask_bits
+    pub fn write_mask_bits(&mut self, mask_bits: u32) {
+        if let Some(ptr) = self.mask_bits {
+            // SAFETY: It's unsound to create a reference in to a `PcieMappedRegisters`, so no references exist for this data
+            unsafe { ptr.write_volatile(mask_bits) }
+        }
+    }
+
+    /// Programs the device to send a single MSI vector to the given address, and enables MSI.
+    ///
+    /// MSI is disabled while the registers are written so that the device doesn't send an interrupt with
+    /// a partially written address. Only one vector is enabled, and it is unmasked if the device supports masking.
+    /// The 32-bit or 64-bit layout of the capability is handled by [`write_message_address`].
+    ///
+    /// [`write_message_address`]: MessageSignalledInterruptsCapability::write_message_address
+    pub fn configure(&mut self, address: X64MsiAddress) {
+        let control = self.control();
+        self.write_control(control.with_enable(false));
+
+        self.write_address_x64(address);
+        self.write_mask_bits(0);
+
+        self.write_control(control.with_multi_message_enable(0).with_enable(true));
+    }
 }
@@ -0,0 +1,221 @@
+//! The [`PowerManagementCapability`] type for a view into a PCI device's power management capability
+//!
+//! Documentation for this capability can be found in section 3.2 of the _PCI Bus Power Management Interface Specification_.
+
+use core::marker::PhantomData;
+
+use crate::{
+    pci::{PciMappedFunction, PcieMappedRegisters},
+    util::{
+        bitfield_enum::bitfield_enum,
+        generic_mutability::{Mutability, Mutable, Pointer, Reference},
+    },
+};
+
+bitfield_enum!(
+    #[bitfield_enum(u16)]
+    /// A power state of a PCI function.
+    /// Higher numbered states use less power, but the function can do less and takes longer to return to [`D0`].
+    ///
+    /// [`D0`]: PowerState::D0
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum PowerState {
+        #[value(0)]
+        /// The function is fully powered and operational
+        D0,
+        #[value(1)]
+        /// A light sleep state. Not all functions support this state.
+        D1,
+        #[value(2)]
+        /// A deeper sleep state than [`D1`][PowerState::D1]. Not all functions support this state.
+        D2,
+        #[value(3)]
+        /// The function is powered off, except for its configuration space.
+        /// Many functions are in this state when the system boots.
+        D3Hot,
+    }
+);
+
+/// The power management capabilities (PMC) register, which describes which power states a function supports
+#[bitfield(u16)]
+pub struct PowerManagementCapabilities {
+    /// The version of the power management specification which the function complies with
+    #[bits(3)]
+    pub version: u8,
+    /// Whether the function needs a PCI clock to generate power management events
+    pub pme_clock: bool,
+    /// Whether the function is ready to be accessed as soon as it is transitioned to [`D0`][PowerState::D0]
+    pub immediate_readiness_on_return_to_d0: bool,
+    /// Whether the function needs device-specific initialisation after being transitioned to [`D0`][PowerState::D0],
+    /// beyond what a generic class driver would do
+    pub device_specific_initialisation: bool,
+    /// The current the function draws from the auxiliary power supply when powered off
+    #[bits(3)]
+    pub aux_current: u8,
+    /// Whether the function supports the [`D1`][PowerState::D1] state
+    pub d1_support: bool,
+    /// Whether the function supports the [`D2`][PowerState::D2] state
+    pub d2_support: bool,
+    /// A bit mask of which power states the function can send power management events from.
+    /// Bit `n` corresponds to state `Dn`, and bit 4 corresponds to `D3Cold`.
+    #[bits(5)]
+    pub pme_support: u8,
+}
+
+impl PowerManagementCapabilities {
+    /// Gets whether the function supports the given power state
+    pub fn supports(&self, state: PowerState) -> bool {
+        match state {
+            PowerState::D0 | PowerState::D3Hot => true,
+            PowerState::D1 => self.d1_support(),
+            PowerState::D2 => self.d2_support(),
+        }
+    }
+}
+
+/// The power management control and status (PMCSR) register, which is used to change a function's power state
+#[bitfield(u16)]
+pub struct PowerManagementStatusAndControl {
+    /// The current power state of the function. Writing to this field changes the power state.
+    #[bits(2)]
+    pub power_state: PowerState,
+    #[bits(1)]
+    #[doc(hidden)]
+    reserved0: u16,
+    /// Whether the function keeps its configuration when transitioning from [`D3Hot`] to [`D0`].
+    /// If this is `false`, the function is reset by the transition, and its BARs need to be re-read.
+    ///
+    /// [`D3Hot`]: PowerState::D3Hot
+    /// [`D0`]: PowerState::D0
+    pub no_soft_reset: bool,
+    #[bits(4)]
+    #[doc(hidden)]
+    reserved1: u16,
+    /// Whether the function may send power management events
+    pub pme_enable: bool,
+    /// Which data to report in the power management capability's data register
+    #[bits(4)]
+    pub data_select: u8,
+    /// The scaling factor of the power management capability's data register
+    #[bits(2)]
+    pub data_scale: u8,
+    /// Whether the function has sent a power management event. This is cleared by writing `true` to it.
+    pub pme_status: bool,
+}
+
+/// A view into the power management capability of a PCI device
+#[derive(Debug)]
+pub struct PowerManagementCapability<'a, M: Mutability> {
+    /// The memory-mapped power management capabilities register
+    capabilities: M::Ptr<PowerManagementCapabilities>,
+    /// The memory-mapped power management control and status register
+    status_and_control: M::Ptr<PowerManagementStatusAndControl>,
+
+    /// PhantomData for the lifetime of the memory-mapped registers
+    _p: PhantomData<M::Ref<'a, PcieMappedRegisters>>,
+}
+
+impl<'a, M: Mutability> PowerManagementCapability<'a, M> {
+    /// # Safety:
+    /// * `offset` is the register (not byte) offset of a power management capability structure within the configuration space of `function`
+    pub(super) unsafe fn new(function: M::Ref<'_, PciMappedFunction>, offset: u8) -> Self {
+        // SAFETY: `offset` is the offset of a power management capability structure
+        let capability_start_ptr = unsafe {
+            function
+                .as_const_ref()
+                .registers
+                .as_generic_ptr::<u32, M>()
+                .add(offset as _)
+        };
+
+        assert!(capability_start_ptr.as_const_ptr().is_aligned_to(4));
+        assert!(!capability_start_ptr.as_const_ptr().is_null());
+
+        // SAFETY: The capabilities register is at byte offset 2 in the power management capability structure
+        let capabilities = unsafe { capability_start_ptr.cast::<u16>().add(1).cast() };
+        // SAFETY: The control and status register is at byte offset 4 in the power management capability structure
+        let status_and_control = unsafe { capability_start_ptr.add(1).cast() };
+
+        Self {
+            capabilities,
+            status_and_control,
+            _p: PhantomData,
+        }
+    }
+
+    /// Reads the [`capabilities`] register
+    ///
+    /// [`capabilities`]: PowerManagementCapability::capabilities
+    pub fn capabilities(&self) -> PowerManagementCapabilities {
+        // SAFETY: It's unsound to create a reference in to a `PcieMappedRegisters`, so no references exist for this data
+        unsafe { self.capabilities.as_const_ptr().read_volatile() }
+    }
+
+    /// Reads the [`status_and_control`] register
+    ///
+    /// [`status_and_control`]: PowerManagementCapability::status_and_control
+    pub fn status_and_control(&self) -> PowerManagementStatusAndControl {
+        // SAFETY: It's unsound to create a reference in to a `PcieMappedRegisters`, so no references exist for this data
+        unsafe { self.status_and_control.as_const_ptr().read_volatile() }
+    }
+
+    /// Reads the current power state of the function
+    pub fn power_state(&self) -> PowerState {
+        self.status_and_control().power_state()
+    }
+}
+
+/// An error occurring when trying to put a function in a power state which it doesn't support
+#[derive(Debug, Clone, Copy)]
+pub struct UnsupportedPowerStateError(pub PowerState);
+
+impl<'a> PowerManagementCapability<'a, Mutable> {
+    /// Writes the [`status_and_control`] register
+    ///
+    /// [`status_and_control`]: PowerManagementCapability::status_and_control
+    pub fn write_status_and_control(&mut self, value: PowerManagementStatusAndControl) {
+        // SAFETY: It's unsound to create a reference in to a `PcieMappedRegisters`, so no references exist for this data
+        unsafe { self.status_and_control.write_volatile(value) }
+    }
+
+    /// Writes the [`power_state`] field of the [`status_and_control`] register.
+    ///
+    /// This doesn't wait for the transition to finish - a function may not be accessed for 10ms after
+    /// a transition from [`D3Hot`] to [`D0`].
+    ///
+    /// [`power_state`]: PowerManagementStatusAndControl::power_state
+    /// [`status_and_control`]: PowerManagementCapability::status_and_control
+    /// [`D3Hot`]: PowerState::D3Hot
+    /// [`D0`]: PowerState::D0
+    pub fn set_power_state(&mut self, state: PowerState) -> Result<(), UnsupportedPowerStateError> {
+        if !self.capabilities().supports(state) {
+            return Err(UnsupportedPowerStateError(state));
+        }
+
+        // Don't write back `pme_status`, as writing `true` to it would clear it
+        let status_and_control = self
+            .status_and_control()
+            .with_pme_status(false)
+            .with_power_state(state);
+        self.write_status_and_control(status_and_control);
+
+        Ok(())
+    }
+}
+
+/// Tests that [`PowerManagementStatusAndControl`] doesn't clear `pme_status` when changing the power state
+#[test_case]
+fn test_power_state_encoding() {
+    // A function in D3Hot with `no_soft_reset` and `pme_status` set
+    let status_and_control = PowerManagementStatusAndControl::from(0x8000 | 0b1011);
+
+    assert_eq!(status_and_control.power_state(), PowerState::D3Hot);
+    assert!(status_and_control.no_soft_reset());
+    assert!(status_and_control.pme_status());
+
+    let new = status_and_control
+        .with_pme_status(false)
+        .with_power_state(PowerState::D0);
+
+    assert_eq!(u16::from(new), 0b1000);
+}
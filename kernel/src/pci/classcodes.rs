@@ -40,6 +40,14 @@ impl UnclassifiedDeviceType {
             }),
         }
     }
+
+    /// Gets a human-readable name for the device type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NonVgaCompatible => "Non-VGA unclassified device",
+            Self::VgaCompatible => "VGA compatible unclassified device",
+        }
+    }
 }
 
 /// A type of mass storage controller
@@ -87,6 +95,22 @@ impl MassStorageControllerType {
             }),
         }
     }
+
+    /// Gets a human-readable name for the controller type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SCSIBusController => "SCSI storage controller",
+            Self::IDEController => "IDE interface",
+            Self::FloppyDiskController => "Floppy disk controller",
+            Self::IPIBusController => "IPI bus controller",
+            Self::RAIDController => "RAID bus controller",
+            Self::ATAController => "ATA controller",
+            Self::SerialATAController => "SATA controller",
+            Self::SerialAttachedSCSIController => "Serial Attached SCSI controller",
+            Self::NonVolatileMemoryController => "Non-volatile memory controller",
+            Self::Other => "Mass storage controller",
+        }
+    }
 }
 
 /// A type of USB controller
@@ -122,6 +146,18 @@ impl USBControllerType {
             }),
         }
     }
+
+    /// Gets a human-readable name for the controller type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Uhci => "USB controller (UHCI)",
+            Self::Ohci => "USB controller (OHCI)",
+            Self::Ehci => "USB controller (EHCI)",
+            Self::Xhci => "USB controller (xHCI)",
+            Self::Unspecified => "USB controller",
+            Self::Device => "USB device",
+        }
+    }
 }
 
 /// A type of serial bus controller
@@ -174,6 +210,23 @@ impl SerialBusControllerType {
             }),
         }
     }
+
+    /// Gets a human-readable name for the controller type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::FireWireController => "FireWire (IEEE 1394)",
+            Self::AccessBusController => "ACCESS.bus controller",
+            Self::Ssa => "SSA controller",
+            Self::UsbController(usb_controller_type) => usb_controller_type.name(),
+            Self::FibreChannel => "Fibre Channel",
+            Self::SMBusController => "SMBus",
+            Self::InfiniBandController => "InfiniBand",
+            Self::IpmiInterface => "IPMI interface",
+            Self::SercosInterface => "SERCOS interface",
+            Self::CanBusController => "CANBus controller",
+            Self::Other => "Serial bus controller",
+        }
+    }
 }
 
 /// A general function performed by a PCI device
@@ -267,4 +320,75 @@ impl ClassCode {
             }),
         }
     }
+
+    /// Gets the top-level class code, without the subclass or programming interface
+    pub fn class_code(&self) -> u8 {
+        match self {
+            Self::Unclassified(_) => 0x00,
+            Self::MassStorageController(_) => 0x01,
+            Self::NetworkController => 0x02,
+            Self::DisplayController => 0x03,
+            Self::MultimediaController => 0x04,
+            Self::MemoryController => 0x05,
+            Self::Bridge => 0x06,
+            Self::SimpleCommunicationController => 0x07,
+            Self::BaseSystemPeripheral => 0x08,
+            Self::InputDeviceController => 0x09,
+            Self::DockingStation => 0x0A,
+            Self::Processor => 0x0B,
+            Self::SerialBusController(_) => 0x0C,
+            Self::WirelessController => 0x0D,
+            Self::IntelligentController => 0x0E,
+            Self::SatelliteCommunicationController => 0x0F,
+            Self::EncryptionController => 0x10,
+            Self::SignalProcessingController => 0x11,
+            Self::ProcessingAccelerator => 0x12,
+            Self::NonEssentialInstrumentation => 0x13,
+
+            Self::CoProcessor => 0x40,
+            Self::Unassigned => 0xFF,
+        }
+    }
+
+    /// Gets a human-readable name for the class code, including the subclass and programming interface if they are known
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unclassified(device_type) => device_type.name(),
+            Self::MassStorageController(controller_type) => controller_type.name(),
+            Self::NetworkController => "Network controller",
+            Self::DisplayController => "Display controller",
+            Self::MultimediaController => "Multimedia controller",
+            Self::MemoryController => "Memory controller",
+            Self::Bridge => "Bridge",
+            Self::SimpleCommunicationController => "Communication controller",
+            Self::BaseSystemPeripheral => "Generic system peripheral",
+            Self::InputDeviceController => "Input device controller",
+            Self::DockingStation => "Docking station",
+            Self::Processor => "Processor",
+            Self::SerialBusController(controller_type) => controller_type.name(),
+            Self::WirelessController => "Wireless controller",
+            Self::IntelligentController => "Intelligent controller",
+            Self::SatelliteCommunicationController => "Satellite communications controller",
+            Self::EncryptionController => "Encryption controller",
+            Self::SignalProcessingController => "Signal processing controller",
+            Self::ProcessingAccelerator => "Processing accelerator",
+            Self::NonEssentialInstrumentation => "Non-essential instrumentation",
+            Self::CoProcessor => "Coprocessor",
+            Self::Unassigned => "Unassigned class",
+        }
+    }
+}
+
+/// Tests that [`ClassCode::name`] includes the subclass and programming interface
+#[test_case]
+fn test_class_code_name() {
+    assert_eq!(
+        ClassCode::new(0x0C, 0x03, 0x30).unwrap().name(),
+        "USB controller (xHCI)"
+    );
+    assert_eq!(
+        ClassCode::new(0x01, 0x06, 0x01).unwrap().name(),
+        "SATA controller"
+    );
+    assert_eq!(ClassCode::new(0x06, 0x00, 0x00).unwrap().name(), "Bridge");
 }
@@ -135,7 +135,7 @@ impl PciRegister {
 }
 
 /// Represents a specific function of a [`PciDevice`]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PciFunction {
     /// The bus number
     bus: u8,
@@ -1,3 +1,77 @@
 //! Drivers for specific types of PCI device
 
 pub mod usb;
+pub mod virtio;
+
+use crate::scheduler::Task;
+
+use self::{
+    usb::xhci::XhciController,
+    virtio::{blk, VIRTIO_VENDOR_ID},
+};
+
+use super::{
+    classcodes::{ClassCode, SerialBusControllerType, USBControllerType},
+    registers::PciHeader,
+    PciMappedFunction,
+};
+
+/// A driver which can be started for PCI functions of a certain type
+#[derive(Debug)]
+pub struct PciDriver {
+    /// Whether the driver can drive the function with the given header
+    pub matches: fn(&PciHeader) -> bool,
+    /// Constructs the [`Task`] which runs the driver for the given function
+    ///
+    /// # Safety
+    /// This may only be called once per function
+    pub init: unsafe fn(PciMappedFunction) -> Task,
+    /// Prints a summary of the function's device-specific registers, for `lspci -s`.
+    /// The `bool` is whether `-v` was also passed, in which case more detail should be printed.
+    /// This may be called while the driver is running, so it must not change the state of the device.
+    pub print_registers: Option<fn(&PciMappedFunction, bool)>,
+}
+
+/// The drivers which [`pci::init`] starts for PCI functions.
+/// For each function, the first driver which [`matches`] it is started.
+///
+/// [`pci::init`]: super::init
+/// [`matches`]: PciDriver::matches
+pub const DRIVERS: &[PciDriver] = &[
+    PciDriver {
+        matches: |header| {
+            header.class_code
+                == ClassCode::SerialBusController(SerialBusControllerType::UsbController(
+                    USBControllerType::Xhci,
+                ))
+        },
+        init: init_xhci,
+        print_registers: Some(usb::xhci::print_registers),
+    },
+    PciDriver {
+        matches: |header| {
+            header.device_code.vendor == VIRTIO_VENDOR_ID
+                && blk::DEVICE_IDS.contains(&header.device_code.device)
+        },
+        init: init_virtio_blk,
+        print_registers: None,
+    },
+];
+
+/// Constructs the [`Task`] which runs the xHCI driver for the given controller
+///
+/// # Safety
+/// This may only be called once per controller
+unsafe fn init_xhci(function: PciMappedFunction) -> Task {
+    // SAFETY: The caller guarantees this is only called once per controller
+    Task::new(Some("xhci"), unsafe { XhciController::init(function) })
+}
+
+/// Constructs the [`Task`] which runs the virtio block driver for the given device
+///
+/// # Safety
+/// This may only be called once per device
+unsafe fn init_virtio_blk(function: PciMappedFunction) -> Task {
+    // SAFETY: The caller guarantees this is only called once per device
+    Task::new(Some("virtio-blk"), unsafe { blk::init(function) })
+}
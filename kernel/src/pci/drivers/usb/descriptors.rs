@@ -0,0 +1,671 @@
+//! Parsers for the standard USB descriptors, which devices use to describe themselves and their capabilities.
+//!
+//! Descriptors are read from a device using `GET_DESCRIPTOR` requests. Most descriptors have a fixed layout,
+//! but a configuration descriptor is returned together with all of its interface, endpoint, and class-specific descriptors
+//! in a single buffer. [`ConfigurationDescriptorSet`] walks this buffer without allocating.
+//!
+//! These data structures are defined in chapter 9.6 of the [USB2 specification].
+//!
+//! [USB2 specification]: https://www.usb.org/document-library/usb-20-specification
+
+use alloc::string::String;
+
+/// The type of a descriptor, stored in its `bDescriptorType` field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorType {
+    /// A [`DeviceDescriptor`]
+    Device,
+    /// A [`ConfigurationDescriptor`]
+    Configuration,
+    /// A string descriptor
+    String,
+    /// An [`InterfaceDescriptor`]
+    Interface,
+    /// An [`EndpointDescriptor`]
+    Endpoint,
+    /// A HID descriptor, defined in the [HID specification]
+    ///
+    /// [HID specification]: https://www.usb.org/document-library/device-class-definition-hid-111
+    Hid,
+    /// A hub descriptor, defined in section 11.23 of the USB2 specification
+    Hub,
+    /// A descriptor type which isn't parsed by this module
+    Other(u8),
+}
+
+impl DescriptorType {
+    /// Constructs a [`DescriptorType`] from its byte representation
+    pub const fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => Self::Device,
+            2 => Self::Configuration,
+            3 => Self::String,
+            4 => Self::Interface,
+            5 => Self::Endpoint,
+            0x21 => Self::Hid,
+            0x29 => Self::Hub,
+            other => Self::Other(other),
+        }
+    }
+
+    /// Converts a [`DescriptorType`] into its byte representation
+    pub const fn to_byte(self) -> u8 {
+        match self {
+            Self::Device => 1,
+            Self::Configuration => 2,
+            Self::String => 3,
+            Self::Interface => 4,
+            Self::Endpoint => 5,
+            Self::Hid => 0x21,
+            Self::Hub => 0x29,
+            Self::Other(other) => other,
+        }
+    }
+}
+
+/// An error which can occur while parsing a descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorError {
+    /// The buffer ended before the end of the descriptor
+    TooShort,
+    /// A descriptor's `bLength` field was too small to hold its own header or fixed-size fields
+    InvalidLength(u8),
+    /// The descriptor was not of the expected type
+    WrongType(DescriptorType),
+}
+
+/// A single descriptor of any type, before its fields have been parsed
+#[derive(Debug, Clone, Copy)]
+pub struct RawDescriptor<'a> {
+    /// The type of the descriptor
+    pub descriptor_type: DescriptorType,
+    /// The bytes of the descriptor, including the `bLength` and `bDescriptorType` header
+    pub bytes: &'a [u8],
+}
+
+impl<'a> RawDescriptor<'a> {
+    /// Reads the descriptor at the start of `buf`, returning it and the rest of the buffer.
+    fn split_from(buf: &'a [u8]) -> Result<(Self, &'a [u8]), DescriptorError> {
+        let [length, descriptor_type, ..] = *buf else {
+            return Err(DescriptorError::TooShort);
+        };
+
+        if length < 2 {
+            return Err(DescriptorError::InvalidLength(length));
+        }
+
+        if buf.len() < length.into() {
+            return Err(DescriptorError::TooShort);
+        }
+
+        let (bytes, rest) = buf.split_at(length.into());
+
+        Ok((
+            Self {
+                descriptor_type: DescriptorType::from_byte(descriptor_type),
+                bytes,
+            },
+            rest,
+        ))
+    }
+
+    /// Checks that the descriptor is of the `expected` type and is at least `min_length` bytes long
+    fn check(&self, expected: DescriptorType, min_length: usize) -> Result<(), DescriptorError> {
+        if self.descriptor_type != expected {
+            return Err(DescriptorError::WrongType(self.descriptor_type));
+        }
+
+        if self.bytes.len() < min_length {
+            #[allow(clippy::cast_possible_truncation)] // The length came from a `u8`
+            return Err(DescriptorError::InvalidLength(self.bytes.len() as u8));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the little-endian `u16` at the given byte offset
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+}
+
+/// An iterator over the descriptors in a buffer, using the `bLength` field of each descriptor to find the next.
+///
+/// If a descriptor is malformed, the error is returned and the iterator ends.
+#[derive(Debug, Clone)]
+pub struct Descriptors<'a>(&'a [u8]);
+
+impl<'a> Descriptors<'a> {
+    /// Constructs an iterator over the descriptors in `buf`
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self(buf)
+    }
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = Result<RawDescriptor<'a>, DescriptorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        match RawDescriptor::split_from(self.0) {
+            Ok((descriptor, rest)) => {
+                self.0 = rest;
+                Some(Ok(descriptor))
+            }
+            Err(e) => {
+                self.0 = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The _Device Descriptor_, which describes general information about a USB device.
+///
+/// This descriptor is defined in section 9.6.1 of the USB2 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// The version of the USB specification which the device complies with, in binary-coded decimal (e.g. `0x0210` for 2.1)
+    pub usb_version: u16,
+    /// The class code of the device. A value of 0 means that each interface specifies its own class.
+    pub device_class: u8,
+    /// The subclass code of the device, qualified by [`device_class`]
+    ///
+    /// [`device_class`]: DeviceDescriptor::device_class
+    pub device_subclass: u8,
+    /// The protocol code of the device, qualified by [`device_class`] and [`device_subclass`]
+    ///
+    /// [`device_class`]: DeviceDescriptor::device_class
+    /// [`device_subclass`]: DeviceDescriptor::device_subclass
+    pub device_protocol: u8,
+    /// The maximum packet size for endpoint 0.
+    /// For USB3 devices, this is an exponent, so the actual size is `2^max_packet_size_0`.
+    pub max_packet_size_0: u8,
+    /// The vendor ID, assigned by the USB-IF
+    pub vendor_id: u16,
+    /// The product ID, assigned by the vendor
+    pub product_id: u16,
+    /// The device release number, in binary-coded decimal
+    pub device_version: u16,
+    /// The index of the string descriptor describing the manufacturer, or 0 if there is none
+    pub manufacturer_index: u8,
+    /// The index of the string descriptor describing the product, or 0 if there is none
+    pub product_index: u8,
+    /// The index of the string descriptor containing the device's serial number, or 0 if there is none
+    pub serial_number_index: u8,
+    /// The number of possible configurations
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    /// The length of the descriptor in bytes
+    pub const LENGTH: usize = 18;
+
+    /// Parses a [`DeviceDescriptor`] from the start of `buf`
+    pub fn parse(buf: &[u8]) -> Result<Self, DescriptorError> {
+        let (d, _) = RawDescriptor::split_from(buf)?;
+        d.check(DescriptorType::Device, Self::LENGTH)?;
+
+        Ok(Self {
+            usb_version: d.read_u16(2),
+            device_class: d.bytes[4],
+            device_subclass: d.bytes[5],
+            device_protocol: d.bytes[6],
+            max_packet_size_0: d.bytes[7],
+            vendor_id: d.read_u16(8),
+            product_id: d.read_u16(10),
+            device_version: d.read_u16(12),
+            manufacturer_index: d.bytes[14],
+            product_index: d.bytes[15],
+            serial_number_index: d.bytes[16],
+            num_configurations: d.bytes[17],
+        })
+    }
+}
+
+/// Parses _String Descriptor_ zero, returning the language IDs of the languages the device's strings are available in.
+///
+/// String descriptors are defined in section 9.6.7 of the USB2 specification.
+pub fn parse_language_ids(buf: &[u8]) -> Result<impl Iterator<Item = u16> + '_, DescriptorError> {
+    let (d, _) = RawDescriptor::split_from(buf)?;
+    d.check(DescriptorType::String, 2)?;
+
+    Ok(d.bytes[2..]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]])))
+}
+
+/// Parses a _String Descriptor_, decoding its UTF-16LE contents.
+/// Invalid UTF-16 sequences are replaced with [`REPLACEMENT_CHARACTER`].
+///
+/// [`REPLACEMENT_CHARACTER`]: char::REPLACEMENT_CHARACTER
+pub fn parse_string(buf: &[u8]) -> Result<String, DescriptorError> {
+    let (d, _) = RawDescriptor::split_from(buf)?;
+    d.check(DescriptorType::String, 2)?;
+
+    let code_units = d.bytes[2..]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]));
+
+    Ok(char::decode_utf16(code_units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect())
+}
+
+/// The _Configuration Descriptor_, which describes one of the configurations a device can be put into.
+///
+/// This descriptor is defined in section 9.6.3 of the USB2 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigurationDescriptor {
+    /// The length in bytes of this descriptor and all the descriptors returned with it
+    pub total_length: u16,
+    /// The number of interfaces supported by this configuration
+    pub num_interfaces: u8,
+    /// The value to pass to `SET_CONFIGURATION` to select this configuration
+    pub configuration_value: u8,
+    /// The index of the string descriptor describing this configuration, or 0 if there is none
+    pub configuration_index: u8,
+    /// The configuration's attributes. Bit 6 is set if the device is self-powered,
+    /// and bit 5 is set if the device supports remote wakeup.
+    pub attributes: u8,
+    /// The maximum power consumption of the device in this configuration, in units of 2mA
+    pub max_power: u8,
+}
+
+impl ConfigurationDescriptor {
+    /// The length of the descriptor in bytes, not including the descriptors which follow it
+    pub const LENGTH: usize = 9;
+
+    /// Parses a [`ConfigurationDescriptor`] from the start of `buf`
+    pub fn parse(buf: &[u8]) -> Result<Self, DescriptorError> {
+        let (d, _) = RawDescriptor::split_from(buf)?;
+        Self::from_raw(d)
+    }
+
+    /// Parses a [`ConfigurationDescriptor`] from a [`RawDescriptor`]
+    fn from_raw(d: RawDescriptor) -> Result<Self, DescriptorError> {
+        d.check(DescriptorType::Configuration, Self::LENGTH)?;
+
+        Ok(Self {
+            total_length: d.read_u16(2),
+            num_interfaces: d.bytes[4],
+            configuration_value: d.bytes[5],
+            configuration_index: d.bytes[6],
+            attributes: d.bytes[7],
+            max_power: d.bytes[8],
+        })
+    }
+
+    /// Whether the device is self-powered in this configuration
+    pub fn self_powered(&self) -> bool {
+        self.attributes & (1 << 6) != 0
+    }
+
+    /// Whether the device supports remote wakeup in this configuration
+    pub fn remote_wakeup(&self) -> bool {
+        self.attributes & (1 << 5) != 0
+    }
+}
+
+/// The _Interface Descriptor_, which describes one interface within a configuration.
+///
+/// This descriptor is defined in section 9.6.5 of the USB2 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    /// The number of this interface within the configuration
+    pub interface_number: u8,
+    /// The alternate setting which this descriptor describes
+    pub alternate_setting: u8,
+    /// The number of endpoints used by this interface, not including endpoint 0
+    pub num_endpoints: u8,
+    /// The class code of the interface
+    pub interface_class: u8,
+    /// The subclass code of the interface, qualified by [`interface_class`]
+    ///
+    /// [`interface_class`]: InterfaceDescriptor::interface_class
+    pub interface_subclass: u8,
+    /// The protocol code of the interface, qualified by [`interface_class`] and [`interface_subclass`]
+    ///
+    /// [`interface_class`]: InterfaceDescriptor::interface_class
+    /// [`interface_subclass`]: InterfaceDescriptor::interface_subclass
+    pub interface_protocol: u8,
+    /// The index of the string descriptor describing this interface, or 0 if there is none
+    pub interface_index: u8,
+}
+
+impl InterfaceDescriptor {
+    /// The length of the descriptor in bytes
+    pub const LENGTH: usize = 9;
+
+    /// Parses an [`InterfaceDescriptor`] from a [`RawDescriptor`]
+    fn from_raw(d: RawDescriptor) -> Result<Self, DescriptorError> {
+        d.check(DescriptorType::Interface, Self::LENGTH)?;
+
+        Ok(Self {
+            interface_number: d.bytes[2],
+            alternate_setting: d.bytes[3],
+            num_endpoints: d.bytes[4],
+            interface_class: d.bytes[5],
+            interface_subclass: d.bytes[6],
+            interface_protocol: d.bytes[7],
+            interface_index: d.bytes[8],
+        })
+    }
+}
+
+/// The direction of data transfer on an endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointDirection {
+    /// Data is sent from the host to the device
+    Out,
+    /// Data is sent from the device to the host
+    In,
+}
+
+/// The type of transfers an endpoint supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointTransferType {
+    /// Control transfers, used for configuration
+    Control,
+    /// Isochronous transfers, which have guaranteed bandwidth but no error correction
+    Isochronous,
+    /// Bulk transfers, used for large amounts of data
+    Bulk,
+    /// Interrupt transfers, which the host polls for at regular intervals
+    Interrupt,
+}
+
+/// The _Endpoint Descriptor_, which describes one endpoint of an interface.
+///
+/// This descriptor is defined in section 9.6.6 of the USB2 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    /// The address of the endpoint. Bits 0-3 are the endpoint number, and bit 7 is the direction.
+    pub endpoint_address: u8,
+    /// The endpoint's attributes. Bits 0-1 are the transfer type.
+    pub attributes: u8,
+    /// The maximum packet size the endpoint can send or receive
+    pub max_packet_size: u16,
+    /// The interval for polling the endpoint. The units depend on the speed of the device and the transfer type.
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    /// The length of the descriptor in bytes
+    pub const LENGTH: usize = 7;
+
+    /// Parses an [`EndpointDescriptor`] from a [`RawDescriptor`]
+    fn from_raw(d: RawDescriptor) -> Result<Self, DescriptorError> {
+        d.check(DescriptorType::Endpoint, Self::LENGTH)?;
+
+        Ok(Self {
+            endpoint_address: d.bytes[2],
+            attributes: d.bytes[3],
+            max_packet_size: d.read_u16(4),
+            interval: d.bytes[6],
+        })
+    }
+
+    /// The endpoint number, in the range `0..=15`
+    pub fn endpoint_number(&self) -> u8 {
+        self.endpoint_address & 0b1111
+    }
+
+    /// The direction of the endpoint
+    pub fn direction(&self) -> EndpointDirection {
+        if self.endpoint_address & (1 << 7) != 0 {
+            EndpointDirection::In
+        } else {
+            EndpointDirection::Out
+        }
+    }
+
+    /// The type of transfers the endpoint supports
+    pub fn transfer_type(&self) -> EndpointTransferType {
+        match self.attributes & 0b11 {
+            0 => EndpointTransferType::Control,
+            1 => EndpointTransferType::Isochronous,
+            2 => EndpointTransferType::Bulk,
+            3 => EndpointTransferType::Interrupt,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The buffer returned by a `GET_DESCRIPTOR(CONFIGURATION)` request,
+/// consisting of a [`ConfigurationDescriptor`] followed by the descriptors of its interfaces and endpoints.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigurationDescriptorSet<'a> {
+    /// The configuration descriptor at the start of the buffer
+    pub configuration: ConfigurationDescriptor,
+    /// The descriptors following the configuration descriptor
+    body: &'a [u8],
+}
+
+impl<'a> ConfigurationDescriptorSet<'a> {
+    /// Parses the configuration descriptor at the start of `buf`.
+    /// The rest of the buffer, up to [`total_length`] bytes, is parsed lazily by [`interfaces`].
+    ///
+    /// [`total_length`]: ConfigurationDescriptor::total_length
+    /// [`interfaces`]: ConfigurationDescriptorSet::interfaces
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DescriptorError> {
+        let (d, _) = RawDescriptor::split_from(buf)?;
+        let configuration = ConfigurationDescriptor::from_raw(d)?;
+
+        let total_length = usize::from(configuration.total_length);
+        if buf.len() < total_length {
+            return Err(DescriptorError::TooShort);
+        }
+
+        Ok(Self {
+            configuration,
+            body: buf.get(d.bytes.len()..total_length).unwrap_or_default(),
+        })
+    }
+
+    /// Gets an iterator over the configuration's interfaces.
+    /// Any descriptors before the first interface descriptor are skipped.
+    pub fn interfaces(&self) -> Interfaces<'a> {
+        Interfaces(Descriptors::new(self.body))
+    }
+}
+
+/// An iterator over the interfaces in a [`ConfigurationDescriptorSet`]
+#[derive(Debug, Clone)]
+pub struct Interfaces<'a>(Descriptors<'a>);
+
+impl<'a> Iterator for Interfaces<'a> {
+    type Item = Result<Interface<'a>, DescriptorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find the next interface descriptor, skipping anything else
+        let descriptor = loop {
+            match self.0.next()? {
+                Ok(d) if d.descriptor_type == DescriptorType::Interface => break d,
+                Ok(_) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        };
+
+        let descriptor = match InterfaceDescriptor::from_raw(descriptor) {
+            Ok(d) => d,
+            Err(e) => return Some(Err(e)),
+        };
+
+        // The interface's body is everything up to the next interface descriptor
+        // A malformed descriptor also ends the body, and will be reported by the next call.
+        let rest = self.0 .0;
+        let mut body_len = 0;
+        for d in Descriptors::new(rest) {
+            match d {
+                Ok(d) if d.descriptor_type != DescriptorType::Interface => {
+                    body_len += d.bytes.len();
+                }
+                _ => break,
+            }
+        }
+
+        self.0 = Descriptors::new(&rest[body_len..]);
+
+        Some(Ok(Interface {
+            descriptor,
+            body: &rest[..body_len],
+        }))
+    }
+}
+
+/// An interface within a configuration, along with the descriptors which follow it
+#[derive(Debug, Clone, Copy)]
+pub struct Interface<'a> {
+    /// The interface's descriptor
+    pub descriptor: InterfaceDescriptor,
+    /// The descriptors between this interface descriptor and the next
+    body: &'a [u8],
+}
+
+impl<'a> Interface<'a> {
+    /// Gets an iterator over the interface's endpoints
+    pub fn endpoints(
+        &self,
+    ) -> impl Iterator<Item = Result<EndpointDescriptor, DescriptorError>> + 'a {
+        Descriptors::new(self.body).filter_map(|d| match d {
+            Ok(d) if d.descriptor_type == DescriptorType::Endpoint => {
+                Some(EndpointDescriptor::from_raw(d))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Gets an iterator over the class-specific and other non-endpoint descriptors of the interface,
+    /// such as HID descriptors
+    pub fn other_descriptors(&self) -> impl Iterator<Item = RawDescriptor<'a>> {
+        Descriptors::new(self.body)
+            .map_while(Result::ok)
+            .filter(|d| d.descriptor_type != DescriptorType::Endpoint)
+    }
+}
+
+/// The _Hub Descriptor_, which describes a USB2 hub and its downstream ports.
+///
+/// This descriptor is defined in section 11.23.2.1 of the USB2 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HubDescriptor {
+    /// The number of downstream facing ports the hub has
+    pub num_ports: u8,
+    /// The hub's characteristics. Bits 5-6 are the TT think time.
+    pub characteristics: u16,
+    /// The time from when a port is powered on until its power is good, in units of 2ms
+    pub power_on_to_power_good: u8,
+    /// The maximum current requirement of the hub controller, in mA
+    pub controller_current: u8,
+}
+
+impl HubDescriptor {
+    /// The minimum length of the descriptor in bytes. The length of the whole descriptor depends on the number of ports.
+    pub const MIN_LENGTH: usize = 7;
+
+    /// Parses a [`HubDescriptor`] from the start of `buf`
+    pub fn parse(buf: &[u8]) -> Result<Self, DescriptorError> {
+        let (d, _) = RawDescriptor::split_from(buf)?;
+        d.check(DescriptorType::Hub, Self::MIN_LENGTH)?;
+
+        Ok(Self {
+            num_ports: d.bytes[2],
+            characteristics: d.read_u16(3),
+            power_on_to_power_good: d.bytes[5],
+            controller_current: d.bytes[6],
+        })
+    }
+
+    /// The time from when a port is powered on until its power is good, in milliseconds
+    pub fn power_good_delay_ms(&self) -> u64 {
+        u64::from(self.power_on_to_power_good) * 2
+    }
+
+    /// The hub's TT think time, in the encoding used by the _TT Think Time_ field of an xHCI slot context
+    pub fn tt_think_time(&self) -> u8 {
+        #[allow(clippy::cast_possible_truncation)] // The value is masked to 2 bits
+        let tt_think_time = (self.characteristics >> 5 & 0b11) as u8;
+        tt_think_time
+    }
+}
+
+/// Tests parsing the hub descriptor of a 4-port hub
+#[test_case]
+fn test_parse_hub_descriptor() {
+    let bytes = [0x09, 0x29, 0x04, 0x69, 0x00, 0x32, 0x64, 0x00, 0xff];
+    let hub = HubDescriptor::parse(&bytes).unwrap();
+
+    assert_eq!(hub.num_ports, 4);
+    assert_eq!(hub.tt_think_time(), 3);
+    assert_eq!(hub.power_good_delay_ms(), 100);
+    assert_eq!(hub.controller_current, 100);
+}
+
+/// Tests parsing the configuration descriptor of a boot-protocol HID keyboard
+#[test_case]
+fn test_parse_hid_keyboard_configuration() {
+    #[rustfmt::skip]
+    const DESCRIPTOR: [u8; 34] = [
+        // Configuration descriptor
+        0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32,
+        // Interface descriptor: HID, boot subclass, keyboard protocol
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00,
+        // HID descriptor
+        0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3f, 0x00,
+        // Endpoint descriptor: 0x81 IN, interrupt, 8 bytes, 10ms interval
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a,
+    ];
+
+    let set = ConfigurationDescriptorSet::parse(&DESCRIPTOR).unwrap();
+    assert_eq!(set.configuration.total_length, 34);
+    assert_eq!(set.configuration.num_interfaces, 1);
+    assert_eq!(set.configuration.configuration_value, 1);
+    assert!(set.configuration.remote_wakeup());
+    assert!(!set.configuration.self_powered());
+
+    let mut interfaces = set.interfaces();
+    let interface = interfaces.next().unwrap().unwrap();
+    assert!(interfaces.next().is_none());
+
+    assert_eq!(interface.descriptor.interface_number, 0);
+    assert_eq!(interface.descriptor.num_endpoints, 1);
+    assert_eq!(interface.descriptor.interface_class, 0x03);
+    assert_eq!(interface.descriptor.interface_subclass, 0x01);
+    assert_eq!(interface.descriptor.interface_protocol, 0x01);
+
+    let mut others = interface.other_descriptors();
+    assert_eq!(others.next().unwrap().descriptor_type, DescriptorType::Hid);
+    assert!(others.next().is_none());
+
+    let mut endpoints = interface.endpoints();
+    let endpoint = endpoints.next().unwrap().unwrap();
+    assert!(endpoints.next().is_none());
+
+    assert_eq!(endpoint.endpoint_number(), 1);
+    assert_eq!(endpoint.direction(), EndpointDirection::In);
+    assert_eq!(endpoint.transfer_type(), EndpointTransferType::Interrupt);
+    assert_eq!(endpoint.max_packet_size, 8);
+    assert_eq!(endpoint.interval, 10);
+}
+
+/// Tests parsing the language ID table and a string descriptor
+#[test_case]
+fn test_parse_string_descriptors() {
+    // English (United States)
+    let language_ids = [0x04, 0x03, 0x09, 0x04];
+    let mut ids = parse_language_ids(&language_ids).unwrap();
+    assert_eq!(ids.next(), Some(0x0409));
+    assert_eq!(ids.next(), None);
+
+    let string = [0x0a, 0x03, b'Q', 0, b'E', 0, b'M', 0, b'U', 0];
+    assert_eq!(parse_string(&string).unwrap(), "QEMU");
+
+    // An unpaired surrogate is replaced rather than causing an error
+    let string = [0x06, 0x03, b'A', 0, 0x00, 0xd8];
+    assert_eq!(parse_string(&string).unwrap(), "A\u{fffd}");
+}
@@ -0,0 +1,187 @@
+//! Types for the USB hub class, which is used to control hubs and their downstream ports.
+//!
+//! The hub class is defined in chapter 11 of the [USB2 specification].
+//!
+//! [USB2 specification]: https://www.usb.org/document-library/usb-20-specification
+
+use super::{
+    descriptors::DescriptorType,
+    requests::{SetupPacket, StandardRequest},
+};
+
+/// The device class code of hubs
+pub const HUB_CLASS: u8 = 0x09;
+
+/// A feature of a hub port, which can be set or cleared with a `SET_FEATURE` or `CLEAR_FEATURE` request.
+///
+/// These values are defined in table 11-17 of the USB2 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::missing_docs_in_private_items)]
+pub enum HubPortFeature {
+    Connection,
+    Enable,
+    Suspend,
+    OverCurrent,
+    Reset,
+    Power,
+    LowSpeed,
+    ConnectionChange,
+    EnableChange,
+    SuspendChange,
+    OverCurrentChange,
+    ResetChange,
+}
+
+impl HubPortFeature {
+    /// Converts a [`HubPortFeature`] into its feature selector value
+    pub const fn to_u16(self) -> u16 {
+        match self {
+            Self::Connection => 0,
+            Self::Enable => 1,
+            Self::Suspend => 2,
+            Self::OverCurrent => 3,
+            Self::Reset => 4,
+            Self::Power => 8,
+            Self::LowSpeed => 9,
+            Self::ConnectionChange => 16,
+            Self::EnableChange => 17,
+            Self::SuspendChange => 18,
+            Self::OverCurrentChange => 19,
+            Self::ResetChange => 20,
+        }
+    }
+}
+
+/// The `wPortStatus` field returned by a hub's `GET_STATUS` request for a port.
+///
+/// This field is defined in section 11.24.2.7.1 of the USB2 specification.
+#[bitfield(u16)]
+pub struct HubPortStatusBits {
+    /// Whether a device is connected to the port
+    pub connection: bool,
+    /// Whether the port is enabled
+    pub enable: bool,
+    /// Whether the port is suspended
+    pub suspend: bool,
+    /// Whether the port is in an over-current condition
+    pub over_current: bool,
+    /// Whether the port is being reset
+    pub reset: bool,
+
+    #[bits(3)]
+    _reserved0: (),
+
+    /// Whether the port is powered
+    pub power: bool,
+    /// Whether the connected device is low-speed
+    pub low_speed: bool,
+    /// Whether the connected device is high-speed
+    pub high_speed: bool,
+    /// Whether the port is in test mode
+    pub test: bool,
+    /// Whether the port indicator is controlled by software
+    pub indicator: bool,
+
+    #[bits(3)]
+    _reserved1: (),
+}
+
+/// The `wPortChange` field returned by a hub's `GET_STATUS` request for a port.
+///
+/// This field is defined in section 11.24.2.7.2 of the USB2 specification.
+#[bitfield(u16)]
+pub struct HubPortChangeBits {
+    /// Whether the [`connection`] status has changed
+    ///
+    /// [`connection`]: HubPortStatusBits::connection
+    pub connection: bool,
+    /// Whether the port has been disabled because of an error
+    pub enable: bool,
+    /// Whether the port has finished resuming
+    pub suspend: bool,
+    /// Whether the [`over_current`] status has changed
+    ///
+    /// [`over_current`]: HubPortStatusBits::over_current
+    pub over_current: bool,
+    /// Whether the port has finished being reset
+    pub reset: bool,
+
+    #[bits(11)]
+    _reserved: (),
+}
+
+/// The status of a hub port, returned by a `GET_STATUS` request for the port
+#[derive(Debug, Clone, Copy)]
+pub struct HubPortStatus {
+    /// The current state of the port
+    pub status: HubPortStatusBits,
+    /// Which parts of the port's state have changed
+    pub change: HubPortChangeBits,
+}
+
+impl HubPortStatus {
+    /// The number of bytes returned by a `GET_STATUS` request for a port
+    pub const LENGTH: u16 = 4;
+
+    /// Parses a [`HubPortStatus`] from the data returned by a `GET_STATUS` request.
+    /// Returns [`None`] if the data is too short.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let [status_low, status_high, change_low, change_high, ..] = *buf else {
+            return None;
+        };
+
+        Some(Self {
+            status: u16::from_le_bytes([status_low, status_high]).into(),
+            change: u16::from_le_bytes([change_low, change_high]).into(),
+        })
+    }
+}
+
+/// Constructors for hub class requests, defined in section 11.24.2 of the USB2 specification
+impl SetupPacket {
+    /// Constructs a `GET_DESCRIPTOR` request for a hub's [`HubDescriptor`]
+    ///
+    /// [`HubDescriptor`]: super::descriptors::HubDescriptor
+    pub fn get_hub_descriptor(length: u16) -> Self {
+        Self {
+            request_type: Self::DEVICE_TO_HOST_CLASS_DEVICE,
+            request: StandardRequest::GetDescriptor.to_byte(),
+            value: u16::from(DescriptorType::Hub.to_byte()) << 8,
+            index: 0,
+            length,
+        }
+    }
+
+    /// Constructs a `GET_STATUS` request for the given hub port
+    pub fn get_port_status(port: u8) -> Self {
+        Self {
+            request_type: Self::DEVICE_TO_HOST_CLASS_OTHER,
+            request: StandardRequest::GetStatus.to_byte(),
+            value: 0,
+            index: port.into(),
+            length: HubPortStatus::LENGTH,
+        }
+    }
+
+    /// Constructs a `SET_FEATURE` request which sets the given feature of a hub port
+    pub fn set_port_feature(port: u8, feature: HubPortFeature) -> Self {
+        Self {
+            request_type: Self::HOST_TO_DEVICE_CLASS_OTHER,
+            request: StandardRequest::SetFeature.to_byte(),
+            value: feature.to_u16(),
+            index: port.into(),
+            length: 0,
+        }
+    }
+
+    /// Constructs a `CLEAR_FEATURE` request which clears the given feature of a hub port
+    pub fn clear_port_feature(port: u8, feature: HubPortFeature) -> Self {
+        Self {
+            request_type: Self::HOST_TO_DEVICE_CLASS_OTHER,
+            request: StandardRequest::ClearFeature.to_byte(),
+            value: feature.to_u16(),
+            index: port.into(),
+            length: 0,
+        }
+    }
+}
@@ -1,7 +1,18 @@
 //! Drivers for USB controllers
 
-use core::fmt::Debug;
+use core::fmt::{Debug, Display, Write};
 
+use alloc::{string::String, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{pci::devices::PciFunction, println};
+
+use self::descriptors::DeviceDescriptor;
+
+pub mod descriptors;
+pub mod hub;
+pub mod requests;
 pub mod xhci;
 
 /// A USB route string. This uniquely identifies a connected USB device on a root port by which port it is plugged into on a hub,
@@ -11,9 +22,12 @@ pub mod xhci;
 ///
 /// [USB3 specification]: https://www.usb.org/document-library/usb-32-revision-11-june-2022
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-struct RouteString(u32);
+pub struct RouteString(u32);
 
 impl RouteString {
+    /// The route string of a device connected directly to a root hub port
+    pub const ROOT: Self = Self(0);
+
     /// Gets the offset at the given `tier`. The `tier` is 1-based and doesn't include the root hub.
     /// For example, a `tier` value of 1 will return the port number on the hub directly connected to the root hub.
     ///
@@ -34,6 +48,39 @@ impl RouteString {
         let copy = *self;
         (1..=5).map(move |i| copy.offset_at_tier(i))
     }
+
+    /// Gets the number of hubs between the root hub and the device. This is 0 for a device connected to a root hub port.
+    pub fn depth(&self) -> u8 {
+        (1..=5)
+            .take_while(|&tier| self.offset_at_tier(tier) != 0)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Whether the device with this route string is `prefix` itself, or is connected through the hub with route string `prefix`.
+    /// Both route strings must be on the same root hub port for this to be meaningful.
+    pub fn starts_with(&self, prefix: RouteString) -> bool {
+        (1..=prefix.depth()).all(|tier| self.offset_at_tier(tier) == prefix.offset_at_tier(tier))
+    }
+
+    /// Gets the route string of a device connected to `port` on the hub with this route string.
+    ///
+    /// Ports numbered above 15 are given the offset 15, as specified by the USB3 specification.
+    /// Returns [`None`] if the hub is already at the maximum depth of 5 tiers.
+    ///
+    /// # Panics
+    /// If `port == 0`, as hub ports are 1-based
+    pub fn child(&self, port: u8) -> Option<Self> {
+        assert!(port != 0, "port is 1-based");
+
+        let depth = self.depth();
+        if depth == 5 {
+            return None;
+        }
+
+        let offset = u32::from(port.min(15));
+        Some(Self(self.0 | offset << (4 * depth)))
+    }
 }
 
 impl Debug for RouteString {
@@ -56,3 +103,191 @@ impl RouteString {
         self.0
     }
 }
+
+/// The speed a USB device is operating at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbSpeed {
+    /// Low-speed (1.5 Mb/s)
+    Low,
+    /// Full-speed (12 Mb/s)
+    Full,
+    /// High-speed (480 Mb/s)
+    High,
+    /// SuperSpeed (5 Gb/s)
+    Super,
+    /// A speed which isn't recognised, with the controller's ID for it
+    Other(u8),
+}
+
+impl Display for UsbSpeed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Low => f.pad("1.5M"),
+            Self::Full => f.pad("12M"),
+            Self::High => f.pad("480M"),
+            Self::Super => f.pad("5G"),
+            Self::Other(id) => write!(f, "speed {id}"),
+        }
+    }
+}
+
+/// A summary of an enumerated USB device, which is kept so that the device can be listed by [`usbls`]
+/// without access to the controller it is attached to.
+#[derive(Debug, Clone)]
+pub struct UsbDeviceInfo {
+    /// The PCI function of the controller the device is attached to
+    pub controller: PciFunction,
+    /// The ID of the device slot the device is using on the controller
+    pub slot_id: u8,
+    /// The root hub port which the device is connected through
+    pub root_port: u8,
+    /// The route from the root hub port to the device through any hubs
+    pub route_string: RouteString,
+    /// The speed the device is operating at
+    pub speed: UsbSpeed,
+    /// The device's device descriptor
+    pub descriptor: DeviceDescriptor,
+    /// The device's manufacturer string, if it has one
+    pub manufacturer: Option<String>,
+    /// The device's product string, if it has one
+    pub product: Option<String>,
+}
+
+/// The USB devices which have been enumerated on any controller
+static USB_DEVICES: Mutex<Vec<UsbDeviceInfo>> = Mutex::new(Vec::new());
+
+/// Adds a device to the list printed by [`usbls`], replacing any existing device in the same slot of the same controller
+pub fn register_device(info: UsbDeviceInfo) {
+    // Controller tasks may run in interrupt handlers, so disable interrupts to avoid deadlock
+    without_interrupts(|| {
+        let mut devices = USB_DEVICES.lock();
+        devices.retain(|d| !(d.controller == info.controller && d.slot_id == info.slot_id));
+        devices.push(info);
+    });
+}
+
+/// Removes the device in the given slot of the given controller from the list printed by [`usbls`]
+pub fn unregister_device(controller: PciFunction, slot_id: u8) {
+    without_interrupts(|| {
+        USB_DEVICES
+            .lock()
+            .retain(|d| !(d.controller == controller && d.slot_id == slot_id));
+    });
+}
+
+/// Lists the enumerated USB devices
+pub fn usbls(_: &[&str]) {
+    let devices = without_interrupts(|| USB_DEVICES.lock().clone());
+
+    if devices.is_empty() {
+        println!("No USB devices");
+        return;
+    }
+
+    for device in devices {
+        // The topology is the root port followed by the hub port at each tier, e.g. `3.1.4`
+        let mut path = String::new();
+        write!(path, "{}", device.root_port).unwrap();
+        for offset in device
+            .route_string
+            .offsets()
+            .take_while(|&offset| offset != 0)
+        {
+            write!(path, ".{offset}").unwrap();
+        }
+
+        let descriptor = device.descriptor;
+
+        println!(
+            "{}  port {path:8}  slot {:3}  {:>4}  {:04x}:{:04x}  class {:02x}:{:02x}:{:02x}  {} {}",
+            device.controller,
+            device.slot_id,
+            device.speed,
+            descriptor.vendor_id,
+            descriptor.product_id,
+            descriptor.device_class,
+            descriptor.device_subclass,
+            descriptor.device_protocol,
+            device.manufacturer.as_deref().unwrap_or("-"),
+            device.product.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+/// Tests that [`RouteString::child`] puts each hub's port number in the nibble for the next tier
+#[test_case]
+fn test_route_string_child() {
+    let root = RouteString::ROOT;
+    assert_eq!(root.depth(), 0);
+
+    let tier_1 = root.child(3).unwrap();
+    let tier_2 = tier_1.child(20).unwrap();
+    assert_eq!(tier_2.depth(), 2);
+    assert_eq!(tier_2.offset_at_tier(1), 3);
+    assert_eq!(tier_2.offset_at_tier(2), 15);
+    assert_eq!(tier_2.offset_at_tier(3), 0);
+
+    let tier_5 = (0..3).fold(tier_2, |route, _| route.child(1).unwrap());
+    // Each tier is a nibble, starting from the least significant
+    assert_eq!(tier_5.into_bits(), 0x111f3);
+    assert_eq!(tier_5.child(1), None);
+}
+
+/// Tests that devices which are repeatedly registered and unregistered, as they would be when plugged and unplugged,
+/// don't accumulate in the device list
+#[test_case]
+fn test_unregister_device_frees_slots() {
+    use crate::pci::devices::PciDevice;
+
+    // A function which no real controller uses, so that the test doesn't interfere with real devices
+    let controller = PciDevice::new(0, 0xff, 31).unwrap().function(7).unwrap();
+    let mut descriptor_bytes = [0; DeviceDescriptor::LENGTH];
+    descriptor_bytes[0] = 18;
+    descriptor_bytes[1] = 1;
+    let descriptor = DeviceDescriptor::parse(&descriptor_bytes).unwrap();
+
+    let count = || {
+        without_interrupts(|| {
+            USB_DEVICES
+                .lock()
+                .iter()
+                .filter(|d| d.controller == controller)
+                .count()
+        })
+    };
+
+    for _ in 0..4 {
+        for slot_id in 1..=8 {
+            register_device(UsbDeviceInfo {
+                controller,
+                slot_id,
+                root_port: 1,
+                route_string: RouteString::ROOT,
+                speed: UsbSpeed::High,
+                descriptor,
+                manufacturer: None,
+                product: None,
+            });
+        }
+        assert_eq!(count(), 8);
+
+        for slot_id in 1..=8 {
+            unregister_device(controller, slot_id);
+        }
+        assert_eq!(count(), 0);
+    }
+}
+
+/// Tests that [`RouteString::starts_with`] matches a hub's own route string and those of devices behind it
+#[test_case]
+fn test_route_string_starts_with() {
+    let hub = RouteString::ROOT.child(2).unwrap();
+    let device = hub.child(3).unwrap();
+    let other = RouteString::ROOT.child(3).unwrap();
+
+    assert!(device.starts_with(hub));
+    assert!(device.starts_with(device));
+    assert!(device.starts_with(RouteString::ROOT));
+    assert!(!hub.starts_with(device));
+    assert!(!device.starts_with(other));
+}
@@ -0,0 +1,141 @@
+//! The [`SetupPacket`] type, which is sent to a device at the start of a control transfer to make a request.
+//!
+//! The standard requests are defined in chapter 9.4 of the [USB2 specification].
+//!
+//! [USB2 specification]: https://www.usb.org/document-library/usb-20-specification
+
+use super::descriptors::DescriptorType;
+
+/// The `bRequest` codes of the standard device requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::missing_docs_in_private_items)]
+pub enum StandardRequest {
+    GetStatus,
+    ClearFeature,
+    SetFeature,
+    SetAddress,
+    GetDescriptor,
+    SetDescriptor,
+    GetConfiguration,
+    SetConfiguration,
+    GetInterface,
+    SetInterface,
+    SynchFrame,
+}
+
+impl StandardRequest {
+    /// Converts a [`StandardRequest`] into its byte representation
+    pub const fn to_byte(self) -> u8 {
+        match self {
+            Self::GetStatus => 0,
+            Self::ClearFeature => 1,
+            Self::SetFeature => 3,
+            Self::SetAddress => 5,
+            Self::GetDescriptor => 6,
+            Self::SetDescriptor => 7,
+            Self::GetConfiguration => 8,
+            Self::SetConfiguration => 9,
+            Self::GetInterface => 10,
+            Self::SetInterface => 11,
+            Self::SynchFrame => 12,
+        }
+    }
+}
+
+/// An 8-byte USB _Setup Packet_, which starts every control transfer.
+///
+/// This data structure is defined in section 9.3 of the USB2 specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    /// The characteristics of the request. Bit 7 is the direction (set for device-to-host),
+    /// bits 5-6 are the type (standard, class, or vendor), and bits 0-4 are the recipient.
+    pub request_type: u8,
+    /// The specific request
+    pub request: u8,
+    /// A request-specific parameter
+    pub value: u16,
+    /// A request-specific parameter, often an interface or endpoint number
+    pub index: u16,
+    /// The number of bytes to transfer in the data stage, if there is one
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// The value of [`request_type`] for a standard request from the device to the host, addressed to the device
+    ///
+    /// [`request_type`]: SetupPacket::request_type
+    pub const DEVICE_TO_HOST_STANDARD_DEVICE: u8 = 0b1000_0000;
+    /// The value of [`request_type`] for a standard request from the host to the device, addressed to the device
+    ///
+    /// [`request_type`]: SetupPacket::request_type
+    pub const HOST_TO_DEVICE_STANDARD_DEVICE: u8 = 0b0000_0000;
+    /// The value of [`request_type`] for a class-specific request from the device to the host, addressed to the device
+    ///
+    /// [`request_type`]: SetupPacket::request_type
+    pub const DEVICE_TO_HOST_CLASS_DEVICE: u8 = 0b1010_0000;
+    /// The value of [`request_type`] for a class-specific request from the host to the device, addressed to something
+    /// other than the device, interface, or an endpoint (e.g. a hub port)
+    ///
+    /// [`request_type`]: SetupPacket::request_type
+    pub const HOST_TO_DEVICE_CLASS_OTHER: u8 = 0b0010_0011;
+    /// The value of [`request_type`] for a class-specific request from the device to the host, addressed to something
+    /// other than the device, interface, or an endpoint (e.g. a hub port)
+    ///
+    /// [`request_type`]: SetupPacket::request_type
+    pub const DEVICE_TO_HOST_CLASS_OTHER: u8 = 0b1010_0011;
+
+    /// Constructs a `GET_DESCRIPTOR` request for the descriptor of the given type and index.
+    ///
+    /// `language_id` is only used for string descriptors, and should be 0 otherwise.
+    pub fn get_descriptor(
+        descriptor_type: DescriptorType,
+        index: u8,
+        language_id: u16,
+        length: u16,
+    ) -> Self {
+        Self {
+            request_type: Self::DEVICE_TO_HOST_STANDARD_DEVICE,
+            request: StandardRequest::GetDescriptor.to_byte(),
+            value: u16::from(descriptor_type.to_byte()) << 8 | u16::from(index),
+            index: language_id,
+            length,
+        }
+    }
+
+    /// Constructs a `SET_CONFIGURATION` request, which selects the configuration with the given
+    /// [`configuration_value`], or puts the device back in the addressed state if `value` is 0.
+    ///
+    /// [`configuration_value`]: super::descriptors::ConfigurationDescriptor::configuration_value
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: Self::HOST_TO_DEVICE_STANDARD_DEVICE,
+            request: StandardRequest::SetConfiguration.to_byte(),
+            value: value.into(),
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Whether the data stage of the request transfers data from the device to the host
+    pub fn is_device_to_host(&self) -> bool {
+        self.request_type & (1 << 7) != 0
+    }
+
+    /// Converts the packet to the bytes which are sent to the device
+    pub fn to_bytes(self) -> [u8; 8] {
+        let [value_low, value_high] = self.value.to_le_bytes();
+        let [index_low, index_high] = self.index.to_le_bytes();
+        let [length_low, length_high] = self.length.to_le_bytes();
+
+        [
+            self.request_type,
+            self.request,
+            value_low,
+            value_high,
+            index_low,
+            index_high,
+            length_low,
+            length_high,
+        ]
+    }
+}
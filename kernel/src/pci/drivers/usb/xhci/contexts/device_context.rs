@@ -34,7 +34,7 @@ impl Debug for OwnedDeviceContext {
 }
 
 impl OwnedDeviceContext {
-    /// Allocates a new device context data structure.
+    /// Allocates a new zeroed device context data structure.
     ///
     /// # Parameters
     /// * `page_size` is the page size supported by the controller, from the controller's operational registers.
@@ -47,12 +47,13 @@ impl OwnedDeviceContext {
     /// [`context_size`]: super::super::registers::capability::CapabilityParameters1::context_size
     /// [`CapabilityParameters1`]: super::super::registers::capability::CapabilityParameters1
     pub fn new(page_size: SupportedPageSize, context_size: ContextSize) -> Self {
-        if page_size.page_size() != 0x1000 {
-            todo!("Non-4k pages");
-        }
+        // The structure is allocated in a 4KiB page, so it is aligned to 4KiB and doesn't cross a 4KiB boundary.
+        // All of the controller's page sizes are multiples of 4KiB, so it doesn't cross one of the controller's pages either.
+        assert_eq!(page_size.page_size() % 0x1000, 0);
 
+        // The spec requires output device contexts to be zeroed before the first Address Device command
         Self {
-            page: PageBox::new(),
+            page: PageBox::new_zeroed(),
             context_size,
         }
     }
@@ -147,7 +148,7 @@ impl<'a, M: Mutability> DeviceContextRef<'a, M> {
     pub fn get_ep_context_out(&self, i: usize) -> Option<EndpointContext> {
         assert_ne!(i, 0, "Slot 0 does not have an OUT EP context");
 
-        if i >= self.out_len() {
+        if i > self.out_len() {
             return None;
         }
 
@@ -168,7 +169,7 @@ impl<'a, M: Mutability> DeviceContextRef<'a, M> {
     pub fn get_ep_context_in(&self, i: usize) -> Option<EndpointContext> {
         assert_ne!(i, 0, "Slot 0 does not have an IP EP context");
 
-        if i >= self.in_len() {
+        if i > self.in_len() {
             return None;
         }
 
@@ -222,7 +223,7 @@ impl<'a> DeviceContextRef<'a, Mutable> {
     /// * The new value must be valid. The caller is responsible for the behaviour of the controller in response to this [`EndpointContext`].
     pub unsafe fn write_ep_context_in(&mut self, i: usize, context: EndpointContext) {
         assert_ne!(i, 0, "Slot 0 does not have an IP EP context");
-        assert!(i < self.in_len(), "Index outside of array");
+        assert!(i <= self.in_len(), "Index outside of array");
 
         // SAFETY: The array is laid out alternating OUT and IN contexts
         // so the offset from the beginning is `stride * (2 * i + 1)`
@@ -241,18 +242,24 @@ impl<'a> DeviceContextRef<'a, Mutable> {
     /// # Safety
     /// * The OS must be allowed to write to the endpoint context (TODO: when is this true?)
     pub unsafe fn reset(&mut self) {
-        /// How many `usize`s fit in a device context
-        const U64_IN_DEVICE_CONTEXT: usize = 0x400 / 8;
+        /// The number of context structures in a device context: the slot context and 31 endpoint contexts
+        const CONTEXTS_IN_DEVICE_CONTEXT: usize = 32;
 
-        // SAFETY: The OS allowed to write to this context
-        unsafe {
-            self.ptr
-                .cast::<[u64; U64_IN_DEVICE_CONTEXT]>()
-                .write_volatile([0; U64_IN_DEVICE_CONTEXT]);
+        // The size of the device context depends on the size of each context structure
+        let len = CONTEXTS_IN_DEVICE_CONTEXT * self.context_size.bytes() / 8;
+        let ptr = self.ptr.cast::<u64>();
+
+        for i in 0..len {
+            // SAFETY: The OS allowed to write to this context, and `i` is within the device context
+            unsafe { ptr.add(i).write_volatile(0) };
         }
     }
 }
 
+/// The maximum number of pairs of endpoint contexts printed by the [`Debug`] impl of [`DeviceContextRef`],
+/// as each pair takes up many lines
+const MAX_DEBUG_ENDPOINT_CONTEXTS: usize = 4;
+
 impl<'a, M: Mutability> Debug for DeviceContextRef<'a, M> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("DeviceContext")
@@ -273,6 +280,7 @@ impl<'a, M: Mutability> Debug for DeviceContextRef<'a, M> {
 
                     Some((context_out, context_in))
                 }))
+                .with_max_items(MAX_DEBUG_ENDPOINT_CONTEXTS)
             })
             .finish()
     }
@@ -42,9 +42,9 @@ impl InputContext {
     /// [`context_size`]: super::super::registers::capability::CapabilityParameters1::context_size
     /// [`CapabilityParameters1`]: super::super::registers::capability::CapabilityParameters1
     pub fn new_zeroed(page_size: SupportedPageSize, context_size: ContextSize) -> Self {
-        if page_size.page_size() != 0x1000 {
-            todo!("Non-4k pages");
-        }
+        // The structure is allocated in a 4KiB page, so it is aligned to 4KiB and doesn't cross a 4KiB boundary.
+        // All of the controller's page sizes are multiples of 4KiB, so it doesn't cross one of the controller's pages either.
+        assert_eq!(page_size.page_size() % 0x1000, 0);
 
         Self {
             page: PageBox::new_zeroed(),
@@ -306,3 +306,50 @@ impl<'a, M: Mutability> Debug for InputControlContext<'a, M> {
             .finish()
     }
 }
+
+/// Tests that the contexts in an [`InputContext`] are at the right offsets for both sizes of context structure
+#[test_case]
+fn test_input_context_strides() {
+    use super::{endpoint_context::EndpointContext, slot_context::SlotContext};
+
+    for context_size in [ContextSize::Small, ContextSize::Large] {
+        let stride = context_size.bytes();
+        let mut input_context = InputContext {
+            page: PageBox::new_zeroed(),
+            context_size,
+        };
+
+        // SAFETY: The input context hasn't been passed to a controller
+        unsafe {
+            input_context
+                .input_control_context_mut()
+                .write_add_context_flags(0b11);
+
+            let mut device_context = input_context.device_context_mut();
+            device_context.set_slot_context(SlotContext::new().with_context_entries(1));
+            device_context.set_ep_context_0(EndpointContext::new().with_max_packet_size(64));
+        }
+
+        let read_dword = |offset: usize| {
+            // SAFETY: The offset is within the input context's page
+            unsafe {
+                input_context
+                    .page
+                    .as_ptr::<u32>()
+                    .byte_add(offset)
+                    .read_volatile()
+            }
+        };
+
+        // The add context flags are the second dword of the input control context
+        assert_eq!(read_dword(4), 0b11);
+        // The slot context is the second context structure, and `context_entries` is the top 5 bits of its first dword
+        assert_eq!(read_dword(stride) >> 27, 1);
+        // Endpoint context 0 is the third context structure, and `max_packet_size` is the top 16 bits of its second dword
+        assert_eq!(read_dword(stride * 2 + 4) >> 16, 64);
+
+        let device_context = input_context.device_context();
+        assert_eq!(device_context.get_slot_context().context_entries(), 1);
+        assert_eq!(device_context.get_ep_context_0().max_packet_size(), 64);
+    }
+}
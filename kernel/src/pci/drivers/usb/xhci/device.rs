@@ -0,0 +1,257 @@
+//! The [`Device`] type, which stores the controller's state for a USB device which has been assigned a slot
+
+use alloc::{collections::BTreeMap, string::String};
+use x86_64::PhysAddr;
+
+use crate::pci::drivers::usb::{descriptors::DeviceDescriptor, RouteString, UsbSpeed};
+
+use super::{
+    contexts::{
+        endpoint_context::{EndpointContext, EndpointType},
+        input_context::InputContext,
+        slot_context::SlotContext,
+        ContextSize,
+    },
+    registers::operational::SupportedPageSize,
+    trb::TransferTrbRing,
+};
+
+/// The value of the port speed field for a full-speed (12 Mb/s) device
+pub const PORT_SPEED_FULL: u8 = 1;
+/// The value of the port speed field for a low-speed (1.5 Mb/s) device
+pub const PORT_SPEED_LOW: u8 = 2;
+/// The value of the port speed field for a high-speed (480 Mb/s) device
+pub const PORT_SPEED_HIGH: u8 = 3;
+/// The value of the port speed field for a SuperSpeed (5 Gb/s) device
+pub const PORT_SPEED_SUPER: u8 = 4;
+
+/// The hub a low- or full-speed device is connected to, if that hub is high-speed.
+/// The hub's _Transaction Translator_ is used to talk to the device, so the controller needs to know which hub and port it is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionTranslator {
+    /// The slot ID of the high-speed hub
+    pub hub_slot_id: u8,
+    /// The port on the hub which the device is connected to
+    pub port: u8,
+}
+
+/// A USB device which has been assigned a device slot by the controller
+#[derive(Debug)]
+pub struct Device {
+    /// The ID of the device slot the device is using
+    slot_id: u8,
+    /// The root hub port which the device is connected through
+    root_port: u8,
+    /// The route from the root hub port to the device through any hubs
+    route_string: RouteString,
+    /// The speed of the device, as the value of the port's [`port_speed`] field
+    ///
+    /// [`port_speed`]: super::registers::operational::port_registers::StatusAndControl::port_speed
+    port_speed: u8,
+
+    /// The input context used to address and configure the device
+    input_context: InputContext,
+    /// The high-speed hub whose transaction translator is used to talk to the device, if any
+    transaction_translator: Option<TransactionTranslator>,
+
+    /// The transfer ring of the device's default control endpoint
+    control_ring: TransferTrbRing,
+    /// The transfer rings of the device's other endpoints which have been configured, indexed by _Device Context Index_
+    endpoint_rings: BTreeMap<u8, TransferTrbRing>,
+
+    /// The device's device descriptor, once it has been read
+    pub descriptor: Option<DeviceDescriptor>,
+    /// The language ID used to read the device's string descriptors, once it has been read
+    pub language_id: Option<u16>,
+    /// The device's manufacturer string, if it has one
+    pub manufacturer: Option<String>,
+    /// The device's product string, if it has one
+    pub product: Option<String>,
+    /// The device's serial number string, if it has one
+    pub serial_number: Option<String>,
+}
+
+impl Device {
+    /// Constructs a new [`Device`] with an input context set up for an _Address Device_ command.
+    /// The input context has the slot context and the default control endpoint's context added.
+    ///
+    /// # Parameters
+    /// * `transaction_translator` is the high-speed hub the device is connected through, if the device is low- or full-speed
+    /// * `page_size` and `context_size` are the controller's page size and context size, as passed to [`InputContext::new_zeroed`]
+    pub fn new(
+        slot_id: u8,
+        root_port: u8,
+        route_string: RouteString,
+        port_speed: u8,
+        transaction_translator: Option<TransactionTranslator>,
+        page_size: SupportedPageSize,
+        context_size: ContextSize,
+    ) -> Self {
+        let mut input_context = InputContext::new_zeroed(page_size, context_size);
+        let control_ring = TransferTrbRing::new();
+
+        let mut slot_context = SlotContext::new()
+            .with_route_string(route_string)
+            .with_root_hub_port_number(root_port)
+            .with_context_entries(1);
+
+        if let Some(tt) = transaction_translator {
+            slot_context = slot_context
+                .with_parent_hub_slot_id(tt.hub_slot_id)
+                .with_parent_port_number(tt.port);
+        }
+
+        let ep_context_0 = EndpointContext::new()
+            .with_endpoint_type(EndpointType::Control)
+            .with_max_packet_size(default_max_packet_size(port_speed))
+            .with_error_count(3)
+            .with_tr_dequeue_pointer(control_ring.ring_start_addr())
+            .with_dequeue_cycle_state(true);
+
+        // SAFETY: The input context has not been passed to the controller yet
+        unsafe {
+            let mut input_control_context = input_context.input_control_context_mut();
+            // Add the slot context and the default control endpoint's context
+            input_control_context.write_add_context_flag(0, true);
+            input_control_context.write_add_context_flag(1, true);
+        }
+
+        // SAFETY: The input context has not been passed to the controller yet
+        unsafe {
+            let mut device_context = input_context.device_context_mut();
+            device_context.set_slot_context(slot_context);
+            device_context.set_ep_context_0(ep_context_0);
+        }
+
+        Self {
+            slot_id,
+            root_port,
+            route_string,
+            port_speed,
+            transaction_translator,
+            input_context,
+            control_ring,
+            endpoint_rings: BTreeMap::new(),
+            descriptor: None,
+            language_id: None,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+        }
+    }
+
+    /// The ID of the device slot the device is using
+    pub fn slot_id(&self) -> u8 {
+        self.slot_id
+    }
+
+    /// The root hub port which the device is connected through
+    pub fn root_port(&self) -> u8 {
+        self.root_port
+    }
+
+    /// The route from the root hub port to the device through any hubs
+    pub fn route_string(&self) -> RouteString {
+        self.route_string
+    }
+
+    /// The speed of the device, as the value of the port's [`port_speed`] field
+    ///
+    /// [`port_speed`]: super::registers::operational::port_registers::StatusAndControl::port_speed
+    pub fn port_speed(&self) -> u8 {
+        self.port_speed
+    }
+
+    /// The high-speed hub whose transaction translator is used to talk to the device, if any
+    pub fn transaction_translator(&self) -> Option<TransactionTranslator> {
+        self.transaction_translator
+    }
+
+    /// The speed of the device as a [`UsbSpeed`]
+    pub fn usb_speed(&self) -> UsbSpeed {
+        match self.port_speed {
+            PORT_SPEED_LOW => UsbSpeed::Low,
+            PORT_SPEED_FULL => UsbSpeed::Full,
+            PORT_SPEED_HIGH => UsbSpeed::High,
+            PORT_SPEED_SUPER => UsbSpeed::Super,
+            other => UsbSpeed::Other(other),
+        }
+    }
+
+    /// The device's input context
+    pub fn input_context(&self) -> &InputContext {
+        &self.input_context
+    }
+
+    /// The device's input context
+    pub fn input_context_mut(&mut self) -> &mut InputContext {
+        &mut self.input_context
+    }
+
+    /// The transfer ring of the device's default control endpoint
+    pub fn control_ring_mut(&mut self) -> &mut TransferTrbRing {
+        &mut self.control_ring
+    }
+
+    /// The transfer ring of the endpoint with the given _Device Context Index_, if it has been configured
+    pub fn endpoint_ring_mut(&mut self, endpoint_id: u8) -> Option<&mut TransferTrbRing> {
+        self.endpoint_rings.get_mut(&endpoint_id)
+    }
+
+    /// Allocates a transfer ring for the endpoint with the given _Device Context Index_, replacing any existing ring,
+    /// and returns the address of the start of the ring to put in the endpoint's context.
+    pub fn add_endpoint_ring(&mut self, endpoint_id: u8) -> PhysAddr {
+        assert!(
+            (2..32).contains(&endpoint_id),
+            "Invalid endpoint ID {endpoint_id}"
+        );
+
+        let ring = TransferTrbRing::new();
+        let addr = ring.ring_start_addr();
+        self.endpoint_rings.insert(endpoint_id, ring);
+
+        addr
+    }
+
+    /// Frees the transfer rings of all the device's endpoints except the default control endpoint
+    ///
+    /// # Safety
+    /// The endpoints must have been deconfigured, so that the controller no longer accesses their rings
+    pub unsafe fn clear_endpoint_rings(&mut self) {
+        self.endpoint_rings.clear();
+    }
+}
+
+/// Converts the `bInterval` field of an interrupt endpoint's descriptor into the value of the [`interval`] field of its
+/// endpoint context, which is the exponent of the polling period in 125µs units.
+///
+/// For low- and full-speed devices `b_interval` is in milliseconds, and the result is rounded down to a power of 2.
+/// For high-speed and SuperSpeed devices, `b_interval` is already an exponent.
+///
+/// See the spec section [6.2.3.6] for more info.
+///
+/// [`interval`]: super::contexts::endpoint_context::EndpointContext::interval
+/// [6.2.3.6]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A460%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C694%2C0%5D
+pub fn interrupt_interval(port_speed: u8, b_interval: u8) -> u8 {
+    match port_speed {
+        PORT_SPEED_LOW | PORT_SPEED_FULL => {
+            // Convert from 1ms frames to 125µs microframes, then take log2
+            let microframes = u16::from(b_interval.max(1)) * 8;
+            #[allow(clippy::cast_possible_truncation)] // log2 of a u16 is at most 15
+            let exponent = microframes.ilog2() as u8;
+            exponent.clamp(3, 10)
+        }
+        _ => b_interval.clamp(1, 16) - 1,
+    }
+}
+
+/// Gets the max packet size of the default control endpoint for a device of the given speed.
+/// For full-speed devices this is only a guess, which should be corrected once the first 8 bytes
+/// of the device descriptor have been read.
+fn default_max_packet_size(port_speed: u8) -> u16 {
+    match port_speed {
+        PORT_SPEED_LOW | PORT_SPEED_FULL => 8,
+        PORT_SPEED_HIGH => 64,
+        _ => 512,
+    }
+}
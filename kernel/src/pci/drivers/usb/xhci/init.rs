@@ -6,15 +6,18 @@ use crate::{
     global_state::KERNEL_STATE,
     pci::{
         bar::Bar,
+        capability_registers::PowerState,
         classcodes::{ClassCode, SerialBusControllerType, USBControllerType},
         drivers::usb::xhci::registers::capability::extended::ExtendedCapabilityRegisters,
+        power::PowerStateError,
         registers::{HeaderType, PciGeneralDeviceHeader},
         PciMappedFunction,
     },
+    scheduler::yield_now,
 };
 
-use alloc::boxed::Box;
-use log::debug;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use log::{debug, warn};
 use x86_64::VirtAddr;
 
 use super::{
@@ -39,6 +42,10 @@ impl XhciController {
     ///
     /// [4.2]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A87%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C374%2C0%5D
     pub async unsafe fn init(mut function: PciMappedFunction) {
+        // The controller's registers may not respond until it is in D0,
+        // and leaving D3 can reset its BARs, so this must happen before they are read
+        power_on(&mut function).await;
+
         // SAFETY: This function is only called once per controller
         let (
             capability_registers,
@@ -49,8 +56,13 @@ impl XhciController {
         ) = unsafe { init_mmio(&function) };
 
         // SAFETY: The controller hasn't been set up yet so nothing is relying on the state being preserved
-        unsafe {
-            reset_and_wait(&mut operational_registers).await;
+        let reset = unsafe { reset_and_wait(&mut operational_registers).await };
+        if let Err(e) = reset {
+            warn!(
+                "XHCI controller {} didn't finish resetting, not initialising it: {e:?}",
+                function.function
+            );
+            return;
         }
 
         enable_all_ports(&capability_registers, &mut operational_registers);
@@ -100,6 +112,9 @@ impl XhciController {
             command_ring,
             interrupters,
             doorbell_registers,
+            devices: BTreeMap::new(),
+            new_hubs: Vec::new(),
+            secondary_interrupters: Vec::new(),
         };
 
         // Make sure `host_controller_halted` is set before starting controller
@@ -128,7 +143,7 @@ impl XhciController {
                 break;
             }
 
-            futures::pending!();
+            yield_now().await;
         }
 
         controller
@@ -136,13 +151,28 @@ impl XhciController {
             .host_controller_doorbell()
             .ring();
 
+        // Check that the command and event rings work before relying on them
+        controller
+            .test_noop()
+            .await
+            .expect("xHCI NoOp self-test failed");
+        debug!("xHCI NoOp self-test passed");
+
         controller.test_command_ring().await;
 
-        for mut port in controller.operational_registers.ports_mut() {
-            // SAFETY: This resets the port, which has no effect on memory safety
-            unsafe {
-                port.write_status_and_control(port.read_status_and_control().with_reset(true));
+        for (i, mut port) in controller.operational_registers.ports_mut().enumerate() {
+            if port.over_current() {
+                warn!("Port {} has an over-current condition", i + 1);
+            }
+
+            // Ports which start powered off won't detect devices until they're powered.
+            // Once a device is detected, the port status change handler will reset the port.
+            if !port.powered() {
+                port.set_power(true);
+                continue;
             }
+
+            port.start_reset();
         }
 
         controller.main_loop().await;
@@ -162,7 +192,11 @@ impl XhciController {
     }
 
     /// Puts a single [`NoOp`] TRB on the command ring and waits for a [`CommandCompletion`] event TRB in response.
+    /// The event must have a [`Success`] completion code and point back to the TRB which was enqueued.
+    ///
+    /// Returns an error if no such event is received within [`NOOP_TIMEOUT_NS`].
     ///
+    /// [`Success`]: CompletionCode::Success
     /// [`NoOp`]: CommandTrb::NoOp
     /// [`CommandCompletion`]: EventTrb::CommandCompletion
     async fn test_noop(&mut self) -> Result<(), &'static str> {
@@ -182,8 +216,8 @@ impl XhciController {
         let trb_addr = unsafe { self.write_command_trb(CommandTrb::NoOp).unwrap() };
 
         // Wait for controller to process TRB
-        // TODO: time based timeout rather than fixed iteration count
-        for _ in 0..20 {
+        let start = KERNEL_STATE.uptime_ns();
+        while KERNEL_STATE.uptime_ns() - start < NOOP_TIMEOUT_NS {
             let read_event_trb = self.read_event_trb(0);
 
             match read_event_trb {
@@ -203,13 +237,43 @@ impl XhciController {
                 },
             }
 
-            futures::pending!();
+            yield_now().await;
         }
 
         Err("No CommandCompletion TRB found")
     }
 }
 
+/// How long [`test_noop`] waits for the controller to respond, in nanoseconds
+///
+/// [`test_noop`]: XhciController::test_noop
+const NOOP_TIMEOUT_NS: u64 = 100_000_000;
+
+/// How long to wait after moving the controller to [`D0`] before accessing it
+///
+/// [`D0`]: PowerState::D0
+const D0_RECOVERY_TIME_NS: u64 = 10_000_000;
+
+/// Puts the controller into the [`D0`] power state if it isn't already, and waits for it to be ready.
+///
+/// [`D0`]: PowerState::D0
+async fn power_on(function: &mut PciMappedFunction) {
+    match function.set_power_state(PowerState::D0) {
+        Ok(PowerState::D0) => (),
+        Ok(previous_state) => {
+            debug!("Moved XHCI controller from {previous_state:?} to D0");
+
+            let start = KERNEL_STATE.uptime_ns();
+            while KERNEL_STATE.uptime_ns() - start < D0_RECOVERY_TIME_NS {
+                yield_now().await;
+            }
+        }
+        // Functions without power management are always in D0
+        Err(PowerStateError::NoPowerManagement) => (),
+        Err(e) => warn!("Failed to move XHCI controller to D0: {e:?}"),
+    }
+}
+
 /// Initialises the MMIO associated with the controller at the given function.
 ///
 /// # Safety
@@ -260,7 +324,7 @@ unsafe fn init_mmio(
 /// Reads the header of the controller.
 ///
 /// This function also sanity checks that the device is actually an XHCI controller.
-fn parse_header(function: &PciMappedFunction) -> PciGeneralDeviceHeader {
+pub(super) fn parse_header(function: &PciMappedFunction) -> PciGeneralDeviceHeader {
     let header = function.read_header().unwrap().unwrap();
     let HeaderType::GeneralDevice(general_device_header) = header.header_type else {
         panic!("Device is not an XHCI controller")
@@ -359,28 +423,57 @@ unsafe fn find_registers(
     )
 }
 
+/// How long [`reset_and_wait`] waits for the controller to finish resetting, in nanoseconds
+const RESET_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// An error returned by [`wait_for`] if its condition doesn't become `true` before the timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TimeoutError;
+
+/// Yields until `done` returns `true`, or returns a [`TimeoutError`] if it doesn't within `timeout_ns` nanoseconds.
+/// `done` is always called at least once.
+async fn wait_for(timeout_ns: u64, mut done: impl FnMut() -> bool) -> Result<(), TimeoutError> {
+    let start = KERNEL_STATE.uptime_ns();
+
+    loop {
+        if done() {
+            return Ok(());
+        }
+
+        if KERNEL_STATE.uptime_ns() - start >= timeout_ns {
+            return Err(TimeoutError);
+        }
+
+        yield_now().await;
+    }
+}
+
 /// Writes `true` to [`UsbCommand::reset`],
-/// and then waits for the controller to write `false` back, signalling the reset has complete.
+/// and then waits for the controller to write `false` back and clear [`controller_not_ready`],
+/// signalling the reset has completed.
+///
+/// Returns a [`TimeoutError`] if this doesn't happen within [`RESET_TIMEOUT_NS`],
+/// in which case the controller is probably broken and shouldn't be used.
 ///
 /// # Safety
 /// This function will completely reset the controller, so the caller needs to ensure no code
 /// is relying on the state of the controller being preserved.
 ///
 /// [`UsbCommand::reset`]: super::registers::operational::UsbCommand::reset
-async unsafe fn reset_and_wait(operational_registers: &mut OperationalRegisters) {
+/// [`controller_not_ready`]: super::registers::operational::UsbStatus::controller_not_ready
+async unsafe fn reset_and_wait(
+    operational_registers: &mut OperationalRegisters,
+) -> Result<(), TimeoutError> {
     let mut usb_command = operational_registers.read_usb_command();
     usb_command.set_reset(true);
     operational_registers.write_usb_command(usb_command);
 
-    loop {
-        futures::pending!();
-
-        let usb_command = &operational_registers.read_usb_command();
-        let usb_status = &operational_registers.read_usb_status();
-        if !usb_command.reset() && !usb_status.controller_not_ready() {
-            break;
-        }
-    }
+    wait_for(RESET_TIMEOUT_NS, || {
+        let usb_command = operational_registers.read_usb_command();
+        let usb_status = operational_registers.read_usb_status();
+        !usb_command.reset() && !usb_status.controller_not_ready()
+    })
+    .await
 }
 
 /// Sets the value of [`max_device_slots_enabled`] to [`max_ports`].
@@ -451,3 +544,33 @@ unsafe fn init_msi(function: &mut PciMappedFunction) {
             .unwrap();
     }
 }
+
+/// Tests that [`wait_for`] times out rather than hanging if a controller never clears [`UsbCommand::reset`].
+/// Emulated controllers always finish resetting, so a stuck reset bit is simulated with a constant register value.
+///
+/// [`UsbCommand::reset`]: super::registers::operational::UsbCommand::reset
+#[test_case]
+fn test_wait_for_stuck_reset_times_out() {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll},
+    };
+
+    use super::registers::operational::UsbCommand;
+    use crate::scheduler::no_op_waker;
+
+    let waker = no_op_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    let stuck = UsbCommand::new().with_reset(true);
+    let mut future = pin!(wait_for(0, || !stuck.reset()));
+    assert_eq!(
+        future.as_mut().poll(&mut cx),
+        Poll::Ready(Err(TimeoutError))
+    );
+
+    let finished = UsbCommand::new();
+    let mut future = pin!(wait_for(0, || !finished.reset()));
+    assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+}
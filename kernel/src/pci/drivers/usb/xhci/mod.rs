@@ -5,15 +5,27 @@
 // TODO: actually fix these warnings instead of ignoring them
 #![allow(dead_code)]
 
-use core::cell::RefCell;
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
-use crate::{pci::devices::PciFunction, KERNEL_STATE};
+use crate::{
+    pci::{bar::BarValue, devices::PciFunction, PciMappedFunction},
+    println,
+    scheduler::yield_now,
+    KERNEL_STATE,
+};
 
-use alloc::boxed::Box;
-use log::error;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use device::Device;
+use log::{error, warn};
 use registers::capability::extended::{Capability, ExtendedCapabilityRegisters};
 use tasks::TaskQueue;
-use x86_64::PhysAddr;
+use x86_64::{
+    instructions::interrupts::{self, without_interrupts},
+    PhysAddr,
+};
 
 use self::{
     registers::{
@@ -28,11 +40,116 @@ use self::{
 };
 
 mod contexts;
+mod device;
 mod init;
 mod registers;
 mod tasks;
 mod trb;
 
+/// The number of controllers whose [`main_loop`] is running and which haven't been halted
+///
+/// [`main_loop`]: XhciController::main_loop
+static RUNNING_CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+/// Set by [`halt_controllers`] to tell each controller's [`main_loop`] to halt the controller
+///
+/// [`main_loop`]: XhciController::main_loop
+static HALT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How long a controller may take to halt after [`enabled`] is cleared, in nanoseconds.
+/// The spec requires controllers to halt within 16ms.
+///
+/// [`enabled`]: registers::operational::UsbCommand::enabled
+const HALT_TIMEOUT_NS: u64 = 16_000_000;
+
+/// How often [`main_loop`] checks the controller's [`UsbStatus`] for fatal errors, in nanoseconds
+///
+/// [`main_loop`]: XhciController::main_loop
+/// [`UsbStatus`]: registers::operational::UsbStatus
+const STATUS_CHECK_INTERVAL_NS: u64 = 100_000_000;
+
+/// An error reported by an xHCI controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XhciError {
+    /// A TRB completed with a [`CompletionCode`] other than [`Success`]
+    ///
+    /// [`Success`]: CompletionCode::Success
+    Completion(CompletionCode),
+}
+
+/// Tells every running controller to stop processing and halt,
+/// and waits for up to `timeout_ns` nanoseconds for them to do so.
+/// Returns the number of controllers which are still running.
+///
+/// Controllers are halted by their own tasks, which are polled in the timer interrupt,
+/// so if interrupts are disabled this doesn't wait.
+pub fn halt_controllers(timeout_ns: u64) -> usize {
+    HALT_REQUESTED.store(true, Ordering::SeqCst);
+
+    let start = KERNEL_STATE.uptime_ns();
+    while RUNNING_CONTROLLERS.load(Ordering::SeqCst) != 0
+        && interrupts::are_enabled()
+        && KERNEL_STATE.uptime_ns() - start < timeout_ns
+    {
+        x86_64::instructions::hlt();
+    }
+
+    RUNNING_CONTROLLERS.load(Ordering::SeqCst)
+}
+
+/// Prints the capability and operational registers of the xHCI controller at `function`, for `lspci -s`.
+///
+/// If `verbose` is `true`, the state of every port is printed, not just the ones with a device connected.
+///
+/// The registers are mapped separately from the controller's driver, and are only read,
+/// so this can be called while the driver is running.
+pub fn print_registers(function: &PciMappedFunction, verbose: bool) {
+    let general_device_header = init::parse_header(function);
+
+    // SAFETY: xHCI controllers are guaranteed to have a BAR in BAR slot 0.
+    // The `Bar` is only used to read the BAR's value, which doesn't affect the controller.
+    let bar = unsafe { general_device_header.bar(function, 0) };
+
+    let BarValue::MemorySpace { base_address, .. } = bar.read_value() else {
+        println!("BAR 0 is not a memory space BAR");
+        return;
+    };
+    // The BAR isn't sized here, as that would disable the controller while its driver is using it
+    let size = function.bar_size(0);
+
+    if base_address.as_address().is_null() || size == 0 {
+        println!("BAR 0 has not been allocated");
+        return;
+    }
+
+    // Driver tasks are polled in the timer interrupt and may map memory themselves,
+    // so interrupts are disabled while the lock is held to avoid deadlock.
+    // SAFETY: The region is the controller's MMIO, which is only read through this mapping
+    let mapping = without_interrupts(|| unsafe {
+        KERNEL_STATE
+            .physical_memory_accessor
+            .lock()
+            .map_region(base_address.as_address(), size.try_into().unwrap())
+    });
+
+    // SAFETY: The capability registers are at the start of the MMIO region.
+    // The driver has its own `CapabilityRegisters`, but these are only read, which doesn't interfere with it.
+    let capability_registers = unsafe { CapabilityRegisters::new(mapping.start()) };
+
+    // SAFETY: The operational registers are directly after the capability registers.
+    // The driver has its own `OperationalRegisters`, but `debug` only reads the registers, which doesn't interfere with it.
+    let operational_registers = unsafe {
+        OperationalRegisters::new(
+            mapping.start() + u64::from(capability_registers.capability_register_length()),
+            &capability_registers,
+        )
+    };
+
+    println!("{capability_registers:#?}");
+    operational_registers.debug(verbose);
+
+    without_interrupts(|| drop(mapping));
+}
+
 /// A specific xHCI USB controller connected to the system by PCI.
 pub struct XhciController {
     /// The PCI function where the controller is connected
@@ -56,29 +173,116 @@ pub struct XhciController {
     interrupters: Box<[Interrupter]>,
     /// The doorbell registers, which software uses to tell the controller there are TRBs to be processed.
     doorbell_registers: DoorbellRegisters,
+
+    /// The [`Device`]s which have been assigned a device slot, indexed by slot ID
+    devices: BTreeMap<u8, Device>,
+    /// The slot IDs of hubs which have been enumerated but don't yet have a task driving them.
+    /// These are taken by [`TaskQueue::poll`], which starts a task for each.
+    new_hubs: Vec<u8>,
+    /// The indices of the [`Interrupter`]s other than the primary interrupter which have had endpoints' events
+    /// routed to them with [`route_endpoint_events`]. These are polled by [`main_loop`] as well as the primary interrupter.
+    ///
+    /// [`route_endpoint_events`]: XhciController::route_endpoint_events
+    /// [`main_loop`]: XhciController::main_loop
+    secondary_interrupters: Vec<u16>,
 }
 
 impl XhciController {
     /// Enters the main loop of the controller. This is called by [`init`] when the controller is set up.
-    /// This function sets up a [`TaskQueue`] and continually polls it.
+    /// This function sets up a [`TaskQueue`] and continually polls it, passing it events from the primary interrupter
+    /// and from any [`secondary_interrupters`].
+    ///
+    /// [`secondary_interrupters`]: XhciController::secondary_interrupters
     ///
     /// [`init`]: XhciController::init
     async fn main_loop(self) -> ! {
         let s = RefCell::new(self);
         let mut tasks = TaskQueue::new(&s);
-        let mut prev_ticks = KERNEL_STATE.ticks();
+        let mut prev_uptime = KERNEL_STATE.uptime_ns();
+        let mut last_status_check = prev_uptime;
+
+        RUNNING_CONTROLLERS.fetch_add(1, Ordering::SeqCst);
 
         loop {
-            futures::pending!();
+            yield_now().await;
+
+            if HALT_REQUESTED.load(Ordering::SeqCst) {
+                s.borrow_mut().stop().await;
+            }
 
-            let ticks = KERNEL_STATE.ticks();
-            let tick_diff = ticks - prev_ticks;
-            prev_ticks = ticks;
-            let ns_since_last = tick_diff * (1_000_000_000 / 100);
+            let uptime = KERNEL_STATE.uptime_ns();
+            let ns_since_last = (uptime - prev_uptime).try_into().unwrap();
+            prev_uptime = uptime;
+
+            // A controller with a fatal error stops producing events, so check for one rather than polling it forever
+            if uptime - last_status_check >= STATUS_CHECK_INTERVAL_NS {
+                last_status_check = uptime;
+
+                let usb_status = s.borrow().operational_registers.read_usb_status();
+                if usb_status.host_controller_error() || usb_status.host_system_error() {
+                    error!(
+                        "XHCI controller {} reported a fatal error (HCE: {}, HSE: {}), stopping its driver",
+                        s.borrow().function,
+                        usb_status.host_controller_error(),
+                        usb_status.host_system_error(),
+                    );
+                    s.borrow_mut().stop().await;
+                }
+            }
 
             let trb = s.borrow_mut().read_event_trb(0);
             tasks.poll(ns_since_last, trb).await;
+
+            // Each secondary interrupter is polled separately, so a busy one doesn't hold up events on the others
+            let secondary_interrupters = s.borrow().secondary_interrupters.clone();
+            for i in secondary_interrupters {
+                let trb = s.borrow_mut().read_event_trb(i.into());
+                if trb.is_some() {
+                    tasks.poll(0, trb).await;
+                }
+            }
+        }
+    }
+
+    /// Halts the controller and then never completes, so that the driver stops using it.
+    /// This is used when the kernel is shutting down, or when the controller has had an error which it can't recover from.
+    async fn stop(&mut self) -> ! {
+        if !self.halt().await {
+            warn!("XHCI controller {} didn't halt", self.function);
+        }
+        RUNNING_CONTROLLERS.fetch_sub(1, Ordering::SeqCst);
+
+        // The controller won't do anything else, so the task never needs to make progress
+        core::future::pending::<()>().await;
+        unreachable!("pending() never completes")
+    }
+
+    /// Stops the controller by clearing [`enabled`], and waits for up to [`HALT_TIMEOUT_NS`] for it to halt.
+    /// Returns whether the controller halted.
+    ///
+    /// [`enabled`]: registers::operational::UsbCommand::enabled
+    async fn halt(&mut self) -> bool {
+        self.operational_registers.write_usb_command(
+            self.operational_registers
+                .read_usb_command()
+                .with_interrupts_enabled(false)
+                .with_enabled(false),
+        );
+
+        let start = KERNEL_STATE.uptime_ns();
+        while !self
+            .operational_registers
+            .read_usb_status()
+            .host_controller_halted()
+        {
+            if KERNEL_STATE.uptime_ns() - start >= HALT_TIMEOUT_NS {
+                return false;
+            }
+
+            yield_now().await;
         }
+
+        true
     }
 
     /// Writes a TRB to the command ring and rings the host controller doorbell to notify the controller to process it.
@@ -94,23 +298,45 @@ impl XhciController {
         Ok(trb_addr)
     }
 
+    /// Writes a TRB to the command ring like [`write_command_trb`], but if the ring is full,
+    /// yields until the controller has processed enough TRBs to make space rather than returning a [`RingFullError`].
+    ///
+    /// The ring's dequeue pointer is only advanced when [`read_event_trb`] reads a [`CommandCompletion`] TRB,
+    /// so this must be awaited from a task which is polled by [`main_loop`], or it will never complete.
+    ///
+    /// # Safety
+    /// The caller is responsible for the behaviour of the controller in response to this TRB
+    ///
+    /// [`write_command_trb`]: XhciController::write_command_trb
+    /// [`read_event_trb`]: XhciController::read_event_trb
+    /// [`CommandCompletion`]: EventTrb::CommandCompletion
+    /// [`main_loop`]: XhciController::main_loop
+    async unsafe fn write_command_trb_wait(c: &RefCell<Self>, trb: CommandTrb) -> PhysAddr {
+        while c.borrow().command_ring.is_full() {
+            yield_now().await;
+        }
+
+        // SAFETY: The caller is responsible for the behaviour of the controller in response to this TRB
+        unsafe {
+            c.borrow_mut()
+                .write_command_trb(trb)
+                .expect("Command ring should have had space")
+        }
+    }
+
     /// Reads an event from the event ring from the `i`th interrupter.
     /// Certain event types will be intercepted and acted on before being returned, such as calling
-    /// [`update_dequeue`] for [`CommandCompletion`] TRBs.
+    /// [`update_dequeue`] for [`CommandCompletion`] TRBs, or on the relevant transfer ring for [`Transfer`] TRBs.
     ///
     /// [`update_dequeue`]: CommandTrbRing::update_dequeue
     /// [`CommandCompletion`]: EventTrb::CommandCompletion
+    /// [`Transfer`]: EventTrb::Transfer
     fn read_event_trb(&mut self, i: usize) -> Option<EventTrb> {
         let trb = self.interrupters[i].dequeue()?;
 
         if let EventTrb::CommandCompletion(command_completion_trb) = trb {
-            match command_completion_trb.completion_code {
-                CompletionCode::Success => (),
-                error => {
-                    error!("Error occurred processing TRB: {error:?}");
-                }
-            }
-
+            // Failed commands aren't logged here, as the task which issued the command receives the completion code
+            // from `wait_for_command_completion` and decides how to handle it.
             assert!(
                 !command_completion_trb.command_trb_pointer.is_null(),
                 "Command TRB pointer should not have been null"
@@ -123,6 +349,26 @@ impl XhciController {
             }
         }
 
+        if let EventTrb::Transfer(transfer_trb) = trb {
+            // If `event_data` is set, `trb_pointer` is not a pointer to a TRB on the ring.
+            if !transfer_trb.flags.event_data() {
+                let ring = self
+                    .devices
+                    .get_mut(&transfer_trb.slot_id())
+                    .and_then(|device| match transfer_trb.endpoint_id() {
+                        1 => Some(device.control_ring_mut()),
+                        endpoint_id => device.endpoint_ring_mut(endpoint_id),
+                    });
+
+                if let Some(ring) = ring {
+                    // SAFETY: The address was read from a transfer event TRB for this ring
+                    unsafe {
+                        ring.update_dequeue(transfer_trb.trb_pointer);
+                    }
+                }
+            }
+        }
+
         Some(trb)
     }
 
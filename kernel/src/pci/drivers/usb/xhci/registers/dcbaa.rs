@@ -6,7 +6,7 @@ use core::fmt::Debug;
 use alloc::boxed::Box;
 use x86_64::PhysAddr;
 
-use crate::allocator::PageBox;
+use crate::allocator::DmaBuffer;
 
 use super::super::{
     contexts::{device_context::OwnedDeviceContext, ContextSize},
@@ -30,10 +30,15 @@ use super::scratchpad::ScratchpadBufferArray;
 ///
 /// [4.20]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A341%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C694%2C0%5D
 pub struct DeviceContextBaseAddressArray {
-    /// The page where the DCBAA is stored in memory
-    page: PageBox,
+    /// The DCBAA in memory: the scratchpad buffer array pointer followed by a pointer for each slot
+    array: DmaBuffer<[u64; 257]>,
     /// The length of the array
     len: usize,
+    /// The number of slots which software has enabled, from the controller's [`max_device_slots_enabled`] field.
+    /// This is never more than `len`.
+    ///
+    /// [`max_device_slots_enabled`]: super::operational::ConfigureRegister::max_device_slots_enabled
+    slots_enabled: usize,
     /// The scratchpad buffer array
     scratchpad_buffer_array: ScratchpadBufferArray,
     /// The device contexts pointed to by the DCBAA
@@ -42,9 +47,12 @@ pub struct DeviceContextBaseAddressArray {
 
 impl DeviceContextBaseAddressArray {
     /// Allocates a new DCBAA using the values in the given registers.
+    /// The controller's [`max_device_slots_enabled`] field must already have been written.
     ///
     /// # Safety
     /// * The given `capability` and `operational` registers must be valid registers from the same controller.
+    ///
+    /// [`max_device_slots_enabled`]: super::operational::ConfigureRegister::max_device_slots_enabled
     pub unsafe fn from_registers(
         capability: &CapabilityRegisters,
         operational: &OperationalRegisters,
@@ -54,6 +62,11 @@ impl DeviceContextBaseAddressArray {
             .max_device_slots()
             .into();
 
+        let slots_enabled = operational
+            .read_configure()
+            .max_device_slots_enabled()
+            .into();
+
         let page_size = operational.read_page_size();
 
         let context_size = capability.capability_parameters_1().context_size();
@@ -63,15 +76,22 @@ impl DeviceContextBaseAddressArray {
             .max_scratchpad_buffers()
             .into();
 
-        // SAFETY: `len`, `page_size`, `context_size`, and `max_scratchpad_buffers` are valid
+        // SAFETY: `len`, `slots_enabled`, `page_size`, `context_size`, and `max_scratchpad_buffers` are valid
         unsafe {
-            DeviceContextBaseAddressArray::new(len, page_size, context_size, max_scratchpad_buffers)
+            DeviceContextBaseAddressArray::new(
+                len,
+                slots_enabled,
+                page_size,
+                context_size,
+                max_scratchpad_buffers,
+            )
         }
     }
 
     /// Allocates a new DCBAA with the given length
     ///
     /// # Safety
+    /// * `slots_enabled` must be the value of the controller's [`max_device_slots_enabled`] field
     /// * `page_size` must be the value of [the controller's `page_size` register]
     /// * `context_size` must be the value of the controller's [`context_size`] register
     /// * `max_scratchpad_buffers` must be the value of the controller's [`max_scratchpad_buffers`] register
@@ -79,8 +99,10 @@ impl DeviceContextBaseAddressArray {
     /// [the controller's `page_size` register]: super::operational::OperationalRegisters::read_page_size
     /// [`context_size`]: super::capability::CapabilityParameters1::context_size
     /// [`max_scratchpad_buffers`]: super::capability::StructuralParameters2::max_scratchpad_buffers
+    /// [`max_device_slots_enabled`]: super::operational::ConfigureRegister::max_device_slots_enabled
     pub unsafe fn new(
         len: usize,
+        slots_enabled: usize,
         page_size: SupportedPageSize,
         context_size: ContextSize,
         max_scratchpad_buffers: usize,
@@ -92,8 +114,9 @@ impl DeviceContextBaseAddressArray {
             unsafe { ScratchpadBufferArray::new(max_scratchpad_buffers, page_size) };
 
         let mut s = Self {
-            page: PageBox::new(),
+            array: DmaBuffer::new([0; 257]),
             len,
+            slots_enabled: slots_enabled.min(len),
             scratchpad_buffer_array: scratchpad_buffer,
             contexts: core::iter::repeat(())
                 .take(len)
@@ -107,27 +130,20 @@ impl DeviceContextBaseAddressArray {
             s.write_scratchpad_buffer_array(s.scratchpad_buffer_array.get_array_addr(), page_size);
         }
 
-        for i in 0..s.contexts.len() {
-            let addr = s.contexts[i].get_addr();
-
-            // SAFETY: `addr` is the address of a device context
-            unsafe {
-                s.set_slot_addr(i, addr);
-            }
-        }
+        // Each slot's entry stays null until the slot is enabled and `init_slot` is called
 
         s
     }
 
     /// Gets the address of the DCBAA
     pub fn array_addr(&self) -> PhysAddr {
-        self.page.phys_frame().start_address()
+        self.array.phys_addr()
     }
 
     /// Reads the physical address of the scratchpad buffer
     fn scratchpad_buffer_array(&self) -> PhysAddr {
-        // SAFETY: The first entry in the array is the scratchpad array
-        let v = unsafe { self.page.as_ptr::<u64>().read_volatile() };
+        // The first entry in the array is the scratchpad array
+        let v = self.array.get(0).expect("DCBAA should not be empty");
 
         // The bottom 5 bits are reserved, so mask.
         PhysAddr::new(v & !0b11111)
@@ -153,13 +169,11 @@ impl DeviceContextBaseAddressArray {
             "Address must be page_size aligned"
         );
 
-        // SAFETY: The first entry in the array is the scratchpad array.
+        // The first entry in the array is the scratchpad array.
         // The caller is responsible for ensuring that the address is valid.
-        unsafe {
-            self.page
-                .as_mut_ptr::<u64>()
-                .write_volatile(address.as_u64());
-        }
+        self.array
+            .set(0, address.as_u64())
+            .expect("DCBAA should not be empty");
     }
 
     /// Gets the address for the given slot.
@@ -168,13 +182,8 @@ impl DeviceContextBaseAddressArray {
             return None;
         }
 
-        // SAFETY: i < len, so i is within the bounds of the array.
-        let v = unsafe {
-            self.page
-                .as_ptr::<u64>()
-                .add(1 + i) // First element in array is scratchpad pointer, so add 1.
-                .read_volatile()
-        };
+        // First element in array is scratchpad pointer, so add 1.
+        let v = self.array.get(1 + i).ok()?;
 
         Some(PhysAddr::new(v & !0b1111))
     }
@@ -191,13 +200,92 @@ impl DeviceContextBaseAddressArray {
 
         assert!(i < self.len, "Index outside of table");
 
-        // SAFETY: The first entry in the array is the scratchpad array.
+        // The first entry in the array is the scratchpad array.
         // The caller is responsible for ensuring that the address is valid.
-        unsafe {
-            self.page
-                .as_mut_ptr::<u64>()
-                .add(1 + i)
-                .write_volatile(address.as_u64());
+        self.array
+            .set(1 + i, address.as_u64())
+            .expect("Index should be within the DCBAA");
+    }
+
+    /// Points the entry for the given slot at the slot's device context, which must be done after the slot is enabled
+    /// and before an _Address Device_ command is issued for it.
+    ///
+    /// # Safety
+    /// * The slot must have just been enabled, so that the controller isn't accessing the slot's entry or device context
+    ///
+    /// # Panics
+    /// * If `slot_id` is 0 or greater than the number of enabled slots
+    pub unsafe fn init_slot(&mut self, slot_id: u8) {
+        let addr = self.get(slot_id).expect("Invalid slot ID").get_addr();
+
+        // SAFETY: `addr` is the address of the slot's device context
+        unsafe { self.set_slot_addr(usize::from(slot_id) - 1, addr) };
+    }
+
+    /// Clears the entry for the given slot and zeroes its device context, so that the slot can be reused
+    /// by a new device. The device context's memory is kept, as the contexts for every slot are allocated up front.
+    ///
+    /// # Safety
+    /// * The slot must have been disabled, so that the controller no longer accesses its device context
+    ///
+    /// # Panics
+    /// * If `slot_id` is 0 or greater than the number of enabled slots
+    pub unsafe fn clear_slot(&mut self, slot_id: u8) {
+        let context = self.get_mut(slot_id).expect("Invalid slot ID");
+
+        // SAFETY: The slot is disabled, so the OS is allowed to write to its device context
+        unsafe { context.get_mut().reset() };
+
+        // SAFETY: The slot is disabled, so the controller doesn't read its entry.
+        // A null entry marks the slot as unused.
+        unsafe { self.set_slot_addr(usize::from(slot_id) - 1, PhysAddr::zero()) };
+    }
+
+    /// Gets whether the given slot is in use, i.e. it has been set up by [`init_slot`] and not yet cleared by [`clear_slot`].
+    ///
+    /// [`init_slot`]: DeviceContextBaseAddressArray::init_slot
+    /// [`clear_slot`]: DeviceContextBaseAddressArray::clear_slot
+    pub fn slot_in_use(&self, slot_id: u8) -> bool {
+        self.slot_index(slot_id)
+            .and_then(|i| self.get_slot_addr(i))
+            .is_some_and(|addr| addr.as_u64() != 0)
+    }
+
+    /// Gets the number of slots which are in use
+    pub fn slots_in_use(&self) -> usize {
+        (1..=self.slots_enabled)
+            .filter(|&slot_id| self.slot_in_use(slot_id.try_into().unwrap()))
+            .count()
+    }
+
+    /// Gets the device context for the given slot.
+    ///
+    /// Returns [`None`] if `slot_id` is 0, as the first entry of the array is the scratchpad buffer array pointer
+    /// rather than a slot, or if `slot_id` is greater than the number of slots which have been enabled.
+    pub fn get(&self, slot_id: u8) -> Option<&OwnedDeviceContext> {
+        let i = self.slot_index(slot_id)?;
+        Some(&self.contexts[i])
+    }
+
+    /// Gets the device context for the given slot mutably.
+    ///
+    /// Returns [`None`] if `slot_id` is 0, as the first entry of the array is the scratchpad buffer array pointer
+    /// rather than a slot, or if `slot_id` is greater than the number of slots which have been enabled.
+    pub fn get_mut(&mut self, slot_id: u8) -> Option<&mut OwnedDeviceContext> {
+        let i = self.slot_index(slot_id)?;
+        Some(&mut self.contexts[i])
+    }
+
+    /// Converts a slot ID into an index into [`contexts`], checking that it is in the range of enabled slots
+    ///
+    /// [`contexts`]: DeviceContextBaseAddressArray::contexts
+    fn slot_index(&self, slot_id: u8) -> Option<usize> {
+        let slot_id = usize::from(slot_id);
+
+        if slot_id == 0 || slot_id > self.slots_enabled {
+            None
+        } else {
+            Some(slot_id - 1)
         }
     }
 
@@ -220,3 +308,49 @@ impl Debug for DeviceContextBaseAddressArray {
             .finish()
     }
 }
+
+/// Tests that slots are freed when devices are unplugged, by setting up and clearing slots as plugging and unplugging
+/// devices would, and checking that a slot is still available for the next device
+#[test_case]
+fn test_unplugged_slots_are_freed() {
+    let slots = 4;
+    // SAFETY: No controller uses this DCBAA
+    let mut dcbaa = unsafe {
+        DeviceContextBaseAddressArray::new(
+            slots,
+            slots,
+            SupportedPageSize::ONLY_4K,
+            ContextSize::Small,
+            0,
+        )
+    };
+    assert_eq!(dcbaa.slots_in_use(), 0);
+
+    // The controller gives each new device the first free slot
+    let plug = |dcbaa: &mut DeviceContextBaseAddressArray| {
+        let slot_id = (1..=slots as u8)
+            .find(|&slot_id| !dcbaa.slot_in_use(slot_id))
+            .expect("A slot should be free");
+        // SAFETY: No controller uses this DCBAA
+        unsafe { dcbaa.init_slot(slot_id) };
+        slot_id
+    };
+
+    // Plugging and unplugging more devices than there are slots doesn't run out of slots
+    for _ in 0..slots * 2 {
+        let slot_id = plug(&mut dcbaa);
+        assert_eq!(slot_id, 1);
+        assert_eq!(dcbaa.slots_in_use(), 1);
+
+        // SAFETY: No controller uses this DCBAA
+        unsafe { dcbaa.clear_slot(slot_id) };
+        assert_eq!(dcbaa.slots_in_use(), 0);
+    }
+
+    // Filling every slot and then unplugging one device frees its slot for the next
+    let slot_ids: alloc::vec::Vec<_> = (0..slots).map(|_| plug(&mut dcbaa)).collect();
+    assert_eq!(dcbaa.slots_in_use(), slots);
+    // SAFETY: No controller uses this DCBAA
+    unsafe { dcbaa.clear_slot(slot_ids[2]) };
+    assert_eq!(plug(&mut dcbaa), slot_ids[2]);
+}
@@ -4,6 +4,8 @@ use core::marker::PhantomData;
 
 use x86_64::VirtAddr;
 
+use crate::util::generic_mutability::{Mutable, VolatileSlice};
+
 /// Which endpoint within the slot this doorbell write is targeting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DoorbellTarget {
@@ -37,11 +39,11 @@ impl DoorbellTarget {
         match self {
             Self::ControlEndpoint => 1,
             Self::OutEndpoint(ep) => {
-                debug_assert!(ep != 1 && ep <= 15);
+                debug_assert!(ep != 0 && ep <= 15);
                 ep * 2
             }
             Self::InEndpoint(ep) => {
-                debug_assert!(ep != 1 && ep <= 15);
+                debug_assert!(ep != 0 && ep <= 15);
                 ep * 2 + 1
             }
             Self::Reserved(v) => {
@@ -60,12 +62,24 @@ impl DoorbellTarget {
         match byte {
             1 => Self::ControlEndpoint,
             2..=30 if byte % 2 == 0 => Self::OutEndpoint(byte / 2),
-            2..=31 => Self::OutEndpoint(byte / 2),
+            2..=31 => Self::InEndpoint(byte / 2),
             0 | 32..=247 => Self::Reserved(byte),
             248..=255 => Self::VendorDefined(byte),
         }
     }
 
+    /// Gets the [`DoorbellTarget`] for the endpoint with the given ID (also called the _Device Context Index_),
+    /// or [`None`] if `endpoint_id` isn't between 1 and 31.
+    ///
+    /// Endpoint ID 1 is the control endpoint, and the rest alternate between OUT and IN endpoints,
+    /// so that the ID is the same as the doorbell target value.
+    pub const fn from_endpoint_id(endpoint_id: u8) -> Option<Self> {
+        match endpoint_id {
+            1..=31 => Some(Self::from_byte(endpoint_id)),
+            _ => None,
+        }
+    }
+
     /// Constructs a [`DoorbellTarget`] from its bit representation
     const fn from_bits(bits: u32) -> Self {
         #[allow(clippy::cast_possible_truncation)]
@@ -94,10 +108,8 @@ pub struct DoorbellArrayEntry {
 /// (e.g. processing a command TRB).
 #[derive(Debug)]
 pub struct DoorbellRegisters {
-    /// Pointer to the first doorbell
-    ptr: *mut DoorbellArrayEntry,
-    /// The number of doorbells
-    len: usize,
+    /// The doorbells - the host controller doorbell followed by one for each device slot
+    doorbells: VolatileSlice<'static, DoorbellArrayEntry, Mutable>,
 }
 
 impl DoorbellRegisters {
@@ -113,14 +125,40 @@ impl DoorbellRegisters {
         assert!(max_device_slots > 1);
 
         Self {
-            ptr: ptr.as_mut_ptr(),
-            len: max_device_slots,
+            // SAFETY: The caller guarantees that `ptr` points to the doorbell array,
+            // which has an entry for the host controller followed by one for each device slot.
+            doorbells: unsafe { VolatileSlice::new(ptr.as_mut_ptr(), max_device_slots + 1) },
         }
     }
 
     /// Gets the host controller doorbell
     pub fn host_controller_doorbell(&mut self) -> HostControllerDoorbell {
-        HostControllerDoorbell(self.ptr.cast(), PhantomData)
+        let ptr = self
+            .doorbells
+            .get_ptr(0)
+            .expect("Doorbell array should not be empty");
+        HostControllerDoorbell(ptr.cast(), PhantomData)
+    }
+
+    /// Gets the doorbell for the device slot with the given ID
+    ///
+    /// # Panics
+    /// If `slot_id` is 0 or greater than the number of device slots
+    pub fn device_doorbell(&mut self, slot_id: u8) -> DeviceDoorbell {
+        self.doorbell(slot_id)
+            .expect("Slot ID should be between 1 and the number of device slots")
+    }
+
+    /// Gets the doorbell for the device slot with the given ID,
+    /// or [`None`] if `slot_id` is 0 or greater than the number of device slots.
+    pub fn doorbell(&mut self, slot_id: u8) -> Option<DeviceDoorbell> {
+        // Index 0 is the host controller doorbell
+        if slot_id == 0 {
+            return None;
+        }
+
+        let ptr = self.doorbells.get_ptr(usize::from(slot_id))?;
+        Some(DeviceDoorbell(ptr, PhantomData))
     }
 }
 
@@ -137,3 +175,79 @@ impl<'a> HostControllerDoorbell<'a> {
         unsafe { self.0.write_volatile(0) }
     }
 }
+
+/// The doorbell for a device slot. A write to it indicates that there are TRBs to be processed
+/// on one of the device's transfer rings.
+#[derive(Debug)]
+pub struct DeviceDoorbell<'a>(
+    *mut DoorbellArrayEntry,
+    PhantomData<&'a mut DoorbellArrayEntry>,
+);
+
+impl<'a> DeviceDoorbell<'a> {
+    /// Rings the doorbell, telling the controller to process TRBs on the transfer ring of the given endpoint
+    pub fn ring(&mut self, target: DoorbellTarget) {
+        // SAFETY: The stored pointer points to a device doorbell
+        unsafe {
+            self.0
+                .write_volatile(DoorbellArrayEntry::new().with_target(target))
+        }
+    }
+
+    /// Rings the doorbell for the endpoint with the given ID (also called the _Device Context Index_).
+    ///
+    /// # Panics
+    /// If `endpoint_id` isn't between 1 and 31
+    pub fn ring_endpoint(&mut self, endpoint_id: u8) {
+        let target = DoorbellTarget::from_endpoint_id(endpoint_id)
+            .expect("Endpoint ID should be between 1 and 31");
+        self.ring(target);
+    }
+}
+
+/// Tests that each slot's doorbell is at the right offset in the doorbell array,
+/// and that [`ring_endpoint`][DeviceDoorbell::ring_endpoint] writes the endpoint ID as the target.
+#[test_case]
+fn test_xhci_doorbell_offsets() {
+    use core::mem::size_of;
+
+    assert_eq!(size_of::<DoorbellArrayEntry>(), 4);
+
+    // The host controller doorbell followed by 8 device slots
+    let mut array = [0u32; 9];
+    // SAFETY: `array` has space for the host controller doorbell and 8 device slots, and isn't used by a controller
+    let mut registers =
+        unsafe { DoorbellRegisters::new(VirtAddr::from_ptr(array.as_mut_ptr()), 8) };
+
+    assert!(registers.doorbell(0).is_none());
+    assert!(registers.doorbell(9).is_none());
+
+    registers.doorbell(3).unwrap().ring_endpoint(5);
+    registers.doorbell(8).unwrap().ring_endpoint(1);
+    registers.host_controller_doorbell().ring();
+
+    assert_eq!(array, [0, 0, 0, 5, 0, 0, 0, 0, 1]);
+}
+
+/// Tests that endpoint IDs are converted to the right [`DoorbellTarget`]s
+#[test_case]
+fn test_doorbell_target_from_endpoint_id() {
+    assert_eq!(DoorbellTarget::from_endpoint_id(0), None);
+    assert_eq!(
+        DoorbellTarget::from_endpoint_id(1),
+        Some(DoorbellTarget::ControlEndpoint)
+    );
+    assert_eq!(
+        DoorbellTarget::from_endpoint_id(2),
+        Some(DoorbellTarget::OutEndpoint(1))
+    );
+    assert_eq!(
+        DoorbellTarget::from_endpoint_id(3),
+        Some(DoorbellTarget::InEndpoint(1))
+    );
+    assert_eq!(
+        DoorbellTarget::from_endpoint_id(31),
+        Some(DoorbellTarget::InEndpoint(15))
+    );
+    assert_eq!(DoorbellTarget::from_endpoint_id(32), None);
+}
@@ -5,7 +5,7 @@ pub mod port_registers;
 use core::fmt::Debug;
 use x86_64::{PhysAddr, VirtAddr};
 
-use self::port_registers::PortRegister;
+use self::port_registers::{PortRegister, PortRegisterFields};
 
 use super::super::{
     registers::capability::CapabilityRegisters, volatile_accessors, volatile_getter,
@@ -14,7 +14,7 @@ use crate::{
     print, println,
     util::{
         bitfield_enum::bitfield_enum,
-        generic_mutability::{Immutable, Mutable},
+        generic_mutability::{Immutable, Mutable, VolatileSlice},
     },
 };
 
@@ -58,7 +58,7 @@ pub struct UsbCommand {
     /// When the reset is complete, the controller will write `false` to this field.
     /// All internal state is reset, but PCI configuration registers (e.g. BARs) are not.
     /// After resetting, the controller must be reinitialised.
-    /// Use [`XhciController::reset_and_wait`][super::XhciController::reset_and_wait] to handle writing to this field.
+    /// Use [`reset_and_wait`][super::super::init::reset_and_wait] to handle writing to this field.
     pub reset: bool,
 
     /// Whether the device will produce host system interrupts (i.e. CPU interrupts) on USB events.
@@ -246,15 +246,33 @@ pub struct UsbStatus {
 pub struct SupportedPageSize(u32);
 
 impl SupportedPageSize {
-    /// Gets the page size supported by the device, e.g. a device supporting 4k pages will return 0x1000
+    /// The value for a controller which only supports 4KiB pages, for tests which don't use a real controller
+    #[cfg(test)]
+    pub const ONLY_4K: Self = Self(0b1);
+
+    /// Gets the page size to use with the device, e.g. a device supporting 4k pages will return 0x1000.
+    ///
+    /// If the device supports multiple page sizes, this is the smallest one. All supported page sizes are at least 4KiB,
+    /// so this is the smallest which is at least as big as the host's pages.
+    ///
+    /// # Panics
+    /// If the device doesn't report any supported page sizes
     pub fn page_size(&self) -> u64 {
-        let v = (self.0 as u64) << 12;
+        self.supported_page_sizes()
+            .next()
+            .expect("Controller should support at least one page size")
+    }
 
-        if !v.is_power_of_two() {
-            unimplemented!("Devices supporting multiple page sizes");
-        }
+    /// Gets an iterator over all the page sizes supported by the device, from smallest to largest.
+    ///
+    /// Bit `n` of the register is set if the device supports pages of size `2^(n+12)`.
+    pub fn supported_page_sizes(&self) -> impl Iterator<Item = u64> {
+        // Only the bottom 16 bits are defined
+        let bits = self.0 & 0xffff;
 
-        v
+        (0..16)
+            .filter(move |i| bits & (1 << i) != 0)
+            .map(|i| 1 << (i + 12))
     }
 }
 
@@ -454,8 +472,8 @@ impl Debug for OperationalRegistersFields {
 pub struct OperationalRegisters {
     /// The address of the registers
     ptr: *mut OperationalRegistersFields,
-    /// The number of [`PortRegister`] structures
-    max_ports: u8,
+    /// The port register sets, which start at offset 0x400 from the operational registers
+    ports: VolatileSlice<'static, PortRegisterFields, Mutable>,
 }
 
 impl OperationalRegisters {
@@ -466,12 +484,23 @@ impl OperationalRegisters {
     /// This function may only be called once per controller.
     /// The passed `capability_registers` must be for the same controller.
     pub unsafe fn new(ptr: VirtAddr, capability_registers: &CapabilityRegisters) -> Self {
-        // SAFETY: `ptr` is valid
-        let ptr = ptr.as_mut_ptr();
+        let max_ports = capability_registers.structural_parameters_1().max_ports();
+
+        // SAFETY: `ptr` points to the operational registers, which have `max_ports` port register sets
+        unsafe { Self::from_ptr(ptr.as_mut_ptr(), max_ports.into()) }
+    }
 
+    /// Wraps the given pointer to operational registers with `max_ports` port register sets.
+    ///
+    /// # Safety
+    /// `ptr` must point to the operational registers of an xHCI controller, or to memory laid out the same way,
+    /// which must be followed by `max_ports` port register sets starting at offset 0x400.
+    unsafe fn from_ptr(ptr: *mut OperationalRegistersFields, max_ports: usize) -> Self {
         Self {
             ptr,
-            max_ports: capability_registers.structural_parameters_1().max_ports(),
+            // SAFETY: The caller guarantees that there are `max_ports` port register sets at this offset,
+            // and they are only accessed through this struct.
+            ports: unsafe { VolatileSlice::new(ptr.byte_add(0x400).cast(), max_ports) },
         }
     }
 }
@@ -520,61 +549,51 @@ impl OperationalRegisters {
     /// Gets the number of port registers the controller has.
     /// This is the largest value where [`port`][OperationalRegisters::port] will return [`Some`]
     pub fn max_ports(&self) -> usize {
-        self.max_ports as usize
+        self.ports.len()
+    }
+
+    /// Gets a pointer to the port register set at the _1 based_ port number given,
+    /// or [`None`] if there is no such port.
+    fn port_ptr(&self, port_number: usize) -> Option<*mut PortRegisterFields> {
+        self.ports.get_ptr(port_number.checked_sub(1)?)
     }
 
     /// Gets the [`PortRegister`] at the _1 based_ port number given.
     pub fn port(&self, port_number: usize) -> Option<PortRegister<'_, Immutable>> {
-        if port_number != 0 && port_number > self.max_ports as usize {
-            None
-        } else {
-            // SAFETY: `port_number` is not greater than `max_ports`, so this pointer is valid
-            unsafe {
-                Some(PortRegister::new(
-                    self.ptr.byte_add(0x400 + 0x10 * (port_number - 1)).cast(),
-                ))
-            }
-        }
+        let ptr = self.port_ptr(port_number)?;
+        // SAFETY: `ptr` is in bounds of the port register sets, so it is valid
+        Some(unsafe { PortRegister::new(ptr) })
     }
 
     /// Gets the [`PortRegister`] at the _1 based_ port number given.
     pub fn port_mut(&mut self, port_number: usize) -> Option<PortRegister<'_, Mutable>> {
-        if port_number != 0 && port_number > self.max_ports as usize {
-            None
-        } else {
-            // SAFETY: `port_number` is not greater than `max_ports`, so this pointer is valid
-            unsafe {
-                Some(PortRegister::new_mut(
-                    self.ptr.byte_add(0x400 + 0x10 * (port_number - 1)).cast(),
-                    self,
-                ))
-            }
-        }
+        let ptr = self.port_ptr(port_number)?;
+        // SAFETY: `ptr` is in bounds of the port register sets, so it is valid
+        Some(unsafe { PortRegister::new_mut(ptr, self) })
     }
 
     /// Get an iterator over the ports
     pub fn ports(&self) -> impl Iterator<Item = PortRegister<'_, Immutable>> {
-        // SAFETY: Each port is only produced once, so it is not possible to create two `PortRegister`
-        // structs for the same port. `PortRegister` contains a phantom mutable reference to the
-        (1..self.max_ports as usize).map(|port_number| unsafe {
-            PortRegister::new(self.ptr.byte_add(0x400 + 0x10 * (port_number - 1)).cast())
-        })
+        (1..=self.max_ports()).map(|port_number| self.port(port_number).unwrap())
     }
 
     /// Get an iterator over mutable the ports
     pub fn ports_mut(&mut self) -> impl Iterator<Item = PortRegister<'_, Mutable>> {
-        // SAFETY: Each port is only produced once, so it is not possible to create two `PortRegister`
-        // structs for the same port. `PortRegister` contains a phantom mutable reference to the
-        (1..self.max_ports as usize).map(|port_number| unsafe {
-            PortRegister::new_mut(
-                self.ptr.byte_add(0x400 + 0x10 * (port_number - 1)).cast(),
-                self,
-            )
+        (1..=self.max_ports()).map(|port_number| {
+            let ptr = self.port_ptr(port_number).unwrap();
+            // SAFETY: Each port is only produced once, so it is not possible to create two `PortRegister`
+            // structs for the same port. `ptr` is in bounds of the port register sets, so it is valid.
+            unsafe { PortRegister::new_mut(ptr, self) }
         })
     }
 
-    /// Reads the fields of the register and prints them in a debug format
-    pub fn debug(&self) {
+    /// Reads the fields of the register and prints them in a debug format.
+    ///
+    /// If `verbose` is `true`, each port's link state, speed, and raw [`StatusAndControl`] value are printed
+    /// even if no device is connected to it.
+    ///
+    /// [`StatusAndControl`]: port_registers::StatusAndControl
+    pub fn debug(&self, verbose: bool) {
         let fields = OperationalRegistersFields {
             usb_command: self.read_usb_command(),
             usb_status: self.read_usb_status(),
@@ -596,7 +615,24 @@ impl OperationalRegisters {
 
         for (i, port) in self.ports().enumerate() {
             print!("Port number {}: ", i + 1);
-            if port.read_status_and_control().device_connected() {
+            let status_and_control = port.read_status_and_control();
+            let connected = status_and_control.device_connected();
+            if verbose {
+                println!(
+                    "{}, link state {:?}, speed {}, status and control {:#010x}",
+                    if connected {
+                        "device connected"
+                    } else {
+                        "no device connected"
+                    },
+                    status_and_control.port_link_state(),
+                    status_and_control.port_speed(),
+                    status_and_control.into_bits(),
+                );
+                if connected {
+                    port.debug();
+                }
+            } else if connected {
                 port.debug();
             } else {
                 println!("no device connected");
@@ -622,3 +658,43 @@ fn test_xhci_operational_field_offsets() {
     assert_eq!(offset_of!(OperationalRegistersFields, device_context_base_address_array_pointer), 0x30);
     assert_eq!(offset_of!(OperationalRegistersFields, configure), 0x38);
 }
+
+/// Tests that [`SupportedPageSize`] picks the smallest page size when several are supported
+#[test_case]
+fn test_supported_page_sizes() {
+    let only_4k = SupportedPageSize(0b1);
+    assert_eq!(only_4k.page_size(), 0x1000);
+
+    let multiple = SupportedPageSize(0b1010);
+    assert_eq!(multiple.page_size(), 0x2000);
+
+    let mut sizes = multiple.supported_page_sizes();
+    assert_eq!(sizes.next(), Some(0x2000));
+    assert_eq!(sizes.next(), Some(0x8000));
+    assert_eq!(sizes.next(), None);
+}
+
+/// Tests that [`ports`][OperationalRegisters::ports] yields every port, including the last one
+#[test_case]
+fn test_xhci_ports_iterator() {
+    use alloc::vec;
+
+    const PORTS: usize = 4;
+
+    // The port registers start at offset 0x400, and are 0x10 bytes each
+    let mut memory = vec![0u64; (0x400 + 0x10 * PORTS) / 8];
+    // Mark a device as connected on the last port
+    memory[(0x400 + 0x10 * (PORTS - 1)) / 8] = 1;
+
+    // SAFETY: `memory` is big enough for the operational registers and `PORTS` port register sets,
+    // and outlives `registers`
+    let registers = unsafe { OperationalRegisters::from_ptr(memory.as_mut_ptr().cast(), PORTS) };
+
+    assert_eq!(registers.ports().count(), PORTS);
+    let last = registers.ports().last().unwrap();
+    assert!(last.read_status_and_control().device_connected());
+
+    assert!(registers.port(0).is_none());
+    assert!(registers.port(PORTS).is_some());
+    assert!(registers.port(PORTS + 1).is_none());
+}
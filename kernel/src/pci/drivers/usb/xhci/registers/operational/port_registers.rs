@@ -300,6 +300,21 @@ impl StatusAndControl {
             .with_port_config_error_change(false)
             .with_warm_reset(false)
     }
+
+    /// Like [`normalised`], but leaves every change bit which is set, so that writing this value back acknowledges
+    /// all pending changes without disabling or resetting the port.
+    ///
+    /// [`normalised`]: StatusAndControl::normalised
+    pub fn changes_acknowledged(self) -> Self {
+        self.normalised()
+            .with_connect_status_change(self.connect_status_change())
+            .with_port_enabled_change(self.port_enabled_change())
+            .with_warm_port_reset_change(self.warm_port_reset_change())
+            .with_over_current_change(self.over_current_change())
+            .with_port_reset_change(self.port_reset_change())
+            .with_port_link_state_change(self.port_link_state_change())
+            .with_port_config_error_change(self.port_config_error_change())
+    }
 }
 
 /// The behaviour of a connection in the U0 state transitioning to the U1 state
@@ -508,6 +523,22 @@ impl<'a, M: PortRegisterMutability> PortRegister<'a, M> {
 
         println!("{fields:#?}");
     }
+
+    /// Whether the port is powered, from the [`port_power`] field.
+    /// A port which is powered off doesn't detect device connections.
+    ///
+    /// [`port_power`]: StatusAndControl::port_power
+    pub fn powered(&self) -> bool {
+        self.read_status_and_control().port_power()
+    }
+
+    /// Whether the port currently has an over-current condition, from the [`over_current_active`] field.
+    /// The controller powers off a port with an over-current condition.
+    ///
+    /// [`over_current_active`]: StatusAndControl::over_current_active
+    pub fn over_current(&self) -> bool {
+        self.read_status_and_control().over_current_active()
+    }
 }
 
 impl<'a> PortRegister<'a, Immutable> {
@@ -585,12 +616,68 @@ impl<'a> PortRegister<'a, Mutable> {
         |v: &PortRegister<'a, Mutable>|!v.operational_registers.read_usb_status().host_controller_halted()
     );
 
-    /// Clears all `RW1C` bits in [`status_and_control`] by reading the field and writing it with the same value
+    /// Acknowledges every pending change in [`status_and_control`] by writing `true` to the change bits which are set.
+    ///
+    /// [`port_enabled`] is also `RW1C`, so it is written as `false` to avoid disabling the port.
+    /// See [`changes_acknowledged`].
     ///
     /// [`status_and_control`]: PortRegisterFields::status_and_control
+    /// [`port_enabled`]: StatusAndControl::port_enabled
+    /// [`changes_acknowledged`]: StatusAndControl::changes_acknowledged
     pub fn clear_status_and_control(&mut self) {
-        // SAFETY: This only clears flags, which has no effect on memory safety
-        unsafe { self.write_status_and_control(self.read_status_and_control()) };
+        let status_and_control = self.read_status_and_control().changes_acknowledged();
+
+        // SAFETY: This only clears change flags, which has no effect on memory safety
+        unsafe { self.write_status_and_control(status_and_control) };
+    }
+
+    /// Changes fields of [`status_and_control`] without affecting any of its other state.
+    ///
+    /// The change bits of [`status_and_control`] are `RW1CS` - writing `true` to them clears them, and they are
+    /// preserved across a controller reset. Writing back a value which was read from the register would acknowledge
+    /// every pending change, so the controller would never report them. Instead, the current value is read and
+    /// [`normalised`] so that these bits, as well as [`port_enabled`] and the reset bits, are written as `false`.
+    /// `f` is then applied to the value to set the fields which should change, and the result is written back.
+    ///
+    /// To acknowledge changes, `f` should set the relevant change bits to `true`.
+    ///
+    /// [`status_and_control`]: PortRegisterFields::status_and_control
+    /// [`normalised`]: StatusAndControl::normalised
+    /// [`port_enabled`]: StatusAndControl::port_enabled
+    pub fn modify_preserving_changes(
+        &mut self,
+        f: impl FnOnce(StatusAndControl) -> StatusAndControl,
+    ) {
+        let status_and_control = f(self.read_status_and_control().normalised());
+
+        // SAFETY: Changing the state of the port has no effect on memory safety
+        unsafe { self.write_status_and_control(status_and_control) };
+    }
+
+    /// Turns the port's power on or off by writing the [`port_power`] field.
+    /// Pending changes are not acknowledged - see [`modify_preserving_changes`].
+    ///
+    /// The controller may take some time to apply the change, so [`powered`] should be read to check that it has taken
+    /// effect before calling this again. See the spec section [4.19.4] for more info.
+    ///
+    /// [`port_power`]: StatusAndControl::port_power
+    /// [`modify_preserving_changes`]: PortRegister::modify_preserving_changes
+    /// [`powered`]: PortRegister::powered
+    /// [4.19.4]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A330%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C387%2C0%5D
+    pub fn set_power(&mut self, powered: bool) {
+        self.modify_preserving_changes(|s| s.with_port_power(powered));
+    }
+
+    /// Starts a reset of the port by writing the [`reset`] field.
+    /// Pending changes are not acknowledged - see [`modify_preserving_changes`].
+    ///
+    /// The controller will set [`port_reset_change`] when the reset completes.
+    ///
+    /// [`reset`]: StatusAndControl::reset
+    /// [`modify_preserving_changes`]: PortRegister::modify_preserving_changes
+    /// [`port_reset_change`]: StatusAndControl::port_reset_change
+    pub fn start_reset(&mut self) {
+        self.modify_preserving_changes(|s| s.with_reset(true));
     }
 }
 
@@ -604,3 +691,54 @@ impl<'a, M: PortRegisterMutability> Debug for PortRegister<'a, M> {
             .finish()
     }
 }
+
+/// Tests that [`StatusAndControl::normalised`] clears every `RW1C` change bit while preserving the port's power,
+/// so that [`PortRegister::set_power`] doesn't acknowledge unrelated changes.
+#[test_case]
+fn test_set_power_preserves_change_bits() {
+    let status = StatusAndControl::new()
+        .with_device_connected(true)
+        .with_port_enabled(true)
+        .with_port_power(true)
+        .with_connect_status_change(true)
+        .with_port_enabled_change(true)
+        .with_warm_port_reset_change(true)
+        .with_over_current_change(true)
+        .with_port_reset_change(true)
+        .with_port_link_state_change(true)
+        .with_port_config_error_change(true)
+        .with_wake_on_connect(true);
+
+    let written = status.normalised().with_port_power(false);
+
+    // Bits 1, 4, 17..=23 and 31 have write-1 side effects
+    let write_1_bits = 1 << 1 | 1 << 4 | 0b111_1111 << 17 | 1 << 31;
+    assert_eq!(u32::from(written) & write_1_bits, 0);
+
+    assert!(!written.port_power());
+    assert!(written.wake_on_connect());
+    assert!(status.normalised().port_power());
+}
+
+/// Tests that acknowledging changes writes back only the change bits which were set,
+/// and never writes to the port enabled or reset bits
+#[test_case]
+fn test_changes_acknowledged_preserves_port_enabled() {
+    let status = StatusAndControl::new()
+        .with_device_connected(true)
+        .with_port_enabled(true)
+        .with_port_power(true)
+        .with_connect_status_change(true)
+        .with_port_reset_change(true);
+
+    let written = status.changes_acknowledged();
+
+    assert!(!written.port_enabled());
+    assert!(!written.reset());
+    assert!(!written.warm_reset());
+    assert!(written.connect_status_change());
+    assert!(written.port_reset_change());
+    assert!(!written.port_enabled_change());
+    assert!(!written.over_current_change());
+    assert!(written.port_power());
+}
@@ -0,0 +1,340 @@
+//! Methods on [`XhciController`] for making control transfers to a device's default control endpoint,
+//! such as reading descriptors.
+
+use core::cell::RefCell;
+
+use alloc::{string::String, vec::Vec};
+use log::warn;
+
+use crate::{
+    allocator::PageBox,
+    pci::drivers::usb::{
+        descriptors::{
+            parse_language_ids, parse_string, ConfigurationDescriptor, DescriptorError,
+            DescriptorType,
+        },
+        requests::SetupPacket,
+    },
+};
+
+use super::{
+    super::{
+        registers::doorbell::DoorbellTarget,
+        trb::{
+            event::command_completion::{CompletionCode, CompletionError},
+            transfer::{
+                control::{DataStageTrb, SetupStageTrb, StatusStageTrb},
+                TransferTrb,
+            },
+            RingFullError,
+        },
+        XhciController, XhciError,
+    },
+    EventTrbError, TaskWaker, TransferEventError, TIMEOUT_1_SECOND,
+};
+
+/// The _Device Context Index_ of a device's default control endpoint
+pub const CONTROL_ENDPOINT_ID: u8 = 1;
+
+/// The language ID for English (United States), which is used to read string descriptors if the device supports it
+const LANGUAGE_ID_EN_US: u16 = 0x0409;
+
+/// An error occurring during a control transfer
+#[derive(Debug, Clone, Copy)]
+pub enum ControlTransferError {
+    /// There is no [`Device`] in the given slot
+    ///
+    /// [`Device`]: super::super::device::Device
+    NoSuchDevice(u8),
+    /// The device's control transfer ring was full
+    RingFull(RingFullError),
+    /// The transfer failed or timed out
+    Transfer(TransferEventError),
+    /// A descriptor read from the device was malformed
+    Descriptor(DescriptorError),
+}
+
+impl From<RingFullError> for ControlTransferError {
+    fn from(v: RingFullError) -> Self {
+        Self::RingFull(v)
+    }
+}
+
+impl From<TransferEventError> for ControlTransferError {
+    fn from(v: TransferEventError) -> Self {
+        Self::Transfer(v)
+    }
+}
+
+impl From<DescriptorError> for ControlTransferError {
+    fn from(v: DescriptorError) -> Self {
+        Self::Descriptor(v)
+    }
+}
+
+impl XhciController {
+    /// Performs a control transfer on the default control endpoint of the device in the given slot, and waits for it to complete.
+    ///
+    /// If the request has a data stage, the data is read into a page-sized buffer, so `packet.length` can be at most 4096.
+    /// The returned [`Vec`] contains the bytes sent by the device, which may be fewer than `packet.length`.
+    /// Requests with a host-to-device data stage are not supported.
+    ///
+    /// # Safety
+    /// The caller is responsible for the behaviour of the device in response to the request
+    pub(super) async unsafe fn control_transfer(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+        packet: SetupPacket,
+    ) -> Result<Vec<u8>, ControlTransferError> {
+        assert!(usize::from(packet.length) <= 0x1000);
+        assert!(
+            packet.length == 0 || packet.is_device_to_host(),
+            "Host-to-device data stages are not supported"
+        );
+
+        let buffer = PageBox::new_zeroed();
+        let has_data_stage = packet.length != 0;
+
+        let status_trb_addr = {
+            let mut controller_borrow = controller.borrow_mut();
+            let ring = controller_borrow
+                .devices
+                .get_mut(&slot_id)
+                .ok_or(ControlTransferError::NoSuchDevice(slot_id))?
+                .control_ring_mut();
+
+            // SAFETY: The caller is responsible for the behaviour of the device.
+            // `buffer` is not dropped until the transfer has completed, or leaked if it times out.
+            let status_trb_addr = unsafe {
+                ring.enqueue(TransferTrb::SetupStage(SetupStageTrb::new(packet)))?;
+
+                if has_data_stage {
+                    ring.enqueue(TransferTrb::DataStage(DataStageTrb::new(
+                        buffer.phys_frame().start_address(),
+                        packet.length,
+                        true,
+                    )))?;
+                }
+
+                // The status stage is in the opposite direction to the data stage, or IN if there is no data stage
+                ring.enqueue(TransferTrb::StatusStage(StatusStageTrb::new(
+                    !has_data_stage,
+                )))?
+            };
+
+            controller_borrow
+                .doorbell_registers
+                .device_doorbell(slot_id)
+                .ring(DoorbellTarget::ControlEndpoint);
+
+            status_trb_addr
+        };
+
+        let mut transferred = u32::from(packet.length);
+
+        // Wait for the status stage to complete.
+        // If the device sends less data than requested, there will also be an event for the data stage first.
+        loop {
+            match t
+                .wait_for_transfer_event(slot_id, CONTROL_ENDPOINT_ID, TIMEOUT_1_SECOND)
+                .await
+            {
+                Ok(trb) if trb.trb_pointer == status_trb_addr => break,
+                Ok(_) => (),
+                Err(EventTrbError::CompletionError(
+                    XhciError::Completion(CompletionCode::Error(CompletionError::ShortPacket)),
+                    trb,
+                )) => transferred = transferred.saturating_sub(trb.transfer_length),
+                Err(
+                    e @ EventTrbError::CompletionError(
+                        XhciError::Completion(CompletionCode::Error(CompletionError::Stall)),
+                        _,
+                    ),
+                ) => {
+                    // A stall halts the endpoint, so it needs to be reset before any more requests can be made
+                    if let Err(recovery_error) =
+                        Self::recover_endpoint(controller, t, slot_id, CONTROL_ENDPOINT_ID).await
+                    {
+                        warn!("Failed to recover control endpoint of slot {slot_id} after a stall: {recovery_error:?}");
+                    }
+
+                    return Err(e.into());
+                }
+                Err(e @ EventTrbError::TimeoutReached(_)) => {
+                    // The controller may still write to the buffer, so it can't be freed
+                    core::mem::forget(buffer);
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // SAFETY: The transfer has completed, so the controller is no longer writing to the buffer
+        let data = unsafe { buffer.as_ptr::<[u8; 0x1000]>().read_volatile() };
+
+        Ok(data[..usize::try_from(transferred).unwrap()].to_vec())
+    }
+
+    /// Reads a descriptor from the device in the given slot. The first 2 bytes are read to find the descriptor's
+    /// `bLength` field, and then the whole descriptor is read.
+    ///
+    /// `language_id` is only used for string descriptors, and should be 0 otherwise.
+    pub(super) async fn read_descriptor(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+        descriptor_type: DescriptorType,
+        index: u8,
+        language_id: u16,
+    ) -> Result<Vec<u8>, ControlTransferError> {
+        // SAFETY: GET_DESCRIPTOR requests don't change the state of the device
+        let header = unsafe {
+            Self::control_transfer(
+                controller,
+                t,
+                slot_id,
+                SetupPacket::get_descriptor(descriptor_type, index, language_id, 2),
+            )
+            .await?
+        };
+
+        let Some(&length) = header.first() else {
+            return Err(DescriptorError::TooShort.into());
+        };
+
+        // SAFETY: GET_DESCRIPTOR requests don't change the state of the device
+        unsafe {
+            Self::control_transfer(
+                controller,
+                t,
+                slot_id,
+                SetupPacket::get_descriptor(descriptor_type, index, language_id, length.into()),
+            )
+            .await
+        }
+    }
+
+    /// Reads the configuration descriptor with the given index from the device in the given slot, together with
+    /// all the interface, endpoint, and class-specific descriptors which follow it. The first 9 bytes are read to find
+    /// the descriptor's `wTotalLength` field, and then all the descriptors are read.
+    ///
+    /// The returned buffer can be parsed using [`ConfigurationDescriptorSet::parse`].
+    ///
+    /// [`ConfigurationDescriptorSet::parse`]: crate::pci::drivers::usb::descriptors::ConfigurationDescriptorSet::parse
+    pub(super) async fn read_configuration_descriptor(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+        index: u8,
+    ) -> Result<Vec<u8>, ControlTransferError> {
+        #[allow(clippy::cast_possible_truncation)] // The length is 9
+        let header_length = ConfigurationDescriptor::LENGTH as u16;
+
+        // SAFETY: GET_DESCRIPTOR requests don't change the state of the device
+        let header = unsafe {
+            Self::control_transfer(
+                controller,
+                t,
+                slot_id,
+                SetupPacket::get_descriptor(DescriptorType::Configuration, index, 0, header_length),
+            )
+            .await?
+        };
+
+        // Control transfers are limited to one page
+        let total_length = ConfigurationDescriptor::parse(&header)?
+            .total_length
+            .min(0x1000);
+
+        // SAFETY: GET_DESCRIPTOR requests don't change the state of the device
+        unsafe {
+            Self::control_transfer(
+                controller,
+                t,
+                slot_id,
+                SetupPacket::get_descriptor(DescriptorType::Configuration, index, 0, total_length),
+            )
+            .await
+        }
+    }
+
+    /// Gets the language ID to read the string descriptors of the device in the given slot with.
+    /// This is English (United States) if the device supports it, or otherwise the first language the device lists.
+    /// The language ID is read from string descriptor zero the first time it is needed and then cached on the [`Device`].
+    ///
+    /// Returns [`None`] if the device does not list any languages.
+    ///
+    /// [`Device`]: super::super::device::Device
+    async fn language_id(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+    ) -> Result<Option<u16>, ControlTransferError> {
+        let cached = controller
+            .borrow()
+            .devices
+            .get(&slot_id)
+            .ok_or(ControlTransferError::NoSuchDevice(slot_id))?
+            .language_id;
+
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        let bytes =
+            Self::read_descriptor(controller, t, slot_id, DescriptorType::String, 0, 0).await?;
+        let language_ids: Vec<u16> = parse_language_ids(&bytes)?.collect();
+
+        let language_id = language_ids
+            .iter()
+            .copied()
+            .find(|&id| id == LANGUAGE_ID_EN_US)
+            .or(language_ids.first().copied());
+
+        if let Some(device) = controller.borrow_mut().devices.get_mut(&slot_id) {
+            device.language_id = language_id;
+        }
+
+        Ok(language_id)
+    }
+
+    /// Reads the string descriptor with the given index from the device in the given slot, and decodes it.
+    ///
+    /// Returns [`None`] if `index` is 0 (which devices use to indicate that they don't have a string),
+    /// if the device doesn't support any languages, or if the string couldn't be read.
+    pub(super) async fn read_string(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+        index: u8,
+    ) -> Option<String> {
+        if index == 0 {
+            return None;
+        }
+
+        let result = async {
+            let Some(language_id) = Self::language_id(controller, t, slot_id).await? else {
+                return Ok(None);
+            };
+
+            let bytes = Self::read_descriptor(
+                controller,
+                t,
+                slot_id,
+                DescriptorType::String,
+                index,
+                language_id,
+            )
+            .await?;
+
+            Ok::<_, ControlTransferError>(Some(parse_string(&bytes)?))
+        }
+        .await;
+
+        result.unwrap_or_else(|e| {
+            warn!("Failed to read string {index} from device in slot {slot_id}: {e:?}");
+            None
+        })
+    }
+}
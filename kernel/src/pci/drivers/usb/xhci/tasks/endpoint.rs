@@ -0,0 +1,301 @@
+//! Methods on [`XhciController`] for managing a device's endpoints, such as recovering an endpoint after a stall,
+//! updating the default control endpoint's max packet size, or routing an endpoint's events to another interrupter.
+
+use core::cell::RefCell;
+
+use super::{
+    super::{
+        trb::{
+            command::{
+                configure_endpoint::ConfigureEndpointTrb,
+                endpoint::{ResetEndpointTrb, SetTrDequeuePointerTrb},
+                evaluate_context::EvaluateContextTrb,
+            },
+            CommandTrb,
+        },
+        XhciController,
+    },
+    control_transfer::CONTROL_ENDPOINT_ID,
+    CommandCompletionError, TaskWaker, TIMEOUT_1_SECOND,
+};
+
+/// An error occurring while recovering an endpoint with [`recover_endpoint`]
+///
+/// [`recover_endpoint`]: XhciController::recover_endpoint
+#[derive(Debug, Clone, Copy)]
+pub enum EndpointRecoveryError {
+    /// There is no configured endpoint with the given endpoint ID in the given slot
+    NoSuchEndpoint {
+        /// The slot ID of the device
+        slot_id: u8,
+        /// The _Device Context Index_ of the endpoint
+        endpoint_id: u8,
+    },
+    /// The _Reset Endpoint_ command failed
+    ResetEndpoint(CommandCompletionError),
+    /// The _Set TR Dequeue Pointer_ command failed
+    SetTrDequeuePointer(CommandCompletionError),
+}
+
+/// An error occurring while updating a device's contexts with an _Evaluate Context_ command
+#[derive(Debug, Clone, Copy)]
+pub enum EvaluateContextError {
+    /// There is no [`Device`] in the given slot
+    ///
+    /// [`Device`]: super::super::device::Device
+    NoSuchDevice(u8),
+    /// The _Evaluate Context_ command failed
+    EvaluateContext(CommandCompletionError),
+}
+
+/// An error occurring while deconfiguring a device with [`deconfigure_device`]
+///
+/// [`deconfigure_device`]: XhciController::deconfigure_device
+#[derive(Debug, Clone, Copy)]
+pub enum DeconfigureError {
+    /// There is no [`Device`] in the given slot
+    ///
+    /// [`Device`]: super::super::device::Device
+    NoSuchDevice(u8),
+    /// The _Configure Endpoint_ command with the _Deconfigure_ bit set failed
+    ConfigureEndpoint(CommandCompletionError),
+}
+
+/// An error occurring while routing an endpoint's events with [`route_endpoint_events`]
+///
+/// [`route_endpoint_events`]: XhciController::route_endpoint_events
+#[derive(Debug, Clone, Copy)]
+pub enum InterrupterRoutingError {
+    /// There is no configured endpoint with the given endpoint ID in the given slot
+    NoSuchEndpoint {
+        /// The slot ID of the device
+        slot_id: u8,
+        /// The _Device Context Index_ of the endpoint
+        endpoint_id: u8,
+    },
+    /// The controller doesn't have an interrupter with the given index
+    NoSuchInterrupter(u16),
+}
+
+impl XhciController {
+    /// Routes the events for transfers on an endpoint to the interrupter with index `interrupter`, by setting the
+    /// _Interrupter Target_ field of TRBs queued on the endpoint's transfer ring from now on.
+    /// If the interrupter isn't the primary interrupter, it is added to the interrupters polled by [`main_loop`],
+    /// so that a high-throughput endpoint's events can be kept separate from the rest of the controller's.
+    ///
+    /// Command completion and port status change events are always sent to the primary interrupter.
+    ///
+    /// See the spec section [4.17] for more info.
+    ///
+    /// [`main_loop`]: XhciController::main_loop
+    /// [4.17]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A293%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C267%2C0%5D
+    #[allow(dead_code)] // Not yet used by any class driver
+    pub(super) fn route_endpoint_events(
+        &mut self,
+        slot_id: u8,
+        endpoint_id: u8,
+        interrupter: u16,
+    ) -> Result<(), InterrupterRoutingError> {
+        if usize::from(interrupter) >= self.interrupters.len() {
+            return Err(InterrupterRoutingError::NoSuchInterrupter(interrupter));
+        }
+
+        let ring = self
+            .devices
+            .get_mut(&slot_id)
+            .and_then(|device| match endpoint_id {
+                CONTROL_ENDPOINT_ID => Some(device.control_ring_mut()),
+                endpoint_id => device.endpoint_ring_mut(endpoint_id),
+            })
+            .ok_or(InterrupterRoutingError::NoSuchEndpoint {
+                slot_id,
+                endpoint_id,
+            })?;
+
+        ring.set_interrupter_target(interrupter);
+
+        if interrupter != 0 && !self.secondary_interrupters.contains(&interrupter) {
+            self.secondary_interrupters.push(interrupter);
+        }
+
+        Ok(())
+    }
+
+    /// Recovers an endpoint which has halted because of a stall or transaction error.
+    ///
+    /// The endpoint is reset with a _Reset Endpoint_ command, and then any TRBs remaining on its transfer ring are
+    /// skipped with a _Set TR Dequeue Pointer_ command, so the next TRB to be queued will be the next one executed.
+    /// The endpoint will restart when its doorbell is next rung.
+    ///
+    /// See the spec section [4.6.8] for more info.
+    ///
+    /// [4.6.8]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+    pub(super) async fn recover_endpoint(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+        endpoint_id: u8,
+    ) -> Result<(), EndpointRecoveryError> {
+        let no_such_endpoint = EndpointRecoveryError::NoSuchEndpoint {
+            slot_id,
+            endpoint_id,
+        };
+
+        if !controller.borrow().devices.contains_key(&slot_id) {
+            return Err(no_such_endpoint);
+        }
+
+        // SAFETY: Resetting the endpoint doesn't restart it, and its transfer state is discarded,
+        // so the controller won't execute any more TRBs from its ring until the dequeue pointer is set below.
+        let trb_addr = unsafe {
+            XhciController::write_command_trb_wait(
+                controller,
+                CommandTrb::ResetEndpoint(ResetEndpointTrb {
+                    slot_id,
+                    endpoint_id,
+                    transfer_state_preserve: false,
+                }),
+            )
+            .await
+        };
+
+        t.wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+            .await
+            .map_err(EndpointRecoveryError::ResetEndpoint)?;
+
+        let (dequeue_pointer, dequeue_cycle_state) = {
+            let mut controller_borrow = controller.borrow_mut();
+            let ring = controller_borrow
+                .devices
+                .get_mut(&slot_id)
+                .and_then(|device| match endpoint_id {
+                    CONTROL_ENDPOINT_ID => Some(device.control_ring_mut()),
+                    endpoint_id => device.endpoint_ring_mut(endpoint_id),
+                })
+                .ok_or(no_such_endpoint)?;
+
+            // SAFETY: The endpoint has just been reset so is in the stopped state,
+            // and its dequeue pointer is set to the returned address below.
+            unsafe { ring.skip_pending() }
+        };
+
+        // SAFETY: The new dequeue pointer is the enqueue pointer of the endpoint's ring,
+        // so the controller will execute the next TRB queued on the ring.
+        let trb_addr = unsafe {
+            XhciController::write_command_trb_wait(
+                controller,
+                CommandTrb::SetTRDequeuePointer(SetTrDequeuePointerTrb {
+                    slot_id,
+                    endpoint_id,
+                    dequeue_pointer,
+                    dequeue_cycle_state,
+                }),
+            )
+            .await
+        };
+
+        t.wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+            .await
+            .map_err(EndpointRecoveryError::SetTrDequeuePointer)?;
+
+        Ok(())
+    }
+
+    /// Updates the max packet size of the default control endpoint of the device in the given slot.
+    ///
+    /// The endpoint's context in the device's input context is updated, and only its add context flag is set,
+    /// so that an _Evaluate Context_ command doesn't change the slot context or any other endpoints.
+    ///
+    /// This is needed for full-speed devices, whose max packet size isn't known until the device descriptor is read.
+    /// See the spec section [4.6.7] for more info.
+    ///
+    /// [4.6.7]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+    pub(super) async fn update_control_max_packet_size(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+        max_packet_size: u16,
+    ) -> Result<(), EvaluateContextError> {
+        let input_context_pointer = {
+            let mut controller_borrow = controller.borrow_mut();
+            let input_context = controller_borrow
+                .devices
+                .get_mut(&slot_id)
+                .ok_or(EvaluateContextError::NoSuchDevice(slot_id))?
+                .input_context_mut();
+
+            let ep_context_0 = input_context
+                .device_context()
+                .get_ep_context_0()
+                .with_max_packet_size(max_packet_size);
+
+            // SAFETY: The controller only reads the input context while executing a command, and no command is in progress.
+            // Only the default control endpoint's context is evaluated, so the slot context isn't changed.
+            unsafe {
+                input_context
+                    .input_control_context_mut()
+                    .write_add_context_flags(1 << CONTROL_ENDPOINT_ID);
+                input_context
+                    .device_context_mut()
+                    .set_ep_context_0(ep_context_0);
+            }
+
+            input_context.phys_addr()
+        };
+
+        // SAFETY: The input context is kept alive in `devices`, and only changes the default control endpoint's max packet size
+        let trb_addr = unsafe {
+            XhciController::write_command_trb_wait(
+                controller,
+                CommandTrb::EvaluateContext(EvaluateContextTrb {
+                    input_context_pointer,
+                    slot_id,
+                }),
+            )
+            .await
+        };
+
+        t.wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+            .await
+            .map_err(EvaluateContextError::EvaluateContext)?;
+
+        Ok(())
+    }
+
+    /// Deconfigures all of the endpoints of the device in the given slot except the default control endpoint,
+    /// using a _Configure Endpoint_ command with the _Deconfigure_ bit set, and then frees their transfer rings.
+    ///
+    /// This is used when a device is disconnected, so that the controller stops scheduling transfers to it.
+    /// See the spec section [4.6.6] for more info.
+    ///
+    /// [4.6.6]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A122%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C511%2C0%5D
+    pub(super) async fn deconfigure_device(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+    ) -> Result<(), DeconfigureError> {
+        if !controller.borrow().devices.contains_key(&slot_id) {
+            return Err(DeconfigureError::NoSuchDevice(slot_id));
+        }
+
+        // SAFETY: The device's endpoints are no longer used, and their rings aren't freed until the command completes
+        let trb_addr = unsafe {
+            XhciController::write_command_trb_wait(
+                controller,
+                CommandTrb::ConfigureEndpoint(ConfigureEndpointTrb::deconfigure(slot_id)),
+            )
+            .await
+        };
+
+        t.wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+            .await
+            .map_err(DeconfigureError::ConfigureEndpoint)?;
+
+        if let Some(device) = controller.borrow_mut().devices.get_mut(&slot_id) {
+            // SAFETY: The endpoints were just deconfigured, so the controller no longer accesses their rings
+            unsafe { device.clear_endpoint_rings() };
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+//! The [`enumerate_device`] function, which assigns a newly attached device a slot and address, and reads its descriptors
+
+use core::cell::RefCell;
+
+use log::debug;
+
+use crate::pci::drivers::usb::{
+    descriptors::{DescriptorType, DeviceDescriptor},
+    hub::HUB_CLASS,
+    register_device,
+    requests::SetupPacket,
+    RouteString, UsbDeviceInfo,
+};
+
+use super::{
+    super::{
+        device::{Device, TransactionTranslator, PORT_SPEED_SUPER},
+        trb::{
+            command::{address_device::AddressDeviceTrb, slot::EnableSlotTrb},
+            event::command_completion::{CompletionCode, CompletionError},
+            CommandTrb,
+        },
+        XhciController,
+    },
+    control_transfer::ControlTransferError,
+    endpoint::EvaluateContextError,
+    CommandCompletionError, TaskWaker, TIMEOUT_1_SECOND,
+};
+
+/// An error occurring while enumerating a device
+#[derive(Debug, Clone, Copy)]
+pub enum EnumerationError {
+    /// The _Enable Slot_ command failed because all of the controller's device slots are in use
+    NoSlotsAvailable,
+    /// The _Enable Slot_ command failed
+    EnableSlot(CommandCompletionError),
+    /// The _Address Device_ command failed
+    AddressDevice(CommandCompletionError),
+    /// Reading the device descriptor failed
+    DeviceDescriptor(ControlTransferError),
+    /// Updating the default control endpoint's max packet size failed
+    EvaluateContext(EvaluateContextError),
+}
+
+/// Enumerates a device which has been attached to a root hub port or an external hub's port, and whose port has been enabled.
+///
+/// A device slot is enabled for the device, and the device is given an address with an _Address Device_ command.
+/// The device's [`DeviceDescriptor`] and its manufacturer, product, and serial number strings are then read and
+/// stored on a [`Device`] in the controller's [`devices`].
+///
+/// If the device is a hub, its slot ID is added to the controller's [`new_hubs`] so that a task is started to drive it.
+///
+/// Returns the ID of the slot assigned to the device.
+///
+/// # Parameters
+/// * `root_port` is the root hub port the device is connected through
+/// * `route_string` is the path from the root hub port to the device, which is [`RouteString::ROOT`] for devices
+///    connected directly to a root hub port
+/// * `port_speed` is the speed of the device, as the value of a root hub port's [`port_speed`] field
+/// * `transaction_translator` is the high-speed hub the device is connected through, if the device is low- or full-speed
+///
+/// See the spec section [4.3] for the steps taken.
+///
+/// [`devices`]: XhciController::devices
+/// [`new_hubs`]: XhciController::new_hubs
+/// [`port_speed`]: super::super::registers::operational::port_registers::StatusAndControl::port_speed
+/// [4.3]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A90%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C658%2C0%5D
+pub(super) async fn enumerate_device(
+    controller: &RefCell<XhciController>,
+    t: &TaskWaker,
+    root_port: u8,
+    route_string: RouteString,
+    port_speed: u8,
+    transaction_translator: Option<TransactionTranslator>,
+) -> Result<u8, EnumerationError> {
+    // Devices behind hubs use the slot type of the root hub port they are connected through
+    let slot_type = controller
+        .borrow()
+        .extended_capability_registers
+        .as_ref()
+        .and_then(|e| e.slot_type(root_port))
+        .unwrap_or(0);
+
+    // SAFETY: Enabling a slot doesn't affect any existing slots
+    let trb_addr = unsafe {
+        XhciController::write_command_trb_wait(
+            controller,
+            CommandTrb::EnableSlot(EnableSlotTrb::new().with_slot_type(slot_type)),
+        )
+        .await
+    };
+
+    let slot_id = t
+        .wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+        .await
+        .map_err(|e| match e.completion_code() {
+            Some(CompletionCode::Error(CompletionError::NoSlotsAvailable)) => {
+                EnumerationError::NoSlotsAvailable
+            }
+            _ => EnumerationError::EnableSlot(e),
+        })?
+        .flags
+        .slot_id();
+
+    // SAFETY: The slot has just been enabled, so the controller isn't using its device context yet
+    unsafe { controller.borrow_mut().dcbaa.init_slot(slot_id) };
+
+    let input_context_pointer = {
+        let mut controller_borrow = controller.borrow_mut();
+        let page_size = controller_borrow.operational_registers.read_page_size();
+        let context_size = controller_borrow
+            .capability_registers
+            .capability_parameters_1()
+            .context_size();
+
+        let device = Device::new(
+            slot_id,
+            root_port,
+            route_string,
+            port_speed,
+            transaction_translator,
+            page_size,
+            context_size,
+        );
+        let input_context_pointer = device.input_context().phys_addr();
+        controller_borrow.devices.insert(slot_id, device);
+
+        input_context_pointer
+    };
+
+    // SAFETY: The input context was set up for this slot by `Device::new`, and is kept alive in `devices`
+    let trb_addr = unsafe {
+        XhciController::write_command_trb_wait(
+            controller,
+            CommandTrb::AddressDevice(AddressDeviceTrb {
+                input_context_pointer,
+                slot_id,
+                block_set_address_request: false,
+            }),
+        )
+        .await
+    };
+
+    t.wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+        .await
+        .map_err(EnumerationError::AddressDevice)?;
+
+    // Only the first 8 bytes are read at first, as the default control endpoint's max packet size may be
+    // smaller than the whole descriptor.
+    // SAFETY: GET_DESCRIPTOR requests don't change the state of the device
+    let descriptor_start = unsafe {
+        XhciController::control_transfer(
+            controller,
+            t,
+            slot_id,
+            SetupPacket::get_descriptor(DescriptorType::Device, 0, 0, 8),
+        )
+        .await
+        .map_err(EnumerationError::DeviceDescriptor)?
+    };
+
+    // `bMaxPacketSize0` is the last of the first 8 bytes. For SuperSpeed devices it is an exponent.
+    if let Some(&max_packet_size_0) = descriptor_start.get(7) {
+        let max_packet_size = if port_speed == PORT_SPEED_SUPER {
+            1u16.checked_shl(max_packet_size_0.into()).unwrap_or(512)
+        } else {
+            max_packet_size_0.into()
+        };
+
+        let current_max_packet_size = controller.borrow().devices.get(&slot_id).map(|device| {
+            device
+                .input_context()
+                .device_context()
+                .get_ep_context_0()
+                .max_packet_size()
+        });
+
+        if current_max_packet_size.is_some_and(|current| current != max_packet_size) {
+            debug!("Updating max packet size of slot {slot_id} to {max_packet_size}");
+
+            XhciController::update_control_max_packet_size(controller, t, slot_id, max_packet_size)
+                .await
+                .map_err(EnumerationError::EvaluateContext)?;
+        }
+    }
+
+    let descriptor =
+        XhciController::read_descriptor(controller, t, slot_id, DescriptorType::Device, 0, 0)
+            .await
+            .and_then(|bytes| Ok(DeviceDescriptor::parse(&bytes)?))
+            .map_err(EnumerationError::DeviceDescriptor)?;
+
+    let manufacturer =
+        XhciController::read_string(controller, t, slot_id, descriptor.manufacturer_index).await;
+    let product =
+        XhciController::read_string(controller, t, slot_id, descriptor.product_index).await;
+    let serial_number =
+        XhciController::read_string(controller, t, slot_id, descriptor.serial_number_index).await;
+
+    debug!(
+        "USB device {:04x}:{:04x} on port {root_port} route {route_string:?} (slot {slot_id}): manufacturer {manufacturer:?}, product {product:?}, serial number {serial_number:?}",
+        descriptor.vendor_id, descriptor.product_id,
+    );
+
+    let mut controller_borrow = controller.borrow_mut();
+    let controller_function = controller_borrow.function;
+
+    if let Some(device) = controller_borrow.devices.get_mut(&slot_id) {
+        register_device(UsbDeviceInfo {
+            controller: controller_function,
+            slot_id,
+            root_port: device.root_port(),
+            route_string: device.route_string(),
+            speed: device.usb_speed(),
+            descriptor,
+            manufacturer: manufacturer.clone(),
+            product: product.clone(),
+        });
+
+        device.descriptor = Some(descriptor);
+        device.manufacturer = manufacturer;
+        device.product = product;
+        device.serial_number = serial_number;
+    }
+
+    if descriptor.device_class == HUB_CLASS {
+        controller_borrow.new_hubs.push(slot_id);
+    }
+
+    Ok(slot_id)
+}
@@ -0,0 +1,554 @@
+//! The [`handle_hub`] task and [`HubTask`] type alias, which configure an external USB2 hub and enumerate the
+//! devices connected to its downstream ports.
+//!
+//! The hub class is defined in chapter 11 of the [USB2 specification].
+//!
+//! [USB2 specification]: https://www.usb.org/document-library/usb-20-specification
+
+use core::cell::RefCell;
+
+use futures::Future;
+use log::{debug, warn};
+
+use crate::{
+    allocator::PageBox,
+    pci::drivers::usb::{
+        descriptors::{
+            ConfigurationDescriptorSet, DescriptorError, DescriptorType, EndpointDescriptor,
+            EndpointDirection, EndpointTransferType, HubDescriptor,
+        },
+        hub::{HubPortFeature, HubPortStatus, HUB_CLASS},
+        requests::SetupPacket,
+        RouteString,
+    },
+};
+
+use super::{
+    super::{
+        contexts::endpoint_context::{EndpointContext, EndpointType},
+        device::{
+            interrupt_interval, TransactionTranslator, PORT_SPEED_FULL, PORT_SPEED_HIGH,
+            PORT_SPEED_LOW, PORT_SPEED_SUPER,
+        },
+        registers::doorbell::DoorbellTarget,
+        trb::{
+            command::configure_endpoint::{ConfigureEndpointTrb, InputContextPointer},
+            event::command_completion::{CompletionCode, CompletionError},
+            transfer::{normal::NormalTrb, TransferTrb},
+            CommandTrb,
+        },
+        XhciController, XhciError,
+    },
+    control_transfer::ControlTransferError,
+    enumeration::{enumerate_device, EnumerationError},
+    CommandCompletionError, EventTrbError, TaskWaker, TransferEventError, TIMEOUT_1_SECOND,
+};
+
+/// The type of the future produced by [`handle_hub_inner`], and stored in [`Hub`] tasks
+///
+/// [`Hub`]: super::TaskType::Hub
+pub type HubTask<'a> = impl Future<Output = Result<(), Error>> + 'a;
+
+/// The time to wait between polls of a port which is being reset, in nanoseconds
+const RESET_POLL_INTERVAL_NS: usize = 10_000_000;
+/// The number of times to poll a port which is being reset before giving up
+const RESET_POLL_ATTEMPTS: usize = 10;
+
+/// An error occurring during the execution of [`handle_hub`]
+#[derive(Debug, Clone, Copy)]
+pub struct Error {
+    /// The slot ID of the hub
+    slot_id: u8,
+    /// The type of error which occurred
+    kind: ErrorKind,
+}
+
+/// A type of [`Error`]
+#[derive(Debug, Clone, Copy)]
+enum ErrorKind {
+    /// A control transfer to the hub failed
+    ControlTransfer(ControlTransferError),
+    /// The hub is a SuperSpeed hub, which are not supported yet
+    SuperSpeed,
+    /// The hub's configuration has no interrupt IN endpoint to report status changes on
+    NoStatusChangeEndpoint,
+    /// The _Configure Endpoint_ command failed
+    ConfigureEndpoint(CommandCompletionError),
+    /// The status change endpoint's transfer ring was full
+    RingFull,
+    /// A transfer on the status change endpoint failed
+    StatusChange(TransferEventError),
+}
+
+impl From<ControlTransferError> for ErrorKind {
+    fn from(v: ControlTransferError) -> Self {
+        Self::ControlTransfer(v)
+    }
+}
+
+/// The properties of a hub which are needed to enumerate the devices connected to it
+#[derive(Debug, Clone, Copy)]
+struct HubInfo {
+    /// The slot ID of the hub
+    slot_id: u8,
+    /// The root hub port the hub is connected through
+    root_port: u8,
+    /// The route from the root hub port to the hub
+    route_string: RouteString,
+    /// The speed of the hub
+    port_speed: u8,
+    /// The transaction translator the hub itself is connected through, if it is a full-speed hub
+    transaction_translator: Option<TransactionTranslator>,
+}
+
+/// Configures the hub in the given slot, powers on its ports, and then enumerates devices as they are connected to it.
+/// The task runs until the hub's slot is disabled.
+async fn handle_hub_inner(
+    controller: &RefCell<XhciController>,
+    t: &TaskWaker,
+    slot_id: u8,
+) -> Result<(), ErrorKind> {
+    let hub_info = {
+        let controller_borrow = controller.borrow();
+        let device = controller_borrow
+            .devices
+            .get(&slot_id)
+            .ok_or(ControlTransferError::NoSuchDevice(slot_id))?;
+
+        HubInfo {
+            slot_id,
+            root_port: device.root_port(),
+            route_string: device.route_string(),
+            port_speed: device.port_speed(),
+            transaction_translator: device.transaction_translator(),
+        }
+    };
+
+    // SuperSpeed hubs use a different hub descriptor and port status format
+    if hub_info.port_speed == PORT_SPEED_SUPER {
+        return Err(ErrorKind::SuperSpeed);
+    }
+
+    // Find the hub's status change endpoint, which is an interrupt IN endpoint of the hub interface
+    let configuration_bytes =
+        XhciController::read_configuration_descriptor(controller, t, slot_id, 0).await?;
+    let configuration = ConfigurationDescriptorSet::parse(&configuration_bytes)
+        .map_err(ControlTransferError::from)?;
+
+    let endpoint = configuration
+        .interfaces()
+        .filter_map(Result::ok)
+        .filter(|interface| interface.descriptor.interface_class == HUB_CLASS)
+        .flat_map(|interface| interface.endpoints().filter_map(Result::ok))
+        .find(|endpoint| {
+            endpoint.direction() == EndpointDirection::In
+                && endpoint.transfer_type() == EndpointTransferType::Interrupt
+        })
+        .ok_or(ErrorKind::NoStatusChangeEndpoint)?;
+
+    // SAFETY: Selecting the hub's configuration enables its status change endpoint, which has not been configured
+    // on the controller yet so no transfers will be made to it.
+    unsafe {
+        XhciController::control_transfer(
+            controller,
+            t,
+            slot_id,
+            SetupPacket::set_configuration(configuration.configuration.configuration_value),
+        )
+        .await?;
+    }
+
+    let hub = read_hub_descriptor(controller, t, slot_id).await?;
+
+    debug!(
+        "Hub in slot {slot_id} has {} ports, status change endpoint {}",
+        hub.num_ports,
+        endpoint.endpoint_number()
+    );
+
+    let endpoint_id = configure_hub(controller, t, &hub_info, &hub, &endpoint).await?;
+
+    // Power on all the ports, and wait for the power to be stable
+    for port in 1..=hub.num_ports {
+        // SAFETY: No devices can be connected through the port before it is powered
+        unsafe {
+            XhciController::control_transfer(
+                controller,
+                t,
+                slot_id,
+                SetupPacket::set_port_feature(port, HubPortFeature::Power),
+            )
+            .await?;
+        }
+    }
+
+    let power_good_delay_ns = usize::try_from(hub.power_good_delay_ms() * 1_000_000).unwrap();
+    t.wait_for_timeout(power_good_delay_ns).await;
+
+    // The status change bitmap has one bit for the hub itself and one for each port
+    let buffer = PageBox::new_zeroed();
+    let bitmap_length = u32::from(hub.num_ports) / 8 + 1;
+
+    loop {
+        // Queue a transfer to read the status change bitmap, which the hub will only complete once something changes
+        {
+            let mut controller_borrow = controller.borrow_mut();
+            let ring = controller_borrow
+                .devices
+                .get_mut(&slot_id)
+                .and_then(|device| device.endpoint_ring_mut(endpoint_id))
+                .ok_or(ControlTransferError::NoSuchDevice(slot_id))?;
+
+            // SAFETY: `buffer` is not dropped until the transfer has completed, or leaked if the hub is removed
+            unsafe {
+                ring.enqueue(TransferTrb::Normal(NormalTrb::new(
+                    buffer.phys_frame().start_address(),
+                    bitmap_length,
+                )))
+                .map_err(|_| ErrorKind::RingFull)?;
+            }
+
+            controller_borrow
+                .doorbell_registers
+                .device_doorbell(slot_id)
+                .ring(DoorbellTarget::InEndpoint(endpoint.endpoint_number()));
+        }
+
+        // Wait for the hub to report a change, checking each second whether the hub has been removed
+        loop {
+            if !controller.borrow().devices.contains_key(&slot_id) {
+                debug!("Hub in slot {slot_id} removed");
+
+                // The controller may still write to the buffer, so it can't be freed
+                core::mem::forget(buffer);
+                return Ok(());
+            }
+
+            match t
+                .wait_for_transfer_event(slot_id, endpoint_id, TIMEOUT_1_SECOND)
+                .await
+            {
+                Ok(_)
+                | Err(EventTrbError::CompletionError(
+                    XhciError::Completion(CompletionCode::Error(CompletionError::ShortPacket)),
+                    _,
+                )) => break,
+                Err(EventTrbError::TimeoutReached(_)) => (),
+                Err(e) => return Err(ErrorKind::StatusChange(e)),
+            }
+        }
+
+        // SAFETY: The transfer has completed, so the controller is no longer writing to the buffer
+        let bitmap = unsafe { buffer.as_ptr::<[u8; 32]>().read_volatile() };
+
+        for port in 1..=hub.num_ports {
+            if bitmap[usize::from(port / 8)] & (1 << (port % 8)) != 0 {
+                handle_port_change(controller, t, &hub_info, port).await?;
+            }
+        }
+    }
+}
+
+/// Reads the [`HubDescriptor`] of the hub in the given slot.
+/// The first 2 bytes are read to find the descriptor's `bLength` field, and then the whole descriptor is read.
+async fn read_hub_descriptor(
+    controller: &RefCell<XhciController>,
+    t: &TaskWaker,
+    slot_id: u8,
+) -> Result<HubDescriptor, ControlTransferError> {
+    // SAFETY: GET_DESCRIPTOR requests don't change the state of the device
+    let header = unsafe {
+        XhciController::control_transfer(controller, t, slot_id, SetupPacket::get_hub_descriptor(2))
+            .await?
+    };
+
+    let Some(&length) = header.first() else {
+        return Err(DescriptorError::TooShort.into());
+    };
+
+    // SAFETY: GET_DESCRIPTOR requests don't change the state of the device
+    let bytes = unsafe {
+        XhciController::control_transfer(
+            controller,
+            t,
+            slot_id,
+            SetupPacket::get_hub_descriptor(length.into()),
+        )
+        .await?
+    };
+
+    debug_assert_eq!(bytes.get(1).copied(), Some(DescriptorType::Hub.to_byte()));
+
+    Ok(HubDescriptor::parse(&bytes)?)
+}
+
+/// Tells the controller that the device in the hub's slot is a hub, and configures its status change endpoint
+/// with a _Configure Endpoint_ command.
+///
+/// Returns the _Device Context Index_ of the status change endpoint.
+async fn configure_hub(
+    controller: &RefCell<XhciController>,
+    t: &TaskWaker,
+    hub_info: &HubInfo,
+    hub: &HubDescriptor,
+    endpoint: &EndpointDescriptor,
+) -> Result<u8, ErrorKind> {
+    let slot_id = hub_info.slot_id;
+    // Interrupt IN endpoints have a DCI of 2n + 1
+    let endpoint_id = endpoint.endpoint_number() * 2 + 1;
+    // Bits 0-10 of wMaxPacketSize are the packet size
+    let max_packet_size = endpoint.max_packet_size & 0x7ff;
+
+    let input_context_pointer = {
+        let mut controller_borrow = controller.borrow_mut();
+        let device = controller_borrow
+            .devices
+            .get_mut(&slot_id)
+            .ok_or(ControlTransferError::NoSuchDevice(slot_id))?;
+
+        let ring_addr = device.add_endpoint_ring(endpoint_id);
+        let input_context = device.input_context_mut();
+
+        // SAFETY: The controller only reads the input context while executing a command, and no command is in progress
+        unsafe {
+            let mut input_control_context = input_context.input_control_context_mut();
+            // Add the slot context and the status change endpoint's context, but not the default control endpoint's
+            input_control_context.write_add_context_flag(0, true);
+            input_control_context.write_add_context_flag(1, false);
+            input_control_context.write_add_context_flag(endpoint_id, true);
+        }
+
+        let mut slot_context = input_context
+            .device_context()
+            .get_slot_context()
+            .with_context_entries(endpoint_id)
+            .with_is_hub(true)
+            .with_num_ports(hub.num_ports);
+
+        if hub_info.port_speed == PORT_SPEED_HIGH {
+            slot_context = slot_context.with_tt_think_time(hub.tt_think_time());
+        }
+
+        let endpoint_context = EndpointContext::new()
+            .with_endpoint_type(EndpointType::InterruptIn)
+            .with_max_packet_size(max_packet_size)
+            .with_interval(interrupt_interval(hub_info.port_speed, endpoint.interval))
+            .with_error_count(3)
+            .with_tr_dequeue_pointer(ring_addr)
+            .with_dequeue_cycle_state(true)
+            .with_average_trb_length(max_packet_size)
+            .with_max_esit_payload(max_packet_size.into());
+
+        // SAFETY: The controller only reads the input context while executing a command, and no command is in progress.
+        // The slot context is written first so that the endpoint context is within `context_entries`.
+        unsafe {
+            let mut device_context = input_context.device_context_mut();
+            device_context.set_slot_context(slot_context);
+            device_context.write_ep_context_in(endpoint.endpoint_number().into(), endpoint_context);
+        }
+
+        input_context.phys_addr()
+    };
+
+    // SAFETY: The input context is kept alive in `devices`, and only adds the status change endpoint
+    let trb_addr = unsafe {
+        XhciController::write_command_trb_wait(
+            controller,
+            CommandTrb::ConfigureEndpoint(ConfigureEndpointTrb::new(
+                InputContextPointer::Configure(input_context_pointer),
+                slot_id,
+            )),
+        )
+        .await
+    };
+
+    t.wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+        .await
+        .map_err(ErrorKind::ConfigureEndpoint)?;
+
+    Ok(endpoint_id)
+}
+
+/// Handles a status change on one of a hub's ports. The change bits are acknowledged, and if a device has been
+/// connected then the port is reset and the device is enumerated.
+///
+/// Errors enumerating the connected device are logged rather than returned, so that they don't stop the hub's task.
+async fn handle_port_change(
+    controller: &RefCell<XhciController>,
+    t: &TaskWaker,
+    hub_info: &HubInfo,
+    port: u8,
+) -> Result<(), ErrorKind> {
+    let slot_id = hub_info.slot_id;
+    let status = get_port_status(controller, t, slot_id, port).await?;
+
+    // Acknowledge each change so that the hub reports future changes
+    let changes = [
+        (status.change.connection(), HubPortFeature::ConnectionChange),
+        (status.change.enable(), HubPortFeature::EnableChange),
+        (status.change.suspend(), HubPortFeature::SuspendChange),
+        (
+            status.change.over_current(),
+            HubPortFeature::OverCurrentChange,
+        ),
+        (status.change.reset(), HubPortFeature::ResetChange),
+    ];
+
+    for (_, feature) in changes.into_iter().filter(|&(changed, _)| changed) {
+        clear_port_feature(controller, t, slot_id, port, feature).await?;
+    }
+
+    if status.change.over_current() && status.status.over_current() {
+        warn!("Over-current condition on hub {slot_id} port {port}");
+    }
+
+    if !status.change.connection() {
+        return Ok(());
+    }
+
+    if !status.status.connection() {
+        debug!("Device detach on hub {slot_id} port {port}");
+
+        // Tear down the device on the port, and any devices behind it if it is a hub
+        if let Some(route_string) = hub_info.route_string.child(port) {
+            XhciController::detach_devices(controller, t, hub_info.root_port, route_string).await;
+        }
+
+        return Ok(());
+    }
+
+    debug!("Device attach on hub {slot_id} port {port}");
+
+    // Reset the port to enable it, and wait for the reset to finish
+    // SAFETY: The newly connected device has not been assigned a slot yet
+    unsafe {
+        XhciController::control_transfer(
+            controller,
+            t,
+            slot_id,
+            SetupPacket::set_port_feature(port, HubPortFeature::Reset),
+        )
+        .await?;
+    }
+
+    let mut status = None;
+    for _ in 0..RESET_POLL_ATTEMPTS {
+        t.wait_for_timeout(RESET_POLL_INTERVAL_NS).await;
+
+        let s = get_port_status(controller, t, slot_id, port).await?;
+        if s.change.reset() {
+            status = Some(s);
+            break;
+        }
+    }
+
+    let Some(status) = status else {
+        warn!("Timed out resetting hub {slot_id} port {port}");
+        return Ok(());
+    };
+
+    clear_port_feature(controller, t, slot_id, port, HubPortFeature::ResetChange).await?;
+
+    if !status.status.enable() {
+        warn!("Hub {slot_id} port {port} was not enabled after reset");
+        return Ok(());
+    }
+
+    // Give the device time to recover from the reset before addressing it
+    t.wait_for_timeout(RESET_POLL_INTERVAL_NS).await;
+
+    let port_speed = if status.status.low_speed() {
+        PORT_SPEED_LOW
+    } else if status.status.high_speed() {
+        PORT_SPEED_HIGH
+    } else {
+        PORT_SPEED_FULL
+    };
+
+    let Some(route_string) = hub_info.route_string.child(port) else {
+        warn!("Device on hub {slot_id} port {port} is nested too deeply to be addressed");
+        return Ok(());
+    };
+
+    // Low- and full-speed devices behind a high-speed hub are reached through that hub's transaction translator.
+    // Devices behind a full-speed hub use the same transaction translator as the hub itself.
+    let transaction_translator =
+        if hub_info.port_speed == PORT_SPEED_HIGH && port_speed != PORT_SPEED_HIGH {
+            Some(TransactionTranslator {
+                hub_slot_id: slot_id,
+                port,
+            })
+        } else {
+            hub_info.transaction_translator
+        };
+
+    match enumerate_device(
+        controller,
+        t,
+        hub_info.root_port,
+        route_string,
+        port_speed,
+        transaction_translator,
+    )
+    .await
+    {
+        Ok(child_slot_id) => debug!("Hub {slot_id} port {port} enumerated as slot {child_slot_id}"),
+        Err(EnumerationError::NoSlotsAvailable) => {
+            warn!("No free device slots for the device on hub {slot_id} port {port}");
+        }
+        Err(e) => warn!("Failed to enumerate device on hub {slot_id} port {port}: {e:?}"),
+    }
+
+    Ok(())
+}
+
+/// Reads the status of a hub port with a `GET_STATUS` request
+async fn get_port_status(
+    controller: &RefCell<XhciController>,
+    t: &TaskWaker,
+    slot_id: u8,
+    port: u8,
+) -> Result<HubPortStatus, ControlTransferError> {
+    // SAFETY: GET_STATUS requests don't change the state of the hub
+    let bytes = unsafe {
+        XhciController::control_transfer(controller, t, slot_id, SetupPacket::get_port_status(port))
+            .await?
+    };
+
+    HubPortStatus::parse(&bytes).ok_or(ControlTransferError::Descriptor(DescriptorError::TooShort))
+}
+
+/// Clears a feature of a hub port with a `CLEAR_FEATURE` request
+async fn clear_port_feature(
+    controller: &RefCell<XhciController>,
+    t: &TaskWaker,
+    slot_id: u8,
+    port: u8,
+    feature: HubPortFeature,
+) -> Result<(), ControlTransferError> {
+    // SAFETY: Only change features are cleared, which just acknowledges the change
+    unsafe {
+        XhciController::control_transfer(
+            controller,
+            t,
+            slot_id,
+            SetupPacket::clear_port_feature(port, feature),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Wrapper around [`handle_hub_inner`] which also acts as the defining use of the [`HubTask`] type alias
+pub fn handle_hub<'a>(
+    s: &'a RefCell<XhciController>,
+    t: &'a TaskWaker,
+    slot_id: u8,
+) -> HubTask<'a> {
+    async move {
+        handle_hub_inner(s, t, slot_id)
+            .await
+            .map_err(|kind| Error { slot_id, kind })
+    }
+}
@@ -1,6 +1,12 @@
 //! Structs which handle the
 
+mod control_transfer;
+mod endpoint;
+mod enumeration;
+mod hub;
 mod port_status_change;
+mod slot;
+mod transfer;
 
 use core::{
     cell::{Cell, RefCell},
@@ -11,19 +17,23 @@ use core::{
 
 use alloc::{boxed::Box, vec::Vec};
 use futures::Future;
+use hub::{handle_hub, HubTask};
 use log::{debug, error, warn};
 use port_status_change::{handle_port_status_change, PortStatusChangeTask};
 use x86_64::PhysAddr;
 
+use crate::scheduler::yield_now;
+
 use super::{
     trb::{
         event::{
             command_completion::{CommandCompletionTrb, CompletionCode},
             port_status_change::PortStatusChangeTrb,
+            transfer::TransferEventTrb,
         },
         EventTrb,
     },
-    XhciController,
+    XhciController, XhciError,
 };
 
 /// A value for a timeout field equivalent to a 1 second timeout
@@ -77,11 +87,29 @@ impl<'a> Task<'a> {
         Self::new_inner(|w| TaskType::PortStatusChange(handle_port_status_change(c, w, trb)))
     }
 
+    /// Constructs a new [`Hub`] [`Task`] driving the hub in the given slot
+    ///
+    /// [`Hub`]: TaskType::Hub
+    fn hub(c: &'a RefCell<XhciController>, slot_id: u8) -> Pin<Box<Self>> {
+        Self::new_inner(|w| TaskType::Hub(handle_hub(c, w, slot_id)))
+    }
+
     /// Checks the type of the passed TRB and constructs a new [`Task`] if needed to handle it.
     fn new(c: &'a RefCell<XhciController>, trb: EventTrb) -> Option<Pin<Box<Self>>> {
         match trb {
             EventTrb::MFINDEXWrap => None,
             EventTrb::PortStatusChange(trb) => Some(Self::port_status_change(c, trb)),
+            // Transfer events are only expected by tasks which are waiting for them, so one which reaches here
+            // is a late event for a transfer which has already finished, such as the last TRB of a TD cut short by a short packet.
+            EventTrb::Transfer(trb) => {
+                debug!(
+                    "Transfer event with no waiting task for slot {}, endpoint {}: {:?}",
+                    trb.slot_id(),
+                    trb.endpoint_id(),
+                    trb.completion_code
+                );
+                None
+            }
 
             _ => {
                 warn!("Unhandled TRB: {trb:?}");
@@ -107,6 +135,9 @@ impl<'a> Future for Task<'a> {
                 TaskType::PortStatusChange(ref mut p) => {
                     Future::poll(Pin::new_unchecked(p), cx).map_err(TaskError::PortStatusChange)
                 }
+                TaskType::Hub(ref mut h) => {
+                    Future::poll(Pin::new_unchecked(h), cx).map_err(TaskError::Hub)
+                }
             }
         }
     }
@@ -117,6 +148,8 @@ impl<'a> Future for Task<'a> {
 enum TaskError {
     /// An error from a [`PortStatusChangeTask`]
     PortStatusChange(port_status_change::Error),
+    /// An error from a [`HubTask`]
+    Hub(hub::Error),
 }
 
 impl From<port_status_change::Error> for TaskError {
@@ -125,18 +158,27 @@ impl From<port_status_change::Error> for TaskError {
     }
 }
 
+impl From<hub::Error> for TaskError {
+    fn from(v: hub::Error) -> Self {
+        Self::Hub(v)
+    }
+}
+
 /// A type of [`Task`]
 enum TaskType<'a> {
     /// A task responding to a [`PortStatusChangeTrb`]
     ///
     /// [`PortStatusChangeTrb`]: super::trb::event::port_status_change::PortStatusChangeTrb
     PortStatusChange(PortStatusChangeTask<'a>),
+    /// A task driving an external hub, which enumerates devices connected to its ports
+    Hub(HubTask<'a>),
 }
 
 impl<'a> Debug for TaskType<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::PortStatusChange(_) => write!(f, "PortStatusChange"),
+            Self::Hub(_) => write!(f, "Hub"),
         }
     }
 }
@@ -155,7 +197,19 @@ enum EventTrbError<T> {
     /// The [`CompletionCode`] was not [`Success`]
     /// 
     /// [`Success`]: CompletionCode::Success 
-    CompletionError(CompletionCode, T),
+    CompletionError(XhciError, T),
+}
+
+impl<T> EventTrbError<T> {
+    /// Gets the completion code of the TRB, if it was received but its completion code was not [`Success`]
+    ///
+    /// [`Success`]: CompletionCode::Success
+    fn completion_code(&self) -> Option<CompletionCode> {
+        match self {
+            Self::TimeoutReached(_) => None,
+            Self::CompletionError(XhciError::Completion(code), _) => Some(*code),
+        }
+    }
 }
 
 impl<T> From<TimeoutReachedError> for EventTrbError<T> {
@@ -168,6 +222,8 @@ impl<T> From<TimeoutReachedError> for EventTrbError<T> {
 type PortStatusChangeError = EventTrbError<PortStatusChangeTrb>;
 /// An error occurring while waiting for a [`CommandCompletionTrb`]
 type CommandCompletionError = EventTrbError<CommandCompletionTrb>;
+/// An error occurring while waiting for a [`TransferEventTrb`]
+type TransferEventError = EventTrbError<TransferEventTrb>;
 
 /// Stores what a [`Task`] is waiting for. This will be checked by [`TaskQueue::poll`] to decide whether
 /// or not to poll a given task. If the task is waiting for some data (e.g. a TRB), the data may also
@@ -189,7 +245,7 @@ impl TaskWaker {
         self.0.set(Waiting::TimeoutNS(timeout_ns));
 
         loop {
-            futures::pending!();
+            yield_now().await;
 
             match self.0.get() {
                 Waiting::TimeoutReached => return,
@@ -212,7 +268,7 @@ impl TaskWaker {
         });
 
         let r = loop {
-            futures::pending!();
+            yield_now().await;
 
             match self.0.get() {
                 Waiting::TimeoutReached => break Err(TimeoutReachedError),
@@ -226,10 +282,11 @@ impl TaskWaker {
 
         let trb = r?;
 
-        match trb.completion_code {
-            CompletionCode::Success => Ok(trb),
-            code => Err(EventTrbError::CompletionError(code, trb)),
-        }
+        trb.completion_code
+            .into_result()
+            .map_err(|e| EventTrbError::CompletionError(e, trb))?;
+
+        Ok(trb)
     }
 
     /// Waits for a [`CommandCompletionTrb`] responding to the command TRB at the given physical address.
@@ -249,7 +306,7 @@ impl TaskWaker {
         });
 
         let r = loop {
-            futures::pending!();
+            yield_now().await;
 
             match self.0.get() {
                 Waiting::TimeoutReached => break Err(TimeoutReachedError),
@@ -263,10 +320,51 @@ impl TaskWaker {
 
         let trb = r?;
 
-        match trb.completion_code {
-            CompletionCode::Success => Ok(trb),
-            code => Err(EventTrbError::CompletionError(code, trb)),
-        }
+        trb.completion_code
+            .into_result()
+            .map_err(|e| EventTrbError::CompletionError(e, trb))?;
+
+        Ok(trb)
+    }
+
+    /// Waits for a [`TransferEventTrb`] from the given endpoint of the given device slot.
+    /// If the TRB is not received within the given timeout in nanoseconds, A [`TimeoutReachedError`] is returned.
+    /// If the TRB is received but the status code is not [`Success`], a [`CompletionError`] is returned.
+    ///
+    /// [`Success`]: CompletionCode::Success
+    /// [`CompletionError`]: TransferEventError::CompletionError
+    async fn wait_for_transfer_event(
+        &self,
+        slot_id: u8,
+        endpoint_id: u8,
+        timeout_ns: usize,
+    ) -> Result<TransferEventTrb, TransferEventError> {
+        self.0.set(Waiting::TransferEvent {
+            slot_id,
+            endpoint_id,
+            timeout: timeout_ns,
+        });
+
+        let r = loop {
+            yield_now().await;
+
+            match self.0.get() {
+                Waiting::TimeoutReached => break Err(TimeoutReachedError),
+                Waiting::TransferEventReceived(trb) => break Ok(trb),
+                Waiting::TransferEvent { .. } => (),
+                _ => panic!("Waiting state changed unexpectedly"),
+            }
+        };
+
+        self.0.set(Waiting::None);
+
+        let trb = r?;
+
+        trb.completion_code
+            .into_result()
+            .map_err(|e| EventTrbError::CompletionError(e, trb))?;
+
+        Ok(trb)
     }
 }
 
@@ -311,6 +409,26 @@ enum Waiting {
     ///
     /// [`CommandCompletion`]: Waiting::CommandCompletion
     CommandCompletionReceived(CommandCompletionTrb),
+    /// The task is waiting for a [`TransferEventTrb`] from the given endpoint of the given device slot.
+    /// If the timeout reaches zero before the TRB is received, the value will be changed to [`TimeoutReached`]
+    ///
+    /// [`TimeoutReached`]: Waiting::TimeoutReached
+    TransferEvent {
+        /// The [`slot_id`] of the TRB
+        ///
+        /// [`slot_id`]: TransferEventTrb::slot_id
+        slot_id: u8,
+        /// The [`endpoint_id`] of the TRB
+        ///
+        /// [`endpoint_id`]: TransferEventTrb::endpoint_id
+        endpoint_id: u8,
+        /// The remaining timeout in nanoseconds
+        timeout: usize,
+    },
+    /// The result of the [`TransferEvent`] variant
+    ///
+    /// [`TransferEvent`]: Waiting::TransferEvent
+    TransferEventReceived(TransferEventTrb),
 }
 
 impl Waiting {
@@ -321,10 +439,81 @@ impl Waiting {
             Waiting::TimeoutReached => true,
             Waiting::PortStatusChangeReceived(_) => true,
             Waiting::CommandCompletionReceived(_) => true,
+            Waiting::TransferEventReceived(_) => true,
 
             Waiting::TimeoutNS(_) => false,
             Waiting::PortStatusChange { .. } => false,
             Waiting::CommandCompletion { .. } => false,
+            Waiting::TransferEvent { .. } => false,
+        }
+    }
+
+    /// Calculates the next state of a task after `ns_since_last` nanoseconds have passed,
+    /// given a TRB which may have been `received` since the last poll.
+    /// Timeouts are counted down, and if the task is waiting for a TRB which matches the received one,
+    /// the TRB is taken out of `received` and stored in the new state.
+    fn update(self, ns_since_last: usize, received: &mut Option<EventTrb>) -> Self {
+        match self {
+            Waiting::TimeoutNS(ns) => match ns.checked_sub(ns_since_last) {
+                Some(ns) => Waiting::TimeoutNS(ns),
+                None => Waiting::TimeoutReached,
+            },
+            Waiting::PortStatusChange { port, timeout } => match *received {
+                Some(EventTrb::PortStatusChange(trb)) if trb.port_id == port => {
+                    *received = None;
+                    Waiting::PortStatusChangeReceived(trb)
+                }
+                _ => match timeout.checked_sub(ns_since_last) {
+                    Some(timeout) => Waiting::PortStatusChange { port, timeout },
+                    None => Waiting::TimeoutReached,
+                },
+            },
+
+            Waiting::CommandCompletion {
+                command_trb_pointer,
+                timeout,
+            } => match *received {
+                Some(EventTrb::CommandCompletion(trb))
+                    if trb.command_trb_pointer == command_trb_pointer =>
+                {
+                    *received = None;
+                    Waiting::CommandCompletionReceived(trb)
+                }
+                _ => match timeout.checked_sub(ns_since_last) {
+                    Some(timeout) => Waiting::CommandCompletion {
+                        command_trb_pointer,
+                        timeout,
+                    },
+                    None => Waiting::TimeoutReached,
+                },
+            },
+
+            Waiting::TransferEvent {
+                slot_id,
+                endpoint_id,
+                timeout,
+            } => match *received {
+                Some(EventTrb::Transfer(trb))
+                    if trb.slot_id() == slot_id && trb.endpoint_id() == endpoint_id =>
+                {
+                    *received = None;
+                    Waiting::TransferEventReceived(trb)
+                }
+                _ => match timeout.checked_sub(ns_since_last) {
+                    Some(timeout) => Waiting::TransferEvent {
+                        slot_id,
+                        endpoint_id,
+                        timeout,
+                    },
+                    None => Waiting::TimeoutReached,
+                },
+            },
+
+            s @ (Waiting::None
+            | Waiting::TimeoutReached
+            | Waiting::PortStatusChangeReceived(_)
+            | Waiting::CommandCompletionReceived(_)
+            | Waiting::TransferEventReceived(_)) => s,
         }
     }
 }
@@ -343,46 +532,7 @@ impl<'a: 'b, 'b> PollTasks<'a, 'b> {
     /// Implementation of [`TaskQueue::poll`]
     fn poll(&mut self, cx: &mut core::task::Context<'_>) -> core::task::Poll<Option<EventTrb>> {
         self.tasks.retain_mut(|i| {
-            let new_state = match i.waker.0.get() {
-                Waiting::TimeoutNS(ns) => match ns.checked_sub(self.ns_since_last) {
-                    Some(ns) => Waiting::TimeoutNS(ns),
-                    None => Waiting::TimeoutReached,
-                },
-                Waiting::PortStatusChange { port, timeout } => match self.trb {
-                    Some(EventTrb::PortStatusChange(trb)) if trb.port_id == port => {
-                        self.trb = None;
-                        Waiting::PortStatusChangeReceived(trb)
-                    }
-                    _ => match timeout.checked_sub(self.ns_since_last) {
-                        Some(timeout) => Waiting::PortStatusChange { port, timeout },
-                        None => Waiting::TimeoutReached,
-                    },
-                },
-
-                Waiting::CommandCompletion {
-                    command_trb_pointer,
-                    timeout,
-                } => match self.trb {
-                    Some(EventTrb::CommandCompletion(trb))
-                        if trb.command_trb_pointer == command_trb_pointer =>
-                    {
-                        self.trb = None;
-                        Waiting::CommandCompletionReceived(trb)
-                    }
-                    _ => match timeout.checked_sub(self.ns_since_last) {
-                        Some(timeout) => Waiting::CommandCompletion {
-                            command_trb_pointer,
-                            timeout,
-                        },
-                        None => Waiting::TimeoutReached,
-                    },
-                },
-
-                s @ (Waiting::None
-                | Waiting::TimeoutReached
-                | Waiting::PortStatusChangeReceived(_)
-                | Waiting::CommandCompletionReceived(_)) => s,
-            };
+            let new_state = i.waker.0.get().update(self.ns_since_last, &mut self.trb);
 
             i.waker.0.set(new_state);
 
@@ -446,6 +596,7 @@ impl<'a> TaskQueue<'a> {
     }
 
     /// Iterates through the queue, polling each active [`Task`] and removing any which complete.
+    /// A [`Hub`] task is then started for each hub which has been enumerated since the last call.
     ///
     /// # Parameters
     /// * `ns_since_last`: The time in nanoseconds since the last call to [`poll`]. This is used to update timeouts.
@@ -454,6 +605,7 @@ impl<'a> TaskQueue<'a> {
     ///     task may be started to handle it.
     ///
     /// [`poll`]: TaskQueue::poll
+    /// [`Hub`]: TaskType::Hub
     pub async fn poll(&mut self, ns_since_last: usize, trb: Option<EventTrb>) {
         let leftover_trb = PollTasks {
             tasks: &mut self.0,
@@ -465,5 +617,47 @@ impl<'a> TaskQueue<'a> {
         if let Some(trb) = leftover_trb {
             self.push(trb);
         }
+
+        let new_hubs = core::mem::take(&mut self.1.borrow_mut().new_hubs);
+        for slot_id in new_hubs {
+            self.0.push(Task::hub(self.1, slot_id));
+        }
     }
 }
+
+/// Tests that a [`TransferEventTrb`] is only passed to a task waiting for an event from the same slot and endpoint,
+/// and that a task which doesn't take the TRB keeps counting down its timeout.
+#[test_case]
+fn test_transfer_event_matches_waiter() {
+    let slot_id = 3;
+    let endpoint_id = 5;
+
+    let trb = TransferEventTrb::new([
+        0x1230,
+        0,
+        // 16 bytes not transferred, with a completion code of Success
+        (1 << 24) | 16,
+        (u32::from(slot_id) << 24) | (u32::from(endpoint_id) << 16) | (32 << 10),
+    ]);
+
+    let waiting_for = |slot_id, endpoint_id| Waiting::TransferEvent {
+        slot_id,
+        endpoint_id,
+        timeout: 100,
+    };
+
+    // A task waiting on a different endpoint doesn't take the TRB
+    let mut received = Some(EventTrb::Transfer(trb));
+    let state = waiting_for(slot_id, endpoint_id + 1).update(10, &mut received);
+    assert!(matches!(state, Waiting::TransferEvent { timeout: 90, .. }));
+    assert!(received.is_some());
+
+    let state = waiting_for(slot_id, endpoint_id).update(10, &mut received);
+    let Waiting::TransferEventReceived(received_trb) = state else {
+        panic!("TRB was not received: {state:?}");
+    };
+    assert!(received.is_none());
+    assert_eq!(received_trb.trb_pointer, PhysAddr::new(0x1230));
+    assert_eq!(received_trb.transfer_length, 16);
+    assert_eq!(received_trb.completion_code, CompletionCode::Success);
+}
@@ -3,15 +3,22 @@
 use core::cell::RefCell;
 
 use futures::Future;
-use log::debug;
+use log::{debug, warn};
 
-use crate::pci::drivers::usb::xhci::{
-    tasks::{TimeoutReachedError, TIMEOUT_1_SECOND},
-    trb::event::{command_completion::CompletionCode, port_status_change::PortStatusChangeTrb},
-    XhciController,
+use crate::pci::drivers::usb::{
+    xhci::{
+        registers::operational::port_registers::StatusAndControl,
+        tasks::{PortStatusChangeError, TIMEOUT_1_SECOND},
+        trb::event::port_status_change::PortStatusChangeTrb,
+        XhciController, XhciError,
+    },
+    RouteString,
 };
 
-use super::TaskWaker;
+use super::{
+    enumeration::{enumerate_device, EnumerationError},
+    TaskWaker,
+};
 
 /// The type of the future produced by [`handle_port_status_change_inner`], and stored in [`PortStatusChange`] tasks
 ///
@@ -31,13 +38,40 @@ pub struct Error {
 #[derive(Debug, Clone, Copy)]
 enum ErrorKind {
     /// The initial TRB had a non-success completion code
-    InitialError(CompletionCode),
+    InitialError(XhciError),
     /// The port failed to reset
     Reset(PortStatusChangeError),
+    /// The port wasn't enabled after being reset or after link training
+    NotEnabled,
+    /// The attached device could not be enumerated
+    Enumeration(EnumerationError),
     /// A timeout expired
     Timeout,
 }
 
+/// The states a root hub port goes through between a device being connected and the device being enumerated.
+///
+/// See the spec section [4.19.1] for the full port state machines.
+///
+/// [4.19.1]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A305%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C195%2C0%5D
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PortState {
+    /// A device has been connected to the port, but the port isn't enabled
+    Connected,
+    /// A USB2 port is being reset, and the controller will send a [`PortStatusChangeTrb`] when the reset completes
+    Resetting,
+    /// A USB3 port is training its link, which the controller starts automatically when a device is connected.
+    /// The controller will send a [`PortStatusChangeTrb`] when the port is enabled.
+    LinkTraining,
+    /// The port is enabled, and the device is running at the given speed
+    Enabled {
+        /// The value of the port's [`port_speed`] field
+        ///
+        /// [`port_speed`]: super::super::registers::operational::port_registers::StatusAndControl::port_speed
+        port_speed: u8,
+    },
+}
+
 /// Handles a [`PortStatusChangeTrb`] following the process defined in the spec section [4.3]
 ///
 /// [`PortStatusChangeTrb`]: super::super::trb::event::port_status_change::PortStatusChangeTrb
@@ -46,68 +80,190 @@ async fn handle_port_status_change_inner<'a>(
     controller: &RefCell<XhciController>,
     t: &TaskWaker,
     trb: PortStatusChangeTrb,
-) -> Result<(), Error> {
+) -> Result<(), ErrorKind> {
     // Check that the TRB which triggered this task was successful
-    if trb.completion_code != CompletionCode::Success {
-        return Err(Error {
-            port_id: trb.port_id,
-            kind: ErrorKind::InitialError(trb.completion_code),
-        });
-    }
+    trb.completion_code
+        .into_result()
+        .map_err(ErrorKind::InitialError)?;
 
-    // Read the status and control register
-    let status_and_control = {
-        let controller_borrow = controller.borrow_mut();
-        let port = controller_borrow
-            .operational_registers
-            .port(trb.port_id.into())
-            .unwrap();
-        port.read_status_and_control()
-    };
+    let status_and_control = read_status_and_control(controller, trb.port_id);
+    acknowledge_changes(controller, trb.port_id, status_and_control);
 
-    // Check whether the status change was an attach or detach
-    if status_and_control.connect_status_change() {
-        // USB2 ports require a reset to advance the port to the enabled state
-        if !status_and_control.port_enabled() {
-            reset_usb2_port(controller, trb.port_id, t).await?;
-        }
+    if !status_and_control.connect_status_change() {
+        return Ok(());
+    }
 
+    // Check whether the status change was an attach or detach
+    if status_and_control.device_connected() {
         debug!("Device attach on port {:?}", trb.port_id);
+
+        let port_speed = enable_port(controller, t, trb.port_id).await?;
+
+        let slot_id = match enumerate_device(
+            controller,
+            t,
+            trb.port_id,
+            RouteString::ROOT,
+            port_speed,
+            None,
+        )
+        .await
+        {
+            Ok(slot_id) => slot_id,
+            // The device can't be used until another device is detached, but this isn't an error in the driver
+            Err(EnumerationError::NoSlotsAvailable) => {
+                warn!(
+                    "No free device slots for the device on port {:?}",
+                    trb.port_id
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(ErrorKind::Enumeration(e)),
+        };
+
+        debug!("Port {:?} enumerated as slot {slot_id}", trb.port_id);
     } else {
         debug!("Device detach on port {:?}", trb.port_id);
+
+        // Tear down every device connected through the port, including any behind hubs
+        XhciController::detach_devices(controller, t, trb.port_id, RouteString::ROOT).await;
     }
 
     Ok(())
 }
 
-/// Resets a USB2 port and waits for a response
-async fn reset_usb2_port(
+/// Reads the status and control register of the given port
+fn read_status_and_control(controller: &RefCell<XhciController>, port_id: u8) -> StatusAndControl {
+    controller
+        .borrow()
+        .operational_registers
+        .port(port_id.into())
+        .unwrap()
+        .read_status_and_control()
+}
+
+/// Clears the change bits which are set in `status_and_control`, so that the controller reports future changes
+fn acknowledge_changes(
     controller: &RefCell<XhciController>,
     port_id: u8,
+    status_and_control: StatusAndControl,
+) {
+    let mut controller_borrow = controller.borrow_mut();
+    let mut port = controller_borrow
+        .operational_registers
+        .port_mut(port_id.into())
+        .unwrap();
+
+    // The change bits are cleared by writing `true` to them, so write back the ones which were read as `true`
+    port.modify_preserving_changes(|s| {
+        s.with_connect_status_change(status_and_control.connect_status_change())
+            .with_port_enabled_change(status_and_control.port_enabled_change())
+            .with_warm_port_reset_change(status_and_control.warm_port_reset_change())
+            .with_over_current_change(status_and_control.over_current_change())
+            .with_port_reset_change(status_and_control.port_reset_change())
+            .with_port_link_state_change(status_and_control.port_link_state_change())
+            .with_port_config_error_change(status_and_control.port_config_error_change())
+    });
+}
+
+/// Brings a port with a newly connected device to the enabled state, and returns the speed of the device.
+///
+/// USB2 ports are reset, which enables them. USB3 ports train their link automatically when a device is connected,
+/// so this only waits for that to finish. If the controller doesn't report the port being enabled within a second,
+/// an error is returned so that the task ends rather than waiting forever.
+///
+/// See the spec section [4.3.1] for more info.
+///
+/// [4.3.1]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+async fn enable_port(
+    controller: &RefCell<XhciController>,
     t: &TaskWaker,
-) -> Result<(), ErrorKind> {
-    debug!("Resetting USB2 port");
+    port_id: u8,
+) -> Result<u8, ErrorKind> {
+    let is_usb3 = controller
+        .borrow()
+        .extended_capability_registers
+        .as_ref()
+        .and_then(|e| e.get_protocol_for_port(port_id))
+        .is_some_and(|protocol| protocol.revision_major() >= 3);
+
+    let mut state = PortState::Connected;
+
+    loop {
+        state = match state {
+            PortState::Connected => {
+                let status_and_control = read_status_and_control(controller, port_id);
 
-    // Write the reset flag to reset the port
-    {
-        let mut controller_borrow = controller.borrow_mut();
-        let mut port = controller_borrow
-            .operational_registers
-            .port_mut(port_id.into())
-            .unwrap();
+                if status_and_control.port_enabled() {
+                    PortState::Enabled {
+                        port_speed: status_and_control.port_speed(),
+                    }
+                } else if is_usb3 {
+                    PortState::LinkTraining
+                } else {
+                    start_reset(controller, port_id);
+                    PortState::Resetting
+                }
+            }
 
-        let new_status_and_control = port.read_status_and_control().normalised().with_reset(true);
+            PortState::Resetting => {
+                debug!("Resetting USB2 port {port_id}");
 
-        port.write_status_and_control(new_status_and_control);
+                // Wait for a PortStatusChange TRB indicating that the port has been reset
+                t.wait_for_port_status_change(port_id, TIMEOUT_1_SECOND)
+                    .await
+                    .map_err(ErrorKind::Reset)?;
+
+                let status_and_control = read_status_and_control(controller, port_id);
+                acknowledge_changes(controller, port_id, status_and_control);
+
+                if status_and_control.reset() {
+                    // The change was something other than the reset completing, so keep waiting
+                    PortState::Resetting
+                } else if status_and_control.port_enabled() {
+                    PortState::Enabled {
+                        port_speed: status_and_control.port_speed(),
+                    }
+                } else {
+                    return Err(ErrorKind::NotEnabled);
+                }
+            }
+
+            PortState::LinkTraining => {
+                t.wait_for_port_status_change(port_id, TIMEOUT_1_SECOND)
+                    .await
+                    .map_err(|_| ErrorKind::Timeout)?;
+
+                let status_and_control = read_status_and_control(controller, port_id);
+                acknowledge_changes(controller, port_id, status_and_control);
+
+                if status_and_control.port_enabled() {
+                    PortState::Enabled {
+                        port_speed: status_and_control.port_speed(),
+                    }
+                } else if status_and_control.device_connected() {
+                    PortState::LinkTraining
+                } else {
+                    return Err(ErrorKind::NotEnabled);
+                }
+            }
+
+            // The port speed is only valid once the port has been enabled
+            PortState::Enabled { port_speed } => return Ok(port_speed),
+        };
     }
+}
 
-    // Wait for a PortStatusChange TRB indicating that the port has been reset
-    let trb = t
-        .wait_for_port_status_change(port_id, TIMEOUT_1_SECOND)
-        .await
-        .map_err(ErrorKind::Reset)?;
+/// Starts a reset of a USB2 port by writing its reset flag.
+/// The controller will send a [`PortStatusChangeTrb`] when the reset completes.
+fn start_reset(controller: &RefCell<XhciController>, port_id: u8) {
+    let mut controller_borrow = controller.borrow_mut();
+    let mut port = controller_borrow
+        .operational_registers
+        .port_mut(port_id.into())
+        .unwrap();
 
-    Ok(())
+    port.start_reset();
 }
 
 /// Wrapper around [`handle_port_status_change_inner`] which also acts as the defining use of the [`PortStatusChangeTask`] type alias
@@ -117,7 +273,7 @@ pub fn handle_port_status_change<'a>(
     trb: PortStatusChangeTrb,
 ) -> PortStatusChangeTask<'a> {
     async move {
-    handle_port_status_change_inner(s, t, trb)
+        handle_port_status_change_inner(s, t, trb)
             .await
             .map_err(|kind| Error {
                 port_id: trb.port_id,
@@ -0,0 +1,117 @@
+//! Methods on [`XhciController`] for tearing down device slots when devices are disconnected
+
+use core::cell::RefCell;
+
+use alloc::vec::Vec;
+use log::{debug, warn};
+
+use crate::pci::drivers::usb::{unregister_device, RouteString};
+
+use super::{
+    super::{
+        trb::{command::slot::DisableSlotTrb, CommandTrb},
+        XhciController,
+    },
+    CommandCompletionError, TaskWaker, TIMEOUT_1_SECOND,
+};
+
+/// An error occurring while disabling a slot with [`disable_slot`]
+///
+/// [`disable_slot`]: XhciController::disable_slot
+#[derive(Debug, Clone, Copy)]
+pub enum DisableSlotError {
+    /// There is no [`Device`] in the given slot
+    ///
+    /// [`Device`]: super::super::device::Device
+    NoSuchDevice(u8),
+    /// The _Disable Slot_ command failed
+    DisableSlot(CommandCompletionError),
+}
+
+impl XhciController {
+    /// Disables the given slot with a _Disable Slot_ command, and then frees the resources used by the device in it.
+    ///
+    /// The [`Device`] is removed from [`devices`], which frees its input context and transfer rings,
+    /// the slot's entry in the DCBAA is cleared, and the device is removed from the list printed by [`usbls`].
+    /// The slot ID can then be reused by the controller for a new device.
+    ///
+    /// See the spec section [4.6.4] for more info.
+    ///
+    /// [`Device`]: super::super::device::Device
+    /// [`devices`]: XhciController::devices
+    /// [`usbls`]: crate::pci::drivers::usb::usbls
+    /// [4.6.4]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+    pub(super) async fn disable_slot(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        slot_id: u8,
+    ) -> Result<(), DisableSlotError> {
+        if !controller.borrow().devices.contains_key(&slot_id) {
+            return Err(DisableSlotError::NoSuchDevice(slot_id));
+        }
+
+        // SAFETY: The device has been disconnected, and its resources aren't freed until the command completes
+        let trb_addr = unsafe {
+            XhciController::write_command_trb_wait(
+                controller,
+                CommandTrb::DisableSlot(DisableSlotTrb::new().with_slot_id(slot_id)),
+            )
+            .await
+        };
+
+        t.wait_for_command_completion(trb_addr, TIMEOUT_1_SECOND)
+            .await
+            .map_err(DisableSlotError::DisableSlot)?;
+
+        let mut controller_borrow = controller.borrow_mut();
+
+        // Dropping the device frees its input context and transfer rings
+        controller_borrow.devices.remove(&slot_id);
+        controller_borrow.new_hubs.retain(|&hub| hub != slot_id);
+
+        // SAFETY: The slot has just been disabled, so the controller no longer accesses its device context
+        unsafe { controller_borrow.dcbaa.clear_slot(slot_id) };
+        debug!(
+            "Disabled slot {slot_id}, {} slots still in use",
+            controller_borrow.dcbaa.slots_in_use()
+        );
+
+        unregister_device(controller_borrow.function, slot_id);
+
+        Ok(())
+    }
+
+    /// Tears down every device connected through the given root hub port whose route string starts with `route_string`,
+    /// i.e. the device at that route and any devices behind it if it is a hub.
+    /// Each device's endpoints are deconfigured and then its slot is disabled.
+    ///
+    /// Errors are logged rather than returned, so that one failure doesn't stop the other devices from being torn down.
+    pub(super) async fn detach_devices(
+        controller: &RefCell<Self>,
+        t: &TaskWaker,
+        root_port: u8,
+        route_string: RouteString,
+    ) {
+        let slot_ids: Vec<_> = controller
+            .borrow()
+            .devices
+            .iter()
+            .filter(|(_, device)| {
+                device.root_port() == root_port && device.route_string().starts_with(route_string)
+            })
+            .map(|(&slot_id, _)| slot_id)
+            .collect();
+
+        for slot_id in slot_ids {
+            debug!("Tearing down slot {slot_id}");
+
+            if let Err(e) = XhciController::deconfigure_device(controller, t, slot_id).await {
+                warn!("Failed to deconfigure slot {slot_id}: {e:?}");
+            }
+
+            if let Err(e) = XhciController::disable_slot(controller, t, slot_id).await {
+                warn!("Failed to disable slot {slot_id}: {e:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! The [`Endpoint`] type, for making bulk and interrupt transfers on a device's endpoints other than the default
+//! control endpoint.
+
+use core::cell::RefCell;
+
+use alloc::vec::Vec;
+use log::warn;
+
+use crate::{allocator::PageBox, pci::drivers::usb::descriptors::EndpointDirection};
+
+use super::{
+    super::{
+        registers::doorbell::DoorbellTarget,
+        trb::{
+            event::command_completion::{CompletionCode, CompletionError},
+            transfer::{normal::NormalTrb, TransferTrb},
+            RingFullError,
+        },
+        XhciController, XhciError,
+    },
+    EventTrbError, TaskWaker, TransferEventError, TIMEOUT_1_SECOND,
+};
+
+/// The maximum number of bytes transferred by each [`NormalTrb`] of a transfer.
+///
+/// A TRB can transfer up to 64KiB, but its buffer must be physically contiguous,
+/// and each TRB's buffer is a separate [`PageBox`], so this is the size of a page.
+const MAX_TRB_LENGTH: usize = 0x1000;
+
+/// The largest value of a TRB's _TD Size_ field
+const MAX_TD_SIZE: usize = 31;
+
+/// An error occurring during a transfer with [`Endpoint::transfer`]
+#[derive(Debug, Clone, Copy)]
+pub enum TransferError {
+    /// There is no configured endpoint with the given endpoint ID in the given slot
+    NoSuchEndpoint {
+        /// The slot ID of the device
+        slot_id: u8,
+        /// The _Device Context Index_ of the endpoint
+        endpoint_id: u8,
+    },
+    /// The endpoint's transfer ring didn't have space for all the transfer's TRBs
+    RingFull(RingFullError),
+    /// The transfer failed or timed out
+    Transfer(TransferEventError),
+}
+
+impl From<RingFullError> for TransferError {
+    fn from(v: RingFullError) -> Self {
+        Self::RingFull(v)
+    }
+}
+
+impl From<TransferEventError> for TransferError {
+    fn from(v: TransferEventError) -> Self {
+        Self::Transfer(v)
+    }
+}
+
+/// One of a device's configured endpoints, other than the default control endpoint.
+/// The endpoint's transfer ring must have been added with [`add_endpoint_ring`] and configured
+/// with a _Configure Endpoint_ command before any transfers are made.
+///
+/// [`add_endpoint_ring`]: super::super::device::Device::add_endpoint_ring
+#[allow(dead_code)] // Not yet used by any class driver
+pub(super) struct Endpoint<'a> {
+    /// The controller the device is connected to
+    controller: &'a RefCell<XhciController>,
+    /// The waker of the task making transfers
+    t: &'a TaskWaker,
+    /// The slot ID of the device
+    slot_id: u8,
+    /// The endpoint number, in the range `1..=15`
+    endpoint_number: u8,
+    /// The maximum packet size of the endpoint, from its [`EndpointDescriptor`]
+    ///
+    /// [`EndpointDescriptor`]: crate::pci::drivers::usb::descriptors::EndpointDescriptor
+    max_packet_size: u16,
+}
+
+#[allow(dead_code)] // Not yet used by any class driver
+impl<'a> Endpoint<'a> {
+    /// Constructs a new [`Endpoint`] for the endpoint with the given number of the device in the given slot
+    ///
+    /// # Panics
+    /// If `endpoint_number` is not in the range `1..=15`, or `max_packet_size` is 0.
+    pub fn new(
+        controller: &'a RefCell<XhciController>,
+        t: &'a TaskWaker,
+        slot_id: u8,
+        endpoint_number: u8,
+        max_packet_size: u16,
+    ) -> Self {
+        assert!(
+            (1..=15).contains(&endpoint_number),
+            "Invalid endpoint number {endpoint_number}"
+        );
+        assert_ne!(max_packet_size, 0, "Max packet size should not be 0");
+
+        Self {
+            controller,
+            t,
+            slot_id,
+            endpoint_number,
+            max_packet_size,
+        }
+    }
+
+    /// The _Device Context Index_ of the endpoint in the given direction
+    fn endpoint_id(&self, direction: EndpointDirection) -> u8 {
+        match direction {
+            EndpointDirection::Out => self.endpoint_number * 2,
+            EndpointDirection::In => self.endpoint_number * 2 + 1,
+        }
+    }
+
+    /// Transfers the data in `buf` to the device for an OUT transfer, or fills `buf` with data from the device for an IN transfer,
+    /// and waits for the transfer to complete.
+    ///
+    /// The transfer is split into [`NormalTrb`]s of at most [`MAX_TRB_LENGTH`] bytes, which are queued as a single TD.
+    /// Returns the number of bytes transferred, which may be less than `buf.len()` if the device sent a short packet.
+    pub async fn transfer(
+        &self,
+        buf: &mut [u8],
+        direction: EndpointDirection,
+    ) -> Result<usize, TransferError> {
+        let slot_id = self.slot_id;
+        let endpoint_id = self.endpoint_id(direction);
+
+        let len = buf.len();
+        // A zero-length transfer still needs one TRB
+        let num_trbs = len.div_ceil(MAX_TRB_LENGTH).max(1);
+        let trb_length = |i: usize| (len - i * MAX_TRB_LENGTH).min(MAX_TRB_LENGTH);
+
+        let buffers: Vec<_> = (0..num_trbs)
+            .map(|i| {
+                let mut page = PageBox::new_zeroed();
+                if direction == EndpointDirection::Out {
+                    let chunk = &buf[i * MAX_TRB_LENGTH..][..trb_length(i)];
+                    // SAFETY: The chunk is at most one page long, so fits in the page
+                    unsafe {
+                        page.as_mut_ptr::<u8>()
+                            .copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+                    }
+                }
+                page
+            })
+            .collect();
+
+        let trb_addrs = {
+            let mut controller_borrow = self.controller.borrow_mut();
+            let ring = controller_borrow
+                .devices
+                .get_mut(&slot_id)
+                .and_then(|device| device.endpoint_ring_mut(endpoint_id))
+                .ok_or(TransferError::NoSuchEndpoint {
+                    slot_id,
+                    endpoint_id,
+                })?;
+
+            // Check for space up front, so that a partial TD is never left on the ring
+            if ring.free_space() < num_trbs {
+                return Err(RingFullError.into());
+            }
+
+            let mut trb_addrs = Vec::with_capacity(num_trbs);
+            let mut queued = 0;
+            for (i, page) in buffers.iter().enumerate() {
+                let length = trb_length(i);
+                queued += length;
+                let last = i == num_trbs - 1;
+                let td_size = if last {
+                    0
+                } else {
+                    td_size(len, queued, self.max_packet_size)
+                };
+
+                // SAFETY: The buffers are not dropped until the transfer has completed, or leaked if it times out.
+                // Normal TRBs only move data between the device and the buffers, so can't reconfigure the device.
+                let trb_addr = unsafe {
+                    ring.enqueue(TransferTrb::Normal(NormalTrb::new_in_td(
+                        page.phys_frame().start_address(),
+                        length.try_into().unwrap(),
+                        td_size,
+                        last,
+                    )))?
+                };
+                trb_addrs.push(trb_addr);
+            }
+
+            let target = match direction {
+                EndpointDirection::Out => DoorbellTarget::OutEndpoint(self.endpoint_number),
+                EndpointDirection::In => DoorbellTarget::InEndpoint(self.endpoint_number),
+            };
+            controller_borrow
+                .doorbell_registers
+                .device_doorbell(slot_id)
+                .ring(target);
+
+            trb_addrs
+        };
+
+        let last_trb_addr = *trb_addrs.last().unwrap();
+
+        // Wait for the last TRB to complete, or for the TD to be cut short by a short packet
+        let transferred = loop {
+            match self
+                .t
+                .wait_for_transfer_event(slot_id, endpoint_id, TIMEOUT_1_SECOND)
+                .await
+            {
+                Ok(trb) if trb.trb_pointer == last_trb_addr => break len,
+                Ok(_) => (),
+                Err(EventTrbError::CompletionError(
+                    XhciError::Completion(CompletionCode::Error(CompletionError::ShortPacket)),
+                    trb,
+                )) => {
+                    if let Some(i) = trb_addrs.iter().position(|&addr| addr == trb.trb_pointer) {
+                        let residual = usize::try_from(trb.transfer_length).unwrap();
+                        break i * MAX_TRB_LENGTH + trb_length(i).saturating_sub(residual);
+                    }
+                }
+                Err(
+                    e @ EventTrbError::CompletionError(
+                        XhciError::Completion(CompletionCode::Error(CompletionError::Stall)),
+                        _,
+                    ),
+                ) => {
+                    // A stall halts the endpoint, so it needs to be reset before any more transfers can be made
+                    if let Err(recovery_error) = XhciController::recover_endpoint(
+                        self.controller,
+                        self.t,
+                        slot_id,
+                        endpoint_id,
+                    )
+                    .await
+                    {
+                        warn!("Failed to recover endpoint {endpoint_id} of slot {slot_id} after a stall: {recovery_error:?}");
+                    }
+
+                    return Err(e.into());
+                }
+                Err(e @ EventTrbError::TimeoutReached(_)) => {
+                    // The controller may still access the buffers, so they can't be freed
+                    core::mem::forget(buffers);
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        if direction == EndpointDirection::In {
+            for (i, page) in buffers.iter().enumerate() {
+                let start = i * MAX_TRB_LENGTH;
+                if start >= transferred {
+                    break;
+                }
+                let chunk = &mut buf[start..transferred.min(start + MAX_TRB_LENGTH)];
+
+                // SAFETY: The transfer has completed, so the controller is no longer writing to the page.
+                // The chunk is at most one page long.
+                unsafe {
+                    chunk
+                        .as_mut_ptr()
+                        .copy_from_nonoverlapping(page.as_ptr::<u8>(), chunk.len());
+                }
+            }
+        }
+
+        Ok(transferred)
+    }
+}
+
+/// Calculates the _TD Size_ field of a TRB which isn't the last in its TD, which is the number of packets remaining in
+/// the TD after the TRB, capped at [`MAX_TD_SIZE`].
+///
+/// `total_length` is the length of the whole TD, and `queued` is the number of bytes in this TRB and all the TRBs before it.
+/// `max_packet_size` must not be 0, which [`Endpoint::new`] checks.
+///
+/// See the spec section [4.11.2.4] for more info.
+///
+/// [4.11.2.4]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A225%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C610%2C0%5D
+fn td_size(total_length: usize, queued: usize, max_packet_size: u16) -> u8 {
+    let max_packet_size = usize::from(max_packet_size);
+    let packets = total_length.div_ceil(max_packet_size);
+    let remaining = packets - queued / max_packet_size;
+
+    remaining.min(MAX_TD_SIZE).try_into().unwrap()
+}
+
+/// Tests that the _TD Size_ of a TRB counts the packets left after it, and saturates at [`MAX_TD_SIZE`]
+#[test_case]
+fn test_td_size() {
+    // 3 pages of 512-byte packets is 24 packets, 8 per page
+    assert_eq!(td_size(0x3000, 0x1000, 512), 16);
+    assert_eq!(td_size(0x3000, 0x2000, 512), 8);
+    // A partial packet at the end still counts as a packet
+    assert_eq!(td_size(0x2100, 0x1000, 512), 9);
+    // 0x10000 bytes of 64-byte packets is more than 31 packets
+    assert_eq!(td_size(0x10000, 0x1000, 64), 31);
+}
@@ -53,6 +53,20 @@ pub struct ConfigureEndpointTrb {
 }
 
 impl ConfigureEndpointTrb {
+    /// Constructs a new [`ConfigureEndpointTrb`] for the given slot
+    pub fn new(input_context_pointer: InputContextPointer, slot_id: u8) -> Self {
+        Self {
+            input_context_pointer,
+            slot_id,
+        }
+    }
+
+    /// Constructs a new [`ConfigureEndpointTrb`] which deconfigures all of the endpoints of the given slot except for
+    /// the default control endpoint, by setting the _Deconfigure_ bit.
+    pub fn deconfigure(slot_id: u8) -> Self {
+        Self::new(InputContextPointer::Deconfigure, slot_id)
+    }
+
     /// Converts the TRB to the data written to a TRB ring
     pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
         let (icp_low, icp_high, deconfigure) = match self.input_context_pointer {
@@ -0,0 +1,199 @@
+//! The [`ResetEndpointTrb`], [`StopEndpointTrb`], and [`SetTrDequeuePointerTrb`] types
+
+use x86_64::PhysAddr;
+
+use crate::pci::drivers::usb::xhci::trb::TrbType;
+
+/// The flags dword of a [`ResetEndpointTrb`]
+#[bitfield(u32)]
+pub struct ResetEndpointTrbFlags {
+    cycle: bool,
+
+    #[bits(8)]
+    _reserved: (),
+
+    transfer_state_preserve: bool,
+
+    #[bits(6, default = TrbType::ResetEndpointCommand)]
+    trb_type: TrbType,
+
+    #[bits(5)]
+    endpoint_id: u8,
+
+    #[bits(3)]
+    _reserved: (),
+
+    slot_id: u8,
+}
+
+/// A _Reset Endpoint TRB_, which resets an endpoint which is in the [`Halted`] state after a stall or transaction error,
+/// moving it to the [`Stopped`] state. The endpoint's transfer ring is not restarted, so this is usually followed by a
+/// [`SetTrDequeuePointerTrb`] to skip the TRB which caused the error.
+/// See the spec sections [4.6.8] and [6.4.3.7] for more information.
+///
+/// [`Halted`]: super::super::super::contexts::endpoint_context::EndpointState::Halted
+/// [`Stopped`]: super::super::super::contexts::endpoint_context::EndpointState::Stopped
+/// [4.6.8]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+/// [6.4.3.7]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+#[derive(Debug)]
+pub struct ResetEndpointTrb {
+    /// The slot ID of the device which the endpoint belongs to
+    pub slot_id: u8,
+    /// The _Device Context Index_ of the endpoint to reset
+    pub endpoint_id: u8,
+    /// Whether the controller should keep the endpoint's transfer state, so that the transfer which caused the error
+    /// can be retried. If this is `false`, the transfer state is discarded.
+    pub transfer_state_preserve: bool,
+}
+
+impl ResetEndpointTrb {
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        let flags = ResetEndpointTrbFlags::new()
+            .with_cycle(cycle)
+            .with_transfer_state_preserve(self.transfer_state_preserve)
+            .with_endpoint_id(self.endpoint_id)
+            .with_slot_id(self.slot_id);
+
+        // The first 3 dwords are all rsvdz, so just return 0s for them.
+        [0, 0, 0, flags.into()]
+    }
+}
+
+/// The flags dword of a [`StopEndpointTrb`]
+#[bitfield(u32)]
+pub struct StopEndpointTrbFlags {
+    cycle: bool,
+
+    #[bits(9)]
+    _reserved: (),
+
+    #[bits(6, default = TrbType::StopEndpointCommand)]
+    trb_type: TrbType,
+
+    #[bits(5)]
+    endpoint_id: u8,
+
+    #[bits(2)]
+    _reserved: (),
+
+    suspend: bool,
+
+    slot_id: u8,
+}
+
+/// A _Stop Endpoint TRB_, which stops the controller from processing an endpoint's transfer ring,
+/// moving it to the [`Stopped`] state. See the spec sections [4.6.9] and [6.4.3.8] for more information.
+///
+/// [`Stopped`]: super::super::super::contexts::endpoint_context::EndpointState::Stopped
+/// [4.6.9]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+/// [6.4.3.8]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+#[derive(Debug)]
+pub struct StopEndpointTrb {
+    /// The slot ID of the device which the endpoint belongs to
+    pub slot_id: u8,
+    /// The _Device Context Index_ of the endpoint to stop
+    pub endpoint_id: u8,
+    /// Whether the endpoint is being stopped so that the device can be suspended
+    pub suspend: bool,
+}
+
+impl StopEndpointTrb {
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        let flags = StopEndpointTrbFlags::new()
+            .with_cycle(cycle)
+            .with_endpoint_id(self.endpoint_id)
+            .with_suspend(self.suspend)
+            .with_slot_id(self.slot_id);
+
+        // The first 3 dwords are all rsvdz, so just return 0s for them.
+        [0, 0, 0, flags.into()]
+    }
+}
+
+/// The flags dword of a [`SetTrDequeuePointerTrb`]
+#[bitfield(u32)]
+pub struct SetTrDequeuePointerTrbFlags {
+    cycle: bool,
+
+    #[bits(9)]
+    _reserved: (),
+
+    #[bits(6, default = TrbType::SetTRDequeuePointerCommand)]
+    trb_type: TrbType,
+
+    #[bits(5)]
+    endpoint_id: u8,
+
+    #[bits(3)]
+    _reserved: (),
+
+    slot_id: u8,
+}
+
+/// A _Set TR Dequeue Pointer TRB_, which moves the controller's dequeue pointer for an endpoint in the [`Stopped`]
+/// or [`Error`] state. This is used to skip TRBs which should not be executed, such as the rest of a transfer which stalled.
+/// See the spec sections [4.6.10] and [6.4.3.9] for more information.
+///
+/// [`Stopped`]: super::super::super::contexts::endpoint_context::EndpointState::Stopped
+/// [`Error`]: super::super::super::contexts::endpoint_context::EndpointState::Error
+/// [4.6.10]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+/// [6.4.3.9]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+#[derive(Debug)]
+pub struct SetTrDequeuePointerTrb {
+    /// The slot ID of the device which the endpoint belongs to
+    pub slot_id: u8,
+    /// The _Device Context Index_ of the endpoint whose dequeue pointer is being set
+    pub endpoint_id: u8,
+    /// The new dequeue pointer. This must point to a TRB in the endpoint's transfer ring.
+    pub dequeue_pointer: PhysAddr,
+    /// The cycle state of the TRB at [`dequeue_pointer`], which the controller compares to the TRB's cycle bit
+    /// to check whether it has been written.
+    ///
+    /// [`dequeue_pointer`]: SetTrDequeuePointerTrb::dequeue_pointer
+    pub dequeue_cycle_state: bool,
+}
+
+impl SetTrDequeuePointerTrb {
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        assert!(
+            self.dequeue_pointer.is_aligned(16u64),
+            "Dequeue pointers passed in a SetTrDequeuePointerTrb must be 16-byte aligned"
+        );
+
+        // The dequeue cycle state is stored in bit 0 of the pointer.
+        // The stream context type in bits 1-3 is left as 0, as streams are not supported.
+        let pointer = self.dequeue_pointer.as_u64() | u64::from(self.dequeue_cycle_state);
+
+        #[allow(clippy::cast_possible_truncation)]
+        let pointer_low = pointer as u32;
+        let pointer_high = (pointer >> 32) as u32;
+
+        let flags = SetTrDequeuePointerTrbFlags::new()
+            .with_cycle(cycle)
+            .with_endpoint_id(self.endpoint_id)
+            .with_slot_id(self.slot_id);
+
+        // The stream ID in dword 2 is left as 0, as streams are not supported
+        [pointer_low, pointer_high, 0, flags.into()]
+    }
+}
+
+#[test_case]
+fn test_set_tr_dequeue_pointer_encoding() {
+    let trb = SetTrDequeuePointerTrb {
+        slot_id: 3,
+        endpoint_id: 5,
+        dequeue_pointer: PhysAddr::new(0x1_2345_6780),
+        dequeue_cycle_state: true,
+    };
+
+    let parts = trb.to_parts(true);
+
+    assert_eq!(parts[0], 0x2345_6781);
+    assert_eq!(parts[1], 0x1);
+    assert_eq!(parts[2], 0);
+    assert_eq!(parts[3], 0x0305_4001);
+}
@@ -0,0 +1,60 @@
+//! The [`EvaluateContextTrb`] type
+
+use x86_64::PhysAddr;
+
+use crate::pci::drivers::usb::xhci::trb::TrbType;
+
+/// The flags dword of an [`EvaluateContextTrb`]
+#[bitfield(u32)]
+pub struct EvaluateContextTrbFlags {
+    cycle: bool,
+
+    #[bits(9)]
+    _reserved: (),
+
+    #[bits(6, default = TrbType::EvaluateContextCommand)]
+    trb_type: TrbType,
+
+    #[bits(8)]
+    _reserved: (),
+
+    slot_id: u8,
+}
+
+/// An _Evaluate Context TRB_, which tells the controller that parameters in a device's slot context or
+/// endpoint contexts have changed, such as the max packet size of the default control endpoint.
+/// The contexts to evaluate are selected by the add context flags of the [`InputContext`].
+/// See the spec sections [4.6.7] and [6.4.3.6] for more information.
+///
+/// [`InputContext`]: super::super::super::contexts::input_context::InputContext
+/// [4.6.7]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+/// [6.4.3.6]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+#[derive(Debug)]
+pub struct EvaluateContextTrb {
+    /// The pointer to the [`InputContext`] to use
+    ///
+    /// [`InputContext`]: super::super::super::contexts::input_context::InputContext
+    pub input_context_pointer: PhysAddr,
+    /// The slot ID of the device whose contexts are being evaluated
+    pub slot_id: u8,
+}
+
+impl EvaluateContextTrb {
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        assert!(
+            self.input_context_pointer.is_aligned(16u64),
+            "Input contexts passed in an EvaluateContextTrb must be 16-byte aligned"
+        );
+
+        #[allow(clippy::cast_possible_truncation)]
+        let icp_low = self.input_context_pointer.as_u64() as u32;
+        let icp_high = (self.input_context_pointer.as_u64() >> 32) as u32;
+
+        let flags = EvaluateContextTrbFlags::new()
+            .with_cycle(cycle)
+            .with_slot_id(self.slot_id);
+
+        [icp_low, icp_high, 0, flags.into()]
+    }
+}
@@ -7,12 +7,16 @@ use crate::pci::drivers::usb::xhci::trb::GenericTrbFlags;
 
 use self::{
     configure_endpoint::ConfigureEndpointTrb,
+    endpoint::{ResetEndpointTrb, SetTrDequeuePointerTrb, StopEndpointTrb},
+    evaluate_context::EvaluateContextTrb,
     slot::{DisableSlotTrb, EnableSlotTrb},
 };
 
 use super::{link::LinkTrb, software_driven_rings::SoftwareDrivenTrbRing, RingFullError, TrbType};
 
 pub mod configure_endpoint;
+pub mod endpoint;
+pub mod evaluate_context;
 pub mod slot;
 pub mod address_device;
 
@@ -34,10 +38,10 @@ pub enum CommandTrb {
     DisableSlot(DisableSlotTrb),
     AddressDevice(AddressDeviceTrb),
     ConfigureEndpoint(ConfigureEndpointTrb),
-    EvaluateContext,
-    ResetEndpoint,
-    StopEndpoint,
-    SetTRDequeuePointer,
+    EvaluateContext(EvaluateContextTrb),
+    ResetEndpoint(ResetEndpointTrb),
+    StopEndpoint(StopEndpointTrb),
+    SetTRDequeuePointer(SetTrDequeuePointerTrb),
     ResetDevice,
     ForceEvent,
     NegotiateBandwidth,
@@ -63,10 +67,10 @@ impl CommandTrb {
             CommandTrb::DisableSlot(_) => TrbType::DisableSlotCommand,
             CommandTrb::AddressDevice(_) => TrbType::AddressDeviceCommand,
             CommandTrb::ConfigureEndpoint(_) => TrbType::ConfigureEndpointCommand,
-            CommandTrb::EvaluateContext => TrbType::EvaluateContextCommand,
-            CommandTrb::ResetEndpoint => TrbType::ResetEndpointCommand,
-            CommandTrb::StopEndpoint => TrbType::StopEndpointCommand,
-            CommandTrb::SetTRDequeuePointer => TrbType::SetTRDequeuePointerCommand,
+            CommandTrb::EvaluateContext(_) => TrbType::EvaluateContextCommand,
+            CommandTrb::ResetEndpoint(_) => TrbType::ResetEndpointCommand,
+            CommandTrb::StopEndpoint(_) => TrbType::StopEndpointCommand,
+            CommandTrb::SetTRDequeuePointer(_) => TrbType::SetTRDequeuePointerCommand,
             CommandTrb::ResetDevice => TrbType::ResetDeviceCommand,
             CommandTrb::ForceEvent => TrbType::ForceEventCommand,
             CommandTrb::NegotiateBandwidth => TrbType::NegotiateBandwidthCommand,
@@ -89,10 +93,10 @@ impl CommandTrb {
             CommandTrb::DisableSlot(disable_slot) => disable_slot.to_parts(cycle),
             CommandTrb::AddressDevice(address_device) => address_device.to_parts(cycle),
             CommandTrb::ConfigureEndpoint(configure_endpoint) => configure_endpoint.to_parts(cycle),
-            CommandTrb::EvaluateContext => todo!(),
-            CommandTrb::ResetEndpoint => todo!(),
-            CommandTrb::StopEndpoint => todo!(),
-            CommandTrb::SetTRDequeuePointer => todo!(),
+            CommandTrb::EvaluateContext(evaluate_context) => evaluate_context.to_parts(cycle),
+            CommandTrb::ResetEndpoint(reset_endpoint) => reset_endpoint.to_parts(cycle),
+            CommandTrb::StopEndpoint(stop_endpoint) => stop_endpoint.to_parts(cycle),
+            CommandTrb::SetTRDequeuePointer(set_dequeue) => set_dequeue.to_parts(cycle),
             CommandTrb::ResetDevice => todo!(),
             CommandTrb::ForceEvent => todo!(),
             CommandTrb::NegotiateBandwidth => todo!(),
@@ -157,6 +161,13 @@ impl CommandTrbRing {
         unsafe { self.0.enqueue(|cycle| trb.to_parts(cycle)) }
     }
 
+    /// Returns whether the ring has no space for new TRBs, meaning [`enqueue`] would return a [`RingFullError`].
+    ///
+    /// [`enqueue`]: CommandTrbRing::enqueue
+    pub fn is_full(&self) -> bool {
+        self.0.is_full()
+    }
+
     /// Updates the ring's dequeue pointer
     ///
     /// # Safety
@@ -48,7 +48,7 @@ pub struct DisableSlotTrb {
     #[bits(9)]
     _reserved: (),
 
-    #[bits(6, default = TrbType::EnableSlotCommand)]
+    #[bits(6, default = TrbType::DisableSlotCommand)]
     pub trb_type: TrbType,
 
     #[bits(8)]
@@ -3,7 +3,7 @@
 
 use x86_64::PhysAddr;
 
-use crate::pci::drivers::usb::xhci::trb::TrbType;
+use crate::pci::drivers::usb::xhci::{trb::TrbType, XhciError};
 
 #[bitfield(u32)]
 pub struct CommandCompletionTrbFlags {
@@ -369,6 +369,17 @@ impl CompletionCode {
     pub const fn new(bits: u8) -> Self {
         Self::from_bits(bits)
     }
+
+    /// Converts the completion code into a [`Result`], which is an [`XhciError::Completion`]
+    /// containing the code unless it is [`Success`].
+    ///
+    /// [`Success`]: CompletionCode::Success
+    pub const fn into_result(self) -> Result<(), XhciError> {
+        match self {
+            Self::Success => Ok(()),
+            code => Err(XhciError::Completion(code)),
+        }
+    }
 }
 
 /// A _Command Completion Event_ TRB. This is sent by the controller when it executes a [`CommandTrb`].
@@ -1,11 +1,15 @@
 //! The [`EventTrb`] type
 
-use self::{command_completion::CommandCompletionTrb, port_status_change::PortStatusChangeTrb};
+use self::{
+    command_completion::CommandCompletionTrb, port_status_change::PortStatusChangeTrb,
+    transfer::TransferEventTrb,
+};
 
 use super::{GenericTrbFlags, TrbType};
 
 pub mod command_completion;
 pub mod port_status_change;
+pub mod transfer;
 
 /// An event sent from the controller to the OS on an [`EventTrbRing`]
 ///
@@ -13,7 +17,10 @@ pub mod port_status_change;
 #[derive(Debug, Clone, Copy)]
 #[allow(clippy::missing_docs_in_private_items)] // TODO: add docs with structs
 pub enum EventTrb {
-    Transfer,
+    /// A TRB sent to indicate the completion or failure of a [`TransferTrb`].
+    ///
+    /// [`TransferTrb`]: super::transfer::TransferTrb
+    Transfer(TransferEventTrb),
     /// A TRB sent to indicate the completion or failure of a [`CommandTrb`].
     ///
     /// [`CommandTrb`]: super::CommandTrb
@@ -32,7 +39,7 @@ impl EventTrb {
         let generic_flags = GenericTrbFlags::from(data[3]);
 
         match generic_flags.trb_type() {
-            TrbType::TransferEvent => Self::Transfer,
+            TrbType::TransferEvent => Self::Transfer(TransferEventTrb::new(data)),
             TrbType::CommandCompletionEvent => {
                 Self::CommandCompletion(CommandCompletionTrb::new(data))
             }
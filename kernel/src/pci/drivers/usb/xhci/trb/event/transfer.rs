@@ -0,0 +1,91 @@
+//! The [`TransferEventTrb`] type
+
+use x86_64::PhysAddr;
+
+use crate::pci::drivers::usb::xhci::trb::TrbType;
+
+use super::command_completion::CompletionCode;
+
+#[bitfield(u32)]
+pub struct TransferEventTrbFlags {
+    pub cycle: bool,
+
+    #[bits(1)]
+    _reserved0: (),
+
+    /// Whether the [`trb_pointer`] field holds the data of an _Event Data_ TRB rather than a pointer
+    ///
+    /// [`trb_pointer`]: TransferEventTrb::trb_pointer
+    pub event_data: bool,
+
+    #[bits(7)]
+    _reserved1: (),
+
+    #[bits(6)]
+    pub trb_type: TrbType,
+
+    /// The _Device Context Index_ of the endpoint which generated the event.
+    /// This is 1 for the control endpoint, `2n` for OUT endpoint `n`, and `2n + 1` for IN endpoint `n`.
+    #[bits(5)]
+    pub endpoint_id: u8,
+
+    #[bits(3)]
+    _reserved2: (),
+
+    /// The ID of the slot which generated the event
+    pub slot_id: u8,
+}
+
+/// A _Transfer Event_ TRB. This is sent by the controller when a TRB on a [`TransferTrbRing`] with its
+/// _Interrupt On Completion_ flag set completes, or when an error occurs while processing a transfer TRB.
+///
+/// See the spec section [6.4.2.1] for the definition of this structure.
+///
+/// [`TransferTrbRing`]: super::super::TransferTrbRing
+/// [6.4.2.1]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A483%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C694%2C0%5D
+#[derive(Debug, Clone, Copy)]
+pub struct TransferEventTrb {
+    /// The address of the [`TransferTrb`] which generated this event
+    ///
+    /// [`TransferTrb`]: super::super::transfer::TransferTrb
+    pub trb_pointer: PhysAddr,
+
+    /// The number of bytes which were _not_ transferred by the TRB which generated this event
+    pub transfer_length: u32,
+
+    /// The success or error code of the transfer
+    pub completion_code: CompletionCode,
+
+    /// The TRB's flags
+    pub flags: TransferEventTrbFlags,
+}
+
+impl TransferEventTrb {
+    /// Constructs a new [`TransferEventTrb`] from the data read from the event ring
+    pub fn new(data: [u32; 4]) -> Self {
+        let trb_pointer = PhysAddr::new(u64::from(data[0]) | u64::from(data[1]) << 32);
+
+        let transfer_length = data[2] & ((1 << 24) - 1);
+        #[allow(clippy::cast_possible_truncation)]
+        let completion_code = CompletionCode::new((data[2] >> 24) as u8);
+
+        let flags = TransferEventTrbFlags::from(data[3]);
+
+        Self {
+            trb_pointer,
+            transfer_length,
+            completion_code,
+            flags,
+        }
+    }
+
+    /// The ID of the slot which generated the event
+    pub fn slot_id(&self) -> u8 {
+        self.flags.slot_id()
+    }
+
+    /// The _Device Context Index_ of the endpoint which generated the event
+    pub fn endpoint_id(&self) -> u8 {
+        self.flags.endpoint_id()
+    }
+}
@@ -2,7 +2,7 @@
 
 use x86_64::PhysAddr;
 
-use crate::allocator::PageBox;
+use crate::allocator::DmaBuffer;
 
 use super::{EventTrb, GenericTrbFlags};
 
@@ -11,14 +11,14 @@ use super::{EventTrb, GenericTrbFlags};
 /// This ring contains [`EventTrb`]s for the OS to respond to.
 #[derive(Debug)]
 pub struct EventTrbRing {
-    /// The page where the ring is in memory
-    ring: PageBox,
+    /// The TRBs of the ring in memory
+    ring: DmaBuffer<EventRingSegment>,
     /// As the event ring is written by the controller, link TRBs can't be used to set the structure of the ring
     /// like for the command and transfer rings. Instead, a secondary table is used which stores the addresses
     /// and lengths of ring segments.
     ///
     /// See [`EventRingSegmentTableEntry`].
-    segment_table: PageBox,
+    segment_table: DmaBuffer<EventRingSegmentTableEntry>,
 
     /// The index where new TRBs will be dequeued
     dequeue: usize,
@@ -26,6 +26,9 @@ pub struct EventTrbRing {
     cycle_state: bool,
 }
 
+/// The memory of an [`EventTrbRing`]'s single segment
+type EventRingSegment = [[u32; 4]; EventTrbRing::SEGMENT_SIZE as usize];
+
 impl EventTrbRing {
     /// The number of TRBs per page of memory
     const SEGMENT_SIZE: u16 = 0x1000 / 16;
@@ -36,18 +39,11 @@ impl EventTrbRing {
     /// * The given `dequeue_pointer_register` pointer must point to a valid register.
     ///    The pointer must be valid for the whole lifetime of this struct.
     pub unsafe fn new() -> Self {
-        let ring = PageBox::new_zeroed();
-        let mut segment_table = PageBox::new_zeroed();
-
-        // SAFETY: This writes the first entry of the ERST
-        unsafe {
-            segment_table
-                .as_mut_ptr::<EventRingSegmentTableEntry>()
-                .write_volatile(EventRingSegmentTableEntry::new(
-                    ring.phys_frame().start_address(),
-                    Self::SEGMENT_SIZE,
-                ));
-        }
+        let ring = DmaBuffer::new([[0; 4]; Self::SEGMENT_SIZE as usize]);
+        let segment_table = DmaBuffer::new(EventRingSegmentTableEntry::new(
+            ring.phys_addr(),
+            Self::SEGMENT_SIZE,
+        ));
 
         Self {
             ring,
@@ -65,13 +61,10 @@ impl EventTrbRing {
     /// The caller must make sure the pointer is updated if this method returns `Some`,
     /// or else the controller will not be able to issue a new TRB in the location this one was read.
     pub unsafe fn dequeue(&mut self) -> Option<(EventTrb, PhysAddr)> {
-        // SAFETY: This reads the TRB at `dequeue`.
-        let raw = unsafe {
-            self.ring
-                .as_ptr::<[u32; 4]>()
-                .add(self.dequeue)
-                .read_volatile()
-        };
+        let raw = self
+            .ring
+            .get(self.dequeue)
+            .expect("Dequeue index should be within the ring");
 
         let current_dequeue = self.dequeue;
         
@@ -94,7 +87,7 @@ impl EventTrbRing {
 
     /// Gets the physical address of the start of the first segment of the ring
     pub fn ring_start_addr(&self) -> PhysAddr {
-        self.ring.phys_frame().start_address()
+        self.ring.phys_addr()
     }
 
     /// Gets the index into the _Event Ring Segment Table_ of the segment of the start of the ring
@@ -109,7 +102,7 @@ impl EventTrbRing {
 
     /// Gets the physical address of the segment table for this event ring
     pub fn segment_table_start_addr(&self) -> PhysAddr {
-        self.segment_table.phys_frame().start_address()
+        self.segment_table.phys_addr()
     }
 
     /// Gets the number of items in the segment table for this event ring
@@ -120,7 +113,7 @@ impl EventTrbRing {
 
 /// An entry in the segment table for an event ring. This indicates the address and length of a segment of an [`EventTrbRing`].
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct EventRingSegmentTableEntry {
     /// The base address of the segment.
     ///
@@ -5,17 +5,27 @@ use core::cmp::Ordering;
 use x86_64::PhysAddr;
 
 use crate::{
-    allocator::PageBox,
+    allocator::DmaBuffer,
     pci::drivers::usb::xhci::trb::{command::CommandTrb, link::LinkTrb},
 };
 
 use super::{transfer::TransferTrb, RingFullError};
 
 /// A type which is used for the implementation of [`CommandTrbRing`] and [`TransferTrbRing`]
+///
+/// The ring consists of a single segment which links back to itself, so it never grows.
+/// When every slot is owned by the controller, [`enqueue`] returns a [`RingFullError`] instead of blocking.
+/// Callers which can wait for the controller to catch up should check [`is_full`] and yield until
+/// [`update_dequeue`] frees up space - see [`XhciController::write_command_trb_wait`].
+///
+/// [`enqueue`]: SoftwareDrivenTrbRing::enqueue
+/// [`is_full`]: SoftwareDrivenTrbRing::is_full
+/// [`update_dequeue`]: SoftwareDrivenTrbRing::update_dequeue
+/// [`XhciController::write_command_trb_wait`]: super::super::XhciController::write_command_trb_wait
 #[derive(Debug)]
 pub(super) struct SoftwareDrivenTrbRing {
-    /// The page where the ring is in memory
-    page: PageBox,
+    /// The TRBs of the ring in memory
+    ring: DmaBuffer<RingMemory>,
 
     /// The index into the ring to write new TRBs
     enqueue: usize,
@@ -36,6 +46,9 @@ pub(super) struct SoftwareDrivenTrbRing {
     dequeue: usize,
 }
 
+/// The memory of a [`SoftwareDrivenTrbRing`]
+type RingMemory = [[u32; 4]; SoftwareDrivenTrbRing::TOTAL_LENGTH];
+
 impl SoftwareDrivenTrbRing {
     /// The total length of the command ring including the link TRB
     pub const TOTAL_LENGTH: usize = 0x1000 / 16;
@@ -45,7 +58,7 @@ impl SoftwareDrivenTrbRing {
     /// Allocates a new [`SoftwareDrivenTrbRing`]
     pub fn new() -> Self {
         Self {
-            page: PageBox::new_zeroed(),
+            ring: DmaBuffer::new([[0; 4]; Self::TOTAL_LENGTH]),
             enqueue: 0,
 
             cycle_state: true,
@@ -55,7 +68,7 @@ impl SoftwareDrivenTrbRing {
 
     /// Gets the physical address of the start of the first segment of the ring
     pub fn ring_start_addr(&self) -> PhysAddr {
-        self.page.phys_frame().start_address()
+        self.ring.phys_addr()
     }
 
     /// Writes the given data to the TRB slot at `i`
@@ -65,17 +78,13 @@ impl SoftwareDrivenTrbRing {
     /// * The TRB at `i` is currently owned by the OS
     /// * The caller is responsible for the behaviour of the controller in response to this TRB
     unsafe fn write(&mut self, i: usize, value: [u32; 4]) {
-        assert!(i < Self::TOTAL_LENGTH);
         assert!(value[3] & 1 == self.cycle_state as u32);
 
-        // SAFETY: This TRB is owned by the OS and is valid.
+        // This TRB is owned by the OS and is valid.
         // The caller is responsible for the behaviour of the controller.
-        unsafe {
-            self.page
-                .as_mut_ptr::<[u32; 4]>()
-                .add(i)
-                .write_volatile(value);
-        }
+        self.ring
+            .set(i, value)
+            .expect("TRB index should be within the ring");
     }
 
     /// Writes to the link TRB at the end of the array.
@@ -127,8 +136,20 @@ impl SoftwareDrivenTrbRing {
     /// This value is only accurate if [`dequeue`] is up-to-date.
     ///
     /// [`dequeue`]: SoftwareDrivenTrbRing::dequeue
-    fn free_space(&self) -> usize {
-        Self::USABLE_LENGTH - self.trbs_in_buffer()
+    pub fn free_space(&self) -> usize {
+        // One slot is always left empty, as otherwise a full ring would have `enqueue == dequeue`
+        // and be indistinguishable from an empty one.
+        Self::USABLE_LENGTH - 1 - self.trbs_in_buffer()
+    }
+
+    /// Returns whether the ring has no space for new TRBs, meaning [`enqueue`] would return a [`RingFullError`].
+    ///
+    /// This value is only accurate if [`dequeue`] is up-to-date.
+    ///
+    /// [`enqueue`]: SoftwareDrivenTrbRing::enqueue
+    /// [`dequeue`]: SoftwareDrivenTrbRing::dequeue
+    pub fn is_full(&self) -> bool {
+        self.free_space() == 0
     }
 
     /// Writes a TRB to the buffer.
@@ -143,7 +164,7 @@ impl SoftwareDrivenTrbRing {
         &mut self,
         trb: impl FnOnce(bool) -> [u32; 4],
     ) -> Result<PhysAddr, RingFullError> {
-        if self.free_space() == 0 {
+        if self.is_full() {
             return Err(RingFullError);
         }
 
@@ -194,7 +215,55 @@ impl SoftwareDrivenTrbRing {
             self.ring_start_addr()
         );
 
-        // The dequeue pointer is one TRB on from the acknowledged TRB, but needs to wrap around the end of the ring.
-        self.dequeue = (acknowledged + 1) % Self::TOTAL_LENGTH;
+        // The dequeue pointer is one TRB on from the acknowledged TRB, but needs to skip over the link TRB at the end of the ring.
+        self.dequeue = (acknowledged + 1) % Self::USABLE_LENGTH;
+    }
+
+    /// Discards any TRBs which the controller has not processed by moving the dequeue pointer up to the enqueue pointer.
+    /// Returns the address of the enqueue pointer and the cycle state of the TRB there, to pass to the controller in a
+    /// [Set TR Dequeue Pointer] command.
+    ///
+    /// # Safety
+    /// * The controller must not be processing the ring, and must not restart until its dequeue pointer has been set to the returned address.
+    ///
+    /// [Set TR Dequeue Pointer]: super::command::endpoint::SetTrDequeuePointerTrb
+    pub unsafe fn skip_pending(&mut self) -> (PhysAddr, bool) {
+        self.dequeue = self.enqueue;
+
+        (self.ring_start_addr() + self.enqueue * 16, self.cycle_state)
+    }
+}
+
+/// Tests that more than [`TOTAL_LENGTH`] TRBs can be written to a ring as long as the dequeue pointer keeps up,
+/// and that the ring reports being full when it doesn't.
+///
+/// [`TOTAL_LENGTH`]: SoftwareDrivenTrbRing::TOTAL_LENGTH
+#[test_case]
+fn test_ring_wraps_when_dequeue_advances() {
+    use alloc::collections::VecDeque;
+
+    let mut ring = SoftwareDrivenTrbRing::new();
+    let mut pending = VecDeque::new();
+
+    for _ in 0..SoftwareDrivenTrbRing::TOTAL_LENGTH * 3 {
+        if ring.is_full() {
+            assert_eq!(pending.len(), SoftwareDrivenTrbRing::USABLE_LENGTH - 1);
+
+            // Act as the controller and acknowledge the oldest TRB
+            let acknowledged = pending.pop_front().unwrap();
+            // SAFETY: No controller is reading from this ring, so the dequeue pointer is only used for bookkeeping
+            unsafe { ring.update_dequeue(acknowledged) };
+        }
+
+        // SAFETY: No controller is reading from this ring
+        let trb_addr = unsafe { ring.enqueue(|cycle| CommandTrb::NoOp.to_parts(cycle)) }
+            .expect("Ring should have had space after the dequeue pointer advanced");
+        pending.push_back(trb_addr);
     }
+
+    // Acknowledging every TRB should leave the ring empty
+    let last = *pending.back().unwrap();
+    // SAFETY: No controller is reading from this ring
+    unsafe { ring.update_dequeue(last) };
+    assert_eq!(ring.trbs_in_buffer(), 0);
 }
\ No newline at end of file
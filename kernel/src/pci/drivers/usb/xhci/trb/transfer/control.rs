@@ -0,0 +1,336 @@
+//! The [`SetupStageTrb`], [`DataStageTrb`], and [`StatusStageTrb`] types, which make up a control transfer.
+//!
+//! See the spec section [4.11.2.2] for how these TRBs are used together.
+//!
+//! [4.11.2.2]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A222%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C694%2C0%5D
+
+use x86_64::PhysAddr;
+
+use crate::pci::drivers::usb::requests::SetupPacket;
+
+use super::super::TrbType;
+
+/// The value of the _Transfer Type_ field of a [`SetupStageTrb`], which tells the controller
+/// whether a data stage follows and which direction it transfers data in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupTransferType {
+    /// There is no data stage
+    NoData,
+    /// The data stage sends data from the host to the device
+    Out,
+    /// The data stage sends data from the device to the host
+    In,
+}
+
+impl SetupTransferType {
+    /// Constructs a [`SetupTransferType`] from its bit representation
+    const fn from_bits(bits: u32) -> Self {
+        match bits {
+            2 => Self::Out,
+            3 => Self::In,
+            _ => Self::NoData,
+        }
+    }
+
+    /// Converts a [`SetupTransferType`] into its bit representation
+    const fn into_bits(self) -> u32 {
+        match self {
+            Self::NoData => 0,
+            Self::Out => 2,
+            Self::In => 3,
+        }
+    }
+}
+
+#[bitfield(u32)]
+struct SetupStageTrbConfig {
+    /// The length of the setup packet. This is always 8.
+    #[bits(17, default = 8)]
+    transfer_length: u32,
+
+    #[bits(5)]
+    _reserved: (),
+
+    /// The index of the Interrupter that will receive events generated by this TRB
+    #[bits(10)]
+    interrupter_target: u16,
+}
+
+#[bitfield(u32)]
+struct SetupStageTrbFlags {
+    /// This bit is used to mark the Enqueue Pointer of the Transfer ring
+    cycle: bool,
+
+    #[bits(4)]
+    _reserved: (),
+
+    /// Whether the controller should send a _Transfer Event_ when this TRB completes
+    interrupt_on_completion: bool,
+
+    /// Whether the setup packet is stored in the TRB itself. This is always `true`.
+    #[bits(1, default = true)]
+    immediate_data: bool,
+
+    #[bits(3)]
+    _reserved: (),
+
+    /// Should always be [`SetupStage`][TrbType::SetupStage]
+    #[bits(6, default = TrbType::SetupStage)]
+    trb_type: TrbType,
+
+    /// Whether a data stage follows and in which direction
+    #[bits(2)]
+    transfer_type: SetupTransferType,
+
+    #[bits(14)]
+    _reserved: (),
+}
+
+/// A _Setup Stage TRB_, the first TRB of a control transfer, which holds the 8-byte [`SetupPacket`] sent to the device.
+///
+/// See the spec section [6.4.1.2.1] for the definition of this TRB.
+///
+/// [6.4.1.2.1]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A475%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C694%2C0%5D
+#[derive(Debug)]
+pub struct SetupStageTrb {
+    /// The setup packet to send to the device
+    packet: SetupPacket,
+    /// Configuration for the TRB
+    config: SetupStageTrbConfig,
+    /// The TRB flags
+    flags: SetupStageTrbFlags,
+}
+
+impl SetupStageTrb {
+    /// Constructs a new [`SetupStageTrb`] for the given packet.
+    /// The transfer type is derived from the direction and length of the packet.
+    pub fn new(packet: SetupPacket) -> Self {
+        let transfer_type = match (packet.length, packet.is_device_to_host()) {
+            (0, _) => SetupTransferType::NoData,
+            (_, true) => SetupTransferType::In,
+            (_, false) => SetupTransferType::Out,
+        };
+
+        Self {
+            packet,
+            config: SetupStageTrbConfig::new(),
+            flags: SetupStageTrbFlags::new().with_transfer_type(transfer_type),
+        }
+    }
+
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        let packet = self.packet.to_bytes();
+
+        [
+            u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]),
+            u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            self.config.into(),
+            self.flags.with_cycle(cycle).into(),
+        ]
+    }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.config.set_interrupter_target(interrupter);
+    }
+}
+
+#[bitfield(u32)]
+struct DataStageTrbConfig {
+    /// The number of bytes to transfer
+    #[bits(17)]
+    transfer_length: u32,
+
+    /// An indicator of the number of packets remaining in the TD.
+    ///
+    /// See the spec section [4.11.2.4] for how to calculate this value
+    ///
+    /// [4.11.2.4]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A225%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C610%2C0%5D
+    #[bits(5)]
+    td_size: u8,
+
+    /// The index of the Interrupter that will receive events generated by this TRB
+    #[bits(10)]
+    interrupter_target: u16,
+}
+
+#[bitfield(u32)]
+struct DataStageTrbFlags {
+    /// This bit is used to mark the Enqueue Pointer of the Transfer ring
+    cycle: bool,
+    /// If `true`, the controller shall fetch and evaluate the next TRB before saving the endpoint state
+    evaluate_next_trb: bool,
+    /// Whether the controller should send a _Transfer Event_ if fewer bytes than [`transfer_length`] are transferred
+    ///
+    /// [`transfer_length`]: DataStageTrbConfig::transfer_length
+    interrupt_on_short_packet: bool,
+    /// If `true`, the controller is allowed to set the No Snoop bit on PCIe transactions initiated by this TRB
+    no_snoop: bool,
+    /// Whether there are more TRBs in the TD after this one
+    chain: bool,
+    /// Whether the controller should send a _Transfer Event_ when this TRB completes
+    interrupt_on_completion: bool,
+    /// Whether the data buffer pointer is immediate data rather than a pointer
+    immediate_data: bool,
+
+    #[bits(3)]
+    _reserved: (),
+
+    /// Should always be [`DataStage`][TrbType::DataStage]
+    #[bits(6, default = TrbType::DataStage)]
+    trb_type: TrbType,
+
+    /// `true` if data is sent from the device to the host, `false` otherwise
+    direction_in: bool,
+
+    #[bits(15)]
+    _reserved: (),
+}
+
+/// A _Data Stage TRB_, which transfers the data of a control transfer to or from a buffer.
+///
+/// See the spec section [6.4.1.2.2] for the definition of this TRB.
+///
+/// [6.4.1.2.2]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A476%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C694%2C0%5D
+#[derive(Debug)]
+pub struct DataStageTrb {
+    /// The physical address of the data buffer
+    buffer: PhysAddr,
+    /// Configuration for the TRB
+    config: DataStageTrbConfig,
+    /// The TRB flags
+    flags: DataStageTrbFlags,
+}
+
+impl DataStageTrb {
+    /// Constructs a new [`DataStageTrb`] transferring `length` bytes to or from `buffer`.
+    ///
+    /// The TRB has its _Interrupt on Short Packet_ flag set, so if the device sends fewer bytes than `length`,
+    /// a _Transfer Event_ with a [`ShortPacket`] completion code is sent giving the number of bytes which were not transferred.
+    ///
+    /// [`ShortPacket`]: super::super::event::command_completion::CompletionError::ShortPacket
+    pub fn new(buffer: PhysAddr, length: u16, direction_in: bool) -> Self {
+        Self {
+            buffer,
+            config: DataStageTrbConfig::new().with_transfer_length(length.into()),
+            flags: DataStageTrbFlags::new()
+                .with_direction_in(direction_in)
+                .with_interrupt_on_short_packet(true),
+        }
+    }
+
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        let buffer = self.buffer.as_u64();
+
+        #[allow(clippy::cast_possible_truncation)]
+        [
+            buffer as u32,
+            (buffer >> 32) as u32,
+            self.config.into(),
+            self.flags.with_cycle(cycle).into(),
+        ]
+    }
+
+    /// The value of the chain bit
+    pub fn chain(&self) -> bool {
+        self.flags.chain()
+    }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.config.set_interrupter_target(interrupter);
+    }
+}
+
+#[bitfield(u32)]
+struct StatusStageTrbConfig {
+    #[bits(22)]
+    _reserved: (),
+
+    /// The index of the Interrupter that will receive events generated by this TRB
+    #[bits(10)]
+    interrupter_target: u16,
+}
+
+#[bitfield(u32)]
+struct StatusStageTrbFlags {
+    /// This bit is used to mark the Enqueue Pointer of the Transfer ring
+    cycle: bool,
+    /// If `true`, the controller shall fetch and evaluate the next TRB before saving the endpoint state
+    evaluate_next_trb: bool,
+
+    #[bits(2)]
+    _reserved: (),
+
+    /// Whether there are more TRBs in the TD after this one
+    chain: bool,
+    /// Whether the controller should send a _Transfer Event_ when this TRB completes
+    interrupt_on_completion: bool,
+
+    #[bits(4)]
+    _reserved: (),
+
+    /// Should always be [`StatusStage`][TrbType::StatusStage]
+    #[bits(6, default = TrbType::StatusStage)]
+    trb_type: TrbType,
+
+    /// `true` if the status is sent from the device to the host, `false` otherwise.
+    /// This is the opposite direction to the data stage, or IN if there is no data stage.
+    direction_in: bool,
+
+    #[bits(15)]
+    _reserved: (),
+}
+
+/// A _Status Stage TRB_, the last TRB of a control transfer, where the receiver of the data acknowledges it.
+///
+/// See the spec section [6.4.1.2.3] for the definition of this TRB.
+///
+/// [6.4.1.2.3]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A478%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C694%2C0%5D
+#[derive(Debug)]
+pub struct StatusStageTrb {
+    /// Configuration for the TRB
+    config: StatusStageTrbConfig,
+    /// The TRB flags
+    flags: StatusStageTrbFlags,
+}
+
+impl StatusStageTrb {
+    /// Constructs a new [`StatusStageTrb`] which sends a _Transfer Event_ when it completes
+    pub fn new(direction_in: bool) -> Self {
+        Self {
+            config: StatusStageTrbConfig::new(),
+            flags: StatusStageTrbFlags::new()
+                .with_direction_in(direction_in)
+                .with_interrupt_on_completion(true),
+        }
+    }
+
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        [
+            0,
+            0,
+            self.config.into(),
+            self.flags.with_cycle(cycle).into(),
+        ]
+    }
+
+    /// The value of the chain bit
+    pub fn chain(&self) -> bool {
+        self.flags.chain()
+    }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.config.set_interrupter_target(interrupter);
+    }
+}
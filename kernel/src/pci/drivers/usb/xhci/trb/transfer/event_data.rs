@@ -0,0 +1,98 @@
+//! The [`EventDataTrb`] type
+
+use super::super::TrbType;
+
+#[bitfield(u32)]
+struct EventDataTrbConfig {
+    #[bits(22)]
+    _reserved: (),
+
+    /// The index of the Interrupter that will receive events generated by this TRB
+    #[bits(10)]
+    interrupter_target: u16,
+}
+
+#[bitfield(u32)]
+struct EventDataTrbFlags {
+    /// This bit is used to mark the Enqueue Pointer of the Transfer ring
+    cycle: bool,
+    /// If `true`, the controller shall fetch and evaluate the next TRB before saving the endpoint state
+    evaluate_next_trb: bool,
+
+    #[bits(2)]
+    _reserved: (),
+
+    /// Whether there are more TRBs in the TD after this one
+    chain: bool,
+    /// Whether the controller should send a _Transfer Event_ when this TRB is reached
+    interrupt_on_completion: bool,
+
+    #[bits(3)]
+    _reserved: (),
+
+    /// If this field and [`interrupt_on_completion`] are both `true`, the _Transfer Event_ will not assert an interrupt.
+    ///
+    /// [`interrupt_on_completion`]: EventDataTrbFlags::interrupt_on_completion
+    block_event_interrupt: bool,
+
+    /// Should always be [`EventData`][TrbType::EventData]
+    #[bits(6, default = TrbType::EventData)]
+    trb_type: TrbType,
+
+    #[bits(16)]
+    _reserved: (),
+}
+
+/// An _Event Data TRB_, which makes the controller send a _Transfer Event_ containing [`data`] instead of a TRB pointer.
+/// This is placed in a TD to find out how far through the TD the controller has got.
+///
+/// See the spec section 6.4.4.2 for the definition of this TRB.
+///
+/// [`data`]: EventDataTrb::data
+#[derive(Debug)]
+pub struct EventDataTrb {
+    /// The value copied into the _Transfer Event_
+    data: u64,
+    /// Configuration for the TRB
+    config: EventDataTrbConfig,
+    /// The TRB flags
+    flags: EventDataTrbFlags,
+}
+
+impl EventDataTrb {
+    /// Constructs a new [`EventDataTrb`] which sends a _Transfer Event_ containing `data` when it is reached.
+    /// `chain` should be `true` unless this is the last TRB in the TD.
+    #[allow(dead_code)] // Not yet used by any driver
+    pub fn new(data: u64, chain: bool) -> Self {
+        Self {
+            data,
+            config: EventDataTrbConfig::new(),
+            flags: EventDataTrbFlags::new()
+                .with_chain(chain)
+                .with_interrupt_on_completion(true),
+        }
+    }
+
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        #[allow(clippy::cast_possible_truncation)]
+        [
+            self.data as u32,
+            (self.data >> 32) as u32,
+            self.config.into(),
+            self.flags.with_cycle(cycle).into(),
+        ]
+    }
+
+    /// The value of the chain bit
+    pub fn chain(&self) -> bool {
+        self.flags.chain()
+    }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.config.set_interrupter_target(interrupter);
+    }
+}
@@ -0,0 +1,123 @@
+//! The [`IsochTrb`] type
+
+use x86_64::PhysAddr;
+
+use super::super::TrbType;
+
+/// The configuration data of an [`IsochTrb`]. This has the same layout as a [`NormalTrb`][super::normal::NormalTrb]'s.
+#[bitfield(u32)]
+struct IsochTrbConfig {
+    /// For an OUT transfer, the number of bytes the controller will fetch from the buffer.
+    /// For an IN transfer, the number of bytes the OS expects to be written to the buffer.
+    #[bits(17)]
+    transfer_length: u32,
+
+    /// An indicator of the number of packets remaining in the TD
+    #[bits(5)]
+    td_size: u8,
+
+    /// The index of the Interrupter that will receive events generated by this TRB
+    #[bits(10)]
+    interrupter_target: u16,
+}
+
+#[bitfield(u32)]
+struct IsochTrbFlags {
+    /// This bit is used to mark the Enqueue Pointer of the Transfer ring
+    cycle: bool,
+    /// If `true`, the controller shall fetch and evaluate the next TRB before saving the endpoint state
+    evaluate_next_trb: bool,
+    /// If `true`, a _Transfer Event_ is sent if a _Short Packet_ is encountered for this TRB
+    interrupt_on_short_packet: bool,
+    /// If `true`, the controller is allowed to set the _No Snoop_ bit on PCIe transactions initiated by this TRB
+    no_snoop: bool,
+    /// Whether there are more TRBs in the TD after this one
+    chain: bool,
+    /// Whether the controller should send a _Transfer Event_ when this TRB completes
+    interrupt_on_completion: bool,
+    /// Whether the data buffer pointer is immediate data rather than a pointer
+    immediate_data: bool,
+
+    /// The number of bursts in the TD, minus one
+    #[bits(2)]
+    transfer_burst_count: u8,
+
+    /// If this field and [`interrupt_on_completion`] are both `true`, the _Transfer Event_ will not assert an interrupt.
+    ///
+    /// [`interrupt_on_completion`]: IsochTrbFlags::interrupt_on_completion
+    block_event_interrupt: bool,
+
+    /// Should always be [`Isoch`][TrbType::Isoch]
+    #[bits(6, default = TrbType::Isoch)]
+    trb_type: TrbType,
+
+    /// The number of packets in the last burst of the TD, minus one
+    #[bits(4)]
+    transfer_last_burst_packet_count: u8,
+
+    /// The frame in which the TD should be transferred, if [`start_isoch_asap`] is `false`
+    ///
+    /// [`start_isoch_asap`]: IsochTrbFlags::start_isoch_asap
+    #[bits(11)]
+    frame_id: u16,
+
+    /// If `true`, the TD is transferred as soon as possible rather than in the frame given by [`frame_id`]
+    ///
+    /// [`frame_id`]: IsochTrbFlags::frame_id
+    start_isoch_asap: bool,
+}
+
+/// An _Isoch TRB_, the first TRB of a TD on an isochronous endpoint.
+///
+/// See the spec section 6.4.1.3 for the definition of this TRB.
+#[derive(Debug)]
+pub struct IsochTrb {
+    /// The buffer which data is read from or written to
+    buffer: PhysAddr,
+    /// Configuration for the TRB
+    config: IsochTrbConfig,
+    /// The TRB flags
+    flags: IsochTrbFlags,
+}
+
+impl IsochTrb {
+    /// Constructs a new [`IsochTrb`] transferring up to `length` bytes to or from `buffer` as soon as possible.
+    ///
+    /// The TRB is the only TRB in its TD, and has its _Interrupt On Completion_ flag set,
+    /// so a _Transfer Event_ is always sent when it completes.
+    #[allow(dead_code)] // Not yet used by any driver
+    pub fn new(buffer: PhysAddr, length: u32) -> Self {
+        Self {
+            buffer,
+            config: IsochTrbConfig::new().with_transfer_length(length),
+            flags: IsochTrbFlags::new()
+                .with_interrupt_on_completion(true)
+                .with_start_isoch_asap(true),
+        }
+    }
+
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        let buffer = self.buffer.as_u64();
+
+        #[allow(clippy::cast_possible_truncation)]
+        [
+            buffer as u32,
+            (buffer >> 32) as u32,
+            self.config.into(),
+            self.flags.with_cycle(cycle).into(),
+        ]
+    }
+
+    /// The value of the chain bit
+    pub fn chain(&self) -> bool {
+        self.flags.chain()
+    }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.config.set_interrupter_target(interrupter);
+    }
+}
@@ -1,13 +1,20 @@
 //! The [`TransferTrb`] type
 
+use control::{DataStageTrb, SetupStageTrb, StatusStageTrb};
+use event_data::EventDataTrb;
+use isoch::IsochTrb;
+use no_op::NoOpTrb;
 use normal::NormalTrb;
 use x86_64::PhysAddr;
 
 use super::{link::LinkTrb, software_driven_rings::SoftwareDrivenTrbRing, RingFullError};
 
+pub mod control;
+pub mod event_data;
+pub mod isoch;
+pub mod no_op;
 pub mod normal;
 
-
 /// A TRB on a transfer TRB ring (TODO: link).
 ///
 /// This tells the controller how to send or receive data.
@@ -16,18 +23,23 @@ pub mod normal;
 ///
 /// [6.4.1]: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf#%5B%7B%22num%22%3A472%2C%22gen%22%3A0%7D%2C%7B%22name%22%3A%22XYZ%22%7D%2C138%2C548%2C0%5D
 #[derive(Debug)]
-#[allow(clippy::missing_docs_in_private_items)] // TODO: add docs with the corresponding structs
 pub enum TransferTrb {
     /// A [`NormalTrb`]
     Normal(NormalTrb),
-    SetupStage,
-    DataStage,
-    StatusStage,
-    Isoch,
+    /// A [`SetupStageTrb`]
+    SetupStage(SetupStageTrb),
+    /// A [`DataStageTrb`]
+    DataStage(DataStageTrb),
+    /// A [`StatusStageTrb`]
+    StatusStage(StatusStageTrb),
+    /// An [`IsochTrb`]
+    Isoch(IsochTrb),
     /// A [`LinkTrb`]
     Link(LinkTrb),
-    EventData,
-    NoOp,
+    /// An [`EventDataTrb`]
+    EventData(EventDataTrb),
+    /// A [`NoOpTrb`]
+    NoOp(NoOpTrb),
 }
 
 impl TransferTrb {
@@ -35,13 +47,13 @@ impl TransferTrb {
     pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
         match self {
             TransferTrb::Normal(normal) => normal.to_parts(cycle),
-            TransferTrb::SetupStage => todo!(),
-            TransferTrb::DataStage => todo!(),
-            TransferTrb::StatusStage => todo!(),
-            TransferTrb::Isoch => todo!(),
+            TransferTrb::SetupStage(setup) => setup.to_parts(cycle),
+            TransferTrb::DataStage(data) => data.to_parts(cycle),
+            TransferTrb::StatusStage(status) => status.to_parts(cycle),
+            TransferTrb::Isoch(isoch) => isoch.to_parts(cycle),
             TransferTrb::Link(link) => link.to_parts(cycle),
-            TransferTrb::EventData => todo!(),
-            TransferTrb::NoOp => todo!(),
+            TransferTrb::EventData(event_data) => event_data.to_parts(cycle),
+            TransferTrb::NoOp(no_op) => no_op.to_parts(cycle),
         }
     }
 
@@ -50,13 +62,30 @@ impl TransferTrb {
     pub fn chain(&self) -> bool {
         match self {
             TransferTrb::Normal(normal) => normal.chain(),
-            TransferTrb::SetupStage => todo!(),
-            TransferTrb::DataStage => todo!(),
-            TransferTrb::StatusStage => todo!(),
-            TransferTrb::Isoch => todo!(),
+            // Setup stage TRBs don't have a chain bit
+            TransferTrb::SetupStage(_) => false,
+            TransferTrb::DataStage(data) => data.chain(),
+            TransferTrb::StatusStage(status) => status.chain(),
+            TransferTrb::Isoch(isoch) => isoch.chain(),
             TransferTrb::Link(link) => link.chain(),
-            TransferTrb::EventData => todo!(),
-            TransferTrb::NoOp => todo!(),
+            TransferTrb::EventData(event_data) => event_data.chain(),
+            TransferTrb::NoOp(no_op) => no_op.chain(),
+        }
+    }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        match self {
+            TransferTrb::Normal(normal) => normal.set_interrupter_target(interrupter),
+            TransferTrb::SetupStage(setup) => setup.set_interrupter_target(interrupter),
+            TransferTrb::DataStage(data) => data.set_interrupter_target(interrupter),
+            TransferTrb::StatusStage(status) => status.set_interrupter_target(interrupter),
+            TransferTrb::Isoch(isoch) => isoch.set_interrupter_target(interrupter),
+            TransferTrb::Link(link) => link.config.set_interrupter_target(interrupter),
+            TransferTrb::EventData(event_data) => event_data.set_interrupter_target(interrupter),
+            TransferTrb::NoOp(no_op) => no_op.set_interrupter_target(interrupter),
         }
     }
 }
@@ -65,7 +94,14 @@ impl TransferTrb {
 ///
 /// This ring contains [`TransferTrb`]s for the controller to execute.
 #[derive(Debug)]
-pub struct TransferTrbRing(SoftwareDrivenTrbRing);
+pub struct TransferTrbRing {
+    /// The underlying ring
+    ring: SoftwareDrivenTrbRing,
+    /// The index of the [`Interrupter`] which events for TRBs on this ring are sent to
+    ///
+    /// [`Interrupter`]: super::super::registers::interrupter::Interrupter
+    interrupter_target: u16,
+}
 
 impl TransferTrbRing {
     /// The total length of the command ring including the link TRB
@@ -73,12 +109,39 @@ impl TransferTrbRing {
 
     /// Allocates a new [`CommandTrbRing`]
     pub fn new() -> Self {
-        Self(SoftwareDrivenTrbRing::new())
+        Self {
+            ring: SoftwareDrivenTrbRing::new(),
+            interrupter_target: 0,
+        }
+    }
+
+    /// The index of the [`Interrupter`] which events for TRBs on this ring are sent to.
+    /// This is the primary interrupter unless it has been changed with [`set_interrupter_target`].
+    ///
+    /// [`Interrupter`]: super::super::registers::interrupter::Interrupter
+    /// [`set_interrupter_target`]: TransferTrbRing::set_interrupter_target
+    pub fn interrupter_target(&self) -> u16 {
+        self.interrupter_target
+    }
+
+    /// Sets the index of the [`Interrupter`] which events for TRBs enqueued on this ring from now on are sent to.
+    /// TRBs which have already been enqueued are not affected.
+    ///
+    /// [`Interrupter`]: super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.interrupter_target = interrupter;
     }
 
     /// Gets the physical address of the start of the first segment of the ring
     pub fn ring_start_addr(&self) -> PhysAddr {
-        self.0.ring_start_addr()
+        self.ring.ring_start_addr()
+    }
+
+    /// Returns the number of TRBs which can be enqueued before the ring is full.
+    ///
+    /// This value is only accurate if the ring's dequeue pointer is up-to-date.
+    pub fn free_space(&self) -> usize {
+        self.ring.free_space()
     }
 
     /// Writes a TRB to the buffer.
@@ -89,20 +152,68 @@ impl TransferTrbRing {
     ///
     /// # Safety
     /// * The caller is responsible for the behaviour of the controller in response to this TRB
-    pub unsafe fn enqueue(&mut self, trb: TransferTrb) -> Result<PhysAddr, RingFullError> {
+    pub unsafe fn enqueue(&mut self, mut trb: TransferTrb) -> Result<PhysAddr, RingFullError> {
+        trb.set_interrupter_target(self.interrupter_target);
+
         // SAFETY: This is just a wrapper function, so the safety requirements are the same.
-        unsafe { self.0.enqueue(|cycle| trb.to_parts(cycle)) }
+        unsafe { self.ring.enqueue(|cycle| trb.to_parts(cycle)) }
     }
 
     /// Updates the ring's dequeue pointer
     ///
     /// # Safety
-    /// * The passed address must have been read from the [`command_trb_pointer`] field of a [`CommandCompletion`] TRB.
+    /// * The passed address must have been read from the [`trb_pointer`] field of a [`Transfer`] event TRB for this ring.
     ///
-    /// [`command_trb_pointer`]: super::event::command_completion::CommandCompletionTrb
-    /// [`CommandCompletion`]: super::EventTrb::CommandCompletion
+    /// [`trb_pointer`]: super::event::transfer::TransferEventTrb::trb_pointer
+    /// [`Transfer`]: super::EventTrb::Transfer
     pub unsafe fn update_dequeue(&mut self, dequeue: PhysAddr) {
         // SAFETY: This is just a wrapper function, so the safety requirements are the same.
-        unsafe { self.0.update_dequeue(dequeue) }
+        unsafe { self.ring.update_dequeue(dequeue) }
+    }
+
+    /// Discards any TRBs which the controller has not processed.
+    /// Returns the address and cycle state of the TRB the controller should process next.
+    ///
+    /// # Safety
+    /// * The endpoint must be stopped, and its dequeue pointer must be set to the returned address before it is restarted.
+    pub unsafe fn skip_pending(&mut self) -> (PhysAddr, bool) {
+        // SAFETY: This is just a wrapper function, so the safety requirements are the same.
+        unsafe { self.ring.skip_pending() }
+    }
+}
+
+/// Tests that TRBs enqueued on a [`TransferTrbRing`] are routed to the ring's interrupter target.
+///
+/// Command TRBs have no _Interrupter Target_ field, as their completion events always go to the primary interrupter,
+/// so this checks a transfer TRB instead.
+#[test_case]
+fn test_interrupter_target_encoding() {
+    let mut ring = TransferTrbRing::new();
+    assert_eq!(ring.interrupter_target(), 0);
+    ring.set_interrupter_target(1);
+    assert_eq!(ring.interrupter_target(), 1);
+
+    let mut trb = TransferTrb::Normal(NormalTrb::new(PhysAddr::new(0x1000), 8));
+    assert_eq!(trb.to_parts(true)[2] >> 22, 0);
+
+    trb.set_interrupter_target(ring.interrupter_target());
+    let parts = trb.to_parts(true);
+    assert_eq!(parts[2] >> 22, 1);
+    // The transfer length is unaffected
+    assert_eq!(parts[2] & 0x1_ffff, 8);
+}
+
+/// Tests that every type of transfer TRB which has an _Interrupter Target_ field puts it in the same place
+#[test_case]
+fn test_interrupter_target_all_trb_types() {
+    let trbs = [
+        TransferTrb::Isoch(IsochTrb::new(PhysAddr::new(0x1000), 8)),
+        TransferTrb::EventData(EventDataTrb::new(0x1234, false)),
+        TransferTrb::NoOp(NoOpTrb::new()),
+    ];
+
+    for mut trb in trbs {
+        trb.set_interrupter_target(3);
+        assert_eq!(trb.to_parts(true)[2] >> 22, 3);
     }
 }
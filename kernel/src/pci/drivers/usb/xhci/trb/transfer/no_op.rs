@@ -0,0 +1,83 @@
+//! The [`NoOpTrb`] type
+
+use super::super::TrbType;
+
+#[bitfield(u32)]
+struct NoOpTrbConfig {
+    #[bits(22)]
+    _reserved: (),
+
+    /// The index of the Interrupter that will receive events generated by this TRB
+    #[bits(10)]
+    interrupter_target: u16,
+}
+
+#[bitfield(u32)]
+struct NoOpTrbFlags {
+    /// This bit is used to mark the Enqueue Pointer of the Transfer ring
+    cycle: bool,
+    /// If `true`, the controller shall fetch and evaluate the next TRB before saving the endpoint state
+    evaluate_next_trb: bool,
+
+    #[bits(2)]
+    _reserved: (),
+
+    /// Whether there are more TRBs in the TD after this one
+    chain: bool,
+    /// Whether the controller should send a _Transfer Event_ when this TRB completes
+    interrupt_on_completion: bool,
+
+    #[bits(4)]
+    _reserved: (),
+
+    /// Should always be [`NoOp`][TrbType::NoOp]
+    #[bits(6, default = TrbType::NoOp)]
+    trb_type: TrbType,
+
+    #[bits(16)]
+    _reserved: (),
+}
+
+/// A _No Op TRB_, which transfers no data. This is used to test the controller's handling of a transfer ring.
+///
+/// See the spec section 6.4.1.4 for the definition of this TRB.
+#[derive(Debug)]
+pub struct NoOpTrb {
+    /// Configuration for the TRB
+    config: NoOpTrbConfig,
+    /// The TRB flags
+    flags: NoOpTrbFlags,
+}
+
+impl NoOpTrb {
+    /// Constructs a new [`NoOpTrb`] which sends a _Transfer Event_ when it completes
+    #[allow(dead_code)] // Not yet used by any driver
+    pub fn new() -> Self {
+        Self {
+            config: NoOpTrbConfig::new(),
+            flags: NoOpTrbFlags::new().with_interrupt_on_completion(true),
+        }
+    }
+
+    /// Converts the TRB to the data written to a TRB ring
+    pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
+        [
+            0,
+            0,
+            self.config.into(),
+            self.flags.with_cycle(cycle).into(),
+        ]
+    }
+
+    /// The value of the chain bit
+    pub fn chain(&self) -> bool {
+        self.flags.chain()
+    }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.config.set_interrupter_target(interrupter);
+    }
+}
@@ -124,6 +124,38 @@ pub struct NormalTrb {
 }
 
 impl NormalTrb {
+    /// Constructs a new [`NormalTrb`] transferring up to `length` bytes to or from `buffer`.
+    ///
+    /// The TRB is the only TRB in its TD, and has its _Interrupt On Completion_ and _Interrupt on Short Packet_ flags set,
+    /// so a _Transfer Event_ is always sent when it completes, giving the number of bytes which were not transferred.
+    pub fn new(buffer: PhysAddr, length: u32) -> Self {
+        Self {
+            data: NormalTrbData::Address(buffer),
+            config: NormalTrbConfig::new().with_transfer_length(length),
+            flags: NormalTrbFlags::new()
+                .with_interrupt_on_short_packet(true)
+                .with_interrupt_on_completion(true),
+        }
+    }
+
+    /// Constructs a new [`NormalTrb`] transferring `length` bytes to or from `buffer`, as part of a TD made of several TRBs.
+    ///
+    /// `td_size` is the number of packets remaining in the TD after this TRB, and `last` is whether this is the last TRB in the TD.
+    /// Every TRB has its _Interrupt on Short Packet_ flag set, but only the last has _Interrupt On Completion_ set,
+    /// so a _Transfer Event_ is sent either when the whole TD completes or when the TD is cut short by a short packet.
+    pub fn new_in_td(buffer: PhysAddr, length: u32, td_size: u8, last: bool) -> Self {
+        Self {
+            data: NormalTrbData::Address(buffer),
+            config: NormalTrbConfig::new()
+                .with_transfer_length(length)
+                .with_td_size(td_size),
+            flags: NormalTrbFlags::new()
+                .with_interrupt_on_short_packet(true)
+                .with_chain(!last)
+                .with_interrupt_on_completion(last),
+        }
+    }
+
     /// Converts the TRB to the data written to a TRB ring
     pub fn to_parts(&self, cycle: bool) -> [u32; 4] {
         let data = match self.data {
@@ -142,4 +174,11 @@ impl NormalTrb {
     pub fn chain(&self) -> bool {
         self.flags.chain()
     }
+
+    /// Sets the index of the [`Interrupter`] which will receive events generated by this TRB
+    ///
+    /// [`Interrupter`]: super::super::super::registers::interrupter::Interrupter
+    pub fn set_interrupter_target(&mut self, interrupter: u16) {
+        self.config.set_interrupter_target(interrupter);
+    }
 }
@@ -0,0 +1,294 @@
+//! A driver for virtio block devices, such as QEMU's `-drive if=virtio`.
+//!
+//! Block devices are described in section 5.2 of the virtio specification.
+
+use alloc::{boxed::Box, sync::Arc};
+use log::{debug, info, warn};
+
+use crate::{
+    allocator::PageBox,
+    block::{register_block_device, BlockDevice, BlockDeviceError, BlockFuture},
+    pci::PciMappedFunction,
+    scheduler::{yield_now, AsyncMutex},
+};
+
+use super::{
+    queue::{Buffer, Virtqueue, MAX_QUEUE_SIZE},
+    VirtioInitError, VirtioPciDevice,
+};
+
+/// The PCI device IDs of virtio block devices.
+/// The first is used by transitional devices, which also support the legacy interface, and the second by modern devices.
+pub const DEVICE_IDS: [u16; 2] = [0x1001, 0x1042];
+
+/// The size of a sector in bytes. Block devices' capacities and addresses are always in units of this size.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The type of a request which reads from the device
+const REQUEST_TYPE_IN: u32 = 0;
+/// The type of a request which writes to the device
+const REQUEST_TYPE_OUT: u32 = 1;
+
+/// The status written by the device when a request succeeds
+const STATUS_OK: u8 = 0;
+/// The status written by the device when a request fails
+const STATUS_IO_ERROR: u8 = 1;
+/// The status written by the device when a request isn't supported
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// The byte offset of the request header in a [`RequestQueue`]'s request page
+const HEADER_OFFSET: usize = 0;
+/// The byte offset of the status byte in a [`RequestQueue`]'s request page
+const STATUS_OFFSET: usize = 16;
+/// The byte offset of the data buffer in a [`RequestQueue`]'s request page
+const DATA_OFFSET: usize = SECTOR_SIZE;
+/// The maximum number of sectors which can be read or written by one request,
+/// limited by the size of the data buffer in the request page
+const MAX_SECTORS_PER_REQUEST: usize = (0x1000 - DATA_OFFSET) / SECTOR_SIZE;
+
+/// The state needed to send requests to a [`VirtioBlockDevice`]
+#[derive(Debug)]
+struct RequestQueue {
+    /// The device's virtio PCI transport
+    device: VirtioPciDevice,
+    /// The device's only virtqueue, which requests are sent on
+    queue: Virtqueue,
+    /// A page containing the header, status, and data buffer of the request being processed.
+    /// Only one request is sent at once, so this is reused for every request.
+    request: PageBox,
+}
+
+impl RequestQueue {
+    /// Gets the data buffer of the request page
+    fn data(&self) -> &[u8] {
+        // SAFETY: The data buffer is within the request page.
+        // No request is in progress, so the device isn't writing to it.
+        unsafe {
+            core::slice::from_raw_parts(
+                self.request.as_ptr::<u8>().add(DATA_OFFSET),
+                MAX_SECTORS_PER_REQUEST * SECTOR_SIZE,
+            )
+        }
+    }
+
+    /// Gets the data buffer of the request page mutably
+    fn data_mut(&mut self) -> &mut [u8] {
+        // SAFETY: The data buffer is within the request page.
+        // No request is in progress, so the device isn't accessing it.
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.request.as_mut_ptr::<u8>().add(DATA_OFFSET),
+                MAX_SECTORS_PER_REQUEST * SECTOR_SIZE,
+            )
+        }
+    }
+
+    /// Sends a request of the given type for `sectors` sectors starting at `lba`, and waits for the device to complete it.
+    /// The data is read from or written to the start of the [data buffer].
+    ///
+    /// If the returned future is dropped before it completes, the request is abandoned,
+    /// and the device will be left in an inconsistent state.
+    ///
+    /// [data buffer]: RequestQueue::data
+    async fn send(
+        &mut self,
+        request_type: u32,
+        lba: u64,
+        sectors: usize,
+    ) -> Result<(), BlockDeviceError> {
+        debug_assert!(sectors <= MAX_SECTORS_PER_REQUEST);
+
+        #[allow(clippy::cast_possible_truncation)] // Truncation is intentional
+        let header = [request_type, 0, lba as u32, (lba >> 32) as u32];
+
+        // SAFETY: The header and status fit in the request page, before the data buffer.
+        // The device isn't using the page as no request is in progress.
+        unsafe {
+            let page = self.request.as_mut_ptr::<u8>();
+            page.add(HEADER_OFFSET)
+                .cast::<[u32; 4]>()
+                .write_volatile(header);
+            // Set the status to something invalid, to make sure the device really writes it
+            page.add(STATUS_OFFSET).write_volatile(0xFF);
+        }
+
+        let page_address = self.request.phys_frame().start_address();
+        let buffers = [
+            Buffer {
+                address: page_address + HEADER_OFFSET as u64,
+                length: 16,
+                device_writable: false,
+            },
+            Buffer {
+                address: page_address + DATA_OFFSET as u64,
+                length: (sectors * SECTOR_SIZE) as u32,
+                device_writable: request_type == REQUEST_TYPE_IN,
+            },
+            Buffer {
+                address: page_address + STATUS_OFFSET as u64,
+                length: 1,
+                device_writable: true,
+            },
+        ];
+
+        // SAFETY: The buffers are in `request`, which lives as long as the device.
+        // The request is a valid read or write request.
+        let head = unsafe { self.queue.add(&buffers) }
+            .expect("Only one request is in progress at once, so the queue shouldn't be full");
+        self.device.notify(&self.queue);
+
+        loop {
+            if let Some(used) = self.queue.pop_used() {
+                debug_assert_eq!(used.head, head);
+                break;
+            }
+
+            yield_now().await;
+        }
+
+        // SAFETY: The device has finished with the request page
+        let status = unsafe {
+            self.request
+                .as_ptr::<u8>()
+                .add(STATUS_OFFSET)
+                .read_volatile()
+        };
+
+        match status {
+            STATUS_OK => Ok(()),
+            STATUS_IO_ERROR => Err(BlockDeviceError::IoError),
+            STATUS_UNSUPPORTED => Err(BlockDeviceError::Unsupported),
+            _ => {
+                warn!("Virtio block device returned unknown status {status:#x}");
+                Err(BlockDeviceError::IoError)
+            }
+        }
+    }
+}
+
+/// A virtio block device
+#[derive(Debug)]
+pub struct VirtioBlockDevice {
+    /// The number of sectors on the device
+    capacity: u64,
+    /// The state needed to send requests, which can only be used by one request at a time
+    requests: AsyncMutex<RequestQueue>,
+}
+
+impl VirtioBlockDevice {
+    /// Initialises the block device at the given function.
+    ///
+    /// # Safety
+    /// * This may only be called once per function
+    pub unsafe fn new(function: PciMappedFunction) -> Result<Self, VirtioInitError> {
+        // SAFETY: The caller guarantees this is only called once per function
+        let mut device = unsafe { VirtioPciDevice::new(function) }?;
+
+        // None of the optional block device features are used
+        device.negotiate_features(0)?;
+        let queue = device.setup_queue(0, MAX_QUEUE_SIZE)?;
+        device.finish_init();
+
+        // SAFETY: The capacity is a `u64` at the start of the block device configuration structure.
+        // It is read as two `u32`s as devices don't have to support 64-bit accesses.
+        let capacity = unsafe {
+            let low: u32 = device.read_device_config(0);
+            let high: u32 = device.read_device_config(4);
+            (high as u64) << 32 | low as u64
+        };
+
+        Ok(Self {
+            capacity,
+            requests: AsyncMutex::new(RequestQueue {
+                device,
+                queue,
+                request: PageBox::new_zeroed(),
+            }),
+        })
+    }
+
+    /// Reads the sector at the given block address
+    pub async fn read_block(&self, lba: u64) -> Result<[u8; SECTOR_SIZE], BlockDeviceError> {
+        let mut block = [0; SECTOR_SIZE];
+        self.read_blocks(lba, 1, &mut block).await?;
+        Ok(block)
+    }
+}
+
+impl BlockDevice for VirtioBlockDevice {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, count: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            self.check_request(lba, count, buf.len())?;
+
+            let mut requests = self.requests.lock().await;
+            let mut lba = lba;
+            for chunk in buf.chunks_mut(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE) {
+                let sectors = chunk.len() / SECTOR_SIZE;
+                requests.send(REQUEST_TYPE_IN, lba, sectors).await?;
+                chunk.copy_from_slice(&requests.data()[..chunk.len()]);
+                lba += sectors as u64;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            if buf.len() % SECTOR_SIZE != 0 {
+                return Err(BlockDeviceError::InvalidBufferLength);
+            }
+            self.check_request(lba, (buf.len() / SECTOR_SIZE) as u64, buf.len())?;
+
+            let mut requests = self.requests.lock().await;
+            let mut lba = lba;
+            for chunk in buf.chunks(MAX_SECTORS_PER_REQUEST * SECTOR_SIZE) {
+                let sectors = chunk.len() / SECTOR_SIZE;
+                requests.data_mut()[..chunk.len()].copy_from_slice(chunk);
+                requests.send(REQUEST_TYPE_OUT, lba, sectors).await?;
+                lba += sectors as u64;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Initialises the block device at the given function, checks that it can be read from,
+/// and then registers it as a [`BlockDevice`].
+///
+/// # Safety
+/// * This may only be called once per function
+pub async unsafe fn init(function: PciMappedFunction) {
+    // SAFETY: The caller guarantees this is only called once per function
+    let mut device = match unsafe { VirtioBlockDevice::new(function) } {
+        Ok(device) => device,
+        Err(e) => {
+            warn!("Failed to initialise virtio block device: {e:?}");
+            return;
+        }
+    };
+
+    info!(
+        "Found virtio block device at {} with {} sectors ({} KiB)",
+        device.requests.get_mut().device.function().function,
+        device.num_blocks(),
+        device.num_blocks() * SECTOR_SIZE as u64 / 1024
+    );
+
+    match device.read_block(0).await {
+        Ok(sector) => debug!("First bytes of sector 0: {:02x?}", &sector[..16]),
+        Err(e) => warn!("Failed to read sector 0 of virtio block device: {e:?}"),
+    }
+
+    let index = register_block_device(Arc::new(device));
+    debug!("Registered virtio block device as blk{index}");
+}
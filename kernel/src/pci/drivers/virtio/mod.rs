@@ -0,0 +1,428 @@
+//! Drivers for virtio devices, which are paravirtualised devices provided by hypervisors such as QEMU.
+//!
+//! This module contains the virtio PCI transport, which is the same for all types of virtio device.
+//! Documentation for virtio devices can be found in the [virtio specification].
+//!
+//! [virtio specification]: https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+
+pub mod blk;
+mod queue;
+
+use core::mem::size_of;
+
+use crate::{
+    cpu::MappedRegion,
+    global_state::KERNEL_STATE,
+    pci::{
+        bar::BarValue, capability_registers::CapabilityEntry, registers::HeaderType,
+        PciMappedFunction,
+    },
+};
+
+use self::queue::Virtqueue;
+
+/// The PCI vendor ID of virtio devices
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+/// The feature bit which indicates that the device complies with version 1 of the virtio specification.
+/// Devices which don't offer this feature are legacy devices, which aren't supported.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The type of configuration structure described by a [`VirtioPciCapability`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigurationType {
+    /// The common configuration structure, which is used to initialise the device and set up its virtqueues
+    Common,
+    /// The notification structure, which is written to in order to tell the device that a virtqueue has new buffers
+    Notify,
+    /// The ISR status structure, which is used with pin-based interrupts
+    Isr,
+    /// The device-specific configuration structure
+    Device,
+    /// An alternative way to access the other structures through the PCI configuration space
+    Pci,
+    /// A type not defined by the version of the specification this driver follows
+    Other(u8),
+}
+
+impl ConfigurationType {
+    /// Constructs a [`ConfigurationType`] from the `cfg_type` field of a [`VirtioPciCapability`]
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Common,
+            2 => Self::Notify,
+            3 => Self::Isr,
+            4 => Self::Device,
+            5 => Self::Pci,
+            _ => Self::Other(value),
+        }
+    }
+}
+
+/// A vendor-specific PCI capability of a virtio device, which describes where one of the device's
+/// configuration structures is in its BARs.
+///
+/// This structure is described in section 4.1.4 of the virtio specification.
+#[derive(Debug, Clone, Copy)]
+struct VirtioPciCapability {
+    /// Which configuration structure the capability describes
+    configuration_type: ConfigurationType,
+    /// The BAR which the structure is in
+    bar: u8,
+    /// The byte offset of the structure from the start of the BAR
+    offset: u32,
+    /// The length of the structure in bytes
+    length: u32,
+    /// For [`Notify`] capabilities, the multiplier for a virtqueue's notification offset to get the byte offset
+    /// of where to write to notify it. This is 0 for other types of capability.
+    ///
+    /// [`Notify`]: ConfigurationType::Notify
+    notify_off_multiplier: u32,
+}
+
+impl VirtioPciCapability {
+    /// Reads the capability from the configuration space of the given function.
+    ///
+    /// # Safety
+    /// * `offset` is the register (not byte) offset of a vendor-specific capability of a virtio device
+    #[allow(clippy::cast_possible_truncation)] // Truncation is intentional
+    unsafe fn read(function: &PciMappedFunction, offset: u8) -> Self {
+        // SAFETY: These registers are within the capability. Reading them has no side effects.
+        let [header, bar, structure_offset, length] =
+            [0, 1, 2, 3].map(|i| unsafe { function.read_reg(offset + i) });
+
+        let configuration_type = ConfigurationType::from_u8((header >> 24) as u8);
+
+        let notify_off_multiplier = if configuration_type == ConfigurationType::Notify {
+            // SAFETY: Notify capabilities have an extra register after the common fields
+            unsafe { function.read_reg(offset + 4) }
+        } else {
+            0
+        };
+
+        Self {
+            configuration_type,
+            bar: bar as u8,
+            offset: structure_offset,
+            length,
+            notify_off_multiplier,
+        }
+    }
+}
+
+/// The device status field of the common configuration structure, which is used to initialise the device.
+///
+/// This field is described in section 2.1 of the virtio specification.
+#[bitfield(u8)]
+struct DeviceStatus {
+    /// The OS has found the device
+    acknowledge: bool,
+    /// The OS has a driver for the device
+    driver: bool,
+    /// The driver has finished setting up the device
+    driver_ok: bool,
+    /// The driver has finished negotiating features
+    features_ok: bool,
+    #[bits(2)]
+    #[doc(hidden)]
+    reserved0: u8,
+    /// The device has encountered an error and needs to be reset
+    device_needs_reset: bool,
+    /// The driver has given up on the device
+    failed: bool,
+}
+
+/// The byte offsets of the fields of the common configuration structure, described in section 4.1.4.3 of the virtio specification
+mod common {
+    /// Selects which 32 bits of the device's features are read from [`DEVICE_FEATURE`]
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    /// 32 bits of the features offered by the device
+    pub const DEVICE_FEATURE: usize = 0x04;
+    /// Selects which 32 bits of the driver's features are written to [`DRIVER_FEATURE`]
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    /// 32 bits of the features accepted by the driver
+    pub const DRIVER_FEATURE: usize = 0x0C;
+    /// The [`DeviceStatus`][super::DeviceStatus]
+    pub const DEVICE_STATUS: usize = 0x14;
+    /// Selects which virtqueue the `QUEUE_*` fields refer to
+    pub const QUEUE_SELECT: usize = 0x16;
+    /// The size of the selected virtqueue. The device sets this to the maximum size, and the driver may reduce it.
+    pub const QUEUE_SIZE: usize = 0x18;
+    /// Whether the selected virtqueue is enabled
+    pub const QUEUE_ENABLE: usize = 0x1C;
+    /// The offset of where to write to notify the selected virtqueue, in multiples of the notify offset multiplier
+    pub const QUEUE_NOTIFY_OFF: usize = 0x1E;
+    /// The physical address of the selected virtqueue's descriptor table
+    pub const QUEUE_DESC: usize = 0x20;
+    /// The physical address of the selected virtqueue's available ring
+    pub const QUEUE_DRIVER: usize = 0x28;
+    /// The physical address of the selected virtqueue's used ring
+    pub const QUEUE_DEVICE: usize = 0x30;
+    /// The size of the structure
+    pub const SIZE: usize = 0x38;
+}
+
+/// An error which can occur when initialising a virtio device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioInitError {
+    /// The PCI function isn't a general device
+    NotGeneralDevice,
+    /// The device doesn't have a capability for a required configuration structure
+    MissingCapability(ConfigurationType),
+    /// A configuration structure is in an IO space BAR, which isn't supported
+    IoSpaceBar,
+    /// The device is a legacy device, which isn't supported
+    LegacyDevice,
+    /// The device didn't accept the features the driver selected
+    FeaturesNotAccepted,
+    /// The device doesn't have the requested virtqueue
+    QueueUnavailable(u16),
+}
+
+/// A virtio device accessed through the virtio PCI transport
+#[derive(Debug)]
+pub struct VirtioPciDevice {
+    /// The PCI function of the device
+    function: PciMappedFunction,
+    /// The mapping of the common configuration structure
+    common: MappedRegion,
+    /// The mapping of the notification structure
+    notify: MappedRegion,
+    /// The multiplier for a virtqueue's notification offset to get the byte offset into [`notify`]
+    ///
+    /// [`notify`]: VirtioPciDevice::notify
+    notify_off_multiplier: u32,
+    /// The mapping of the device-specific configuration structure
+    device: MappedRegion,
+}
+
+impl VirtioPciDevice {
+    /// Finds and maps the device's configuration structures, then resets the device and tells it that a driver has been found.
+    ///
+    /// # Safety
+    /// * This may only be called once per function
+    pub unsafe fn new(mut function: PciMappedFunction) -> Result<Self, VirtioInitError> {
+        let header = function.read_header().unwrap().unwrap();
+        let HeaderType::GeneralDevice(general_device_header) = header.header_type else {
+            return Err(VirtioInitError::NotGeneralDevice);
+        };
+
+        // The driver should use the first capability of each type, as later ones are alternatives
+        let find_capability = |configuration_type| {
+            function
+                .capabilities()?
+                .filter(|(c, _)| matches!(c, CapabilityEntry::VendorSpecific))
+                // SAFETY: `offset` is the offset of a vendor-specific capability of a virtio device
+                .map(|(_, offset)| unsafe { VirtioPciCapability::read(&function, offset) })
+                .find(|c| c.configuration_type == configuration_type)
+        };
+
+        let map_capability = |configuration_type| {
+            let capability = find_capability(configuration_type)
+                .ok_or(VirtioInitError::MissingCapability(configuration_type))?;
+
+            // SAFETY: The BAR number was read from a virtio capability, so it is a real BAR.
+            // The `Bar` is dropped at the end of this closure, so no other `Bar` exists for it.
+            let bar = unsafe { general_device_header.bar(&function, capability.bar) };
+            let BarValue::MemorySpace { base_address, .. } = bar.read_value() else {
+                return Err(VirtioInitError::IoSpaceBar);
+            };
+
+            // SAFETY: The address and length were read from the device's capability, so they are MMIO.
+            // Each structure is only mapped once, when the device is initialised.
+            let region = unsafe {
+                KERNEL_STATE.physical_memory_accessor.lock().map_region(
+                    base_address.as_address() + capability.offset as u64,
+                    capability.length as usize,
+                )
+            };
+
+            Ok((region, capability))
+        };
+
+        let (common, _) = map_capability(ConfigurationType::Common)?;
+        let (notify, notify_capability) = map_capability(ConfigurationType::Notify)?;
+        let (device, _) = map_capability(ConfigurationType::Device)?;
+
+        // SAFETY: The device isn't given any buffers until a virtqueue is set up,
+        // so it won't access memory until then
+        unsafe { function.enable_bus_mastering() };
+
+        let mut device = Self {
+            function,
+            common,
+            notify,
+            notify_off_multiplier: notify_capability.notify_off_multiplier,
+            device,
+        };
+
+        device.reset();
+        device.set_status(DeviceStatus::new().with_acknowledge(true).with_driver(true));
+
+        Ok(device)
+    }
+
+    /// Reads a field of the common configuration structure at the given byte offset
+    fn read_common<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= common::SIZE);
+
+        // SAFETY: The field is within the common configuration structure, which is mapped.
+        // Reading the common configuration structure has no side effects.
+        unsafe {
+            self.common
+                .as_ptr::<u8>()
+                .add(offset)
+                .cast::<T>()
+                .read_volatile()
+        }
+    }
+
+    /// Writes a field of the common configuration structure at the given byte offset
+    fn write_common<T: Copy>(&mut self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= common::SIZE);
+
+        // SAFETY: The field is within the common configuration structure, which is mapped.
+        // The methods which call this are responsible for the side effects of the write.
+        unsafe {
+            self.common
+                .as_mut_ptr::<u8>()
+                .add(offset)
+                .cast::<T>()
+                .write_volatile(value)
+        }
+    }
+
+    /// Writes a 64-bit field of the common configuration structure as two 32-bit writes,
+    /// as the specification doesn't require devices to support 64-bit accesses.
+    #[allow(clippy::cast_possible_truncation)] // Truncation is intentional
+    fn write_common_u64(&mut self, offset: usize, value: u64) {
+        self.write_common(offset, value as u32);
+        self.write_common(offset + 4, (value >> 32) as u32);
+    }
+
+    /// Reads the device status
+    fn status(&self) -> DeviceStatus {
+        self.read_common::<u8>(common::DEVICE_STATUS).into()
+    }
+
+    /// Writes the device status
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write_common::<u8>(common::DEVICE_STATUS, status.into());
+    }
+
+    /// Resets the device, which stops it from using any virtqueues
+    fn reset(&mut self) {
+        self.set_status(DeviceStatus::new());
+
+        // The reset has finished when the device status reads as 0
+        while u8::from(self.status()) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Reads the features offered by the device, and accepts those which are also in `features`.
+    /// [`VIRTIO_F_VERSION_1`] is always accepted, and doesn't need to be included in `features`.
+    /// Returns the features which were accepted.
+    pub fn negotiate_features(&mut self, features: u64) -> Result<u64, VirtioInitError> {
+        self.write_common::<u32>(common::DEVICE_FEATURE_SELECT, 0);
+        let low = self.read_common::<u32>(common::DEVICE_FEATURE);
+        self.write_common::<u32>(common::DEVICE_FEATURE_SELECT, 1);
+        let high = self.read_common::<u32>(common::DEVICE_FEATURE);
+
+        let offered = (high as u64) << 32 | low as u64;
+
+        if offered & VIRTIO_F_VERSION_1 == 0 {
+            self.set_status(self.status().with_failed(true));
+            return Err(VirtioInitError::LegacyDevice);
+        }
+
+        let accepted = offered & (features | VIRTIO_F_VERSION_1);
+
+        self.write_common::<u32>(common::DRIVER_FEATURE_SELECT, 0);
+        self.write_common::<u32>(common::DRIVER_FEATURE, accepted as u32);
+        self.write_common::<u32>(common::DRIVER_FEATURE_SELECT, 1);
+        self.write_common::<u32>(common::DRIVER_FEATURE, (accepted >> 32) as u32);
+
+        self.set_status(self.status().with_features_ok(true));
+
+        // The device clears `features_ok` if it doesn't support the accepted features
+        if !self.status().features_ok() {
+            self.set_status(self.status().with_failed(true));
+            return Err(VirtioInitError::FeaturesNotAccepted);
+        }
+
+        Ok(accepted)
+    }
+
+    /// Sets up the virtqueue with the given index, with at most `max_size` descriptors.
+    /// This must be called after [`negotiate_features`] and before [`finish_init`].
+    ///
+    /// [`negotiate_features`]: VirtioPciDevice::negotiate_features
+    /// [`finish_init`]: VirtioPciDevice::finish_init
+    pub fn setup_queue(&mut self, index: u16, max_size: u16) -> Result<Virtqueue, VirtioInitError> {
+        self.write_common::<u16>(common::QUEUE_SELECT, index);
+
+        let device_max_size = self.read_common::<u16>(common::QUEUE_SIZE);
+        if device_max_size == 0 {
+            return Err(VirtioInitError::QueueUnavailable(index));
+        }
+
+        let notify_offset = self.read_common::<u16>(common::QUEUE_NOTIFY_OFF);
+        let queue = Virtqueue::new(index, device_max_size.min(max_size), notify_offset);
+
+        self.write_common::<u16>(common::QUEUE_SIZE, queue.size());
+        self.write_common_u64(
+            common::QUEUE_DESC,
+            queue.descriptor_table_address().as_u64(),
+        );
+        self.write_common_u64(
+            common::QUEUE_DRIVER,
+            queue.available_ring_address().as_u64(),
+        );
+        self.write_common_u64(common::QUEUE_DEVICE, queue.used_ring_address().as_u64());
+        self.write_common::<u16>(common::QUEUE_ENABLE, 1);
+
+        Ok(queue)
+    }
+
+    /// Tells the device that the driver has finished setting it up, so it can start processing virtqueues
+    pub fn finish_init(&mut self) {
+        self.set_status(self.status().with_driver_ok(true));
+    }
+
+    /// Tells the device that the given virtqueue has new available buffers
+    pub fn notify(&mut self, queue: &Virtqueue) {
+        let offset = queue.notify_offset() as usize * self.notify_off_multiplier as usize;
+
+        // SAFETY: The offset was calculated as described in section 4.1.4.4 of the virtio specification,
+        // so it is within the notification structure. The queue's buffers are valid.
+        unsafe {
+            self.notify
+                .as_mut_ptr::<u8>()
+                .add(offset)
+                .cast::<u16>()
+                .write_volatile(queue.index())
+        }
+    }
+
+    /// Reads a field of the device-specific configuration structure at the given byte offset
+    ///
+    /// # Safety
+    /// * The field must be within the device-specific configuration structure for the type of device
+    pub unsafe fn read_device_config<T: Copy>(&self, offset: usize) -> T {
+        // SAFETY: The caller guarantees that the field is within the structure.
+        // Reading the device-specific configuration structure has no side effects.
+        unsafe {
+            self.device
+                .as_ptr::<u8>()
+                .add(offset)
+                .cast::<T>()
+                .read_volatile()
+        }
+    }
+
+    /// Gets the PCI function of the device
+    pub fn function(&self) -> &PciMappedFunction {
+        &self.function
+    }
+}
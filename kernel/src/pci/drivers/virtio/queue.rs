@@ -0,0 +1,255 @@
+//! The [`Virtqueue`] type, a split virtqueue which is used to send buffers to a virtio device.
+//!
+//! Split virtqueues are described in section 2.7 of the virtio specification.
+
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+use crate::allocator::PageBox;
+
+/// The maximum number of descriptors in a [`Virtqueue`].
+/// This is limited by the descriptor table and the rings each having to fit in one page.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+/// The flag of a [`Descriptor`] which indicates that the buffer continues in the descriptor at [`next`][Descriptor::next]
+const DESCRIPTOR_NEXT: u16 = 1;
+/// The flag of a [`Descriptor`] which indicates that the buffer is written by the device rather than read
+const DESCRIPTOR_WRITE: u16 = 2;
+
+/// An entry in a virtqueue's descriptor table, which describes one buffer
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Descriptor {
+    /// The physical address of the buffer
+    address: u64,
+    /// The length of the buffer in bytes
+    length: u32,
+    /// The [`DESCRIPTOR_NEXT`] and [`DESCRIPTOR_WRITE`] flags
+    flags: u16,
+    /// The index of the next descriptor in the chain, if [`DESCRIPTOR_NEXT`] is set
+    next: u16,
+}
+
+/// A buffer to be sent to the device using [`Virtqueue::add`]
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    /// The physical address of the buffer
+    pub address: PhysAddr,
+    /// The length of the buffer in bytes
+    pub length: u32,
+    /// Whether the device writes to the buffer. If this is `false`, the device reads from the buffer.
+    pub device_writable: bool,
+}
+
+/// A chain of buffers which the device has finished using, returned by [`Virtqueue::pop_used`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsedChain {
+    /// The index of the first descriptor in the chain, which was returned by [`Virtqueue::add`]
+    pub head: u16,
+    /// The number of bytes the device wrote to the chain's device-writable buffers
+    #[allow(dead_code)]
+    pub length: u32,
+}
+
+/// A split virtqueue, made up of a descriptor table, an available ring which the driver adds buffers to,
+/// and a used ring which the device returns buffers on.
+#[derive(Debug)]
+pub struct Virtqueue {
+    /// The index of the queue on its device
+    index: u16,
+    /// The number of descriptors in the queue
+    size: u16,
+    /// The offset of where to write to notify the device of new buffers, in multiples of the device's notify offset multiplier
+    notify_offset: u16,
+
+    /// The descriptor table
+    descriptors: PageBox,
+    /// The available ring, where the driver puts the heads of descriptor chains for the device to use
+    available: PageBox,
+    /// The used ring, where the device puts the heads of descriptor chains it has finished using
+    used: PageBox,
+
+    /// The indices of the descriptors which aren't part of a chain owned by the device
+    free_descriptors: Vec<u16>,
+    /// The index of the next entry to be read from the used ring
+    next_used: u16,
+}
+
+impl Virtqueue {
+    /// Allocates a new [`Virtqueue`] with the given number of descriptors.
+    ///
+    /// # Panics
+    /// If `size` is not a power of 2, or is greater than [`MAX_QUEUE_SIZE`]
+    pub fn new(index: u16, size: u16, notify_offset: u16) -> Self {
+        assert!(size.is_power_of_two());
+        assert!(size <= MAX_QUEUE_SIZE);
+
+        Self {
+            index,
+            size,
+            notify_offset,
+
+            descriptors: PageBox::new_zeroed(),
+            available: PageBox::new_zeroed(),
+            used: PageBox::new_zeroed(),
+
+            free_descriptors: (0..size).rev().collect(),
+            next_used: 0,
+        }
+    }
+
+    /// Gets the index of the queue on its device
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Gets the number of descriptors in the queue
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Gets the offset of where to write to notify the device of new buffers,
+    /// in multiples of the device's notify offset multiplier
+    pub fn notify_offset(&self) -> u16 {
+        self.notify_offset
+    }
+
+    /// Gets the physical address of the descriptor table
+    pub fn descriptor_table_address(&self) -> PhysAddr {
+        self.descriptors.phys_frame().start_address()
+    }
+
+    /// Gets the physical address of the available ring
+    pub fn available_ring_address(&self) -> PhysAddr {
+        self.available.phys_frame().start_address()
+    }
+
+    /// Gets the physical address of the used ring
+    pub fn used_ring_address(&self) -> PhysAddr {
+        self.used.phys_frame().start_address()
+    }
+
+    /// Adds a chain of buffers to the available ring, and returns the index of the first descriptor in the chain.
+    /// Returns [`None`] if there aren't enough free descriptors.
+    ///
+    /// The device isn't told about the buffers until it is [notified].
+    ///
+    /// # Safety
+    /// * The buffers must stay valid until the chain is returned by [`pop_used`]
+    /// * The caller is responsible for the device's behaviour in response to the buffers
+    ///
+    /// [notified]: super::VirtioPciDevice::notify
+    /// [`pop_used`]: Virtqueue::pop_used
+    pub unsafe fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_descriptors.len() {
+            return None;
+        }
+
+        let indices = self
+            .free_descriptors
+            .split_off(self.free_descriptors.len() - buffers.len());
+
+        for (i, (buffer, &index)) in buffers.iter().zip(&indices).enumerate() {
+            let next = indices.get(i + 1).copied();
+
+            let mut flags = 0;
+            if next.is_some() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            if buffer.device_writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+
+            let descriptor = Descriptor {
+                address: buffer.address.as_u64(),
+                length: buffer.length,
+                flags,
+                next: next.unwrap_or(0),
+            };
+
+            // SAFETY: `index` is less than `size`, so the descriptor is within the table.
+            // The descriptor was free, so the device isn't using it.
+            unsafe {
+                self.descriptors
+                    .as_mut_ptr::<Descriptor>()
+                    .add(index.into())
+                    .write_volatile(descriptor);
+            }
+        }
+
+        let head = indices[0];
+
+        // The available ring is made up of a flags field, an index field, and then the ring
+        let ring = self.available.as_mut_ptr::<u16>();
+        // SAFETY: The index field is the second `u16` of the available ring
+        let available_index = unsafe { ring.add(1).read_volatile() };
+
+        // SAFETY: The entry is within the ring, as it is taken modulo the ring's size
+        unsafe {
+            ring.add(2 + (available_index % self.size) as usize)
+                .write_volatile(head);
+        }
+
+        // The device must see the new entry before the updated index
+        fence(Ordering::SeqCst);
+
+        // SAFETY: The index field is the second `u16` of the available ring
+        unsafe {
+            ring.add(1).write_volatile(available_index.wrapping_add(1));
+        }
+
+        Some(head)
+    }
+
+    /// Takes the next chain of buffers which the device has finished using from the used ring,
+    /// freeing its descriptors. Returns [`None`] if the device hasn't returned any more chains.
+    pub fn pop_used(&mut self) -> Option<UsedChain> {
+        // The used ring is made up of a flags field, an index field, and then the ring.
+        // Each entry of the ring is a `u32` descriptor index followed by a `u32` length.
+        let ring = self.used.as_ptr::<u16>();
+
+        // SAFETY: The index field is the second `u16` of the used ring
+        let used_index = unsafe { ring.add(1).read_volatile() };
+        if used_index == self.next_used {
+            return None;
+        }
+
+        // The entry must be read after the index
+        fence(Ordering::SeqCst);
+
+        // SAFETY: The entry is within the ring, as it is taken modulo the ring's size
+        let [head, length] = unsafe {
+            ring.add(2)
+                .cast::<[u32; 2]>()
+                .add((self.next_used % self.size) as usize)
+                .read_volatile()
+        };
+        self.next_used = self.next_used.wrapping_add(1);
+
+        let head = head.try_into().unwrap();
+
+        // Free each descriptor in the chain
+        let mut index: u16 = head;
+        loop {
+            // SAFETY: `index` is less than `size`, so the descriptor is within the table.
+            // The device has finished using the chain, so it won't write to the descriptor.
+            let descriptor = unsafe {
+                self.descriptors
+                    .as_ptr::<Descriptor>()
+                    .add(index.into())
+                    .read_volatile()
+            };
+
+            self.free_descriptors.push(index);
+
+            if descriptor.flags & DESCRIPTOR_NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+        }
+
+        Some(UsedChain { head, length })
+    }
+}
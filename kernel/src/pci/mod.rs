@@ -6,36 +6,42 @@ mod classcodes;
 mod devices;
 mod drivers;
 mod msi;
+mod power;
 mod registers;
 
 use acpica_bindings::types::tables::mcfg::Mcfg;
+use alloc::format;
 use alloc::sync::Arc;
 use alloc::{collections::VecDeque, vec::Vec};
 use core::mem::size_of;
-use x86_64::structures::paging::frame::PhysFrameRange;
-use x86_64::structures::paging::page::PageRange;
-use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::structures::paging::PhysFrame;
 use x86_64::PhysAddr;
 
+use crate::cpu::MappedRegion;
 use crate::global_state::KERNEL_STATE;
+use crate::input::interrupt_requested;
 use crate::print;
-use crate::scheduler::Task;
+use crate::util::bytes::Bytes;
 use crate::util::generic_mutability::{Mutability, VirtAddrGenericMutabilityExt};
+use crate::util::iterator_list_debug::IteratorListDebug;
 use crate::{global_state::GlobalState, println};
 use devices::*;
 use registers::HeaderType;
 use registers::PciHeader;
 
-use self::classcodes::{ClassCode, SerialBusControllerType};
-use self::drivers::usb::xhci::XhciController;
+use self::bar::{Bar, BarValue, MemorySpaceBarBaseAddress};
+use self::classcodes::ClassCode;
+use self::drivers::DRIVERS;
 use self::registers::PciDeviceId;
 
+pub use self::drivers::usb::{usbls, xhci::halt_controllers};
+
 /// A mapping into the PCIe configuration space of a PCI device.
 /// When this struct is dropped, the mapping is deleted.
 #[derive(Debug)]
 pub struct PcieMappedRegisters {
-    /// The page where the function's config registers are mapped
-    page: Page,
+    /// The mapping of the function's config registers into virtual memory
+    mapping: MappedRegion,
     /// The physical address of the registers
     phys_frame: PhysFrame,
 }
@@ -47,35 +53,32 @@ impl PcieMappedRegisters {
     /// * `phys_frame` must be the configuration registers of a PCIe device
     unsafe fn new(phys_frame: PhysFrame) -> Self {
         // SAFETY: No other code is using these registers
-        let pages = unsafe {
+        let mapping = unsafe {
             KERNEL_STATE
                 .physical_memory_accessor
                 .lock()
-                .map_frames(PhysFrameRange {
-                    start: phys_frame,
-                    end: phys_frame + 1,
-                })
+                .map_region(phys_frame.start_address(), 0x1000)
         };
 
         Self {
-            page: pages.start,
+            mapping,
             phys_frame,
         }
     }
 
     /// Gets a pointer to the start of the configuration space
     unsafe fn as_ptr<T>(&self) -> *const T {
-        self.page.start_address().as_ptr()
+        self.mapping.as_ptr()
     }
 
     /// Gets a mutable pointer to the start of the configuration space
     unsafe fn as_mut_ptr<T>(&self) -> *mut T {
-        self.page.start_address().as_mut_ptr()
+        self.mapping.as_mut_ptr()
     }
 
     /// Gets a pointer to the start of the configuration space
     unsafe fn as_generic_ptr<T, M: Mutability>(&self) -> M::Ptr<T> {
-        VirtAddrGenericMutabilityExt::<M>::as_generic_ptr::<T>(self.page.start_address())
+        VirtAddrGenericMutabilityExt::<M>::as_generic_ptr::<T>(self.mapping.start())
     }
 
     /// Reads the register at the given offset into the configuration space.
@@ -88,6 +91,24 @@ impl PcieMappedRegisters {
         unsafe { self.as_ptr::<u32>().add(register.into()).read_volatile() }
     }
 
+    /// Reads the register at the given offset into the extended configuration space.
+    /// Unlike [`read_reg`], this can read past the first 256 bytes of the configuration space.
+    /// `register` is in registers, i.e. 4-byte multiples, **not** in bytes.
+    ///
+    /// # Safety
+    /// * The caller is responsible for managing any side effects this read may have.
+    ///
+    /// [`read_reg`]: PcieMappedRegisters::read_reg
+    unsafe fn read_extended_reg(&self, register: u16) -> u32 {
+        assert!(
+            register < 0x400,
+            "Register is outside the configuration space"
+        );
+
+        // SAFETY: `register` is within the mapped page. Side-effects are the caller's responsibility.
+        unsafe { self.as_ptr::<u32>().add(register.into()).read_volatile() }
+    }
+
     /// Reads the register at the given offset into the configuration space.
     /// `register` is in registers, i.e. 4-byte multiples, **not** in bytes.
     ///
@@ -124,23 +145,6 @@ impl PcieMappedRegisters {
     }
 }
 
-impl Drop for PcieMappedRegisters {
-    fn drop(&mut self) {
-        let pages = PageRange {
-            start: self.page,
-            end: self.page + 1,
-        };
-
-        // SAFETY: `addr` is the start address
-        unsafe {
-            KERNEL_STATE
-                .physical_memory_accessor
-                .lock()
-                .unmap_frames(pages)
-        };
-    }
-}
-
 /// A cached header of a [`PciFunction`]
 #[derive(Debug, Clone)]
 pub struct PciMappedFunction {
@@ -155,6 +159,10 @@ pub struct PciMappedFunction {
     /// The type of device
     class_code: ClassCode,
 
+    /// The size of each of the function's BARs, measured when the function was enumerated, before any driver was using it.
+    /// Unimplemented BARs and the upper halves of 64-bit BARs have a size of 0.
+    bar_sizes: [u64; 6],
+
     registers: Arc<PcieMappedRegisters>,
 }
 
@@ -208,6 +216,16 @@ impl PciMappedFunction {
         unsafe { self.registers.read_reg(register) }
     }
 
+    /// Reads the register at the given offset into the extended configuration space.
+    /// `register` is in registers, i.e. 4-byte multiples, **not** in bytes.
+    ///
+    /// # Safety
+    /// * The caller is responsible for managing any side effects this read may have.
+    unsafe fn read_extended_reg(&self, register: u16) -> u32 {
+        // SAFETY: Side-effects are the caller's responsibility
+        unsafe { self.registers.read_extended_reg(register) }
+    }
+
     /// Reads the register at the given offset into the configuration space.
     /// `register` is in registers, i.e. 4-byte multiples, **not** in bytes.
     ///
@@ -229,6 +247,58 @@ impl PciMappedFunction {
 
         PciHeader::from_registers(registers, &self.function)
     }
+
+    /// Gets the size of the BAR with the given number, as measured when the function was enumerated.
+    /// Returns 0 if the BAR isn't implemented.
+    ///
+    /// Sizing a BAR disables the device's memory accesses, so this should be used rather than [`Bar::get_size`]
+    /// once a driver has started using the device.
+    pub fn bar_size(&self, bar_number: u8) -> u64 {
+        self.bar_sizes
+            .get(usize::from(bar_number))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Measures the size of each of the function's BARs.
+    /// This must only be called before any driver is using the function, as sizing a BAR briefly disables the device.
+    fn measure_bar_sizes(&self) -> [u64; 6] {
+        let mut sizes = [0; 6];
+
+        let Ok(Some(header)) = self.read_header() else {
+            return sizes;
+        };
+
+        let mut bar_number = 0;
+        while bar_number < bar_count(&header) {
+            // SAFETY: The function's header type has `bar_count` BARs, and the upper halves of 64-bit BARs are skipped below.
+            // No driver has been started for the function yet, so nothing else is accessing the BAR.
+            let bar = unsafe { Bar::new_from_bar_number(&self.registers, bar_number) };
+            sizes[usize::from(bar_number)] = bar.get_size();
+
+            // A 64-bit BAR also takes up the next BAR's register
+            if let BarValue::MemorySpace {
+                base_address: MemorySpaceBarBaseAddress::Large(_),
+                ..
+            } = bar.read_value()
+            {
+                bar_number += 1;
+            }
+
+            bar_number += 1;
+        }
+
+        sizes
+    }
+}
+
+/// Gets the number of BARs which a function with the given header has
+fn bar_count(header: &PciHeader) -> u8 {
+    match header.header_type {
+        HeaderType::GeneralDevice(_) => 6,
+        HeaderType::PciToPciBridge(_) => 2,
+        HeaderType::PciToCardbusBridge() => 0,
+    }
 }
 
 impl PciDeviceCache {
@@ -301,6 +371,14 @@ impl PciCache {
             .iter()
             .find(|segment_cache| segment_cache.controller.segment == segment)
     }
+
+    /// Gets the function at the address given by `selector`, if present.
+    fn get_function(&self, selector: FunctionSelector) -> Option<&PciMappedFunction> {
+        self.get_segment(selector.segment)?
+            .get_bus(selector.bus)?
+            .get_device(selector.device)?
+            .get_function(selector.function)
+    }
 }
 
 /// Maps the configuration space of a device on a given PCIe controller into virtual memory.
@@ -347,7 +425,7 @@ unsafe fn scan_function(
         let class_code = ClassCode::new(class_code, subclass, prog_if)
             .expect("PCI device should have had a valid class code");
 
-        Some(PciMappedFunction {
+        let mut function_cache = PciMappedFunction {
             segment: controller.segment,
             function,
             registers: Arc::new(registers),
@@ -356,7 +434,11 @@ unsafe fn scan_function(
                 device: device_id,
             },
             class_code,
-        })
+            bar_sizes: [0; 6],
+        };
+        function_cache.bar_sizes = function_cache.measure_bar_sizes();
+
+        Some(function_cache)
     }
 }
 
@@ -422,33 +504,355 @@ unsafe fn scan_bus(controller: &PcieController, bus: u8) -> (PciBusCache, Vec<u8
     (PciBusCache { bus, devices }, buses)
 }
 
-/// Enumerates the system's PCI devices and prints info about them
+/// The maximum number of capabilities [`lspci`] prints for each function
+const MAX_LISTED_CAPABILITIES: usize = 8;
+
+/// The address of a single function to print with `lspci -s`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FunctionSelector {
+    /// The PCI segment of the function
+    segment: u16,
+    /// The bus number of the function
+    bus: u8,
+    /// The device number of the function
+    device: u8,
+    /// The function number of the function
+    function: u8,
+}
+
+/// The options which can be passed to [`lspci`]
+#[derive(Debug, Default)]
+struct LspciArgs {
+    /// Whether to print extra info about each function
+    verbose: bool,
+    /// Whether to also print each function's PCI Express extended capabilities
+    very_verbose: bool,
+    /// Whether to print functions as a tree following PCI bridges, rather than as a flat list
+    tree: bool,
+    /// Only show functions with this top-level class code
+    class: Option<u8>,
+    /// Only show functions with this vendor ID
+    vendor: Option<u16>,
+    /// Only show functions with this device ID
+    device: Option<u16>,
+    /// Print everything about this one function, rather than listing functions
+    selector: Option<FunctionSelector>,
+}
+
+impl LspciArgs {
+    /// Parses the arguments given to [`lspci`]
+    fn parse(args: &[&str]) -> Result<Self, &'static str> {
+        /// Parses a hex number, with or without a `0x` prefix
+        fn parse_hex<T: TryFrom<u32>>(s: &str) -> Option<T> {
+            let s = s.strip_prefix("0x").unwrap_or(s);
+            u32::from_str_radix(s, 16).ok()?.try_into().ok()
+        }
+
+        /// Parses an ID which may be empty or `*` to match any ID
+        fn parse_id(s: &str) -> Result<Option<u16>, &'static str> {
+            match s {
+                "" | "*" => Ok(None),
+                s => parse_hex(s)
+                    .map(Some)
+                    .ok_or("IDs must be 16-bit hex numbers"),
+            }
+        }
+
+        /// Parses a function address in the form `[<segment>:]<bus>:<device>.<function>`.
+        /// The segment defaults to 0 if it is left out.
+        fn parse_selector(s: &str) -> Option<FunctionSelector> {
+            let (s, function) = s.rsplit_once('.')?;
+            let (s, device) = s.rsplit_once(':')?;
+            let (segment, bus) = match s.rsplit_once(':') {
+                Some((segment, bus)) => (parse_hex(segment)?, bus),
+                None => (0, s),
+            };
+
+            let selector = FunctionSelector {
+                segment,
+                bus: parse_hex(bus)?,
+                device: parse_hex(device)?,
+                function: parse_hex(function)?,
+            };
+
+            (selector.device < 32 && selector.function < 8).then_some(selector)
+        }
+
+        let mut parsed = Self::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match *arg {
+                "-v" => parsed.verbose = true,
+                "-vv" => {
+                    parsed.verbose = true;
+                    parsed.very_verbose = true;
+                }
+                "-t" => parsed.tree = true,
+                "-c" => {
+                    let class = args.next().ok_or("-c requires a class code")?;
+                    parsed.class =
+                        Some(parse_hex(class).ok_or("Class codes must be 8-bit hex numbers")?);
+                }
+                "-d" => {
+                    let ids = args
+                        .next()
+                        .ok_or("-d requires a <vendor>:<device> argument")?;
+                    let (vendor, device) = ids
+                        .split_once(':')
+                        .ok_or("-d requires a <vendor>:<device> argument")?;
+
+                    parsed.vendor = parse_id(vendor)?;
+                    parsed.device = parse_id(device)?;
+                }
+                "-s" => {
+                    let selector = args
+                        .next()
+                        .ok_or("-s requires a [<segment>:]<bus>:<device>.<function> argument")?;
+                    parsed.selector = Some(parse_selector(selector).ok_or(
+                        "Function addresses must be [<segment>:]<bus>:<device>.<function> in hex",
+                    )?);
+                }
+                _ => return Err("Unknown argument"),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Gets whether a function matches the filters in these arguments
+    fn matches(&self, function: &PciMappedFunction) -> bool {
+        self.class
+            .map_or(true, |class| function.class_code.class_code() == class)
+            && self
+                .vendor
+                .map_or(true, |vendor| function.id.vendor == vendor)
+            && self
+                .device
+                .map_or(true, |device| function.id.device == device)
+    }
+}
+
+/// Enumerates the system's PCI devices and prints info about them.
+///
+/// # Arguments
+/// * `-v`: Print where each function's registers are mapped and its capabilities
+/// * `-vv`: As `-v`, but also print each function's PCI Express extended capabilities
+/// * `-t`: Print the functions as a tree, with the functions behind each PCI bridge indented below it
+/// * `-c <class>`: Only print functions with the given top-level class code (in hex)
+/// * `-d <vendor>:<device>`: Only print functions with the given vendor and device IDs (in hex).
+///     Either ID can be left empty or set to `*` to match any ID.
+/// * `-s [<segment>:]<bus>:<device>.<function>`: Only print the function at the given address (in hex),
+///     with its whole header, BARs, capabilities, and any registers its driver can summarise.
+///     With `-v`, the driver prints its registers in more detail.
 pub fn lspci(args: &[&str]) {
-    let is_verbose = args.contains(&"-v");
+    let args = match LspciArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("{e}");
+            return;
+        }
+    };
 
-    PCI_CACHE.lock().functions().for_each(|function_cache| {
-        let header = function_cache.read_header().unwrap().unwrap();
+    let cache = PCI_CACHE.lock();
 
-        print!("{:04x}:", function_cache.segment);
-        print!("{}  ", function_cache.function);
-        print!("{}  ", header.device_code);
-        print!("{:?}", header.class_code);
-        println!();
+    if let Some(selector) = args.selector {
+        match cache.get_function(selector) {
+            Some(function_cache) => print_function_details(function_cache, args.verbose),
+            None => println!("No such PCI function"),
+        }
+    } else if args.tree {
+        for segment in &cache.segments {
+            println!("Segment {:04x}:", segment.controller.segment);
+            let mut visited = Vec::new();
+            print_bus_tree(segment, segment.controller.min_bus, 1, &args, &mut visited);
+        }
+    } else {
+        for function_cache in cache.functions() {
+            // Stop early if Ctrl+C is pressed
+            if interrupt_requested() {
+                return;
+            }
+
+            if args.matches(function_cache) {
+                print_function(function_cache, 0, &args);
+            }
+        }
+    }
+}
+
+/// Prints info about a function, for [`lspci`]
+fn print_function(function_cache: &PciMappedFunction, indent: usize, args: &LspciArgs) {
+    let header = function_cache.read_header().unwrap().unwrap();
+
+    print!("{:indent$}", "", indent = indent * 2);
+    print!("{:04x}:", function_cache.segment);
+    print!("{}  ", function_cache.function);
+    print!("{}  ", header.device_code);
+    print!("{}", header.class_code.name());
+    if let Some(vendor) = header.device_code.vendor_name() {
+        print!(": {vendor}");
+    }
+    println!();
+
+    if args.verbose {
+        println!(
+            "{:indent$}  Mapped at {:#x}",
+            "",
+            function_cache.registers.mapping.start(),
+            indent = indent * 2
+        );
+
+        if let Some(capabilities) = function_cache.capabilities() {
+            let capabilities =
+                IteratorListDebug::new_with_default_formatting(capabilities.map(|(c, _)| c))
+                    .with_max_items(MAX_LISTED_CAPABILITIES);
+
+            // Print the list line by line so that each line is indented
+            println!("{:indent$}  Capabilities:", "", indent = indent * 2);
+            for line in format!("{capabilities:#?}").lines() {
+                println!("{:indent$}    {line}", "", indent = indent * 2);
+            }
+        }
 
-        if is_verbose {
+        if args.very_verbose {
             println!(
-                "  Mapped at {:#x}",
-                function_cache.registers.page.start_address()
+                "{:indent$}  Extended capabilities:",
+                "",
+                indent = indent * 2
             );
+            for (id, offset) in function_cache.extended_capabilities() {
+                println!(
+                    "{:indent$}    [{offset:#05x}] {id:?}",
+                    "",
+                    indent = indent * 2
+                );
+            }
+        }
+    }
+}
+
+/// Prints everything known about a function, for `lspci -s`.
+/// `verbose` is passed on to the driver's [`print_registers`][drivers::PciDriver::print_registers].
+fn print_function_details(function_cache: &PciMappedFunction, verbose: bool) {
+    print_function(function_cache, 0, &LspciArgs::default());
+
+    let header = function_cache.read_header().unwrap().unwrap();
+
+    println!("Mapped at {:#x}", function_cache.registers.mapping.start());
+    println!("{header:#?}");
+
+    print_bars(function_cache, &header);
+
+    if let Some(capabilities) = function_cache.capabilities() {
+        let capabilities =
+            IteratorListDebug::new_with_default_formatting(capabilities.map(|(c, _)| c));
+        println!("Capabilities: {capabilities:#?}");
+    }
+
+    println!("Extended capabilities:");
+    for (id, offset) in function_cache.extended_capabilities() {
+        println!("  [{offset:#05x}] {id:?}");
+    }
+
+    let print_registers = DRIVERS
+        .iter()
+        .find(|driver| (driver.matches)(&header))
+        .and_then(|driver| driver.print_registers);
+
+    if let Some(print_registers) = print_registers {
+        println!("Registers:");
+        print_registers(function_cache, verbose);
+    }
+}
+
+/// Prints the address and size of each of a function's BARs, for `lspci -s`.
+/// The sizes are the ones measured at enumeration, as the function's driver may be using it.
+fn print_bars(function_cache: &PciMappedFunction, header: &PciHeader) {
+    let mut bar_number = 0;
+    while bar_number < bar_count(header) {
+        // SAFETY: The function's header type has `bar_count` BARs, and the upper halves of 64-bit BARs are skipped below.
+        // The `Bar` is only used to read the BAR's value, which doesn't affect the device.
+        let bar = unsafe { Bar::new_from_bar_number(&function_cache.registers, bar_number) };
+
+        match bar.read_value() {
+            BarValue::MemorySpace {
+                base_address,
+                prefetchable,
+            } => {
+                let is_64_bit = matches!(base_address, MemorySpaceBarBaseAddress::Large(_));
+                let size = function_cache.bar_size(bar_number);
+
+                if size == 0 {
+                    println!("BAR {bar_number}: unused");
+                } else {
+                    println!(
+                        "BAR {bar_number}: memory at {:#x} ({}-bit, {}prefetchable), size {}",
+                        base_address.as_address(),
+                        if is_64_bit { 64 } else { 32 },
+                        if prefetchable { "" } else { "non-" },
+                        Bytes(size),
+                    );
+                }
 
-            if let Some(capabilities) = function_cache.capabilities() {
-                println!("  Capabilities:");
-                for (c, _) in capabilities {
-                    println!("    {c:?}");
+                // A 64-bit BAR also takes up the next BAR's register
+                if is_64_bit {
+                    bar_number += 1;
                 }
             }
+            BarValue::IOSpace { base_address } => {
+                println!("BAR {bar_number}: IO ports at {base_address:#x}");
+            }
         }
-    });
+
+        bar_number += 1;
+    }
+}
+
+/// Prints the functions on a bus as a tree for [`lspci`], recursing into the buses behind any PCI bridges.
+/// Bridges are always printed so that the tree structure is visible,
+/// but other functions are only printed if they match the filters in `args`.
+///
+/// `visited` is the list of buses which have already been printed,
+/// to prevent infinite recursion if the bridges are misconfigured.
+fn print_bus_tree(
+    segment: &PciSegmentCache,
+    bus: u8,
+    indent: usize,
+    args: &LspciArgs,
+    visited: &mut Vec<u8>,
+) {
+    // Stop early if Ctrl+C is pressed
+    if visited.contains(&bus) || interrupt_requested() {
+        return;
+    }
+    visited.push(bus);
+
+    let Some(bus_cache) = segment.get_bus(bus) else {
+        return;
+    };
+
+    println!("{:indent$}Bus {bus:02x}:", "", indent = indent * 2);
+
+    for device in &bus_cache.devices {
+        for function in &device.functions {
+            let secondary_bus = match function.read_header() {
+                Ok(Some(PciHeader {
+                    header_type: HeaderType::PciToPciBridge(h),
+                    ..
+                })) => Some(h.secondary_bus_number),
+                _ => None,
+            };
+
+            if secondary_bus.is_some() || args.matches(function) {
+                print_function(function, indent + 1, args);
+            }
+
+            if let Some(secondary_bus) = secondary_bus {
+                print_bus_tree(segment, secondary_bus, indent + 2, args, visited);
+            }
+        }
+    }
 }
 
 /// A cache of the system's PCI devices
@@ -506,15 +910,12 @@ pub unsafe fn init(mcfg: Mcfg) {
     for function in lock.functions_mut() {
         let header = function.read_header().unwrap().unwrap();
 
-        if let classcodes::ClassCode::SerialBusController(SerialBusControllerType::UsbController(
-            classcodes::USBControllerType::Xhci,
-        )) = header.class_code
-        {
+        if let Some(driver) = DRIVERS.iter().find(|driver| (driver.matches)(&header)) {
             // SAFETY: This function may only be called once, and `PCI_CACHE.lock().functions()`
-            // produces each function only once, so `XhciController::new` will only be called once per function.
-            let task = unsafe { XhciController::init(function.clone()) };
+            // produces each function only once, so each driver's `init` will only be called once per function.
+            let task = unsafe { (driver.init)(function.clone()) };
 
-            Task::register(task);
+            task.start();
         }
     }
 }
@@ -573,3 +974,32 @@ fn split_to_u8(value: u32) -> (u8, u8, u8, u8) {
         (value >> 24) as u8,
     )
 }
+
+/// Tests that `lspci -s` function addresses are parsed with and without a segment, and that invalid ones are rejected
+#[test_case]
+fn test_lspci_selector() {
+    let selector = |args: &[&str]| LspciArgs::parse(args).map(|args| args.selector);
+
+    assert_eq!(
+        selector(&["-s", "0001:0a:1f.7"]),
+        Ok(Some(FunctionSelector {
+            segment: 1,
+            bus: 0xa,
+            device: 0x1f,
+            function: 7,
+        }))
+    );
+    assert_eq!(
+        selector(&["-s", "00:02.0"]),
+        Ok(Some(FunctionSelector {
+            segment: 0,
+            bus: 0,
+            device: 2,
+            function: 0,
+        }))
+    );
+    assert!(selector(&["-s", "00:20.0"]).is_err());
+    assert!(selector(&["-s", "00:02.8"]).is_err());
+    assert!(selector(&["-s", "00.0"]).is_err());
+    assert!(selector(&["-s"]).is_err());
+}
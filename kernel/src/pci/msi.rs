@@ -1,6 +1,7 @@
-//! The [`setup_msi`] method on [`PciMappedFunction`]
+//! The [`setup_msi`] and [`configure_msi`] methods on [`PciMappedFunction`]
 //!
 //! [`setup_msi`]: PciMappedFunction::setup_msi
+//! [`configure_msi`]: PciMappedFunction::configure_msi
 
 use log::debug;
 
@@ -84,7 +85,63 @@ impl PciMappedFunction {
             return Err(MsiInitError::NoMsiSupport);
         }
 
-        // SAFETY: This sets the 'bus master' bit of the command register, which allows the device to make memory accesses
+        // SAFETY: MSI has been set up, so the device's memory writes will be interrupts
+        unsafe {
+            self.enable_bus_mastering();
+        }
+
+        Ok(())
+    }
+
+    /// Programs the device's MSI capability to send a single interrupt vector, `vector`, to the core with the given APIC ID,
+    /// and enables MSI. This is for devices which don't support MSI-X - devices which do should use [`setup_msi`] instead.
+    ///
+    /// # Safety
+    /// * This function will overwrite whatever MSI configuration is already present
+    /// * The caller must make sure that the interrupt handler for `vector` is set up for this device.
+    ///
+    /// [`setup_msi`]: PciMappedFunction::setup_msi
+    #[allow(dead_code)] // Not yet used by any driver
+    pub unsafe fn configure_msi(&mut self, vector: u8, apic_id: u8) -> Result<(), MsiInitError> {
+        let address = X64MsiAddress {
+            apic_id,
+            redirection_hint: false,
+            destination_is_logical: false,
+            delivery_mode: X64MsiDeliveryMode::Fixed,
+            trigger_mode: X64MsiTriggerMode::Edge,
+            vector,
+        };
+
+        {
+            let Some(mut capabilities) = self.capabilities_mut() else {
+                return Err(MsiInitError::NoMsiSupport);
+            };
+
+            let Some(mut msi) = capabilities.find_map(|(c, _)| match c {
+                CapabilityEntry::MessageSignalledInterrupts(msi) => Some(msi),
+                _ => None,
+            }) else {
+                return Err(MsiInitError::NoMsiSupport);
+            };
+
+            msi.configure(address);
+        }
+
+        // SAFETY: MSI has been set up, so the device's memory writes will be interrupts
+        unsafe {
+            self.enable_bus_mastering();
+        }
+
+        Ok(())
+    }
+
+    /// Sets the 'bus master' bit of the command register, which allows the device to make memory accesses
+    /// (including sending MSI interrupts), and disables pin-based interrupts.
+    ///
+    /// # Safety
+    /// * The device must be set up so that any memory accesses it makes are sound
+    pub(super) unsafe fn enable_bus_mastering(&mut self) {
+        // SAFETY: The caller guarantees that the device's memory accesses are sound
         unsafe {
             let status_and_command = self.read_reg(1);
             self.write_reg(1, status_and_command | (1 << 2) | (1 << 10));
@@ -102,8 +159,6 @@ impl PciMappedFunction {
                 .command
                 .interrupts_disabled());
         }
-
-        Ok(())
     }
 }
 
@@ -207,7 +262,7 @@ unsafe fn setup_msi_standard(
     mut msi: capability_registers::MessageSignalledInterruptsCapability<'_, Mutable>,
     vector: u8,
 ) -> Result<(), MsiInitError> {
-    msi.write_address_x64(X64MsiAddress {
+    msi.configure(X64MsiAddress {
         apic_id: current_apic_id().unwrap().try_into().unwrap(),
         redirection_hint: false,
         destination_is_logical: false,
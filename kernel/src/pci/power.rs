@@ -0,0 +1,51 @@
+//! The [`set_power_state`] method on [`PciMappedFunction`]
+//!
+//! [`set_power_state`]: PciMappedFunction::set_power_state
+
+use super::{
+    capability_registers::{CapabilityEntry, PowerState},
+    PciMappedFunction,
+};
+
+/// An error which can occur when changing the power state of a PCI device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerStateError {
+    /// The device doesn't have a power management capability, so its power state can't be changed
+    NoPowerManagement,
+    /// The device doesn't support the requested power state
+    UnsupportedState(PowerState),
+}
+
+impl PciMappedFunction {
+    /// Puts the device into the given power state by writing its power management capability's
+    /// control and status register, and returns the state the device was in before.
+    ///
+    /// After a transition from [`D3Hot`] to [`D0`], the device may not be accessed for 10ms.
+    /// Unless the device reports [`no_soft_reset`], the transition also resets the device,
+    /// which can clear its BARs and other configuration registers, so the caller should re-read them afterwards.
+    ///
+    /// [`D3Hot`]: PowerState::D3Hot
+    /// [`D0`]: PowerState::D0
+    /// [`no_soft_reset`]: super::capability_registers::PowerManagementStatusAndControl::no_soft_reset
+    pub fn set_power_state(&mut self, state: PowerState) -> Result<PowerState, PowerStateError> {
+        let mut capabilities = self
+            .capabilities_mut()
+            .ok_or(PowerStateError::NoPowerManagement)?;
+
+        let mut power_management = capabilities
+            .find_map(|(c, _)| match c {
+                CapabilityEntry::PciPowerManagementInterface(p) => Some(p),
+                _ => None,
+            })
+            .ok_or(PowerStateError::NoPowerManagement)?;
+
+        let previous_state = power_management.power_state();
+        if previous_state != state {
+            power_management
+                .set_power_state(state)
+                .map_err(|e| PowerStateError::UnsupportedState(e.0))?;
+        }
+
+        Ok(previous_state)
+    }
+}
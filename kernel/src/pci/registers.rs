@@ -18,7 +18,29 @@ pub struct PciDeviceId {
     pub device: u16,
 }
 
+/// Gets the name of the vendor with the given PCI vendor ID.
+/// Only a few common vendors, including those of QEMU's emulated devices, are known.
+pub fn vendor_name(vendor: u16) -> Option<&'static str> {
+    match vendor {
+        0x1002 => Some("Advanced Micro Devices, Inc. [AMD/ATI]"),
+        0x1022 => Some("Advanced Micro Devices, Inc. [AMD]"),
+        0x10de => Some("NVIDIA Corporation"),
+        0x1234 => Some("Technical Corp."),
+        0x15ad => Some("VMware"),
+        0x1af4 | 0x1b36 => Some("Red Hat, Inc."),
+        0x80ee => Some("InnoTek Systemberatung GmbH"),
+        0x8086 => Some("Intel Corporation"),
+        _ => None,
+    }
+}
+
 impl PciDeviceId {
+    /// Gets the name of the device's vendor, if it is known.
+    /// See [`vendor_name`] for which vendors are known.
+    pub fn vendor_name(&self) -> Option<&'static str> {
+        vendor_name(self.vendor)
+    }
+
     /// Gets whether the device code is valid.
     /// A code is invalid if the vendor is `0xffff`, which signals that there is no device connected to that slot.
     pub fn is_valid(&self) -> bool {
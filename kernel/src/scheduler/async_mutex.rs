@@ -0,0 +1,197 @@
+//! The [`AsyncMutex`] type, a mutex which can be held across `.await` points in tasks
+
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// A mutex whose [`lock`][AsyncMutex::lock] method returns a future, which is pending until the lock is free.
+///
+/// A [`spin::Mutex`] can't be held across an `.await` point, because tasks are polled one at a time in the timer
+/// interrupt handler: if another task tried to lock it, that task would spin forever in the interrupt handler and the
+/// task holding the lock would never be polled again to release it. A [`RefCell`] has the same problem, except that
+/// the second borrow panics instead of deadlocking. A task waiting for an [`AsyncMutex`] instead returns
+/// [`Poll::Pending`], so the scheduler moves on to the other tasks, including the one holding the lock.
+///
+/// [`RefCell`]: core::cell::RefCell
+pub struct AsyncMutex<T> {
+    /// Whether an [`AsyncMutexGuard`] currently exists
+    locked: AtomicBool,
+    /// The wakers of the tasks waiting for the lock, which are woken when it is released
+    waiters: Mutex<Vec<Waker>>,
+    /// The data protected by the mutex
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The mutex only gives access to the data through an `AsyncMutexGuard`, and `locked` ensures only one guard
+// exists at a time. This is the same requirement as `spin::Mutex`, so the data only needs to be `Send`.
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+// SAFETY: Moving the mutex just moves the data, which is `Send`
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+
+#[allow(dead_code)]
+impl<T> AsyncMutex<T> {
+    /// Constructs a new unlocked [`AsyncMutex`] containing `data`
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Locks the mutex if it is free, without waiting
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| AsyncMutexGuard {
+                mutex: self,
+                _not_auto_sync: PhantomData,
+            })
+    }
+
+    /// Returns a future which resolves to a guard once the mutex has been locked
+    pub fn lock(&self) -> AsyncMutexLock<'_, T> {
+        AsyncMutexLock { mutex: self }
+    }
+
+    /// Gets a mutable reference to the data. No locking is needed because the mutex is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+/// A future returned by [`AsyncMutex::lock`], which resolves once the mutex has been locked
+pub struct AsyncMutexLock<'a, T> {
+    /// The mutex being locked
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<'a, T> Future for AsyncMutexLock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(guard) = self.mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+
+        // Tasks may be polled in the timer interrupt handler, so disable interrupts while `waiters` is locked
+        without_interrupts(|| {
+            let mut waiters = self.mutex.waiters.lock();
+            // The same task may be polled many times while waiting, so only store its waker once
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+        });
+
+        // The lock may have been released between the first check and the waker being stored,
+        // in which case nothing would wake this task, so check again
+        match self.mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// A guard giving access to the data in an [`AsyncMutex`]. The mutex is unlocked when the guard is dropped.
+pub struct AsyncMutexGuard<'a, T> {
+    /// The mutex which is locked
+    mutex: &'a AsyncMutex<T>,
+    /// Stops the guard being automatically [`Send`] and [`Sync`], which would only require `T: Send`
+    /// because of the [`AsyncMutex`]'s impls. The correct bounds are implemented manually below.
+    _not_auto_sync: PhantomData<*const ()>,
+}
+
+// SAFETY: Sharing the guard only gives out `&T`, so it is sound to share between threads if `T` is `Sync`
+unsafe impl<T: Sync> Sync for AsyncMutexGuard<'_, T> {}
+// SAFETY: The mutex can be unlocked from any thread, so sending the guard is like sending a `&mut T`
+unsafe impl<T: Send> Send for AsyncMutexGuard<'_, T> {}
+
+impl<'a, T> Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: This guard is the only one for the mutex, so nothing else is accessing the data
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for AsyncMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: This guard is the only one for the mutex, and it is borrowed mutably,
+        // so nothing else is accessing the data
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for AsyncMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.mutex.waiters.lock()));
+
+        // Wake every waiting task, as the first one to be polled may not be the first one woken.
+        // The others will store their wakers again when they find the mutex locked.
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll},
+    };
+
+    use alloc::vec::Vec;
+
+    use super::AsyncMutex;
+    use crate::scheduler::{no_op_waker, yield_now};
+
+    #[test_case]
+    fn test_async_mutex_contention() {
+        let mutex = AsyncMutex::new(Vec::new());
+
+        // Each task pushes two values with an await point in between, so if the tasks weren't exclusive,
+        // their values would be interleaved
+        let task = |id: u8| {
+            let mutex = &mutex;
+            async move {
+                let mut guard = mutex.lock().await;
+                guard.push(id);
+                yield_now().await;
+                guard.push(id);
+            }
+        };
+
+        let mut a = pin!(task(1));
+        let mut b = pin!(task(2));
+        let waker = no_op_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // `a` takes the lock, then `b` waits for it
+        assert_eq!(a.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(b.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(b.as_mut().poll(&mut cx), Poll::Pending);
+
+        // `a` finishes and releases the lock, so `b` can take it
+        assert_eq!(a.as_mut().poll(&mut cx), Poll::Ready(()));
+        assert_eq!(b.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(b.as_mut().poll(&mut cx), Poll::Ready(()));
+
+        assert_eq!(mutex.try_lock().unwrap().as_slice(), &[1, 1, 2, 2]);
+    }
+}
@@ -6,79 +6,346 @@ use core::{
     task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
 };
 
-use alloc::{boxed::Box, vec::Vec};
-use spin::Mutex;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use spin::{Mutex, MutexGuard};
 use x86_64::instructions::interrupts::without_interrupts;
 
-use crate::println;
+use crate::{cpu::smp::is_bsp, println};
 
-/// An async task which is polled on each timer interrupt
-pub struct Task(Pin<Box<dyn Future<Output = ()>>>);
+mod async_mutex;
+pub mod watchdog;
 
-// SAFETY: Currently the kernel doesn't have threads.
-// TODO: When threads are added, this code will need to be updated to ensure soundness.
-unsafe impl Send for Task {}
+pub use async_mutex::{AsyncMutex, AsyncMutexGuard, AsyncMutexLock};
+
+/// An async task which is polled on each timer interrupt.
+///
+/// Tasks' futures don't need to be [`Send`], so [`Task`] isn't [`Send`] either.
+/// Tasks are only ever stored in a [`TaskQueue`], which keeps them on the BSP.
+pub struct Task {
+    /// The name of the task, shown by the `ps` shell command
+    name: Option<&'static str>,
+    /// Whether the task has been polled yet
+    state: TaskState,
+    /// The future which the task runs
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
 
 impl Task {
-    /// Registers a new task
-    pub fn register<T>(t: T)
+    /// Registers a new task, and returns a [`JoinHandle`] which can be awaited to get the task's output.
+    /// The task keeps running if the [`JoinHandle`] is dropped.
+    ///
+    /// `name` is shown by the `ps` shell command.
+    #[allow(dead_code)]
+    pub fn register<F>(name: Option<&'static str>, f: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = join_pair(f);
+        Self::register_detached(name, task);
+        handle
+    }
+
+    /// Registers a new task without a way to wait for it to complete.
+    ///
+    /// `name` is shown by the `ps` shell command.
+    pub fn register_detached<T>(name: Option<&'static str>, t: T)
     where
         T: Future<Output = ()> + 'static,
     {
-        // The `TASKS` vector is used in the timer interrupt handler,
-        // so disable interrupts while modifying it to avoid deadlock
+        Self::new(name, t).start();
+    }
+
+    /// Constructs a new task without registering it.
+    /// The task won't be polled until it is registered using [`start`][Task::start].
+    ///
+    /// `name` is shown by the `ps` shell command.
+    pub fn new<T>(name: Option<&'static str>, t: T) -> Self
+    where
+        T: Future<Output = ()> + 'static,
+    {
+        Self {
+            name,
+            state: TaskState::New,
+            future: Box::pin(t),
+        }
+    }
+
+    /// Gets the task's name and state
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            name: self.name,
+            state: self.state,
+        }
+    }
+
+    /// Registers the task, so that it will be polled on each timer interrupt
+    pub fn start(self) {
+        // Tasks may be registered while `TASKS` is locked by `poll_tasks`, so they are added to `NEW_TASKS` instead.
+        // `NEW_TASKS` is used in the timer interrupt handler, so disable interrupts while modifying it to avoid deadlock.
+        without_interrupts(|| NEW_TASKS.lock().push(self));
+    }
+}
+
+/// The state shared between a task and its [`JoinHandle`]
+struct JoinState<T> {
+    /// The output of the task, once it has completed and until it is taken by the [`JoinHandle`]
+    output: Option<T>,
+    /// The waker of the last context the [`JoinHandle`] was polled in
+    waker: Option<Waker>,
+}
+
+/// A handle to a task registered with [`Task::register`], which resolves to the task's output once it completes.
+///
+/// A [`JoinHandle`] should not be polled again after it has returned [`Poll::Ready`].
+pub struct JoinHandle<T> {
+    /// The state shared with the task
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Whether the task has completed and its output hasn't been taken yet
+    #[allow(dead_code)]
+    pub fn is_finished(&self) -> bool {
+        without_interrupts(|| self.state.lock().output.is_some())
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The task may complete in the timer interrupt handler, so disable interrupts while the state is locked
         without_interrupts(|| {
-            TASKS.lock().push(Self(Box::pin(t)));
+            let mut state = self.state.lock();
+
+            match state.output.take() {
+                Some(output) => Poll::Ready(output),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+/// Wraps `f` in a future which stores its output in a shared cell when it completes,
+/// and constructs a [`JoinHandle`] which reads the output from that cell.
+fn join_pair<F>(f: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        waker: None,
+    }));
+    let task_state = Arc::clone(&state);
+
+    let task = async move {
+        let output = f.await;
+
+        let waker = without_interrupts(|| {
+            let mut state = task_state.lock();
+            state.output = Some(output);
+            state.waker.take()
         });
+
+        // Wake the task awaiting the handle after the lock is released, in case waking it polls it
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    };
+
+    (task, JoinHandle { state })
+}
+
+/// A future which returns [`Poll::Pending`] once before completing, returned by [`yield_now`]
+struct YieldNow {
+    /// Whether the future has already returned [`Poll::Pending`]
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            // The task isn't waiting for anything, so it should be polled again straight away
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Yields to the scheduler once, so that other tasks can run before the current task continues
+pub fn yield_now() -> impl Future<Output = ()> {
+    YieldNow { yielded: false }
+}
+
+/// A list of [`Task`]s which can only be accessed from the BSP.
+///
+/// Many tasks hold values which aren't [`Send`] (such as [`RefCell`]s shared between tasks), so a task must never be
+/// polled or dropped on a different core to the one it was registered on. Until tasks can be scheduled on other cores,
+/// all tasks are registered and polled on the BSP, and [`lock`] checks this in debug builds.
+///
+/// [`RefCell`]: core::cell::RefCell
+/// [`lock`]: TaskQueue::lock
+struct TaskQueue {
+    /// The tasks in the queue
+    tasks: Mutex<Vec<Task>>,
+}
+
+// SAFETY: The tasks are only accessed through `lock`, which is only called on the BSP,
+// so no task is ever accessed from more than one core.
+unsafe impl Sync for TaskQueue {}
+
+impl TaskQueue {
+    /// Constructs a new empty [`TaskQueue`]
+    const fn new() -> Self {
+        Self {
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Locks the queue.
+    ///
+    /// # Panics
+    /// In debug builds, if this function is not called on the BSP
+    fn lock(&self) -> MutexGuard<Vec<Task>> {
+        debug_assert!(is_bsp(), "Tasks can only be accessed from the BSP");
+        self.tasks.lock()
     }
 }
 
 /// A global list of tasks
-static TASKS: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+static TASKS: TaskQueue = TaskQueue::new();
+/// Tasks which have been registered since [`TASKS`] was last polled
+static NEW_TASKS: TaskQueue = TaskQueue::new();
 
-/// Constructs a [`RawWaker`] which panics if [`wake`][Waker::wake] is called
-fn dummy_raw_waker() -> RawWaker {
+/// Constructs a [`RawWaker`] which does nothing when [`wake`][Waker::wake] is called.
+/// Every task is polled on each timer interrupt, so tasks don't need to be woken to make progress.
+fn no_op_raw_waker() -> RawWaker {
     /// Constructs a new [`RawWaker`]
     fn clone(_: *const ()) -> RawWaker {
-        dummy_raw_waker()
-    }
-    /// Panics
-    /// TODO: an actual waker implementation
-    fn waker_panic(_: *const ()) {
-        panic!("Dummy waker should not be used");
+        no_op_raw_waker()
     }
     /// Does nothing
     fn no_op(_: *const ()) {}
 
-    let vtable = &RawWakerVTable::new(clone, waker_panic, waker_panic, no_op);
+    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
 
     RawWaker::new(core::ptr::null(), vtable)
 }
 
-/// Constructs a [`Waker`] which panics if [`wake`][Waker::wake] is called
-fn dummy_waker() -> Waker {
-    let raw_waker = dummy_raw_waker();
+/// Constructs a [`Waker`] which does nothing if [`wake`][Waker::wake] is called
+pub fn no_op_waker() -> Waker {
+    let raw_waker = no_op_raw_waker();
 
-    // SAFETY: This waker always panics if called
+    // SAFETY: None of the waker's functions use the data pointer, so they are all sound to call
     unsafe { Waker::from_raw(raw_waker) }
 }
 
-/// Polls all registered devices
+/// Polls all registered tasks, including any registered since the last call.
+///
+/// This must only be called on the BSP, as tasks can't be moved between cores.
 pub fn poll_tasks() {
-    let devices = &mut *TASKS.lock();
-    devices.retain_mut(|device| {
-        match device
-            .0
+    let tasks = &mut *TASKS.lock();
+    tasks.append(&mut NEW_TASKS.lock());
+    watchdog::record_tasks(tasks);
+
+    let mut index = 0;
+    tasks.retain_mut(|task| {
+        watchdog::polling(index);
+        index += 1;
+
+        match task
+            .future
             .as_mut()
-            .poll(&mut Context::from_waker(&dummy_waker()))
+            .poll(&mut Context::from_waker(&no_op_waker()))
         {
-            Poll::Pending => true,
+            Poll::Pending => {
+                task.state = TaskState::Pending;
+                true
+            }
             Poll::Ready(()) => false,
         }
     });
+
+    watchdog::polled();
 }
 
-/// Gets the number of tasks in [`TASKS`]
+/// Gets the number of registered tasks
 pub fn num_tasks() -> usize {
-    TASKS.lock().len()
+    without_interrupts(|| TASKS.lock().len() + NEW_TASKS.lock().len())
+}
+
+/// The state of a task listed by [`list_tasks`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// The task has been registered but not polled yet
+    New,
+    /// The task has been polled and returned [`Poll::Pending`]
+    Pending,
+}
+
+/// Information about a registered task, returned by [`list_tasks`]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    /// The name the task was registered with
+    pub name: Option<&'static str>,
+    /// Whether the task has been polled yet
+    pub state: TaskState,
+}
+
+/// Lists the registered tasks. Tasks which complete are removed, so every listed task is still running.
+pub fn list_tasks() -> Vec<TaskInfo> {
+    // `TASKS` and `NEW_TASKS` are used in the timer interrupt handler, so disable interrupts while they are locked
+    without_interrupts(|| {
+        let tasks = TASKS.lock();
+        let new_tasks = NEW_TASKS.lock();
+
+        tasks
+            .iter()
+            .chain(new_tasks.iter())
+            .map(Task::info)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll},
+    };
+
+    use super::{join_pair, no_op_waker, yield_now};
+
+    #[test_case]
+    fn test_join_handle_resolves_to_output() {
+        let (task, handle) = join_pair(async { 42 });
+        let mut task = pin!(task);
+        let mut handle = pin!(handle);
+        let waker = no_op_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(handle.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(task.as_mut().poll(&mut cx), Poll::Ready(()));
+        assert_eq!(handle.as_mut().poll(&mut cx), Poll::Ready(42));
+    }
+
+    #[test_case]
+    fn test_yield_now_is_pending_once() {
+        let mut future = pin!(yield_now());
+        let waker = no_op_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(()));
+    }
 }
@@ -0,0 +1,213 @@
+//! A watchdog which detects when tasks stop being polled, and prints which task was running.
+//!
+//! Tasks are polled from the timer interrupt handler with interrupts disabled, so a task which never returns
+//! stops the timer interrupt as well, and the kernel appears frozen. To catch this, the watchdog is driven by
+//! the PIT, which is routed to the BSP as a _non-maskable interrupt_ so that it still arrives while a task is stuck.
+//! Each NMI calls [`tick`], and [`poll_tasks`] resets the count each time it finishes.
+//! If the count reaches [`TIMEOUT_MS`] worth of ticks, the task list is printed.
+//!
+//! The task list can't be locked while a task is being polled, so [`poll_tasks`] keeps a copy of the task names
+//! for the watchdog to print. The copy is stored in a fixed-size buffer, so that recording it never allocates.
+//!
+//! The NMI can interrupt code which holds any lock, so the report is only written to outputs which can be
+//! locked without blocking, and is dropped from any which can't.
+//!
+//! [`poll_tasks`]: super::poll_tasks
+
+use core::{
+    fmt::{Display, Write},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    acpi::{InterruptActiveState, InterruptTriggerMode},
+    cpu::{
+        interrupt_controllers::{isa_irq_route, route_nmi, RouteInterruptError},
+        pit,
+    },
+    graphics::{flush, WRITER},
+    serial::{log_print, SERIAL1},
+};
+
+use super::{Task, TaskInfo, TaskState};
+
+/// How many times per second the PIT sends an NMI to the watchdog
+const WATCHDOG_HZ: u32 = 20;
+/// How long tasks can go without being polled before the watchdog prints the task list, in milliseconds
+pub const TIMEOUT_MS: usize = 5000;
+/// The number of watchdog ticks in [`TIMEOUT_MS`]
+const TIMEOUT_TICKS: usize = TIMEOUT_MS * WATCHDOG_HZ as usize / 1000;
+/// The maximum number of tasks whose names are recorded for the watchdog to print
+const MAX_RECORDED_TASKS: usize = 64;
+
+/// Whether [`start`] has started the watchdog
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// The number of watchdog ticks since [`poll_tasks`][super::poll_tasks] last finished
+static TICKS_SINCE_POLL: AtomicUsize = AtomicUsize::new(0);
+/// Whether the watchdog has already printed the task list since tasks were last polled,
+/// so that it is only printed once for each hang
+static REPORTED: AtomicBool = AtomicBool::new(false);
+/// The index in [`TASK_NAMES`] of the task being polled, or [`usize::MAX`] if no task is being polled
+static POLLING_INDEX: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// The names and states of the tasks being polled by [`poll_tasks`][super::poll_tasks]
+static TASK_NAMES: Mutex<RecordedTasks> = Mutex::new(RecordedTasks {
+    tasks: [TaskInfo {
+        name: None,
+        state: TaskState::New,
+    }; MAX_RECORDED_TASKS],
+    len: 0,
+});
+
+/// A copy of the task list which is stored without allocating
+struct RecordedTasks {
+    /// The first [`MAX_RECORDED_TASKS`] tasks in the list
+    tasks: [TaskInfo; MAX_RECORDED_TASKS],
+    /// The total number of tasks in the list, which may be more than the number recorded
+    len: usize,
+}
+
+/// An error which can occur when starting the watchdog with [`start`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogStartError {
+    /// The PIT couldn't be routed to this core as an NMI
+    Route(RouteInterruptError),
+    /// The PIT couldn't be set to [`WATCHDOG_HZ`]
+    InvalidFrequency,
+}
+
+/// Starts the PIT sending NMIs to this core, which drive the watchdog.
+///
+/// # Safety
+/// This function may only be called once, on the BSP, after the I/O APIC has been initialised.
+/// Nothing else may be using the PIT.
+pub unsafe fn start() -> Result<(), WatchdogStartError> {
+    // NMIs are always edge-triggered, so only the GSI is needed
+    let gsi = isa_irq_route(
+        pit::PIT_IRQ,
+        InterruptTriggerMode::EdgeTriggered,
+        InterruptActiveState::ActiveHigh,
+    )
+    .gsi
+    .try_into()
+    .map_err(|_| WatchdogStartError::Route(RouteInterruptError::InvalidEntry))?;
+
+    // SAFETY: The NMI handler calls `tick`, which is all the NMI is used for
+    unsafe { route_nmi(gsi) }.map_err(WatchdogStartError::Route)?;
+
+    // SAFETY: The caller guarantees that nothing else is using the PIT
+    unsafe { pit::start_periodic(WATCHDOG_HZ) }
+        .map_err(|_| WatchdogStartError::InvalidFrequency)?;
+
+    STARTED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Counts a watchdog tick, and prints the task list if tasks haven't been polled for [`TIMEOUT_MS`].
+/// This is called from the NMI handler, so it can interrupt any code, including while locks are held.
+pub fn tick() {
+    if !STARTED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let ticks = TICKS_SINCE_POLL.fetch_add(1, Ordering::Relaxed) + 1;
+    if ticks >= TIMEOUT_TICKS && !REPORTED.swap(true, Ordering::Relaxed) {
+        report(ticks);
+    }
+}
+
+/// Prints a warning with the list of tasks and which one is being polled
+fn report(ticks: usize) {
+    let milliseconds = ticks * 1000 / WATCHDOG_HZ as usize;
+
+    match TASK_NAMES.try_lock() {
+        Some(tasks) => nmi_print(format_args!(
+            "WATCHDOG: Tasks haven't been polled for {milliseconds}ms - a task may be stuck.{}\n",
+            TaskList {
+                tasks: &tasks,
+                polling: POLLING_INDEX.load(Ordering::Relaxed),
+            }
+        )),
+        None => nmi_print(format_args!(
+            "WATCHDOG: Tasks haven't been polled for {milliseconds}ms, and the task list is locked\n"
+        )),
+    }
+
+    // The screen is normally flushed by the timer interrupt, which isn't running
+    let _ = flush();
+}
+
+/// Prints to the screen and the serial ports from the NMI handler, without blocking on any locks.
+/// Outputs which are locked are skipped.
+fn nmi_print(args: core::fmt::Arguments) {
+    // `log_print` only writes to COM2 if its lock is free
+    log_print(args);
+
+    // The writer mirrors everything to COM1, blocking on its lock, so it can only be used if this core doesn't hold that lock.
+    // The NMI is only sent to the BSP, so if the lock is free now, it can only be taken by other cores,
+    // which will release it without waiting for this core.
+    if SERIAL1.is_locked() {
+        return;
+    }
+
+    if let Ok(mut writer) = WRITER.try_locked_if_init() {
+        let _ = writer.write_fmt(args);
+    }
+}
+
+/// Records the tasks which are about to be polled, so that they can be printed if one gets stuck
+pub(super) fn record_tasks(tasks: &[Task]) {
+    let mut recorded = TASK_NAMES.lock();
+    recorded.len = tasks.len();
+    for (slot, task) in recorded.tasks.iter_mut().zip(tasks) {
+        *slot = task.info();
+    }
+}
+
+/// Records that the task at `index` in the list passed to [`record_tasks`] is being polled
+pub(super) fn polling(index: usize) {
+    POLLING_INDEX.store(index, Ordering::Relaxed);
+}
+
+/// Records that all tasks have been polled, resetting the watchdog
+pub(super) fn polled() {
+    POLLING_INDEX.store(usize::MAX, Ordering::Relaxed);
+    TICKS_SINCE_POLL.store(0, Ordering::Relaxed);
+    REPORTED.store(false, Ordering::Relaxed);
+}
+
+/// Displays a list of tasks, one per line, marking the one being polled
+struct TaskList<'a> {
+    /// The tasks to list
+    tasks: &'a RecordedTasks,
+    /// The index of the task being polled, if any
+    polling: usize,
+}
+
+impl Display for TaskList<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let recorded = self.tasks.len.min(MAX_RECORDED_TASKS);
+
+        for (i, task) in self.tasks.tasks[..recorded].iter().enumerate() {
+            write!(
+                f,
+                "\n    {} ({:?})",
+                task.name.unwrap_or("<unnamed>"),
+                task.state
+            )?;
+            if i == self.polling {
+                write!(f, " <- being polled")?;
+            }
+        }
+
+        if self.tasks.len > recorded {
+            write!(f, "\n    ...and {} more", self.tasks.len - recorded)?;
+            if (recorded..self.tasks.len).contains(&self.polling) {
+                write!(f, ", including task {} which is being polled", self.polling)?;
+            }
+        }
+
+        Ok(())
+    }
+}
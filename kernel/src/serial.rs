@@ -1,25 +1,197 @@
-//! [`serial_print!`][crate::serial_print!] and [`serial_println!`][crate::serial_println!] macros for writing to serial port
+//! The [`SerialPort`] type for driving 16550 UART serial ports,
+//! [`serial_print!`][crate::serial_print!] and [`serial_println!`][crate::serial_println!] macros for writing to serial port,
+//! and an interrupt-driven buffer of bytes received from the serial port
 
+use core::fmt::Write;
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
 use lazy_static::lazy_static;
+use log::warn;
 use spin::Mutex;
-use uart_16550::SerialPort;
-use x86_64::instructions::interrupts;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The base I/O port of the COM1 serial port
+pub const COM1: u16 = 0x3F8;
+/// The base I/O port of the COM2 serial port
+pub const COM2: u16 = 0x2F8;
+/// The base I/O port of the COM3 serial port
+pub const COM3: u16 = 0x3E8;
+/// The base I/O port of the COM4 serial port
+pub const COM4: u16 = 0x2E8;
+
+/// The baud rate divisor for 115200 baud, the fastest rate a 16550 UART supports
+pub const BAUD_DIVISOR_115200: u16 = 1;
+/// The baud rate divisor for 38400 baud
+pub const BAUD_DIVISOR_38400: u16 = 3;
+
+/// A 16550 UART serial port, accessed through 8 consecutive I/O ports starting at [`base`].
+///
+/// See the [OSDev wiki] for the meanings of the registers.
+///
+/// [`base`]: SerialPort::base
+/// [OSDev wiki]: https://wiki.osdev.org/Serial_Ports
+#[derive(Debug)]
+pub struct SerialPort {
+    /// The first I/O port of the serial port, such as [`COM1`]
+    base: u16,
+}
+
+impl SerialPort {
+    /// The offset of the data register, or the low byte of the baud divisor if DLAB is set
+    const DATA: u16 = 0;
+    /// The offset of the interrupt enable register, or the high byte of the baud divisor if DLAB is set
+    const INTERRUPT_ENABLE: u16 = 1;
+    /// The offset of the FIFO control register
+    const FIFO_CONTROL: u16 = 2;
+    /// The offset of the line control register
+    const LINE_CONTROL: u16 = 3;
+    /// The offset of the modem control register
+    const MODEM_CONTROL: u16 = 4;
+    /// The offset of the line status register
+    const LINE_STATUS: u16 = 5;
+    /// The offset of the scratch register, which the UART doesn't use
+    const SCRATCH: u16 = 7;
+
+    /// Constructs a new [`SerialPort`] at the given base I/O port. The port is not initialised until [`init`] is called.
+    ///
+    /// # Safety
+    /// There must be either a 16550 UART or no device at all at the given I/O ports.
+    ///
+    /// [`init`]: SerialPort::init
+    pub const unsafe fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    /// The first I/O port of the serial port
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Gets the I/O port for the register at the given offset from [`base`]
+    ///
+    /// [`base`]: SerialPort::base
+    fn register(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Initialises the serial port with the given baud rate divisor, which divides 115200 to get the baud rate.
+    /// The port is set up for 8 data bits, no parity, and one stop bit, with all interrupts disabled.
+    pub fn init(&mut self, baud_divisor: u16) {
+        let [divisor_low, divisor_high] = baud_divisor.to_le_bytes();
+
+        // SAFETY: The caller of `new` guaranteed that these ports belong to a UART, so writing to them only configures it
+        unsafe {
+            self.register(Self::INTERRUPT_ENABLE).write(0x00);
+
+            // Set the DLAB bit to write the baud rate divisor
+            self.register(Self::LINE_CONTROL).write(0x80);
+            self.register(Self::DATA).write(divisor_low);
+            self.register(Self::INTERRUPT_ENABLE).write(divisor_high);
+
+            // 8 data bits, no parity, one stop bit, and clear DLAB
+            self.register(Self::LINE_CONTROL).write(0x03);
+            // Enable and clear the FIFOs, with a 14 byte interrupt threshold
+            self.register(Self::FIFO_CONTROL).write(0xC7);
+            // Set DTR and RTS, and OUT2 which connects the UART's interrupt line
+            self.register(Self::MODEM_CONTROL).write(0x0B);
+        }
+    }
+
+    /// Checks whether there is a UART at the port, by checking that a value written to the scratch register can be read back.
+    /// If there is no device, reads return `0xFF`.
+    pub fn is_present(&mut self) -> bool {
+        // SAFETY: The caller of `new` guaranteed that these ports belong to a UART or to nothing,
+        // and the scratch register doesn't affect the UART's behaviour
+        unsafe {
+            let mut scratch = self.register(Self::SCRATCH);
+            scratch.write(0x5A);
+            scratch.read() == 0x5A
+        }
+    }
+
+    /// Enables the _Received Data Available_ interrupt, which is raised whenever a byte is received
+    pub fn enable_receive_interrupt(&mut self) {
+        // SAFETY: The caller of `new` guaranteed that this port belongs to a UART
+        unsafe { self.register(Self::INTERRUPT_ENABLE).write(0x01) }
+    }
+
+    /// Reads the line status register
+    fn line_status(&self) -> u8 {
+        // SAFETY: Reading the line status register has no side effects
+        unsafe { self.register(Self::LINE_STATUS).read() }
+    }
+
+    /// Whether a received byte is waiting to be read
+    pub fn data_ready(&self) -> bool {
+        self.line_status() & 1 != 0
+    }
+
+    /// Writes a byte to the serial port, waiting until the transmit buffer is empty
+    pub fn write_byte(&mut self, b: u8) {
+        // Bit 5 of the line status register is set when the transmit buffer is empty
+        while self.line_status() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+
+        // SAFETY: The transmit buffer is empty, so the byte will be sent
+        unsafe { self.register(Self::DATA).write(b) }
+    }
+
+    /// Reads a byte from the serial port, if one has been received
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        // SAFETY: There is a byte waiting, so reading the data register takes it from the receive buffer
+        self.data_ready()
+            .then(|| unsafe { self.register(Self::DATA).read() })
+    }
+
+    /// Reads a byte from the serial port, waiting until one is received
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(b) = self.try_read_byte() {
+                return b;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            self.write_byte(b);
+        }
+
+        Ok(())
+    }
+}
 
 lazy_static! {
+    /// The COM1 serial port, which is written to by [`serial_print!`][crate::serial_print!]
+    /// and used for the test runner's command protocol
     pub static ref SERIAL1: Mutex<SerialPort> = {
         // SAFETY:
         // This just assumes a serial port exists on this port, which may not be the case
         // TODO: detect whether there really is a serial port
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        serial_port.init(BAUD_DIVISOR_38400);
         Mutex::new(serial_port)
     };
+
+    /// The COM2 serial port, which kernel logs are written to, or [`None`] if there is no serial port there
+    pub static ref SERIAL2: Option<Mutex<SerialPort>> = {
+        // SAFETY: Same as SERIAL1
+        let mut serial_port = unsafe { SerialPort::new(COM2) };
+        serial_port.is_present().then(|| {
+            serial_port.init(BAUD_DIVISOR_38400);
+            Mutex::new(serial_port)
+        })
+    };
 }
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    use core::fmt::Write;
-
     // Disable interrupts while locking mutex to prevent deadlocks
     interrupts::without_interrupts(|| {
         SERIAL1
@@ -29,6 +201,26 @@ pub fn _print(args: core::fmt::Arguments) {
     });
 }
 
+/// Whether there is a COM2 serial port for kernel logs to be written to
+pub fn has_log_port() -> bool {
+    SERIAL2.is_some()
+}
+
+/// Prints to [`SERIAL2`], where kernel logs are written, if it exists
+pub fn log_print(args: core::fmt::Arguments) {
+    let Some(serial2) = &*SERIAL2 else {
+        return;
+    };
+
+    // Disable interrupts while locking mutex to prevent deadlocks
+    interrupts::without_interrupts(|| {
+        // A log message which interrupted another is dropped rather than deadlocking
+        if let Some(mut port) = serial2.try_lock() {
+            let _ = port.write_fmt(args);
+        }
+    });
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -46,37 +238,102 @@ macro_rules! serial_println {
         concat!($fmt, "\n"), $($arg)*));
 }
 
-/// Reads a byte from the serial input.
+/// A buffer of bytes received on the serial port. A byte will be added to this buffer by the serial interrupt handler,
+/// and removed when it is read by [`pop_byte`].
+static INPUT_BUFFER: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+/// Initialise [`INPUT_BUFFER`] with a new heap allocated [`ArrayQueue`],
+/// and enable the serial port's _Received Data Available_ interrupt.
+pub fn init_input_buffer() {
+    INPUT_BUFFER.init_once(|| ArrayQueue::new(1024));
+
+    // Disable interrupts while locking mutex to prevent deadlocks
+    interrupts::without_interrupts(|| SERIAL1.lock().enable_receive_interrupt());
+}
+
+/// Reads all the bytes which have been received on the serial port into [`INPUT_BUFFER`].
+/// This function is called by the serial port's interrupt handler.
+pub fn receive_pending() {
+    let Ok(buffer) = INPUT_BUFFER.try_get() else {
+        warn!("Serial input buffer not initialised");
+        return;
+    };
+
+    let mut dropped = false;
+
+    // This function is called from an interrupt handler, and all other code which locks the port disables interrupts
+    // while holding the lock, so the lock is never held here.
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        while let Some(b) = serial.try_read_byte() {
+            dropped |= buffer.push(b).is_err();
+        }
+    }
+
+    // Logging may print to the serial port, so this is done after the lock is released
+    if dropped {
+        warn!("Dropped serial input");
+    }
+}
+
+/// Gets the oldest byte received on the serial port, if there are any which haven't been read yet
+pub fn pop_byte() -> Option<u8> {
+    INPUT_BUFFER.try_get().ok().and_then(ArrayQueue::pop)
+}
+
+/// Reads a byte from the serial input, waiting for at most `ticks` timer ticks.
+/// Returns [`None`] if no data is sent to the serial port in that time.
 ///
-/// This function will block if no data is sent to the serial port, so should only be called if this is guaranteed.
+/// While no data is available, the CPU is halted until the next interrupt.
+/// This function enables interrupts, as they are needed to receive data and to count ticks.
 /// This function is intended to be used to read commands from the test handler (see [`test_runner`])
 ///
 /// [`test_runner`]: crate::tests::test_runner
 #[cfg(test)]
-pub fn read() -> u8 {
-    // Disable interrupts while locking mutex to prevent deadlocks
-    interrupts::without_interrupts(|| SERIAL1.lock().receive())
+pub fn readch_timeout(ticks: usize) -> Option<u8> {
+    let start = KERNEL_STATE.ticks();
+
+    loop {
+        // Interrupts are disabled while checking the buffer, so that a byte can't be received
+        // between the check and the `hlt` instruction without waking the CPU.
+        interrupts::disable();
+
+        if let Some(b) = pop_byte() {
+            interrupts::enable();
+            return Some(b);
+        }
+
+        if KERNEL_STATE.ticks().wrapping_sub(start) >= ticks {
+            interrupts::enable();
+            return None;
+        }
+
+        interrupts::enable_and_hlt();
+    }
 }
 
+#[cfg(test)]
+use crate::global_state::KERNEL_STATE;
 #[cfg(test)]
 use alloc::{
     string::{String, ToString},
     vec::Vec,
 };
 
-/// Reads a line from the serial input.
+/// Reads a line from the serial input, waiting for at most `ticks` timer ticks for the whole line.
+/// Returns [`None`] if the line isn't finished in that time.
 ///
-/// This function will block if no data is sent to the serial port, so should only be called if this is guaranteed.
 /// This function is intended to be used to read commands from the test handler (see [`test_runner`])
 ///
 /// [`test_runner`]: crate::tests::test_runner
 #[cfg(test)]
-pub fn readln() -> String {
+pub fn readln_timeout(ticks: usize) -> Option<String> {
+    let start = KERNEL_STATE.ticks();
     let mut s = Vec::new();
 
     loop {
-        // Disable interrupts while locking mutex to prevent deadlocks
-        let b = read();
+        // Bytes which have already been received can still be read once the time is up
+        let elapsed = KERNEL_STATE.ticks().wrapping_sub(start);
+        let b = readch_timeout(ticks.saturating_sub(elapsed))?;
 
         if b == b'\n' {
             break;
@@ -85,5 +342,5 @@ pub fn readln() -> String {
         }
     }
 
-    String::from_utf8_lossy(&s).to_string()
+    Some(String::from_utf8_lossy(&s).to_string())
 }
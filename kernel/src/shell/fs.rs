@@ -0,0 +1,155 @@
+//! The `ls`, `write`, and `append` commands and the block device support of the `cat` command, which read and write files on FAT filesystems
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+};
+
+use crate::{
+    block::{block_devices, BlockDevice},
+    fs::fat::{FatError, FatFilesystem},
+    println,
+    scheduler::Task,
+};
+
+/// Splits a path of the form `blk<n>:<path>` into the index of the block device and the path on it
+pub fn split_device_path(path: &str) -> Option<(usize, &str)> {
+    let (device, path) = path.strip_prefix("blk")?.split_once(':')?;
+    Some((device.parse().ok()?, path))
+}
+
+/// Gets the block device with the given index, printing a message if there isn't one
+fn get_device(index: usize) -> Option<Arc<dyn BlockDevice>> {
+    let device = block_devices().get(index).cloned();
+    if device.is_none() {
+        println!("No block device blk{index}");
+    }
+    device
+}
+
+/// Finds the FAT filesystem on the given device, printing a message if there isn't one
+async fn open_filesystem(index: usize, device: Arc<dyn BlockDevice>) -> Option<FatFilesystem> {
+    match FatFilesystem::new(device).await {
+        Ok(fs) => Some(fs),
+        Err(FatError::NoFilesystem) => {
+            println!("No FAT32 filesystem on blk{index}");
+            None
+        }
+        Err(e) => {
+            println!("Error opening filesystem on blk{index}: {e:?}");
+            None
+        }
+    }
+}
+
+/// The `ls` command - lists the contents of a directory on a block device
+pub fn ls(args: &[&str]) {
+    let Some((index, path)) = args.first().and_then(|arg| split_device_path(arg)) else {
+        println!("Provide a directory in the form blk<n>:<path>");
+        return;
+    };
+    let Some(device) = get_device(index) else {
+        return;
+    };
+    let path = path.to_string();
+
+    // Reading from the device is asynchronous, so it's done in a task rather than in the shell's loop
+    Task::register_detached(Some("ls"), async move {
+        let Some(fs) = open_filesystem(index, device).await else {
+            return;
+        };
+
+        match fs.read_dir(&path).await {
+            Ok(Some(entries)) => {
+                for entry in entries {
+                    if entry.is_dir() {
+                        println!("{:>10}  {}/", "", entry.name);
+                    } else {
+                        println!("{:>10}  {}", entry.size, entry.name);
+                    }
+                }
+            }
+            Ok(None) => println!("No such directory {path}"),
+            Err(e) => println!("Error reading {path}: {e:?}"),
+        }
+    });
+}
+
+/// Prints the contents of the file at `path` on the block device with the given index
+pub fn cat(index: usize, path: &str) {
+    let Some(device) = get_device(index) else {
+        return;
+    };
+    let path = path.to_string();
+
+    // Reading from the device is asynchronous, so it's done in a task rather than in the shell's loop
+    Task::register_detached(Some("cat"), async move {
+        let Some(fs) = open_filesystem(index, device).await else {
+            return;
+        };
+
+        let file = match fs.open(&path).await {
+            Ok(Some(file)) => file,
+            Ok(None) => {
+                println!("No such file {path}");
+                return;
+            }
+            Err(e) => {
+                println!("Error opening {path}: {e:?}");
+                return;
+            }
+        };
+
+        match file.read_to_end().await {
+            Ok(data) => println!("{}", String::from_utf8_lossy(&data)),
+            Err(e) => println!("Error reading {path}: {e:?}"),
+        }
+    });
+}
+
+/// How [`write`] changes a file's contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Replace the file's contents
+    Truncate,
+    /// Add to the end of the file
+    Append,
+}
+
+/// Writes `contents` to the file at `path` on the block device with the given index, creating the file if it doesn't exist
+pub fn write(index: usize, path: &str, contents: String, mode: WriteMode) {
+    let Some(device) = get_device(index) else {
+        return;
+    };
+    let path = path.to_string();
+
+    // Writing to the device is asynchronous, so it's done in a task rather than in the shell's loop
+    Task::register_detached(Some("write"), async move {
+        let Some(fs) = open_filesystem(index, device).await else {
+            return;
+        };
+
+        let file = match fs.open(&path).await {
+            Ok(Some(file)) => Ok(file),
+            Ok(None) => fs.create(&path).await,
+            Err(e) => Err(e),
+        };
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                println!("Error opening {path}: {e:?}");
+                return;
+            }
+        };
+
+        let result = match mode {
+            WriteMode::Truncate => file.write_all(contents.as_bytes()).await,
+            WriteMode::Append => file.append(contents.as_bytes()).await,
+        };
+
+        match result {
+            Ok(()) => println!("Wrote {} bytes to {path}", contents.len()),
+            Err(e) => println!("Error writing {path}: {e:?}"),
+        }
+    });
+}
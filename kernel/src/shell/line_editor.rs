@@ -0,0 +1,338 @@
+//! The [`LineEditor`] type, which handles editing a line of input for the shell
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{graphics::WRITER, print, println, serial_print};
+
+/// A line editor which reads keypresses and draws the line being edited to the screen.
+///
+/// The editor supports:
+/// * Backspace and Delete to remove characters before and after the cursor
+/// * Left / Right and Home / End to move the cursor
+/// * Up / Down to cycle through previously entered lines
+/// * Tab to complete the first word of the line, or list the possible completions if Tab is pressed twice
+///
+/// The line is drawn to the screen as it is edited. If [`set_serial_echo`] is enabled,
+/// it is also drawn over the serial port, so that the shell can be used from a terminal connected to it.
+///
+/// [`set_serial_echo`]: LineEditor::set_serial_echo
+#[derive(Debug)]
+pub struct LineEditor {
+    /// The prompt printed before each line
+    prompt: &'static str,
+    /// A function which returns all the possible completions of the first word of the line
+    complete: fn(&str) -> Vec<&'static str>,
+    /// Whether the last key pressed was Tab, so that a second Tab press lists the possible completions
+    last_key_was_tab: bool,
+
+    /// The characters of the line being edited
+    line: Vec<char>,
+    /// The index in [`line`][Self::line] where characters will be inserted
+    cursor: usize,
+
+    /// Previously entered lines, with the most recent at the back
+    history: VecDeque<String>,
+    /// The maximum number of lines stored in [`history`][Self::history]
+    max_history: usize,
+    /// The index into [`history`][Self::history] of the line currently being shown,
+    /// or [`None`] if a new line is being edited
+    history_index: Option<usize>,
+    /// The line which was being edited before the user started cycling through [`history`][Self::history],
+    /// so that it can be restored when they go past the most recent line.
+    saved_line: Vec<char>,
+
+    /// The `(row, column)` position on the screen where the line starts
+    start: (usize, usize),
+    /// How many characters were drawn the last time the line was drawn,
+    /// so that any left over characters can be cleared if the line gets shorter.
+    drawn_len: usize,
+    /// Whether the line is also drawn over the serial port while it's being edited
+    serial_echo: bool,
+}
+
+impl LineEditor {
+    /// Constructs a new [`LineEditor`].
+    ///
+    /// # Parameters
+    /// * `prompt`: The prompt to print before each line
+    /// * `max_history`: The maximum number of previous lines to keep
+    /// * `complete`: A function which returns all the possible completions of a partially typed first word
+    pub fn new(
+        prompt: &'static str,
+        max_history: usize,
+        complete: fn(&str) -> Vec<&'static str>,
+    ) -> Self {
+        Self {
+            prompt,
+            complete,
+            last_key_was_tab: false,
+
+            line: Vec::new(),
+            cursor: 0,
+
+            history: VecDeque::with_capacity(max_history),
+            max_history,
+            history_index: None,
+            saved_line: Vec::new(),
+
+            start: (0, 0),
+            drawn_len: 0,
+            serial_echo: false,
+        }
+    }
+
+    /// Sets whether the line is drawn over the serial port while it's being edited.
+    /// This should be enabled when input is received from the serial port,
+    /// as terminals don't show what is typed until the kernel echoes it back.
+    pub fn set_serial_echo(&mut self, serial_echo: bool) {
+        self.serial_echo = serial_echo;
+    }
+
+    /// Prints the prompt and starts editing a new line
+    pub fn start_line(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.history_index = None;
+        self.saved_line.clear();
+        self.last_key_was_tab = false;
+
+        self.print_prompt();
+    }
+
+    /// Prints the prompt and records where the line starts on the screen
+    fn print_prompt(&mut self) {
+        print!("{}", self.prompt);
+
+        self.drawn_len = 0;
+
+        if let Ok(writer) = WRITER.try_locked_if_init() {
+            self.start = writer.position();
+        }
+    }
+
+    /// Processes a keypress. If the key ends the line (i.e. Enter was pressed),
+    /// the line is added to the history and returned.
+    pub fn handle_key(&mut self, key: DecodedKey) -> Option<String> {
+        let last_key_was_tab = core::mem::replace(&mut self.last_key_was_tab, false);
+
+        match key {
+            DecodedKey::Unicode('\n') => return Some(self.finish_line()),
+            // Backspace
+            DecodedKey::Unicode('\u{8}') => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                }
+            }
+            // Delete
+            DecodedKey::Unicode('\u{7f}') => {
+                if self.cursor < self.line.len() {
+                    self.line.remove(self.cursor);
+                }
+            }
+            DecodedKey::Unicode('\t') => {
+                self.last_key_was_tab = true;
+                self.complete(last_key_was_tab);
+            }
+            DecodedKey::Unicode(c) if !c.is_control() => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            DecodedKey::Unicode(_) => return None,
+
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.cursor = self.cursor.saturating_sub(1),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                self.cursor = (self.cursor + 1).min(self.line.len());
+            }
+            DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+            DecodedKey::RawKey(KeyCode::End) => self.cursor = self.line.len(),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_previous(),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(),
+            DecodedKey::RawKey(_) => return None,
+        }
+
+        self.redraw();
+        None
+    }
+
+    /// Gets the line currently being edited
+    fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    /// Completes the first word of the line, if the cursor is in it.
+    /// The word is extended to the longest prefix shared by all possible completions.
+    /// If this doesn't extend the word and `list` is `true`, all the possible completions are printed.
+    fn complete(&mut self, list: bool) {
+        let before_cursor: String = self.line[..self.cursor].iter().collect();
+        let prefix = before_cursor.trim_start();
+
+        // Only the first word is completed
+        if prefix.contains(char::is_whitespace) {
+            return;
+        }
+
+        let candidates = (self.complete)(prefix);
+
+        let Some((first, rest)) = candidates.split_first() else {
+            return;
+        };
+
+        // Find the longest prefix shared by all the candidates
+        let common_len = rest.iter().fold(first.len(), |len, candidate| {
+            first
+                .bytes()
+                .zip(candidate.bytes())
+                .take(len)
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+
+        if common_len > prefix.len() {
+            for c in first[prefix.len()..common_len].chars() {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+
+            if rest.is_empty() {
+                self.line.insert(self.cursor, ' ');
+                self.cursor += 1;
+            }
+        } else if list && !rest.is_empty() {
+            // Move to the end of the line before printing the candidates so that the line isn't overwritten
+            let cursor = self.cursor;
+            self.cursor = self.line.len();
+            self.redraw();
+            self.cursor = cursor;
+
+            println!();
+            for candidate in &candidates {
+                print!("{candidate}  ");
+            }
+            println!();
+
+            self.print_prompt();
+        }
+    }
+
+    /// Shows the previous line in the history
+    fn history_previous(&mut self) {
+        let index = match self.history_index {
+            None if self.history.is_empty() => return,
+            None => {
+                self.saved_line = core::mem::take(&mut self.line);
+                self.history.len() - 1
+            }
+            Some(0) => return,
+            Some(i) => i - 1,
+        };
+
+        self.history_index = Some(index);
+        self.line = self.history[index].chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    /// Shows the next line in the history, or the line which was being edited if the end of the history is reached
+    fn history_next(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.line = self.history[index + 1].chars().collect();
+        } else {
+            self.history_index = None;
+            self.line = core::mem::take(&mut self.saved_line);
+        }
+
+        self.cursor = self.line.len();
+    }
+
+    /// Ends the current line, moving the screen's cursor to the next line and adding the line to the history
+    fn finish_line(&mut self) -> String {
+        self.cursor = self.line.len();
+        self.redraw();
+
+        let line = self.line();
+
+        // The line is only drawn to the screen while it's being edited, so echo the final version to the serial port
+        // if it hasn't already been drawn there
+        if !self.serial_echo {
+            serial_print!("{line}");
+        }
+        println!();
+
+        // Don't store empty lines or repeats of the previous line
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == self.max_history {
+                self.history.pop_front();
+            }
+
+            self.history.push_back(line.clone());
+        }
+
+        line
+    }
+
+    /// Draws the line to the screen, clearing any characters left over from when it was last drawn,
+    /// and leaves the screen's cursor at the position of [`cursor`][Self::cursor].
+    fn redraw(&mut self) {
+        without_interrupts(|| {
+            let Ok(mut writer) = WRITER.try_locked_if_init() else {
+                return;
+            };
+
+            let (start_row, start_column) = self.start;
+            writer.set_position(start_row, start_column);
+
+            let mut buf = [0; 4];
+            let scrolled_before = writer.lines_scrolled();
+
+            for c in &self.line[..self.cursor] {
+                writer.draw_str(c.encode_utf8(&mut buf));
+            }
+
+            let (cursor_row, cursor_column) = writer.position();
+            let scrolled_at_cursor = writer.lines_scrolled();
+
+            for c in &self.line[self.cursor..] {
+                writer.draw_str(c.encode_utf8(&mut buf));
+            }
+
+            // Overwrite any characters left over from a longer line
+            for _ in self.line.len()..self.drawn_len {
+                writer.draw_str(" ");
+            }
+
+            let scrolled_after = writer.lines_scrolled();
+
+            // If drawing the line scrolled the screen, the line and the cursor will have moved up
+            self.start.0 = start_row.saturating_sub(scrolled_after - scrolled_before);
+            writer.set_position(
+                cursor_row.saturating_sub(scrolled_after - scrolled_at_cursor),
+                cursor_column,
+            );
+
+            self.drawn_len = self.line.len();
+        });
+
+        if self.serial_echo {
+            self.redraw_serial();
+        }
+    }
+
+    /// Draws the line over the serial port using ANSI escape codes,
+    /// leaving the terminal's cursor at the position of [`cursor`][Self::cursor].
+    fn redraw_serial(&self) {
+        // Go back to the start of the line, draw it, and clear any characters left over from a longer line
+        serial_print!("\r{}{}\x1b[K", self.prompt, self.line());
+
+        let after_cursor = self.line.len() - self.cursor;
+        if after_cursor > 0 {
+            serial_print!("\x1b[{after_cursor}D");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! The `memtest` shell command, which stress-tests the kernel heap and frame allocator
+
+use alloc::vec::Vec;
+
+use crate::{
+    allocator::{heap_stats, reset_peak_heap_usage, PageBox},
+    println,
+    scheduler::{yield_now, Task},
+};
+
+/// The number of iterations to run if none is given
+const DEFAULT_ITERATIONS: u64 = 100;
+/// The maximum number of [`Vec`]s allocated in each iteration
+const MAX_VECS: u64 = 32;
+/// The maximum length of each [`Vec`] when it is first allocated
+const MAX_VEC_LEN: u64 = 4096;
+/// The maximum number of [`PageBox`]es allocated in each iteration
+const MAX_PAGES: u64 = 8;
+
+/// A xorshift pseudo-random number generator.
+/// This isn't suitable for anything other than generating test data.
+struct XorShift(u64);
+
+impl XorShift {
+    /// Gets the next number in the sequence
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Gets a number in the range `1..=max`
+    fn between_one_and(&mut self, max: u64) -> u64 {
+        self.next() % max + 1
+    }
+}
+
+/// The byte written at `index` in the allocation with the given `seed`.
+/// Each allocation has a different seed, so data written to the wrong allocation is also detected.
+fn pattern(seed: u64, index: usize) -> u8 {
+    (seed ^ (index as u64).wrapping_mul(0x9E37_79B9)) as u8
+}
+
+/// Checks that the data in an allocation matches the pattern it was filled with, panicking if it doesn't
+fn verify(kind: &str, seed: u64, data: &[u8]) {
+    for (i, &byte) in data.iter().enumerate() {
+        let expected = pattern(seed, i);
+        assert_eq!(
+            byte,
+            expected,
+            "memtest: {kind} at {:p} was corrupted at offset {i:#x}",
+            data.as_ptr()
+        );
+    }
+}
+
+/// The `memtest` command - runs a task which repeatedly allocates, fills, verifies, and frees memory
+pub fn memtest(args: &[&str]) {
+    let iterations = match args.first().map(|n| n.parse()) {
+        None => DEFAULT_ITERATIONS,
+        Some(Ok(iterations)) => iterations,
+        Some(Err(_)) => {
+            println!("First argument must be a number of iterations");
+            return;
+        }
+    };
+
+    println!("Running memtest for {iterations} iterations in the background");
+    Task::register_detached(Some("memtest"), run(iterations));
+}
+
+/// Runs the memory test for the given number of iterations, yielding to the scheduler between each one
+async fn run(iterations: u64) {
+    let start = heap_stats();
+    reset_peak_heap_usage();
+
+    let mut rng = XorShift(0x2545_F491_4F6C_DD1D ^ start.allocated as u64);
+    let mut max_pages = 0;
+
+    for _ in 0..iterations {
+        let mut vecs: Vec<(u64, Vec<u8>)> = Vec::new();
+        let mut pages: Vec<(u64, PageBox)> = Vec::new();
+
+        for _ in 0..rng.between_one_and(MAX_VECS) {
+            let seed = rng.next();
+            let len = rng.between_one_and(MAX_VEC_LEN) as usize;
+            let vec = (0..len).map(|i| pattern(seed, i)).collect();
+            vecs.push((seed, vec));
+        }
+
+        for _ in 0..rng.between_one_and(MAX_PAGES) {
+            let seed = rng.next();
+            let mut page = PageBox::new();
+
+            // SAFETY: The page is 4096 bytes long, and is owned by `page` so nothing else is accessing it
+            let data = unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr::<u8>(), 0x1000) };
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = pattern(seed, i);
+            }
+
+            pages.push((seed, page));
+        }
+        max_pages = max_pages.max(pages.len());
+
+        // Grow some of the vecs, which reallocates them and copies their contents
+        for (seed, vec) in vecs.iter_mut() {
+            if rng.next() % 2 == 0 {
+                let extra = rng.between_one_and(MAX_VEC_LEN) as usize;
+                let len = vec.len();
+                vec.extend((len..len + extra).map(|i| pattern(*seed, i)));
+            }
+        }
+
+        for (seed, vec) in &vecs {
+            verify("Vec", *seed, vec);
+        }
+
+        for (seed, page) in &pages {
+            // SAFETY: The page is 4096 bytes long, and is owned by `page` so nothing else is accessing it
+            let data = unsafe { core::slice::from_raw_parts(page.as_ptr::<u8>(), 0x1000) };
+            verify("PageBox", *seed, data);
+        }
+
+        drop(vecs);
+        drop(pages);
+
+        yield_now().await;
+    }
+
+    let end = heap_stats();
+    println!("memtest: {iterations} iterations passed");
+    println!(
+        "memtest: peak heap usage {} bytes ({} bytes before starting), at most {max_pages} pages held at once",
+        end.peak, start.allocated
+    );
+}
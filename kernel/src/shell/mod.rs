@@ -0,0 +1,665 @@
+//! The kernel's debug shell, which reads commands from keyboard input and runs them
+
+mod fs;
+mod line_editor;
+mod memtest;
+mod serial_input;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::Ordering;
+
+use crate::{
+    acpi::hpet,
+    block::blkls,
+    cpu::{
+        features::cpu_features,
+        interrupt_controllers::{send_debug_self_interrupt, APIC_EOI, PIC_EOI},
+        interrupt_counts,
+        pat::MemoryType,
+        print_translation,
+        smp::online_cpus,
+        spurious_interrupt_count, BootInfoFrameAllocator,
+    },
+    exec::LoadedProgram,
+    global_state::KERNEL_STATE,
+    graphics::{
+        clear, draw_test_pattern, restore_framebuffer_memory_type, saved_framebuffer_memory_type,
+        set_framebuffer_memory_type, time_scrolls, SetScaleError, MAX_SCALE, WRITER,
+    },
+    initrd,
+    input::{clear_interrupt, pop_key, print_queue_stats},
+    pci::{lspci, usbls},
+    print, println,
+    scheduler::{list_tasks, num_tasks, TaskState},
+    serial,
+    shutdown::shutdown,
+    symbols,
+    util::{bytes::Bytes, hms::Hms},
+};
+
+use pc_keyboard::DecodedKey;
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+use self::{line_editor::LineEditor, memtest::memtest, serial_input::SerialKeyDecoder};
+
+/// The maximum number of lines kept in the shell's history
+const HISTORY_LENGTH: usize = 50;
+
+/// A command which the shell can run
+struct Command {
+    /// The name used to invoke the command
+    name: &'static str,
+    /// A one-line description of what the command does, printed by `help`
+    description: &'static str,
+    /// A longer description of the command's arguments, printed by `help <command>`
+    usage: &'static str,
+    /// The function which runs the command, taking the command's arguments
+    run: fn(&[&str]),
+}
+
+/// The commands which the shell can run.
+/// Dispatching commands, tab completion, and `help` all use this table, so new commands only need to be added here.
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        description: "Lists the available commands, or describes a command in detail",
+        usage: "help [command]\n\
+            With no arguments, lists all commands with a short description.\n\
+            With a command name, prints how to use that command.",
+        run: help,
+    },
+    Command {
+        name: "echo",
+        description: "Prints its arguments",
+        usage: "echo [args...]\n\
+            Prints each argument separated by a space.",
+        run: echo,
+    },
+    Command {
+        name: "lspci",
+        description: "Lists the system's PCI devices",
+        usage: "lspci [-v|-vv] [-t] [-c <class>] [-d <vendor>:<device>] [-s [<segment>:]<bus>:<device>.<function>]\n\
+            Prints the address, vendor and device IDs, and class code of each PCI function.\n\
+            -v: also print where each function's registers are mapped and its capabilities\n\
+            -vv: as -v, but also print each function's PCI Express extended capabilities\n\
+            -t: print functions as a tree following PCI bridges\n\
+            -c <class>: only print functions with the given class code (hex)\n\
+            -d <vendor>:<device>: only print functions with the given IDs (hex, either may be empty)\n\
+            -s <address>: print everything about the function at the given address (hex),\n\
+            including its header, BARs, capabilities, and a summary of its registers if its driver supports it\n\
+            (with -v, the registers are printed in more detail)",
+        run: lspci,
+    },
+    Command {
+        name: "usbls",
+        description: "Lists the enumerated USB devices",
+        usage: "usbls\n\
+            Prints the controller, port, slot, speed, vendor and product IDs, class code,\n\
+            and manufacturer and product strings of each enumerated USB device.\n\
+            The port is the root hub port followed by the port on each hub, e.g. `3.1`.",
+        run: usbls,
+    },
+    Command {
+        name: "blkls",
+        description: "Lists the block devices",
+        usage: "blkls\n\
+            Prints the number and size of blocks, and total size, of each block device.",
+        run: blkls,
+    },
+    Command {
+        name: "ls",
+        description: "Lists a directory on a block device",
+        usage: "ls blk<n>:<path>\n\
+            Lists the files and directories in the given directory of the FAT32 filesystem on block device n.\n\
+            Directories are printed with a trailing `/`, and files with their size in bytes.",
+        run: fs::ls,
+    },
+    Command {
+        name: "cat",
+        description: "Prints a file from the initrd or a block device",
+        usage: "cat <path>\n\
+            Prints the contents of the file at the given path in the initrd.\n\
+            If the path is of the form blk<n>:<path>, the file is read from the FAT32 filesystem on block device n instead.\n\
+            Bytes which aren't valid UTF-8 are replaced with \u{FFFD}.",
+        run: cat,
+    },
+    Command {
+        name: "write",
+        description: "Writes text to a file on a block device",
+        usage: "write blk<n>:<path> <text>\n\
+            Replaces the contents of the file at the given path on the FAT32 filesystem on block device n with the text.\n\
+            If the file doesn't exist, it is created. New files' names must fit in an 8.3 short name.",
+        run: write,
+    },
+    Command {
+        name: "append",
+        description: "Appends text to a file on a block device",
+        usage: "append blk<n>:<path> <text>\n\
+            Adds the text and a newline to the end of the file at the given path on the FAT32 filesystem on block device n.\n\
+            If the file doesn't exist, it is created. New files' names must fit in an 8.3 short name.",
+        run: append,
+    },
+    Command {
+        name: "run",
+        description: "Runs a program from the initrd",
+        usage: "run <path>\n\
+            Loads the static x86_64 ELF executable at the given path in the initrd, runs it, and prints its exit code.\n\
+            Programs run in ring 3, and can print text and exit using syscalls.",
+        run: run,
+    },
+    Command {
+        name: "poweroff",
+        description: "Powers off the computer",
+        usage: "poweroff\n\
+            Halts USB controllers, disables PS/2 ports, and flushes the screen,\n\
+            then powers off the computer using ACPI.",
+        run: poweroff,
+    },
+    Command {
+        name: "clear",
+        description: "Clears the screen",
+        usage: "clear\n\
+            Clears the screen and moves the cursor to the top.",
+        run: |_| clear(),
+    },
+    Command {
+        name: "draw",
+        description: "Draws a test pattern",
+        usage: "draw\n\
+            Draws coloured squares, a rectangle, and lines in the top right of the screen\n\
+            to check that the graphics drawing primitives work.",
+        run: |_| {
+            if draw_test_pattern().is_err() {
+                println!("The screen is too small to draw the test pattern");
+            }
+        },
+    },
+    Command {
+        name: "fbbench",
+        description: "Measures how fast the screen scrolls",
+        usage: "fbbench [scrolls]\n\
+            Times scrolling and flushing the whole screen with the framebuffer mapped as uncacheable,\n\
+            and then as write-combining, which is how it is normally mapped if the CPU supports it.\n\
+            The framebuffer's original memory type is restored afterwards, and the screen is cleared before the results are printed.\n\
+            Scrolls 100 times by default.",
+        run: fbbench,
+    },
+    Command {
+        name: "fontscale",
+        description: "Changes the size of text on the screen",
+        usage: "fontscale <n>\n\
+            Draws text n times larger, from 1 up to 4. The screen is cleared, because existing text can't be resized.",
+        run: font_scale,
+    },
+    Command {
+        name: "kinfo",
+        description: "Prints info about the kernel's state",
+        usage: "kinfo <schedule|uptime|acpi|cpu|cpus|mem|input|interrupts|pagetable <addr>>\n\
+            schedule: print the number of timer ticks, the tick rate, and the number of tasks\n\
+            uptime: print the time since the kernel started as HH:MM:SS\n\
+            acpi: print the MADT, FADT, DSDT, and MCFG ACPI tables\n\
+            cpu: print the CPU's vendor and the features detected using CPUID\n\
+            cpus: print the local APIC ID of each online core\n\
+            mem: print the total, usable, and reserved physical memory, and the memory of each kind in the memory map\n\
+            input: print the number of queued and dropped keyboard and mouse events\n\
+            interrupts: print how many times each vector without a dedicated handler has been received,\n\
+            the number of spurious interrupts, and the number of EOIs sent\n\
+            pagetable <addr>: print the page table entries used to translate the given virtual address (hex),\n\
+            and the physical address it is mapped to",
+        run: kinfo,
+    },
+    Command {
+        name: "sym",
+        description: "Looks up the kernel function containing an address",
+        usage: "sym <addr>\n\
+            Prints the name of the kernel function containing the given virtual address (hex), and the offset into it.\n\
+            This needs the kernel's symbols, which are only in the initrd in debug builds.",
+        run: sym,
+    },
+    Command {
+        name: "ps",
+        description: "Lists the scheduler's tasks",
+        usage: "ps\n\
+            Lists the name of each task registered with the scheduler, and whether it has been polled yet.",
+        run: ps,
+    },
+    Command {
+        name: "memtest",
+        description: "Stress-tests the memory allocators",
+        usage: "memtest [iterations]\n\
+            Repeatedly allocates, fills, checks, and frees heap memory and pages in a background task,\n\
+            then prints the peak heap usage. Panics if any memory is corrupted. Runs 100 iterations by default.",
+        run: memtest,
+    },
+    Command {
+        name: "interrupt",
+        description: "Sends an interrupt to the current core (for debugging)",
+        usage: "interrupt <vector>\n\
+            Sends an interrupt on the given vector to the current core using the local APIC.\n\
+            This is for debugging only and is not sound.",
+        // SAFETY: This command is only run when the user asks for it, and its usage says that it isn't sound
+        run: |args| unsafe { debug_interrupt(args) },
+    },
+    Command {
+        name: "panic",
+        description: "Panics the kernel",
+        usage: "panic\n\
+            Causes a kernel panic, which prints a backtrace in debug builds.",
+        run: |_| panic!("User-instructed panic"),
+    },
+];
+
+/// Loops while receiving commands from keyboard input, or from a terminal connected to the serial port
+pub fn shell_loop() -> ! {
+    let mut editor = LineEditor::new(">", HISTORY_LENGTH, complete_command);
+    let mut serial_decoder = SerialKeyDecoder::new();
+
+    without_interrupts(|| {
+        if let Ok(mut writer) = WRITER.try_locked_if_init() {
+            writer.set_cursor_visible(true);
+        }
+    });
+
+    editor.start_line();
+
+    loop {
+        x86_64::instructions::hlt();
+
+        while let Some(key) = pop_key() {
+            handle_key(&mut editor, key);
+        }
+
+        while let Some(byte) = serial::pop_byte() {
+            // Echo the line back once someone is typing over the serial port
+            editor.set_serial_echo(true);
+
+            if let Some(key) = serial_decoder.decode(byte) {
+                handle_key(&mut editor, key);
+            }
+        }
+    }
+}
+
+/// Passes a keypress to the line editor, running the line as a command if it's finished
+fn handle_key(editor: &mut LineEditor, key: DecodedKey) {
+    if let Some(line) = editor.handle_key(key) {
+        run_command(&line);
+        editor.start_line();
+    }
+}
+
+/// Parses a line of input and runs the command it specifies
+fn run_command(line: &str) {
+    let commands: Vec<_> = line.split_whitespace().filter(|a| !a.is_empty()).collect();
+
+    if let Some(c) = commands.first() {
+        match find_command(c) {
+            Some(command) => {
+                // Only Ctrl+C presses while the command is running should stop it
+                clear_interrupt();
+                (command.run)(&commands[1..]);
+
+                if clear_interrupt() {
+                    println!("^C");
+                }
+            }
+            None => println!("Unknown command {c} - run `help` to list commands"),
+        }
+    }
+}
+
+/// Finds the command with the given name in [`COMMANDS`]
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Gets the names of all commands which start with `prefix`
+fn complete_command(prefix: &str) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .map(|command| command.name)
+        .filter(|name| name.starts_with(prefix))
+        .collect()
+}
+
+/// The `help` command - lists the available commands, or prints the usage of a specific command
+fn help(args: &[&str]) {
+    match args.first() {
+        None => {
+            let name_width = COMMANDS
+                .iter()
+                .map(|command| command.name.len())
+                .max()
+                .unwrap_or(0);
+
+            for command in COMMANDS {
+                println!("{:name_width$}  {}", command.name, command.description);
+            }
+        }
+        Some(name) => match find_command(name) {
+            Some(command) => {
+                println!("{} - {}", command.name, command.description);
+                println!("Usage: {}", command.usage);
+            }
+            None => println!("Unknown command {name}"),
+        },
+    }
+}
+
+/// The `echo` command - prints its arguments separated by a space
+fn echo(args: &[&str]) {
+    for arg in args {
+        print!("{arg} ");
+    }
+    println!();
+}
+
+/// The `cat` command - prints the contents of a file in the initrd or on a block device
+fn cat(args: &[&str]) {
+    let Some(path) = args.first() else {
+        println!("Provide the path of a file to print");
+        return;
+    };
+
+    if let Some((device, path)) = fs::split_device_path(path) {
+        fs::cat(device, path);
+        return;
+    }
+
+    match initrd::open(path) {
+        Some(data) => println!("{}", String::from_utf8_lossy(data)),
+        None => println!("No such file {path}"),
+    }
+}
+
+/// Writes text to a file on a block device, replacing its contents
+fn write(args: &[&str]) {
+    write_with_mode(args, fs::WriteMode::Truncate);
+}
+
+/// Appends a line of text to a file on a block device
+fn append(args: &[&str]) {
+    write_with_mode(args, fs::WriteMode::Append);
+}
+
+/// Parses the arguments of the `write` and `append` commands, and writes the text to the file
+fn write_with_mode(args: &[&str], mode: fs::WriteMode) {
+    let Some((device, path)) = args.first().and_then(|arg| fs::split_device_path(arg)) else {
+        println!("Provide a file in the form blk<n>:<path>");
+        return;
+    };
+
+    let mut text = args[1..].join(" ");
+    if mode == fs::WriteMode::Append {
+        text.push('\n');
+    }
+
+    fs::write(device, path, text, mode);
+}
+
+/// The `run` command - loads a program from the initrd and runs it
+fn run(args: &[&str]) {
+    let Some(path) = args.first() else {
+        println!("Provide the path of a program to run");
+        return;
+    };
+
+    let Some(data) = initrd::open(path) else {
+        println!("No such file {path}");
+        return;
+    };
+
+    match LoadedProgram::load(data) {
+        Ok(program) => {
+            // SAFETY: The shell runs on the BSP, and programs can't run other programs
+            let exit_code = unsafe { program.run() };
+            println!("{path} exited with code {exit_code}");
+        }
+        Err(e) => println!("Error loading {path}: {e:?}"),
+    }
+}
+
+/// The `sym` command - prints the name of the kernel function containing an address
+fn sym(args: &[&str]) {
+    let Some(addr) = args.first() else {
+        println!("Provide an address to look up");
+        return;
+    };
+
+    let Ok(addr) = u64::from_str_radix(addr.trim_start_matches("0x"), 16) else {
+        println!("Invalid address '{addr}'");
+        return;
+    };
+
+    if !symbols::is_loaded() {
+        println!("Kernel symbols are not loaded - they are only available in debug builds");
+        return;
+    }
+
+    match symbols::resolve(addr) {
+        Some((name, offset)) => println!(
+            "{addr:#x} is {:#}+{offset:#x}",
+            rustc_demangle::demangle(name)
+        ),
+        None => println!("{addr:#x} is not in any kernel function"),
+    }
+}
+
+/// Prints info about the kernel's state
+fn kinfo(args: &[&str]) {
+    match args.first().copied() {
+        Some("schedule") => {
+            let ticks = KERNEL_STATE.ticks();
+            println!(
+                "Kernel ticks: {ticks} ({:.2}s)",
+                KERNEL_STATE.ticks_to_duration(ticks).as_secs_f64()
+            );
+            println!("Tick rate: {}Hz", KERNEL_STATE.tick_hz());
+            println!("Registered tasks: {}", num_tasks());
+        }
+
+        Some("acpi") => {
+            let acpica = KERNEL_STATE.acpica.lock();
+
+            println!("MADT: {:?}", acpica.madt());
+            println!("FADT: {:?}", acpica.fadt());
+            println!("DSDT: {:?}", acpica.dsdt());
+
+            if let Some(mcfg) = acpica.mcfg() {
+                println!("MCFG: {:?}", acpica.mcfg());
+                for record in mcfg.records() {
+                    println!("    Record: {record:?}");
+                }
+            }
+        }
+
+        Some("cpu") => {
+            let features = cpu_features();
+            println!("Vendor: {}", features.vendor());
+            println!("Flags: {}", features.flags().collect::<Vec<_>>().join(" "));
+        }
+
+        Some("cpus") => {
+            let cpus = online_cpus();
+            println!("{} online CPU(s)", cpus.len());
+            for cpu in cpus {
+                let role = if cpu.is_bsp { " (BSP)" } else { "" };
+                println!("    APIC ID {}{role}", cpu.apic_id);
+            }
+        }
+
+        Some("mem") => {
+            // The frame allocator is locked when the heap grows, so nothing is printed or allocated while it is held,
+            // and interrupts are disabled so that an interrupt handler can't try to lock it
+            let (total, usable, reserved, memory_map) = without_interrupts(|| {
+                let frame_allocator = KERNEL_STATE.frame_allocator.lock();
+                (
+                    frame_allocator.total_memory(),
+                    frame_allocator.usable_memory(),
+                    frame_allocator.reserved_memory(),
+                    frame_allocator.memory_map(),
+                )
+            });
+
+            println!("Total: {}", Bytes(total));
+            println!("Usable: {}", Bytes(usable));
+            println!("Reserved: {}", Bytes(reserved));
+            for (kind, bytes) in BootInfoFrameAllocator::memory_by_kind(memory_map) {
+                println!("    {kind:?}: {}", Bytes(bytes));
+            }
+        }
+
+        Some("uptime") => {
+            let source = if hpet::nanoseconds().is_some() {
+                "HPET"
+            } else {
+                "timer ticks"
+            };
+            println!("Uptime: {} (from {source})", Hms(KERNEL_STATE.uptime()));
+        }
+
+        Some("input") => print_queue_stats(),
+
+        Some("interrupts") => {
+            for (vector, count) in interrupt_counts().into_iter().enumerate() {
+                if count != 0 {
+                    println!("Vector {vector:#04x}: {count}");
+                }
+            }
+
+            println!("Spurious interrupts: {}", spurious_interrupt_count());
+            println!(
+                "EOIs sent: {} (PIC), {} (APIC)",
+                PIC_EOI.load(Ordering::Relaxed),
+                APIC_EOI.load(Ordering::Relaxed)
+            );
+        }
+
+        Some("pagetable") => {
+            let Some(addr) = args.get(1) else {
+                println!("Provide a virtual address to translate");
+                return;
+            };
+
+            match u64::from_str_radix(addr.trim_start_matches("0x"), 16) {
+                Ok(addr) => match VirtAddr::try_new(addr) {
+                    Ok(addr) => print_translation(addr),
+                    Err(_) => println!("{addr:#x} is not a canonical virtual address"),
+                },
+                Err(_) => println!("Invalid address '{addr}'"),
+            }
+        }
+
+        Some(a) => {
+            println!("Unknown argument '{a}'");
+        }
+        None => println!("Provide argument for what to give info about"),
+    }
+}
+
+/// Times scrolling the screen with the framebuffer mapped as uncacheable and as write-combining,
+/// scrolling the number of times given in the first argument
+fn fbbench(args: &[&str]) {
+    let scrolls = match args.first().map(|n| n.parse::<u32>()) {
+        None => 100,
+        Some(Ok(scrolls)) if scrolls > 0 => scrolls,
+        Some(_) => {
+            println!("First argument must be a positive number of scrolls");
+            return;
+        }
+    };
+
+    // The framebuffer isn't always write-combining to start with, e.g. if the CPU doesn't support the PAT
+    let original = match saved_framebuffer_memory_type() {
+        Ok(original) => original,
+        Err(e) => {
+            println!("Couldn't get the framebuffer's memory type: {e:?}");
+            return;
+        }
+    };
+
+    let mut results = Vec::new();
+    for memory_type in [MemoryType::Uncacheable, MemoryType::WriteCombining] {
+        let result = set_framebuffer_memory_type(memory_type)
+            .map(|()| time_scrolls(scrolls as usize) / scrolls);
+        results.push((memory_type, result));
+    }
+
+    // Put the framebuffer back how it was mapped, even if changing its memory type failed part way through
+    let restored = restore_framebuffer_memory_type(original);
+
+    for (memory_type, result) in &results {
+        match result {
+            Ok(time) => println!("{memory_type:?}: {time:?} per scroll"),
+            Err(e) => println!("{memory_type:?}: couldn't map the framebuffer: {e:?}"),
+        }
+    }
+
+    if let [(_, Ok(uncacheable)), (_, Ok(write_combining))] = results[..] {
+        if !write_combining.is_zero() {
+            println!(
+                "Write-combining is {:.1} times as fast",
+                uncacheable.as_secs_f64() / write_combining.as_secs_f64()
+            );
+        }
+    }
+
+    if let Err(e) = restored {
+        println!("Couldn't restore the framebuffer's memory type: {e:?}");
+    }
+}
+
+/// Sets the scale which text is drawn at to the value given in the first argument
+fn font_scale(args: &[&str]) {
+    let Some(Ok(scale)) = args.first().map(|n| n.parse()) else {
+        println!("First argument must be a scale");
+        return;
+    };
+
+    let result = without_interrupts(|| WRITER.lock().set_scale(scale));
+
+    match result {
+        Ok(()) => (),
+        Err(SetScaleError::InvalidScale) => println!("Scale must be between 1 and {MAX_SCALE}"),
+        Err(SetScaleError::ScreenTooSmall) => println!("The screen is too small for that scale"),
+    }
+}
+
+/// Lists the scheduler's tasks
+fn ps(_: &[&str]) {
+    let tasks = list_tasks();
+
+    println!("ID   State    Name");
+    for (i, task) in tasks.iter().enumerate() {
+        let state = match task.state {
+            TaskState::New => "new",
+            TaskState::Pending => "pending",
+        };
+
+        println!("{i:<4} {state:<8} {}", task.name.unwrap_or("<unnamed>"));
+    }
+}
+
+/// Powers off the computer
+fn poweroff(_: &[&str]) {
+    // SAFETY: This is just a debug console, so killing the OS is fine.
+    let error = unsafe { shutdown() };
+    println!("Failed to power off: {error:?}");
+}
+
+/// Sends an interrupt on the vector specified in the first argument.
+///
+/// # Safety
+/// This is for debugging only. Raising an interrupt which the kernel isn't expecting is not sound,
+/// e.g. an exception handler may read an error code which wasn't pushed, or a driver may act on a device event which didn't happen.
+unsafe fn debug_interrupt(args: &[&str]) {
+    match args.first().map(|n| n.parse()) {
+        Some(Ok(vector)) => {
+            // SAFETY: The caller accepts that this may not be sound
+            unsafe { send_debug_self_interrupt(vector) }
+        }
+        _ => {
+            println!("First argument must be interrupt vector");
+        }
+    };
+}
@@ -0,0 +1,114 @@
+//! The [`SerialKeyDecoder`] type, which turns bytes received on the serial port into keypresses for the shell
+
+use pc_keyboard::{DecodedKey, KeyCode};
+
+/// The state of a [`SerialKeyDecoder`] between bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    /// Not in an escape sequence
+    Normal,
+    /// An escape byte was received, so this may be the start of an escape sequence
+    Escape,
+    /// In a _Control Sequence Introducer_ escape sequence (`ESC [`), with the number received so far
+    ControlSequence(u8),
+}
+
+/// Decodes the bytes sent by a terminal connected to the serial port into the keys understood by
+/// [`LineEditor`][super::line_editor::LineEditor].
+///
+/// Terminals send most keys as ASCII, but send the arrow keys, Home, End, and Delete as escape sequences
+/// such as `ESC [ A`, so the decoder keeps track of partially received sequences.
+/// Only ASCII is supported, as the screen's font only has ASCII characters.
+#[derive(Debug)]
+pub struct SerialKeyDecoder {
+    /// Whether an escape sequence is being received
+    state: DecoderState,
+    /// Whether the last byte was a carriage return, so that a following line feed isn't treated as a second Enter
+    last_was_cr: bool,
+}
+
+impl SerialKeyDecoder {
+    /// Constructs a new [`SerialKeyDecoder`]
+    pub const fn new() -> Self {
+        Self {
+            state: DecoderState::Normal,
+            last_was_cr: false,
+        }
+    }
+
+    /// Processes a byte received on the serial port,
+    /// returning a key if the byte completes one
+    pub fn decode(&mut self, byte: u8) -> Option<DecodedKey> {
+        let last_was_cr = core::mem::replace(&mut self.last_was_cr, byte == b'\r');
+
+        match self.state {
+            DecoderState::Normal => match byte {
+                0x1B => {
+                    self.state = DecoderState::Escape;
+                    None
+                }
+                // Terminals send either CR or CR LF for Enter
+                b'\r' => Some(DecodedKey::Unicode('\n')),
+                b'\n' if last_was_cr => None,
+                // Most terminals send DEL for Backspace
+                0x7F | 0x08 => Some(DecodedKey::Unicode('\u{8}')),
+                0..=0x7F => Some(DecodedKey::Unicode(char::from(byte))),
+                _ => None,
+            },
+            DecoderState::Escape => {
+                self.state = match byte {
+                    b'[' => DecoderState::ControlSequence(0),
+                    _ => DecoderState::Normal,
+                };
+                None
+            }
+            DecoderState::ControlSequence(n) => {
+                if byte.is_ascii_digit() {
+                    self.state = DecoderState::ControlSequence(
+                        n.saturating_mul(10).saturating_add(byte - b'0'),
+                    );
+                    return None;
+                }
+
+                self.state = DecoderState::Normal;
+
+                match (byte, n) {
+                    (b'A', _) => Some(DecodedKey::RawKey(KeyCode::ArrowUp)),
+                    (b'B', _) => Some(DecodedKey::RawKey(KeyCode::ArrowDown)),
+                    (b'C', _) => Some(DecodedKey::RawKey(KeyCode::ArrowRight)),
+                    (b'D', _) => Some(DecodedKey::RawKey(KeyCode::ArrowLeft)),
+                    (b'H', _) | (b'~', 1 | 7) => Some(DecodedKey::RawKey(KeyCode::Home)),
+                    (b'F', _) | (b'~', 4 | 8) => Some(DecodedKey::RawKey(KeyCode::End)),
+                    (b'~', 3) => Some(DecodedKey::Unicode('\u{7f}')),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// Tests that text, line endings, backspace, and escape sequences are decoded
+#[test_case]
+fn test_serial_key_decoder() {
+    use alloc::vec::Vec;
+
+    let mut decoder = SerialKeyDecoder::new();
+    let keys: Vec<_> = b"ab\x7f\r\n\x1b[D\x1b[3~\x1b[1~\n"
+        .iter()
+        .filter_map(|&byte| decoder.decode(byte))
+        .collect();
+
+    assert_eq!(
+        keys,
+        [
+            DecodedKey::Unicode('a'),
+            DecodedKey::Unicode('b'),
+            DecodedKey::Unicode('\u{8}'),
+            DecodedKey::Unicode('\n'),
+            DecodedKey::RawKey(KeyCode::ArrowLeft),
+            DecodedKey::Unicode('\u{7f}'),
+            DecodedKey::RawKey(KeyCode::Home),
+            DecodedKey::Unicode('\n'),
+        ]
+    );
+}
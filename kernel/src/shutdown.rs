@@ -0,0 +1,60 @@
+//! Stopping the kernel's drivers before powering off, so that devices aren't left running when the power is cut
+
+use log::{info, warn};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    acpi::{power_off, PowerOffError},
+    cpu::ps2::PS2_CONTROLLER,
+    global_state::TryLockedIfInitError,
+    graphics,
+    pci::halt_controllers,
+};
+
+/// How long to wait for all xHCI controllers to halt, in nanoseconds
+const XHCI_HALT_TIMEOUT_NS: u64 = 100_000_000;
+
+/// Halts the xHCI controllers, disables the PS/2 ports, and flushes the screen, and then powers the machine off.
+/// Each step is skipped with a log message if its subsystem hasn't been initialised or can't be shut down.
+///
+/// This only returns if powering off fails, in which case some drivers may no longer be running.
+///
+/// # Safety
+/// All running programs will be stopped and anything in RAM will be lost.
+pub unsafe fn shutdown() -> PowerOffError {
+    info!("Shutting down");
+
+    match halt_controllers(XHCI_HALT_TIMEOUT_NS) {
+        0 => info!("Halted USB controllers"),
+        running => warn!("{running} USB controllers didn't halt"),
+    }
+
+    // The PS/2 controller is used in its interrupt handlers, so disable interrupts while it is locked
+    let ps2_result = without_interrupts(|| {
+        PS2_CONTROLLER.try_locked_if_init().map(|mut controller| {
+            // SAFETY: The kernel is shutting down, so nothing is relying on input from the PS/2 devices
+            unsafe { controller.disable() }
+        })
+    });
+
+    match ps2_result {
+        Ok(Ok(())) => info!("Disabled PS/2 ports"),
+        Ok(Err(e)) => warn!("Failed to disable PS/2 ports: {e:?}"),
+        Err(TryLockedIfInitError::NotInitialised) => info!("No PS/2 controller to disable"),
+        Err(TryLockedIfInitError::Locked) => {
+            warn!("PS/2 controller is locked, so not disabling it");
+        }
+    }
+
+    info!("Powering off");
+
+    if graphics::flush().is_err() {
+        warn!("Failed to flush the screen");
+    }
+
+    // SAFETY: The drivers have been shut down, and the caller accepts that the kernel will stop
+    match unsafe { power_off() } {
+        Ok(never) => match never {},
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,113 @@
+//! Resolving addresses in the kernel to the names of the functions they are in.
+//!
+//! In debug builds, `kernel-builder` puts the un-stripped kernel in the initrd at [`DEBUG_SYMBOLS_PATH`].
+//! [`init`] parses the ELF symbol table from it at boot, so that [`resolve`] doesn't need to parse anything,
+//! which means it can be used while panicking.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use object::{elf::FileHeader64, Object, ObjectSymbol, SymbolKind};
+
+use crate::{
+    initrd::{self, DEBUG_SYMBOLS_PATH},
+    KERNEL_VIRT_ADDR,
+};
+
+/// An ELF file
+type ElfFile<'a> = object::read::elf::ElfFile<'a, FileHeader64<object::NativeEndian>>;
+
+/// A function in the kernel's symbol table
+#[derive(Debug)]
+struct Symbol {
+    /// The virtual address of the start of the function
+    start: u64,
+    /// The length of the function in bytes
+    size: u64,
+    /// The mangled name of the function
+    name: &'static str,
+}
+
+/// The kernel's functions, sorted by start address
+static SYMBOLS: OnceCell<Vec<Symbol>> = OnceCell::uninit();
+
+/// An error which can occur when loading the kernel's symbols with [`init`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolsInitError {
+    /// The kernel's debug symbols were not in the initrd - this will be the case in release builds
+    NoDebugSymbols,
+    /// Couldn't read the debug symbols as an ELF file
+    Parse(object::read::Error),
+    /// The symbols have already been loaded
+    AlreadyInitialised,
+}
+
+impl From<object::read::Error> for SymbolsInitError {
+    fn from(value: object::read::Error) -> Self {
+        Self::Parse(value)
+    }
+}
+
+/// Loads the symbol table from the kernel's debug symbols in the initrd, returning the number of functions found.
+/// The heap and the initrd must have been initialised.
+pub fn init() -> Result<usize, SymbolsInitError> {
+    let debug_symbols = initrd::open(DEBUG_SYMBOLS_PATH).ok_or(SymbolsInitError::NoDebugSymbols)?;
+    let file = ElfFile::parse(debug_symbols)?;
+
+    // The symbols' addresses are relative to the start of the kernel
+    let mut symbols: Vec<_> = file
+        .symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() != 0)
+        .filter_map(|symbol| {
+            Some(Symbol {
+                start: symbol.address() + KERNEL_VIRT_ADDR,
+                size: symbol.size(),
+                name: symbol.name().ok()?,
+            })
+        })
+        .collect();
+    symbols.sort_unstable_by_key(|symbol| symbol.start);
+
+    let count = symbols.len();
+    SYMBOLS
+        .try_init_once(|| symbols)
+        .map_err(|_| SymbolsInitError::AlreadyInitialised)?;
+
+    Ok(count)
+}
+
+/// Gets whether the kernel's symbols have been loaded by [`init`]
+pub fn is_loaded() -> bool {
+    SYMBOLS.is_initialized()
+}
+
+/// Finds the function containing `address`, returning its mangled name and the offset of `address` from its start.
+/// Returns [`None`] if the symbols haven't been loaded, or if the address isn't in any function.
+pub fn resolve(address: u64) -> Option<(&'static str, usize)> {
+    let symbols = SYMBOLS.get()?;
+
+    // Find the last symbol starting at or before the address
+    let index = symbols
+        .partition_point(|symbol| symbol.start <= address)
+        .checked_sub(1)?;
+    let symbol = &symbols[index];
+
+    let offset = address - symbol.start;
+    if offset < symbol.size {
+        Some((symbol.name, offset.try_into().ok()?))
+    } else {
+        None
+    }
+}
+
+/// Tests that addresses in a function resolve to that function, if the symbols are loaded
+#[test_case]
+fn test_resolve() {
+    if !is_loaded() {
+        return;
+    }
+
+    let address = test_resolve as usize as u64;
+    let (name, offset) = resolve(address + 1).expect("Test function should have a symbol");
+    assert!(name.contains("test_resolve"));
+    assert_eq!(offset, 1);
+}
@@ -0,0 +1,178 @@
+//! Running user programs in ring 3, and the syscalls they use to talk to the kernel.
+//!
+//! # Syscall convention
+//! A program makes a syscall with `int 0x80`, with the syscall number in `rax` and its arguments in `rdi` and `rsi`.
+//! The result is returned in `rax`, with negative values being errors.
+//! Syscalls are treated like a System V function call, so only `rbx`, `rbp`, `rsp`, and `r12`-`r15` are preserved.
+//!
+//! | Number | Syscall | Arguments | Result |
+//! |--------|---------|-----------|--------|
+//! | 0 | Exit | `rdi`: exit code | Doesn't return |
+//! | 1 | Write to the console | `rdi`: pointer to UTF-8 text, `rsi`: length in bytes | Bytes written |
+
+use core::arch::global_asm;
+
+use alloc::string::String;
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, Page, PageTableFlags, Size4KiB, Translate},
+    VirtAddr,
+};
+
+use crate::{cpu::gdt, global_state::KERNEL_STATE, print};
+
+/// The interrupt vector which user programs use to make syscalls
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// The syscall number to exit the program
+const SYSCALL_EXIT: u64 = 0;
+/// The syscall number to write text to the console
+const SYSCALL_WRITE: u64 = 1;
+
+/// The result of a syscall with an unknown syscall number
+const ERROR_UNKNOWN_SYSCALL: i64 = -1;
+/// The result of a syscall which was passed memory the program can't access
+const ERROR_BAD_ADDRESS: i64 = -2;
+
+/// The end of the lower half of the address space, which is the only part user programs can access
+const USER_ADDRESS_END: u64 = 0x0000_8000_0000_0000;
+
+/// The kernel's stack pointer while a user program is running, saved by `enter_user_mode`.
+/// The exit syscall restores this to return from `enter_user_mode`.
+static mut USER_MODE_KERNEL_RSP: u64 = 0;
+
+global_asm!(
+    ".global enter_user_mode",
+    ".global syscall_entry",
+    // Arguments: rdi = entry point, rsi = stack pointer, rdx = code selector, rcx = data selector
+    "enter_user_mode:",
+    "    push %rbx",
+    "    push %rbp",
+    "    push %r12",
+    "    push %r13",
+    "    push %r14",
+    "    push %r15",
+    "    pushfq",
+    "    mov %rsp, {kernel_rsp}(%rip)",
+    // Build an interrupt stack frame for the program, with interrupts enabled
+    "    push %rcx",
+    "    push %rsi",
+    "    push $0x202",
+    "    push %rdx",
+    "    push %rdi",
+    "    iretq",
+    "syscall_entry:",
+    "    cld",
+    // Syscalls are only valid from user mode, as the kernel's stack pointer is only saved while a program is running
+    "    testb $3, 8(%rsp)",
+    "    jz 2f",
+    "    cmp ${exit}, %rax",
+    "    je 3f",
+    // The CPU pushed 5 values onto the 16-byte aligned kernel entry stack, so realign it for the call
+    "    sub $8, %rsp",
+    "    mov %rsi, %rdx",
+    "    mov %rdi, %rsi",
+    "    mov %rax, %rdi",
+    "    call {dispatch}",
+    "    add $8, %rsp",
+    "    iretq",
+    "2:",
+    "    mov ${unknown}, %rax",
+    "    iretq",
+    // Exit: discard the interrupt stack frame and return from `enter_user_mode` with the exit code
+    "3:",
+    "    mov {kernel_rsp}(%rip), %rsp",
+    "    mov %rdi, %rax",
+    "    popfq",
+    "    pop %r15",
+    "    pop %r14",
+    "    pop %r13",
+    "    pop %r12",
+    "    pop %rbp",
+    "    pop %rbx",
+    "    ret",
+    kernel_rsp = sym USER_MODE_KERNEL_RSP,
+    dispatch = sym dispatch_syscall,
+    exit = const SYSCALL_EXIT,
+    unknown = const ERROR_UNKNOWN_SYSCALL,
+    options(att_syntax)
+);
+
+extern "C" {
+    /// Enters ring 3 at `entry` with the given stack pointer and segment selectors,
+    /// and returns the exit code when the program makes the exit syscall.
+    fn enter_user_mode(entry: u64, stack: u64, code_selector: u64, data_selector: u64) -> i64;
+    /// The handler for [`SYSCALL_VECTOR`]
+    fn syscall_entry();
+}
+
+/// Gets the address of the handler to register in the IDT for [`SYSCALL_VECTOR`].
+/// The handler is not an `extern "x86-interrupt"` function, as it needs to read the program's registers.
+pub fn syscall_entry_address() -> VirtAddr {
+    VirtAddr::new(syscall_entry as usize as u64)
+}
+
+/// Runs a user program in ring 3 until it makes the exit syscall, and returns its exit code.
+///
+/// # Safety
+/// The program's code and stack must be mapped as user accessible, and `stack` must be 16-byte aligned.
+/// This must only be called on the BSP, and not while another program is running.
+pub unsafe fn run_user_program(entry: VirtAddr, stack: VirtAddr) -> i64 {
+    // SAFETY: The caller guarantees that the program's memory is mapped,
+    // and the selectors are for the user segments in the GDT.
+    // `enter_user_mode` saves and restores all callee-saved registers, so it can be called like a normal function.
+    unsafe {
+        enter_user_mode(
+            entry.as_u64(),
+            stack.as_u64(),
+            gdt::user_code_selector().0.into(),
+            gdt::user_data_selector().0.into(),
+        )
+    }
+}
+
+/// Runs the syscall with the given number, called from `syscall_entry`
+extern "C" fn dispatch_syscall(number: u64, arg0: u64, arg1: u64) -> i64 {
+    match number {
+        SYSCALL_WRITE => write(arg0, arg1),
+        _ => ERROR_UNKNOWN_SYSCALL,
+    }
+}
+
+/// The write syscall - prints `len` bytes of UTF-8 text at `ptr` to the console
+fn write(ptr: u64, len: u64) -> i64 {
+    if !is_user_accessible(ptr, len) {
+        return ERROR_BAD_ADDRESS;
+    }
+
+    // SAFETY: The memory was checked to be mapped for the program, which isn't running while the syscall is handled
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    print!("{}", String::from_utf8_lossy(bytes));
+
+    i64::try_from(len).expect("Length should be less than USER_ADDRESS_END")
+}
+
+/// Checks whether `len` bytes at `ptr` are all mapped as accessible to user programs
+fn is_user_accessible(ptr: u64, len: u64) -> bool {
+    let Some(end) = ptr.checked_add(len) else {
+        return false;
+    };
+    if end > USER_ADDRESS_END {
+        return false;
+    }
+    if len == 0 {
+        return true;
+    }
+
+    let page_table = KERNEL_STATE.page_table.lock();
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(VirtAddr::new(ptr)),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    );
+
+    pages.into_iter().all(|page| {
+        matches!(
+            page_table.translate(page.start_address()),
+            TranslateResult::Mapped { flags, .. } if flags.contains(PageTableFlags::USER_ACCESSIBLE)
+        )
+    })
+}
@@ -1,13 +1,24 @@
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use alloc::string::String;
 use bootloader_api::BootInfo;
 
-use crate::{cpu, init, println, serial, serial_println, BOOT_CONFIG};
+use crate::{cpu, global_state::KERNEL_STATE, init, println, serial, serial_println, BOOT_CONFIG};
 
+/// A value written to qemu's `isa-debug-exit` device to exit qemu.
+///
+/// Qemu exits with the code `(value << 1) | 1`, so [`Success`] gives an exit code of 33 and [`Failed`] gives 35.
+/// [`Success`] must be the same as `QEMU_EXIT_SUCCESS` in `kernel-builder/src/main.rs`, which checks for this exit code.
+///
+/// [`Success`]: QemuExitCode::Success
+/// [`Failed`]: QemuExitCode::Failed
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
+    /// The test runner succeeded
     Success = 0x10,
+    /// The test runner failed
     Failed = 0x11,
 }
 
@@ -44,16 +55,79 @@ fn panic(info: &PanicInfo) -> ! {
     );
     println!("In stack {:?}", cpu::gdt::get_stack(stack_pointer_approx));
 
-    #[cfg(debug_assertions)]
-    match crate::panic::backtrace::backtrace() {
-        Ok(_) => (),
-        Err(e) => println!("Error printing backtrace: {e:?}"),
-    }
+    crate::panic::print_backtrace();
 
     exit_qemu(QemuExitCode::Failed);
 }
 
+/// Whether the currently running test expects to overflow the kernel stack
+static EXPECT_STACK_OVERFLOW: AtomicBool = AtomicBool::new(false);
+
+/// Marks that the currently running test is about to deliberately overflow the kernel stack.
+/// If a double fault then occurs because of a page fault in the kernel stack's guard page,
+/// the test will pass rather than panicking.
+pub fn expect_stack_overflow() {
+    EXPECT_STACK_OVERFLOW.store(true, Ordering::Relaxed);
+}
+
+/// Recurses until the kernel stack overflows.
+/// [`expect_stack_overflow`] should be called first so that the resulting double fault doesn't fail the test.
+pub fn overflow_stack() -> ! {
+    /// Recurses forever, using up some stack space in each call
+    #[allow(unconditional_recursion)]
+    fn recurse(depth: u64) -> u64 {
+        let data = core::hint::black_box([depth; 16]);
+        recurse(depth + 1) + data[0]
+    }
+
+    recurse(0);
+    unreachable!("Recursing forever should have overflowed the stack")
+}
+
+/// Called by the double fault handler in test builds.
+/// If the current test called [`expect_stack_overflow`], this checks that the double fault handler is running
+/// on its own stack and that the double fault was caused by the kernel stack overflowing into its guard page,
+/// then exits qemu with the test's result.
+/// Otherwise, this function returns and the double fault handler panics as normal.
+pub fn double_fault_hook() {
+    use x86_64::registers::control::Cr2;
+
+    if !EXPECT_STACK_OVERFLOW.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // The address of a local variable is used as an approximation of the stack pointer
+    let stack_pointer_approx = 0u8;
+    let stack = cpu::gdt::get_stack(&stack_pointer_approx as *const _ as usize);
+
+    if stack != cpu::gdt::Stack::DoubleFaultHandler {
+        println!("Double fault handler was running on stack {stack:?}");
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    println!("Double fault handler is running on its own stack");
+
+    let accessed_address = Cr2::read();
+    let guard_page = cpu::kernel_stack_guard_page().expect("Kernel stack should have a guard page");
+
+    if guard_page.start_address() <= accessed_address
+        && accessed_address < guard_page.start_address() + guard_page.size()
+    {
+        println!("Kernel stack overflowed into guard page at {accessed_address:?}");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        println!(
+            "Expected a fault in the guard page at {:?}, but the accessed address was {accessed_address:?}",
+            guard_page.start_address()
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
 pub trait Testable {
+    /// The name of the test, which is the path of the test function
+    fn name(&self) -> &'static str;
+
     fn run(&self);
 }
 
@@ -61,8 +135,12 @@ impl<T> Testable for T
 where
     T: Fn(),
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        println!("{}", core::any::type_name::<T>());
+        println!("{}", self.name());
         self();
     }
 }
@@ -84,22 +162,43 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     exit_qemu(QemuExitCode::Success);
 }
 
+/// How long [`test_runner`] waits for each command from the host before giving up, in seconds.
+/// This is generous so that slow hosts don't cause spurious failures.
+const COMMAND_TIMEOUT_SECONDS: usize = 60;
+
+/// Reads a line from serial input for [`test_runner`], exiting QEMU with a failure
+/// if nothing is received within [`COMMAND_TIMEOUT_SECONDS`], so that the kernel doesn't hang if the host has gone away.
+fn read_command() -> String {
+    let ticks = COMMAND_TIMEOUT_SECONDS * KERNEL_STATE.tick_hz();
+
+    serial::readln_timeout(ticks).unwrap_or_else(|| {
+        println!("No test command received within {COMMAND_TIMEOUT_SECONDS}s - exiting");
+        exit_qemu(QemuExitCode::Failed)
+    })
+}
+
 /// The runner for a test. Because of the way the host-side of the test runner is written,
-/// this function responds to two different types of command, read from serial input:
+/// this function responds to three different types of command, read from serial input:
 ///
 /// * `count`: Writes the number of tests to serial output.
+/// * `list`: Writes the index and name of each test to serial output, one per line in the form `index: name`.
 /// * `run`: Reads the test number from serial input and runs it.
 pub fn test_runner(tests: &[&dyn Testable]) {
     // This is so that the host test runner script knows when to send the command
     println!(">>>>>> READY FOR TEST COMMAND");
-    let command = serial::readln();
+    let command = read_command();
 
     match command.as_str() {
         "count" => {
             serial_println!("{}", tests.len());
         }
+        "list" => {
+            for (i, test) in tests.iter().enumerate() {
+                serial_println!("{i}: {}", test.name());
+            }
+        }
         "run" => {
-            let i = serial::readln().parse::<usize>().unwrap();
+            let i = read_command().parse::<usize>().unwrap();
             let test = tests[i];
             test.run();
         }
@@ -115,4 +214,4 @@ fn always_passes() {
 // #[test_case]
 // fn failure() {
 //     panic!("Test failure panic")
-// }
\ No newline at end of file
+// }
@@ -0,0 +1,46 @@
+//! Contains the [`Bytes`] struct for printing out sizes in human-readable units
+
+use core::fmt::Display;
+
+/// A utility struct for implementing [`Display`] for a number of bytes, using the largest binary unit
+/// (KiB, MiB, GiB, or TiB) which the size is at least one of, to two decimal places.
+///
+/// ```
+/// println!("{}", Bytes(3 * 1024 * 1024 / 2)); // Prints "1.50 MiB"
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes(pub u64);
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        /// The units larger than a byte, in increasing order
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+        let mut unit = None;
+        let mut size = self.0 as f64;
+        for next_unit in UNITS {
+            if size < 1024.0 {
+                break;
+            }
+            size /= 1024.0;
+            unit = Some(next_unit);
+        }
+
+        match unit {
+            Some(unit) => write!(f, "{size:.2} {unit}"),
+            None => write!(f, "{} B", self.0),
+        }
+    }
+}
+
+/// Tests that sizes are printed with the right unit
+#[test_case]
+fn test_bytes() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", Bytes(0)), "0 B");
+    assert_eq!(format!("{}", Bytes(1023)), "1023 B");
+    assert_eq!(format!("{}", Bytes(1024)), "1.00 KiB");
+    assert_eq!(format!("{}", Bytes(3 * 1024 * 1024 / 2)), "1.50 MiB");
+    assert_eq!(format!("{}", Bytes(4 << 40)), "4.00 TiB");
+}
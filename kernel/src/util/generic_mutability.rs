@@ -1,6 +1,7 @@
-//! The [`Mutability`] trait and [`Mutable`] and [`Immutable`] marker types for being generic over mutability
+//! The [`Mutability`] trait and [`Mutable`] and [`Immutable`] marker types for being generic over mutability,
+//! and the [`VolatileSlice`] type built on them.
 
-use core::fmt::Debug;
+use core::{fmt::Debug, marker::PhantomData};
 
 use x86_64::VirtAddr;
 
@@ -221,6 +222,116 @@ impl<M: Mutability> VirtAddrGenericMutabilityExt<M> for VirtAddr {
     }
 }
 
+/// A slice of values which must be read and written with volatile accesses, such as an array of MMIO registers.
+/// All accesses are bounds-checked.
+///
+/// Like [`Mutability::Ref`], a [`VolatileSlice`] is generic over whether it can be written to.
+pub struct VolatileSlice<'a, T: 'a, M: Mutability> {
+    /// Pointer to the first value
+    ptr: M::Ptr<T>,
+    /// The number of values
+    len: usize,
+    /// A phantom reference to the values so that the slice is borrow-checked
+    _phantom: PhantomData<M::Ref<'a, T>>,
+}
+
+/// The error returned when accessing a [`VolatileSlice`] at an index which is out of bounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexOutOfBoundsError {
+    /// The index which was accessed
+    pub index: usize,
+    /// The length of the slice
+    pub len: usize,
+}
+
+impl<'a, T: 'a, M: Mutability> VolatileSlice<'a, T, M> {
+    /// Constructs a new [`VolatileSlice`] of `len` values starting at `ptr`.
+    ///
+    /// # Safety
+    /// * `ptr` must point to `len` consecutive values of type `T`, which must be valid for reads for `'a`.
+    /// * If `M` is [`Mutable`], the values must also be valid for writes for `'a`,
+    ///     and must not be accessed except through this slice.
+    pub unsafe fn new(ptr: M::Ptr<T>, len: usize) -> Self {
+        Self {
+            ptr,
+            len,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Gets the number of values in the slice
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Gets whether the slice has no values
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets a pointer to the value at `index`, or [`None`] if `index` is out of bounds.
+    /// This is useful when the values are structs whose fields need to be accessed individually.
+    pub fn get_ptr(&self, index: usize) -> Option<M::Ptr<T>> {
+        if index < self.len {
+            // SAFETY: `index` is in bounds, so the pointer is within the slice
+            Some(unsafe { self.ptr.add(index) })
+        } else {
+            None
+        }
+    }
+
+    /// Reads the value at `index`, or returns an error if `index` is out of bounds
+    pub fn get(&self, index: usize) -> Result<T, IndexOutOfBoundsError>
+    where
+        T: Copy,
+    {
+        let ptr = self.checked_ptr(index)?.as_const_ptr();
+        // SAFETY: `ptr` is in bounds, and the caller of `new` guaranteed that the values are valid for reads
+        Ok(unsafe { ptr.read_volatile() })
+    }
+
+    /// Gets a pointer to the value at `index`, or an [`IndexOutOfBoundsError`] if `index` is out of bounds
+    fn checked_ptr(&self, index: usize) -> Result<M::Ptr<T>, IndexOutOfBoundsError> {
+        self.get_ptr(index).ok_or(IndexOutOfBoundsError {
+            index,
+            len: self.len,
+        })
+    }
+}
+
+impl<'a, T: 'a> VolatileSlice<'a, T, Mutable> {
+    /// Writes `value` to the value at `index`, or returns an error if `index` is out of bounds
+    pub fn set(&mut self, index: usize, value: T) -> Result<(), IndexOutOfBoundsError> {
+        let ptr = self.checked_ptr(index)?;
+        // SAFETY: `ptr` is in bounds, and the caller of `new` guaranteed that the values are valid for writes
+        unsafe { ptr.write_volatile(value) };
+        Ok(())
+    }
+}
+
+impl<'a, T: 'a, M: Mutability> Debug for VolatileSlice<'a, T, M> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VolatileSlice")
+            .field("ptr", &self.ptr.as_const_ptr())
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<'a, T: 'a> From<&'a [T]> for VolatileSlice<'a, T, Immutable> {
+    fn from(slice: &'a [T]) -> Self {
+        // SAFETY: The values are valid for reads for as long as the slice is borrowed
+        unsafe { Self::new(slice.as_ptr(), slice.len()) }
+    }
+}
+
+impl<'a, T: 'a> From<&'a mut [T]> for VolatileSlice<'a, T, Mutable> {
+    fn from(slice: &'a mut [T]) -> Self {
+        // SAFETY: The values are valid for reads and writes for as long as the slice is mutably borrowed
+        unsafe { Self::new(slice.as_mut_ptr(), slice.len()) }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use core::marker::PhantomData;
@@ -267,4 +378,41 @@ mod tests {
             }
         }
     }
+
+    #[test_case]
+    fn test_volatile_slice_get() {
+        let values = [1u32, 2, 3];
+        let slice = VolatileSlice::from(&values[..]);
+
+        assert_eq!(slice.len(), 3);
+        assert_eq!(slice.get(0), Ok(1));
+        assert_eq!(slice.get(2), Ok(3));
+        assert_eq!(
+            slice.get(3),
+            Err(IndexOutOfBoundsError { index: 3, len: 3 })
+        );
+    }
+
+    #[test_case]
+    fn test_volatile_slice_set() {
+        let mut values = [0u16; 4];
+        let mut slice = VolatileSlice::from(&mut values[..]);
+
+        assert_eq!(slice.set(1, 10), Ok(()));
+        assert_eq!(slice.set(3, 30), Ok(()));
+        assert_eq!(
+            slice.set(4, 40),
+            Err(IndexOutOfBoundsError { index: 4, len: 4 })
+        );
+        assert_eq!(slice.get(1), Ok(10));
+
+        assert_eq!(values, [0, 10, 0, 30]);
+    }
+
+    #[test_case]
+    fn test_empty_volatile_slice() {
+        let slice = VolatileSlice::<u8, Immutable>::from(&[][..]);
+        assert!(slice.is_empty());
+        assert!(slice.get_ptr(0).is_none());
+    }
 }
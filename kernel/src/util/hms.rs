@@ -0,0 +1,41 @@
+//! Contains the [`Hms`] struct for printing out durations as hours, minutes, and seconds
+
+use core::{fmt::Display, time::Duration};
+
+/// A utility struct for implementing [`Display`] for a [`Duration`] in the format `HH:MM:SS`.
+/// The fractional part of the last second is not printed, and the hours go above 99 rather than wrapping.
+///
+/// ```
+/// println!("{}", Hms(Duration::from_secs(3725))); // Prints "01:02:05"
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hms(pub Duration);
+
+impl Display for Hms {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let seconds = self.0.as_secs();
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+
+/// Tests that durations are split into hours, minutes, and seconds correctly
+#[test_case]
+fn test_hms() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", Hms(Duration::ZERO)), "00:00:00");
+    assert_eq!(
+        format!("{}", Hms(Duration::from_millis(3_725_999))),
+        "01:02:05"
+    );
+    assert_eq!(
+        format!("{}", Hms(Duration::from_secs(100 * 3600))),
+        "100:00:00"
+    );
+}
@@ -27,9 +27,17 @@ enum Format {
 /// or [`new_with_alternate_formatting`] are used then a new formatter is created with the given attributes.
 /// This can be used to make the output more compact, such as forcing the elements to print on one line each.
 ///
+/// Long lists can be shortened with [`with_max_items`], which prints only the first few elements
+/// followed by how many were left out:
+///
+/// ```
+/// println!("{:?}", IteratorListDebug::new(0..10).with_max_items(3)); // Prints "[0, 1, 2, ... 7 more]"
+/// ```
+///
 /// [`new`]: [IteratorListDebug::new]
 /// [`new_with_default_formatting`]: [IteratorListDebug::new_with_default_formatting]
 /// [`new_with_alternate_formatting`]: [IteratorListDebug::new_with_alternate_formatting]
+/// [`with_max_items`]: [IteratorListDebug::with_max_items]
 ///
 pub struct IteratorListDebug<T, U>
 where
@@ -41,6 +49,8 @@ where
     iterator: RefCell<U>,
     /// The [`Format`] which will be applied to the arguments
     format: Format,
+    /// The maximum number of elements to print, or [`None`] to print all of them
+    max_items: Option<usize>,
 }
 
 impl<T, U> IteratorListDebug<T, U>
@@ -54,6 +64,7 @@ where
         Self {
             iterator: RefCell::new(iterator),
             format: Format::PassThrough,
+            max_items: None,
         }
     }
 
@@ -64,6 +75,7 @@ where
         Self {
             iterator: RefCell::new(iterator),
             format: Format::DefaultDebug,
+            max_items: None,
         }
     }
 
@@ -75,8 +87,17 @@ where
         Self {
             iterator: RefCell::new(iterator),
             format: Format::AlternateDebug,
+            max_items: None,
         }
     }
+
+    /// Only prints the first `max_items` elements. If the iterator has more elements than this,
+    /// the rest are counted and replaced with a single `... N more` entry.
+    #[must_use]
+    pub fn with_max_items(mut self, max_items: usize) -> Self {
+        self.max_items = Some(max_items);
+        self
+    }
 }
 
 impl<T, U> Debug for IteratorListDebug<T, U>
@@ -88,14 +109,37 @@ where
         let mut l = f.debug_list();
         let iterator = &mut *self.iterator.borrow_mut();
 
-        for entry in iterator {
+        let mut printed = 0;
+        while let Some(entry) = iterator.next() {
+            if self.max_items.is_some_and(|max_items| printed >= max_items) {
+                let remaining = iterator.count() + 1;
+                l.entry(&format_args!("... {remaining} more"));
+                break;
+            }
+
             match self.format {
                 Format::PassThrough => l.entry(&entry),
                 Format::DefaultDebug => l.entry(&format_args!("{entry:?}")),
                 Format::AlternateDebug => l.entry(&format_args!("{entry:#?}")),
             };
+            printed += 1;
         }
 
         l.finish()
     }
 }
+
+/// Tests that [`IteratorListDebug::with_max_items`] shortens long lists but not short ones
+#[test_case]
+fn test_max_items() {
+    use alloc::format;
+
+    let list = IteratorListDebug::new(0..10).with_max_items(3);
+    assert_eq!(format!("{list:?}"), "[0, 1, 2, ... 7 more]");
+
+    let list = IteratorListDebug::new(0..3).with_max_items(3);
+    assert_eq!(format!("{list:?}"), "[0, 1, 2]");
+
+    let list = IteratorListDebug::new(0..10);
+    assert_eq!(format!("{list:?}"), "[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]");
+}
@@ -2,6 +2,8 @@
 //! which do not clearly fit into another region of the code.
 
 pub mod byte_align_ints;
+pub mod bytes;
+pub mod hms;
 pub mod iter_switch;
 pub mod iterator_list_debug;
 pub mod generic_mutability;